@@ -0,0 +1,337 @@
+//! Conformance test driver for `polyfuse` filesystems.
+//!
+//! [`Harness`] mounts a filesystem on a temporary directory and runs the
+//! canonical filesystem test suites against it:
+//!
+//! * [pjdfstest], the POSIX compliance test suite. Each test script is run
+//!   with the mountpoint as its working directory, and its TAP output is
+//!   reported per script.
+//! * Optionally, [xfstests]. The `check` script is run for the specified
+//!   group (typically `fuse` or `quick`) with `FSTYP=fuse` and `TEST_DIR`
+//!   pointing to the mountpoint, and its summary is reported per test.
+//!
+//! The suites exercise ownership and permission changes, so they must be
+//! run as root, and the filesystem should be mounted with `allow_other`
+//! (and usually `default_permissions`).
+//!
+//! The filesystem is given either as a request handler served on a background
+//! thread ([`Harness::run_session`]) or as a command that mounts it on the
+//! path appended to its arguments ([`Harness::run_command`]), which fits the
+//! examples in this repository.
+//!
+//! [pjdfstest]: https://github.com/pjd/pjdfstest
+//! [xfstests]: https://git.kernel.org/pub/scm/fs/xfs/xfstests-dev.git
+
+#![forbid(clippy::todo, clippy::unimplemented)]
+
+mod report;
+mod tap;
+mod xfstests;
+
+pub use crate::report::{Outcome, Report, Suite, TestResult};
+
+use polyfuse::{KernelConfig, Request, Session};
+use std::{
+    ffi::{OsStr, OsString},
+    fs, io,
+    os::unix::prelude::*,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+const MOUNT_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The driver of the conformance test suites.
+#[derive(Debug, Default)]
+pub struct Harness {
+    pjdfstest: Option<PathBuf>,
+    xfstests: Option<Xfstests>,
+    filters: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Xfstests {
+    dir: PathBuf,
+    group: String,
+    envs: Vec<(OsString, OsString)>,
+}
+
+impl Harness {
+    /// Create a harness without any suite.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run pjdfstest checked out and built in the specified directory.
+    pub fn pjdfstest(&mut self, dir: impl Into<PathBuf>) -> &mut Self {
+        self.pjdfstest = Some(dir.into());
+        self
+    }
+
+    /// Run the group of xfstests checked out and built in the specified directory.
+    pub fn xfstests(&mut self, dir: impl Into<PathBuf>, group: &str) -> &mut Self {
+        self.xfstests = Some(Xfstests {
+            dir: dir.into(),
+            group: group.to_owned(),
+            envs: vec![],
+        });
+        self
+    }
+
+    /// Set an additional environment variable for xfstests, e.g. `FUSE_SUBTYP`
+    /// or `TEST_DEV`.
+    ///
+    /// This has no effect unless [`xfstests`](Harness::xfstests) is set.
+    pub fn xfstests_env(&mut self, key: impl AsRef<OsStr>, value: impl AsRef<OsStr>) -> &mut Self {
+        if let Some(ref mut xfstests) = self.xfstests {
+            xfstests
+                .envs
+                .push((key.as_ref().into(), value.as_ref().into()));
+        }
+        self
+    }
+
+    /// Run only the pjdfstest scripts whose names contain the specified pattern.
+    ///
+    /// This can be called multiple times, and the scripts matching any of
+    /// the patterns are run.
+    pub fn filter(&mut self, pattern: &str) -> &mut Self {
+        self.filters.push(pattern.to_owned());
+        self
+    }
+
+    /// Mount a filesystem served by the specified handler and run the suites.
+    ///
+    /// The handler is called on a background thread for each request.
+    pub fn run_session<F>(&self, config: KernelConfig, mut f: F) -> io::Result<Report>
+    where
+        F: FnMut(&Session, Request) -> io::Result<()> + Send + 'static,
+    {
+        let mountpoint = TempDir::new()?;
+
+        let session = Session::mount(mountpoint.path().to_owned(), config)?;
+        let handle = thread::spawn(move || -> io::Result<()> {
+            while let Some(req) = session.next_request()? {
+                f(&session, req)?;
+            }
+            Ok(())
+        });
+
+        let report = self.run_suites(mountpoint.path());
+
+        unmount(mountpoint.path())?;
+        match handle.join() {
+            Ok(res) => res?,
+            Err(..) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "the handler panicked",
+                ))
+            }
+        }
+
+        report
+    }
+
+    /// Spawn the command mounting a filesystem, and run the suites.
+    ///
+    /// The path to the mountpoint is appended to the arguments of the command.
+    /// The command is expected to keep running until the filesystem is unmounted.
+    pub fn run_command(&self, command: &mut Command) -> io::Result<Report> {
+        let mountpoint = TempDir::new()?;
+
+        let mut child = command
+            .arg(mountpoint.path())
+            .stdin(Stdio::null())
+            .spawn()?;
+        if let Err(err) = wait_mounted(mountpoint.path(), &mut child) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(err);
+        }
+
+        let report = self.run_suites(mountpoint.path());
+
+        unmount(mountpoint.path())?;
+        wait_exited(&mut child)?;
+
+        report
+    }
+
+    /// Run the suites against a filesystem already mounted on the specified path.
+    pub fn run_suites(&self, mountpoint: &Path) -> io::Result<Report> {
+        let mut report = Report::default();
+        if let Some(ref dir) = self.pjdfstest {
+            report.extend(self.run_pjdfstest(dir, mountpoint)?);
+        }
+        if let Some(ref xfstests) = self.xfstests {
+            report.extend(run_xfstests(xfstests, mountpoint)?);
+        }
+        Ok(report)
+    }
+
+    fn run_pjdfstest(&self, dir: &Path, mountpoint: &Path) -> io::Result<Vec<TestResult>> {
+        let tests_dir = dir.join("tests");
+        let mut scripts = vec![];
+        collect_scripts(&tests_dir, &mut scripts)?;
+        scripts.sort();
+
+        let mut results = vec![];
+        for script in scripts {
+            let name = script
+                .strip_prefix(&tests_dir)
+                .unwrap_or(&script)
+                .to_string_lossy()
+                .into_owned();
+            if !self.filters.is_empty() && !self.filters.iter().any(|p| name.contains(&**p)) {
+                continue;
+            }
+
+            tracing::debug!("run pjdfstest {}", name);
+            let output = Command::new("/bin/sh")
+                .arg(&script)
+                .current_dir(mountpoint)
+                .stdin(Stdio::null())
+                .output()?;
+            let outcome = tap::parse(
+                &String::from_utf8_lossy(&output.stdout),
+                output.status.success(),
+            );
+            results.push(TestResult::new(Suite::Pjdfstest, name, outcome));
+        }
+
+        Ok(results)
+    }
+}
+
+fn collect_scripts(dir: &Path, scripts: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_scripts(&path, scripts)?;
+        } else if path.extension() == Some(OsStr::new("t")) {
+            scripts.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn run_xfstests(xfstests: &Xfstests, mountpoint: &Path) -> io::Result<Vec<TestResult>> {
+    tracing::debug!("run xfstests -g {}", xfstests.group);
+    let output = Command::new("./check")
+        .arg("-g")
+        .arg(&xfstests.group)
+        .current_dir(&xfstests.dir)
+        .env("FSTYP", "fuse")
+        .env("TEST_DIR", mountpoint)
+        .envs(xfstests.envs.iter().map(|(k, v)| (k, v)))
+        .stdin(Stdio::null())
+        .output()?;
+
+    let results = xfstests::parse(&String::from_utf8_lossy(&output.stdout));
+    if results.is_empty() && !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "xfstests failed without running any test: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+    Ok(results)
+}
+
+fn is_mounted(mountpoint: &Path) -> io::Result<bool> {
+    let parent = mountpoint.parent().unwrap_or(mountpoint);
+    Ok(fs::metadata(mountpoint)?.dev() != fs::metadata(parent)?.dev())
+}
+
+fn wait_mounted(mountpoint: &Path, child: &mut Child) -> io::Result<()> {
+    let started = Instant::now();
+    loop {
+        if is_mounted(mountpoint)? {
+            return Ok(());
+        }
+        if let Some(status) = child.try_wait()? {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("the filesystem exited before mounting: {}", status),
+            ));
+        }
+        if started.elapsed() > MOUNT_TIMEOUT {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the filesystem was not mounted in time",
+            ));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn wait_exited(child: &mut Child) -> io::Result<()> {
+    let started = Instant::now();
+    while child.try_wait()?.is_none() {
+        if started.elapsed() > MOUNT_TIMEOUT {
+            tracing::warn!("the filesystem did not exit after unmounting");
+            child.kill()?;
+            child.wait()?;
+            break;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+fn unmount(mountpoint: &Path) -> io::Result<()> {
+    if unsafe { libc::geteuid() } == 0 {
+        let path = std::ffi::CString::new(mountpoint.as_os_str().as_bytes())?;
+        let res = unsafe { libc::umount2(path.as_ptr(), libc::MNT_DETACH) };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        return Ok(());
+    }
+
+    let status = Command::new("fusermount")
+        .arg("-u")
+        .arg("-z")
+        .arg(mountpoint)
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("fusermount failed: {}", status),
+        ));
+    }
+    Ok(())
+}
+
+/// A temporary mountpoint removed on drop.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> io::Result<Self> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "polyfuse-conformance.{}.{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst),
+        ));
+        fs::create_dir(&path)?;
+        Ok(Self(path))
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir(&self.0);
+    }
+}
@@ -0,0 +1,88 @@
+use polyfuse_conformance::Harness;
+use std::{env, ffi::OsString, process::Command};
+
+fn show_help() {
+    eprintln!(
+        "\
+polyfuse-conformance
+Run pjdfstest/xfstests against a FUSE filesystem
+
+Usage:
+    polyfuse-conformance [OPTIONS] -- <COMMAND> [ARGS]...
+
+The filesystem is mounted by running COMMAND with the path to
+a temporary mountpoint appended to ARGS.
+
+Options:
+    --pjdfstest <DIR>   Run pjdfstest built in DIR
+    --xfstests <DIR>    Run xfstests built in DIR
+    --group <GROUP>     The group of xfstests to run [default: fuse]
+    --filter <PATTERN>  Run only the pjdfstest scripts matching PATTERN
+    -h, --help          Show this message
+"
+    );
+}
+
+fn main() {
+    let mut harness = Harness::new();
+    let mut xfstests = None;
+    let mut group = String::from("fuse");
+    let mut command: Vec<OsString> = vec![];
+
+    let mut args = env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next().unwrap_or_else(|| {
+                eprintln!("error: missing value for {}", name);
+                std::process::exit(2);
+            })
+        };
+        match arg.to_str() {
+            Some("-h") | Some("--help") => {
+                show_help();
+                return;
+            }
+            Some("--pjdfstest") => {
+                harness.pjdfstest(value("--pjdfstest"));
+            }
+            Some("--xfstests") => xfstests = Some(value("--xfstests")),
+            Some("--group") => group = value("--group").to_string_lossy().into_owned(),
+            Some("--filter") => {
+                harness.filter(&value("--filter").to_string_lossy());
+            }
+            Some("--") => {
+                command.extend(args);
+                break;
+            }
+            _ => {
+                eprintln!("error: unexpected argument: {:?}", arg);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    if let Some(dir) = xfstests {
+        harness.xfstests(dir, &group);
+    }
+
+    if command.is_empty() {
+        show_help();
+        std::process::exit(2);
+    }
+    let mut command_iter = command.into_iter();
+    let mut command = Command::new(command_iter.next().unwrap());
+    command.args(command_iter);
+
+    match harness.run_command(&mut command) {
+        Ok(report) => {
+            println!("{}", report);
+            if !report.is_success() {
+                std::process::exit(1);
+            }
+        }
+        Err(err) => {
+            eprintln!("error: {}", err);
+            std::process::exit(2);
+        }
+    }
+}
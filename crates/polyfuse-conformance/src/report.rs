@@ -0,0 +1,119 @@
+use std::fmt;
+
+/// The test suite which a test belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Suite {
+    Pjdfstest,
+    Xfstests,
+}
+
+impl fmt::Display for Suite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Suite::Pjdfstest => f.write_str("pjdfstest"),
+            Suite::Xfstests => f.write_str("xfstests"),
+        }
+    }
+}
+
+/// The outcome of a test.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// All checks in the test passed.
+    Passed,
+    /// Some checks failed, with the diagnostics.
+    Failed(Vec<String>),
+    /// The test was not run, with the reason.
+    Skipped(String),
+}
+
+/// The result of a test.
+#[derive(Debug, Clone)]
+pub struct TestResult {
+    suite: Suite,
+    name: String,
+    outcome: Outcome,
+}
+
+impl TestResult {
+    pub(crate) fn new(suite: Suite, name: impl Into<String>, outcome: Outcome) -> Self {
+        Self {
+            suite,
+            name: name.into(),
+            outcome,
+        }
+    }
+
+    /// Return the suite that the test belongs to.
+    pub fn suite(&self) -> Suite {
+        self.suite
+    }
+
+    /// Return the name of the test, e.g. `chmod/00.t` or `generic/001`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the outcome of the test.
+    pub fn outcome(&self) -> &Outcome {
+        &self.outcome
+    }
+
+    /// Return whether the test failed.
+    pub fn is_failed(&self) -> bool {
+        matches!(self.outcome, Outcome::Failed(..))
+    }
+}
+
+/// The results of a run of the suites.
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    results: Vec<TestResult>,
+}
+
+impl Report {
+    pub(crate) fn extend(&mut self, results: impl IntoIterator<Item = TestResult>) {
+        self.results.extend(results);
+    }
+
+    /// Return the results of all tests in the order they were run.
+    pub fn results(&self) -> &[TestResult] {
+        &self.results[..]
+    }
+
+    /// Return an iterator over the failed tests.
+    pub fn failures(&self) -> impl Iterator<Item = &TestResult> + '_ {
+        self.results.iter().filter(|result| result.is_failed())
+    }
+
+    /// Return whether no test failed.
+    pub fn is_success(&self) -> bool {
+        self.failures().next().is_none()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut passed = 0;
+        let mut failed = 0;
+        let mut skipped = 0;
+        for result in &self.results {
+            match result.outcome {
+                Outcome::Passed => passed += 1,
+                Outcome::Failed(ref diagnostics) => {
+                    failed += 1;
+                    writeln!(f, "FAILED {}: {}", result.suite, result.name)?;
+                    for line in diagnostics {
+                        writeln!(f, "    {}", line)?;
+                    }
+                }
+                Outcome::Skipped(..) => skipped += 1,
+            }
+        }
+        write!(
+            f,
+            "{} passed; {} failed; {} skipped",
+            passed, failed, skipped
+        )
+    }
+}
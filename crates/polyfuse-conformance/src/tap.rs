@@ -0,0 +1,105 @@
+//! Parser of the Test Anything Protocol output emitted by pjdfstest.
+
+use crate::report::Outcome;
+
+/// Summarize the TAP output of a test script.
+///
+/// `success` is whether the script exited successfully.
+pub(crate) fn parse(output: &str, success: bool) -> Outcome {
+    let mut planned = None;
+    let mut ran = 0;
+    let mut failures = vec![];
+
+    for line in output.lines() {
+        let line = line.trim_end();
+        if let Some(plan) = line.strip_prefix("1..") {
+            let mut plan = plan.splitn(2, '#');
+            let count = plan.next().unwrap_or("").trim();
+            if count == "0" {
+                let reason = plan.next().unwrap_or("").trim();
+                return Outcome::Skipped(reason.trim_start_matches("SKIP").trim().to_owned());
+            }
+            planned = count.parse::<usize>().ok();
+        } else if line.starts_with("not ok") {
+            ran += 1;
+            if !is_todo(line) {
+                failures.push(line.to_owned());
+            }
+        } else if line.starts_with("ok") {
+            ran += 1;
+        }
+    }
+
+    match planned {
+        Some(planned) if planned != ran => {
+            failures.push(format!("planned {} tests but ran {}", planned, ran));
+        }
+        None => failures.push("missing test plan".into()),
+        _ => (),
+    }
+    if failures.is_empty() && !success {
+        failures.push("the test script exited with an error".into());
+    }
+
+    if failures.is_empty() {
+        Outcome::Passed
+    } else {
+        Outcome::Failed(failures)
+    }
+}
+
+fn is_todo(line: &str) -> bool {
+    match line.find('#') {
+        Some(pos) => line[pos + 1..]
+            .trim_start()
+            .to_uppercase()
+            .starts_with("TODO"),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passed() {
+        let output = "1..3\nok 1\nok 2\nok 3 # SKIP no symlinks\n";
+        assert_eq!(parse(output, true), Outcome::Passed);
+    }
+
+    #[test]
+    fn failed() {
+        let output = "1..3\nok 1\nnot ok 2 - tried 'mkdir foo 0755', expected 0, got EIO\nok 3\n";
+        assert_eq!(
+            parse(output, false),
+            Outcome::Failed(vec![
+                "not ok 2 - tried 'mkdir foo 0755', expected 0, got EIO".into()
+            ])
+        );
+    }
+
+    #[test]
+    fn todo_is_not_failure() {
+        let output = "1..2\nok 1\nnot ok 2 # TODO broken on Linux\n";
+        assert_eq!(parse(output, true), Outcome::Passed);
+    }
+
+    #[test]
+    fn aborted() {
+        let output = "1..4\nok 1\nok 2\n";
+        assert_eq!(
+            parse(output, false),
+            Outcome::Failed(vec!["planned 4 tests but ran 2".into()])
+        );
+    }
+
+    #[test]
+    fn skipped() {
+        let output = "1..0 # SKIP not supported on this filesystem\n";
+        assert_eq!(
+            parse(output, true),
+            Outcome::Skipped("not supported on this filesystem".into())
+        );
+    }
+}
@@ -0,0 +1,118 @@
+//! Parser of the output of the xfstests `check` script.
+
+use crate::report::{Outcome, Suite, TestResult};
+use std::collections::HashMap;
+
+/// Collect the results from the output of `check`.
+///
+/// The outcomes are taken from the summary lines (`Ran:`, `Not run:` and
+/// `Failures:`) and the messages from the per-test progress lines.
+pub(crate) fn parse(output: &str) -> Vec<TestResult> {
+    let mut ran = vec![];
+    let mut not_run = vec![];
+    let mut failures = vec![];
+    let mut messages: HashMap<&str, Vec<String>> = HashMap::new();
+
+    for line in output.lines() {
+        let line = line.trim_end();
+        if let Some(names) = line.strip_prefix("Ran:") {
+            ran.extend(names.split_whitespace());
+        } else if let Some(names) = line.strip_prefix("Not run:") {
+            not_run.extend(names.split_whitespace());
+        } else if let Some(names) = line.strip_prefix("Failures:") {
+            failures.extend(names.split_whitespace());
+        } else {
+            let mut tokens = line.splitn(2, char::is_whitespace);
+            let name = tokens.next().unwrap_or("");
+            if is_test_name(name) {
+                let message = tokens.next().unwrap_or("").trim();
+                if !message.is_empty() {
+                    messages.entry(name).or_default().push(message.to_owned());
+                }
+            }
+        }
+    }
+
+    let mut take_messages = |name: &str| messages.remove(name).unwrap_or_default();
+
+    let mut results = vec![];
+    for name in ran {
+        let outcome = if failures.contains(&name) {
+            Outcome::Failed(take_messages(name))
+        } else {
+            Outcome::Passed
+        };
+        results.push(TestResult::new(Suite::Xfstests, name, outcome));
+    }
+    for name in not_run {
+        let reason = take_messages(name)
+            .into_iter()
+            .map(|msg| msg.trim_start_matches("[not run]").trim().to_owned())
+            .collect::<Vec<_>>()
+            .join("; ");
+        results.push(TestResult::new(
+            Suite::Xfstests,
+            name,
+            Outcome::Skipped(reason),
+        ));
+    }
+    results
+}
+
+/// Test names look like `generic/001`.
+fn is_test_name(s: &str) -> bool {
+    let mut parts = s.splitn(2, '/');
+    match (parts.next(), parts.next()) {
+        (Some(group), Some(num)) => {
+            !group.is_empty()
+                && group.bytes().all(|b| b.is_ascii_alphanumeric())
+                && !num.is_empty()
+                && num.bytes().all(|b| b.is_ascii_digit())
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_check_output() {
+        let output = "\
+FSTYP         -- fuse
+PLATFORM      -- Linux/x86_64
+
+generic/001 3s ...  3s
+generic/002 - output mismatch (see results/generic/002.out.bad)
+generic/003       [not run] this test requires a valid $SCRATCH_DEV
+generic/004 1s ...  1s
+Ran: generic/001 generic/002 generic/004
+Not run: generic/003
+Failures: generic/002
+Failed 1 of 3 tests
+";
+        let results = parse(output);
+        let summary: Vec<_> = results
+            .iter()
+            .map(|r| (r.name(), r.outcome().clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("generic/001", Outcome::Passed),
+                (
+                    "generic/002",
+                    Outcome::Failed(vec![
+                        "- output mismatch (see results/generic/002.out.bad)".into()
+                    ])
+                ),
+                ("generic/004", Outcome::Passed),
+                (
+                    "generic/003",
+                    Outcome::Skipped("this test requires a valid $SCRATCH_DEV".into())
+                ),
+            ]
+        );
+    }
+}
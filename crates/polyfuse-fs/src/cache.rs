@@ -0,0 +1,663 @@
+//! Daemon-side caching kept coherent with the kernel.
+//!
+//! [`CacheLayer`] wraps a service and caches the replies of `Lookup`,
+//! `Getattr` and `Readdir` for a fixed period. The cached values are dropped
+//! when the filesystem is modified through the kernel, and can be dropped
+//! explicitly through [`Cache::invalidate_inode`] and
+//! [`Cache::invalidate_entry`], which also notify the invalidation to the
+//! kernel so that both caches stay consistent.
+//!
+//! ```no_run
+//! use polyfuse::{KernelConfig, Session};
+//! use polyfuse_fs::{
+//!     cache::CacheLayer,
+//!     memfs::MemFs,
+//!     service::{FilesystemService, Layer},
+//! };
+//! use std::time::Duration;
+//!
+//! # async fn run() -> std::io::Result<()> {
+//! let session = Session::mount("/mnt".into(), KernelConfig::default())?;
+//! let layer = CacheLayer::with_notifier(Duration::from_secs(1), session.notifier());
+//! let service = layer.layer(FilesystemService::new(MemFs::new()));
+//! while let Some(req) = session.next_request()? {
+//!     polyfuse_fs::service::serve(&service, &req).await?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    service::{Layer, Reply, Service},
+    Context,
+};
+use polyfuse::{
+    op::{self, ReaddirMode},
+    reply::{AttrOut, EntryOut, ReaddirOut},
+    Data, Notifier, Operation,
+};
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fmt,
+    hash::Hash,
+    io,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+struct Cached<T> {
+    value: T,
+    expires: Instant,
+}
+
+impl<T: Clone> Cached<T> {
+    fn get(&self, now: Instant) -> Option<T> {
+        if now < self.expires {
+            Some(self.value.clone())
+        } else {
+            None
+        }
+    }
+}
+
+/// The key of a directory page: the directory, the handle, the offset and
+/// the size of the page.
+type PageKey = (u64, u64, u64, u32);
+
+#[derive(Default)]
+struct CacheState {
+    // Bumped on every invalidation, so that the replies fetched before the
+    // invalidation are not cached after it.
+    generation: u64,
+    attrs: HashMap<u64, Cached<AttrOut>>,
+    entries: HashMap<(u64, OsString), Cached<EntryOut>>,
+    pages: HashMap<PageKey, Cached<ReaddirOut>>,
+    // The number of lookups replied from the cache, which the inner
+    // service does not know about.
+    lookups: HashMap<u64, u64>,
+}
+
+impl CacheState {
+    fn forget_inode(&mut self, ino: u64) {
+        self.generation += 1;
+        self.attrs.remove(&ino);
+        // The entries embed the attributes of the inodes they point to.
+        self.entries
+            .retain(|_, cached| cached.value.get_ino() != ino);
+        self.pages.retain(|&(dir, _, _, _), _| dir != ino);
+    }
+
+    /// Drop the entry and the attributes of the inode it points to, and
+    /// return whether the entry was known.
+    fn forget_entry(&mut self, parent: u64, name: &OsStr) -> bool {
+        let cached = self.entries.remove(&(parent, name.to_owned()));
+        if let Some(ref cached) = cached {
+            self.attrs.remove(&cached.value.get_ino());
+        }
+        self.forget_inode(parent);
+        cached.is_some()
+    }
+
+    /// Drop the entry removed or replaced by a modification.
+    ///
+    /// If the entry is not known, the attributes of all inodes are dropped,
+    /// since the link count of the removed inode is changed.
+    fn forget_target(&mut self, parent: u64, name: &OsStr) {
+        if !self.forget_entry(parent, name) {
+            self.attrs.clear();
+        }
+    }
+
+    fn insert<K, V>(map: &mut HashMap<K, Cached<V>>, key: K, value: V, ttl: Duration)
+    where
+        K: Hash + Eq,
+    {
+        let expires = Instant::now() + ttl;
+        map.insert(key, Cached { value, expires });
+    }
+
+    /// Subtract the lookup counts replied from the cache, and return the
+    /// rest to be passed to the inner service.
+    fn forget(&mut self, forgets: &[op::Forget]) -> Vec<op::Forget> {
+        forgets
+            .iter()
+            .filter_map(|forget| {
+                // The cached entries pointing to the forgotten inode are
+                // dropped, so that the cache never revives an inode that the
+                // inner service may have released.
+                self.entries
+                    .retain(|_, cached| cached.value.get_ino() != forget.ino());
+                let mut nlookup = forget.nlookup();
+                if let Some(served) = self.lookups.get_mut(&forget.ino()) {
+                    let n = std::cmp::min(*served, nlookup);
+                    *served -= n;
+                    nlookup -= n;
+                    if *served == 0 {
+                        self.lookups.remove(&forget.ino());
+                    }
+                }
+                if nlookup > 0 {
+                    Some(op::Forget::new(forget.ino(), nlookup))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// The cache shared by [`CacheLayer`] and its services.
+pub struct Cache {
+    ttl: Duration,
+    notifier: Option<Notifier>,
+    state: Mutex<CacheState>,
+}
+
+impl fmt::Debug for Cache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cache").field("ttl", &self.ttl).finish()
+    }
+}
+
+impl Cache {
+    fn state(&self) -> MutexGuard<'_, CacheState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Drop the cached attributes, directory pages and entries of the inode,
+    /// and notify the kernel to invalidate its caches about the inode.
+    pub fn invalidate_inode(&self, ino: u64) -> io::Result<()> {
+        self.state().forget_inode(ino);
+        match self.notifier {
+            Some(ref notifier) => ignore_enoent(notifier.inval_inode(ino, 0, 0)),
+            None => Ok(()),
+        }
+    }
+
+    /// Drop the cached entry in the directory, and notify the kernel
+    /// to invalidate the corresponding dentry.
+    pub fn invalidate_entry(&self, parent: u64, name: &OsStr) -> io::Result<()> {
+        self.state().forget_entry(parent, name);
+        match self.notifier {
+            Some(ref notifier) => ignore_enoent(notifier.inval_entry(parent, name)),
+            None => Ok(()),
+        }
+    }
+
+    /// Drop all of the cached values.
+    ///
+    /// Unlike the other invalidation methods, the kernel is not notified.
+    pub fn clear(&self) {
+        let mut state = self.state();
+        state.generation += 1;
+        state.attrs.clear();
+        state.entries.clear();
+        state.pages.clear();
+    }
+}
+
+// The kernel returns ENOENT if the target is not in its cache.
+fn ignore_enoent(res: io::Result<()>) -> io::Result<()> {
+    match res {
+        Err(ref err) if err.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+        res => res,
+    }
+}
+
+/// A layer that caches the attributes, entries and directory pages.
+///
+/// The cached values affected by a modification are dropped after the
+/// wrapped service has processed it, whether it has succeeded or not. The
+/// directory pages are cached per directory handle, and dropped when the
+/// handle is released. The entries in `READDIRPLUS` replies are not cached.
+#[derive(Debug, Clone)]
+pub struct CacheLayer {
+    cache: Arc<Cache>,
+}
+
+impl CacheLayer {
+    /// Create a layer caching the replies for the specified period.
+    pub fn new(ttl: Duration) -> Self {
+        Self::with_cache(ttl, None)
+    }
+
+    /// Create a layer caching the replies for the specified period, which
+    /// forwards the explicit invalidations to the kernel with the notifier.
+    pub fn with_notifier(ttl: Duration, notifier: Notifier) -> Self {
+        Self::with_cache(ttl, Some(notifier))
+    }
+
+    fn with_cache(ttl: Duration, notifier: Option<Notifier>) -> Self {
+        Self {
+            cache: Arc::new(Cache {
+                ttl,
+                notifier,
+                state: Mutex::default(),
+            }),
+        }
+    }
+
+    /// Return a reference to the cache shared by the services.
+    #[inline]
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+}
+
+impl<S> Layer<S> for CacheLayer {
+    type Service = CacheService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CacheService {
+            inner,
+            cache: self.cache.clone(),
+        }
+    }
+}
+
+/// The service produced by [`CacheLayer`].
+#[derive(Debug)]
+pub struct CacheService<S> {
+    inner: S,
+    cache: Arc<Cache>,
+}
+
+impl<S> CacheService<S> {
+    /// Return a reference to the cache.
+    #[inline]
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+}
+
+/// What the layer does around an operation.
+enum Action {
+    None,
+    Lookup(u64, OsString),
+    Getattr(u64),
+    Readdir(PageKey),
+    Releasedir(u64, u64),
+    Inode(u64),
+    Create(u64, OsString),
+    Remove(u64, OsString),
+    Rename(u64, OsString, u64, OsString),
+    Link(u64, u64, OsString),
+}
+
+impl Action {
+    fn new<T>(op: &Operation<'_, T>) -> Self {
+        match op {
+            Operation::Lookup(op) => Action::Lookup(op.parent(), op.name().to_owned()),
+            Operation::Getattr(op) => Action::Getattr(op.ino()),
+            Operation::Readdir(op) if op.mode() == ReaddirMode::Normal => {
+                Action::Readdir((op.ino(), op.fh(), op.offset(), op.size()))
+            }
+            Operation::Releasedir(op) => Action::Releasedir(op.ino(), op.fh()),
+
+            Operation::Setattr(op) => Action::Inode(op.ino()),
+            Operation::Write(op, _) => Action::Inode(op.ino()),
+            Operation::Open(op) if op.flags() as i32 & libc::O_TRUNC != 0 => {
+                Action::Inode(op.ino())
+            }
+            Operation::Setxattr(op) => Action::Inode(op.ino()),
+            Operation::Removexattr(op) => Action::Inode(op.ino()),
+            Operation::Fallocate(op) => Action::Inode(op.ino()),
+            Operation::CopyFileRange(op) => Action::Inode(op.ino_out()),
+
+            Operation::Symlink(op) => Action::Create(op.parent(), op.name().to_owned()),
+            Operation::Mknod(op) => Action::Create(op.parent(), op.name().to_owned()),
+            Operation::Mkdir(op) => Action::Create(op.parent(), op.name().to_owned()),
+            Operation::Create(op) => Action::Create(op.parent(), op.name().to_owned()),
+            Operation::Unlink(op) => Action::Remove(op.parent(), op.name().to_owned()),
+            Operation::Rmdir(op) => Action::Remove(op.parent(), op.name().to_owned()),
+            Operation::Rename(op) => Action::Rename(
+                op.parent(),
+                op.name().to_owned(),
+                op.newparent(),
+                op.newname().to_owned(),
+            ),
+            Operation::Link(op) => Action::Link(op.ino(), op.newparent(), op.newname().to_owned()),
+            _ => Action::None,
+        }
+    }
+}
+
+#[crate::async_trait]
+impl<S> Service for CacheService<S>
+where
+    S: Service,
+{
+    async fn call(&self, cx: &Context<'_>, op: Operation<'_, Data<'_>>) -> io::Result<Reply> {
+        if let Operation::Forget(ref forgets) = op {
+            let forgets = self.cache.state().forget(forgets);
+            if forgets.is_empty() {
+                return Ok(Reply::None);
+            }
+            let op = Operation::Forget(op::Forgets::new(&forgets));
+            return self.inner.call(cx, op).await;
+        }
+
+        let action = Action::new(&op);
+        let generation = {
+            let mut state = self.cache.state();
+            let now = Instant::now();
+            match action {
+                Action::Lookup(parent, ref name) => {
+                    let key = (parent, name.clone());
+                    let cached = state.entries.get(&key).and_then(|c| c.get(now));
+                    if let Some(out) = cached {
+                        *state.lookups.entry(out.get_ino()).or_insert(0) += 1;
+                        return Ok(Reply::Entry(out));
+                    }
+                }
+                Action::Getattr(ino) => {
+                    if let Some(out) = state.attrs.get(&ino).and_then(|c| c.get(now)) {
+                        return Ok(Reply::Attr(out));
+                    }
+                }
+                Action::Readdir(key) => {
+                    if let Some(out) = state.pages.get(&key).and_then(|c| c.get(now)) {
+                        return Ok(Reply::Readdir(out));
+                    }
+                }
+                _ => (),
+            }
+            state.generation
+        };
+
+        let res = self.inner.call(cx, op).await;
+
+        let ttl = self.cache.ttl;
+        let mut state = self.cache.state();
+        // The replies fetched before an invalidation may be stale.
+        let fresh = state.generation == generation;
+        match (action, &res) {
+            (Action::Lookup(parent, name), Ok(Reply::Entry(out)))
+                if fresh && out.get_ino() != 0 =>
+            {
+                CacheState::insert(&mut state.entries, (parent, name), out.clone(), ttl);
+            }
+            (Action::Getattr(ino), Ok(Reply::Attr(out))) if fresh => {
+                CacheState::insert(&mut state.attrs, ino, out.clone(), ttl);
+            }
+            (Action::Readdir(key), Ok(Reply::Readdir(out))) if fresh => {
+                CacheState::insert(&mut state.pages, key, out.clone(), ttl);
+            }
+            (Action::Releasedir(ino, fh), _) => {
+                state
+                    .pages
+                    .retain(|&(dir, dh, _, _), _| dir != ino || dh != fh);
+            }
+
+            (Action::Inode(ino), _) => state.forget_inode(ino),
+            (Action::Create(parent, name), res) => {
+                state.forget_entry(parent, &name);
+                if let Ok(Reply::Entry(out)) | Ok(Reply::Create(out, _)) = res {
+                    // The new entry is replied as a lookup.
+                    CacheState::insert(&mut state.entries, (parent, name), out.clone(), ttl);
+                }
+            }
+            (Action::Remove(parent, name), _) => state.forget_target(parent, &name),
+            (Action::Rename(parent, name, newparent, newname), _) => {
+                state.forget_target(parent, &name);
+                state.forget_target(newparent, &newname);
+            }
+            (Action::Link(ino, newparent, newname), res) => {
+                state.forget_inode(ino);
+                state.forget_entry(newparent, &newname);
+                if let Ok(Reply::Entry(out)) = res {
+                    let key = (newparent, newname);
+                    CacheState::insert(&mut state.entries, key, out.clone(), ttl);
+                }
+            }
+            _ => (),
+        }
+        drop(state);
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{service::FilesystemService, testing::Harness, Filesystem};
+    use polyfuse_kernel::{
+        fuse_attr_out, fuse_entry_out, fuse_opcode, fuse_read_in, fuse_release_in, fuse_setattr_in,
+        FATTR_SIZE,
+    };
+    use polyfuse_test::RequestBuilder;
+
+    /// The root directory with the file `a` (inode 2), recording the calls.
+    #[derive(Default)]
+    struct Tree {
+        calls: Mutex<Vec<&'static str>>,
+        forgets: Mutex<Vec<(u64, u64)>>,
+        size: Mutex<u64>,
+        nlink: Mutex<u32>,
+        // Modifies the file during the next `Getattr`, as if it were
+        // modified concurrently.
+        modify: Mutex<Option<CacheLayer>>,
+    }
+
+    impl Tree {
+        fn calls(&self, name: &str) -> usize {
+            self.calls
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|&&n| n == name)
+                .count()
+        }
+
+        fn attr(&self, ino: u64) -> AttrOut {
+            let mut out = AttrOut::default();
+            out.attr().ino(ino);
+            out.attr().size(*self.size.lock().unwrap());
+            out.attr().nlink(*self.nlink.lock().unwrap());
+            out
+        }
+    }
+
+    #[crate::async_trait]
+    impl Filesystem for Arc<Tree> {
+        async fn lookup(&self, _: &Context<'_>, _: op::Lookup<'_>) -> io::Result<EntryOut> {
+            self.calls.lock().unwrap().push("lookup");
+            let mut out = EntryOut::default();
+            out.ino(2);
+            out.attr().ino(2);
+            out.attr().size(*self.size.lock().unwrap());
+            Ok(out)
+        }
+
+        async fn forget(&self, _: &Context<'_>, forgets: &[op::Forget]) {
+            let mut recorded = self.forgets.lock().unwrap();
+            recorded.extend(forgets.iter().map(|f| (f.ino(), f.nlookup())));
+        }
+
+        async fn getattr(&self, _: &Context<'_>, op: op::Getattr<'_>) -> io::Result<AttrOut> {
+            self.calls.lock().unwrap().push("getattr");
+            let out = self.attr(op.ino());
+            if let Some(layer) = self.modify.lock().unwrap().take() {
+                *self.size.lock().unwrap() += 1;
+                layer.cache().invalidate_inode(op.ino())?;
+            }
+            Ok(out)
+        }
+
+        async fn setattr(&self, _: &Context<'_>, op: op::Setattr<'_>) -> io::Result<AttrOut> {
+            if let Some(size) = op.size() {
+                *self.size.lock().unwrap() = size;
+            }
+            Ok(self.attr(op.ino()))
+        }
+
+        async fn unlink(&self, _: &Context<'_>, _: op::Unlink<'_>) -> io::Result<()> {
+            *self.nlink.lock().unwrap() -= 1;
+            Ok(())
+        }
+
+        async fn readdir(&self, _: &Context<'_>, op: op::Readdir<'_>) -> io::Result<ReaddirOut> {
+            self.calls.lock().unwrap().push("readdir");
+            Ok(ReaddirOut::new(op.size() as usize))
+        }
+
+        async fn releasedir(&self, _: &Context<'_>, _: op::Releasedir<'_>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn tree() -> (
+        Arc<Tree>,
+        CacheLayer,
+        CacheService<FilesystemService<Arc<Tree>>>,
+    ) {
+        let tree = Arc::new(Tree::default());
+        *tree.nlink.lock().unwrap() = 1;
+        let layer = CacheLayer::new(Duration::from_secs(3600));
+        let service = layer.layer(FilesystemService::new(tree.clone()));
+        (tree, layer, service)
+    }
+
+    fn getattr<S: Service>(harness: &Harness, service: &S, ino: u64) -> fuse_attr_out {
+        let reply = harness
+            .call(service, &RequestBuilder::getattr(ino))
+            .unwrap();
+        assert_eq!(reply.error(), 0);
+        reply.arg().unwrap()
+    }
+
+    fn truncate(ino: u64, size: u64) -> RequestBuilder {
+        let mut req = RequestBuilder::new(fuse_opcode::FUSE_SETATTR);
+        req.nodeid(ino).arg(&fuse_setattr_in {
+            valid: FATTR_SIZE,
+            size,
+            ..Default::default()
+        });
+        req
+    }
+
+    fn readdir(ino: u64, fh: u64) -> RequestBuilder {
+        let mut req = RequestBuilder::new(fuse_opcode::FUSE_READDIR);
+        req.nodeid(ino).arg(&fuse_read_in {
+            fh,
+            size: 4096,
+            ..Default::default()
+        });
+        req
+    }
+
+    #[test]
+    fn cached_attrs() {
+        let harness = Harness::new();
+        let (tree, _, service) = tree();
+
+        assert_eq!(getattr(&harness, &service, 2).attr.size, 0);
+        assert_eq!(getattr(&harness, &service, 2).attr.size, 0);
+        assert_eq!(tree.calls("getattr"), 1);
+
+        // The attributes are dropped after the modification.
+        let reply = harness.call(&service, &truncate(2, 10));
+        assert_eq!(reply.unwrap().error(), 0);
+        assert_eq!(getattr(&harness, &service, 2).attr.size, 10);
+        assert_eq!(tree.calls("getattr"), 2);
+    }
+
+    #[test]
+    fn lookup_after_setattr() {
+        let harness = Harness::new();
+        let (tree, _, service) = tree();
+        let lookup = || {
+            let reply = harness.call(&service, &RequestBuilder::lookup(1, "a"));
+            reply.unwrap().arg::<fuse_entry_out>().unwrap().attr.size
+        };
+
+        assert_eq!(lookup(), 0);
+        assert_eq!(lookup(), 0);
+        assert_eq!(tree.calls("lookup"), 1);
+
+        // The entries embedding the modified attributes are dropped.
+        let reply = harness.call(&service, &truncate(2, 10));
+        assert_eq!(reply.unwrap().error(), 0);
+        assert_eq!(lookup(), 10);
+        assert_eq!(tree.calls("lookup"), 2);
+    }
+
+    #[test]
+    fn stale_attrs() {
+        let harness = Harness::new();
+        let (tree, layer, service) = tree();
+
+        // The reply fetched before the invalidation is not cached.
+        *tree.modify.lock().unwrap() = Some(layer);
+        assert_eq!(getattr(&harness, &service, 2).attr.size, 0);
+        assert_eq!(getattr(&harness, &service, 2).attr.size, 1);
+        assert_eq!(getattr(&harness, &service, 2).attr.size, 1);
+        assert_eq!(tree.calls("getattr"), 2);
+    }
+
+    #[test]
+    fn cached_lookups() {
+        let harness = Harness::new();
+        let (tree, _, service) = tree();
+
+        for _ in 0..3 {
+            let reply = harness.call(&service, &RequestBuilder::lookup(1, "a"));
+            assert_eq!(reply.unwrap().arg::<fuse_entry_out>().unwrap().nodeid, 2);
+        }
+        assert_eq!(tree.calls("lookup"), 1);
+
+        // The lookups replied from the cache are not passed to the filesystem.
+        assert!(harness
+            .call(&service, &RequestBuilder::forget(2, 3))
+            .is_none());
+        assert_eq!(*tree.forgets.lock().unwrap(), [(2, 1)]);
+
+        // The forgotten inode is looked up again.
+        harness.call(&service, &RequestBuilder::lookup(1, "a"));
+        assert_eq!(tree.calls("lookup"), 2);
+    }
+
+    #[test]
+    fn unlink_target() {
+        let harness = Harness::new();
+        let (tree, _, service) = tree();
+
+        harness.call(&service, &RequestBuilder::lookup(1, "a"));
+        assert_eq!(getattr(&harness, &service, 2).attr.nlink, 1);
+        let reply = harness.call(&service, &RequestBuilder::unlink(1, "a"));
+        assert_eq!(reply.unwrap().error(), 0);
+        assert_eq!(getattr(&harness, &service, 2).attr.nlink, 0);
+        assert_eq!(tree.calls("getattr"), 2);
+
+        // The attributes of all inodes are dropped if the target is unknown.
+        *tree.nlink.lock().unwrap() = 1;
+        harness.call(&service, &RequestBuilder::unlink(1, "b"));
+        assert_eq!(getattr(&harness, &service, 2).attr.nlink, 0);
+        assert_eq!(tree.calls("getattr"), 3);
+    }
+
+    #[test]
+    fn pages_per_handle() {
+        let harness = Harness::new();
+        let (tree, _, service) = tree();
+
+        harness.call(&service, &readdir(1, 1));
+        harness.call(&service, &readdir(1, 1));
+        assert_eq!(tree.calls("readdir"), 1);
+        harness.call(&service, &readdir(1, 2));
+        assert_eq!(tree.calls("readdir"), 2);
+
+        // The pages are dropped when the handle is released.
+        let mut req = RequestBuilder::new(fuse_opcode::FUSE_RELEASEDIR);
+        req.nodeid(1).arg(&fuse_release_in {
+            fh: 1,
+            ..Default::default()
+        });
+        harness.call(&service, &req);
+        harness.call(&service, &readdir(1, 1));
+        harness.call(&service, &readdir(1, 2));
+        assert_eq!(tree.calls("readdir"), 3);
+    }
+}
@@ -0,0 +1,346 @@
+//! UID/GID mapping of requests and replies.
+//!
+//! [`IdMapLayer`] translates the credentials of requests from the IDs seen by
+//! the callers ("outer" IDs) to the ones used in the filesystem ("inner" IDs),
+//! and the ownership in the replied attributes, including the entries of
+//! `READDIRPLUS` and the `Statx` replies, in the opposite direction.
+//! The mapping is described by ranges in the same manner as
+//! `/proc/<pid>/uid_map` of user namespaces.
+//!
+//! Note that the ownership values requested by `Setattr` are passed to the
+//! filesystem without translation. The filesystems supporting `chown(2)`
+//! should translate them with [`IdMap::to_inner_uid`] and
+//! [`IdMap::to_inner_gid`].
+
+use crate::{
+    service::{Layer, Reply, Service},
+    Context,
+};
+use polyfuse::{
+    reply::{FileAttr, StatxAttr},
+    Data, Operation,
+};
+use std::{io, sync::Arc};
+
+/// The ID that unmapped IDs are translated into, i.e. `nobody`.
+const OVERFLOW_ID: u32 = 65534;
+
+#[derive(Debug, Clone, Copy)]
+struct IdRange {
+    inner: u32,
+    outer: u32,
+    count: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+struct IdRanges(Vec<IdRange>);
+
+impl IdRanges {
+    fn to_inner(&self, outer: u32) -> Option<u32> {
+        if self.0.is_empty() {
+            return Some(outer);
+        }
+        self.0.iter().find_map(|r| {
+            let offset = outer.checked_sub(r.outer)?;
+            if offset < r.count {
+                // The range overflowing the ID space is unmapped there.
+                r.inner.checked_add(offset)
+            } else {
+                None
+            }
+        })
+    }
+
+    fn to_outer(&self, inner: u32) -> Option<u32> {
+        if self.0.is_empty() {
+            return Some(inner);
+        }
+        self.0.iter().find_map(|r| {
+            let offset = inner.checked_sub(r.inner)?;
+            if offset < r.count {
+                r.outer.checked_add(offset)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// The mapping table of user and group IDs.
+///
+/// Without any ranges, the IDs are mapped to themselves.
+#[derive(Debug, Clone)]
+pub struct IdMap {
+    uids: IdRanges,
+    gids: IdRanges,
+    squash_root: Option<(u32, u32)>,
+    overflow_uid: u32,
+    overflow_gid: u32,
+}
+
+impl Default for IdMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IdMap {
+    /// Create an identity mapping.
+    pub fn new() -> Self {
+        Self {
+            uids: IdRanges::default(),
+            gids: IdRanges::default(),
+            squash_root: None,
+            overflow_uid: OVERFLOW_ID,
+            overflow_gid: OVERFLOW_ID,
+        }
+    }
+
+    /// Map `count` user IDs starting at `outer` to the ones starting at `inner`.
+    pub fn map_uid(&mut self, inner: u32, outer: u32, count: u32) -> &mut Self {
+        self.uids.0.push(IdRange {
+            inner,
+            outer,
+            count,
+        });
+        self
+    }
+
+    /// Map `count` group IDs starting at `outer` to the ones starting at `inner`.
+    pub fn map_gid(&mut self, inner: u32, outer: u32, count: u32) -> &mut Self {
+        self.gids.0.push(IdRange {
+            inner,
+            outer,
+            count,
+        });
+        self
+    }
+
+    /// Treat the requests from the root user as the specified user and group.
+    ///
+    /// The squashed IDs are the inner ones, typically the owner of the mount.
+    pub fn squash_root(&mut self, uid: u32, gid: u32) -> &mut Self {
+        self.squash_root = Some((uid, gid));
+        self
+    }
+
+    /// Set the IDs that the unmapped IDs are translated into.
+    ///
+    /// The default value is `65534` (`nobody`).
+    pub fn overflow_id(&mut self, uid: u32, gid: u32) -> &mut Self {
+        self.overflow_uid = uid;
+        self.overflow_gid = gid;
+        self
+    }
+
+    /// Translate the user ID of a caller into the filesystem's one.
+    pub fn to_inner_uid(&self, uid: u32) -> u32 {
+        self.uids.to_inner(uid).unwrap_or(self.overflow_uid)
+    }
+
+    /// Translate the group ID of a caller into the filesystem's one.
+    pub fn to_inner_gid(&self, gid: u32) -> u32 {
+        self.gids.to_inner(gid).unwrap_or(self.overflow_gid)
+    }
+
+    /// Translate the user ID in the filesystem into the one seen by callers.
+    pub fn to_outer_uid(&self, uid: u32) -> u32 {
+        self.uids.to_outer(uid).unwrap_or(self.overflow_uid)
+    }
+
+    /// Translate the group ID in the filesystem into the one seen by callers.
+    pub fn to_outer_gid(&self, gid: u32) -> u32 {
+        self.gids.to_outer(gid).unwrap_or(self.overflow_gid)
+    }
+
+    /// Translate the credentials of a caller, taking root squashing into account.
+    pub fn to_inner_credentials(&self, uid: u32, gid: u32) -> (u32, u32) {
+        match self.squash_root {
+            Some(squashed) if uid == 0 => squashed,
+            _ => (self.to_inner_uid(uid), self.to_inner_gid(gid)),
+        }
+    }
+
+    fn map_attr(&self, attr: &mut FileAttr) {
+        let uid = self.to_outer_uid(attr.get_uid());
+        let gid = self.to_outer_gid(attr.get_gid());
+        attr.uid(uid);
+        attr.gid(gid);
+    }
+
+    fn map_statx(&self, attr: &mut StatxAttr) {
+        // Keep the IDs marked as invalid untouched.
+        let mask = attr.get_mask();
+        if mask & libc::STATX_UID != 0 {
+            let uid = self.to_outer_uid(attr.get_uid());
+            attr.uid(uid);
+        }
+        if mask & libc::STATX_GID != 0 {
+            let gid = self.to_outer_gid(attr.get_gid());
+            attr.gid(gid);
+        }
+    }
+}
+
+/// A layer that translates the user and group IDs according to an [`IdMap`].
+#[derive(Debug, Clone)]
+pub struct IdMapLayer {
+    map: Arc<IdMap>,
+}
+
+impl IdMapLayer {
+    /// Create a layer with the specified mapping.
+    pub fn new(map: IdMap) -> Self {
+        Self { map: Arc::new(map) }
+    }
+}
+
+impl<S> Layer<S> for IdMapLayer {
+    type Service = IdMapService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        IdMapService {
+            inner,
+            map: self.map.clone(),
+        }
+    }
+}
+
+/// The service produced by [`IdMapLayer`].
+#[derive(Debug)]
+pub struct IdMapService<S> {
+    inner: S,
+    map: Arc<IdMap>,
+}
+
+#[crate::async_trait]
+impl<S> Service for IdMapService<S>
+where
+    S: Service,
+{
+    async fn call(&self, cx: &Context<'_>, op: Operation<'_, Data<'_>>) -> io::Result<Reply> {
+        let (uid, gid) = self.map.to_inner_credentials(cx.uid(), cx.gid());
+        let cx = cx.with_credentials(uid, gid);
+
+        let mut reply = self.inner.call(&cx, op).await?;
+        match reply {
+            Reply::Entry(ref mut out) | Reply::Create(ref mut out, _) => {
+                self.map.map_attr(out.attr())
+            }
+            Reply::Attr(ref mut out) => self.map.map_attr(out.attr()),
+            Reply::ReaddirPlus(ref mut out) => {
+                out.for_each_entry(|_, entry| self.map.map_attr(entry.attr()))
+            }
+            Reply::Statx(ref mut out) => self.map.map_statx(out.attr()),
+            _ => (),
+        }
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{service::FilesystemService, testing::Harness, Filesystem};
+    use polyfuse::{
+        op,
+        reply::{ReaddirPlusOut, StatxOut},
+    };
+    use polyfuse_kernel::{
+        fuse_direntplus, fuse_opcode, fuse_read_in, fuse_statx_in, fuse_statx_out,
+    };
+    use polyfuse_test::RequestBuilder;
+
+    #[test]
+    fn identity() {
+        let map = IdMap::new();
+        assert_eq!(map.to_inner_uid(1000), 1000);
+        assert_eq!(map.to_outer_gid(0), 0);
+        assert_eq!(map.to_inner_credentials(0, 0), (0, 0));
+    }
+
+    #[test]
+    fn container_offset() {
+        let mut map = IdMap::new();
+        map.map_uid(0, 100_000, 65536).map_gid(0, 100_000, 65536);
+
+        assert_eq!(map.to_inner_uid(100_000), 0);
+        assert_eq!(map.to_inner_uid(101_000), 1000);
+        assert_eq!(map.to_outer_uid(1000), 101_000);
+        assert_eq!(map.to_inner_uid(1000), OVERFLOW_ID);
+        assert_eq!(map.to_outer_gid(70_000), OVERFLOW_ID);
+    }
+
+    #[test]
+    fn squash_root() {
+        let mut map = IdMap::new();
+        map.squash_root(1000, 100);
+        assert_eq!(map.to_inner_credentials(0, 0), (1000, 100));
+        assert_eq!(map.to_inner_credentials(1001, 101), (1001, 101));
+    }
+
+    #[test]
+    fn overflowing_range() {
+        let mut map = IdMap::new();
+        map.map_uid(u32::MAX - 1, 0, 10)
+            .map_gid(0, u32::MAX - 1, 10);
+        assert_eq!(map.to_inner_uid(1), u32::MAX);
+        assert_eq!(map.to_inner_uid(2), OVERFLOW_ID);
+        assert_eq!(map.to_outer_gid(1), u32::MAX);
+        assert_eq!(map.to_outer_gid(2), OVERFLOW_ID);
+    }
+
+    struct Owned;
+
+    #[crate::async_trait]
+    impl Filesystem for Owned {
+        async fn readdirplus(
+            &self,
+            _: &Context<'_>,
+            op: op::Readdir<'_>,
+        ) -> io::Result<ReaddirPlusOut> {
+            let mut entry = polyfuse::reply::EntryOut::default();
+            entry.ino(2);
+            entry.attr().uid(1000);
+            entry.attr().gid(2000);
+            let mut out = ReaddirPlusOut::new(op.size() as usize);
+            assert!(!out.entry("foo".as_ref(), 0, 1, &entry));
+            Ok(out)
+        }
+
+        async fn statx(&self, _: &Context<'_>, _: op::Statx<'_>) -> io::Result<StatxOut> {
+            let mut out = StatxOut::default();
+            out.attr().uid(1000);
+            Ok(out)
+        }
+    }
+
+    #[test]
+    fn map_replies() {
+        let harness = Harness::new();
+        let mut map = IdMap::new();
+        map.map_uid(1000, 101_000, 1).map_gid(2000, 102_000, 1);
+        let service = IdMapLayer::new(map).layer(FilesystemService::new(Owned));
+
+        let mut req = RequestBuilder::new(fuse_opcode::FUSE_READDIRPLUS);
+        req.nodeid(1).arg(&fuse_read_in {
+            size: 4096,
+            ..Default::default()
+        });
+        let reply = harness.call(&service, &req).unwrap();
+        let plus = reply.arg::<fuse_direntplus>().unwrap();
+        assert_eq!(plus.dirent.ino, 2);
+        assert_eq!(
+            (plus.entry_out.attr.uid, plus.entry_out.attr.gid),
+            (101_000, 102_000)
+        );
+
+        // The group ID is not valid in the reply, and left as is.
+        let mut req = RequestBuilder::new(fuse_opcode::FUSE_STATX);
+        req.nodeid(1).arg(&fuse_statx_in::default());
+        let reply = harness.call(&service, &req).unwrap();
+        let out = reply.arg::<fuse_statx_out>().unwrap();
+        assert_eq!((out.stat.uid, out.stat.gid), (101_000, 0));
+        assert_eq!(out.stat.mask, libc::STATX_UID);
+    }
+}
@@ -0,0 +1,812 @@
+//! Write-ahead journal of the modifications.
+//!
+//! [`JournalLayer`] wraps a service and logs the payload of each `Write`
+//! and `Setattr` request to a [`Journal`] on disk, synchronously, before
+//! passing the request to the wrapped service. If the daemon crashes
+//! before the filesystem has persisted the acknowledged modifications, they
+//! can be applied again on restart with [`Journal::replay`]:
+//!
+//! ```ignore
+//! let journal = Journal::open("/var/lib/myfs/journal")?;
+//! journal.replay(|entry| backend.apply(entry))?;
+//! let service = JournalLayer::new(journal).layer(FilesystemService::new(MyFs::new(backend)));
+//! ```
+//!
+//! The daemon calls [`Journal::checkpoint`] through [`JournalLayer::journal`]
+//! whenever all of the modifications made so far have been persisted, e.g.
+//! after flushing the write-back buffers, to discard the logged entries. The entries may be
+//! applied more than once, so the replay must be idempotent, which is the
+//! case for the positional writes and the attribute changes. The inode
+//! numbers must also be stable across the restarts of the daemon.
+//!
+//! The appends are serialized and each of them waits for `fdatasync(2)`, so
+//! the journal trades the throughput of writes for their durability.
+
+use crate::{
+    service::{Layer, Reply, Service},
+    Context,
+};
+use polyfuse::{
+    op::{self, SetAttrTime},
+    Data, Operation,
+};
+use std::{
+    collections::HashSet,
+    convert::TryInto as _,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, prelude::*, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const KIND_WRITE: u8 = 1;
+const KIND_SETATTR: u8 = 2;
+const KIND_ABORT: u8 = 3;
+
+/// The size of the record header, i.e. the payload length and its CRC-32.
+const RECORD_HEADER_LEN: usize = 8;
+
+/// A modification logged in the journal.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JournalEntry {
+    /// The data written at the offset of the file.
+    Write {
+        /// The inode number of the file.
+        ino: u64,
+        /// The offset of the written data.
+        offset: u64,
+        /// The written data.
+        data: Vec<u8>,
+    },
+
+    /// The attributes changed by `Setattr`.
+    ///
+    /// The timestamps set to the current time are logged with the time at
+    /// which the request has been received.
+    Setattr {
+        /// The inode number of the file.
+        ino: u64,
+        /// The new file mode.
+        mode: Option<u32>,
+        /// The new owner.
+        uid: Option<u32>,
+        /// The new group.
+        gid: Option<u32>,
+        /// The new size of the file.
+        size: Option<u64>,
+        /// The new access time, since the Unix epoch.
+        atime: Option<Duration>,
+        /// The new modification time, since the Unix epoch.
+        mtime: Option<Duration>,
+    },
+}
+
+impl JournalEntry {
+    fn decode(payload: &[u8]) -> Option<Record> {
+        let mut r = Reader(payload);
+        let kind = r.u8()?;
+        let seq = r.u64()?;
+        let entry = match kind {
+            KIND_WRITE => JournalEntry::Write {
+                ino: r.u64()?,
+                offset: r.u64()?,
+                data: r.0.to_vec(),
+            },
+            KIND_SETATTR => {
+                let ino = r.u64()?;
+                let flags = r.u8()?;
+                let mode = r.u32()?;
+                let uid = r.u32()?;
+                let gid = r.u32()?;
+                let size = r.u64()?;
+                let atime = Duration::new(r.u64()?, r.u32()?);
+                let mtime = Duration::new(r.u64()?, r.u32()?);
+                let set = |bit: u8| flags & (1 << bit) != 0;
+                JournalEntry::Setattr {
+                    ino,
+                    mode: if set(0) { Some(mode) } else { None },
+                    uid: if set(1) { Some(uid) } else { None },
+                    gid: if set(2) { Some(gid) } else { None },
+                    size: if set(3) { Some(size) } else { None },
+                    atime: if set(4) { Some(atime) } else { None },
+                    mtime: if set(5) { Some(mtime) } else { None },
+                }
+            }
+            KIND_ABORT => return Some(Record::Abort(seq)),
+            _ => return None,
+        };
+        Some(Record::Entry(seq, entry))
+    }
+}
+
+enum Record {
+    Entry(u64, JournalEntry),
+    Abort(u64),
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes(8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+    }
+}
+
+fn encode_write(seq: u64, ino: u64, offset: u64, data: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(25 + data.len());
+    payload.push(KIND_WRITE);
+    payload.extend_from_slice(&seq.to_le_bytes());
+    payload.extend_from_slice(&ino.to_le_bytes());
+    payload.extend_from_slice(&offset.to_le_bytes());
+    payload.extend_from_slice(data);
+    payload
+}
+
+impl JournalEntry {
+    fn encode(&self, seq: u64) -> Vec<u8> {
+        match *self {
+            JournalEntry::Write {
+                ino,
+                offset,
+                ref data,
+            } => encode_write(seq, ino, offset, data),
+            JournalEntry::Setattr {
+                ino,
+                mode,
+                uid,
+                gid,
+                size,
+                atime,
+                mtime,
+            } => {
+                let flags = [
+                    mode.is_some(),
+                    uid.is_some(),
+                    gid.is_some(),
+                    size.is_some(),
+                    atime.is_some(),
+                    mtime.is_some(),
+                ]
+                .iter()
+                .enumerate()
+                .fold(0u8, |flags, (bit, &set)| flags | (set as u8) << bit);
+                let atime = atime.unwrap_or_default();
+                let mtime = mtime.unwrap_or_default();
+
+                let mut payload = Vec::with_capacity(70);
+                payload.push(KIND_SETATTR);
+                payload.extend_from_slice(&seq.to_le_bytes());
+                payload.extend_from_slice(&ino.to_le_bytes());
+                payload.push(flags);
+                payload.extend_from_slice(&mode.unwrap_or(0).to_le_bytes());
+                payload.extend_from_slice(&uid.unwrap_or(0).to_le_bytes());
+                payload.extend_from_slice(&gid.unwrap_or(0).to_le_bytes());
+                payload.extend_from_slice(&size.unwrap_or(0).to_le_bytes());
+                payload.extend_from_slice(&atime.as_secs().to_le_bytes());
+                payload.extend_from_slice(&atime.subsec_nanos().to_le_bytes());
+                payload.extend_from_slice(&mtime.as_secs().to_le_bytes());
+                payload.extend_from_slice(&mtime.subsec_nanos().to_le_bytes());
+                payload
+            }
+        }
+    }
+
+    fn from_setattr(op: &op::Setattr<'_>) -> Self {
+        let resolve = |time| match time {
+            SetAttrTime::Timespec(time) => time,
+            _ => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+        };
+        JournalEntry::Setattr {
+            ino: op.ino(),
+            mode: op.mode(),
+            uid: op.uid(),
+            gid: op.gid(),
+            size: op.size(),
+            atime: op.atime().map(resolve),
+            mtime: op.mtime().map(resolve),
+        }
+    }
+}
+
+/// Frame the payload with its length and CRC-32.
+fn encode_record(payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32(payload).to_le_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+/// Parse the records, and return them with the length of the valid prefix.
+///
+/// The parsing stops at the first incomplete or corrupted record, which
+/// is the one being appended when the daemon crashed.
+fn parse_records(buf: &[u8]) -> (Vec<Record>, usize) {
+    let mut records = vec![];
+    let mut pos = 0;
+    while buf.len() - pos >= RECORD_HEADER_LEN {
+        let mut header = Reader(&buf[pos..pos + RECORD_HEADER_LEN]);
+        let len = header.u32().unwrap() as usize;
+        let crc = header.u32().unwrap();
+        let end = pos + RECORD_HEADER_LEN + len;
+        if end > buf.len() {
+            break;
+        }
+        let payload = &buf[pos + RECORD_HEADER_LEN..end];
+        if crc32(payload) != crc {
+            break;
+        }
+        match JournalEntry::decode(payload) {
+            Some(record) => records.push(record),
+            None => break,
+        }
+        pos = end;
+    }
+    (records, pos)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (!(crc & 1)).wrapping_add(1));
+        }
+    }
+    !crc
+}
+
+/// A write-ahead journal stored in a file.
+pub struct Journal {
+    path: PathBuf,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    file: File,
+    next_seq: u64,
+    // The entries whose operations have not been completed yet.
+    in_flight: HashSet<u64>,
+}
+
+impl fmt::Debug for Journal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Journal").field("path", &self.path).finish()
+    }
+}
+
+impl Journal {
+    /// Open the journal at the specified path, creating it if missing.
+    ///
+    /// A record torn by a crash in the middle of its append is discarded.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        let mut buf = vec![];
+        file.read_to_end(&mut buf)?;
+        let (records, len) = parse_records(&buf);
+        if len < buf.len() {
+            tracing::warn!(
+                "discarding the torn record at the end of {}",
+                path.display()
+            );
+            file.set_len(len as u64)?;
+            file.sync_data()?;
+        }
+        file.seek(SeekFrom::Start(len as u64))?;
+
+        let next_seq = records
+            .iter()
+            .map(|record| match *record {
+                Record::Entry(seq, ..) | Record::Abort(seq) => seq + 1,
+            })
+            .max()
+            .unwrap_or(0);
+
+        Ok(Self {
+            path,
+            inner: Mutex::new(Inner {
+                file,
+                next_seq,
+                in_flight: HashSet::new(),
+            }),
+        })
+    }
+
+    /// Return the logged entries that have not been checkpointed, in the
+    /// order of their appends.
+    ///
+    /// The entries whose operations have failed are excluded.
+    pub fn pending(&self) -> io::Result<Vec<JournalEntry>> {
+        let mut inner = self.inner.lock().unwrap();
+        Ok(read_pending(&mut inner.file)?
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .collect())
+    }
+
+    /// Apply the pending entries with `f`, and then discard them.
+    ///
+    /// This is intended to be called on startup, before serving the requests.
+    pub fn replay<F>(&self, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(JournalEntry) -> io::Result<()>,
+    {
+        for entry in self.pending()? {
+            f(entry)?;
+        }
+        self.checkpoint()
+    }
+
+    /// Discard the logged entries, since their modifications have been
+    /// persisted by the filesystem.
+    ///
+    /// The entries of the operations still in progress are kept.
+    pub fn checkpoint(&self) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.in_flight.is_empty() {
+            inner.file.set_len(0)?;
+            inner.file.seek(SeekFrom::Start(0))?;
+            return inner.file.sync_data();
+        }
+
+        // Rewrite the journal with the remaining entries, and replace it
+        // atomically so that a crash never loses them.
+        let mut buf = vec![];
+        for (seq, entry) in read_pending(&mut inner.file)? {
+            if inner.in_flight.contains(&seq) {
+                buf.extend_from_slice(&encode_record(&entry.encode(seq)));
+            }
+        }
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_data()?;
+        std::fs::rename(&tmp, &self.path)?;
+        inner.file = file;
+
+        // Persist the rename, or the old journal may come back after a crash.
+        let dir = match self.path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()
+    }
+
+    fn append_write(&self, ino: u64, offset: u64, data: &[u8]) -> io::Result<u64> {
+        self.append(|seq| encode_write(seq, ino, offset, data))
+    }
+
+    fn append_setattr(&self, op: &op::Setattr<'_>) -> io::Result<u64> {
+        let entry = JournalEntry::from_setattr(op);
+        self.append(|seq| entry.encode(seq))
+    }
+
+    fn append<F>(&self, payload: F) -> io::Result<u64>
+    where
+        F: FnOnce(u64) -> Vec<u8>,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        let seq = inner.next_seq;
+        inner.write_record(&payload(seq))?;
+        inner.next_seq += 1;
+        inner.in_flight.insert(seq);
+        Ok(seq)
+    }
+
+    /// Mark the operation of the entry as completed.
+    ///
+    /// The entries of the failed operations are cancelled, so that they
+    /// are not replayed.
+    fn complete(&self, seq: u64, succeeded: bool) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.in_flight.remove(&seq);
+        if !succeeded {
+            let mut payload = vec![KIND_ABORT];
+            payload.extend_from_slice(&seq.to_le_bytes());
+            // The failed operation must not be replayed, since its caller
+            // has been told that it had no effect.
+            inner.write_record(&payload)?;
+        }
+        Ok(())
+    }
+}
+
+impl Inner {
+    fn write_record(&mut self, payload: &[u8]) -> io::Result<()> {
+        let pos = self.file.seek(SeekFrom::End(0))?;
+        let res = self
+            .file
+            .write_all(&encode_record(payload))
+            .and_then(|()| self.file.sync_data());
+        if res.is_err() {
+            // Drop the partially written record, so that the subsequent
+            // records remain readable.
+            let _ = self.file.set_len(pos);
+            let _ = self.file.seek(SeekFrom::Start(pos));
+        }
+        res
+    }
+}
+
+/// Read the entries in the journal file, leaving the cursor at the end of
+/// the valid records.
+fn read_pending(file: &mut File) -> io::Result<Vec<(u64, JournalEntry)>> {
+    let mut buf = vec![];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut buf)?;
+    let (records, len) = parse_records(&buf);
+    if len < buf.len() {
+        file.set_len(len as u64)?;
+    }
+    file.seek(SeekFrom::Start(len as u64))?;
+
+    let aborted: HashSet<u64> = records
+        .iter()
+        .filter_map(|record| match *record {
+            Record::Abort(seq) => Some(seq),
+            _ => None,
+        })
+        .collect();
+    Ok(records
+        .into_iter()
+        .filter_map(|record| match record {
+            Record::Entry(seq, entry) if !aborted.contains(&seq) => Some((seq, entry)),
+            _ => None,
+        })
+        .collect())
+}
+
+/// A layer that logs `Write` and `Setattr` to a [`Journal`] before
+/// forwarding them.
+///
+/// The entry is cancelled if the wrapped service fails the operation.
+/// If the journal cannot be written, the operation fails without reaching
+/// the wrapped service. Once the operation has reached the wrapped service,
+/// its result is replied even if the journal fails to record the completion.
+#[derive(Debug, Clone)]
+pub struct JournalLayer {
+    journal: Arc<Journal>,
+}
+
+impl JournalLayer {
+    /// Create a layer logging to the journal.
+    ///
+    /// The pending entries should have been replayed beforehand.
+    pub fn new(journal: Journal) -> Self {
+        Self {
+            journal: Arc::new(journal),
+        }
+    }
+
+    /// Return a reference to the journal shared by the services.
+    #[inline]
+    pub fn journal(&self) -> &Journal {
+        &self.journal
+    }
+}
+
+impl<S> Layer<S> for JournalLayer {
+    type Service = JournalService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        JournalService {
+            inner,
+            journal: self.journal.clone(),
+        }
+    }
+}
+
+/// The service produced by [`JournalLayer`].
+#[derive(Debug)]
+pub struct JournalService<S> {
+    inner: S,
+    journal: Arc<Journal>,
+}
+
+impl<S> JournalService<S> {
+    /// Return a reference to the journal.
+    #[inline]
+    pub fn journal(&self) -> &Journal {
+        &self.journal
+    }
+}
+
+#[crate::async_trait]
+impl<S> Service for JournalService<S>
+where
+    S: Service,
+{
+    async fn call(&self, cx: &Context<'_>, mut op: Operation<'_, Data<'_>>) -> io::Result<Reply> {
+        let seq = match op {
+            Operation::Setattr(ref op) => self.journal.append_setattr(op)?,
+            Operation::Write(ref op, ref mut data) => {
+                // The payload is kept in the buffer of the request, so it can
+                // be passed to the wrapped service after being logged.
+                let buf = data.fill_buf()?;
+                if buf.len() < op.size() as usize {
+                    return Err(io::Error::from_raw_os_error(libc::EINVAL));
+                }
+                self.journal
+                    .append_write(op.ino(), op.offset(), &buf[..op.size() as usize])?
+            }
+            _ => return self.inner.call(cx, op).await,
+        };
+
+        let res = self.inner.call(cx, op).await;
+        if let Err(err) = self.journal.complete(seq, res.is_ok()) {
+            tracing::error!(seq, "failed to complete the journal entry: {}", err);
+        }
+        res
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{service::FilesystemService, testing::Harness, Filesystem};
+    use polyfuse::reply::{AttrOut, WriteOut};
+    use polyfuse_kernel::{fuse_opcode, fuse_setattr_in, FATTR_MODE};
+    use polyfuse_test::RequestBuilder;
+
+    struct TempJournal(PathBuf);
+
+    impl TempJournal {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "polyfuse-journal-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempJournal {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn setattr(ino: u64) -> JournalEntry {
+        JournalEntry::Setattr {
+            ino,
+            mode: Some(0o644),
+            uid: None,
+            gid: Some(100),
+            size: Some(4096),
+            atime: None,
+            mtime: Some(Duration::new(1_600_000_000, 5)),
+        }
+    }
+
+    fn append(journal: &Journal, entry: &JournalEntry) -> u64 {
+        journal.append(|seq| entry.encode(seq)).unwrap()
+    }
+
+    #[test]
+    fn reopen_pending() {
+        let tmp = TempJournal::new("reopen");
+        let journal = Journal::open(&tmp.0).unwrap();
+        let seq = journal.append_write(2, 10, b"hello").unwrap();
+        journal.complete(seq, true).unwrap();
+        let seq = append(&journal, &setattr(3));
+        journal.complete(seq, true).unwrap();
+        drop(journal);
+
+        let journal = Journal::open(&tmp.0).unwrap();
+        assert_eq!(
+            journal.pending().unwrap(),
+            [
+                JournalEntry::Write {
+                    ino: 2,
+                    offset: 10,
+                    data: b"hello".to_vec(),
+                },
+                setattr(3),
+            ]
+        );
+        assert_eq!(journal.append_write(2, 0, b"").unwrap(), 2);
+    }
+
+    #[test]
+    fn aborted_entries() {
+        let tmp = TempJournal::new("abort");
+        let journal = Journal::open(&tmp.0).unwrap();
+        let failed = journal.append_write(2, 0, b"a").unwrap();
+        let seq = journal.append_write(2, 1, b"b").unwrap();
+        journal.complete(failed, false).unwrap();
+        journal.complete(seq, true).unwrap();
+
+        let pending = journal.pending().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert!(matches!(pending[0], JournalEntry::Write { offset: 1, .. }));
+    }
+
+    #[test]
+    fn torn_tail() {
+        let tmp = TempJournal::new("torn");
+        let journal = Journal::open(&tmp.0).unwrap();
+        journal.append_write(2, 0, b"complete").unwrap();
+        journal.append_write(2, 8, b"torn").unwrap();
+        drop(journal);
+
+        let len = std::fs::metadata(&tmp.0).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&tmp.0)
+            .unwrap()
+            .set_len(len - 2)
+            .unwrap();
+
+        let journal = Journal::open(&tmp.0).unwrap();
+        assert_eq!(journal.pending().unwrap().len(), 1);
+        journal.append_write(2, 8, b"again").unwrap();
+        drop(journal);
+
+        let journal = Journal::open(&tmp.0).unwrap();
+        let pending = journal.pending().unwrap();
+        assert_eq!(pending.len(), 2);
+        assert!(matches!(pending[1], JournalEntry::Write { ref data, .. } if data == b"again"));
+    }
+
+    #[test]
+    fn checkpoint_and_replay() {
+        let tmp = TempJournal::new("checkpoint");
+        let journal = Journal::open(&tmp.0).unwrap();
+        let done = journal.append_write(2, 0, b"done").unwrap();
+        journal.complete(done, true).unwrap();
+        journal.append_write(2, 4, b"in-flight").unwrap();
+
+        // The entry of the operation in progress survives the checkpoint.
+        journal.checkpoint().unwrap();
+        drop(journal);
+
+        let journal = Journal::open(&tmp.0).unwrap();
+        let mut replayed = vec![];
+        journal
+            .replay(|entry| {
+                replayed.push(entry);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(replayed.len(), 1);
+        assert!(matches!(replayed[0], JournalEntry::Write { offset: 4, .. }));
+        assert!(journal.pending().unwrap().is_empty());
+    }
+
+    /// A filesystem with the file `2`, recording the written data.
+    #[derive(Default)]
+    struct Files {
+        written: Mutex<Vec<u8>>,
+    }
+
+    #[crate::async_trait]
+    impl Filesystem for Arc<Files> {
+        async fn setattr(&self, _: &Context<'_>, op: op::Setattr<'_>) -> io::Result<AttrOut> {
+            if op.ino() != 2 {
+                return Err(io::Error::from_raw_os_error(libc::ENOENT));
+            }
+            Ok(AttrOut::default())
+        }
+
+        async fn write(
+            &self,
+            _: &Context<'_>,
+            op: op::Write<'_>,
+            mut data: Data<'_>,
+        ) -> io::Result<WriteOut> {
+            if op.ino() != 2 {
+                return Err(io::Error::from_raw_os_error(libc::ENOENT));
+            }
+            let mut written = self.written.lock().unwrap();
+            let n = data.read_to_end(&mut written)?;
+            let mut out = WriteOut::default();
+            out.size(n as u32);
+            Ok(out)
+        }
+    }
+
+    fn chmod(ino: u64, mode: u32) -> RequestBuilder {
+        let mut req = RequestBuilder::new(fuse_opcode::FUSE_SETATTR);
+        req.nodeid(ino).arg(&fuse_setattr_in {
+            valid: FATTR_MODE,
+            mode,
+            ..Default::default()
+        });
+        req
+    }
+
+    #[test]
+    fn service_logs() {
+        let tmp = TempJournal::new("service");
+        let harness = Harness::new();
+        let journal = Journal::open(&tmp.0).unwrap();
+        let service =
+            JournalLayer::new(journal).layer(FilesystemService::new(Arc::new(Files::default())));
+
+        let reply = harness.call(&service, &RequestBuilder::write(2, 0, 3, b"hello"));
+        assert_eq!(reply.unwrap().error(), 0);
+        let reply = harness.call(&service, &chmod(2, 0o600));
+        assert_eq!(reply.unwrap().error(), 0);
+
+        // The failed operations are replied as is, and not replayed.
+        let reply = harness.call(&service, &RequestBuilder::write(3, 0, 0, b"lost"));
+        assert_eq!(reply.unwrap().error(), libc::ENOENT);
+        let reply = harness.call(&service, &chmod(3, 0o600));
+        assert_eq!(reply.unwrap().error(), libc::ENOENT);
+
+        let pending = service.journal().pending().unwrap();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(
+            pending[0],
+            JournalEntry::Write {
+                ino: 2,
+                offset: 3,
+                data: b"hello".to_vec(),
+            }
+        );
+        assert!(matches!(
+            pending[1],
+            JournalEntry::Setattr {
+                ino: 2,
+                mode: Some(0o600),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn service_passes_payload() {
+        let tmp = TempJournal::new("payload");
+        let harness = Harness::new();
+        let files = Arc::new(Files::default());
+        let service = JournalLayer::new(Journal::open(&tmp.0).unwrap())
+            .layer(FilesystemService::new(files.clone()));
+
+        let reply = harness.call(&service, &RequestBuilder::write(2, 0, 0, b"payload"));
+        assert_eq!(reply.unwrap().error(), 0);
+        assert_eq!(*files.written.lock().unwrap(), b"payload");
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+}
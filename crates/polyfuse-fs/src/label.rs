@@ -0,0 +1,447 @@
+//! Security labels of the inodes.
+//!
+//! A filesystem supporting an LSM such as SELinux has to keep the labels in
+//! the `security.*` extended attributes, assign the label to each inode on
+//! its creation, and list them along with the other extended attributes.
+//! [`LabelLayer`] does all of it in front of the filesystem, so that the
+//! labels are not handled in each of `Mknod`, `Mkdir`, `Symlink`, `Create`
+//! and the xattr operations:
+//!
+//! ```no_run
+//! use polyfuse::{KernelConfig, Session};
+//! use polyfuse_fs::{
+//!     label::{LabelLayer, MemoryLabels},
+//!     memfs::MemFs,
+//!     service::{FilesystemService, Layer},
+//! };
+//!
+//! # async fn run() -> std::io::Result<()> {
+//! let mut config = KernelConfig::default();
+//! config.security_context(true);
+//! let session = Session::mount("/mnt".into(), config)?;
+//!
+//! let service = LabelLayer::new(MemoryLabels::new()).layer(FilesystemService::new(MemFs::new()));
+//! while let Some(req) = session.next_request()? {
+//!     polyfuse_fs::service::serve(&service, &req).await?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The label of a new inode is the security context computed by the kernel
+//! by default, which is sent only if enabled by
+//! [`KernelConfig::security_context`](polyfuse::KernelConfig::security_context).
+//! A [`LabelPolicy`] can override it, and restrict the relabeling.
+
+use crate::{
+    service::{Layer, Reply, Service},
+    Context,
+};
+use either::Either;
+use polyfuse::{op::SecurityContext, reply::XattrOut, Data, Operation};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::{OsStr, OsString},
+    fmt, io,
+    os::unix::prelude::*,
+    sync::{Arc, Mutex},
+};
+
+/// The prefix of the extended attributes handled as the labels.
+const SECURITY_PREFIX: &[u8] = b"security.";
+
+/// The storage of the labels.
+///
+/// The filesystems storing the extended attributes natively can implement
+/// it to keep the labels along with the other attributes.
+pub trait LabelStore: Send + Sync {
+    /// Return the label of the inode.
+    fn get(&self, ino: u64, name: &OsStr) -> io::Result<Option<Vec<u8>>>;
+
+    /// Set the label of the inode, replacing the existing one.
+    fn set(&self, ino: u64, name: &OsStr, value: &[u8]) -> io::Result<()>;
+
+    /// Remove the label of the inode, and return whether it existed.
+    fn remove(&self, ino: u64, name: &OsStr) -> io::Result<bool>;
+
+    /// Return the names of the labels of the inode.
+    fn list(&self, ino: u64) -> io::Result<Vec<OsString>>;
+}
+
+/// A [`LabelStore`] keeping the labels in memory.
+///
+/// The labels are not removed with the inodes, so the filesystem should
+/// call [`remove_inode`](Self::remove_inode) when it frees an inode.
+#[derive(Default)]
+pub struct MemoryLabels {
+    labels: Mutex<HashMap<u64, BTreeMap<OsString, Vec<u8>>>>,
+}
+
+impl fmt::Debug for MemoryLabels {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryLabels")
+            .field("inodes", &self.labels.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl MemoryLabels {
+    /// Create an empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remove all the labels of the inode.
+    pub fn remove_inode(&self, ino: u64) {
+        self.labels.lock().unwrap().remove(&ino);
+    }
+}
+
+impl LabelStore for MemoryLabels {
+    fn get(&self, ino: u64, name: &OsStr) -> io::Result<Option<Vec<u8>>> {
+        let labels = self.labels.lock().unwrap();
+        Ok(labels.get(&ino).and_then(|l| l.get(name)).cloned())
+    }
+
+    fn set(&self, ino: u64, name: &OsStr, value: &[u8]) -> io::Result<()> {
+        let mut labels = self.labels.lock().unwrap();
+        labels
+            .entry(ino)
+            .or_default()
+            .insert(name.to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    fn remove(&self, ino: u64, name: &OsStr) -> io::Result<bool> {
+        let mut labels = self.labels.lock().unwrap();
+        let inode = match labels.get_mut(&ino) {
+            Some(inode) => inode,
+            None => return Ok(false),
+        };
+        let removed = inode.remove(name).is_some();
+        if inode.is_empty() {
+            labels.remove(&ino);
+        }
+        Ok(removed)
+    }
+
+    fn list(&self, ino: u64) -> io::Result<Vec<OsString>> {
+        let labels = self.labels.lock().unwrap();
+        Ok(labels
+            .get(&ino)
+            .map(|l| l.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+}
+
+/// The hooks deciding the labels.
+#[allow(unused_variables)]
+pub trait LabelPolicy: Send + Sync {
+    /// Return the label of a new inode named `name` in `parent`, as the
+    /// name of the extended attribute and its value.
+    ///
+    /// `secctx` is the context computed by the kernel, which is used by
+    /// default. No label is assigned if `None` is returned.
+    fn initial_label(
+        &self,
+        cx: &Context<'_>,
+        parent: u64,
+        name: &OsStr,
+        secctx: Option<&SecurityContext<'_>>,
+    ) -> Option<(OsString, Vec<u8>)> {
+        secctx.map(|secctx| (secctx.name().to_owned(), secctx.value().to_owned()))
+    }
+
+    /// Check whether the caller may set the label of the inode with
+    /// `setxattr(2)`.
+    ///
+    /// The kernel has already checked the permission of the caller with the
+    /// LSM, so the default allows any relabeling.
+    fn check_relabel(
+        &self,
+        cx: &Context<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+    ) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The [`LabelPolicy`] with the default behaviors.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KernelLabels;
+
+impl LabelPolicy for KernelLabels {}
+
+/// A layer that keeps the `security.*` extended attributes in a
+/// [`LabelStore`].
+#[derive(Clone)]
+pub struct LabelLayer {
+    store: Arc<dyn LabelStore>,
+    policy: Arc<dyn LabelPolicy>,
+}
+
+impl fmt::Debug for LabelLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LabelLayer").finish()
+    }
+}
+
+impl LabelLayer {
+    /// Create a layer labeling the new inodes with the security context
+    /// computed by the kernel.
+    pub fn new<S>(store: S) -> Self
+    where
+        S: LabelStore + 'static,
+    {
+        Self::with_policy(store, KernelLabels)
+    }
+
+    /// Create a layer with the specified policy.
+    pub fn with_policy<S, P>(store: S, policy: P) -> Self
+    where
+        S: LabelStore + 'static,
+        P: LabelPolicy + 'static,
+    {
+        Self {
+            store: Arc::new(store),
+            policy: Arc::new(policy),
+        }
+    }
+}
+
+impl<S> Layer<S> for LabelLayer {
+    type Service = LabelService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        LabelService {
+            inner,
+            store: self.store.clone(),
+            policy: self.policy.clone(),
+        }
+    }
+}
+
+/// The service produced by [`LabelLayer`].
+pub struct LabelService<S> {
+    inner: S,
+    store: Arc<dyn LabelStore>,
+    policy: Arc<dyn LabelPolicy>,
+}
+
+impl<S> fmt::Debug for LabelService<S>
+where
+    S: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LabelService")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+#[crate::async_trait]
+impl<S> Service for LabelService<S>
+where
+    S: Service,
+{
+    async fn call(&self, cx: &Context<'_>, op: Operation<'_, Data<'_>>) -> io::Result<Reply> {
+        let label = match op {
+            Operation::Getxattr(ref op) if is_label(op.name()) => {
+                let value = self
+                    .store
+                    .get(op.ino(), op.name())?
+                    .ok_or_else(|| io::Error::from_raw_os_error(libc::ENODATA))?;
+                return xattr_reply(op.size(), value).map(Reply::Xattr);
+            }
+            Operation::Setxattr(ref op) if is_label(op.name()) => {
+                self.policy
+                    .check_relabel(cx, op.ino(), op.name(), op.value())?;
+                set_label(&*self.store, op.ino(), op.name(), op.value(), op.flags())?;
+                return Ok(Reply::Empty);
+            }
+            Operation::Removexattr(ref op) if is_label(op.name()) => {
+                return if self.store.remove(op.ino(), op.name())? {
+                    Ok(Reply::Empty)
+                } else {
+                    Err(io::Error::from_raw_os_error(libc::ENODATA))
+                };
+            }
+            Operation::Mknod(ref op) => {
+                self.initial_label(cx, op.parent(), op.name(), op.security_context())
+            }
+            Operation::Mkdir(ref op) => {
+                self.initial_label(cx, op.parent(), op.name(), op.security_context())
+            }
+            Operation::Symlink(ref op) => {
+                self.initial_label(cx, op.parent(), op.name(), op.security_context())
+            }
+            Operation::Create(ref op) => {
+                self.initial_label(cx, op.parent(), op.name(), op.security_context())
+            }
+            _ => None,
+        };
+
+        let listxattr = match op {
+            Operation::Listxattr(ref op) => Some((op.ino(), op.size())),
+            _ => None,
+        };
+
+        let res = self.inner.call(cx, op).await;
+        if let Some((ino, size)) = listxattr {
+            let reply = match res {
+                Ok(Reply::Xattr(reply)) => Some(reply),
+                Ok(..) => None,
+                // The filesystem without the extended attributes.
+                Err(ref err) if err.raw_os_error() == Some(libc::ENOSYS) => None,
+                Err(err) => return Err(err),
+            };
+            let labels = self.store.list(ino)?;
+            return merge_names(size, reply, &labels).map(Reply::Xattr);
+        }
+
+        let reply = res?;
+        if let Some((name, value)) = label {
+            if let Reply::Entry(ref out) | Reply::Create(ref out, _) = reply {
+                // The inode has been created, so the failure is not
+                // propagated and it is left unlabeled.
+                if let Err(err) = self.store.set(out.get_ino(), &name, &value) {
+                    tracing::error!("failed to label the inode {}: {}", out.get_ino(), err);
+                }
+            }
+        }
+        Ok(reply)
+    }
+}
+
+impl<S> LabelService<S> {
+    fn initial_label(
+        &self,
+        cx: &Context<'_>,
+        parent: u64,
+        name: &OsStr,
+        secctx: Option<&SecurityContext<'_>>,
+    ) -> Option<(OsString, Vec<u8>)> {
+        self.policy
+            .initial_label(cx, parent, name, secctx)
+            .filter(|(name, _)| is_label(name))
+    }
+}
+
+#[inline]
+fn is_label(name: &OsStr) -> bool {
+    name.as_bytes().starts_with(SECURITY_PREFIX)
+}
+
+fn set_label(
+    store: &dyn LabelStore,
+    ino: u64,
+    name: &OsStr,
+    value: &[u8],
+    flags: u32,
+) -> io::Result<()> {
+    let flags = flags as i32;
+    let exists = store.get(ino, name)?.is_some();
+    if exists && flags & libc::XATTR_CREATE != 0 {
+        return Err(io::Error::from_raw_os_error(libc::EEXIST));
+    }
+    if !exists && flags & libc::XATTR_REPLACE != 0 {
+        return Err(io::Error::from_raw_os_error(libc::ENODATA));
+    }
+    store.set(ino, name, value)
+}
+
+fn xattr_reply(size: u32, value: Vec<u8>) -> io::Result<Either<XattrOut, Vec<u8>>> {
+    match size {
+        0 => {
+            let mut out = XattrOut::default();
+            out.size(value.len() as u32);
+            Ok(Either::Left(out))
+        }
+        size if value.len() > size as usize => Err(io::Error::from_raw_os_error(libc::ERANGE)),
+        _ => Ok(Either::Right(value)),
+    }
+}
+
+/// Append the names of the labels to the list replied by the filesystem,
+/// skipping the ones it has also listed.
+fn merge_names(
+    size: u32,
+    reply: Option<Either<XattrOut, Vec<u8>>>,
+    labels: &[OsString],
+) -> io::Result<Either<XattrOut, Vec<u8>>> {
+    let (queried, mut names) = match reply {
+        Some(Either::Left(out)) => (out.get_size(), vec![]),
+        Some(Either::Right(names)) => (0, names),
+        None => (0, vec![]),
+    };
+    for label in labels {
+        if !names
+            .split(|&b| b == b'\0')
+            .any(|name| name == label.as_bytes())
+        {
+            names.extend_from_slice(label.as_bytes());
+            names.push(b'\0');
+        }
+    }
+
+    if size == 0 {
+        // The names listed by the filesystem are unknown when only the size
+        // is queried, so the labels are counted even if it lists them too.
+        let mut out = XattrOut::default();
+        out.size(queried + names.len() as u32);
+        return Ok(Either::Left(out));
+    }
+    xattr_reply(size, names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_labels() {
+        let store = MemoryLabels::new();
+        let name = OsStr::new("security.selinux");
+        set_label(&store, 2, name, b"a", libc::XATTR_CREATE as u32).unwrap();
+        let err = set_label(&store, 2, name, b"b", libc::XATTR_CREATE as u32).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EEXIST));
+        let err = set_label(&store, 3, name, b"b", libc::XATTR_REPLACE as u32).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENODATA));
+        set_label(&store, 2, name, b"b", libc::XATTR_REPLACE as u32).unwrap();
+        assert_eq!(store.get(2, name).unwrap(), Some(b"b".to_vec()));
+        assert_eq!(store.list(2).unwrap(), [name]);
+
+        assert!(store.remove(2, name).unwrap());
+        assert!(!store.remove(2, name).unwrap());
+        assert!(store.list(2).unwrap().is_empty());
+        assert!(is_label(name));
+        assert!(!is_label(OsStr::new("user.security")));
+    }
+
+    #[test]
+    fn merge_listed_names() {
+        let labels = [OsString::from("security.selinux")];
+        let listed = b"user.a\0security.selinux\0".to_vec();
+
+        match merge_names(64, Some(Either::Right(b"user.a\0".to_vec())), &labels).unwrap() {
+            Either::Right(names) => assert_eq!(names, listed),
+            Either::Left(..) => panic!("unexpected size reply"),
+        }
+        match merge_names(64, Some(Either::Right(listed.clone())), &labels).unwrap() {
+            Either::Right(names) => assert_eq!(names, listed),
+            Either::Left(..) => panic!("unexpected size reply"),
+        }
+
+        let mut out = XattrOut::default();
+        out.size(7);
+        match merge_names(0, Some(Either::Left(out)), &labels).unwrap() {
+            Either::Left(out) => assert_eq!(out.get_size() as usize, listed.len()),
+            Either::Right(..) => panic!("unexpected names reply"),
+        }
+
+        let err = merge_names(8, None, &labels).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ERANGE));
+    }
+}
@@ -0,0 +1,415 @@
+//! High-level filesystem abstraction for `polyfuse`.
+//!
+//! This crate provides the [`Filesystem`] trait, which has an asynchronous
+//! method for each kind of filesystem operation, and [`dispatch`] that drives
+//! an implementation of the trait with the requests received from `Session`.
+//!
+//! Every method has a default implementation that fails with `ENOSYS`,
+//! so the filesystem only needs to implement the operations it supports.
+//!
+//! ```no_run
+//! use polyfuse::{op, reply::AttrOut, KernelConfig, Session};
+//! use polyfuse_fs::{Context, Filesystem};
+//! use std::io;
+//!
+//! struct Hello;
+//!
+//! #[polyfuse_fs::async_trait]
+//! impl Filesystem for Hello {
+//!     async fn getattr(&self, _: &Context<'_>, op: op::Getattr<'_>) -> io::Result<AttrOut> {
+//!         if op.ino() != 1 {
+//!             return Err(io::Error::from_raw_os_error(libc::ENOENT));
+//!         }
+//!         let mut out = AttrOut::default();
+//!         out.attr().ino(1);
+//!         out.attr().mode(libc::S_IFDIR | 0o555);
+//!         out.attr().nlink(2);
+//!         Ok(out)
+//!     }
+//! }
+//!
+//! # async fn run() -> io::Result<()> {
+//! let session = Session::mount("/mnt".into(), KernelConfig::default())?;
+//! let fs = Hello;
+//! while let Some(req) = session.next_request()? {
+//!     polyfuse_fs::dispatch(&fs, &req).await?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+#![doc(html_root_url = "https://docs.rs/polyfuse-fs/0.1.0")]
+#![forbid(clippy::todo, clippy::unimplemented)]
+
+pub mod cache;
+pub mod idmap;
+pub mod journal;
+pub mod label;
+pub mod locks;
+pub mod memfs;
+pub mod perm;
+pub mod service;
+
+#[cfg(test)]
+mod testing;
+
+#[doc(no_inline)]
+pub use async_trait::async_trait;
+
+use either::Either;
+use polyfuse::{
+    op,
+    reply::{
+        AttrOut, BmapOut, EntryOut, LkOut, OpenOut, PollOut, ReaddirOut, ReaddirPlusOut, StatfsOut,
+        StatxOut, WriteOut, XattrOut,
+    },
+    Data, Request,
+};
+use std::{fmt, io};
+
+/// Contextual information about the request being processed.
+#[derive(Clone)]
+pub struct Context<'req> {
+    req: &'req Request,
+    uid: u32,
+    gid: u32,
+}
+
+impl fmt::Debug for Context<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Context")
+            .field("unique", &self.unique())
+            .field("uid", &self.uid())
+            .field("gid", &self.gid())
+            .field("pid", &self.pid())
+            .finish()
+    }
+}
+
+impl<'req> Context<'req> {
+    #[inline]
+    fn new(req: &'req Request) -> Self {
+        Self {
+            req,
+            uid: req.uid(),
+            gid: req.gid(),
+        }
+    }
+
+    /// Create a copy of this context with the credentials replaced.
+    ///
+    /// This is intended to be used by the layers rewriting the credentials
+    /// of requests before they reach the filesystem.
+    pub fn with_credentials(&self, uid: u32, gid: u32) -> Self {
+        Self {
+            req: self.req,
+            uid,
+            gid,
+        }
+    }
+
+    /// Return the unique ID of the request.
+    #[inline]
+    pub fn unique(&self) -> u64 {
+        self.req.unique()
+    }
+
+    /// Return the opcode of the request.
+    #[inline]
+    pub fn opcode(&self) -> u32 {
+        self.req.opcode()
+    }
+
+    /// Return the user ID of the calling process.
+    #[inline]
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    /// Return the group ID of the calling process.
+    #[inline]
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    /// Return the process ID of the calling process.
+    #[inline]
+    pub fn pid(&self) -> u32 {
+        self.req.pid()
+    }
+}
+
+#[inline]
+fn enosys<T>() -> io::Result<T> {
+    Err(io::Error::from_raw_os_error(libc::ENOSYS))
+}
+
+/// The filesystem running on the user space.
+///
+/// Each method corresponds to an operation requested by the kernel.
+/// The returned value is sent to the kernel as the reply of the request,
+/// and the error is replied using its raw OS error code (or `EIO` if the
+/// error does not have one).
+#[async_trait]
+#[allow(unused_variables)]
+pub trait Filesystem: Sync {
+    /// Look up a directory entry by name.
+    async fn lookup(&self, cx: &Context<'_>, op: op::Lookup<'_>) -> io::Result<EntryOut> {
+        enosys()
+    }
+
+    /// Forget about inodes removed from the kernel's internal caches.
+    ///
+    /// The kernel does not wait for a reply to this operation.
+    async fn forget(&self, cx: &Context<'_>, forgets: &[op::Forget]) {}
+
+    /// Get file attributes.
+    async fn getattr(&self, cx: &Context<'_>, op: op::Getattr<'_>) -> io::Result<AttrOut> {
+        enosys()
+    }
+
+    /// Set file attributes.
+    async fn setattr(&self, cx: &Context<'_>, op: op::Setattr<'_>) -> io::Result<AttrOut> {
+        enosys()
+    }
+
+    /// Read a symbolic link.
+    async fn readlink(&self, cx: &Context<'_>, op: op::Readlink<'_>) -> io::Result<Vec<u8>> {
+        enosys()
+    }
+
+    /// Create a symbolic link.
+    async fn symlink(&self, cx: &Context<'_>, op: op::Symlink<'_>) -> io::Result<EntryOut> {
+        enosys()
+    }
+
+    /// Create a file node.
+    async fn mknod(&self, cx: &Context<'_>, op: op::Mknod<'_>) -> io::Result<EntryOut> {
+        enosys()
+    }
+
+    /// Create a directory node.
+    async fn mkdir(&self, cx: &Context<'_>, op: op::Mkdir<'_>) -> io::Result<EntryOut> {
+        enosys()
+    }
+
+    /// Remove a file.
+    async fn unlink(&self, cx: &Context<'_>, op: op::Unlink<'_>) -> io::Result<()> {
+        enosys()
+    }
+
+    /// Remove a directory.
+    async fn rmdir(&self, cx: &Context<'_>, op: op::Rmdir<'_>) -> io::Result<()> {
+        enosys()
+    }
+
+    /// Rename a file.
+    async fn rename(&self, cx: &Context<'_>, op: op::Rename<'_>) -> io::Result<()> {
+        enosys()
+    }
+
+    /// Create a hard link.
+    async fn link(&self, cx: &Context<'_>, op: op::Link<'_>) -> io::Result<EntryOut> {
+        enosys()
+    }
+
+    /// Open a file.
+    async fn open(&self, cx: &Context<'_>, op: op::Open<'_>) -> io::Result<OpenOut> {
+        enosys()
+    }
+
+    /// Read data from a file.
+    async fn read(&self, cx: &Context<'_>, op: op::Read<'_>) -> io::Result<Vec<u8>> {
+        enosys()
+    }
+
+    /// Write data to a file.
+    async fn write(
+        &self,
+        cx: &Context<'_>,
+        op: op::Write<'_>,
+        data: Data<'_>,
+    ) -> io::Result<WriteOut> {
+        enosys()
+    }
+
+    /// Release an opened file.
+    async fn release(&self, cx: &Context<'_>, op: op::Release<'_>) -> io::Result<()> {
+        enosys()
+    }
+
+    /// Get the filesystem statistics.
+    async fn statfs(&self, cx: &Context<'_>, op: op::Statfs<'_>) -> io::Result<StatfsOut> {
+        enosys()
+    }
+
+    /// Synchronize the file contents.
+    async fn fsync(&self, cx: &Context<'_>, op: op::Fsync<'_>) -> io::Result<()> {
+        enosys()
+    }
+
+    /// Set an extended attribute.
+    async fn setxattr(&self, cx: &Context<'_>, op: op::Setxattr<'_>) -> io::Result<()> {
+        enosys()
+    }
+
+    /// Get an extended attribute.
+    ///
+    /// The filesystem returns the length of the value with `XattrOut`
+    /// when `op.size()` is zero, and the value itself otherwise.
+    async fn getxattr(
+        &self,
+        cx: &Context<'_>,
+        op: op::Getxattr<'_>,
+    ) -> io::Result<Either<XattrOut, Vec<u8>>> {
+        enosys()
+    }
+
+    /// List extended attribute names.
+    ///
+    /// As with `getxattr`, the length of the names list is returned
+    /// when `op.size()` is zero.
+    async fn listxattr(
+        &self,
+        cx: &Context<'_>,
+        op: op::Listxattr<'_>,
+    ) -> io::Result<Either<XattrOut, Vec<u8>>> {
+        enosys()
+    }
+
+    /// Remove an extended attribute.
+    async fn removexattr(&self, cx: &Context<'_>, op: op::Removexattr<'_>) -> io::Result<()> {
+        enosys()
+    }
+
+    /// Close a file descriptor.
+    async fn flush(&self, cx: &Context<'_>, op: op::Flush<'_>) -> io::Result<()> {
+        enosys()
+    }
+
+    /// Open a directory.
+    async fn opendir(&self, cx: &Context<'_>, op: op::Opendir<'_>) -> io::Result<OpenOut> {
+        enosys()
+    }
+
+    /// Read contents from an opened directory.
+    async fn readdir(&self, cx: &Context<'_>, op: op::Readdir<'_>) -> io::Result<ReaddirOut> {
+        enosys()
+    }
+
+    /// Read contents from an opened directory, along with the attributes of
+    /// the entries.
+    ///
+    /// This is called instead of [`readdir`](Self::readdir) for the
+    /// `READDIRPLUS` requests, which are sent only if `readdirplus` is
+    /// enabled in `KernelConfig`.
+    async fn readdirplus(
+        &self,
+        cx: &Context<'_>,
+        op: op::Readdir<'_>,
+    ) -> io::Result<ReaddirPlusOut> {
+        enosys()
+    }
+
+    /// Release an opened directory.
+    async fn releasedir(&self, cx: &Context<'_>, op: op::Releasedir<'_>) -> io::Result<()> {
+        enosys()
+    }
+
+    /// Synchronize the directory contents.
+    async fn fsyncdir(&self, cx: &Context<'_>, op: op::Fsyncdir<'_>) -> io::Result<()> {
+        enosys()
+    }
+
+    /// Test for a POSIX file lock.
+    async fn getlk(&self, cx: &Context<'_>, op: op::Getlk<'_>) -> io::Result<LkOut> {
+        enosys()
+    }
+
+    /// Acquire, modify or release a POSIX file lock.
+    async fn setlk(&self, cx: &Context<'_>, op: op::Setlk<'_>) -> io::Result<()> {
+        enosys()
+    }
+
+    /// Acquire, modify or release a BSD file lock.
+    async fn flock(&self, cx: &Context<'_>, op: op::Flock<'_>) -> io::Result<()> {
+        enosys()
+    }
+
+    /// Check file access permissions.
+    async fn access(&self, cx: &Context<'_>, op: op::Access<'_>) -> io::Result<()> {
+        enosys()
+    }
+
+    /// Create and open a file.
+    async fn create(
+        &self,
+        cx: &Context<'_>,
+        op: op::Create<'_>,
+    ) -> io::Result<(EntryOut, OpenOut)> {
+        enosys()
+    }
+
+    /// Map block index within a file to block index within device.
+    async fn bmap(&self, cx: &Context<'_>, op: op::Bmap<'_>) -> io::Result<BmapOut> {
+        enosys()
+    }
+
+    /// Allocate requested space.
+    async fn fallocate(&self, cx: &Context<'_>, op: op::Fallocate<'_>) -> io::Result<()> {
+        enosys()
+    }
+
+    /// Copy a range of data from an opened file to another.
+    async fn copy_file_range(
+        &self,
+        cx: &Context<'_>,
+        op: op::CopyFileRange<'_>,
+    ) -> io::Result<WriteOut> {
+        enosys()
+    }
+
+    /// Poll for readiness.
+    async fn poll(&self, cx: &Context<'_>, op: op::Poll<'_>) -> io::Result<PollOut> {
+        enosys()
+    }
+
+    /// Get the extended file attributes.
+    async fn statx(&self, cx: &Context<'_>, op: op::Statx<'_>) -> io::Result<StatxOut> {
+        enosys()
+    }
+
+    /// Interrupt a previous request.
+    ///
+    /// The kernel does not wait for a reply to this operation.
+    async fn interrupt(&self, cx: &Context<'_>, op: op::Interrupt<'_>) {}
+
+    /// Receive the data retrieved from the kernel cache by `Notifier::retrieve`.
+    ///
+    /// The kernel does not wait for a reply to this operation.
+    async fn notify_reply(&self, cx: &Context<'_>, op: op::NotifyReply<'_>, data: Data<'_>) {}
+}
+
+/// Handle an incoming request using the specified filesystem.
+///
+/// The operation is decoded from the request and passed to the corresponding
+/// method of `fs`, and then its result is replied to the kernel.
+pub async fn dispatch<F>(fs: &F, req: &Request) -> io::Result<()>
+where
+    F: Filesystem + ?Sized,
+{
+    let op = match req.operation() {
+        Ok(op) => op,
+        Err(err) => {
+            tracing::error!("failed to decode the request: {}", err);
+            req.reply_error(libc::EIO)?;
+            return Ok(());
+        }
+    };
+    tracing::debug!(unique = req.unique(), ?op);
+
+    let expects_reply = service::expects_reply(&op);
+    let cx = Context::new(req);
+    let res = service::call_fs(fs, &cx, op).await;
+    service::respond(req, expects_reply, res)
+}
@@ -0,0 +1,836 @@
+//! Bookkeeping of POSIX byte-range locks.
+//!
+//! [`LockManager`] keeps track of the locks acquired through `Setlk`
+//! requests and answers `Getlk` requests, so that the filesystem without
+//! the native support of locks can provide the POSIX semantics:
+//!
+//! * The ranges locked by the same owner are split and merged as needed,
+//!   and a lock replaces the ones of the same owner over the same range.
+//! * Locks conflict only with the ones held by other owners, when either
+//!   of them is a write lock.
+//! * A blocking `Setlk` waits asynchronously until the conflicting locks
+//!   are released, and fails with `EDEADLK` if waiting would deadlock.
+//!
+//! The ranges are inclusive at both ends, as in the FUSE protocol.
+//!
+//! [`LockLayer`] wraps a service to serve `Getlk` and `Setlk` with a lock
+//! manager, and releases the locks of the closing owners on `Flush` as the
+//! kernel expects.
+
+use crate::{
+    service::{Layer, Reply, Service},
+    Context,
+};
+use polyfuse::{
+    op::{self, LockOwner},
+    reply::LkOut,
+    Data, Operation,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{self, Poll, Waker},
+};
+
+/// The kind of locks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    /// A shared lock (`F_RDLCK`).
+    Read,
+    /// An exclusive lock (`F_WRLCK`).
+    Write,
+}
+
+impl LockKind {
+    /// Convert the lock type in the requests, where `F_UNLCK` is mapped to `None`.
+    pub fn from_typ(typ: u32) -> io::Result<Option<Self>> {
+        match typ as i32 {
+            libc::F_RDLCK => Ok(Some(LockKind::Read)),
+            libc::F_WRLCK => Ok(Some(LockKind::Write)),
+            libc::F_UNLCK => Ok(None),
+            _ => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+
+    /// Return the lock type to be replied to the kernel.
+    pub fn typ(self) -> u32 {
+        match self {
+            LockKind::Read => libc::F_RDLCK as u32,
+            LockKind::Write => libc::F_WRLCK as u32,
+        }
+    }
+}
+
+/// A lock held by an owner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lock {
+    kind: LockKind,
+    start: u64,
+    end: u64,
+    owner: LockOwner,
+    pid: u32,
+}
+
+impl Lock {
+    /// Return the kind of this lock.
+    #[inline]
+    pub fn kind(&self) -> LockKind {
+        self.kind
+    }
+
+    /// Return the starting offset of the locked range.
+    #[inline]
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Return the ending offset (inclusive) of the locked range.
+    #[inline]
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    /// Return the owner of this lock.
+    #[inline]
+    pub fn owner(&self) -> LockOwner {
+        self.owner
+    }
+
+    /// Return the process ID that acquired this lock.
+    #[inline]
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    #[inline]
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    fn conflicts(&self, req: &LockRequest) -> bool {
+        // Unlocking never conflicts with the locks of the other owners.
+        req.kind.is_some()
+            && self.owner != req.owner
+            && self.overlaps(req.start, req.end)
+            && (self.kind == LockKind::Write || req.kind == Some(LockKind::Write))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LockRequest {
+    ino: u64,
+    owner: LockOwner,
+    kind: Option<LockKind>,
+    start: u64,
+    end: u64,
+    pid: u32,
+}
+
+#[derive(Default)]
+struct LockedFile {
+    locks: Vec<Lock>,
+    wakers: Vec<Waker>,
+}
+
+#[derive(Default)]
+struct State {
+    files: HashMap<u64, LockedFile>,
+    waiting: HashMap<u64, LockRequest>,
+    next_waiter: u64,
+}
+
+impl State {
+    fn conflict(&self, req: &LockRequest) -> Option<&Lock> {
+        self.files
+            .get(&req.ino)?
+            .locks
+            .iter()
+            .find(|lock| lock.conflicts(req))
+    }
+
+    /// Return whether the owner of `req` would wait for itself by
+    /// following the owners of conflicting locks and their pending requests.
+    fn would_deadlock(&self, req: &LockRequest) -> bool {
+        let blockers = |req: &LockRequest| -> Vec<LockOwner> {
+            self.files.get(&req.ino).map_or_else(Vec::new, |file| {
+                file.locks
+                    .iter()
+                    .filter(|lock| lock.conflicts(req))
+                    .map(|lock| lock.owner)
+                    .collect()
+            })
+        };
+
+        let mut stack = blockers(req);
+        let mut visited = HashSet::new();
+        while let Some(owner) = stack.pop() {
+            if owner == req.owner {
+                return true;
+            }
+            if !visited.insert(owner) {
+                continue;
+            }
+            for pending in self.waiting.values().filter(|p| p.owner == owner) {
+                stack.extend(blockers(pending));
+            }
+        }
+        false
+    }
+
+    /// Apply the lock (or unlock) request, assuming it has no conflicts.
+    ///
+    /// The wakers of the tasks waiting on the file are returned.
+    fn apply(&mut self, req: &LockRequest) -> Vec<Waker> {
+        let file = match req.kind {
+            Some(..) => self.files.entry(req.ino).or_default(),
+            None => match self.files.get_mut(&req.ino) {
+                Some(file) => file,
+                None => return Vec::new(),
+            },
+        };
+
+        let mut locks = Vec::with_capacity(file.locks.len() + 2);
+        for lock in file.locks.drain(..) {
+            if lock.owner != req.owner || !lock.overlaps(req.start, req.end) {
+                locks.push(lock);
+                continue;
+            }
+            if lock.start < req.start {
+                locks.push(Lock {
+                    end: req.start - 1,
+                    ..lock
+                });
+            }
+            if lock.end > req.end {
+                locks.push(Lock {
+                    start: req.end + 1,
+                    ..lock
+                });
+            }
+        }
+
+        if let Some(kind) = req.kind {
+            let mut new = Lock {
+                kind,
+                start: req.start,
+                end: req.end,
+                owner: req.owner,
+                pid: req.pid,
+            };
+            // Merge the adjacent locks of the same owner and kind.
+            locks.retain(|lock| {
+                if lock.owner != new.owner || lock.kind != new.kind {
+                    return true;
+                }
+                if lock.end.checked_add(1) == Some(new.start) {
+                    new.start = lock.start;
+                    false
+                } else if new.end.checked_add(1) == Some(lock.start) {
+                    new.end = lock.end;
+                    false
+                } else {
+                    true
+                }
+            });
+            locks.push(new);
+        }
+
+        file.locks = locks;
+        let wakers = std::mem::take(&mut file.wakers);
+        if file.locks.is_empty() {
+            self.files.remove(&req.ino);
+        }
+        wakers
+    }
+}
+
+fn wake_all(wakers: Vec<Waker>) {
+    for waker in wakers {
+        waker.wake();
+    }
+}
+
+/// The manager of POSIX byte-range locks.
+pub struct LockManager {
+    state: Mutex<State>,
+}
+
+impl fmt::Debug for LockManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("LockManager")
+            .field("files", &state.files.len())
+            .field("waiting", &state.waiting.len())
+            .finish()
+    }
+}
+
+impl Default for LockManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LockManager {
+    /// Create an empty lock manager.
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Return a lock that conflicts with the specified one, if any.
+    pub fn test(
+        &self,
+        ino: u64,
+        owner: LockOwner,
+        kind: LockKind,
+        start: u64,
+        end: u64,
+    ) -> Option<Lock> {
+        let req = LockRequest {
+            ino,
+            owner,
+            kind: Some(kind),
+            start,
+            end,
+            pid: 0,
+        };
+        self.state.lock().unwrap().conflict(&req).copied()
+    }
+
+    /// Acquire a lock without waiting.
+    ///
+    /// If a conflicting lock is held by another owner, `EAGAIN` is returned.
+    pub fn try_lock(
+        &self,
+        ino: u64,
+        owner: LockOwner,
+        kind: LockKind,
+        start: u64,
+        end: u64,
+        pid: u32,
+    ) -> io::Result<()> {
+        let req = LockRequest {
+            ino,
+            owner,
+            kind: Some(kind),
+            start,
+            end,
+            pid,
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.conflict(&req).is_some() {
+            return Err(io::Error::from_raw_os_error(libc::EAGAIN));
+        }
+        let wakers = state.apply(&req);
+        drop(state);
+        wake_all(wakers);
+        Ok(())
+    }
+
+    /// Acquire a lock, waiting until the conflicting locks are released.
+    ///
+    /// The returned future fails with `EDEADLK` if waiting for the lock
+    /// would deadlock. Dropping the future (e.g. when the request is
+    /// interrupted) cancels the waiting.
+    pub fn lock(
+        &self,
+        ino: u64,
+        owner: LockOwner,
+        kind: LockKind,
+        start: u64,
+        end: u64,
+        pid: u32,
+    ) -> LockFuture<'_> {
+        LockFuture {
+            manager: self,
+            req: LockRequest {
+                ino,
+                owner,
+                kind: Some(kind),
+                start,
+                end,
+                pid,
+            },
+            wait: true,
+            waiter: None,
+        }
+    }
+
+    /// Release the locks of the owner over the specified range.
+    pub fn unlock(&self, ino: u64, owner: LockOwner, start: u64, end: u64) {
+        let req = LockRequest {
+            ino,
+            owner,
+            kind: None,
+            start,
+            end,
+            pid: 0,
+        };
+        let wakers = self.state.lock().unwrap().apply(&req);
+        wake_all(wakers);
+    }
+
+    /// Release all the locks of the owner on the inode.
+    ///
+    /// The kernel does not send the unlock requests when a file descriptor
+    /// is closed, so this needs to be called on `Flush`, as done by
+    /// [`flush`](Self::flush).
+    pub fn release_owner(&self, ino: u64, owner: LockOwner) {
+        self.unlock(ino, owner, 0, u64::MAX);
+    }
+
+    /// Handle a `Flush` request, releasing the locks of its owner.
+    ///
+    /// The POSIX locks of a process are dropped when any of its descriptors
+    /// referring to the file is closed.
+    pub fn flush(&self, op: &op::Flush<'_>) {
+        self.release_owner(op.ino(), op.lock_owner());
+    }
+
+    /// Handle a `Release` request.
+    ///
+    /// This does nothing for the POSIX locks, which are released on `Flush`.
+    /// The kernel fills the lock owner of `Release` only if
+    /// [`flock_release`](op::Release::flock_release) is set, and then it
+    /// identifies the `flock(2)` locks of the closed file instead. The
+    /// `Flock` requests are not handled by this manager, so the locks of
+    /// that owner exist only if the filesystem has acquired them itself,
+    /// e.g. with [`try_lock`](Self::try_lock) over the whole file.
+    pub fn release(&self, op: &op::Release<'_>) {
+        if op.flock_release() {
+            self.release_owner(op.ino(), op.lock_owner());
+        }
+    }
+
+    /// Forget all the locks on the inode.
+    pub fn remove_inode(&self, ino: u64) {
+        let file = self.state.lock().unwrap().files.remove(&ino);
+        if let Some(file) = file {
+            wake_all(file.wakers);
+        }
+    }
+
+    /// Handle a `Getlk` request.
+    pub fn getlk(&self, op: &op::Getlk<'_>) -> io::Result<LkOut> {
+        let mut out = LkOut::default();
+        let kind = match LockKind::from_typ(op.typ())? {
+            Some(kind) => kind,
+            None => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+        match self.test(op.ino(), op.owner(), kind, op.start(), op.end()) {
+            Some(lock) => {
+                let lk = out.file_lock();
+                lk.typ(lock.kind.typ());
+                lk.start(lock.start);
+                lk.end(lock.end);
+                lk.pid(lock.pid);
+            }
+            None => out.file_lock().typ(libc::F_UNLCK as u32),
+        }
+        Ok(out)
+    }
+
+    /// Handle a `Setlk` request.
+    ///
+    /// The returned future waits for the lock only if the request may sleep.
+    /// An unknown lock type is rejected with `EINVAL`.
+    pub fn setlk(&self, op: &op::Setlk<'_>) -> io::Result<LockFuture<'_>> {
+        let kind = LockKind::from_typ(op.typ())?;
+        Ok(LockFuture {
+            manager: self,
+            req: LockRequest {
+                ino: op.ino(),
+                owner: op.owner(),
+                kind,
+                start: op.start(),
+                end: op.end(),
+                pid: op.pid(),
+            },
+            wait: kind.is_some() && op.sleep(),
+            waiter: None,
+        })
+    }
+}
+
+/// The future returned from [`LockManager::lock`] and [`LockManager::setlk`].
+#[must_use = "futures do nothing unless polled"]
+pub struct LockFuture<'a> {
+    manager: &'a LockManager,
+    req: LockRequest,
+    wait: bool,
+    waiter: Option<u64>,
+}
+
+impl fmt::Debug for LockFuture<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LockFuture")
+            .field("ino", &self.req.ino)
+            .field("kind", &self.req.kind)
+            .field("start", &self.req.start)
+            .field("end", &self.req.end)
+            .field("wait", &self.wait)
+            .finish()
+    }
+}
+
+impl Future for LockFuture<'_> {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        let me = self.get_mut();
+        let mut state = me.manager.state.lock().unwrap();
+
+        if let Some(id) = me.waiter.take() {
+            state.waiting.remove(&id);
+        }
+
+        if state.conflict(&me.req).is_some() {
+            if !me.wait {
+                return Poll::Ready(Err(io::Error::from_raw_os_error(libc::EAGAIN)));
+            }
+            if state.would_deadlock(&me.req) {
+                return Poll::Ready(Err(io::Error::from_raw_os_error(libc::EDEADLK)));
+            }
+
+            let id = state.next_waiter;
+            state.next_waiter = state.next_waiter.wrapping_add(1);
+            state.waiting.insert(id, me.req);
+            me.waiter = Some(id);
+
+            let file = state.files.entry(me.req.ino).or_default();
+            if !file.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                file.wakers.push(cx.waker().clone());
+            }
+            return Poll::Pending;
+        }
+
+        let wakers = state.apply(&me.req);
+        drop(state);
+        wake_all(wakers);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for LockFuture<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.waiter.take() {
+            self.manager.state.lock().unwrap().waiting.remove(&id);
+        }
+    }
+}
+
+/// A layer that handles the POSIX locks with a [`LockManager`].
+///
+/// `Getlk` and `Setlk` are served by the lock manager without reaching the
+/// wrapped service, and the locks owned by the closing processes are
+/// released before `Flush` is forwarded. `Release` is handled with
+/// [`LockManager::release`] before being forwarded. The other operations,
+/// including `Flock`, are forwarded as is.
+///
+/// The kernel sends the lock requests only if `FUSE_POSIX_LOCKS` is enabled
+/// in [`KernelConfig`](polyfuse::KernelConfig).
+#[derive(Debug, Default, Clone)]
+pub struct LockLayer {
+    manager: Arc<LockManager>,
+}
+
+impl LockLayer {
+    /// Create a layer with an empty lock manager.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return a reference to the lock manager shared by the services.
+    #[inline]
+    pub fn lock_manager(&self) -> &LockManager {
+        &self.manager
+    }
+}
+
+impl<S> Layer<S> for LockLayer {
+    type Service = LockService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        LockService {
+            inner,
+            manager: self.manager.clone(),
+        }
+    }
+}
+
+/// The service produced by [`LockLayer`].
+#[derive(Debug)]
+pub struct LockService<S> {
+    inner: S,
+    manager: Arc<LockManager>,
+}
+
+impl<S> LockService<S> {
+    /// Return a reference to the lock manager.
+    #[inline]
+    pub fn lock_manager(&self) -> &LockManager {
+        &self.manager
+    }
+}
+
+#[crate::async_trait]
+impl<S> Service for LockService<S>
+where
+    S: Service,
+{
+    async fn call(&self, cx: &Context<'_>, op: Operation<'_, Data<'_>>) -> io::Result<Reply> {
+        let flush = match op {
+            Operation::Getlk(ref op) => return self.manager.getlk(op).map(Reply::Lk),
+            Operation::Setlk(ref op) => {
+                self.manager.setlk(op)?.await?;
+                return Ok(Reply::Empty);
+            }
+            Operation::Flush(ref op) => {
+                self.manager.flush(op);
+                true
+            }
+            Operation::Release(ref op) => {
+                self.manager.release(op);
+                false
+            }
+            _ => false,
+        };
+
+        match self.inner.call(cx, op).await {
+            // The kernel stops sending `Flush` once it fails with `ENOSYS`,
+            // which would leave the locks of the closing processes behind.
+            Err(ref err) if flush && err.raw_os_error() == Some(libc::ENOSYS) => Ok(Reply::Empty),
+            res => res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{noop_waker, Harness, Recorder};
+    use polyfuse_kernel::{
+        fuse_file_lock, fuse_flush_in, fuse_lk_in, fuse_lk_out, fuse_opcode, fuse_release_in,
+        FUSE_RELEASE_FLOCK_UNLOCK,
+    };
+    use polyfuse_test::RequestBuilder;
+
+    const A: LockOwner = LockOwner::from_raw(1);
+    const B: LockOwner = LockOwner::from_raw(2);
+
+    fn poll_once(fut: &mut LockFuture<'_>) -> Poll<io::Result<()>> {
+        let waker = noop_waker();
+        let mut cx = task::Context::from_waker(&waker);
+        Pin::new(fut).poll(&mut cx)
+    }
+
+    fn locks(manager: &LockManager, ino: u64) -> Vec<(LockKind, u64, u64)> {
+        let state = manager.state.lock().unwrap();
+        let mut locks: Vec<_> = state
+            .files
+            .get(&ino)
+            .map_or_else(Vec::new, |file| file.locks.clone())
+            .into_iter()
+            .map(|lock| (lock.kind, lock.start, lock.end))
+            .collect();
+        locks.sort_by_key(|&(_, start, _)| start);
+        locks
+    }
+
+    #[test]
+    fn split_and_merge() {
+        let manager = LockManager::new();
+        manager.try_lock(1, A, LockKind::Write, 0, 99, 1).unwrap();
+        manager.try_lock(1, A, LockKind::Read, 40, 59, 1).unwrap();
+        assert_eq!(
+            locks(&manager, 1),
+            vec![
+                (LockKind::Write, 0, 39),
+                (LockKind::Read, 40, 59),
+                (LockKind::Write, 60, 99),
+            ]
+        );
+
+        manager.try_lock(1, A, LockKind::Write, 40, 59, 1).unwrap();
+        assert_eq!(locks(&manager, 1), vec![(LockKind::Write, 0, 99)]);
+
+        manager.unlock(1, A, 10, 19);
+        assert_eq!(
+            locks(&manager, 1),
+            vec![(LockKind::Write, 0, 9), (LockKind::Write, 20, 99)]
+        );
+
+        manager.release_owner(1, A);
+        assert_eq!(locks(&manager, 1), vec![]);
+    }
+
+    #[test]
+    fn conflicts() {
+        let manager = LockManager::new();
+        manager.try_lock(1, A, LockKind::Read, 0, 9, 1).unwrap();
+        manager.try_lock(1, B, LockKind::Read, 5, 14, 2).unwrap();
+
+        let err = manager
+            .try_lock(1, B, LockKind::Write, 0, 4, 2)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EAGAIN));
+
+        let lock = manager.test(1, B, LockKind::Write, 0, 4).unwrap();
+        assert_eq!((lock.owner(), lock.pid()), (A, 1));
+        assert!(manager.test(1, B, LockKind::Write, 10, 19).is_none());
+        assert!(manager.test(2, B, LockKind::Write, 0, 4).is_none());
+    }
+
+    #[test]
+    fn wait_and_deadlock() {
+        let manager = LockManager::new();
+        manager.try_lock(1, A, LockKind::Write, 0, 9, 1).unwrap();
+        manager.try_lock(1, B, LockKind::Write, 10, 19, 2).unwrap();
+
+        let mut a_waits = manager.lock(1, A, LockKind::Write, 10, 19, 1);
+        assert!(poll_once(&mut a_waits).is_pending());
+
+        let mut b_waits = manager.lock(1, B, LockKind::Write, 0, 9, 2);
+        match poll_once(&mut b_waits) {
+            Poll::Ready(Err(err)) => assert_eq!(err.raw_os_error(), Some(libc::EDEADLK)),
+            _ => panic!("deadlock is not detected"),
+        }
+
+        manager.release_owner(1, B);
+        assert!(matches!(poll_once(&mut a_waits), Poll::Ready(Ok(()))));
+        assert_eq!(locks(&manager, 1), vec![(LockKind::Write, 0, 19)]);
+    }
+
+    fn lk(opcode: fuse_opcode, owner: u64, typ: i32, start: u64, end: u64) -> RequestBuilder {
+        let mut req = RequestBuilder::new(opcode);
+        req.nodeid(1).arg(&fuse_lk_in {
+            owner,
+            lk: fuse_file_lock {
+                start,
+                end,
+                typ: typ as u32,
+                pid: owner as u32,
+            },
+            ..Default::default()
+        });
+        req
+    }
+
+    #[test]
+    fn service_locks() {
+        let harness = Harness::new();
+        let inner = Arc::new(Recorder::default());
+        let service = LockLayer::new().layer(inner.clone());
+
+        let setlk = |owner, typ, start, end| {
+            let req = lk(fuse_opcode::FUSE_SETLK, owner, typ, start, end);
+            harness.call(&service, &req).unwrap().error()
+        };
+        assert_eq!(setlk(1, libc::F_WRLCK, 0, 9), 0);
+        assert_eq!(setlk(2, libc::F_RDLCK, 5, 14), libc::EAGAIN);
+
+        let req = lk(fuse_opcode::FUSE_GETLK, 2, libc::F_RDLCK, 0, 99);
+        let reply = harness.call(&service, &req).unwrap();
+        let out = reply.arg::<fuse_lk_out>().unwrap();
+        assert_eq!(
+            (out.lk.typ, out.lk.start, out.lk.end),
+            (libc::F_WRLCK as u32, 0, 9)
+        );
+        assert_eq!(out.lk.pid, 1);
+
+        // A malformed request must not release the locks.
+        assert_eq!(setlk(1, 42, 0, 9), libc::EINVAL);
+        assert_eq!(
+            locks(service.lock_manager(), 1),
+            vec![(LockKind::Write, 0, 9)]
+        );
+
+        assert_eq!(setlk(1, libc::F_UNLCK, 0, 4), 0);
+        assert_eq!(
+            locks(service.lock_manager(), 1),
+            vec![(LockKind::Write, 5, 9)]
+        );
+        assert!(inner.opcodes().is_empty());
+    }
+
+    #[test]
+    fn service_unlock_over_others() {
+        let harness = Harness::new();
+        let service = LockLayer::new().layer(Recorder::default());
+
+        let setlk = |opcode, owner, typ, start, end| {
+            let req = lk(opcode, owner, typ, start, end);
+            harness.call(&service, &req).unwrap().error()
+        };
+        assert_eq!(
+            setlk(fuse_opcode::FUSE_SETLK, 2, libc::F_WRLCK, 100, 200),
+            0
+        );
+        assert_eq!(
+            setlk(fuse_opcode::FUSE_SETLK, 1, libc::F_UNLCK, 0, u64::MAX),
+            0
+        );
+        assert_eq!(
+            setlk(fuse_opcode::FUSE_SETLKW, 1, libc::F_UNLCK, 150, 150),
+            0
+        );
+        assert_eq!(
+            locks(service.lock_manager(), 1),
+            vec![(LockKind::Write, 100, 200)]
+        );
+    }
+
+    #[test]
+    fn service_release_owner() {
+        let harness = Harness::new();
+        let inner = Arc::new(Recorder::default());
+        let service = LockLayer::new().layer(inner.clone());
+        let manager = service.lock_manager();
+
+        manager.try_lock(1, A, LockKind::Read, 0, 9, 1).unwrap();
+        manager.try_lock(1, B, LockKind::Read, 0, 9, 2).unwrap();
+
+        // The locks are released even if the filesystem does not implement `Flush`.
+        let mut req = RequestBuilder::new(fuse_opcode::FUSE_FLUSH);
+        req.nodeid(1).arg(&fuse_flush_in {
+            lock_owner: 1,
+            ..Default::default()
+        });
+        assert_eq!(harness.call(&service, &req).unwrap().error(), 0);
+        assert!(manager.test(1, A, LockKind::Write, 0, 9).is_some());
+        assert!(manager.test(1, B, LockKind::Write, 0, 9).is_none());
+
+        // The lock owner of `Release` is meaningless without `FUSE_RELEASE_FLOCK_UNLOCK`.
+        let mut req = RequestBuilder::new(fuse_opcode::FUSE_RELEASE);
+        req.nodeid(1).arg(&fuse_release_in {
+            lock_owner: 2,
+            ..Default::default()
+        });
+        assert_eq!(harness.call(&service, &req).unwrap().error(), libc::ENOSYS);
+        assert_eq!(locks(manager, 1), vec![(LockKind::Read, 0, 9)]);
+
+        let mut req = RequestBuilder::new(fuse_opcode::FUSE_RELEASE);
+        req.nodeid(1).arg(&fuse_release_in {
+            release_flags: FUSE_RELEASE_FLOCK_UNLOCK,
+            lock_owner: 2,
+            ..Default::default()
+        });
+        assert_eq!(harness.call(&service, &req).unwrap().error(), libc::ENOSYS);
+        assert_eq!(locks(manager, 1), vec![]);
+
+        assert_eq!(
+            inner.opcodes(),
+            vec![
+                fuse_opcode::FUSE_FLUSH as u32,
+                fuse_opcode::FUSE_RELEASE as u32,
+                fuse_opcode::FUSE_RELEASE as u32
+            ]
+        );
+    }
+}
@@ -0,0 +1,1207 @@
+//! In-memory filesystem.
+//!
+//! [`MemFs`] is a tmpfs-like filesystem that keeps the whole directory tree
+//! in memory. It can be mounted as it is, or used as a scaffold of other
+//! filesystems by populating the tree in advance or by delegating the
+//! operations to it from another [`Filesystem`] implementation.
+
+use crate::{Context, Filesystem};
+use either::Either;
+use polyfuse::{
+    op,
+    reply::{AttrOut, EntryOut, FileAttr, OpenOut, ReaddirOut, StatfsOut, WriteOut, XattrOut},
+    util::{HandleTable, InodeTable},
+    Data,
+};
+use std::{
+    collections::{btree_map, hash_map, BTreeMap, HashMap},
+    convert::TryFrom,
+    ffi::{OsStr, OsString},
+    io::{self, prelude::*},
+    os::unix::prelude::*,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, SystemTime},
+};
+
+const ROOT_INO: u64 = 1;
+
+/// The maximum length of the entry names, in bytes.
+const NAME_MAX: usize = 255;
+
+/// The default limit of the file sizes, in bytes.
+const DEFAULT_MAX_FILE_SIZE: u64 = 1 << 30;
+
+/// A node in the directory tree.
+#[derive(Debug)]
+pub struct Node {
+    attr: Attr,
+    nlink: u32,
+    xattrs: HashMap<OsString, Vec<u8>>,
+    kind: NodeKind,
+}
+
+#[derive(Debug)]
+struct Attr {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u32,
+    atime: Duration,
+    mtime: Duration,
+    ctime: Duration,
+}
+
+/// The content of a node.
+#[derive(Debug)]
+pub enum NodeKind {
+    /// A regular file.
+    File(Vec<u8>),
+    /// A directory.
+    Directory(Directory),
+    /// A symbolic link.
+    Symlink(OsString),
+    /// A special file, such as a FIFO or a device node.
+    Special,
+}
+
+/// The entries of a directory.
+#[derive(Debug)]
+pub struct Directory {
+    children: BTreeMap<OsString, u64>,
+    parent: u64,
+}
+
+impl Default for Directory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Directory {
+    /// Create an empty directory.
+    ///
+    /// The parent directory is set when the node is inserted into the tree.
+    pub fn new() -> Self {
+        Self {
+            children: BTreeMap::new(),
+            parent: ROOT_INO,
+        }
+    }
+
+    /// Return the inode number of the entry with the specified name.
+    #[inline]
+    pub fn get(&self, name: &OsStr) -> Option<u64> {
+        self.children.get(name).copied()
+    }
+
+    /// Return the inode number of the parent directory.
+    #[inline]
+    pub fn parent(&self) -> u64 {
+        self.parent
+    }
+
+    /// Return an iterator over the names and inode numbers of the entries.
+    pub fn iter(&self) -> impl Iterator<Item = (&OsStr, u64)> + '_ {
+        self.children
+            .iter()
+            .map(|(name, &ino)| (name.as_os_str(), ino))
+    }
+
+    /// Return whether the directory has no entries.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.children.is_empty()
+    }
+}
+
+impl Node {
+    fn new(kind: NodeKind, mode: u32, uid: u32, gid: u32) -> Self {
+        let now = now();
+        Self {
+            attr: Attr {
+                mode,
+                uid,
+                gid,
+                rdev: 0,
+                atime: now,
+                mtime: now,
+                ctime: now,
+            },
+            nlink: match kind {
+                NodeKind::Directory(..) => 2,
+                _ => 1,
+            },
+            xattrs: HashMap::new(),
+            kind,
+        }
+    }
+
+    /// Return the file mode, including the file type.
+    #[inline]
+    pub fn mode(&self) -> u32 {
+        self.attr.mode
+    }
+
+    /// Return the number of hard links.
+    #[inline]
+    pub fn nlink(&self) -> u32 {
+        self.nlink
+    }
+
+    /// Return the content of this node.
+    #[inline]
+    pub fn kind(&self) -> &NodeKind {
+        &self.kind
+    }
+
+    /// Return the mutable reference to the content of this node.
+    #[inline]
+    pub fn kind_mut(&mut self) -> &mut NodeKind {
+        &mut self.kind
+    }
+
+    /// Return the extended attributes of this node.
+    #[inline]
+    pub fn xattrs(&self) -> &HashMap<OsString, Vec<u8>> {
+        &self.xattrs
+    }
+
+    /// Return the mutable reference to the extended attributes of this node.
+    #[inline]
+    pub fn xattrs_mut(&mut self) -> &mut HashMap<OsString, Vec<u8>> {
+        &mut self.xattrs
+    }
+
+    fn size(&self) -> u64 {
+        match self.kind {
+            NodeKind::File(ref data) => data.len() as u64,
+            NodeKind::Symlink(ref link) => link.len() as u64,
+            NodeKind::Directory(..) | NodeKind::Special => 0,
+        }
+    }
+
+    fn dir(&self) -> io::Result<&Directory> {
+        match self.kind {
+            NodeKind::Directory(ref dir) => Ok(dir),
+            _ => Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+        }
+    }
+
+    fn dir_mut(&mut self) -> io::Result<&mut Directory> {
+        match self.kind {
+            NodeKind::Directory(ref mut dir) => Ok(dir),
+            _ => Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+        }
+    }
+
+    fn file_mut(&mut self) -> io::Result<&mut Vec<u8>> {
+        match self.kind {
+            NodeKind::File(ref mut data) => Ok(data),
+            NodeKind::Directory(..) => Err(io::Error::from_raw_os_error(libc::EISDIR)),
+            _ => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+
+    fn fill_attr(&self, ino: u64, attr: &mut FileAttr) {
+        let size = self.size();
+        attr.ino(ino);
+        attr.size(size);
+        attr.mode(self.attr.mode);
+        attr.nlink(self.nlink);
+        attr.uid(self.attr.uid);
+        attr.gid(self.attr.gid);
+        attr.rdev(self.attr.rdev);
+        attr.blksize(BLOCK_SIZE);
+        attr.blocks(div_round_up(size, 512));
+        attr.atime(self.attr.atime);
+        attr.mtime(self.attr.mtime);
+        attr.ctime(self.attr.ctime);
+    }
+}
+
+const BLOCK_SIZE: u32 = 4096;
+
+struct DirEntry {
+    name: OsString,
+    ino: u64,
+    typ: u32,
+}
+
+/// An in-memory filesystem.
+#[derive(Debug)]
+pub struct MemFs {
+    inodes: Mutex<InodeTable<Node>>,
+    dirs: HandleTable<Vec<DirEntry>>,
+    ttl: Duration,
+    max_file_size: u64,
+}
+
+impl Default for MemFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemFs {
+    /// Create an empty filesystem.
+    ///
+    /// The root directory is owned by the user running the process.
+    pub fn new() -> Self {
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+
+        let mut inodes = InodeTable::new();
+        let root = inodes.insert(Node::new(
+            NodeKind::Directory(Directory::new()),
+            libc::S_IFDIR | 0o755,
+            uid,
+            gid,
+        ));
+        debug_assert_eq!(root, ROOT_INO);
+
+        Self {
+            inodes: Mutex::new(inodes),
+            dirs: HandleTable::new(),
+            ttl: Duration::from_secs(1),
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+        }
+    }
+
+    /// Set the validity timeout for the entries and attributes.
+    pub fn ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    /// Set the maximum size of the regular files, in bytes.
+    ///
+    /// The writes and truncations beyond the limit fail with `EFBIG`. The
+    /// default value is 1 GiB.
+    pub fn max_file_size(&mut self, size: u64) {
+        self.max_file_size = size;
+    }
+
+    /// Return the end of the range in a file, checking it against the limit.
+    fn file_end(&self, offset: u64, len: usize) -> io::Result<usize> {
+        offset
+            .checked_add(len as u64)
+            .filter(|&end| end <= self.max_file_size)
+            .and_then(|end| usize::try_from(end).ok())
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EFBIG))
+    }
+
+    fn inodes(&self) -> MutexGuard<'_, InodeTable<Node>> {
+        self.inodes.lock().unwrap()
+    }
+
+    /// Apply the function to the node with the specified inode number.
+    pub fn with_node<F, R>(&self, ino: u64, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut Node) -> R,
+    {
+        self.inodes().get_mut(ino).map(f)
+    }
+
+    /// Create a new node in the directory and return its inode number.
+    ///
+    /// The file type bits of `mode` are derived from `kind`. The created node
+    /// is not counted as being looked up by the kernel.
+    pub fn insert(
+        &self,
+        parent: u64,
+        name: &OsStr,
+        kind: NodeKind,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+    ) -> io::Result<u64> {
+        let mut inodes = self.inodes();
+        make_node(&mut inodes, parent, name, kind, mode, uid, gid)
+    }
+
+    fn entry_out(&self, inodes: &mut InodeTable<Node>, ino: u64) -> io::Result<EntryOut> {
+        let mut out = EntryOut::default();
+        let node = inodes.lookup(ino, &mut out).ok_or_else(no_entry)?;
+        node.fill_attr(ino, out.attr());
+        out.ttl_attr(self.ttl);
+        out.ttl_entry(self.ttl);
+        Ok(out)
+    }
+
+    fn attr_out(&self, ino: u64, node: &Node) -> AttrOut {
+        let mut out = AttrOut::default();
+        node.fill_attr(ino, out.attr());
+        out.ttl(self.ttl);
+        out
+    }
+
+    fn make_entry(
+        &self,
+        cx: &Context<'_>,
+        parent: u64,
+        name: &OsStr,
+        kind: NodeKind,
+        mode: u32,
+    ) -> io::Result<EntryOut> {
+        let mut inodes = self.inodes();
+        let ino = make_node(&mut inodes, parent, name, kind, mode, cx.uid(), cx.gid())?;
+        self.entry_out(&mut inodes, ino)
+    }
+}
+
+fn make_node(
+    inodes: &mut InodeTable<Node>,
+    parent: u64,
+    name: &OsStr,
+    kind: NodeKind,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+) -> io::Result<u64> {
+    let typ = match kind {
+        NodeKind::File(..) => libc::S_IFREG,
+        NodeKind::Directory(..) => libc::S_IFDIR,
+        NodeKind::Symlink(..) => libc::S_IFLNK,
+        NodeKind::Special => mode & libc::S_IFMT,
+    };
+    let is_dir = typ == libc::S_IFDIR;
+
+    check_name(name)?;
+    let dir = inodes.get(parent).ok_or_else(no_entry)?.dir()?;
+    if dir.children.contains_key(name) {
+        return Err(io::Error::from_raw_os_error(libc::EEXIST));
+    }
+
+    let kind = match kind {
+        NodeKind::Directory(mut dir) => {
+            dir.parent = parent;
+            NodeKind::Directory(dir)
+        }
+        kind => kind,
+    };
+    let ino = inodes.insert(Node::new(kind, typ | (mode & !libc::S_IFMT), uid, gid));
+
+    let now = now();
+    let parent = inodes.get_mut(parent).unwrap_or_else(|| unreachable!());
+    parent
+        .dir_mut()
+        .unwrap_or_else(|_| unreachable!())
+        .children
+        .insert(name.into(), ino);
+    if is_dir {
+        parent.nlink += 1;
+    }
+    parent.attr.mtime = now;
+    parent.attr.ctime = now;
+
+    Ok(ino)
+}
+
+/// Remove the link to the node and release the node if it is no longer referenced.
+fn drop_link(inodes: &mut InodeTable<Node>, ino: u64) {
+    let node = match inodes.get_mut(ino) {
+        Some(node) => node,
+        None => return,
+    };
+    node.nlink = match node.kind {
+        NodeKind::Directory(..) => 0,
+        _ => node.nlink.saturating_sub(1),
+    };
+    node.attr.ctime = now();
+    if node.nlink == 0 && inodes.nlookup(ino) == Some(0) {
+        inodes.remove(ino);
+    }
+}
+
+fn unlink_entry(
+    inodes: &mut InodeTable<Node>,
+    parent: u64,
+    name: &OsStr,
+    is_dir: bool,
+) -> io::Result<()> {
+    let ino = inodes
+        .get(parent)
+        .ok_or_else(no_entry)?
+        .dir()?
+        .get(name)
+        .ok_or_else(no_entry)?;
+
+    let node = inodes.get(ino).unwrap_or_else(|| unreachable!());
+    match (&node.kind, is_dir) {
+        (NodeKind::Directory(dir), true) if !dir.is_empty() => {
+            return Err(io::Error::from_raw_os_error(libc::ENOTEMPTY))
+        }
+        (NodeKind::Directory(..), true) => (),
+        (NodeKind::Directory(..), false) => return Err(io::Error::from_raw_os_error(libc::EISDIR)),
+        (_, true) => return Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+        (_, false) => (),
+    }
+
+    let now = now();
+    let parent = inodes.get_mut(parent).unwrap_or_else(|| unreachable!());
+    parent
+        .dir_mut()
+        .unwrap_or_else(|_| unreachable!())
+        .children
+        .remove(name);
+    if is_dir {
+        parent.nlink -= 1;
+    }
+    parent.attr.mtime = now;
+    parent.attr.ctime = now;
+
+    drop_link(inodes, ino);
+
+    Ok(())
+}
+
+/// Swap the nodes linked by the two entries, for `RENAME_EXCHANGE`.
+fn exchange_entries(
+    inodes: &mut InodeTable<Node>,
+    (parent, name): (u64, &OsStr),
+    (newparent, newname): (u64, &OsStr),
+) -> io::Result<()> {
+    let lookup =
+        |inodes: &InodeTable<Node>, parent: u64, name: &OsStr| -> io::Result<(u64, bool)> {
+            let ino = inodes
+                .get(parent)
+                .ok_or_else(no_entry)?
+                .dir()?
+                .get(name)
+                .ok_or_else(no_entry)?;
+            let is_dir = matches!(
+                inodes.get(ino).map(|node| &node.kind),
+                Some(NodeKind::Directory(..))
+            );
+            Ok((ino, is_dir))
+        };
+    let (ino, is_dir) = lookup(inodes, parent, name)?;
+    let (target, target_is_dir) = lookup(inodes, newparent, newname)?;
+    if ino == target {
+        return Ok(());
+    }
+
+    if is_dir {
+        check_subtree(inodes, ino, newparent)?;
+    }
+    if target_is_dir {
+        check_subtree(inodes, target, parent)?;
+    }
+
+    let now = now();
+    for &(dir, name, old_is_dir, new, new_is_dir) in &[
+        (parent, name, is_dir, target, target_is_dir),
+        (newparent, newname, target_is_dir, ino, is_dir),
+    ] {
+        let node = inodes.get_mut(dir).unwrap_or_else(|| unreachable!());
+        node.dir_mut()?.children.insert(name.into(), new);
+        // The subdirectories link to their parents by `..`.
+        if old_is_dir {
+            node.nlink -= 1;
+        }
+        if new_is_dir {
+            node.nlink += 1;
+        }
+        node.attr.mtime = now;
+        node.attr.ctime = now;
+    }
+    for &(ino, parent) in &[(ino, newparent), (target, parent)] {
+        let node = inodes.get_mut(ino).unwrap_or_else(|| unreachable!());
+        if let NodeKind::Directory(ref mut dir) = node.kind {
+            dir.parent = parent;
+        }
+        node.attr.ctime = now;
+    }
+
+    Ok(())
+}
+
+/// Forbid moving the directory `ino` into its own subtree, i.e. under `newparent`.
+fn check_subtree(inodes: &InodeTable<Node>, ino: u64, newparent: u64) -> io::Result<()> {
+    let mut cur = newparent;
+    while cur != ROOT_INO {
+        if cur == ino {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        cur = inodes.get(cur).ok_or_else(no_entry)?.dir()?.parent;
+    }
+    Ok(())
+}
+
+fn check_name(name: &OsStr) -> io::Result<()> {
+    if name.len() > NAME_MAX {
+        return Err(io::Error::from_raw_os_error(libc::ENAMETOOLONG));
+    }
+    Ok(())
+}
+
+#[inline]
+fn no_entry() -> io::Error {
+    io::Error::from_raw_os_error(libc::ENOENT)
+}
+
+#[inline]
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+#[inline]
+fn div_round_up(n: u64, d: u64) -> u64 {
+    match n % d {
+        0 => n / d,
+        _ => n / d + 1,
+    }
+}
+
+#[inline]
+fn dir_type(mode: u32) -> u32 {
+    (mode & libc::S_IFMT) >> 12
+}
+
+fn xattr_reply(size: u32, value: Vec<u8>) -> io::Result<Either<XattrOut, Vec<u8>>> {
+    match size {
+        0 => {
+            let mut out = XattrOut::default();
+            out.size(value.len() as u32);
+            Ok(Either::Left(out))
+        }
+        size if value.len() > size as usize => Err(io::Error::from_raw_os_error(libc::ERANGE)),
+        _ => Ok(Either::Right(value)),
+    }
+}
+
+#[crate::async_trait]
+impl Filesystem for MemFs {
+    async fn lookup(&self, _: &Context<'_>, op: op::Lookup<'_>) -> io::Result<EntryOut> {
+        let mut inodes = self.inodes();
+        let ino = inodes
+            .get(op.parent())
+            .ok_or_else(no_entry)?
+            .dir()?
+            .get(op.name())
+            .ok_or_else(no_entry)?;
+        self.entry_out(&mut inodes, ino)
+    }
+
+    async fn forget(&self, _: &Context<'_>, forgets: &[op::Forget]) {
+        let mut inodes = self.inodes();
+        for forget in forgets {
+            let nlookup = inodes.unref(forget.ino(), forget.nlookup());
+            let nlink = inodes.get(forget.ino()).map(|node| node.nlink);
+            if nlookup == Some(0) && nlink == Some(0) {
+                inodes.remove(forget.ino());
+            }
+        }
+    }
+
+    async fn getattr(&self, _: &Context<'_>, op: op::Getattr<'_>) -> io::Result<AttrOut> {
+        let inodes = self.inodes();
+        let node = inodes.get(op.ino()).ok_or_else(no_entry)?;
+        Ok(self.attr_out(op.ino(), node))
+    }
+
+    async fn setattr(&self, _: &Context<'_>, op: op::Setattr<'_>) -> io::Result<AttrOut> {
+        let mut inodes = self.inodes();
+        let node = inodes.get_mut(op.ino()).ok_or_else(no_entry)?;
+
+        fn to_duration(t: op::SetAttrTime) -> Duration {
+            match t {
+                op::SetAttrTime::Timespec(ts) => ts,
+                _ => now(),
+            }
+        }
+
+        if let Some(size) = op.size() {
+            let size = self.file_end(size, 0)?;
+            node.file_mut()?.resize(size, 0);
+            node.attr.mtime = now();
+        }
+        if let Some(mode) = op.mode() {
+            node.attr.mode = (node.attr.mode & libc::S_IFMT) | (mode & !libc::S_IFMT);
+        }
+        if let Some(uid) = op.uid() {
+            node.attr.uid = uid;
+        }
+        if let Some(gid) = op.gid() {
+            node.attr.gid = gid;
+        }
+        if let Some(atime) = op.atime() {
+            node.attr.atime = to_duration(atime);
+        }
+        if let Some(mtime) = op.mtime() {
+            node.attr.mtime = to_duration(mtime);
+        }
+        node.attr.ctime = op.ctime().unwrap_or_else(now);
+
+        Ok(self.attr_out(op.ino(), node))
+    }
+
+    async fn readlink(&self, _: &Context<'_>, op: op::Readlink<'_>) -> io::Result<Vec<u8>> {
+        let inodes = self.inodes();
+        match inodes.get(op.ino()).ok_or_else(no_entry)?.kind {
+            NodeKind::Symlink(ref link) => Ok(link.as_bytes().to_owned()),
+            _ => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+
+    async fn symlink(&self, cx: &Context<'_>, op: op::Symlink<'_>) -> io::Result<EntryOut> {
+        let kind = NodeKind::Symlink(op.link().to_owned());
+        self.make_entry(cx, op.parent(), op.name(), kind, 0o777)
+    }
+
+    async fn mknod(&self, cx: &Context<'_>, op: op::Mknod<'_>) -> io::Result<EntryOut> {
+        let mode = op.mode() & !op.umask();
+        let kind = match mode & libc::S_IFMT {
+            libc::S_IFREG => NodeKind::File(vec![]),
+            libc::S_IFDIR => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+            _ => NodeKind::Special,
+        };
+
+        let mut inodes = self.inodes();
+        let ino = make_node(
+            &mut inodes,
+            op.parent(),
+            op.name(),
+            kind,
+            mode,
+            cx.uid(),
+            cx.gid(),
+        )?;
+        if let Some(node) = inodes.get_mut(ino) {
+            node.attr.rdev = op.rdev();
+        }
+        self.entry_out(&mut inodes, ino)
+    }
+
+    async fn mkdir(&self, cx: &Context<'_>, op: op::Mkdir<'_>) -> io::Result<EntryOut> {
+        let kind = NodeKind::Directory(Directory::new());
+        self.make_entry(cx, op.parent(), op.name(), kind, op.mode() & !op.umask())
+    }
+
+    async fn unlink(&self, _: &Context<'_>, op: op::Unlink<'_>) -> io::Result<()> {
+        unlink_entry(&mut self.inodes(), op.parent(), op.name(), false)
+    }
+
+    async fn rmdir(&self, _: &Context<'_>, op: op::Rmdir<'_>) -> io::Result<()> {
+        unlink_entry(&mut self.inodes(), op.parent(), op.name(), true)
+    }
+
+    async fn rename(&self, _: &Context<'_>, op: op::Rename<'_>) -> io::Result<()> {
+        const RENAME_NOREPLACE: u32 = 1;
+        const RENAME_EXCHANGE: u32 = 2;
+
+        match op.flags() {
+            0 | RENAME_NOREPLACE => (),
+            RENAME_EXCHANGE => {
+                return exchange_entries(
+                    &mut self.inodes(),
+                    (op.parent(), op.name()),
+                    (op.newparent(), op.newname()),
+                );
+            }
+            _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+        check_name(op.newname())?;
+
+        let mut inodes = self.inodes();
+
+        let ino = inodes
+            .get(op.parent())
+            .ok_or_else(no_entry)?
+            .dir()?
+            .get(op.name())
+            .ok_or_else(no_entry)?;
+        let is_dir = matches!(
+            inodes.get(ino).map(|node| &node.kind),
+            Some(NodeKind::Directory(..))
+        );
+
+        if is_dir {
+            check_subtree(&inodes, ino, op.newparent())?;
+        }
+
+        let target = inodes
+            .get(op.newparent())
+            .ok_or_else(no_entry)?
+            .dir()?
+            .get(op.newname());
+        if let Some(target) = target {
+            if op.flags() & RENAME_NOREPLACE != 0 {
+                return Err(io::Error::from_raw_os_error(libc::EEXIST));
+            }
+            if target == ino {
+                return Ok(());
+            }
+            unlink_entry(&mut inodes, op.newparent(), op.newname(), is_dir)?;
+        }
+
+        let now = now();
+
+        let parent = inodes
+            .get_mut(op.parent())
+            .unwrap_or_else(|| unreachable!());
+        parent.dir_mut()?.children.remove(op.name());
+        if is_dir {
+            parent.nlink -= 1;
+        }
+        parent.attr.mtime = now;
+        parent.attr.ctime = now;
+
+        let newparent = inodes
+            .get_mut(op.newparent())
+            .unwrap_or_else(|| unreachable!());
+        newparent
+            .dir_mut()?
+            .children
+            .insert(op.newname().into(), ino);
+        if is_dir {
+            newparent.nlink += 1;
+        }
+        newparent.attr.mtime = now;
+        newparent.attr.ctime = now;
+
+        let node = inodes.get_mut(ino).unwrap_or_else(|| unreachable!());
+        if let NodeKind::Directory(ref mut dir) = node.kind {
+            dir.parent = op.newparent();
+        }
+        node.attr.ctime = now;
+
+        Ok(())
+    }
+
+    async fn link(&self, _: &Context<'_>, op: op::Link<'_>) -> io::Result<EntryOut> {
+        check_name(op.newname())?;
+        let mut inodes = self.inodes();
+
+        if let NodeKind::Directory(..) = inodes.get(op.ino()).ok_or_else(no_entry)?.kind {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+
+        let now = now();
+        let newparent = inodes.get_mut(op.newparent()).ok_or_else(no_entry)?;
+        match newparent.dir_mut()?.children.entry(op.newname().into()) {
+            btree_map::Entry::Occupied(..) => {
+                return Err(io::Error::from_raw_os_error(libc::EEXIST))
+            }
+            btree_map::Entry::Vacant(entry) => {
+                entry.insert(op.ino());
+            }
+        }
+        newparent.attr.mtime = now;
+        newparent.attr.ctime = now;
+
+        let node = inodes.get_mut(op.ino()).unwrap_or_else(|| unreachable!());
+        node.nlink += 1;
+        node.attr.ctime = now;
+
+        self.entry_out(&mut inodes, op.ino())
+    }
+
+    async fn open(&self, _: &Context<'_>, op: op::Open<'_>) -> io::Result<OpenOut> {
+        let mut inodes = self.inodes();
+        let node = inodes.get_mut(op.ino()).ok_or_else(no_entry)?;
+        let data = node.file_mut()?;
+        if op.flags() as i32 & libc::O_TRUNC != 0 {
+            data.clear();
+            node.attr.mtime = now();
+        }
+        Ok(OpenOut::default())
+    }
+
+    async fn read(&self, _: &Context<'_>, op: op::Read<'_>) -> io::Result<Vec<u8>> {
+        let mut inodes = self.inodes();
+        let node = inodes.get_mut(op.ino()).ok_or_else(no_entry)?;
+        let data = node.file_mut()?;
+
+        let offset = match usize::try_from(op.offset()) {
+            Ok(offset) if offset < data.len() => offset,
+            _ => return Ok(vec![]),
+        };
+        let end = std::cmp::min(data.len(), offset.saturating_add(op.size() as usize));
+        let content = data[offset..end].to_owned();
+        node.attr.atime = now();
+
+        Ok(content)
+    }
+
+    async fn write(
+        &self,
+        _: &Context<'_>,
+        op: op::Write<'_>,
+        data: Data<'_>,
+    ) -> io::Result<WriteOut> {
+        let mut buf = Vec::with_capacity(op.size() as usize);
+        data.take(op.size() as u64).read_to_end(&mut buf)?;
+
+        let mut inodes = self.inodes();
+        let node = inodes.get_mut(op.ino()).ok_or_else(no_entry)?;
+        let content = node.file_mut()?;
+
+        let end = self.file_end(op.offset(), buf.len())?;
+        let offset = end - buf.len();
+        if content.len() < end {
+            content.resize(end, 0);
+        }
+        content[offset..end].copy_from_slice(&buf[..]);
+
+        let now = now();
+        node.attr.mtime = now;
+        node.attr.ctime = now;
+
+        let mut out = WriteOut::default();
+        out.size(buf.len() as u32);
+        Ok(out)
+    }
+
+    async fn release(&self, _: &Context<'_>, _: op::Release<'_>) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn statfs(&self, _: &Context<'_>, _: op::Statfs<'_>) -> io::Result<StatfsOut> {
+        let inodes = self.inodes();
+        let blocks: u64 = inodes
+            .iter()
+            .map(|(_, node)| div_round_up(node.size(), BLOCK_SIZE as u64))
+            .sum();
+
+        let mut out = StatfsOut::default();
+        let statfs = out.statfs();
+        statfs.bsize(BLOCK_SIZE);
+        statfs.frsize(BLOCK_SIZE);
+        statfs.blocks(blocks);
+        statfs.files(inodes.len() as u64);
+        statfs.namelen(NAME_MAX as u32);
+        Ok(out)
+    }
+
+    async fn fsync(&self, _: &Context<'_>, _: op::Fsync<'_>) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn setxattr(&self, _: &Context<'_>, op: op::Setxattr<'_>) -> io::Result<()> {
+        let mut inodes = self.inodes();
+        let node = inodes.get_mut(op.ino()).ok_or_else(no_entry)?;
+
+        let flags = op.flags() as i32;
+        match node.xattrs.entry(op.name().into()) {
+            hash_map::Entry::Occupied(..) if flags & libc::XATTR_CREATE != 0 => {
+                return Err(io::Error::from_raw_os_error(libc::EEXIST));
+            }
+            hash_map::Entry::Occupied(mut entry) => {
+                *entry.get_mut() = op.value().to_owned();
+            }
+            hash_map::Entry::Vacant(..) if flags & libc::XATTR_REPLACE != 0 => {
+                return Err(io::Error::from_raw_os_error(libc::ENODATA));
+            }
+            hash_map::Entry::Vacant(entry) => {
+                entry.insert(op.value().to_owned());
+            }
+        }
+        node.attr.ctime = now();
+
+        Ok(())
+    }
+
+    async fn getxattr(
+        &self,
+        _: &Context<'_>,
+        op: op::Getxattr<'_>,
+    ) -> io::Result<Either<XattrOut, Vec<u8>>> {
+        let inodes = self.inodes();
+        let node = inodes.get(op.ino()).ok_or_else(no_entry)?;
+        let value = node
+            .xattrs
+            .get(op.name())
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENODATA))?;
+        xattr_reply(op.size(), value.clone())
+    }
+
+    async fn listxattr(
+        &self,
+        _: &Context<'_>,
+        op: op::Listxattr<'_>,
+    ) -> io::Result<Either<XattrOut, Vec<u8>>> {
+        let inodes = self.inodes();
+        let node = inodes.get(op.ino()).ok_or_else(no_entry)?;
+        let mut names = vec![];
+        for name in node.xattrs.keys() {
+            names.extend_from_slice(name.as_bytes());
+            names.push(b'\0');
+        }
+        xattr_reply(op.size(), names)
+    }
+
+    async fn removexattr(&self, _: &Context<'_>, op: op::Removexattr<'_>) -> io::Result<()> {
+        let mut inodes = self.inodes();
+        let node = inodes.get_mut(op.ino()).ok_or_else(no_entry)?;
+        match node.xattrs.remove(op.name()) {
+            Some(..) => {
+                node.attr.ctime = now();
+                Ok(())
+            }
+            None => Err(io::Error::from_raw_os_error(libc::ENODATA)),
+        }
+    }
+
+    async fn flush(&self, _: &Context<'_>, _: op::Flush<'_>) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn opendir(&self, _: &Context<'_>, op: op::Opendir<'_>) -> io::Result<OpenOut> {
+        let inodes = self.inodes();
+        let node = inodes.get(op.ino()).ok_or_else(no_entry)?;
+        let dir = node.dir()?;
+
+        let mut entries = Vec::with_capacity(dir.children.len() + 2);
+        entries.push(DirEntry {
+            name: ".".into(),
+            ino: op.ino(),
+            typ: dir_type(libc::S_IFDIR),
+        });
+        entries.push(DirEntry {
+            name: "..".into(),
+            ino: dir.parent,
+            typ: dir_type(libc::S_IFDIR),
+        });
+        for (name, &ino) in &dir.children {
+            let mode = inodes.get(ino).map_or(0, |node| node.attr.mode);
+            entries.push(DirEntry {
+                name: name.clone(),
+                ino,
+                typ: dir_type(mode),
+            });
+        }
+
+        let mut out = OpenOut::default();
+        out.fh(self.dirs.insert(entries));
+        Ok(out)
+    }
+
+    async fn readdir(&self, _: &Context<'_>, op: op::Readdir<'_>) -> io::Result<ReaddirOut> {
+        let entries = self.dirs.get(op.fh())?;
+
+        let mut out = ReaddirOut::new(op.size() as usize);
+        for (i, entry) in entries.iter().enumerate().skip(op.offset() as usize) {
+            if out.entry(&entry.name, entry.ino, entry.typ, i as u64 + 1) {
+                break;
+            }
+        }
+        Ok(out)
+    }
+
+    async fn releasedir(&self, _: &Context<'_>, op: op::Releasedir<'_>) -> io::Result<()> {
+        self.dirs.remove(op.fh())?;
+        Ok(())
+    }
+
+    async fn fsyncdir(&self, _: &Context<'_>, _: op::Fsyncdir<'_>) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn create(
+        &self,
+        cx: &Context<'_>,
+        op: op::Create<'_>,
+    ) -> io::Result<(EntryOut, OpenOut)> {
+        let mode = op.mode() & !op.umask();
+        let entry = self.make_entry(cx, op.parent(), op.name(), NodeKind::File(vec![]), mode)?;
+        Ok((entry, OpenOut::default()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{service::FilesystemService, testing::Harness};
+    use polyfuse_kernel::{
+        fuse_attr_out, fuse_opcode, fuse_rename2_in, fuse_setattr_in, fuse_write_out, FATTR_SIZE,
+    };
+    use polyfuse_test::RequestBuilder;
+
+    fn file(fs: &MemFs, parent: u64, name: &str) -> u64 {
+        fs.insert(parent, name.as_ref(), NodeKind::File(vec![]), 0o644, 0, 0)
+            .unwrap()
+    }
+
+    fn dir(fs: &MemFs, parent: u64, name: &str) -> u64 {
+        let kind = NodeKind::Directory(Directory::new());
+        fs.insert(parent, name.as_ref(), kind, 0o755, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn insert_nodes() {
+        let fs = MemFs::new();
+        let foo = dir(&fs, ROOT_INO, "foo");
+        let bar = file(&fs, foo, "bar");
+
+        assert_eq!(fs.with_node(ROOT_INO, |node| node.nlink()), Some(3));
+        assert_eq!(
+            fs.with_node(foo, |node| node.dir().unwrap().get("bar".as_ref())),
+            Some(Some(bar))
+        );
+        assert_eq!(
+            fs.with_node(bar, |node| node.mode()),
+            Some(libc::S_IFREG | 0o644)
+        );
+        assert!(fs
+            .insert(foo, "bar".as_ref(), NodeKind::Special, 0, 0, 0)
+            .is_err());
+
+        let long = "a".repeat(NAME_MAX + 1);
+        let err = fs
+            .insert(foo, long.as_ref(), NodeKind::Special, 0, 0, 0)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENAMETOOLONG));
+    }
+
+    #[test]
+    fn keep_unlinked_node_until_forget() {
+        let fs = MemFs::new();
+        let foo = file(&fs, ROOT_INO, "foo");
+        let _ = fs.entry_out(&mut fs.inodes(), foo).unwrap();
+
+        unlink_entry(&mut fs.inodes(), ROOT_INO, "foo".as_ref(), false).unwrap();
+        assert_eq!(fs.with_node(foo, |node| node.nlink()), Some(0));
+
+        assert_eq!(fs.inodes().unref(foo, 1), Some(0));
+        drop_link(&mut fs.inodes(), foo);
+        assert!(fs.with_node(foo, |_| ()).is_none());
+    }
+
+    #[test]
+    fn rmdir_not_empty() {
+        let fs = MemFs::new();
+        let foo = dir(&fs, ROOT_INO, "foo");
+        let _bar = file(&fs, foo, "bar");
+
+        let err = unlink_entry(&mut fs.inodes(), ROOT_INO, "foo".as_ref(), true).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTEMPTY));
+        let err = unlink_entry(&mut fs.inodes(), ROOT_INO, "foo".as_ref(), false).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EISDIR));
+    }
+
+    fn rename2(
+        parent: u64,
+        name: &str,
+        newparent: u64,
+        newname: &str,
+        flags: u32,
+    ) -> RequestBuilder {
+        let mut req = RequestBuilder::new(fuse_opcode::FUSE_RENAME2);
+        req.nodeid(parent)
+            .arg(&fuse_rename2_in {
+                newdir: newparent,
+                flags,
+                ..Default::default()
+            })
+            .name(name)
+            .name(newname);
+        req
+    }
+
+    #[test]
+    fn rename_exchange() {
+        let harness = Harness::new();
+        let service = FilesystemService::new(MemFs::new());
+        let fs = service.get_ref();
+        let foo = dir(fs, ROOT_INO, "foo");
+        let bar = file(fs, ROOT_INO, "bar");
+        let baz = dir(fs, foo, "baz");
+
+        let reply = harness
+            .call(&service, &rename2(ROOT_INO, "bar", foo, "baz", 2))
+            .unwrap();
+        assert_eq!(reply.error(), 0);
+        assert_eq!(
+            fs.with_node(ROOT_INO, |node| node.dir().unwrap().get("bar".as_ref())),
+            Some(Some(baz))
+        );
+        assert_eq!(
+            fs.with_node(foo, |node| node.dir().unwrap().get("baz".as_ref())),
+            Some(Some(bar))
+        );
+        assert_eq!(
+            fs.with_node(baz, |node| node.dir().unwrap().parent),
+            Some(ROOT_INO)
+        );
+        assert_eq!(fs.with_node(ROOT_INO, |node| node.nlink()), Some(4));
+        assert_eq!(fs.with_node(foo, |node| node.nlink()), Some(2));
+
+        // The directory cannot be swapped into its own subtree.
+        let reply = harness
+            .call(&service, &rename2(ROOT_INO, "foo", foo, "baz", 2))
+            .unwrap();
+        assert_eq!(reply.error(), libc::EINVAL);
+
+        let reply = harness
+            .call(&service, &rename2(ROOT_INO, "bar", foo, "qux", 2))
+            .unwrap();
+        assert_eq!(reply.error(), libc::ENOENT);
+        let reply = harness
+            .call(&service, &rename2(ROOT_INO, "bar", foo, "baz", 3))
+            .unwrap();
+        assert_eq!(reply.error(), libc::EINVAL);
+    }
+
+    fn truncate(ino: u64, size: u64) -> RequestBuilder {
+        let mut req = RequestBuilder::new(fuse_opcode::FUSE_SETATTR);
+        req.nodeid(ino).arg(&fuse_setattr_in {
+            valid: FATTR_SIZE,
+            size,
+            ..Default::default()
+        });
+        req
+    }
+
+    #[test]
+    fn write_and_read() {
+        let harness = Harness::new();
+        let service = FilesystemService::new(MemFs::new());
+        let foo = file(service.get_ref(), ROOT_INO, "foo");
+
+        // Writing beyond the end fills the gap with zeros.
+        let reply = harness
+            .call(&service, &RequestBuilder::write(foo, 0, 2, b"abc"))
+            .unwrap();
+        assert_eq!(reply.error(), 0);
+        assert_eq!(reply.arg::<fuse_write_out>().unwrap().size, 3);
+
+        let reply = harness
+            .call(&service, &RequestBuilder::read(foo, 0, 1, 10))
+            .unwrap();
+        assert_eq!(reply.data(), b"\0abc");
+        let reply = harness
+            .call(&service, &RequestBuilder::read(foo, 0, u64::MAX, 10))
+            .unwrap();
+        assert_eq!((reply.error(), reply.data()), (0, &b""[..]));
+
+        let reply = harness.call(&service, &truncate(foo, 2)).unwrap();
+        assert_eq!(reply.arg::<fuse_attr_out>().unwrap().attr.size, 2);
+    }
+
+    #[test]
+    fn file_size_limit() {
+        let harness = Harness::new();
+        let mut fs = MemFs::new();
+        fs.max_file_size(16);
+        let service = FilesystemService::new(fs);
+        let foo = file(service.get_ref(), ROOT_INO, "foo");
+
+        let write = |offset, data: &[u8]| {
+            let req = RequestBuilder::write(foo, 0, offset, data);
+            harness.call(&service, &req).unwrap().error()
+        };
+        assert_eq!(write(u64::MAX - 1, b"abc"), libc::EFBIG);
+        assert_eq!(write(14, b"abc"), libc::EFBIG);
+        assert_eq!(write(13, b"abc"), 0);
+
+        let reply = harness.call(&service, &truncate(foo, u64::MAX)).unwrap();
+        assert_eq!(reply.error(), libc::EFBIG);
+        let reply = harness.call(&service, &truncate(foo, 17)).unwrap();
+        assert_eq!(reply.error(), libc::EFBIG);
+        assert_eq!(
+            service.get_ref().with_node(foo, |node| node.size()),
+            Some(16)
+        );
+    }
+}
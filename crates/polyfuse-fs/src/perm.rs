@@ -0,0 +1,1217 @@
+//! Permission checking for mounts without `default_permissions`.
+//!
+//! When the filesystem is mounted without `-o default_permissions`, the
+//! kernel does not check the access permission of files and the filesystem
+//! is responsible for it. The functions in this module implement the POSIX
+//! permission algorithm against the attributes of the inode and the
+//! credentials of the caller.
+//!
+//! All checks return `EACCES` (or `EPERM` for the checks about ownership)
+//! when the access is denied, so the result can be propagated with `?`.
+//!
+//! [`PermissionLayer`] applies the checks to the operations in front of the
+//! filesystem, only when the kernel does not check them by itself:
+//!
+//! ```no_run
+//! use polyfuse::{KernelConfig, Session};
+//! use polyfuse_fs::{
+//!     memfs::MemFs,
+//!     perm::PermissionLayer,
+//!     service::{FilesystemService, Layer},
+//! };
+//!
+//! # async fn run() -> std::io::Result<()> {
+//! let session = Session::mount("/mnt".into(), KernelConfig::default())?;
+//! let service = PermissionLayer::for_session(&session).layer(FilesystemService::new(MemFs::new()));
+//! while let Some(req) = session.next_request()? {
+//!     polyfuse_fs::service::serve(&service, &req).await?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    service::{Layer, Reply, Service},
+    Context,
+};
+use polyfuse::{
+    op::{self, GetattrArgs, SetAttrTime},
+    reply::{AttrOut, FileAttr},
+    Data, Operation, Session,
+};
+use std::{
+    collections::{hash_map, HashMap},
+    ffi::OsStr,
+    fmt, fs, io,
+    os::unix::prelude::*,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The permission to read the file.
+pub const MAY_READ: u32 = libc::R_OK as u32;
+
+/// The permission to write the file.
+pub const MAY_WRITE: u32 = libc::W_OK as u32;
+
+/// The permission to execute the file or search the directory.
+pub const MAY_EXEC: u32 = libc::X_OK as u32;
+
+/// The credentials of the caller used in the permission checks.
+#[derive(Debug, Clone, Copy)]
+pub struct Credentials<'a> {
+    uid: u32,
+    gid: u32,
+    groups: &'a [u32],
+}
+
+impl<'a> Credentials<'a> {
+    /// Create a credentials with the specified user and group.
+    pub fn new(uid: u32, gid: u32) -> Self {
+        Self {
+            uid,
+            gid,
+            groups: &[],
+        }
+    }
+
+    /// Create a credentials from the caller of the request.
+    pub fn from_context(cx: &Context<'_>) -> Self {
+        Self::new(cx.uid(), cx.gid())
+    }
+
+    /// Set the supplementary groups of the caller.
+    ///
+    /// FUSE requests carry only the primary group, so the supplementary
+    /// groups must be resolved by the filesystem if needed.
+    pub fn groups(self, groups: &'a [u32]) -> Self {
+        Self { groups, ..self }
+    }
+
+    /// Return the user ID.
+    #[inline]
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    /// Return the primary group ID.
+    #[inline]
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    /// Return whether the caller is the superuser.
+    #[inline]
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    /// Return whether the caller is a member of the specified group.
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+}
+
+/// The tag of an ACL entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclTag {
+    /// The owner of the file (`ACL_USER_OBJ`).
+    UserObj,
+    /// A named user (`ACL_USER`).
+    User(u32),
+    /// The owning group of the file (`ACL_GROUP_OBJ`).
+    GroupObj,
+    /// A named group (`ACL_GROUP`).
+    Group(u32),
+    /// The upper bound of the permissions granted to the group class (`ACL_MASK`).
+    Mask,
+    /// The others (`ACL_OTHER`).
+    Other,
+}
+
+/// An entry of access ACL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AclEntry {
+    tag: AclTag,
+    perm: u32,
+}
+
+impl AclEntry {
+    /// Create an ACL entry.
+    ///
+    /// `perm` is a combination of `MAY_READ`, `MAY_WRITE` and `MAY_EXEC`.
+    pub fn new(tag: AclTag, perm: u32) -> Self {
+        Self {
+            tag,
+            perm: perm & 0o7,
+        }
+    }
+
+    /// Return the tag of this entry.
+    #[inline]
+    pub fn tag(&self) -> AclTag {
+        self.tag
+    }
+
+    /// Return the permission bits of this entry.
+    #[inline]
+    pub fn perm(&self) -> u32 {
+        self.perm
+    }
+}
+
+/// The name of the extended attribute holding the access ACL.
+pub const XATTR_POSIX_ACL_ACCESS: &str = "system.posix_acl_access";
+
+/// The name of the extended attribute holding the default ACL of a directory.
+pub const XATTR_POSIX_ACL_DEFAULT: &str = "system.posix_acl_default";
+
+const ACL_XATTR_VERSION: u32 = 0x0002;
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+const ACL_XATTR_HEADER_LEN: usize = 4;
+const ACL_XATTR_ENTRY_LEN: usize = 8;
+
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+impl AclTag {
+    fn to_raw(self) -> (u16, u32) {
+        match self {
+            AclTag::UserObj => (ACL_USER_OBJ, ACL_UNDEFINED_ID),
+            AclTag::User(uid) => (ACL_USER, uid),
+            AclTag::GroupObj => (ACL_GROUP_OBJ, ACL_UNDEFINED_ID),
+            AclTag::Group(gid) => (ACL_GROUP, gid),
+            AclTag::Mask => (ACL_MASK, ACL_UNDEFINED_ID),
+            AclTag::Other => (ACL_OTHER, ACL_UNDEFINED_ID),
+        }
+    }
+
+    fn from_raw(tag: u16, id: u32) -> Option<Self> {
+        match tag {
+            ACL_USER_OBJ => Some(AclTag::UserObj),
+            ACL_USER => Some(AclTag::User(id)),
+            ACL_GROUP_OBJ => Some(AclTag::GroupObj),
+            ACL_GROUP => Some(AclTag::Group(id)),
+            ACL_MASK => Some(AclTag::Mask),
+            ACL_OTHER => Some(AclTag::Other),
+            _ => None,
+        }
+    }
+}
+
+/// Decode the value of `system.posix_acl_access` or `system.posix_acl_default`.
+///
+/// The value is the binary format shared with the kernel, i.e. a version
+/// header followed by the `(tag, perm, id)` triples in little endian. The
+/// entries are validated in the same way as the kernel does before they
+/// are returned, so they can be passed to [`check_access_acl`] or stored as
+/// is. An empty ACL is returned for the value without any entries.
+///
+/// `EOPNOTSUPP` is returned for an unknown version, and `EINVAL` for a
+/// malformed or invalid ACL.
+pub fn decode_acl(value: &[u8]) -> io::Result<Vec<AclEntry>> {
+    if value.len() < ACL_XATTR_HEADER_LEN {
+        return Err(einval());
+    }
+    let version = u32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+    if version != ACL_XATTR_VERSION {
+        return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+    }
+
+    let entries = value[ACL_XATTR_HEADER_LEN..].chunks_exact(ACL_XATTR_ENTRY_LEN);
+    if !entries.remainder().is_empty() {
+        return Err(einval());
+    }
+    let acl = entries
+        .map(|raw| {
+            let tag = u16::from_le_bytes([raw[0], raw[1]]);
+            let perm = u16::from_le_bytes([raw[2], raw[3]]);
+            let id = u32::from_le_bytes([raw[4], raw[5], raw[6], raw[7]]);
+            if perm & !0o7 != 0 {
+                return Err(einval());
+            }
+            let tag = AclTag::from_raw(tag, id).ok_or_else(einval)?;
+            Ok(AclEntry::new(tag, u32::from(perm)))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    if !acl.is_empty() {
+        validate_acl(&acl)?;
+    }
+    Ok(acl)
+}
+
+/// Encode the ACL into the value of `system.posix_acl_access` or
+/// `system.posix_acl_default`.
+///
+/// `EINVAL` is returned if the ACL is invalid. See [`decode_acl`] for the
+/// rules.
+pub fn encode_acl(acl: &[AclEntry]) -> io::Result<Vec<u8>> {
+    if !acl.is_empty() {
+        validate_acl(acl)?;
+    }
+
+    let mut value = Vec::with_capacity(ACL_XATTR_HEADER_LEN + ACL_XATTR_ENTRY_LEN * acl.len());
+    value.extend_from_slice(&ACL_XATTR_VERSION.to_le_bytes());
+    for entry in acl {
+        let (tag, id) = entry.tag.to_raw();
+        value.extend_from_slice(&tag.to_le_bytes());
+        value.extend_from_slice(&(entry.perm as u16).to_le_bytes());
+        value.extend_from_slice(&id.to_le_bytes());
+    }
+    Ok(value)
+}
+
+/// Check the ordering of the entries, as `posix_acl_valid` of the kernel.
+///
+/// The entries must be sorted by the tag in the order of `UserObj`,
+/// `User`, `GroupObj`, `Group`, `Mask` and `Other`, where the named
+/// entries are sorted by their IDs without duplicates. Exactly one of
+/// each of `UserObj`, `GroupObj` and `Other` is required, and `Mask` is
+/// required if there are any named entries.
+fn validate_acl(acl: &[AclEntry]) -> io::Result<()> {
+    let mut prev: Option<(u16, u32)> = None;
+    let mut named = false;
+    let mut has_mask = false;
+    let mut required = 0u16;
+
+    for entry in acl {
+        let (tag, id) = entry.tag.to_raw();
+        if let Some((prev_tag, prev_id)) = prev {
+            // Only the named entries may repeat, in ascending order.
+            let repeated =
+                prev_tag == tag && ((tag != ACL_USER && tag != ACL_GROUP) || prev_id >= id);
+            if prev_tag > tag || repeated {
+                return Err(einval());
+            }
+        }
+        match tag {
+            ACL_USER | ACL_GROUP => named = true,
+            ACL_MASK => has_mask = true,
+            _ => required |= tag,
+        }
+        prev = Some((tag, id));
+    }
+
+    if required != ACL_USER_OBJ | ACL_GROUP_OBJ | ACL_OTHER || (named && !has_mask) {
+        return Err(einval());
+    }
+    Ok(())
+}
+
+#[inline]
+fn einval() -> io::Error {
+    io::Error::from_raw_os_error(libc::EINVAL)
+}
+
+#[inline]
+fn eacces() -> io::Error {
+    io::Error::from_raw_os_error(libc::EACCES)
+}
+
+#[inline]
+fn eperm() -> io::Error {
+    io::Error::from_raw_os_error(libc::EPERM)
+}
+
+#[inline]
+fn granted(perm: u32, mask: u32) -> io::Result<()> {
+    if mask & !perm & 0o7 == 0 {
+        Ok(())
+    } else {
+        Err(eacces())
+    }
+}
+
+/// The superuser bypasses the checks except executing a regular file
+/// without any execute bits.
+fn check_root(attr: &FileAttr, mask: u32) -> io::Result<()> {
+    let mode = attr.get_mode();
+    if mask & MAY_EXEC != 0 && mode & libc::S_IFMT != libc::S_IFDIR && mode & 0o111 == 0 {
+        return Err(eacces());
+    }
+    Ok(())
+}
+
+/// Check whether the caller is permitted to access the inode.
+///
+/// `mask` is a combination of `MAY_READ`, `MAY_WRITE` and `MAY_EXEC`,
+/// which is compatible with the value of `op::Access::mask`.
+pub fn check_access(cred: &Credentials<'_>, attr: &FileAttr, mask: u32) -> io::Result<()> {
+    if cred.is_root() {
+        return check_root(attr, mask);
+    }
+
+    let mode = attr.get_mode();
+    let perm = if cred.uid == attr.get_uid() {
+        mode >> 6
+    } else if cred.in_group(attr.get_gid()) {
+        mode >> 3
+    } else {
+        mode
+    };
+    granted(perm, mask)
+}
+
+/// Check whether the caller is permitted to access the inode, taking
+/// the access ACL of the inode into account.
+///
+/// If `acl` is empty, this function is equivalent to [`check_access`].
+pub fn check_access_acl(
+    cred: &Credentials<'_>,
+    attr: &FileAttr,
+    acl: &[AclEntry],
+    mask: u32,
+) -> io::Result<()> {
+    if acl.is_empty() {
+        return check_access(cred, attr, mask);
+    }
+    if cred.is_root() {
+        return check_root(attr, mask);
+    }
+
+    let mode = attr.get_mode();
+    let find = |tag: AclTag| acl.iter().find(|e| e.tag == tag).map(|e| e.perm);
+
+    if cred.uid == attr.get_uid() {
+        let perm = find(AclTag::UserObj).unwrap_or(mode >> 6);
+        return granted(perm, mask);
+    }
+
+    let acl_mask = find(AclTag::Mask).unwrap_or(0o7);
+
+    if let Some(perm) = find(AclTag::User(cred.uid)) {
+        return granted(perm & acl_mask, mask);
+    }
+
+    // The access is granted if any of the matched group entries contains
+    // the requested permissions, and denied if none of them contains.
+    let mut matched = false;
+    for entry in acl {
+        let gid = match entry.tag {
+            AclTag::GroupObj => attr.get_gid(),
+            AclTag::Group(gid) => gid,
+            _ => continue,
+        };
+        if cred.in_group(gid) {
+            if granted(entry.perm & acl_mask, mask).is_ok() {
+                return Ok(());
+            }
+            matched = true;
+        }
+    }
+    if matched {
+        return Err(eacces());
+    }
+
+    granted(find(AclTag::Other).unwrap_or(mode), mask)
+}
+
+/// Check whether the caller owns the inode.
+///
+/// This is the check required for changing the mode, the ownership
+/// or setting the timestamps to arbitrary values.
+pub fn check_owner(cred: &Credentials<'_>, attr: &FileAttr) -> io::Result<()> {
+    if cred.is_root() || cred.uid == attr.get_uid() {
+        Ok(())
+    } else {
+        Err(eperm())
+    }
+}
+
+/// Check whether the caller is permitted to remove or rename an entry
+/// in the directory.
+///
+/// In addition to the write and search permissions of the directory,
+/// the entry in a sticky directory can be removed only by the owner of
+/// the entry or the directory.
+pub fn check_remove(cred: &Credentials<'_>, dir: &FileAttr, target: &FileAttr) -> io::Result<()> {
+    check_access(cred, dir, MAY_WRITE | MAY_EXEC)?;
+
+    if dir.get_mode() & libc::S_ISVTX != 0
+        && !cred.is_root()
+        && cred.uid != dir.get_uid()
+        && cred.uid != target.get_uid()
+    {
+        return Err(eperm());
+    }
+
+    Ok(())
+}
+
+/// Check whether the caller is permitted to create an entry in the directory.
+pub fn check_create(cred: &Credentials<'_>, dir: &FileAttr) -> io::Result<()> {
+    check_access(cred, dir, MAY_WRITE | MAY_EXEC)
+}
+
+/// Check the permission required to open the inode with the specified flags.
+pub fn check_open(cred: &Credentials<'_>, attr: &FileAttr, flags: u32) -> io::Result<()> {
+    let mask = match flags as i32 & libc::O_ACCMODE {
+        libc::O_RDONLY => MAY_READ,
+        libc::O_WRONLY => MAY_WRITE,
+        _ => MAY_READ | MAY_WRITE,
+    };
+    let mask = if flags as i32 & libc::O_TRUNC != 0 {
+        mask | MAY_WRITE
+    } else {
+        mask
+    };
+    check_access(cred, attr, mask)
+}
+
+/// Check whether the caller is permitted to change the owner and the group
+/// of the inode to `uid` and `gid`.
+///
+/// Only the superuser can change the owner, and the owner can change the
+/// group to one of its own groups. The IDs equal to the current ones are
+/// always permitted.
+pub fn check_chown(
+    cred: &Credentials<'_>,
+    attr: &FileAttr,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> io::Result<()> {
+    if cred.is_root() {
+        return Ok(());
+    }
+    if matches!(uid, Some(uid) if uid != attr.get_uid()) {
+        return Err(eperm());
+    }
+    if let Some(gid) = gid {
+        if gid != attr.get_gid() && (cred.uid != attr.get_uid() || !cred.in_group(gid)) {
+            return Err(eperm());
+        }
+    }
+    Ok(())
+}
+
+/// Check the permission required by `Setattr`, as `setattr_prepare` of the
+/// kernel.
+fn check_setattr(cred: &Credentials<'_>, attr: &FileAttr, op: &op::Setattr<'_>) -> io::Result<()> {
+    check_chown(cred, attr, op.uid(), op.gid())?;
+    if op.mode().is_some() {
+        check_owner(cred, attr)?;
+    }
+
+    let times = [op.atime(), op.mtime()];
+    if times
+        .iter()
+        .any(|time| matches!(time, Some(SetAttrTime::Timespec(..))))
+    {
+        check_owner(cred, attr)?;
+    } else if times.iter().any(Option::is_some) && check_owner(cred, attr).is_err() {
+        // Touching with the current time is also permitted to the writers.
+        check_access(cred, attr, MAY_WRITE)?;
+    }
+
+    // The truncation through an open file has been checked at the open.
+    if op.size().is_some() && op.fh().is_none() {
+        check_access(cred, attr, MAY_WRITE)?;
+    }
+    Ok(())
+}
+
+/// Read the supplementary groups of the process from `/proc/<pid>/status`.
+///
+/// The groups are returned only if the filesystem user and group IDs of
+/// the process are equal to `uid` and `gid`, since the process may have
+/// changed its credentials or exited (and the PID reused) after issuing
+/// the request. `None` is returned in that case.
+pub fn supplementary_groups(pid: u32, uid: u32, gid: u32) -> io::Result<Option<Vec<u32>>> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid))?;
+    Ok(parse_status(&status).and_then(|(fsuid, fsgid, groups)| {
+        if fsuid == uid && fsgid == gid {
+            Some(groups)
+        } else {
+            None
+        }
+    }))
+}
+
+/// Extract the filesystem UID/GID and the supplementary groups.
+fn parse_status(status: &str) -> Option<(u32, u32, Vec<u32>)> {
+    let mut fsuid = None;
+    let mut fsgid = None;
+    let mut groups = None;
+    for line in status.lines() {
+        let mut fields = line.split_whitespace();
+        match fields.next() {
+            // Uid: real effective saved filesystem
+            Some("Uid:") => fsuid = fields.nth(3).and_then(|id| id.parse().ok()),
+            Some("Gid:") => fsgid = fields.nth(3).and_then(|id| id.parse().ok()),
+            Some("Groups:") => {
+                groups = fields
+                    .map(|id| id.parse().ok())
+                    .collect::<Option<Vec<u32>>>();
+            }
+            _ => (),
+        }
+    }
+    Some((fsuid?, fsgid?, groups?))
+}
+
+/// The number of cached entries that triggers the eviction of expired ones.
+const GROUP_CACHE_PRUNE_THRESHOLD: usize = 1024;
+
+struct CachedGroups {
+    uid: u32,
+    gid: u32,
+    groups: Arc<[u32]>,
+    expires: Instant,
+}
+
+/// A cache of the supplementary groups of the callers.
+///
+/// Reading `/proc` on every request is expensive, so the resolved groups are
+/// cached per PID for the specified duration. The cached entry is discarded
+/// when the credentials of the request do not match the ones at the time
+/// of resolution.
+///
+/// The resolution is best-effort: if the process is not visible from
+/// the filesystem daemon (e.g. it lives in another PID namespace and
+/// the request has PID 0) or has already exited, only the primary group
+/// is available and the returned list is empty. This errs on the side
+/// of denying the access.
+///
+/// The groups are resolved against the credentials of the original request,
+/// i.e. before rewriting by the layers like [`IdMapLayer`](crate::idmap::IdMapLayer).
+pub struct GroupCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<u32, CachedGroups>>,
+}
+
+impl fmt::Debug for GroupCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GroupCache")
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+impl GroupCache {
+    /// Create a cache that holds the resolved groups for `ttl`.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Return the supplementary groups of the caller of the request.
+    pub fn get(&self, cx: &Context<'_>) -> Arc<[u32]> {
+        let (pid, uid, gid) = (cx.pid(), cx.req.uid(), cx.req.gid());
+        if pid == 0 {
+            return Arc::from(Vec::new());
+        }
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(cached) = entries.get(&pid) {
+            if cached.uid == uid && cached.gid == gid && cached.expires > now {
+                return cached.groups.clone();
+            }
+        }
+
+        let groups: Arc<[u32]> = match supplementary_groups(pid, uid, gid) {
+            Ok(Some(groups)) => Arc::from(groups),
+            Ok(None) | Err(..) => {
+                entries.remove(&pid);
+                return Arc::from(Vec::new());
+            }
+        };
+
+        if entries.len() >= GROUP_CACHE_PRUNE_THRESHOLD {
+            entries.retain(|_, cached| cached.expires > now);
+        }
+        entries.insert(
+            pid,
+            CachedGroups {
+                uid,
+                gid,
+                groups: groups.clone(),
+                expires: now + self.ttl,
+            },
+        );
+
+        groups
+    }
+
+    /// Discard the cached groups of the process.
+    pub fn invalidate(&self, pid: u32) {
+        self.entries.lock().unwrap().remove(&pid);
+    }
+
+    /// Discard all cached groups.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+/// How long the supplementary groups of the callers are cached by
+/// [`PermissionLayer`].
+const GROUPS_TTL: Duration = Duration::from_secs(1);
+
+/// A layer checking the access permissions of the callers in front of the
+/// filesystem.
+///
+/// The attributes of the inodes are taken from the replies to `Lookup`,
+/// `Getattr`, `Setattr`, `READDIRPLUS` and the operations creating the
+/// inodes, and are dropped when the kernel forgets all the lookups of the
+/// inode. The attributes of the other inodes, such as the root, are fetched
+/// from the wrapped service with `Getattr` before checking the operations on
+/// them. `Access` is replied by the layer itself.
+///
+/// The sticky bit of the directories is not enforced, since the inode
+/// removed by `Unlink`, `Rmdir` or `Rename` is not known from the request,
+/// and the ACLs are not taken into account.
+///
+/// The checks are redundant with `default_permissions`, with which the
+/// kernel checks the permissions by itself, so
+/// [`for_session`](Self::for_session) enables the layer only for the mounts
+/// without the option, and the access control is configured only with the
+/// mount options. [`enabled`](Self::enabled) overrides it.
+#[derive(Debug, Clone)]
+pub struct PermissionLayer {
+    enabled: bool,
+}
+
+impl Default for PermissionLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PermissionLayer {
+    /// Create a layer that always checks the permissions.
+    pub fn new() -> Self {
+        Self { enabled: true }
+    }
+
+    /// Create a layer that checks the permissions unless the session is
+    /// mounted with `default_permissions`.
+    pub fn for_session(session: &Session) -> Self {
+        Self {
+            enabled: !session.default_permissions(),
+        }
+    }
+
+    /// Specify whether to check the permissions, regardless of the mount
+    /// options.
+    pub fn enabled(&mut self, enabled: bool) -> &mut Self {
+        self.enabled = enabled;
+        self
+    }
+
+    /// Return whether the permissions are checked.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+}
+
+impl<S> Layer<S> for PermissionLayer {
+    type Service = PermissionService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        PermissionService {
+            inner,
+            enabled: self.enabled,
+            groups: GroupCache::new(GROUPS_TTL),
+            attrs: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+/// The cached attributes of an inode.
+struct CachedAttr {
+    attr: AttrOut,
+    nlookup: u64,
+}
+
+/// The service produced by [`PermissionLayer`].
+pub struct PermissionService<S> {
+    inner: S,
+    enabled: bool,
+    groups: GroupCache,
+    attrs: Mutex<HashMap<u64, CachedAttr>>,
+}
+
+impl<S> fmt::Debug for PermissionService<S>
+where
+    S: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PermissionService")
+            .field("inner", &self.inner)
+            .field("enabled", &self.enabled)
+            .finish()
+    }
+}
+
+#[crate::async_trait]
+impl<S> Service for PermissionService<S>
+where
+    S: Service,
+{
+    async fn call(&self, cx: &Context<'_>, op: Operation<'_, Data<'_>>) -> io::Result<Reply> {
+        if !self.enabled {
+            return self.inner.call(cx, op).await;
+        }
+
+        for ino in check_targets(&op).iter().flatten() {
+            self.fetch(cx, *ino).await?;
+        }
+
+        match op {
+            Operation::Access(ref op) => {
+                self.check(cx, op.ino(), |cred, attr| {
+                    check_access(cred, attr, op.mask())
+                })?;
+                return Ok(Reply::Empty);
+            }
+            Operation::Forget(ref forgets) => {
+                let mut attrs = self.attrs.lock().unwrap();
+                for forget in forgets.iter() {
+                    if let hash_map::Entry::Occupied(mut entry) = attrs.entry(forget.ino()) {
+                        let cached = entry.get_mut();
+                        cached.nlookup = cached.nlookup.saturating_sub(forget.nlookup());
+                        if cached.nlookup == 0 {
+                            entry.remove();
+                        }
+                    }
+                }
+            }
+            ref op => self.check_op(cx, op)?,
+        }
+
+        let target = match op {
+            Operation::Getattr(ref op) => Some(op.ino()),
+            Operation::Setattr(ref op) => Some(op.ino()),
+            _ => None,
+        };
+        let mut reply = self.inner.call(cx, op).await?;
+        match reply {
+            Reply::Entry(ref mut out) | Reply::Create(ref mut out, _) => {
+                let ino = out.get_ino();
+                self.record(ino, out.attr(), 1);
+            }
+            Reply::ReaddirPlus(ref mut out) => {
+                // The entries are counted as lookups, except the negative ones.
+                out.for_each_entry(|_, entry| {
+                    let ino = entry.get_ino();
+                    if ino != 0 {
+                        self.record(ino, entry.attr(), 1);
+                    }
+                });
+            }
+            Reply::Attr(ref mut out) => {
+                if let Some(ino) = target {
+                    self.record(ino, out.attr(), 0);
+                }
+            }
+            _ => (),
+        }
+        Ok(reply)
+    }
+}
+
+/// Return the inodes whose attributes are needed to check the operation.
+fn check_targets<T>(op: &Operation<'_, T>) -> [Option<u64>; 2] {
+    match op {
+        Operation::Access(op) => [Some(op.ino()), None],
+        Operation::Lookup(op) => [Some(op.parent()), None],
+        Operation::Open(op) => [Some(op.ino()), None],
+        Operation::Opendir(op) => [Some(op.ino()), None],
+        Operation::Setattr(op) => [Some(op.ino()), None],
+        Operation::Mknod(op) => [Some(op.parent()), None],
+        Operation::Mkdir(op) => [Some(op.parent()), None],
+        Operation::Symlink(op) => [Some(op.parent()), None],
+        Operation::Create(op) => [Some(op.parent()), None],
+        Operation::Link(op) => [Some(op.newparent()), None],
+        Operation::Unlink(op) => [Some(op.parent()), None],
+        Operation::Rmdir(op) => [Some(op.parent()), None],
+        Operation::Rename(op) => [Some(op.parent()), Some(op.newparent())],
+        Operation::Getxattr(op) if is_user_xattr(op.name()) => [Some(op.ino()), None],
+        Operation::Setxattr(op) if is_user_xattr(op.name()) => [Some(op.ino()), None],
+        Operation::Removexattr(op) if is_user_xattr(op.name()) => [Some(op.ino()), None],
+        _ => [None, None],
+    }
+}
+
+impl<S> PermissionService<S>
+where
+    S: Service,
+{
+    /// Fetch the attributes of the inode from the wrapped service, unless
+    /// they are cached.
+    async fn fetch(&self, cx: &Context<'_>, ino: u64) -> io::Result<()> {
+        if self.attrs.lock().unwrap().contains_key(&ino) {
+            return Ok(());
+        }
+        let args = GetattrArgs::new(ino);
+        match self.inner.call(cx, Operation::Getattr(args.op())).await? {
+            Reply::Attr(mut out) => {
+                self.record(ino, out.attr(), 0);
+                Ok(())
+            }
+            reply => {
+                tracing::error!(ino, ?reply, "unexpected reply to Getattr");
+                Err(io::Error::from_raw_os_error(libc::EIO))
+            }
+        }
+    }
+}
+
+impl<S> PermissionService<S> {
+    /// Record the attributes of the inode, adding the lookup count.
+    fn record(&self, ino: u64, attr: &FileAttr, nlookup: u64) {
+        let mut out = AttrOut::default();
+        out.attr().mode(attr.get_mode());
+        out.attr().uid(attr.get_uid());
+        out.attr().gid(attr.get_gid());
+
+        let mut attrs = self.attrs.lock().unwrap();
+        let cached = attrs.entry(ino).or_insert_with(|| CachedAttr {
+            attr: AttrOut::default(),
+            nlookup: 0,
+        });
+        cached.attr = out;
+        cached.nlookup += nlookup;
+    }
+
+    /// Run the check against the attributes of the inode.
+    ///
+    /// The access is denied if the attributes are not known, which happens
+    /// only when the inode has been forgotten after they were fetched.
+    fn check<F>(&self, cx: &Context<'_>, ino: u64, f: F) -> io::Result<()>
+    where
+        F: FnOnce(&Credentials<'_>, &FileAttr) -> io::Result<()>,
+    {
+        let mut attr = match self.attrs.lock().unwrap().get(&ino) {
+            Some(cached) => cached.attr.clone(),
+            None => return Err(io::Error::from_raw_os_error(libc::EACCES)),
+        };
+        let groups = self.groups.get(cx);
+        let cred = Credentials::from_context(cx).groups(&groups);
+        f(&cred, attr.attr())
+    }
+
+    fn check_op<T>(&self, cx: &Context<'_>, op: &Operation<'_, T>) -> io::Result<()> {
+        match op {
+            Operation::Lookup(op) => self.check(cx, op.parent(), |cred, dir| {
+                check_access(cred, dir, MAY_EXEC)
+            }),
+            Operation::Open(op) => self.check(cx, op.ino(), |cred, attr| {
+                check_open(cred, attr, op.flags())
+            }),
+            Operation::Opendir(op) => {
+                self.check(cx, op.ino(), |cred, dir| check_access(cred, dir, MAY_READ))
+            }
+            Operation::Setattr(op) => {
+                self.check(cx, op.ino(), |cred, attr| check_setattr(cred, attr, op))
+            }
+            Operation::Mknod(op) => self.check(cx, op.parent(), check_create),
+            Operation::Mkdir(op) => self.check(cx, op.parent(), check_create),
+            Operation::Symlink(op) => self.check(cx, op.parent(), check_create),
+            Operation::Create(op) => self.check(cx, op.parent(), check_create),
+            Operation::Link(op) => self.check(cx, op.newparent(), check_create),
+            Operation::Unlink(op) => self.check(cx, op.parent(), check_create),
+            Operation::Rmdir(op) => self.check(cx, op.parent(), check_create),
+            Operation::Rename(op) => {
+                self.check(cx, op.parent(), check_create)?;
+                self.check(cx, op.newparent(), check_create)
+            }
+            Operation::Getxattr(op) if is_user_xattr(op.name()) => {
+                self.check(cx, op.ino(), |cred, attr| {
+                    check_access(cred, attr, MAY_READ)
+                })
+            }
+            Operation::Setxattr(op) if is_user_xattr(op.name()) => {
+                self.check(cx, op.ino(), |cred, attr| {
+                    check_access(cred, attr, MAY_WRITE)
+                })
+            }
+            Operation::Removexattr(op) if is_user_xattr(op.name()) => {
+                self.check(cx, op.ino(), |cred, attr| {
+                    check_access(cred, attr, MAY_WRITE)
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn is_user_xattr(name: &OsStr) -> bool {
+    name.as_bytes().starts_with(b"user.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{service::FilesystemService, testing::Harness, Filesystem};
+    use polyfuse::reply::{EntryOut, OpenOut, ReaddirPlusOut};
+    use polyfuse_kernel::{fuse_access_in, fuse_opcode, fuse_read_in};
+    use polyfuse_test::RequestBuilder;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn attr(mode: u32, uid: u32, gid: u32) -> AttrOut {
+        let mut out = AttrOut::default();
+        out.attr().mode(mode);
+        out.attr().uid(uid);
+        out.attr().gid(gid);
+        out
+    }
+
+    #[test]
+    fn mode_bits() {
+        let mut out = attr(libc::S_IFREG | 0o640, 1000, 100);
+        let attr = out.attr();
+
+        let owner = Credentials::new(1000, 1000);
+        assert!(check_access(&owner, attr, MAY_READ | MAY_WRITE).is_ok());
+        assert!(check_access(&owner, attr, MAY_EXEC).is_err());
+
+        let member = Credentials::new(1001, 1001).groups(&[100]);
+        assert!(check_access(&member, attr, MAY_READ).is_ok());
+        assert!(check_access(&member, attr, MAY_WRITE).is_err());
+
+        let other = Credentials::new(1002, 1002);
+        assert!(check_access(&other, attr, MAY_READ).is_err());
+
+        let root = Credentials::new(0, 0);
+        assert!(check_access(&root, attr, MAY_READ | MAY_WRITE).is_ok());
+        assert!(check_access(&root, attr, MAY_EXEC).is_err());
+    }
+
+    #[test]
+    fn ownership() {
+        let mut out = attr(libc::S_IFREG | 0o644, 1000, 100);
+        let attr = out.attr();
+
+        let owner = Credentials::new(1000, 100).groups(&[200]);
+        assert!(check_chown(&owner, attr, Some(1000), Some(200)).is_ok());
+        assert!(check_chown(&owner, attr, None, Some(300)).is_err());
+        assert!(check_chown(&owner, attr, Some(1001), None).is_err());
+
+        let other = Credentials::new(1001, 200);
+        assert!(check_chown(&other, attr, None, Some(100)).is_ok());
+        assert!(check_chown(&other, attr, None, Some(200)).is_err());
+
+        let root = Credentials::new(0, 0);
+        assert!(check_chown(&root, attr, Some(0), Some(0)).is_ok());
+    }
+
+    #[test]
+    fn sticky_directory() {
+        let mut dir = attr(libc::S_IFDIR | libc::S_ISVTX | 0o777, 0, 0);
+        let mut file = attr(libc::S_IFREG | 0o644, 1000, 1000);
+
+        let owner = Credentials::new(1000, 1000);
+        let other = Credentials::new(1001, 1001);
+        assert!(check_remove(&owner, dir.attr(), file.attr()).is_ok());
+        assert_eq!(
+            check_remove(&other, dir.attr(), file.attr())
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EPERM)
+        );
+    }
+
+    #[test]
+    fn acl_mask() {
+        let mut out = attr(libc::S_IFREG | 0o640, 1000, 100);
+        let attr = out.attr();
+        let acl = [
+            AclEntry::new(AclTag::UserObj, 0o6),
+            AclEntry::new(AclTag::User(1001), 0o6),
+            AclEntry::new(AclTag::GroupObj, 0o4),
+            AclEntry::new(AclTag::Group(200), 0o6),
+            AclEntry::new(AclTag::Mask, 0o4),
+            AclEntry::new(AclTag::Other, 0o0),
+        ];
+
+        let named = Credentials::new(1001, 1001);
+        assert!(check_access_acl(&named, attr, &acl, MAY_READ).is_ok());
+        assert!(check_access_acl(&named, attr, &acl, MAY_WRITE).is_err());
+
+        let member = Credentials::new(1002, 200);
+        assert!(check_access_acl(&member, attr, &acl, MAY_READ).is_ok());
+        assert!(check_access_acl(&member, attr, &acl, MAY_WRITE).is_err());
+
+        let other = Credentials::new(1003, 1003);
+        assert!(check_access_acl(&other, attr, &acl, MAY_READ).is_err());
+    }
+
+    #[test]
+    fn acl_xattr() {
+        let acl = vec![
+            AclEntry::new(AclTag::UserObj, 0o6),
+            AclEntry::new(AclTag::User(1001), 0o6),
+            AclEntry::new(AclTag::User(1002), 0o4),
+            AclEntry::new(AclTag::GroupObj, 0o4),
+            AclEntry::new(AclTag::Mask, 0o6),
+            AclEntry::new(AclTag::Other, 0o0),
+        ];
+        let value = encode_acl(&acl).unwrap();
+        assert_eq!(value.len(), 4 + 8 * 6);
+        assert_eq!(&value[..4], &[2, 0, 0, 0]);
+        // ACL_USER, rw-, uid 1001
+        assert_eq!(&value[12..20], &[0x02, 0, 0o6, 0, 0xe9, 0x03, 0, 0]);
+        assert_eq!(decode_acl(&value).unwrap(), acl);
+
+        assert!(decode_acl(&[2, 0, 0, 0]).unwrap().is_empty());
+        assert_eq!(
+            decode_acl(&[1, 0, 0, 0]).unwrap_err().raw_os_error(),
+            Some(libc::EOPNOTSUPP)
+        );
+        assert_eq!(
+            decode_acl(&value[..value.len() - 1])
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EINVAL)
+        );
+
+        let invalid = |acl: &[AclEntry]| encode_acl(acl).unwrap_err().raw_os_error();
+        let mut unsorted = acl.clone();
+        unsorted.swap(1, 2);
+        assert_eq!(invalid(&unsorted), Some(libc::EINVAL));
+        let mut misplaced = acl.clone();
+        misplaced.swap(3, 4);
+        assert_eq!(invalid(&misplaced), Some(libc::EINVAL));
+        let mut without_mask = acl.clone();
+        without_mask.remove(4);
+        assert_eq!(invalid(&without_mask), Some(libc::EINVAL));
+        assert_eq!(invalid(&acl[1..]), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn parse_proc_status() {
+        let status = "Name:\tcat\n\
+                      Uid:\t1000\t1000\t1000\t1001\n\
+                      Gid:\t100\t100\t100\t101\n\
+                      Groups:\t4 24 27 \n";
+        assert_eq!(parse_status(status), Some((1001, 101, vec![4, 24, 27])));
+
+        let status = "Uid:\t0\t0\t0\t0\nGid:\t0\t0\t0\t0\nGroups:\t\n";
+        assert_eq!(parse_status(status), Some((0, 0, vec![])));
+
+        assert_eq!(parse_status("Name:\tcat\n"), None);
+    }
+
+    #[test]
+    fn groups_of_self() {
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let groups = supplementary_groups(std::process::id(), uid, gid).unwrap();
+        assert!(groups.is_some());
+    }
+
+    /// The root directory with the private files `2` and `3` in it, all
+    /// owned by the user `1000`.
+    struct Private {
+        getattrs: Arc<AtomicUsize>,
+    }
+
+    fn private_attr(ino: u64, attr: &mut FileAttr) {
+        let mode = match ino {
+            1 => libc::S_IFDIR | 0o700,
+            _ => libc::S_IFREG | 0o600,
+        };
+        attr.ino(ino);
+        attr.mode(mode);
+        attr.uid(1000);
+        attr.gid(1000);
+    }
+
+    fn private_entry(ino: u64) -> EntryOut {
+        let mut out = EntryOut::default();
+        out.ino(ino);
+        private_attr(ino, out.attr());
+        out
+    }
+
+    #[crate::async_trait]
+    impl Filesystem for Private {
+        async fn lookup(&self, _: &Context<'_>, _: op::Lookup<'_>) -> io::Result<EntryOut> {
+            Ok(private_entry(3))
+        }
+
+        async fn getattr(&self, _: &Context<'_>, op: op::Getattr<'_>) -> io::Result<AttrOut> {
+            self.getattrs.fetch_add(1, Ordering::SeqCst);
+            if op.ino() > 3 {
+                return Err(io::Error::from_raw_os_error(libc::ENOENT));
+            }
+            let mut out = AttrOut::default();
+            private_attr(op.ino(), out.attr());
+            Ok(out)
+        }
+
+        async fn open(&self, _: &Context<'_>, _: op::Open<'_>) -> io::Result<OpenOut> {
+            Ok(OpenOut::default())
+        }
+
+        async fn readdirplus(
+            &self,
+            _: &Context<'_>,
+            op: op::Readdir<'_>,
+        ) -> io::Result<ReaddirPlusOut> {
+            let mut out = ReaddirPlusOut::new(op.size() as usize);
+            assert!(!out.entry("b".as_ref(), 0, 1, &private_entry(2)));
+            Ok(out)
+        }
+    }
+
+    #[test]
+    fn service_checks() {
+        let harness = Harness::new();
+        let getattrs = Arc::new(AtomicUsize::new(0));
+        let service = PermissionLayer::new().layer(FilesystemService::new(Private {
+            getattrs: getattrs.clone(),
+        }));
+        let call = |req: &mut RequestBuilder, uid| {
+            req.credentials(uid, uid, 0);
+            harness.call(&service, req).map_or(0, |reply| reply.error())
+        };
+        let access = |ino| {
+            let mut req = RequestBuilder::new(fuse_opcode::FUSE_ACCESS);
+            req.nodeid(ino).arg(&fuse_access_in {
+                mask: MAY_READ,
+                ..Default::default()
+            });
+            req
+        };
+        let open = |ino| RequestBuilder::open(ino, libc::O_RDONLY as u32);
+
+        // The attributes of the root are fetched once.
+        assert_eq!(call(&mut access(1), 2000), libc::EACCES);
+        assert_eq!(call(&mut access(1), 1000), 0);
+        assert_eq!(getattrs.load(Ordering::SeqCst), 1);
+
+        // The entries of READDIRPLUS are recorded.
+        let mut req = RequestBuilder::new(fuse_opcode::FUSE_READDIRPLUS);
+        req.nodeid(1).arg(&fuse_read_in {
+            size: 4096,
+            ..Default::default()
+        });
+        assert_eq!(call(&mut req, 1000), 0);
+        assert_eq!(call(&mut open(2), 2000), libc::EACCES);
+        assert_eq!(getattrs.load(Ordering::SeqCst), 1);
+
+        // The attributes are kept until all the lookups are forgotten.
+        assert_eq!(call(&mut RequestBuilder::lookup(1, "c"), 1000), 0);
+        assert_eq!(call(&mut RequestBuilder::lookup(1, "c"), 1000), 0);
+        assert_eq!(call(&mut RequestBuilder::forget(3, 1), 1000), 0);
+        assert_eq!(call(&mut open(3), 2000), libc::EACCES);
+        assert_eq!(getattrs.load(Ordering::SeqCst), 1);
+
+        assert_eq!(call(&mut RequestBuilder::forget(3, 1), 1000), 0);
+        assert_eq!(call(&mut open(3), 2000), libc::EACCES);
+        assert_eq!(call(&mut open(3), 1000), 0);
+        assert_eq!(getattrs.load(Ordering::SeqCst), 2);
+
+        // The unknown inodes are not passed through.
+        assert_eq!(call(&mut open(4), 1000), libc::ENOENT);
+    }
+}
@@ -0,0 +1,454 @@
+//! Composable request processing.
+//!
+//! A [`Service`] processes a decoded operation and returns the [`Reply`] to
+//! be sent to the kernel, and a [`Layer`] wraps a service to add behaviors
+//! around it. Cross-cutting concerns such as logging, metrics or access
+//! control can be written once as a layer and stacked on top of any
+//! filesystem through [`FilesystemService`].
+//!
+//! ```no_run
+//! use polyfuse::{KernelConfig, Session};
+//! use polyfuse_fs::{
+//!     memfs::MemFs,
+//!     service::{FilesystemService, Layer, TraceLayer},
+//! };
+//!
+//! # async fn run() -> std::io::Result<()> {
+//! let session = Session::mount("/mnt".into(), KernelConfig::default())?;
+//! let service = TraceLayer::new().layer(FilesystemService::new(MemFs::new()));
+//! while let Some(req) = session.next_request()? {
+//!     polyfuse_fs::service::serve(&service, &req).await?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{Context, Filesystem};
+use either::Either;
+use polyfuse::{
+    op::ReaddirMode,
+    reply::{
+        AttrOut, BmapOut, EntryOut, LkOut, OpenOut, PollOut, ReaddirOut, ReaddirPlusOut, StatfsOut,
+        StatxOut, WriteOut, XattrOut,
+    },
+    Data, Errno, Operation, Request,
+};
+use std::{fmt, io, time::Instant};
+use tracing::Instrument as _;
+
+/// The reply to an operation.
+pub enum Reply {
+    /// A reply to `Lookup`, `Mknod`, `Mkdir`, `Symlink` and `Link`.
+    Entry(EntryOut),
+    /// A reply to `Getattr` and `Setattr`.
+    Attr(AttrOut),
+    /// A reply with the raw bytes, used by `Read` and `Readlink`.
+    Data(Vec<u8>),
+    /// A reply to `Open` and `Opendir`.
+    Open(OpenOut),
+    /// A reply to `Write` and `CopyFileRange`.
+    Write(WriteOut),
+    /// A reply to `Statfs`.
+    Statfs(StatfsOut),
+    /// A reply to `Getxattr` and `Listxattr`.
+    Xattr(Either<XattrOut, Vec<u8>>),
+    /// A reply to `Readdir`.
+    Readdir(ReaddirOut),
+    /// A reply to `Readdir` in the `READDIRPLUS` mode.
+    ReaddirPlus(ReaddirPlusOut),
+    /// A reply to `Getlk`.
+    Lk(LkOut),
+    /// A reply to `Create`.
+    Create(EntryOut, OpenOut),
+    /// A reply to `Bmap`.
+    Bmap(BmapOut),
+    /// A reply to `Poll`.
+    Poll(PollOut),
+    /// A reply to `Statx`.
+    Statx(StatxOut),
+    /// An empty reply.
+    Empty,
+    /// No reply is sent, used by `Forget`, `Interrupt` and `NotifyReply`.
+    None,
+}
+
+impl fmt::Debug for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reply::Entry(out) => out.fmt(f),
+            Reply::Attr(out) => out.fmt(f),
+            Reply::Data(data) => f.debug_tuple("Data").field(&data.len()).finish(),
+            Reply::Open(out) => out.fmt(f),
+            Reply::Write(out) => out.fmt(f),
+            Reply::Statfs(out) => out.fmt(f),
+            Reply::Xattr(out) => out.fmt(f),
+            Reply::Readdir(out) => out.fmt(f),
+            Reply::ReaddirPlus(out) => out.fmt(f),
+            Reply::Lk(out) => out.fmt(f),
+            Reply::Create(entry, open) => f.debug_tuple("Create").field(entry).field(open).finish(),
+            Reply::Bmap(out) => out.fmt(f),
+            Reply::Poll(out) => out.fmt(f),
+            Reply::Statx(out) => out.fmt(f),
+            Reply::Empty => f.write_str("Empty"),
+            Reply::None => f.write_str("None"),
+        }
+    }
+}
+
+impl Reply {
+    /// Send this reply to the kernel.
+    pub fn send(self, req: &Request) -> io::Result<()> {
+        match self {
+            Reply::Entry(out) => req.reply(out)?,
+            Reply::Attr(out) => req.reply(out)?,
+            Reply::Data(data) => req.reply(data)?,
+            Reply::Open(out) => req.reply(out)?,
+            Reply::Write(out) => req.reply(out)?,
+            Reply::Statfs(out) => req.reply(out)?,
+            Reply::Xattr(out) => req.reply(out)?,
+            Reply::Readdir(out) => req.reply(out)?,
+            Reply::ReaddirPlus(out) => req.reply(out)?,
+            Reply::Lk(out) => req.reply(out)?,
+            Reply::Create(entry, open) => req.reply((entry, open))?,
+            Reply::Bmap(out) => req.reply(out)?,
+            Reply::Poll(out) => req.reply(out)?,
+            Reply::Statx(out) => req.reply(out)?,
+            Reply::Empty => req.reply(())?,
+            Reply::None => return Ok(()),
+        };
+        Ok(())
+    }
+}
+
+/// An asynchronous function from an operation to its reply.
+#[crate::async_trait]
+pub trait Service: Sync {
+    /// Process the operation.
+    async fn call(&self, cx: &Context<'_>, op: Operation<'_, Data<'_>>) -> io::Result<Reply>;
+}
+
+#[crate::async_trait]
+impl<S> Service for Box<S>
+where
+    S: Service + Send + ?Sized,
+{
+    async fn call(&self, cx: &Context<'_>, op: Operation<'_, Data<'_>>) -> io::Result<Reply> {
+        (**self).call(cx, op).await
+    }
+}
+
+#[crate::async_trait]
+impl<S> Service for std::sync::Arc<S>
+where
+    S: Service + Send + ?Sized,
+{
+    async fn call(&self, cx: &Context<'_>, op: Operation<'_, Data<'_>>) -> io::Result<Reply> {
+        (**self).call(cx, op).await
+    }
+}
+
+/// Decorates a service with additional behaviors.
+pub trait Layer<S> {
+    /// The wrapped service.
+    type Service;
+
+    /// Wrap the service.
+    fn layer(&self, inner: S) -> Self::Service;
+}
+
+/// A layer that does nothing.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Identity;
+
+impl<S> Layer<S> for Identity {
+    type Service = S;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        inner
+    }
+}
+
+/// Two layers chained together.
+///
+/// The `outer` layer wraps the service produced by the `inner` layer.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Stack<Inner, Outer> {
+    inner: Inner,
+    outer: Outer,
+}
+
+impl<Inner, Outer> Stack<Inner, Outer> {
+    /// Create a new `Stack`.
+    pub fn new(inner: Inner, outer: Outer) -> Self {
+        Self { inner, outer }
+    }
+}
+
+impl<S, Inner, Outer> Layer<S> for Stack<Inner, Outer>
+where
+    Inner: Layer<S>,
+    Outer: Layer<Inner::Service>,
+{
+    type Service = Outer::Service;
+
+    fn layer(&self, service: S) -> Self::Service {
+        self.outer.layer(self.inner.layer(service))
+    }
+}
+
+/// A service that passes the operations to a [`Filesystem`].
+#[derive(Debug)]
+pub struct FilesystemService<F> {
+    fs: F,
+}
+
+impl<F> FilesystemService<F> {
+    /// Create a service from the filesystem.
+    pub fn new(fs: F) -> Self {
+        Self { fs }
+    }
+
+    /// Return a reference to the filesystem.
+    #[inline]
+    pub fn get_ref(&self) -> &F {
+        &self.fs
+    }
+}
+
+#[crate::async_trait]
+impl<F> Service for FilesystemService<F>
+where
+    F: Filesystem,
+{
+    async fn call(&self, cx: &Context<'_>, op: Operation<'_, Data<'_>>) -> io::Result<Reply> {
+        call_fs(&self.fs, cx, op).await
+    }
+}
+
+pub(crate) async fn call_fs<F>(
+    fs: &F,
+    cx: &Context<'_>,
+    op: Operation<'_, Data<'_>>,
+) -> io::Result<Reply>
+where
+    F: Filesystem + ?Sized,
+{
+    let reply = match op {
+        Operation::Lookup(op) => Reply::Entry(fs.lookup(cx, op).await?),
+        Operation::Getattr(op) => Reply::Attr(fs.getattr(cx, op).await?),
+        Operation::Setattr(op) => Reply::Attr(fs.setattr(cx, op).await?),
+        Operation::Readlink(op) => Reply::Data(fs.readlink(cx, op).await?),
+        Operation::Symlink(op) => Reply::Entry(fs.symlink(cx, op).await?),
+        Operation::Mknod(op) => Reply::Entry(fs.mknod(cx, op).await?),
+        Operation::Mkdir(op) => Reply::Entry(fs.mkdir(cx, op).await?),
+        Operation::Unlink(op) => fs.unlink(cx, op).await.map(|()| Reply::Empty)?,
+        Operation::Rmdir(op) => fs.rmdir(cx, op).await.map(|()| Reply::Empty)?,
+        Operation::Rename(op) => fs.rename(cx, op).await.map(|()| Reply::Empty)?,
+        Operation::Link(op) => Reply::Entry(fs.link(cx, op).await?),
+        Operation::Open(op) => Reply::Open(fs.open(cx, op).await?),
+        Operation::Read(op) => Reply::Data(fs.read(cx, op).await?),
+        Operation::Write(op, data) => Reply::Write(fs.write(cx, op, data).await?),
+        Operation::Release(op) => fs.release(cx, op).await.map(|()| Reply::Empty)?,
+        Operation::Statfs(op) => Reply::Statfs(fs.statfs(cx, op).await?),
+        Operation::Fsync(op) => fs.fsync(cx, op).await.map(|()| Reply::Empty)?,
+        Operation::Setxattr(op) => fs.setxattr(cx, op).await.map(|()| Reply::Empty)?,
+        Operation::Getxattr(op) => Reply::Xattr(fs.getxattr(cx, op).await?),
+        Operation::Listxattr(op) => Reply::Xattr(fs.listxattr(cx, op).await?),
+        Operation::Removexattr(op) => fs.removexattr(cx, op).await.map(|()| Reply::Empty)?,
+        Operation::Flush(op) => fs.flush(cx, op).await.map(|()| Reply::Empty)?,
+        Operation::Opendir(op) => Reply::Open(fs.opendir(cx, op).await?),
+        Operation::Readdir(op) => match op.mode() {
+            ReaddirMode::Plus => Reply::ReaddirPlus(fs.readdirplus(cx, op).await?),
+            ReaddirMode::Normal => Reply::Readdir(fs.readdir(cx, op).await?),
+        },
+        Operation::Releasedir(op) => fs.releasedir(cx, op).await.map(|()| Reply::Empty)?,
+        Operation::Fsyncdir(op) => fs.fsyncdir(cx, op).await.map(|()| Reply::Empty)?,
+        Operation::Getlk(op) => Reply::Lk(fs.getlk(cx, op).await?),
+        Operation::Setlk(op) => fs.setlk(cx, op).await.map(|()| Reply::Empty)?,
+        Operation::Flock(op) => fs.flock(cx, op).await.map(|()| Reply::Empty)?,
+        Operation::Access(op) => fs.access(cx, op).await.map(|()| Reply::Empty)?,
+        Operation::Create(op) => {
+            let (entry, open) = fs.create(cx, op).await?;
+            Reply::Create(entry, open)
+        }
+        Operation::Bmap(op) => Reply::Bmap(fs.bmap(cx, op).await?),
+        Operation::Fallocate(op) => fs.fallocate(cx, op).await.map(|()| Reply::Empty)?,
+        Operation::CopyFileRange(op) => Reply::Write(fs.copy_file_range(cx, op).await?),
+        Operation::Poll(op) => Reply::Poll(fs.poll(cx, op).await?),
+        Operation::Statx(op) => Reply::Statx(fs.statx(cx, op).await?),
+
+        Operation::Forget(forgets) => {
+            fs.forget(cx, &forgets).await;
+            Reply::None
+        }
+        Operation::Interrupt(op) => {
+            fs.interrupt(cx, op).await;
+            Reply::None
+        }
+        Operation::NotifyReply(op, data) => {
+            fs.notify_reply(cx, op, data).await;
+            Reply::None
+        }
+
+        _ => return Err(io::Error::from_raw_os_error(libc::ENOSYS)),
+    };
+    Ok(reply)
+}
+
+/// Return whether the kernel waits for a reply to the operation.
+pub(crate) fn expects_reply<T>(op: &Operation<'_, T>) -> bool {
+    !matches!(
+        op,
+        Operation::Forget(..) | Operation::Interrupt(..) | Operation::NotifyReply(..)
+    )
+}
+
+pub(crate) fn respond(
+    req: &Request,
+    expects_reply: bool,
+    res: io::Result<Reply>,
+) -> io::Result<()> {
+    match res {
+        Ok(reply) => reply.send(req),
+        Err(err) if expects_reply => {
+            req.reply_error(Errno::from(err).code())?;
+            Ok(())
+        }
+        Err(err) => {
+            tracing::warn!(unique = req.unique(), "an error occurred: {}", err);
+            Ok(())
+        }
+    }
+}
+
+/// Handle an incoming request using the specified service.
+///
+/// This is the counterpart of [`dispatch`](crate::dispatch) for services.
+pub async fn serve<S>(service: &S, req: &Request) -> io::Result<()>
+where
+    S: Service + ?Sized,
+{
+    let op = match req.operation() {
+        Ok(op) => op,
+        Err(err) => {
+            tracing::error!("failed to decode the request: {}", err);
+            req.reply_error(libc::EIO)?;
+            return Ok(());
+        }
+    };
+    tracing::debug!(unique = req.unique(), ?op);
+
+    let expects_reply = expects_reply(&op);
+    let cx = Context::new(req);
+    let res = service.call(&cx, op).await;
+    respond(req, expects_reply, res)
+}
+
+/// A layer that records the processing of each operation with `tracing`.
+///
+/// All operations are recorded by default. On a busy mount, the recording
+/// can be restricted to the operations of interest with [`opcodes`], and
+/// the others are passed through without being recorded:
+///
+/// ```
+/// use polyfuse_fs::service::TraceLayer;
+/// use polyfuse_kernel::fuse_opcode;
+///
+/// let layer = TraceLayer::new().opcodes(&[
+///     fuse_opcode::FUSE_WRITE as u32,
+///     fuse_opcode::FUSE_FSYNC as u32,
+/// ]);
+/// ```
+///
+/// [`opcodes`]: TraceLayer::opcodes
+#[derive(Debug, Default, Clone)]
+pub struct TraceLayer {
+    opcodes: Option<Vec<u32>>,
+}
+
+impl TraceLayer {
+    /// Create a layer recording all operations.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record only the operations with the specified opcodes, i.e. the
+    /// values of `fuse_opcode` as returned by [`Context::opcode`].
+    pub fn opcodes(mut self, opcodes: &[u32]) -> Self {
+        let mut opcodes = opcodes.to_vec();
+        opcodes.sort_unstable();
+        opcodes.dedup();
+        self.opcodes = Some(opcodes);
+        self
+    }
+
+    fn traces(&self, opcode: u32) -> bool {
+        match self.opcodes {
+            Some(ref opcodes) => opcodes.binary_search(&opcode).is_ok(),
+            None => true,
+        }
+    }
+}
+
+impl<S> Layer<S> for TraceLayer {
+    type Service = Trace<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Trace {
+            inner,
+            filter: self.clone(),
+        }
+    }
+}
+
+/// The service produced by [`TraceLayer`].
+#[derive(Debug)]
+pub struct Trace<S> {
+    inner: S,
+    filter: TraceLayer,
+}
+
+#[crate::async_trait]
+impl<S> Service for Trace<S>
+where
+    S: Service,
+{
+    async fn call(&self, cx: &Context<'_>, op: Operation<'_, Data<'_>>) -> io::Result<Reply> {
+        if !self.filter.traces(cx.opcode()) {
+            return self.inner.call(cx, op).await;
+        }
+
+        let span = tracing::debug_span!("call", unique = cx.unique());
+        span.in_scope(|| tracing::debug!(?op, uid = cx.uid(), gid = cx.gid(), pid = cx.pid()));
+
+        let start = Instant::now();
+        let res = self.inner.call(cx, op).instrument(span.clone()).await;
+        span.in_scope(|| match res {
+            Ok(ref reply) => tracing::debug!(elapsed = ?start.elapsed(), ?reply),
+            Err(ref err) => tracing::debug!(elapsed = ?start.elapsed(), error = %err),
+        });
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace_opcodes() {
+        use polyfuse_kernel::fuse_opcode::{FUSE_FSYNC, FUSE_GETATTR, FUSE_WRITE};
+
+        let (getattr, write, fsync) = (FUSE_GETATTR as u32, FUSE_WRITE as u32, FUSE_FSYNC as u32);
+
+        let layer = TraceLayer::new();
+        assert!(layer.traces(getattr) && layer.traces(write));
+
+        let layer = layer.opcodes(&[fsync, write, fsync]);
+        assert_eq!(layer.opcodes.as_deref(), Some(&[write, fsync][..]));
+        assert!(layer.traces(write) && layer.traces(fsync));
+        assert!(!layer.traces(getattr));
+
+        assert!(!TraceLayer::new().opcodes(&[]).traces(getattr));
+    }
+}
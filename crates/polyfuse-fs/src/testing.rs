@@ -0,0 +1,90 @@
+//! A harness for driving the services with the mocked kernel.
+
+use crate::{
+    service::{self, serve, Service},
+    Context,
+};
+use polyfuse::{Data, KernelConfig, Operation, Session};
+use polyfuse_test::{MockKernel, Reply, RequestBuilder};
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::Mutex,
+    task::{self, Poll, RawWaker, RawWakerVTable, Waker},
+};
+
+pub(crate) fn noop_waker() -> Waker {
+    unsafe fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(std::ptr::null(), &VTABLE)
+    }
+    unsafe fn noop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+    unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &VTABLE)) }
+}
+
+/// Run the future, which must complete without waiting.
+pub(crate) fn block_on<F>(fut: F) -> F::Output
+where
+    F: Future,
+{
+    let waker = noop_waker();
+    let mut cx = task::Context::from_waker(&waker);
+    let mut fut = Box::pin(fut);
+    match Pin::new(&mut fut).poll(&mut cx) {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("the future is blocked"),
+    }
+}
+
+/// A session on the mocked kernel, serving one request at a time.
+pub(crate) struct Harness {
+    kernel: MockKernel,
+    session: Session,
+}
+
+impl Harness {
+    pub(crate) fn new() -> Self {
+        let (kernel, session) = MockKernel::new(KernelConfig::default()).unwrap();
+        Self { kernel, session }
+    }
+
+    /// Send the request, process it with the service, and return the reply
+    /// if the kernel waits for one.
+    pub(crate) fn call<S>(&self, service: &S, req: &RequestBuilder) -> Option<Reply>
+    where
+        S: Service + ?Sized,
+    {
+        let unique = self.kernel.send_request(req).unwrap();
+        let req = self.session.next_request().unwrap().unwrap();
+        block_on(serve(service, &req)).unwrap();
+
+        let reply = self.kernel.try_recv().unwrap()?;
+        assert_eq!(reply.unique(), unique);
+        Some(reply)
+    }
+}
+
+/// A service recording the opcodes of the operations, which fail with `ENOSYS`.
+#[derive(Debug, Default)]
+pub(crate) struct Recorder {
+    opcodes: Mutex<Vec<u32>>,
+}
+
+impl Recorder {
+    pub(crate) fn opcodes(&self) -> Vec<u32> {
+        self.opcodes.lock().unwrap().clone()
+    }
+}
+
+#[crate::async_trait]
+impl Service for Recorder {
+    async fn call(
+        &self,
+        cx: &Context<'_>,
+        _: Operation<'_, Data<'_>>,
+    ) -> io::Result<service::Reply> {
+        self.opcodes.lock().unwrap().push(cx.opcode());
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+}
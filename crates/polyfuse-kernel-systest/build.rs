@@ -11,12 +11,20 @@ fn generate_abi_tests() {
     cfg.include("libfuse/include");
 
     cfg.field_name(|_s, field| field.replace("typ", "type"));
-    cfg.skip_field(|s, field| s == "fuse_dirent" && field == "name");
+    cfg.skip_field(|s, field| {
+        (s == "fuse_dirent" && field == "name")
+            // `padding` has been renamed to `flags` since ABI 7.32.
+            || (s == "fuse_attr" && field == "flags")
+    });
 
     cfg.skip_struct(|s| s == "UnknownOpcode" || s == "InvalidFileLock");
 
-    // FUSE_FSYNC_FDATASYNC is defined since libfuse 3.7.0.
-    cfg.skip_const(|name| name == "FUSE_FSYNC_FDATASYNC");
+    // The definitions newer than the bundled libfuse.
+    cfg.skip_struct(|s| s.starts_with("fuse_statx") || s == "fuse_sx_time");
+    cfg.skip_const(|name| {
+        // FUSE_FSYNC_FDATASYNC is defined since libfuse 3.7.0.
+        name == "FUSE_FSYNC_FDATASYNC" || name == "FUSE_STATX" || name.starts_with("FUSE_ATTR_")
+    });
 
     cfg.generate("../polyfuse-kernel/src/lib.rs", "kernel.rs");
 }
@@ -1,6 +1,7 @@
 //! FUSE application binary interface for `polyfuse`.
 //!
-//! The binding is compatible with ABI 7.31 (in libfuse 3.10.1).
+//! The binding is compatible with ABI 7.31 (in libfuse 3.10.1), plus the
+//! `flags` of `fuse_attr` (7.32) and `FUSE_STATX` (7.39).
 
 #![allow(nonstandard_style, clippy::identity_op)]
 
@@ -41,6 +42,7 @@ pub const FOPEN_KEEP_CACHE: u32 = 1 << 1;
 pub const FOPEN_NONSEEKABLE: u32 = 1 << 2;
 pub const FOPEN_CACHE_DIR: u32 = 1 << 3;
 pub const FOPEN_STREAM: u32 = 1 << 4;
+pub const FOPEN_PARALLEL_DIRECT_WRITES: u32 = 1 << 6;
 
 // INIT request/reply flags.
 pub const FUSE_ASYNC_READ: u32 = 1;
@@ -69,6 +71,11 @@ pub const FUSE_MAX_PAGES: u32 = 1 << 22;
 pub const FUSE_CACHE_SYMLINKS: u32 = 1 << 23;
 pub const FUSE_NO_OPENDIR_SUPPORT: u32 = 1 << 24;
 pub const FUSE_EXPLICIT_INVAL_DATA: u32 = 1 << 25;
+pub const FUSE_INIT_EXT: u32 = 1 << 30;
+
+// INIT request/reply flags2, i.e. the upper half of the flags extended
+// by `FUSE_INIT_EXT`.
+pub const FUSE_SECURITY_CTX: u32 = 1 << 0;
 
 // CUSE INIT request/reply flags.
 pub const CUSE_UNRESTRICTED_IOCTL: u32 = 1 << 0;
@@ -105,6 +112,10 @@ pub const FUSE_POLL_SCHEDULE_NOTIFY: u32 = 1 << 0;
 // Fsync flags.
 pub const FUSE_FSYNC_FDATASYNC: u32 = 1 << 0;
 
+// fuse_attr flags.
+pub const FUSE_ATTR_SUBMOUNT: u32 = 1 << 0;
+pub const FUSE_ATTR_DAX: u32 = 1 << 1;
+
 // misc
 pub const FUSE_COMPAT_ENTRY_OUT_SIZE: usize = 120;
 pub const FUSE_COMPAT_ATTR_OUT_SIZE: usize = 96;
@@ -133,7 +144,41 @@ pub struct fuse_attr {
     pub gid: u32,
     pub rdev: u32,
     pub blksize: u32,
-    pub padding: u32,
+    pub flags: u32,
+}
+
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_sx_time {
+    pub tv_sec: i64,
+    pub tv_nsec: u32,
+    pub __reserved: i32,
+}
+
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_statx {
+    pub mask: u32,
+    pub blksize: u32,
+    pub attributes: u64,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u16,
+    pub __spare0: [u16; 1],
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    pub attributes_mask: u64,
+    pub atime: fuse_sx_time,
+    pub btime: fuse_sx_time,
+    pub ctime: fuse_sx_time,
+    pub mtime: fuse_sx_time,
+    pub rdev_major: u32,
+    pub rdev_minor: u32,
+    pub dev_major: u32,
+    pub dev_minor: u32,
+    pub __spare2: [u64; 14],
 }
 
 #[derive(Clone, Copy, Default, FromBytes, AsBytes)]
@@ -259,6 +304,7 @@ define_opcode! {
     FUSE_RENAME2 = 45,
     FUSE_LSEEK = 46,
     FUSE_COPY_FILE_RANGE = 47,
+    FUSE_STATX = 52,
 
     CUSE_INIT = 4096,
 }
@@ -457,6 +503,20 @@ pub struct fuse_create_in {
     pub padding: u32,
 }
 
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_secctx_header {
+    pub size: u32,
+    pub nr_secctx: u32,
+}
+
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_secctx {
+    pub size: u32,
+    pub padding: u32,
+}
+
 #[derive(Clone, Copy, Default, FromBytes, AsBytes)]
 #[repr(C)]
 pub struct fuse_bmap_in {
@@ -507,7 +567,8 @@ pub struct fuse_init_out {
     pub time_gran: u32,
     pub max_pages: u16,
     pub padding: u16,
-    pub unused: [u32; 8],
+    pub flags2: u32,
+    pub unused: [u32; 7],
 }
 
 impl Default for fuse_init_out {
@@ -523,7 +584,8 @@ impl Default for fuse_init_out {
             time_gran: 0,
             max_pages: 0,
             padding: 0,
-            unused: [0; 8],
+            flags2: 0,
+            unused: [0; 7],
         }
     }
 }
@@ -664,6 +726,26 @@ pub struct fuse_lseek_out {
     pub offset: u64,
 }
 
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_statx_in {
+    pub getattr_flags: u32,
+    pub reserved: u32,
+    pub fh: u64,
+    pub sx_flags: u32,
+    pub sx_mask: u32,
+}
+
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_statx_out {
+    pub attr_valid: u64,
+    pub attr_valid_nsec: u32,
+    pub flags: u32,
+    pub spare: [u64; 2],
+    pub stat: fuse_statx,
+}
+
 #[derive(Clone, Copy, Default, FromBytes, AsBytes)]
 #[repr(C)]
 pub struct fuse_copy_file_range_in {
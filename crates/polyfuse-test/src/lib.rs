@@ -0,0 +1,1270 @@
+//! In-process mock of the FUSE kernel driver for testing `polyfuse` filesystems.
+//!
+//! [`MockKernel`] creates a pair of connected sockets, one of which is
+//! handed to a [`Session`] in place of `/dev/fuse`. The test sends crafted
+//! requests through the other end, drives the filesystem with the requests
+//! received from the session, and makes assertions on the captured replies.
+//! No mount, root privilege or actual FUSE driver is required.
+//!
+//! ```
+//! use polyfuse::KernelConfig;
+//! use polyfuse_kernel::fuse_opcode;
+//! use polyfuse_test::MockKernel;
+//!
+//! let (kernel, session) = MockKernel::new(KernelConfig::default())?;
+//!
+//! let unique = kernel.send(fuse_opcode::FUSE_LOOKUP, 1, &[b"foo\0"])?;
+//!
+//! let req = session.next_request()?.expect("session is closed");
+//! assert_eq!(req.unique(), unique);
+//! req.reply_error(libc::ENOENT)?;
+//!
+//! let reply = kernel.recv()?;
+//! assert_eq!(reply.unique(), unique);
+//! assert_eq!(reply.error(), libc::ENOENT);
+//! # Ok::<_, std::io::Error>(())
+//! ```
+
+#![doc(html_root_url = "https://docs.rs/polyfuse-test/0.1.0")]
+#![forbid(clippy::todo, clippy::unimplemented)]
+
+macro_rules! syscall {
+    ($fn:ident ( $($arg:expr),* $(,)* ) ) => {{
+        #[allow(unused_unsafe)]
+        let res = unsafe { libc::$fn($($arg),*) };
+        if res == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        res
+    }};
+}
+
+mod mount;
+mod request;
+pub mod stress;
+pub mod trace;
+
+pub use crate::{mount::NamespaceMount, request::RequestBuilder};
+
+use polyfuse::{KernelConfig, Session};
+use polyfuse_kernel::*;
+use std::{
+    fmt, io, mem,
+    os::unix::prelude::*,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+use zerocopy::{AsBytes, FromBytes};
+
+/// The flags of `FUSE_INIT` sent by [`MockKernel::new`].
+pub const DEFAULT_INIT_FLAGS: u32 = FUSE_ASYNC_READ
+    | FUSE_POSIX_LOCKS
+    | FUSE_ATOMIC_O_TRUNC
+    | FUSE_EXPORT_SUPPORT
+    | FUSE_DONT_MASK
+    | FUSE_FLOCK_LOCKS
+    | FUSE_DO_READDIRPLUS
+    | FUSE_READDIRPLUS_AUTO
+    | FUSE_ASYNC_DIO
+    | FUSE_WRITEBACK_CACHE
+    | FUSE_PARALLEL_DIROPS
+    | FUSE_HANDLE_KILLPRIV
+    | FUSE_POSIX_ACL
+    | FUSE_AUTO_INVAL_DATA
+    | FUSE_MAX_PAGES;
+
+const DEFAULT_MAX_READAHEAD: u32 = 128 * 1024;
+
+/// The kernel side of a mocked FUSE connection.
+///
+/// The messages are exchanged over a `SOCK_SEQPACKET` socket, which
+/// preserves the message boundaries as `/dev/fuse` does. Note that
+/// a message larger than the socket buffer cannot be sent, so the tests
+/// of large writes should lower `max_write` accordingly.
+///
+/// Dropping the mock closes the connection, and the session sees it as
+/// the filesystem being unmounted.
+pub struct MockKernel {
+    fd: RawFd,
+    init_out: fuse_init_out,
+    unique: AtomicU64,
+    credentials: Mutex<Credentials>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Credentials {
+    uid: u32,
+    gid: u32,
+    pid: u32,
+}
+
+impl fmt::Debug for MockKernel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockKernel")
+            .field("fd", &self.fd)
+            .field("credentials", &*self.credentials.lock().unwrap())
+            .finish()
+    }
+}
+
+impl Drop for MockKernel {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+impl AsRawFd for MockKernel {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl MockKernel {
+    /// Create a mocked connection and start a session on it.
+    pub fn new(config: KernelConfig) -> io::Result<(Self, Session)> {
+        Self::with_init_flags(config, DEFAULT_INIT_FLAGS)
+    }
+
+    /// Create a mocked connection, sending `FUSE_INIT` with the specified flags.
+    pub fn with_init_flags(config: KernelConfig, flags: u32) -> io::Result<(Self, Session)> {
+        let init_in = fuse_init_in {
+            major: FUSE_KERNEL_VERSION,
+            minor: FUSE_KERNEL_MINOR_VERSION,
+            max_readahead: DEFAULT_MAX_READAHEAD,
+            flags,
+        };
+        let init = RequestBuilder::new(fuse_opcode::FUSE_INIT)
+            .unique(1)
+            .credentials(
+                unsafe { libc::getuid() },
+                unsafe { libc::getgid() },
+                std::process::id(),
+            )
+            .arg(&init_in)
+            .build();
+
+        let (kernel, session, reply) = Self::start(config, &init)?;
+        if reply.unique() != 1 || reply.error() != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected reply to FUSE_INIT",
+            ));
+        }
+        Ok((kernel, session))
+    }
+
+    /// Start a session with the raw `FUSE_INIT` message and return its reply.
+    pub(crate) fn start(config: KernelConfig, init: &[u8]) -> io::Result<(Self, Session, Reply)> {
+        let mut fds = [0; 2];
+        syscall! {
+            socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        };
+
+        let mut kernel = Self {
+            fd: fds[0],
+            init_out: fuse_init_out::default(),
+            unique: AtomicU64::new(1),
+            credentials: Mutex::new(Credentials {
+                uid: unsafe { libc::getuid() },
+                gid: unsafe { libc::getgid() },
+                pid: std::process::id(),
+            }),
+        };
+
+        kernel.send_raw(init)?;
+
+        // The INIT request is already queued in the socket, so the handshake
+        // completes without another thread.
+        let session = unsafe { Session::from_raw_fd(fds[1], config)? };
+
+        let reply = kernel.recv()?;
+        if reply.error() == 0 {
+            kernel.init_out = reply.arg().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "fuse_init_out is too short")
+            })?;
+        }
+
+        Ok((kernel, session, reply))
+    }
+
+    /// Return the parameters replied to `FUSE_INIT`.
+    pub fn init_out(&self) -> &fuse_init_out {
+        &self.init_out
+    }
+
+    /// Set the credentials of the caller filled in the subsequent requests.
+    ///
+    /// By default, the credentials of the test process are used.
+    pub fn set_credentials(&self, uid: u32, gid: u32, pid: u32) {
+        *self.credentials.lock().unwrap() = Credentials { uid, gid, pid };
+    }
+
+    /// Send a request and return its unique ID.
+    ///
+    /// The header is filled automatically, and `args` are concatenated
+    /// as the payload of the request.
+    pub fn send(&self, opcode: fuse_opcode, nodeid: u64, args: &[&[u8]]) -> io::Result<u64> {
+        let mut req = RequestBuilder::new(opcode);
+        req.nodeid(nodeid);
+        for arg in args {
+            req.data(arg);
+        }
+        self.send_request(&req)
+    }
+
+    /// Send a request assembled by the builder and return its unique ID.
+    ///
+    /// The unique ID and the credentials are filled by the mock unless
+    /// they are explicitly set in the builder.
+    pub fn send_request(&self, req: &RequestBuilder) -> io::Result<u64> {
+        let unique = req.unique_id().unwrap_or_else(|| self.next_unique());
+        let creds = *self.credentials.lock().unwrap();
+        let msg = req.build_with(unique, (creds.uid, creds.gid, creds.pid));
+        self.send_raw(&msg)?;
+        Ok(unique)
+    }
+
+    /// Send a raw message as is.
+    ///
+    /// This is useful for testing the handling of malformed requests.
+    pub fn send_raw(&self, msg: &[u8]) -> io::Result<()> {
+        self.write_message(&[io::IoSlice::new(msg)])
+    }
+
+    /// Send `FUSE_INTERRUPT` for the specified request.
+    pub fn interrupt(&self, unique: u64) -> io::Result<u64> {
+        self.send_request(&RequestBuilder::interrupt(unique))
+    }
+
+    /// Receive a reply or notification sent by the filesystem.
+    ///
+    /// This method blocks until a message is available.
+    pub fn recv(&self) -> io::Result<Reply> {
+        self.recv_message(0)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::WouldBlock, "no message available"))
+    }
+
+    /// Receive a reply or notification without blocking.
+    ///
+    /// `None` is returned if no message is available.
+    pub fn try_recv(&self) -> io::Result<Option<Reply>> {
+        self.recv_message(libc::MSG_DONTWAIT)
+    }
+
+    fn recv_message(&self, flags: libc::c_int) -> io::Result<Option<Reply>> {
+        // Peek the actual length of the next message.
+        let len = unsafe {
+            libc::recv(
+                self.fd,
+                std::ptr::null_mut(),
+                0,
+                flags | libc::MSG_PEEK | libc::MSG_TRUNC,
+            )
+        };
+        if len == -1 {
+            let err = io::Error::last_os_error();
+            return match err.kind() {
+                io::ErrorKind::WouldBlock => Ok(None),
+                _ => Err(err),
+            };
+        }
+
+        let mut buf = vec![0u8; len as usize];
+        let received = syscall! {
+            recv(
+                self.fd,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                flags,
+            )
+        } as usize;
+        buf.truncate(received);
+
+        Reply::from_bytes(buf).map(Some)
+    }
+
+    fn next_unique(&self) -> u64 {
+        self.unique.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    fn write_message(&self, iov: &[io::IoSlice<'_>]) -> io::Result<()> {
+        let len: usize = iov.iter().map(|s| s.len()).sum();
+        let written = syscall! {
+            writev(
+                self.fd,
+                iov.as_ptr() as *const libc::iovec,
+                iov.len() as libc::c_int,
+            )
+        } as usize;
+        if written < len {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "written message is too short",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A message sent from the filesystem to the mocked kernel.
+pub struct Reply {
+    header: fuse_out_header,
+    data: Vec<u8>,
+}
+
+impl fmt::Debug for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Reply")
+            .field("unique", &self.header.unique)
+            .field("error", &self.header.error)
+            .field("len", &self.data.len())
+            .finish()
+    }
+}
+
+impl Reply {
+    pub(crate) fn from_bytes(mut buf: Vec<u8>) -> io::Result<Self> {
+        if buf.len() < mem::size_of::<fuse_out_header>() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "reply message is too short",
+            ));
+        }
+        let mut header = fuse_out_header::default();
+        header
+            .as_bytes_mut()
+            .copy_from_slice(&buf[..mem::size_of::<fuse_out_header>()]);
+        if header.len as usize != buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the length in reply header is mismatched",
+            ));
+        }
+        buf.drain(..mem::size_of::<fuse_out_header>());
+
+        Ok(Self { header, data: buf })
+    }
+
+    /// Return the raw bytes of the message, including the header.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.header.len as usize);
+        bytes.extend_from_slice(self.header.as_bytes());
+        bytes.extend_from_slice(&self.data[..]);
+        bytes
+    }
+
+    /// Return the unique ID of the request that this message replies to.
+    ///
+    /// The value is zero if the message is a notification.
+    #[inline]
+    pub fn unique(&self) -> u64 {
+        self.header.unique
+    }
+
+    /// Return the error number (as a positive value) of the reply.
+    #[inline]
+    pub fn error(&self) -> i32 {
+        -self.header.error
+    }
+
+    /// Return whether the message is a notification.
+    #[inline]
+    pub fn is_notification(&self) -> bool {
+        self.header.unique == 0
+    }
+
+    /// Return the notification code if the message is a notification.
+    ///
+    /// The value corresponds to the variants of `fuse_notify_code`.
+    pub fn notify_code(&self) -> Option<u32> {
+        if self.is_notification() {
+            Some(self.header.error as u32)
+        } else {
+            None
+        }
+    }
+
+    /// Return the payload of the message.
+    #[inline]
+    pub fn data(&self) -> &[u8] {
+        &self.data[..]
+    }
+
+    /// Decode the leading part of the payload as a kernel ABI structure.
+    ///
+    /// `None` is returned if the payload is too short.
+    pub fn arg<T>(&self) -> Option<T>
+    where
+        T: FromBytes + AsBytes + Default,
+    {
+        let mut arg = T::default();
+        let dst = arg.as_bytes_mut();
+        dst.copy_from_slice(self.data.get(..dst.len())?);
+        Some(arg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake() {
+        let mut config = KernelConfig::default();
+        config.max_write(64 * 1024);
+        let (kernel, _session) = MockKernel::new(config).unwrap();
+
+        assert_eq!(kernel.init_out().major, FUSE_KERNEL_VERSION);
+        assert_eq!(kernel.init_out().max_write, 64 * 1024);
+    }
+
+    #[test]
+    fn reply_and_notify() {
+        let (kernel, session) = MockKernel::new(KernelConfig::default()).unwrap();
+        kernel.set_credentials(1000, 100, 42);
+
+        let arg = fuse_getattr_in::default();
+        let unique = kernel
+            .send(fuse_opcode::FUSE_GETATTR, 1, &[arg.as_bytes()])
+            .unwrap();
+
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.unique(), unique);
+        assert_eq!((req.uid(), req.gid(), req.pid()), (1000, 100, 42));
+        req.reply_error(libc::ENOSYS).unwrap();
+
+        let reply = kernel.recv().unwrap();
+        assert_eq!(reply.unique(), unique);
+        assert_eq!(reply.error(), libc::ENOSYS);
+        assert!(reply.data().is_empty());
+
+        session.notifier().poll_wakeup(7).unwrap();
+        let notify = kernel.recv().unwrap();
+        assert_eq!(
+            notify.notify_code(),
+            Some(fuse_notify_code::FUSE_NOTIFY_POLL as u32)
+        );
+        let wakeup: fuse_notify_poll_wakeup_out = notify.arg().unwrap();
+        assert_eq!(wakeup.kh, 7);
+    }
+
+    #[test]
+    fn notifications() {
+        let (kernel, session) = MockKernel::new(KernelConfig::default()).unwrap();
+        let notifier = session.notifier();
+
+        notifier.inval_entry(1, "foo").unwrap();
+        let notify = kernel.recv().unwrap();
+        assert_eq!(
+            notify.notify_code(),
+            Some(fuse_notify_code::FUSE_NOTIFY_INVAL_ENTRY as u32)
+        );
+        let arg: fuse_notify_inval_entry_out = notify.arg().unwrap();
+        assert_eq!((arg.parent, arg.namelen), (1, 3));
+        assert_eq!(notify.data()[mem::size_of_val(&arg)..], b"foo\0"[..]);
+
+        notifier.delete(1, 2, "bar").unwrap();
+        let notify = kernel.recv().unwrap();
+        let arg: fuse_notify_delete_out = notify.arg().unwrap();
+        assert_eq!((arg.parent, arg.child, arg.namelen), (1, 2, 3));
+        assert_eq!(notify.data()[mem::size_of_val(&arg)..], b"bar\0"[..]);
+
+        // The scattered chunks are sent as a single message.
+        let data: &[&[u8]] = &[b"hello, ", b"", b"world"];
+        notifier.store(3, 4, data).unwrap();
+        let notify = kernel.recv().unwrap();
+        assert_eq!(
+            notify.notify_code(),
+            Some(fuse_notify_code::FUSE_NOTIFY_STORE as u32)
+        );
+        let arg: fuse_notify_store_out = notify.arg().unwrap();
+        assert_eq!((arg.nodeid, arg.offset, arg.size), (3, 4, 12));
+        assert_eq!(notify.data()[mem::size_of_val(&arg)..], b"hello, world"[..]);
+
+        let unique = notifier.retrieve(3, 0, 10).unwrap();
+        let notify = kernel.recv().unwrap();
+        let arg: fuse_notify_retrieve_out = notify.arg().unwrap();
+        assert_eq!((arg.nodeid, arg.size, arg.notify_unique), (3, 10, unique));
+        assert_eq!(notify.data().len(), mem::size_of_val(&arg));
+    }
+
+    #[test]
+    fn inval_batcher() {
+        use polyfuse::util::InvalBatcher;
+        use std::{sync::Arc, time::Duration};
+
+        let (kernel, session) = MockKernel::new(KernelConfig::default()).unwrap();
+        let pagesize = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as i64 };
+
+        let batcher = Arc::new(InvalBatcher::new(session.notifier()));
+        batcher.add(2, 10, 20);
+        batcher.add(2, pagesize as u64 + 1, 1);
+        batcher.add(2, 5 * pagesize as u64, 1);
+        batcher.add(3, 0, u64::MAX);
+        assert_eq!(batcher.len(), 2);
+        batcher.flush_inode(3).unwrap();
+        batcher.flush().unwrap();
+        assert!(batcher.is_empty());
+
+        let mut notifies = vec![];
+        while let Some(notify) = kernel.try_recv().unwrap() {
+            let arg: fuse_notify_inval_inode_out = notify.arg().unwrap();
+            notifies.push((arg.ino, arg.off, arg.len));
+        }
+        assert_eq!(
+            notifies,
+            [(3, 0, 0), (2, 0, 2 * pagesize), (2, 5 * pagesize, pagesize)]
+        );
+
+        // The pending ranges are sent when the flusher stops.
+        let flusher = InvalBatcher::spawn_flusher(&batcher, Duration::from_secs(3600)).unwrap();
+        batcher.add(4, 0, 1);
+        drop(flusher);
+        let arg: fuse_notify_inval_inode_out = kernel.recv().unwrap().arg().unwrap();
+        assert_eq!((arg.ino, arg.off, arg.len), (4, 0, pagesize));
+    }
+
+    #[test]
+    fn dir_watcher() {
+        use polyfuse::util::DirWatcher;
+
+        const ENTRY: u32 = fuse_notify_code::FUSE_NOTIFY_INVAL_ENTRY as u32;
+        const INODE: u32 = fuse_notify_code::FUSE_NOTIFY_INVAL_INODE as u32;
+        const DELETE: u32 = fuse_notify_code::FUSE_NOTIFY_DELETE as u32;
+
+        let (kernel, session) = MockKernel::new(KernelConfig::default()).unwrap();
+        let watcher = DirWatcher::new(session.notifier());
+
+        watcher.created(1, "foo".as_ref()).unwrap();
+        watcher.deleted(1, "foo".as_ref(), Some(2)).unwrap();
+        watcher.deleted(1, "bar".as_ref(), None).unwrap();
+        watcher
+            .renamed(1, "baz".as_ref(), 3, "qux".as_ref())
+            .unwrap();
+
+        let mut notifies = vec![];
+        while let Some(notify) = kernel.try_recv().unwrap() {
+            let code = notify.notify_code().unwrap();
+            let target = match code {
+                ENTRY => notify.arg::<fuse_notify_inval_entry_out>().unwrap().parent,
+                INODE => notify.arg::<fuse_notify_inval_inode_out>().unwrap().ino,
+                DELETE => notify.arg::<fuse_notify_delete_out>().unwrap().child,
+                _ => panic!("unexpected notification: {}", code),
+            };
+            notifies.push((code, target));
+        }
+        assert_eq!(
+            notifies,
+            [
+                (ENTRY, 1),
+                (INODE, 1),
+                (DELETE, 2),
+                (INODE, 1),
+                (ENTRY, 1),
+                (INODE, 1),
+                (ENTRY, 1),
+                (ENTRY, 3),
+                (INODE, 1),
+                (INODE, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn store_spliced() {
+        let (kernel, session) = MockKernel::new(KernelConfig::default()).unwrap();
+
+        let head = vec![b'a'; 100];
+        let tail = vec![b'b'; 3 * 4096];
+        let data: &[&[u8]] = &[&head, &tail];
+        session.notifier().store_spliced(3, 4, data).unwrap();
+
+        let notify = kernel.recv().unwrap();
+        assert_eq!(
+            notify.notify_code(),
+            Some(fuse_notify_code::FUSE_NOTIFY_STORE as u32)
+        );
+        let arg: fuse_notify_store_out = notify.arg().unwrap();
+        assert_eq!((arg.nodeid, arg.offset, arg.size), (3, 4, 100 + 3 * 4096));
+        let payload = &notify.data()[mem::size_of_val(&arg)..];
+        assert_eq!(payload[..100], head[..]);
+        assert_eq!(payload[100..], tail[..]);
+    }
+
+    #[test]
+    fn decode_built_requests() {
+        use polyfuse::Operation;
+
+        let (kernel, session) = MockKernel::new(KernelConfig::default()).unwrap();
+
+        kernel
+            .send_request(&RequestBuilder::mkdir(1, "dir", 0o755, 0o022))
+            .unwrap();
+        kernel
+            .send_request(&RequestBuilder::write(2, 3, 4, b"hello"))
+            .unwrap();
+        kernel
+            .send_request(&RequestBuilder::lookup(1, "a/b"))
+            .unwrap();
+
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Mkdir(op) => {
+                assert_eq!(op.parent(), 1);
+                assert_eq!(op.name(), "dir");
+                assert_eq!(op.mode(), 0o755);
+                assert_eq!(op.umask(), 0o022);
+            }
+            _ => panic!("unexpected operation"),
+        }
+
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Write(op, mut data) => {
+                assert_eq!((op.ino(), op.fh(), op.offset(), op.size()), (2, 3, 4, 5));
+                let mut buf = Vec::new();
+                io::Read::read_to_end(&mut data, &mut buf).unwrap();
+                assert_eq!(buf, b"hello");
+            }
+            _ => panic!("unexpected operation"),
+        }
+
+        // The names containing a slash are rejected by the decoder.
+        let req = session.next_request().unwrap().unwrap();
+        assert!(req.operation().is_err());
+    }
+
+    #[test]
+    fn process_context() {
+        use polyfuse::{reply::AttrOut, Errno, Operation};
+
+        let (kernel, session) = MockKernel::new(KernelConfig::default()).unwrap();
+        kernel.set_credentials(1000, 100, 42);
+        let unique = kernel.send_request(&RequestBuilder::getattr(2)).unwrap();
+        kernel
+            .send_request(&RequestBuilder::interrupt(unique))
+            .unwrap();
+
+        let req = session.next_request().unwrap().unwrap();
+        let interrupt = session.next_request().unwrap().unwrap();
+        interrupt.process(|_, _| Ok::<_, Errno>(())).unwrap();
+
+        req.process(|cx, op| match op {
+            Operation::Getattr(op) => {
+                assert_eq!((cx.uid(), cx.gid(), cx.pid()), (1000, 100, 42));
+                assert!(cx.is_interrupted());
+                assert_eq!(cx.connection_info().raw().max_write, session.max_write());
+                let mut out = AttrOut::default();
+                out.attr().ino(op.ino());
+                cx.reply(out)?;
+                Ok::<_, Errno>(())
+            }
+            _ => panic!("unexpected operation"),
+        })
+        .unwrap();
+
+        let reply = kernel.recv().unwrap();
+        assert_eq!((reply.unique(), reply.error()), (unique, 0));
+    }
+
+    #[test]
+    fn process_errors() {
+        use polyfuse::{Errno, Operation};
+
+        let (kernel, session) = MockKernel::new(KernelConfig::default()).unwrap();
+        let missing = kernel
+            .send_request(&RequestBuilder::lookup(1, "missing"))
+            .unwrap();
+        kernel.send_request(&RequestBuilder::forget(2, 1)).unwrap();
+        let malformed = kernel
+            .send_request(&RequestBuilder::lookup(1, "a/b"))
+            .unwrap();
+
+        let req = session.next_request().unwrap().unwrap();
+        req.process(|_, op| match op {
+            Operation::Lookup(op) => {
+                std::fs::metadata(std::path::Path::new("/nonexistent").join(op.name())).map(drop)
+            }
+            _ => panic!("unexpected operation"),
+        })
+        .unwrap();
+        let reply = kernel.recv().unwrap();
+        assert_eq!((reply.unique(), reply.error()), (missing, libc::ENOENT));
+
+        // The forget requests are not replied to.
+        let req = session.next_request().unwrap().unwrap();
+        req.process(|_, _| Err(Errno::io())).unwrap();
+
+        let req = session.next_request().unwrap().unwrap();
+        assert!(req.process(|_, _| Ok::<_, Errno>(())).is_err());
+        let reply = kernel.recv().unwrap();
+        assert_eq!((reply.unique(), reply.error()), (malformed, libc::EIO));
+
+        // The error after replying is not replied again.
+        let replied = kernel.send_request(&RequestBuilder::getattr(2)).unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        req.process(|cx, _| {
+            cx.reply(())?;
+            Err(Errno::io())
+        })
+        .unwrap();
+        let reply = kernel.recv().unwrap();
+        assert_eq!((reply.unique(), reply.error()), (replied, 0));
+        assert!(kernel.try_recv().unwrap().is_none());
+
+        // So is the error after replying through a sender of the request.
+        let replied = kernel.send_request(&RequestBuilder::getattr(2)).unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        let sender = req.reply_sender();
+        req.process(|_, _| {
+            std::thread::spawn(move || sender.reply(()))
+                .join()
+                .unwrap()?;
+            Err(Errno::io())
+        })
+        .unwrap();
+        let reply = kernel.recv().unwrap();
+        assert_eq!((reply.unique(), reply.error()), (replied, 0));
+        assert!(kernel.try_recv().unwrap().is_none());
+
+        // The failure of the reply is returned as is.
+        kernel.send_request(&RequestBuilder::getattr(2)).unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        drop(kernel);
+        let err = req
+            .process(|cx, _| {
+                cx.reply(())?;
+                Ok::<_, Errno>(())
+            })
+            .unwrap_err();
+        assert!(matches!(err, polyfuse::Error::Io(..)), "{:?}", err);
+    }
+
+    #[test]
+    fn reply_combinators() {
+        let (kernel, session) = MockKernel::new(KernelConfig::default()).unwrap();
+        let found = kernel.send_request(&RequestBuilder::getattr(5)).unwrap();
+        let missing = kernel.send_request(&RequestBuilder::getattr(6)).unwrap();
+
+        let req = session.next_request().unwrap().unwrap();
+        let mut st = unsafe { std::mem::zeroed::<libc::stat>() };
+        st.st_ino = 5;
+        st.st_mode = libc::S_IFREG | 0o644;
+        req.attr_or_errno(Ok::<_, io::Error>(st)).unwrap();
+        let reply = kernel.recv().unwrap();
+        assert_eq!((reply.unique(), reply.error()), (found, 0));
+        let out: fuse_attr_out = reply.arg().unwrap();
+        assert_eq!((out.attr.ino, out.attr.mode), (5, libc::S_IFREG | 0o644));
+
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_result(Err::<(), _>(polyfuse::Errno::not_found()))
+            .unwrap();
+        let reply = kernel.recv().unwrap();
+        assert_eq!((reply.unique(), reply.error()), (missing, libc::ENOENT));
+    }
+
+    #[test]
+    fn control_dir() {
+        let mut config = KernelConfig::default();
+        config.control_dir(true);
+        let (kernel, session) = MockKernel::new(config).unwrap();
+        const DIR: u64 = 0xFFFF_FFFF_FFFF_FF00;
+        const STATS: u64 = DIR + 1;
+        const LOG_LEVEL: u64 = DIR + 3;
+
+        let mut denied = RequestBuilder::write(LOG_LEVEL, 0, 0, b"trace");
+        denied.credentials(12345, 12345, 1);
+        for req in &[
+            RequestBuilder::lookup(1, ".polyfuse"),
+            RequestBuilder::lookup(DIR, "log_level"),
+            RequestBuilder::write(LOG_LEVEL, 0, 0, b"debug\n"),
+            RequestBuilder::read(LOG_LEVEL, 0, 0, 4096),
+            denied,
+            RequestBuilder::open(STATS, libc::O_WRONLY as u32),
+            RequestBuilder::read(STATS, 0, 0, 4096),
+        ] {
+            kernel.send_request(req).unwrap();
+        }
+        let unique = kernel
+            .send_request(&RequestBuilder::lookup(1, "foo"))
+            .unwrap();
+
+        // The requests on the control directory never reach the filesystem.
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.unique(), unique);
+        assert_eq!(
+            session.log_level().map(|level| level.to_string()),
+            Some("debug".into())
+        );
+
+        let dir: fuse_entry_out = kernel.recv().unwrap().arg().unwrap();
+        assert_eq!(dir.nodeid, DIR);
+        assert_eq!(dir.attr.mode, libc::S_IFDIR | 0o555);
+        let file: fuse_entry_out = kernel.recv().unwrap().arg().unwrap();
+        assert_eq!(file.nodeid, LOG_LEVEL);
+        let out: fuse_write_out = kernel.recv().unwrap().arg().unwrap();
+        assert_eq!(out.size, 6);
+        assert_eq!(kernel.recv().unwrap().data(), b"debug\n");
+        assert_eq!(kernel.recv().unwrap().error(), libc::EACCES);
+        assert_eq!(kernel.recv().unwrap().error(), libc::EACCES);
+
+        let reply = kernel.recv().unwrap();
+        let stats = std::str::from_utf8(reply.data()).unwrap();
+        assert!(stats.contains("requests: 7\n"), "{}", stats);
+        assert!(stats.contains("in_flight: 1\n"), "{}", stats);
+    }
+
+    #[test]
+    fn statx() {
+        use polyfuse::Operation;
+
+        let (kernel, session) = MockKernel::new(KernelConfig::default()).unwrap();
+        let arg = fuse_statx_in {
+            getattr_flags: FUSE_GETATTR_FH,
+            fh: 3,
+            sx_mask: libc::STATX_BASIC_STATS | libc::STATX_BTIME,
+            ..Default::default()
+        };
+        let unique = kernel
+            .send(fuse_opcode::FUSE_STATX, 5, &[arg.as_bytes()])
+            .unwrap();
+
+        let req = session.next_request().unwrap().unwrap();
+        let mut out = req.statx_out();
+        match req.operation().unwrap() {
+            Operation::Statx(op) => {
+                assert_eq!((op.ino(), op.fh()), (5, Some(3)));
+                assert_ne!(op.mask() & libc::STATX_BTIME, 0);
+                let mut attr = polyfuse::reply::AttrOut::default();
+                attr.attr().ino(5);
+                attr.attr().mode(libc::S_IFREG | 0o644);
+                attr.attr().rdev(0x1234_5678);
+                out.attr().copy_from(attr.attr());
+            }
+            _ => panic!("unexpected operation"),
+        }
+        req.reply(out).unwrap();
+
+        // The creation time is not available.
+        let reply = kernel.recv().unwrap();
+        assert_eq!(reply.unique(), unique);
+        let out: fuse_statx_out = reply.arg().unwrap();
+        assert_eq!(out.stat.mask, libc::STATX_BASIC_STATS);
+        assert_eq!(out.stat.mode as u32, libc::S_IFREG | 0o644);
+        assert_eq!(
+            (out.stat.rdev_major, out.stat.rdev_minor),
+            (0x456, 0x1_2378)
+        );
+    }
+
+    #[test]
+    fn restrict_callers() {
+        let owner = unsafe { libc::getuid() };
+        let mut config = KernelConfig::default();
+        config.allow_caller(2000);
+        let (kernel, session) = MockKernel::new(config).unwrap();
+
+        let mut rejected = RequestBuilder::getattr(1);
+        rejected.credentials(3000, 3000, 42);
+        let rejected = kernel.send_request(&rejected).unwrap();
+        let mut forget = RequestBuilder::forget(2, 1);
+        forget.credentials(3000, 3000, 42);
+        let forget = kernel.send_request(&forget).unwrap();
+        let mut allowed = RequestBuilder::getattr(1);
+        allowed.credentials(2000, 2000, 42);
+        let allowed = kernel.send_request(&allowed).unwrap();
+        let mut own = RequestBuilder::getattr(1);
+        own.credentials(owner, owner, 42);
+        let own = kernel.send_request(&own).unwrap();
+
+        for &unique in &[forget, allowed, own] {
+            let req = session.next_request().unwrap().unwrap();
+            assert_eq!(req.unique(), unique);
+        }
+        let reply = kernel.recv().unwrap();
+        assert_eq!((reply.unique(), reply.error()), (rejected, libc::EACCES));
+    }
+
+    #[test]
+    fn into_parts() {
+        use polyfuse::{Operation, Request};
+
+        let (kernel, session) = MockKernel::new(KernelConfig::default()).unwrap();
+        let unique = kernel
+            .send_request(&RequestBuilder::mkdir(1, "dir", 0o755, 0o022))
+            .unwrap();
+
+        let req = session.next_request().unwrap().unwrap();
+        let (header, arg, sender) = req.into_parts();
+        assert_eq!(header.unique(), unique);
+        assert_eq!(header.opcode(), fuse_opcode::FUSE_MKDIR as u32);
+        assert_eq!(header.nodeid(), 1);
+
+        // Decode and reply on another thread.
+        std::thread::spawn(move || {
+            let req = Request::from_parts(header, arg, sender);
+            match req.operation().unwrap() {
+                Operation::Mkdir(op) => assert_eq!(op.name(), "dir"),
+                _ => panic!("unexpected operation"),
+            }
+            req.reply_sender().reply_error(libc::EEXIST).unwrap();
+        })
+        .join()
+        .unwrap();
+
+        let reply = kernel.recv().unwrap();
+        assert_eq!(reply.unique(), unique);
+        assert_eq!(reply.error(), libc::EEXIST);
+    }
+
+    #[test]
+    fn security_context() {
+        use polyfuse::Operation;
+
+        let (kernel, session) = MockKernel::new(KernelConfig::default()).unwrap();
+        let mut mkdir = RequestBuilder::mkdir(1, "dir", 0o755, 0o022);
+        let label = b"system_u:object_r:tmp_t:s0\0";
+        let name = b"security.selinux\0";
+        let size = 8 + 8 + name.len() + label.len();
+        mkdir
+            .data(&(size as u32).to_ne_bytes())
+            .data(&1u32.to_ne_bytes())
+            .data(&(label.len() as u32).to_ne_bytes())
+            .data(&0u32.to_ne_bytes())
+            .data(name)
+            .data(label);
+        kernel.send_request(&mkdir).unwrap();
+        kernel
+            .send_request(&RequestBuilder::mkdir(1, "plain", 0o755, 0o022))
+            .unwrap();
+
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Mkdir(op) => {
+                assert_eq!(op.name(), "dir");
+                let secctx = op.security_context().unwrap();
+                assert_eq!(secctx.name(), "security.selinux");
+                assert_eq!(secctx.value(), &label[..]);
+            }
+            _ => panic!("unexpected operation"),
+        }
+
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Mkdir(op) => assert!(op.security_context().is_none()),
+            _ => panic!("unexpected operation"),
+        }
+    }
+
+    #[test]
+    fn request_from_bytes() {
+        use polyfuse::{Error, Operation};
+
+        let (kernel, session) = MockKernel::new(KernelConfig::default()).unwrap();
+        assert!(session.request_buffer_size() >= session.max_write() as usize);
+
+        let msg = RequestBuilder::write(2, 3, 4, b"hello").unique(42).build();
+        let req = session.request_from_bytes(msg).unwrap();
+        assert_eq!(req.unique(), 42);
+        match req.operation().unwrap() {
+            Operation::Write(op, mut data) => {
+                assert_eq!((op.ino(), op.fh(), op.offset(), op.size()), (2, 3, 4, 5));
+                let mut buf = Vec::new();
+                io::Read::read_to_end(&mut data, &mut buf).unwrap();
+                assert_eq!(buf, b"hello");
+            }
+            _ => panic!("unexpected operation"),
+        }
+        let (header, arg, sender) = req.into_parts();
+        assert_eq!(header.nodeid(), 2);
+        assert_eq!(&arg[arg.len() - 5..], b"hello");
+        sender.reply_error(libc::EIO).unwrap();
+
+        let reply = kernel.recv().unwrap();
+        assert_eq!(reply.unique(), 42);
+        assert_eq!(reply.error(), libc::EIO);
+
+        let mut msg = RequestBuilder::getattr(1).build();
+        msg.push(0);
+        let res = session.request_from_bytes(msg);
+        assert!(matches!(res, Err(Error::Protocol(..))));
+        let res = session.request_from_bytes(vec![0; 4]);
+        assert!(matches!(res, Err(Error::Protocol(..))));
+    }
+
+    #[test]
+    fn default_ttl() {
+        let mut config = KernelConfig::default();
+        config.entry_ttl(std::time::Duration::from_secs(5));
+        let (kernel, session) = MockKernel::new(config).unwrap();
+
+        kernel
+            .send(fuse_opcode::FUSE_LOOKUP, 1, &[b"foo\0"])
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        let mut out = req.entry_out();
+        out.ino(2);
+        req.reply(out).unwrap();
+
+        let entry_out: fuse_entry_out = kernel.recv().unwrap().arg().unwrap();
+        assert_eq!(entry_out.nodeid, 2);
+        assert_eq!(entry_out.entry_valid, 5);
+        assert_eq!(entry_out.attr_valid, 1);
+    }
+
+    #[test]
+    fn run_fast_paths() {
+        use polyfuse::{op::Forget, Handler, Request};
+        use std::sync::{mpsc, Arc};
+
+        #[derive(Debug, PartialEq)]
+        enum Event {
+            Forget(u64, u64),
+            Interrupt(u64),
+        }
+
+        struct TestHandler {
+            events: mpsc::Sender<Event>,
+            release: Arc<Mutex<mpsc::Receiver<()>>>,
+        }
+
+        impl Handler for TestHandler {
+            fn handle(&mut self, req: Request) {
+                let release = self.release.clone();
+                std::thread::spawn(move || {
+                    release.lock().unwrap().recv().unwrap();
+                    req.reply_error(libc::EINTR).unwrap();
+                });
+            }
+
+            fn forget(&mut self, forgets: &[Forget]) {
+                for forget in forgets {
+                    let event = Event::Forget(forget.ino(), forget.nlookup());
+                    self.events.send(event).unwrap();
+                }
+            }
+
+            fn interrupt(&mut self, unique: u64) {
+                self.events.send(Event::Interrupt(unique)).unwrap();
+            }
+        }
+
+        let (kernel, session) = MockKernel::new(KernelConfig::default()).unwrap();
+        let (events_tx, events) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel();
+        let handler = TestHandler {
+            events: events_tx,
+            release: Arc::new(Mutex::new(release_rx)),
+        };
+        let run = std::thread::spawn(move || session.run(handler));
+
+        let unique = kernel
+            .send_request(&RequestBuilder::lookup(1, "slow"))
+            .unwrap();
+        kernel.send_request(&RequestBuilder::forget(2, 3)).unwrap();
+        kernel.interrupt(unique).unwrap();
+
+        // The pending lookup does not block the forget and the interrupt.
+        assert_eq!(events.recv().unwrap(), Event::Forget(2, 3));
+        assert_eq!(events.recv().unwrap(), Event::Interrupt(unique));
+        assert!(kernel.try_recv().unwrap().is_none());
+
+        release.send(()).unwrap();
+        let reply = kernel.recv().unwrap();
+        assert_eq!(reply.unique(), unique);
+        assert_eq!(reply.error(), libc::EINTR);
+
+        drop(kernel);
+        run.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn forget_fast_path() {
+        let mut config = KernelConfig::default();
+        config.control_dir(true);
+        let (kernel, session) = MockKernel::new(config).unwrap();
+
+        kernel.send_request(&RequestBuilder::forget(2, 3)).unwrap();
+        kernel
+            .send_request(&RequestBuilder::forget(0xFFFF_FFFF_FFFF_FF00, 1))
+            .unwrap();
+        kernel.send_request(&RequestBuilder::forget(4, 1)).unwrap();
+        let unique = kernel.send_request(&RequestBuilder::getattr(2)).unwrap();
+
+        let mut forgotten = vec![];
+        let req = session
+            .next_request_or_forget(|forgets| {
+                forgotten.extend(forgets.iter().map(|f| (f.ino(), f.nlookup())))
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(req.unique(), unique);
+        // The forget on the control directory is swallowed.
+        assert_eq!(forgotten, [(2, 3), (4, 1)]);
+
+        kernel.send_request(&RequestBuilder::forget(5, 1)).unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.nodeid(), 5);
+    }
+
+    #[test]
+    fn worker_pool_sharding() {
+        use polyfuse::util::{WorkerOptions, WorkerPool};
+        use std::{collections::HashMap, sync::mpsc};
+
+        let (kernel, session) = MockKernel::new(KernelConfig::default()).unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let mut options = WorkerOptions::default();
+        options.threads(3).shard_by_nodeid(true);
+        let pool = WorkerPool::spawn(&options, move |req| {
+            let worker = std::thread::current().name().map(ToOwned::to_owned);
+            tx.lock().unwrap().send((req.nodeid(), worker)).unwrap();
+            req.reply_error(libc::ENOSYS).unwrap();
+        })
+        .unwrap();
+        let run = std::thread::spawn(move || session.run(pool));
+
+        for i in 0..30 {
+            kernel
+                .send_request(&RequestBuilder::getattr(i % 5))
+                .unwrap();
+        }
+        let mut workers = HashMap::new();
+        for _ in 0..30 {
+            let (nodeid, worker) = rx.recv().unwrap();
+            let worker = worker.unwrap();
+            assert_eq!(
+                *workers.entry(nodeid).or_insert_with(|| worker.clone()),
+                worker
+            );
+        }
+        assert_eq!(workers.len(), 5);
+        assert_eq!(workers[&1], workers[&4]);
+        assert_ne!(workers[&1], workers[&2]);
+        for _ in 0..30 {
+            assert_eq!(kernel.recv().unwrap().error(), libc::ENOSYS);
+        }
+
+        drop(kernel);
+        run.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn worker_pool_failures() {
+        use polyfuse::util::{WorkerOptions, WorkerPool};
+
+        let (kernel, session) = MockKernel::new(KernelConfig::default()).unwrap();
+
+        let mut options = WorkerOptions::default();
+        options.threads(1).shard_by_nodeid(true);
+        let mut pool = WorkerPool::spawn_fallible(&options, |req| match req.nodeid() {
+            2 => panic!("oops"),
+            3 => Err("failed"),
+            _ => req
+                .reply_error(libc::ENOSYS)
+                .map(drop)
+                .map_err(|_| "failed to reply"),
+        })
+        .unwrap();
+        // The session created from the raw fd cannot be unmounted, which is
+        // only logged.
+        pool.unmount_on_error(&session);
+        let run = std::thread::spawn(move || session.run(pool));
+
+        for ino in 2..=4 {
+            kernel.send_request(&RequestBuilder::getattr(ino)).unwrap();
+        }
+        // The worker survives the panic and the error, and the request
+        // whose handler panicked is replied to.
+        let last = kernel.send_request(&RequestBuilder::getattr(1)).unwrap();
+        let reply = kernel.recv().unwrap();
+        assert_eq!(reply.error(), libc::EIO);
+        assert_eq!(reply.unique(), last - 3);
+        let reply = kernel.recv().unwrap();
+        assert_eq!(reply.error(), libc::ENOSYS);
+        assert_eq!(reply.unique(), last - 1);
+        assert_eq!(kernel.recv().unwrap().unique(), last);
+
+        drop(kernel);
+        run.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn memory_budget() {
+        use std::{sync::mpsc, time::Duration};
+
+        let mut config = KernelConfig::default();
+        config.max_write(64 * 1024).memory_budget(100 * 1024);
+        let (kernel, session) = MockKernel::new(config).unwrap();
+
+        for _ in 0..3 {
+            kernel.send_request(&RequestBuilder::getattr(1)).unwrap();
+        }
+
+        // A single request fits in the budget.
+        let req = session.next_request().unwrap().unwrap();
+        let (_header, _arg, sender) = req.into_parts();
+
+        let (tx, rx) = mpsc::channel();
+        let receiver = std::thread::spawn(move || {
+            while let Some(req) = session.next_request().unwrap() {
+                tx.send(req.unique()).unwrap();
+            }
+        });
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // The buffers are returned to the budget as the requests are dropped.
+        let first = sender.unique();
+        drop(sender);
+        assert_eq!(rx.recv().unwrap(), first + 1);
+        assert_eq!(rx.recv().unwrap(), first + 2);
+
+        drop(kernel);
+        receiver.join().unwrap();
+    }
+
+    #[test]
+    fn max_read() {
+        use polyfuse::Operation;
+
+        let mut config = KernelConfig::default();
+        config.max_read(8192);
+        let (kernel, session) = MockKernel::new(config).unwrap();
+        assert_eq!(session.max_read(), Some(8192));
+
+        let oversized = kernel
+            .send_request(&RequestBuilder::read(2, 0, 0, 65536))
+            .unwrap();
+        kernel
+            .send_request(&RequestBuilder::read(2, 0, 0, 8192))
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Read(op) => assert_eq!(op.size(), 8192),
+            _ => panic!("unexpected operation"),
+        }
+
+        // The oversized request is rejected rather than shortened.
+        let reply = kernel.recv().unwrap();
+        assert_eq!(reply.unique(), oversized);
+        assert_eq!(reply.error(), libc::EINVAL);
+    }
+
+    #[test]
+    fn disconnect() {
+        let (kernel, session) = MockKernel::new(KernelConfig::default()).unwrap();
+        drop(kernel);
+        assert!(session.next_request().unwrap().is_none());
+    }
+}
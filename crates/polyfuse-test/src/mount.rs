@@ -0,0 +1,494 @@
+use polyfuse::{KernelConfig, Request, Session};
+use std::{
+    ffi::CString,
+    fmt, io, mem,
+    os::unix::{net::UnixStream, prelude::*},
+    path::{Path, PathBuf},
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread::{self, JoinHandle},
+};
+
+/// A filesystem mounted in a private user and mount namespace.
+///
+/// The mount is performed by a helper process that unshares its user and
+/// mount namespaces, becomes root in them and mounts `/dev/fuse` on a
+/// temporary directory. The connection is handed back to the test process
+/// and served by a [`Session`] on a background thread, so that the filesystem
+/// is visible only through the helper's view of the directory tree, i.e.
+/// [`path`](NamespaceMount::path). Neither root privilege nor `fusermount`
+/// is required, but the kernel must allow unprivileged user namespaces
+/// (see [`is_supported`](NamespaceMount::is_supported)).
+///
+/// The filesystem is unmounted when the value is dropped. The helper is
+/// killed if the test process dies, so the mount never outlives the test.
+///
+/// ```no_run
+/// use polyfuse::{reply::AttrOut, KernelConfig, Operation};
+/// use polyfuse_test::NamespaceMount;
+///
+/// let mount = NamespaceMount::mount(KernelConfig::default(), |_session, req| {
+///     match req.operation() {
+///         Ok(Operation::Getattr(op)) if op.ino() == 1 => {
+///             let mut out = AttrOut::default();
+///             out.attr().ino(1);
+///             out.attr().mode(libc::S_IFDIR | 0o755);
+///             out.attr().nlink(2);
+///             req.reply(out)
+///         }
+///         _ => req.reply_error(libc::ENOSYS),
+///     }
+///     .map(drop)
+/// })?;
+///
+/// mount.run(|root| {
+///     let metadata = std::fs::metadata(root)?;
+///     assert!(metadata.is_dir());
+///     Ok::<_, std::io::Error>(())
+/// })?;
+///
+/// mount.unmount()?;
+/// # Ok::<_, std::io::Error>(())
+/// ```
+pub struct NamespaceMount {
+    pid: libc::pid_t,
+    control: Option<UnixStream>,
+    mountpoint: PathBuf,
+    session: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl fmt::Debug for NamespaceMount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NamespaceMount")
+            .field("pid", &self.pid)
+            .field("mountpoint", &self.mountpoint)
+            .finish()
+    }
+}
+
+impl Drop for NamespaceMount {
+    fn drop(&mut self) {
+        let _ = self.teardown();
+    }
+}
+
+impl NamespaceMount {
+    /// Return whether the current process can create a user and mount namespace
+    /// and open `/dev/fuse`.
+    ///
+    /// The tests using this harness should be skipped if this returns `false`.
+    pub fn is_supported() -> bool {
+        if !Path::new("/dev/fuse").exists() {
+            return false;
+        }
+
+        let pid = unsafe { libc::fork() };
+        match pid {
+            -1 => false,
+            0 => unsafe {
+                let res = libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS);
+                libc::_exit(if res == 0 { 0 } else { 1 });
+            },
+            pid => match waitpid(pid) {
+                Ok(status) => libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0,
+                Err(..) => false,
+            },
+        }
+    }
+
+    /// Mount a filesystem and serve it with the specified handler.
+    ///
+    /// The handler is called on a background thread for each request
+    /// received by the session. An error returned from the handler stops
+    /// the session, and the subsequent accesses to the filesystem fail
+    /// with `ENOTCONN`.
+    ///
+    /// The mount options in `config` are ignored.
+    pub fn mount<F>(config: KernelConfig, mut f: F) -> io::Result<Self>
+    where
+        F: FnMut(&Session, Request) -> io::Result<()> + Send + 'static,
+    {
+        let mountpoint = make_tempdir()?;
+        let mut mount = Self {
+            pid: -1,
+            control: None,
+            mountpoint,
+            session: None,
+        };
+
+        let (fd, pid, control) = spawn_helper(&mount.mountpoint)?;
+        mount.pid = pid;
+        mount.control = Some(control);
+
+        // The kernel has already queued FUSE_INIT after the mount.
+        let session = unsafe { Session::from_raw_fd(fd, config)? };
+        mount.session = Some(thread::spawn(move || -> io::Result<()> {
+            while let Some(req) = session.next_request()? {
+                f(&session, req)?;
+            }
+            Ok(())
+        }));
+
+        Ok(mount)
+    }
+
+    /// Return the path to the root of the mounted filesystem, as seen from
+    /// the test process.
+    pub fn path(&self) -> PathBuf {
+        let mut path = PathBuf::from(format!("/proc/{}/root", self.pid));
+        path.push(
+            self.mountpoint
+                .strip_prefix("/")
+                .unwrap_or(&self.mountpoint),
+        );
+        path
+    }
+
+    /// Run a closure with the path to the root of the mounted filesystem.
+    pub fn run<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&Path) -> R,
+    {
+        f(&self.path())
+    }
+
+    /// Unmount the filesystem and wait for the session to finish.
+    ///
+    /// The error returned from the handler, if any, is reported here.
+    pub fn unmount(mut self) -> io::Result<()> {
+        self.teardown()
+    }
+
+    fn teardown(&mut self) -> io::Result<()> {
+        let mut result = Ok(());
+
+        if let Some(control) = self.control.take() {
+            // The helper unmounts the filesystem and exits when the control
+            // socket is closed.
+            drop(control);
+            if let Err(err) = waitpid(self.pid) {
+                result = Err(err);
+            }
+        }
+
+        if let Some(session) = self.session.take() {
+            let res = session.join().unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "the handler panicked",
+                ))
+            });
+            result = result.and(res);
+        }
+
+        let _ = std::fs::remove_dir(&self.mountpoint);
+
+        result
+    }
+}
+
+fn make_tempdir() -> io::Result<PathBuf> {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    loop {
+        let path = std::env::temp_dir().join(format!(
+            "polyfuse-test.{}.{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst),
+        ));
+        match std::fs::create_dir(&path) {
+            Ok(()) => return Ok(path),
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn waitpid(pid: libc::pid_t) -> io::Result<libc::c_int> {
+    let mut status = 0;
+    loop {
+        let res = unsafe { libc::waitpid(pid, &mut status, 0) };
+        if res != -1 {
+            return Ok(status);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Everything the helper needs, prepared before `fork` since the child of
+/// a multi-threaded process must not allocate.
+struct HelperArgs {
+    mountpoint: CString,
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+}
+
+fn spawn_helper(mountpoint: &Path) -> io::Result<(RawFd, libc::pid_t, UnixStream)> {
+    let args = HelperArgs {
+        mountpoint: CString::new(mountpoint.as_os_str().as_bytes())?,
+        uid_map: format!("0 {} 1\n", unsafe { libc::geteuid() }).into_bytes(),
+        gid_map: format!("0 {} 1\n", unsafe { libc::getegid() }).into_bytes(),
+    };
+
+    let (parent, child) = UnixStream::pair()?;
+
+    let pid = syscall! { fork() };
+    if pid == 0 {
+        drop(parent);
+        unsafe { run_helper(&args, child.as_raw_fd()) }
+    }
+    drop(child);
+
+    let fd = match recv_fd(parent.as_raw_fd()) {
+        Ok(fd) => fd,
+        Err(err) => {
+            drop(parent);
+            let _ = waitpid(pid);
+            return Err(err);
+        }
+    };
+
+    Ok((fd, pid, parent))
+}
+
+/// The body of the helper process.
+///
+/// Only async-signal-safe functions are called here.
+unsafe fn run_helper(args: &HelperArgs, sock: RawFd) -> ! {
+    // Kill the helper, and hence the mount, if the test process dies.
+    libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+
+    let code = match setup_mount(args) {
+        Ok(fd) => {
+            let code = send_fd(sock, fd, 0);
+            libc::close(fd);
+            code
+        }
+        Err(errno) => send_fd(sock, -1, errno),
+    };
+    if code != 0 {
+        libc::_exit(1);
+    }
+
+    // Wait for the control socket to be closed.
+    let mut buf = [0u8; 1];
+    while libc::read(sock, buf.as_mut_ptr() as *mut libc::c_void, 1) != 0 {
+        if *libc::__errno_location() != libc::EINTR {
+            break;
+        }
+    }
+
+    libc::umount2(args.mountpoint.as_ptr(), libc::MNT_DETACH);
+    libc::_exit(0);
+}
+
+unsafe fn setup_mount(args: &HelperArgs) -> Result<RawFd, libc::c_int> {
+    macro_rules! check {
+        ($e:expr) => {{
+            let res = $e;
+            if res == -1 {
+                return Err(*libc::__errno_location());
+            }
+            res
+        }};
+    }
+
+    check!(libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS));
+
+    // setgroups(2) must be denied before writing gid_map as an unprivileged user.
+    // The file is missing on old kernels.
+    match write_file(b"/proc/self/setgroups\0", b"deny") {
+        Ok(()) => {}
+        Err(libc::ENOENT) => {}
+        Err(errno) => return Err(errno),
+    }
+    write_file(b"/proc/self/uid_map\0", &args.uid_map)?;
+    write_file(b"/proc/self/gid_map\0", &args.gid_map)?;
+
+    // Keep the mount away from the parent namespace.
+    check!(libc::mount(
+        ptr::null(),
+        b"/\0".as_ptr() as *const libc::c_char,
+        ptr::null(),
+        libc::MS_REC | libc::MS_PRIVATE,
+        ptr::null(),
+    ));
+
+    // The device must be opened in the new user namespace.
+    let fd = check!(libc::open(
+        b"/dev/fuse\0".as_ptr() as *const libc::c_char,
+        libc::O_RDWR | libc::O_CLOEXEC,
+    ));
+
+    let mut opts = [0u8; 128];
+    let mut len = 0;
+    for chunk in &[
+        &b"fd="[..],
+        format_fd(fd, &mut [0u8; 16]),
+        &b",rootmode=40000,user_id=0,group_id=0\0"[..],
+    ] {
+        opts[len..len + chunk.len()].copy_from_slice(chunk);
+        len += chunk.len();
+    }
+
+    let res = libc::mount(
+        b"polyfuse-test\0".as_ptr() as *const libc::c_char,
+        args.mountpoint.as_ptr(),
+        b"fuse.polyfuse-test\0".as_ptr() as *const libc::c_char,
+        libc::MS_NOSUID | libc::MS_NODEV,
+        opts.as_ptr() as *const libc::c_void,
+    );
+    if res == -1 {
+        let errno = *libc::__errno_location();
+        libc::close(fd);
+        return Err(errno);
+    }
+
+    Ok(fd)
+}
+
+unsafe fn write_file(path: &[u8], content: &[u8]) -> Result<(), libc::c_int> {
+    let fd = libc::open(path.as_ptr() as *const libc::c_char, libc::O_WRONLY);
+    if fd == -1 {
+        return Err(*libc::__errno_location());
+    }
+    let res = libc::write(fd, content.as_ptr() as *const libc::c_void, content.len());
+    let errno = *libc::__errno_location();
+    libc::close(fd);
+    if res == -1 {
+        return Err(errno);
+    }
+    Ok(())
+}
+
+fn format_fd(fd: RawFd, buf: &mut [u8; 16]) -> &[u8] {
+    let mut n = fd as u32;
+    let mut pos = buf.len();
+    loop {
+        pos -= 1;
+        buf[pos] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    &buf[pos..]
+}
+
+#[repr(C)]
+struct Cmsg {
+    header: libc::cmsghdr,
+    fd: RawFd,
+}
+
+/// Send the result of the mount, along with the descriptor on success.
+unsafe fn send_fd(sock: RawFd, fd: RawFd, errno: libc::c_int) -> libc::c_int {
+    let mut payload = errno;
+    let mut iov = libc::iovec {
+        iov_base: &mut payload as *mut libc::c_int as *mut libc::c_void,
+        iov_len: mem::size_of::<libc::c_int>(),
+    };
+
+    let mut cmsg: Cmsg = mem::zeroed();
+    let mut msg: libc::msghdr = mem::zeroed();
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if fd != -1 {
+        cmsg.header.cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+        cmsg.header.cmsg_level = libc::SOL_SOCKET;
+        cmsg.header.cmsg_type = libc::SCM_RIGHTS;
+        cmsg.fd = fd;
+        msg.msg_control = &mut cmsg as *mut Cmsg as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of::<Cmsg>() as _;
+    }
+
+    if libc::sendmsg(sock, &msg, 0) == -1 {
+        return -1;
+    }
+    0
+}
+
+fn recv_fd(sock: RawFd) -> io::Result<RawFd> {
+    let mut payload: libc::c_int = 0;
+    let mut iov = libc::iovec {
+        iov_base: &mut payload as *mut libc::c_int as *mut libc::c_void,
+        iov_len: mem::size_of::<libc::c_int>(),
+    };
+
+    let mut cmsg: Cmsg = unsafe { mem::zeroed() };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = &mut cmsg as *mut Cmsg as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of::<Cmsg>() as _;
+
+    let len = syscall! { recvmsg(sock, &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if len == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "the mount helper exited unexpectedly",
+        ));
+    }
+    if payload != 0 {
+        return Err(io::Error::from_raw_os_error(payload));
+    }
+
+    if msg.msg_controllen < mem::size_of::<Cmsg>() as _ || cmsg.header.cmsg_type != libc::SCM_RIGHTS
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the mount helper did not send the connection",
+        ));
+    }
+
+    Ok(cmsg.fd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polyfuse::{reply::AttrOut, Operation};
+    use std::fs;
+
+    #[test]
+    fn format_fd_digits() {
+        assert_eq!(format_fd(0, &mut [0; 16]), b"0");
+        assert_eq!(format_fd(7, &mut [0; 16]), b"7");
+        assert_eq!(format_fd(1024, &mut [0; 16]), b"1024");
+    }
+
+    #[test]
+    fn mount_and_stat() {
+        if !NamespaceMount::is_supported() {
+            eprintln!("user namespaces are not available; skipped");
+            return;
+        }
+
+        let mount = NamespaceMount::mount(KernelConfig::default(), |_session, req| {
+            match req.operation() {
+                Ok(Operation::Getattr(op)) if op.ino() == 1 => {
+                    let mut out = AttrOut::default();
+                    out.attr().ino(1);
+                    out.attr().mode(libc::S_IFDIR | 0o755);
+                    out.attr().nlink(2);
+                    req.reply(out)
+                }
+                _ => req.reply_error(libc::ENOSYS),
+            }
+            .map(drop)
+        })
+        .unwrap();
+
+        let mountpoint = mount.mountpoint.clone();
+        mount.run(|root| {
+            let metadata = fs::metadata(root).unwrap();
+            assert!(metadata.is_dir());
+            assert_eq!(metadata.ino(), 1);
+        });
+        mount.unmount().unwrap();
+
+        assert!(!mountpoint.exists());
+    }
+}
@@ -0,0 +1,249 @@
+use polyfuse_kernel::*;
+use std::{convert::TryFrom, ffi::OsStr, mem, os::unix::prelude::*};
+use zerocopy::AsBytes;
+
+/// A builder of raw FUSE request messages.
+///
+/// The message consists of `fuse_in_header` followed by the payload
+/// appended with [`arg`](RequestBuilder::arg), [`name`](RequestBuilder::name)
+/// and [`data`](RequestBuilder::data) in order. The `len` field of the
+/// header is computed from the payload.
+///
+/// The unset fields of the header are filled by [`MockKernel`](crate::MockKernel)
+/// when the request is sent, or with zeros by [`build`](RequestBuilder::build).
+#[derive(Debug, Clone)]
+pub struct RequestBuilder {
+    opcode: u32,
+    nodeid: u64,
+    unique: Option<u64>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    pid: Option<u32>,
+    payload: Vec<u8>,
+}
+
+impl RequestBuilder {
+    /// Start building a request with the specified opcode.
+    pub fn new(opcode: fuse_opcode) -> Self {
+        Self::with_raw_opcode(opcode as u32)
+    }
+
+    /// Start building a request with a raw opcode, which may be unknown to `polyfuse`.
+    pub fn with_raw_opcode(opcode: u32) -> Self {
+        Self {
+            opcode,
+            nodeid: 0,
+            unique: None,
+            uid: None,
+            gid: None,
+            pid: None,
+            payload: Vec::new(),
+        }
+    }
+
+    /// Set the inode number that the request is targeted.
+    pub fn nodeid(&mut self, nodeid: u64) -> &mut Self {
+        self.nodeid = nodeid;
+        self
+    }
+
+    /// Set the unique ID of the request.
+    pub fn unique(&mut self, unique: u64) -> &mut Self {
+        self.unique = Some(unique);
+        self
+    }
+
+    /// Set the credentials of the caller.
+    pub fn credentials(&mut self, uid: u32, gid: u32, pid: u32) -> &mut Self {
+        self.uid = Some(uid);
+        self.gid = Some(gid);
+        self.pid = Some(pid);
+        self
+    }
+
+    /// Append an argument structure to the payload.
+    pub fn arg<T>(&mut self, arg: &T) -> &mut Self
+    where
+        T: AsBytes + ?Sized,
+    {
+        self.payload.extend_from_slice(arg.as_bytes());
+        self
+    }
+
+    /// Append a NUL-terminated name to the payload.
+    pub fn name<T>(&mut self, name: T) -> &mut Self
+    where
+        T: AsRef<OsStr>,
+    {
+        self.payload.extend_from_slice(name.as_ref().as_bytes());
+        self.payload.push(b'\0');
+        self
+    }
+
+    /// Append raw bytes to the payload.
+    pub fn data(&mut self, data: &[u8]) -> &mut Self {
+        self.payload.extend_from_slice(data);
+        self
+    }
+
+    /// Assemble the message.
+    pub fn build(&self) -> Vec<u8> {
+        self.build_with(0, (0, 0, 0))
+    }
+
+    pub(crate) fn opcode(&self) -> u32 {
+        self.opcode
+    }
+
+    pub(crate) fn unique_id(&self) -> Option<u64> {
+        self.unique
+    }
+
+    pub(crate) fn build_with(&self, unique: u64, (uid, gid, pid): (u32, u32, u32)) -> Vec<u8> {
+        let len = mem::size_of::<fuse_in_header>() + self.payload.len();
+        let header = fuse_in_header {
+            len: u32::try_from(len).expect("request is too large"),
+            opcode: self.opcode,
+            unique: self.unique.unwrap_or(unique),
+            nodeid: self.nodeid,
+            uid: self.uid.unwrap_or(uid),
+            gid: self.gid.unwrap_or(gid),
+            pid: self.pid.unwrap_or(pid),
+            padding: 0,
+        };
+
+        let mut msg = Vec::with_capacity(len);
+        msg.extend_from_slice(header.as_bytes());
+        msg.extend_from_slice(&self.payload[..]);
+        msg
+    }
+}
+
+/// Shortcuts for the frequently used requests.
+impl RequestBuilder {
+    /// `FUSE_LOOKUP`.
+    pub fn lookup(parent: u64, name: impl AsRef<OsStr>) -> Self {
+        let mut b = Self::new(fuse_opcode::FUSE_LOOKUP);
+        b.nodeid(parent).name(name);
+        b
+    }
+
+    /// `FUSE_FORGET`.
+    pub fn forget(ino: u64, nlookup: u64) -> Self {
+        let mut b = Self::new(fuse_opcode::FUSE_FORGET);
+        b.nodeid(ino).arg(&fuse_forget_in { nlookup });
+        b
+    }
+
+    /// `FUSE_GETATTR`.
+    pub fn getattr(ino: u64) -> Self {
+        let mut b = Self::new(fuse_opcode::FUSE_GETATTR);
+        b.nodeid(ino).arg(&fuse_getattr_in::default());
+        b
+    }
+
+    /// `FUSE_MKDIR`.
+    pub fn mkdir(parent: u64, name: impl AsRef<OsStr>, mode: u32, umask: u32) -> Self {
+        let mut b = Self::new(fuse_opcode::FUSE_MKDIR);
+        b.nodeid(parent)
+            .arg(&fuse_mkdir_in { mode, umask })
+            .name(name);
+        b
+    }
+
+    /// `FUSE_UNLINK`.
+    pub fn unlink(parent: u64, name: impl AsRef<OsStr>) -> Self {
+        let mut b = Self::new(fuse_opcode::FUSE_UNLINK);
+        b.nodeid(parent).name(name);
+        b
+    }
+
+    /// `FUSE_RENAME`.
+    pub fn rename(
+        parent: u64,
+        name: impl AsRef<OsStr>,
+        newparent: u64,
+        newname: impl AsRef<OsStr>,
+    ) -> Self {
+        let mut b = Self::new(fuse_opcode::FUSE_RENAME);
+        b.nodeid(parent)
+            .arg(&fuse_rename_in { newdir: newparent })
+            .name(name)
+            .name(newname);
+        b
+    }
+
+    /// `FUSE_OPEN`.
+    pub fn open(ino: u64, flags: u32) -> Self {
+        let mut b = Self::new(fuse_opcode::FUSE_OPEN);
+        b.nodeid(ino).arg(&fuse_open_in {
+            flags,
+            ..Default::default()
+        });
+        b
+    }
+
+    /// `FUSE_READ`.
+    pub fn read(ino: u64, fh: u64, offset: u64, size: u32) -> Self {
+        let mut b = Self::new(fuse_opcode::FUSE_READ);
+        b.nodeid(ino).arg(&fuse_read_in {
+            fh,
+            offset,
+            size,
+            ..Default::default()
+        });
+        b
+    }
+
+    /// `FUSE_WRITE`.
+    pub fn write(ino: u64, fh: u64, offset: u64, data: &[u8]) -> Self {
+        let mut b = Self::new(fuse_opcode::FUSE_WRITE);
+        b.nodeid(ino)
+            .arg(&fuse_write_in {
+                fh,
+                offset,
+                size: u32::try_from(data.len()).expect("data is too large"),
+                ..Default::default()
+            })
+            .data(data);
+        b
+    }
+
+    /// `FUSE_RELEASE`.
+    pub fn release(ino: u64, fh: u64) -> Self {
+        let mut b = Self::new(fuse_opcode::FUSE_RELEASE);
+        b.nodeid(ino).arg(&fuse_release_in {
+            fh,
+            ..Default::default()
+        });
+        b
+    }
+
+    /// `FUSE_INTERRUPT`.
+    pub fn interrupt(unique: u64) -> Self {
+        let mut b = Self::new(fuse_opcode::FUSE_INTERRUPT);
+        b.arg(&fuse_interrupt_in { unique });
+        b
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_lookup() {
+        let msg = RequestBuilder::lookup(1, "foo")
+            .unique(2)
+            .credentials(100, 100, 12)
+            .build();
+
+        let header_len = mem::size_of::<fuse_in_header>();
+        assert_eq!(msg.len(), header_len + 4);
+        assert_eq!(msg[0..4], (msg.len() as u32).to_ne_bytes());
+        assert_eq!(msg[4..8], (fuse_opcode::FUSE_LOOKUP as u32).to_ne_bytes());
+        assert_eq!(msg[8..16], 2u64.to_ne_bytes());
+        assert_eq!(msg[16..24], 1u64.to_ne_bytes());
+        assert_eq!(&msg[header_len..], b"foo\0");
+    }
+}
@@ -0,0 +1,641 @@
+//! Concurrency stress testing of the session machinery.
+//!
+//! [`Stress`] drives a filesystem through a [`MockKernel`] with a large
+//! number of interleaved `LOOKUP`, `OPEN`, `READ`, `WRITE`, `RENAME`,
+//! `RELEASE` and `FORGET` requests, interrupting the in-flight requests at
+//! random. The requests are served by a pool of threads calling the
+//! handler, and the replies are checked against the following invariants:
+//!
+//! * every request, except `FORGET` and `INTERRUPT`, is replied exactly once,
+//! * no reply is sent for an unknown or already replied request,
+//! * no file handle is handed out twice while it is still open,
+//! * `READ` and `WRITE` replies never exceed the requested size.
+//!
+//! The operations are chosen by a pseudo-random generator seeded explicitly,
+//! so a failing run can be retried with the same seed, although the
+//! interleaving of the requests still depends on the thread scheduling.
+
+use crate::{MockKernel, Reply, RequestBuilder};
+use polyfuse::{KernelConfig, Request, Session};
+use polyfuse_kernel::*;
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    fmt, io,
+    os::unix::prelude::*,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The builder of a stress test run.
+#[derive(Debug, Clone)]
+pub struct Stress {
+    seed: u64,
+    iterations: usize,
+    threads: usize,
+    names: usize,
+    max_in_flight: usize,
+    timeout: Duration,
+}
+
+impl Default for Stress {
+    fn default() -> Self {
+        Self {
+            seed: 0x5eed,
+            iterations: 10_000,
+            threads: 4,
+            names: 16,
+            max_in_flight: 64,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// The results of a stress test run.
+#[derive(Debug, Default)]
+pub struct StressReport {
+    requests: usize,
+    replies: usize,
+    errors: HashMap<i32, usize>,
+    violations: Vec<String>,
+}
+
+impl StressReport {
+    /// Return the number of requests sent.
+    pub fn requests(&self) -> usize {
+        self.requests
+    }
+
+    /// Return the number of replies received.
+    pub fn replies(&self) -> usize {
+        self.replies
+    }
+
+    /// Return the number of error replies for each error code.
+    pub fn errors(&self) -> &HashMap<i32, usize> {
+        &self.errors
+    }
+
+    /// Return the detected violations of the invariants.
+    pub fn violations(&self) -> &[String] {
+        &self.violations[..]
+    }
+
+    /// Return whether no violation is detected.
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+impl fmt::Display for StressReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} requests, {} replies, {} violations",
+            self.requests,
+            self.replies,
+            self.violations.len()
+        )?;
+        for violation in &self.violations {
+            write!(f, "\n  {}", violation)?;
+        }
+        Ok(())
+    }
+}
+
+impl Stress {
+    /// Create a stress test with the default parameters.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the seed of the pseudo-random generator.
+    pub fn seed(&mut self, seed: u64) -> &mut Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Set the number of requests to be sent, excluding the releases and forgets
+    /// sent at the end of the run.
+    pub fn iterations(&mut self, iterations: usize) -> &mut Self {
+        self.iterations = iterations;
+        self
+    }
+
+    /// Set the number of threads serving the requests.
+    pub fn threads(&mut self, threads: usize) -> &mut Self {
+        self.threads = std::cmp::max(threads, 1);
+        self
+    }
+
+    /// Set the number of names in the root directory used in the requests.
+    ///
+    /// The names are `f0`, `f1`, and so on.
+    pub fn names(&mut self, names: usize) -> &mut Self {
+        self.names = std::cmp::max(names, 1);
+        self
+    }
+
+    /// Set the maximum number of the requests in flight.
+    pub fn max_in_flight(&mut self, max_in_flight: usize) -> &mut Self {
+        self.max_in_flight = std::cmp::max(max_in_flight, 1);
+        self
+    }
+
+    /// Set how long to wait for the outstanding replies at the end of the run.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Run the stress test against the filesystem served by the handler.
+    ///
+    /// The handler is shared by the serving threads. An error returned
+    /// from the handler is recorded as a violation.
+    pub fn run<F>(&self, config: KernelConfig, f: F) -> io::Result<StressReport>
+    where
+        F: Fn(&Session, Request) -> io::Result<()> + Send + Sync + 'static,
+    {
+        let (kernel, session) = MockKernel::new(config)?;
+        let kernel = Arc::new(kernel);
+        let session = Arc::new(session);
+        let f = Arc::new(f);
+        let state = Arc::new(Shared::default());
+
+        let workers: Vec<_> = (0..self.threads)
+            .map(|_| {
+                let session = session.clone();
+                let f = f.clone();
+                let state = state.clone();
+                thread::spawn(move || loop {
+                    match session.next_request() {
+                        Ok(Some(req)) => {
+                            if let Err(err) = f(&session, req) {
+                                state.violation(format!("handler failed: {}", err));
+                            }
+                        }
+                        Ok(None) => break,
+                        Err(err) => {
+                            state.violation(format!("failed to receive a request: {}", err));
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        let receiver = {
+            let kernel = kernel.clone();
+            let state = state.clone();
+            thread::spawn(move || {
+                while let Ok(reply) = kernel.recv() {
+                    state.on_reply(reply);
+                }
+            })
+        };
+
+        let mut driver = Driver {
+            kernel: &kernel,
+            state: &state,
+            rng: XorShift::new(self.seed),
+            names: self.names,
+            max_in_flight: self.max_in_flight,
+            requests: 0,
+        };
+        let mut result = Ok(());
+        for _ in 0..self.iterations {
+            if let Err(err) = driver.step() {
+                result = Err(err);
+                break;
+            }
+        }
+        if result.is_ok() {
+            result = driver.finish();
+        }
+        let requests = driver.requests;
+
+        let drained = state.wait_drained(self.timeout);
+
+        // Close the connection to stop the workers and the receiver.
+        unsafe {
+            libc::shutdown(kernel.as_raw_fd(), libc::SHUT_RDWR);
+        }
+        for worker in workers {
+            let _ = worker.join();
+        }
+        let _ = receiver.join();
+        result?;
+
+        let mut inner = state.inner.lock().unwrap();
+        if !drained {
+            let mut uniques: Vec<_> = inner.pending.keys().copied().collect();
+            uniques.sort();
+            for unique in uniques {
+                let opcode = inner.pending[&unique].opcode;
+                inner.violations.push(format!(
+                    "no reply for {} (unique={})",
+                    opcode_name(opcode),
+                    unique
+                ));
+            }
+        }
+
+        Ok(StressReport {
+            requests,
+            replies: inner.replies,
+            errors: std::mem::take(&mut inner.errors),
+            violations: std::mem::take(&mut inner.violations),
+        })
+    }
+}
+
+struct Pending {
+    opcode: fuse_opcode,
+    size: u32,
+    fh: u64,
+}
+
+#[derive(Default)]
+struct Shared {
+    inner: Mutex<Inner>,
+    condvar: Condvar,
+}
+
+#[derive(Default)]
+struct Inner {
+    pending: HashMap<u64, Pending>,
+    interrupts: HashSet<u64>,
+    replied: HashSet<u64>,
+    // ino -> number of lookups not forgotten yet.
+    lookups: HashMap<u64, u64>,
+    opened: HashSet<u64>,
+    replies: usize,
+    errors: HashMap<i32, usize>,
+    violations: Vec<String>,
+}
+
+impl Shared {
+    fn violation(&self, msg: String) {
+        self.inner.lock().unwrap().violations.push(msg);
+    }
+
+    fn on_reply(&self, reply: Reply) {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+
+        if reply.is_notification() {
+            return;
+        }
+        inner.replies += 1;
+
+        let unique = reply.unique();
+        if reply.error() != 0 {
+            *inner.errors.entry(reply.error()).or_default() += 1;
+        }
+
+        if inner.interrupts.remove(&unique) {
+            // The filesystem may ask for the interrupt to be requeued.
+            if reply.error() != libc::EAGAIN {
+                inner.violations.push(format!(
+                    "unexpected reply to INTERRUPT (unique={}, error={})",
+                    unique,
+                    reply.error()
+                ));
+            }
+            return;
+        }
+
+        let pending = match inner.pending.remove(&unique) {
+            Some(pending) => pending,
+            None => {
+                let msg = if inner.replied.contains(&unique) {
+                    format!("duplicate reply (unique={})", unique)
+                } else {
+                    format!("reply to unknown request (unique={})", unique)
+                };
+                inner.violations.push(msg);
+                return;
+            }
+        };
+        inner.replied.insert(unique);
+        self.condvar.notify_all();
+
+        if reply.error() != 0 {
+            return;
+        }
+
+        match pending.opcode {
+            fuse_opcode::FUSE_LOOKUP => match reply.arg::<fuse_entry_out>() {
+                Some(out) if out.nodeid != 0 => {
+                    *inner.lookups.entry(out.nodeid).or_default() += 1;
+                }
+                Some(..) => {}
+                None => inner
+                    .violations
+                    .push(format!("truncated LOOKUP reply (unique={})", unique)),
+            },
+            fuse_opcode::FUSE_OPEN => match reply.arg::<fuse_open_out>() {
+                Some(out) => {
+                    if !inner.opened.insert(out.fh) {
+                        inner.violations.push(format!(
+                            "file handle {} handed out twice (unique={})",
+                            out.fh, unique
+                        ));
+                    }
+                }
+                None => inner
+                    .violations
+                    .push(format!("truncated OPEN reply (unique={})", unique)),
+            },
+            fuse_opcode::FUSE_READ if reply.data().len() > pending.size as usize => {
+                inner.violations.push(format!(
+                    "READ replied {} bytes for {} bytes (unique={}, fh={})",
+                    reply.data().len(),
+                    pending.size,
+                    unique,
+                    pending.fh
+                ));
+            }
+            fuse_opcode::FUSE_WRITE => match reply.arg::<fuse_write_out>() {
+                Some(out) if out.size > pending.size => inner.violations.push(format!(
+                    "WRITE reported {} bytes for {} bytes (unique={}, fh={})",
+                    out.size, pending.size, unique, pending.fh
+                )),
+                Some(..) => {}
+                None => inner
+                    .violations
+                    .push(format!("truncated WRITE reply (unique={})", unique)),
+            },
+            _ => {}
+        }
+    }
+
+    fn wait_drained(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut inner = self.inner.lock().unwrap();
+        while !inner.pending.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            inner = self.condvar.wait_timeout(inner, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
+struct Driver<'a> {
+    kernel: &'a MockKernel,
+    state: &'a Shared,
+    rng: XorShift,
+    names: usize,
+    max_in_flight: usize,
+    requests: usize,
+}
+
+impl Driver<'_> {
+    fn step(&mut self) -> io::Result<()> {
+        let name = format!("f{}", self.rng.below(self.names as u64));
+        let (inos, fhs) = {
+            let inner = self.wait_capacity();
+            let inos: Vec<u64> = inner.lookups.keys().copied().collect();
+            let fhs: Vec<u64> = inner.opened.iter().copied().collect();
+            (inos, fhs)
+        };
+        let ino = self.rng.choose(&inos).unwrap_or(1);
+        let fh = self.rng.choose(&fhs);
+
+        match self.rng.below(10) {
+            0 | 1 => self.send(RequestBuilder::lookup(1, &name), 0, 0),
+            2 => self.send(RequestBuilder::getattr(ino), 0, 0),
+            3 => self.send(RequestBuilder::open(ino, libc::O_RDWR as u32), 0, 0),
+            4 => match fh {
+                Some(fh) => {
+                    let size = 1 + self.rng.below(8192) as u32;
+                    let offset = self.rng.below(65536);
+                    self.send(RequestBuilder::read(ino, fh, offset, size), size, fh)
+                }
+                None => Ok(()),
+            },
+            5 => match fh {
+                Some(fh) => {
+                    let data = vec![0x5a; 1 + self.rng.below(4096) as usize];
+                    let offset = self.rng.below(65536);
+                    let req = RequestBuilder::write(ino, fh, offset, &data);
+                    self.send(req, data.len() as u32, fh)
+                }
+                None => Ok(()),
+            },
+            6 => {
+                let newname = format!("f{}", self.rng.below(self.names as u64));
+                self.send(RequestBuilder::rename(1, &name, 1, &newname), 0, 0)
+            }
+            7 => match fh {
+                Some(fh) => self.release(ino, fh),
+                None => Ok(()),
+            },
+            8 => self.forget(ino),
+            _ => self.interrupt(),
+        }
+    }
+
+    /// Release all open handles and forget all looked up inodes.
+    fn finish(&mut self) -> io::Result<()> {
+        // Wait for the in-flight opens to be replied.
+        let _ = self.state.wait_drained(Duration::from_secs(10));
+
+        let fhs: Vec<u64> = {
+            let inner = self.state.inner.lock().unwrap();
+            inner.opened.iter().copied().collect()
+        };
+        for fh in fhs {
+            self.release(1, fh)?;
+        }
+
+        let inos: Vec<u64> = {
+            let inner = self.state.inner.lock().unwrap();
+            inner.lookups.keys().copied().collect()
+        };
+        for ino in inos {
+            self.forget(ino)?;
+        }
+        Ok(())
+    }
+
+    fn wait_capacity(&self) -> std::sync::MutexGuard<'_, Inner> {
+        let mut inner = self.state.inner.lock().unwrap();
+        while inner.pending.len() >= self.max_in_flight {
+            inner = self.state.condvar.wait(inner).unwrap();
+        }
+        inner
+    }
+
+    fn send(&mut self, mut req: RequestBuilder, size: u32, fh: u64) -> io::Result<()> {
+        let opcode = fuse_opcode::try_from(req.opcode()).expect("unknown opcode");
+        let unique = self.kernel.next_unique();
+        req.unique(unique);
+
+        // Register before sending so that the reply is never missed.
+        self.state
+            .inner
+            .lock()
+            .unwrap()
+            .pending
+            .insert(unique, Pending { opcode, size, fh });
+        self.requests += 1;
+        self.kernel.send_request(&req).map(drop)
+    }
+
+    fn release(&mut self, ino: u64, fh: u64) -> io::Result<()> {
+        // The handle may be reused by the filesystem as soon as it is released.
+        self.state.inner.lock().unwrap().opened.remove(&fh);
+        self.send(RequestBuilder::release(ino, fh), 0, fh)
+    }
+
+    fn forget(&mut self, ino: u64) -> io::Result<()> {
+        let nlookup = self.state.inner.lock().unwrap().lookups.remove(&ino);
+        match nlookup {
+            Some(nlookup) => {
+                self.requests += 1;
+                self.kernel
+                    .send_request(&RequestBuilder::forget(ino, nlookup))
+                    .map(drop)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn interrupt(&mut self) -> io::Result<()> {
+        let mut inner = self.state.inner.lock().unwrap();
+        let uniques: Vec<u64> = inner.pending.keys().copied().collect();
+        let target = match self.rng.choose(&uniques) {
+            Some(target) => target,
+            None => return Ok(()),
+        };
+        let unique = self.kernel.next_unique();
+        inner.interrupts.insert(unique);
+        drop(inner);
+
+        self.requests += 1;
+        let mut req = RequestBuilder::interrupt(target);
+        req.unique(unique);
+        self.kernel.send_request(&req).map(drop)
+    }
+}
+
+fn opcode_name(opcode: fuse_opcode) -> &'static str {
+    match opcode {
+        fuse_opcode::FUSE_LOOKUP => "LOOKUP",
+        fuse_opcode::FUSE_GETATTR => "GETATTR",
+        fuse_opcode::FUSE_OPEN => "OPEN",
+        fuse_opcode::FUSE_READ => "READ",
+        fuse_opcode::FUSE_WRITE => "WRITE",
+        fuse_opcode::FUSE_RENAME => "RENAME",
+        fuse_opcode::FUSE_RELEASE => "RELEASE",
+        _ => "request",
+    }
+}
+
+/// xorshift64*, which is good enough for choosing the operations.
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        Self(if seed == 0 {
+            0x9e37_79b9_7f4a_7c15
+        } else {
+            seed
+        })
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn choose<T: Copy>(&mut self, items: &[T]) -> Option<T> {
+        if items.is_empty() {
+            None
+        } else {
+            Some(items[self.below(items.len() as u64) as usize])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polyfuse::{
+        reply::{AttrOut, EntryOut, OpenOut, WriteOut},
+        util::HandleTable,
+        Operation,
+    };
+
+    #[test]
+    fn stress_handle_table() {
+        let handles = Arc::new(HandleTable::<u64>::new());
+        let mut config = KernelConfig::default();
+        config.max_write(64 * 1024);
+        let report = Stress::new()
+            .seed(42)
+            .iterations(3000)
+            .threads(4)
+            .run(config, {
+                let handles = handles.clone();
+                move |_session, req| {
+                    match req.operation() {
+                        Ok(Operation::Lookup(op)) => {
+                            let name = op.name().to_string_lossy();
+                            let mut out = EntryOut::default();
+                            out.ino(name[1..].parse::<u64>().unwrap() + 2);
+                            out.attr().mode(libc::S_IFREG | 0o644);
+                            req.reply(out)
+                        }
+                        Ok(Operation::Getattr(op)) => {
+                            let mut out = AttrOut::default();
+                            out.attr().ino(op.ino());
+                            req.reply(out)
+                        }
+                        Ok(Operation::Open(op)) => {
+                            let mut out = OpenOut::default();
+                            out.fh(handles.insert(op.ino()));
+                            req.reply(out)
+                        }
+                        Ok(Operation::Read(op)) => match handles.get(op.fh()) {
+                            Ok(..) => {
+                                req.reply(&[0u8; 16][..std::cmp::min(16, op.size() as usize)])
+                            }
+                            Err(..) => req.reply_error(libc::EBADF),
+                        },
+                        Ok(Operation::Write(op, _data)) => match handles.get(op.fh()) {
+                            Ok(..) => {
+                                let mut out = WriteOut::default();
+                                out.size(op.size());
+                                req.reply(out)
+                            }
+                            Err(..) => req.reply_error(libc::EBADF),
+                        },
+                        Ok(Operation::Release(op)) => match handles.remove(op.fh()) {
+                            Ok(..) => req.reply(()),
+                            Err(..) => req.reply_error(libc::EBADF),
+                        },
+                        Ok(Operation::Forget(..)) | Ok(Operation::Interrupt(..)) => return Ok(()),
+                        _ => req.reply_error(libc::ENOSYS),
+                    }
+                    .map(drop)
+                }
+            })
+            .unwrap();
+
+        assert!(report.is_ok(), "{}", report);
+        assert!(report.replies() > 0);
+        assert!(handles.is_empty());
+    }
+}
@@ -0,0 +1,459 @@
+//! Recording and replaying the traces of FUSE messages.
+//!
+//! A [`Trace`] is a sequence of the raw request and reply messages exchanged
+//! between the kernel and a filesystem. The trace captured from a live mount
+//! with [`relay`] can be saved as a fixture file and replayed later against
+//! a handler with [`replay`], which verifies that the handler still produces
+//! the same replies byte for byte.
+//!
+//! The replay assumes that the handler replies synchronously, i.e. all the
+//! replies (and notifications) to a request are sent before the handler
+//! returns. The traces of handlers replying out of order should be recorded
+//! with a single-threaded daemon.
+
+use crate::{MockKernel, Reply};
+use polyfuse::{KernelConfig, Request};
+use std::{
+    convert::TryFrom,
+    error, fmt,
+    fs::File,
+    io::{self, prelude::*, BufReader, BufWriter},
+    os::unix::prelude::*,
+    path::Path,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+const MAGIC: &[u8; 8] = b"PFTRACE1";
+
+/// The buffer size used when reading the requests from `/dev/fuse`.
+///
+/// It must be larger than `max_write` plus the size of request headers.
+const RELAY_BUFFER_SIZE: usize = 16 * 1024 * 1024 + 0x1000;
+
+/// The direction of a recorded message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// A request sent from the kernel.
+    Request,
+    /// A reply or notification sent from the filesystem.
+    Reply,
+}
+
+/// A recorded message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    direction: Direction,
+    bytes: Vec<u8>,
+}
+
+impl Record {
+    /// Return the direction of this message.
+    #[inline]
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// Return the raw bytes of this message.
+    #[inline]
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..]
+    }
+}
+
+/// A sequence of recorded messages.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+    records: Vec<Record>,
+}
+
+impl Trace {
+    /// Create an empty trace.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a request message.
+    pub fn push_request(&mut self, bytes: impl Into<Vec<u8>>) {
+        self.records.push(Record {
+            direction: Direction::Request,
+            bytes: bytes.into(),
+        });
+    }
+
+    /// Append a reply message.
+    pub fn push_reply(&mut self, bytes: impl Into<Vec<u8>>) {
+        self.records.push(Record {
+            direction: Direction::Reply,
+            bytes: bytes.into(),
+        });
+    }
+
+    /// Return the recorded messages.
+    #[inline]
+    pub fn records(&self) -> &[Record] {
+        &self.records[..]
+    }
+
+    /// Decode a trace from the reader.
+    ///
+    /// The format is the magic bytes `PFTRACE1`, followed by the records
+    /// each of which consists of the direction (`0` for requests and `1`
+    /// for replies), the length of message as a little-endian `u32`, and
+    /// the message itself.
+    pub fn read_from<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != *MAGIC {
+            return Err(invalid_data("not a trace file"));
+        }
+
+        let mut records = Vec::new();
+        loop {
+            let mut direction = [0u8; 1];
+            if reader.read(&mut direction)? == 0 {
+                break;
+            }
+            let direction = match direction[0] {
+                0 => Direction::Request,
+                1 => Direction::Reply,
+                _ => return Err(invalid_data("unknown direction")),
+            };
+
+            let mut len = [0u8; 4];
+            reader.read_exact(&mut len)?;
+            let mut bytes = vec![0u8; u32::from_le_bytes(len) as usize];
+            reader.read_exact(&mut bytes[..])?;
+
+            records.push(Record { direction, bytes });
+        }
+
+        Ok(Self { records })
+    }
+
+    /// Encode this trace into the writer.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        for record in &self.records {
+            let direction = match record.direction {
+                Direction::Request => 0u8,
+                Direction::Reply => 1u8,
+            };
+            let len = u32::try_from(record.bytes.len())
+                .map_err(|_| invalid_data("message is too large"))?;
+            writer.write_all(&[direction])?;
+            writer.write_all(&len.to_le_bytes())?;
+            writer.write_all(&record.bytes[..])?;
+        }
+        writer.flush()
+    }
+
+    /// Load a trace from the fixture file.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::read_from(BufReader::new(File::open(path)?))
+    }
+
+    /// Save this trace to the fixture file.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.write_to(BufWriter::new(File::create(path)?))
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Relay the messages between a FUSE connection and a session, recording
+/// them into `trace`.
+///
+/// `kernel_fd` is the file descriptor connected to the FUSE kernel driver
+/// (e.g. `/dev/fuse` mounted by `fusermount`), whose ownership is
+/// transferred to the relay. The returned file descriptor should be passed
+/// to `Session::from_raw_fd` in place of `kernel_fd`. The relay thread
+/// finishes when either side of the connection is closed.
+pub fn relay(
+    kernel_fd: RawFd,
+    trace: Arc<Mutex<Trace>>,
+) -> io::Result<(RawFd, JoinHandle<io::Result<()>>)> {
+    let mut fds = [0; 2];
+    syscall! {
+        socketpair(
+            libc::AF_UNIX,
+            libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+            0,
+            fds.as_mut_ptr(),
+        )
+    };
+    let (daemon_fd, session_fd) = (fds[0], fds[1]);
+
+    let handle = thread::spawn(move || {
+        let res = relay_loop(kernel_fd, daemon_fd, &trace);
+        unsafe {
+            libc::close(daemon_fd);
+            libc::close(kernel_fd);
+        }
+        res
+    });
+
+    Ok((session_fd, handle))
+}
+
+fn relay_loop(kernel_fd: RawFd, daemon_fd: RawFd, trace: &Mutex<Trace>) -> io::Result<()> {
+    let mut buf = vec![0u8; RELAY_BUFFER_SIZE];
+    loop {
+        let mut fds = [
+            libc::pollfd {
+                fd: kernel_fd,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: daemon_fd,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        if unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) } == -1 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+
+        if fds[0].revents != 0 {
+            let len =
+                unsafe { libc::read(kernel_fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if len == -1 {
+                let err = io::Error::last_os_error();
+                match err.raw_os_error() {
+                    // The filesystem has been unmounted.
+                    Some(libc::ENODEV) => return Ok(()),
+                    Some(libc::ENOENT) | Some(libc::EINTR) | Some(libc::EAGAIN) => (),
+                    _ => return Err(err),
+                }
+            } else {
+                let msg = &buf[..len as usize];
+                trace.lock().unwrap().push_request(msg);
+                syscall! {
+                    write(daemon_fd, msg.as_ptr() as *const libc::c_void, msg.len())
+                };
+            }
+        }
+
+        if fds[1].revents != 0 {
+            let len = unsafe {
+                libc::recv(
+                    daemon_fd,
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            match len {
+                -1 => return Err(io::Error::last_os_error()),
+                // The session has been closed.
+                0 => return Ok(()),
+                len => {
+                    let msg = &buf[..len as usize];
+                    trace.lock().unwrap().push_reply(msg);
+                    let res = unsafe {
+                        libc::write(kernel_fd, msg.as_ptr() as *const libc::c_void, msg.len())
+                    };
+                    if res == -1 {
+                        let err = io::Error::last_os_error();
+                        // The request has already been interrupted.
+                        if err.raw_os_error() != Some(libc::ENOENT) {
+                            return Err(err);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// An error during replaying a trace.
+#[derive(Debug)]
+pub enum ReplayError {
+    /// An I/O error in the mocked connection or the handler.
+    Io(io::Error),
+
+    /// The handler replied differently from the trace.
+    Mismatch {
+        /// The index of the request record that the reply is associated with.
+        index: usize,
+        /// The recorded reply, or `None` if the handler sent an extra message.
+        expected: Option<Vec<u8>>,
+        /// The actual reply, or `None` if the handler did not reply.
+        actual: Option<Vec<u8>>,
+    },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Io(err) => write!(f, "I/O error during replay: {}", err),
+            ReplayError::Mismatch {
+                index,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "reply mismatch at record #{}: expected {:?}, actual {:?}",
+                index, expected, actual
+            ),
+        }
+    }
+}
+
+impl error::Error for ReplayError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ReplayError::Io(err) => Some(err),
+            ReplayError::Mismatch { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for ReplayError {
+    fn from(err: io::Error) -> Self {
+        ReplayError::Io(err)
+    }
+}
+
+/// Replay the requests in the trace against the handler and verify
+/// that the replies match the recorded ones.
+///
+/// The first record must be the `FUSE_INIT` request, which is processed
+/// by the session itself with `config`.
+pub fn replay<F>(trace: &Trace, config: KernelConfig, mut handler: F) -> Result<(), ReplayError>
+where
+    F: FnMut(Request) -> io::Result<()>,
+{
+    let records = trace.records();
+    let init = match records.first() {
+        Some(record) if record.direction == Direction::Request => record,
+        _ => return Err(invalid_data("trace does not start with a request").into()),
+    };
+
+    let (kernel, session, init_reply) = MockKernel::start(config, init.bytes())?;
+    let mut actual = vec![init_reply];
+
+    let mut request_index = 0;
+    for (index, record) in records.iter().enumerate().skip(1) {
+        match record.direction {
+            Direction::Request => {
+                verify(&kernel, request_index, &mut actual)?;
+                request_index = index;
+
+                kernel.send_raw(record.bytes())?;
+                let req = session
+                    .next_request()
+                    .map_err(io::Error::from)?
+                    .ok_or_else(|| invalid_data("session is closed"))?;
+                handler(req)?;
+                while let Some(reply) = kernel.try_recv()? {
+                    actual.push(reply);
+                }
+            }
+            Direction::Reply => {
+                let reply = if actual.is_empty() {
+                    None
+                } else {
+                    Some(actual.remove(0).to_bytes())
+                };
+                if reply.as_deref() != Some(record.bytes()) {
+                    return Err(ReplayError::Mismatch {
+                        index: request_index,
+                        expected: Some(record.bytes.clone()),
+                        actual: reply,
+                    });
+                }
+            }
+        }
+    }
+
+    verify(&kernel, request_index, &mut actual)
+}
+
+/// Check that no unexpected messages are left.
+fn verify(kernel: &MockKernel, index: usize, actual: &mut Vec<Reply>) -> Result<(), ReplayError> {
+    while let Some(reply) = kernel.try_recv()? {
+        actual.push(reply);
+    }
+    if !actual.is_empty() {
+        return Err(ReplayError::Mismatch {
+            index,
+            expected: None,
+            actual: Some(actual.remove(0).to_bytes()),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RequestBuilder;
+    use polyfuse_kernel::*;
+
+    fn record(handler: impl Fn(Request) -> io::Result<()>) -> Trace {
+        let mut trace = Trace::new();
+
+        let init = RequestBuilder::new(fuse_opcode::FUSE_INIT)
+            .unique(1)
+            .arg(&fuse_init_in {
+                major: FUSE_KERNEL_VERSION,
+                minor: FUSE_KERNEL_MINOR_VERSION,
+                max_readahead: 4096,
+                flags: 0,
+            })
+            .build();
+        let (kernel, session, reply) = MockKernel::start(KernelConfig::default(), &init).unwrap();
+        trace.push_request(init);
+        trace.push_reply(reply.to_bytes());
+
+        let lookup = RequestBuilder::lookup(1, "foo")
+            .unique(2)
+            .credentials(0, 0, 1)
+            .build();
+        kernel.send_raw(&lookup).unwrap();
+        handler(session.next_request().unwrap().unwrap()).unwrap();
+        trace.push_request(lookup);
+        trace.push_reply(kernel.recv().unwrap().to_bytes());
+
+        trace
+    }
+
+    #[test]
+    fn save_and_load() {
+        let trace = record(|req| req.reply_error(libc::ENOENT).map(drop));
+
+        let mut buf = Vec::new();
+        trace.write_to(&mut buf).unwrap();
+        assert_eq!(Trace::read_from(&buf[..]).unwrap(), trace);
+    }
+
+    #[test]
+    fn replay_detects_changes() {
+        let trace = record(|req| req.reply_error(libc::ENOENT).map(drop));
+
+        replay(&trace, KernelConfig::default(), |req| {
+            req.reply_error(libc::ENOENT).map(drop)
+        })
+        .unwrap();
+
+        match replay(&trace, KernelConfig::default(), |req| {
+            req.reply_error(libc::EACCES).map(drop)
+        }) {
+            Err(ReplayError::Mismatch { index: 2, .. }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        match replay(&trace, KernelConfig::default(), |_| Ok(())) {
+            Err(ReplayError::Mismatch { actual: None, .. }) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
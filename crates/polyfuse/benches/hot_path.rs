@@ -0,0 +1,210 @@
+//! Benchmarks of the request decoding, the dirent packing and the reply path.
+//!
+//! The session is connected to one end of a `SOCK_SEQPACKET` socket pair
+//! in place of `/dev/fuse`, and the other end plays the kernel.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use polyfuse::{
+    reply::{EntryOut, ReaddirOut},
+    KernelConfig, Request, Session,
+};
+use polyfuse_kernel::*;
+use std::{
+    ffi::OsStr,
+    mem,
+    os::unix::prelude::*,
+    thread::{self, JoinHandle},
+};
+use zerocopy::AsBytes as _;
+
+struct Connection {
+    kernel: RawFd,
+    session: Session,
+}
+
+impl Connection {
+    fn new() -> Self {
+        let mut fds = [0; 2];
+        let res = unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        };
+        assert_eq!(res, 0, "socketpair: {}", std::io::Error::last_os_error());
+
+        let init_in = fuse_init_in {
+            major: FUSE_KERNEL_VERSION,
+            minor: FUSE_KERNEL_MINOR_VERSION,
+            max_readahead: 128 * 1024,
+            flags: FUSE_ASYNC_READ | FUSE_DO_READDIRPLUS | FUSE_MAX_PAGES,
+        };
+        send(fds[0], fuse_opcode::FUSE_INIT, 1, init_in.as_bytes());
+
+        let mut config = KernelConfig::default();
+        config.max_write(128 * 1024);
+        let session = unsafe { Session::from_raw_fd(fds[1], config) }.expect("INIT");
+        recv(fds[0], &mut [0u8; 1024]);
+
+        Self {
+            kernel: fds[0],
+            session,
+        }
+    }
+
+    fn request(&self, opcode: fuse_opcode, arg: &[u8]) -> Request {
+        send(self.kernel, opcode, 2, arg);
+        self.session.next_request().unwrap().expect("disconnected")
+    }
+
+    /// Keep receiving the replies in the background.
+    fn drain(&self) -> JoinHandle<()> {
+        let kernel = self.kernel;
+        thread::spawn(move || {
+            let mut buf = vec![0u8; 256 * 1024];
+            while recv(kernel, &mut buf) > 0 {}
+        })
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe {
+            libc::shutdown(self.kernel, libc::SHUT_RDWR);
+        }
+    }
+}
+
+fn send(fd: RawFd, opcode: fuse_opcode, unique: u64, arg: &[u8]) {
+    let header = fuse_in_header {
+        len: (mem::size_of::<fuse_in_header>() + arg.len()) as u32,
+        opcode: opcode as u32,
+        unique,
+        nodeid: 1,
+        uid: 0,
+        gid: 0,
+        pid: 0,
+        padding: 0,
+    };
+    let iov = [
+        libc::iovec {
+            iov_base: header.as_bytes().as_ptr() as *mut libc::c_void,
+            iov_len: mem::size_of::<fuse_in_header>(),
+        },
+        libc::iovec {
+            iov_base: arg.as_ptr() as *mut libc::c_void,
+            iov_len: arg.len(),
+        },
+    ];
+    let res = unsafe { libc::writev(fd, iov.as_ptr(), 2) };
+    assert!(res > 0, "writev: {}", std::io::Error::last_os_error());
+}
+
+fn recv(fd: RawFd, buf: &mut [u8]) -> usize {
+    let res = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+    if res < 0 {
+        0
+    } else {
+        res as usize
+    }
+}
+
+fn decode(c: &mut Criterion) {
+    let conn = Connection::new();
+    let mut group = c.benchmark_group("decode");
+
+    let req = conn.request(fuse_opcode::FUSE_LOOKUP, b"some-file-name.txt\0");
+    group.bench_function("lookup", |b| b.iter(|| req.operation().unwrap()));
+
+    let req = conn.request(
+        fuse_opcode::FUSE_GETATTR,
+        fuse_getattr_in::default().as_bytes(),
+    );
+    group.bench_function("getattr", |b| b.iter(|| req.operation().unwrap()));
+
+    let mut rename = fuse_rename_in { newdir: 1 }.as_bytes().to_vec();
+    rename.extend_from_slice(b"old-name\0new-name\0");
+    let req = conn.request(fuse_opcode::FUSE_RENAME, &rename);
+    group.bench_function("rename", |b| b.iter(|| req.operation().unwrap()));
+
+    let write_in = fuse_write_in {
+        size: 4096,
+        ..Default::default()
+    };
+    let mut write = write_in.as_bytes().to_vec();
+    write.resize(write.len() + 4096, 0xaa);
+    let req = conn.request(fuse_opcode::FUSE_WRITE, &write);
+    group.bench_function("write_4k", |b| b.iter(|| req.operation().unwrap()));
+
+    group.finish();
+}
+
+fn dirent(c: &mut Criterion) {
+    let names: Vec<String> = (0..4096).map(|i| format!("file-{:06}.dat", i)).collect();
+
+    let mut group = c.benchmark_group("dirent");
+    for &capacity in &[4096usize, 128 * 1024] {
+        let count = {
+            let mut out = ReaddirOut::new(capacity);
+            names
+                .iter()
+                .enumerate()
+                .take_while(|(i, name)| !out.entry(OsStr::new(name), *i as u64, 0, *i as u64 + 1))
+                .count()
+        };
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_function(format!("pack_{}", capacity), |b| {
+            b.iter(|| {
+                let mut out = ReaddirOut::new(capacity);
+                for (i, name) in names.iter().enumerate() {
+                    if out.entry(OsStr::new(name), i as u64, 0, i as u64 + 1) {
+                        break;
+                    }
+                }
+                out
+            })
+        });
+    }
+    group.finish();
+}
+
+fn reply(c: &mut Criterion) {
+    let conn = Connection::new();
+    let _drain = conn.drain();
+    let req = conn.request(
+        fuse_opcode::FUSE_GETATTR,
+        fuse_getattr_in::default().as_bytes(),
+    );
+
+    let mut group = c.benchmark_group("reply");
+
+    group.bench_function("error", |b| {
+        b.iter(|| req.reply_error(libc::ENOENT).unwrap())
+    });
+
+    group.bench_function("entry", |b| {
+        b.iter(|| {
+            let mut out = EntryOut::default();
+            out.ino(2);
+            out.attr().mode(libc::S_IFREG | 0o644);
+            req.reply(out).unwrap()
+        })
+    });
+
+    let data = vec![0x55u8; 64 * 1024];
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("data_64k", |b| b.iter(|| req.reply(&data[..]).unwrap()));
+
+    // Scattered chunks are assembled into a single writev.
+    let chunks: Vec<&[u8]> = data.chunks(4096).collect();
+    group.bench_function("data_64k_chunked", |b| {
+        b.iter(|| req.reply(&chunks[..]).unwrap())
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, decode, dirent, reply);
+criterion_main!(benches);
@@ -0,0 +1,64 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use polyfuse::{
+    bytes::{Bytes, FillBytes},
+    reply::ReaddirOut,
+};
+use polyfuse_kernel::fuse_dirent;
+use std::{convert::TryInto, ffi::OsStr, mem, os::unix::prelude::*};
+
+struct Collect<'a> {
+    chunks: Vec<&'a [u8]>,
+}
+
+impl<'a> FillBytes<'a> for Collect<'a> {
+    fn put(&mut self, chunk: &'a [u8]) {
+        self.chunks.push(chunk);
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+    let capacity = u16::from_le_bytes([data[0], data[1]]) as usize;
+    let mut input = &data[2..];
+
+    // Each entry is encoded as the length of its name, followed by the name.
+    let mut out = ReaddirOut::new(capacity);
+    let mut names = vec![];
+    while let Some((&len, rest)) = input.split_first() {
+        let len = std::cmp::min(len as usize, rest.len());
+        let (name, rest) = rest.split_at(len);
+        input = rest;
+
+        let off = names.len() as u64 + 1;
+        if out.entry(OsStr::from_bytes(name), off, 0, off) {
+            break;
+        }
+        names.push(name);
+    }
+
+    let mut collect = Collect { chunks: vec![] };
+    out.fill_bytes(&mut collect);
+    let buf: Vec<u8> = collect.chunks.concat();
+    assert_eq!(buf.len(), out.size());
+    assert!(buf.len() <= capacity);
+    assert_eq!(buf.len() % 8, 0);
+
+    // Decode the entries back.
+    let mut pos = 0;
+    for (i, name) in names.iter().enumerate() {
+        let header = &buf[pos..pos + mem::size_of::<fuse_dirent>()];
+        let ino = u64::from_ne_bytes(header[0..8].try_into().unwrap());
+        let namelen = u32::from_ne_bytes(header[16..20].try_into().unwrap()) as usize;
+        assert_eq!(ino, i as u64 + 1);
+        assert_eq!(namelen, name.len());
+
+        let start = pos + mem::size_of::<fuse_dirent>();
+        assert_eq!(&buf[start..start + namelen], *name);
+        pos += (mem::size_of::<fuse_dirent>() + namelen + 7) & !7;
+    }
+    assert_eq!(pos, buf.len());
+});
@@ -0,0 +1,231 @@
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How long the information of a process is reused, bounding the chance of
+/// confusing a process with another one reusing its PID.
+const CACHE_TTL: Duration = Duration::from_secs(1);
+
+/// The number of processes in the cache at which the expired ones are purged.
+const CACHE_CAPACITY: usize = 1024;
+
+/// The credentials of the process that issued a request, obtained by
+/// [`Request::caller`](crate::Request::caller).
+///
+/// The other information of the process is read from `/proc` on demand and
+/// cached in the session for a short while, so that the authorization and
+/// audit layers can query it per request without reading `/proc` each time:
+///
+/// ```ignore
+/// let caller = req.caller();
+/// if !caller.groups()?.contains(&TRUSTED_GID) {
+///     tracing::warn!(exe = ?caller.exe().ok(), "access denied");
+///     return Err(Errno::permission_denied());
+/// }
+/// ```
+///
+/// The PID is the one in the PID namespace of the mount, so `/proc` of the
+/// daemon must belong to the same namespace. The processes may have exited
+/// by the time the information is queried, in which case `ESRCH` is
+/// returned. The requests issued by the kernel itself, whose PID is zero,
+/// fail in the same way.
+pub struct Caller<'a> {
+    uid: u32,
+    gid: u32,
+    pid: u32,
+    cache: &'a ProcCache,
+}
+
+impl fmt::Debug for Caller<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Caller")
+            .field("uid", &self.uid)
+            .field("gid", &self.gid)
+            .field("pid", &self.pid)
+            .finish()
+    }
+}
+
+impl<'a> Caller<'a> {
+    pub(crate) fn new(uid: u32, gid: u32, pid: u32, cache: &'a ProcCache) -> Self {
+        Self {
+            uid,
+            gid,
+            pid,
+            cache,
+        }
+    }
+
+    /// Return the effective user ID of the process.
+    #[inline]
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    /// Return the effective group ID of the process.
+    #[inline]
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    /// Return the process ID.
+    #[inline]
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Return the path of the executable of the process.
+    pub fn exe(&self) -> io::Result<PathBuf> {
+        self.query(|info| &info.exe, |pid| fs::read_link(proc_path(pid, "exe")))
+    }
+
+    /// Return the path of the cgroup (v2) the process belongs to.
+    ///
+    /// `NotFound` is returned if the process is not in the unified hierarchy.
+    pub fn cgroup(&self) -> io::Result<String> {
+        self.query(
+            |info| &info.cgroup,
+            |pid| parse_cgroup(&fs::read_to_string(proc_path(pid, "cgroup"))?),
+        )
+    }
+
+    /// Return the supplementary group IDs of the process.
+    pub fn groups(&self) -> io::Result<Vec<u32>> {
+        self.query(
+            |info| &info.groups,
+            |pid| parse_groups(&fs::read_to_string(proc_path(pid, "status"))?),
+        )
+    }
+
+    fn query<T, S, F>(&self, slot: S, read: F) -> io::Result<T>
+    where
+        T: Clone,
+        S: FnOnce(&ProcInfo) -> &Mutex<Option<T>>,
+        F: FnOnce(u32) -> io::Result<T>,
+    {
+        if self.pid == 0 {
+            return Err(io::Error::from_raw_os_error(libc::ESRCH));
+        }
+        let info = self.cache.get(self.pid);
+        let mut slot = slot(&info).lock().unwrap();
+        if let Some(ref value) = *slot {
+            return Ok(value.clone());
+        }
+        let value = read(self.pid).map_err(|err| match err.kind() {
+            // The directory of the process has been removed.
+            io::ErrorKind::NotFound if !proc_path(self.pid, "").exists() => {
+                io::Error::from_raw_os_error(libc::ESRCH)
+            }
+            _ => err,
+        })?;
+        *slot = Some(value.clone());
+        Ok(value)
+    }
+}
+
+/// The cache of the process information, shared by the session.
+pub(crate) struct ProcCache {
+    procs: Mutex<HashMap<u32, Arc<ProcInfo>>>,
+}
+
+struct ProcInfo {
+    created: Instant,
+    exe: Mutex<Option<PathBuf>>,
+    cgroup: Mutex<Option<String>>,
+    groups: Mutex<Option<Vec<u32>>>,
+}
+
+impl ProcInfo {
+    fn is_expired(&self, now: Instant) -> bool {
+        now.duration_since(self.created) >= CACHE_TTL
+    }
+}
+
+impl ProcCache {
+    pub(crate) fn new() -> Self {
+        Self {
+            procs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, pid: u32) -> Arc<ProcInfo> {
+        let now = Instant::now();
+        let mut procs = self.procs.lock().unwrap();
+        if let Some(info) = procs.get(&pid) {
+            if !info.is_expired(now) {
+                return info.clone();
+            }
+        }
+        if procs.len() >= CACHE_CAPACITY {
+            procs.retain(|_, info| !info.is_expired(now));
+        }
+        let info = Arc::new(ProcInfo {
+            created: now,
+            exe: Mutex::new(None),
+            cgroup: Mutex::new(None),
+            groups: Mutex::new(None),
+        });
+        procs.insert(pid, info.clone());
+        info
+    }
+}
+
+fn proc_path(pid: u32, name: &str) -> PathBuf {
+    PathBuf::from(format!("/proc/{}/{}", pid, name))
+}
+
+fn parse_cgroup(content: &str) -> io::Result<String> {
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(ToOwned::to_owned)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not in the cgroup v2 hierarchy"))
+}
+
+fn parse_groups(content: &str) -> io::Result<Vec<u32>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed Groups in status");
+    let line = content
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))
+        .ok_or_else(invalid)?;
+    line.split_whitespace()
+        .map(|gid| gid.parse().map_err(|_| invalid()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_proc_files() {
+        let cgroup = "12:cpu:/legacy\n0::/user.slice/session-1.scope\n";
+        assert_eq!(parse_cgroup(cgroup).unwrap(), "/user.slice/session-1.scope");
+        assert_eq!(
+            parse_cgroup("3:memory:/\n").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        let status = "Name:\tcat\nGid:\t100\t100\t100\t100\nGroups:\t4 24 100 \nVmPeak:\t1 kB\n";
+        assert_eq!(parse_groups(status).unwrap(), vec![4, 24, 100]);
+        assert_eq!(parse_groups("Groups:\t\n").unwrap(), Vec::<u32>::new());
+        assert!(parse_groups("Groups:\tx\n").is_err());
+    }
+
+    #[test]
+    fn query_self() {
+        let cache = ProcCache::new();
+        let caller = Caller::new(0, 0, std::process::id(), &cache);
+        let exe = caller.exe().unwrap();
+        assert_eq!(exe, std::env::current_exe().unwrap());
+        assert!(caller.groups().is_ok());
+
+        // The kernel itself.
+        let caller = Caller::new(0, 0, 0, &cache);
+        assert_eq!(caller.exe().unwrap_err().raw_os_error(), Some(libc::ESRCH));
+    }
+}
@@ -1,8 +1,8 @@
 use libc::{c_int, c_void, iovec};
 use std::{
     cmp,
-    ffi::{OsStr, OsString},
-    io,
+    ffi::{CString, OsStr, OsString},
+    fs, io,
     mem::{self, MaybeUninit},
     os::unix::{net::UnixStream, prelude::*},
     path::{Path, PathBuf},
@@ -11,7 +11,12 @@ use std::{
 };
 
 const FUSERMOUNT_PROG: &str = "/usr/bin/fusermount";
+const FUSERMOUNT3_PROG: &str = "/usr/bin/fusermount3";
 const FUSE_COMMFD_ENV: &str = "_FUSE_COMMFD";
+const FUSE_DEV: &str = "/dev/fuse";
+
+/// The block size of `fuseblk` mounts used by the kernel by default.
+pub(crate) const DEFAULT_BLKSIZE: u32 = 512;
 
 macro_rules! syscall {
     ($fn:ident ( $($arg:expr),* $(,)* ) ) => {{
@@ -29,8 +34,11 @@ macro_rules! syscall {
 pub struct Connection {
     fd: RawFd,
     child: Option<Fusermount>,
-    mountpoint: PathBuf,
+    mountpoint: Option<PathBuf>,
     mountopts: MountOptions,
+    // Resolved before mounting, since resolving it afterwards would access
+    // the filesystem served by this connection.
+    canonical_mountpoint: Option<PathBuf>,
 }
 
 impl Drop for Connection {
@@ -41,16 +49,80 @@ impl Drop for Connection {
 
 impl Connection {
     /// Establish a connection with the FUSE kernel driver.
-    pub(crate) fn open(mountpoint: PathBuf, mountopts: MountOptions) -> io::Result<Self> {
+    pub(crate) fn open(mountpoint: PathBuf, mut mountopts: MountOptions) -> io::Result<Self> {
+        let canonical_mountpoint = mountpoint.canonicalize().ok();
+        // Resolved once, so that the unmount runs the same program.
+        mountopts.fusermount_path = Some(fusermount_path(&mountopts));
         let (fd, child) = mount(&mountpoint, &mountopts)?;
         Ok(Self {
             fd,
             child,
-            mountpoint,
+            mountpoint: Some(mountpoint),
             mountopts,
+            canonical_mountpoint,
         })
     }
 
+    /// Create a connection from an already opened file descriptor.
+    ///
+    /// The descriptor is closed on drop, but no unmount is performed.
+    pub(crate) unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        Self {
+            fd,
+            child: None,
+            mountpoint: None,
+            mountopts: MountOptions::default(),
+            canonical_mountpoint: None,
+        }
+    }
+
+    /// Return the mount options passed to `fusermount`.
+    pub(crate) fn mount_options(&self) -> &[String] {
+        &self.mountopts.options
+    }
+
+    /// Return the block size of the `fuseblk` mount, or `None` if not
+    /// mounted on a block device.
+    pub(crate) fn blksize(&self) -> Option<u32> {
+        let blkdev = self.mountopts.blkdev.is_some()
+            || self
+                .mountopts
+                .options
+                .iter()
+                .any(|option| option == "blkdev");
+        if blkdev {
+            Some(self.mountopts.blksize.unwrap_or(DEFAULT_BLKSIZE))
+        } else {
+            None
+        }
+    }
+
+    /// Abort the connection through the `abort` file of the FUSE control
+    /// filesystem, failing all the pending and future requests.
+    ///
+    /// The connection is found by the device number of the mount in
+    /// `/proc/self/mountinfo`, so the filesystem itself is never accessed.
+    pub(crate) fn abort(&self) -> io::Result<()> {
+        let mountpoint = self
+            .canonical_mountpoint
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "the mountpoint is unknown"))?;
+        let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+        let dev = find_mount_dev(&mountinfo, mountpoint)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "the mount is not found"))?;
+        fs::write(format!("/sys/fs/fuse/connections/{}/abort", dev), "1")
+    }
+
+    /// Detach the mount with `fusermount -u -z`, running the same program
+    /// as the mount.
+    pub(crate) fn lazy_unmount(&self) -> io::Result<()> {
+        let mountpoint = self
+            .mountpoint
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "the mountpoint is unknown"))?;
+        lazy_unmount(&self.mountopts, mountpoint)
+    }
+
     fn read(&self, dst: &mut [u8]) -> io::Result<usize> {
         let len = syscall! {
             read(
@@ -104,7 +176,9 @@ impl Connection {
             let _ = child.wait();
         }
 
-        unmount(&self.mountpoint);
+        if let Some(ref mountpoint) = self.mountpoint {
+            unmount(&self.mountopts, mountpoint);
+        }
     }
 }
 
@@ -172,6 +246,120 @@ impl io::Write for &Connection {
     }
 }
 
+// ==== splice ====
+
+impl Connection {
+    /// Write a message by mapping its pages into a pipe with `vmsplice(2)` and
+    /// splicing them into the connection, without copying the data in between.
+    ///
+    /// The whole message must be in the pipe before splicing it, since the
+    /// kernel accepts only a complete message. `Ok(false)` is returned, with
+    /// nothing written, if the pipe cannot be grown to hold it or if either
+    /// call would block.
+    pub(crate) fn splice_vectored(&self, bufs: &[io::IoSlice<'_>]) -> io::Result<bool> {
+        let pagesize = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+
+        // Each pipe buffer holds (a part of) a single page.
+        let npages: usize = bufs
+            .iter()
+            .filter(|buf| !buf.is_empty())
+            .map(|buf| {
+                let start = buf.as_ptr() as usize % pagesize;
+                (start + buf.len() - 1) / pagesize + 1
+            })
+            .sum();
+
+        let pipe = Pipe::new()?;
+        if !pipe.reserve(npages * pagesize) {
+            return Ok(false);
+        }
+
+        let mut size = 0;
+        for buf in bufs {
+            let mut buf = &buf[..];
+            while !buf.is_empty() {
+                let iov = iovec {
+                    iov_base: buf.as_ptr() as *mut c_void,
+                    iov_len: buf.len(),
+                };
+                let res = unsafe { libc::vmsplice(pipe.wr, &iov, 1, libc::SPLICE_F_NONBLOCK) };
+                if res == -1 {
+                    let err = io::Error::last_os_error();
+                    match err.kind() {
+                        io::ErrorKind::Interrupted => continue,
+                        // The pipe is full, and nothing is written yet.
+                        io::ErrorKind::WouldBlock => return Ok(false),
+                        _ => return Err(err),
+                    }
+                }
+                let n = res as usize;
+                buf = &buf[n..];
+                size += n;
+            }
+        }
+
+        let spliced = loop {
+            let res = unsafe {
+                libc::splice(pipe.rd, ptr::null_mut(), self.fd, ptr::null_mut(), size, 0)
+            };
+            if res == -1 {
+                let err = io::Error::last_os_error();
+                match err.kind() {
+                    io::ErrorKind::Interrupted => continue,
+                    // The message is written to the device at once, so
+                    // nothing is written in this case either.
+                    io::ErrorKind::WouldBlock => return Ok(false),
+                    _ => return Err(err),
+                }
+            }
+            break res as usize;
+        };
+        if spliced < size {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "spliced data is too short",
+            ));
+        }
+
+        Ok(true)
+    }
+}
+
+struct Pipe {
+    rd: RawFd,
+    wr: RawFd,
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.rd);
+            libc::close(self.wr);
+        }
+    }
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        syscall! { pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) };
+        Ok(Self {
+            rd: fds[0],
+            wr: fds[1],
+        })
+    }
+
+    /// Grow the capacity of the pipe to `size` bytes or more.
+    ///
+    /// It fails if `size` exceeds `/proc/sys/fs/pipe-max-size` for an
+    /// unprivileged process.
+    fn reserve(&self, size: usize) -> bool {
+        let size = cmp::min(size, c_int::MAX as usize) as c_int;
+        let res = unsafe { libc::fcntl(self.wr, libc::F_SETPIPE_SZ, size) };
+        res >= size
+    }
+}
+
 // ==== mount ====
 
 #[derive(Debug)]
@@ -179,7 +367,11 @@ pub(crate) struct MountOptions {
     pub(crate) options: Vec<String>,
     pub(crate) auto_unmount: bool,
     pub(crate) fusermount_path: Option<PathBuf>,
+    pub(crate) fusermount3: Option<bool>,
+    pub(crate) fusermount_env: Vec<(OsString, OsString)>,
     pub(crate) fuse_comm_fd: Option<OsString>,
+    pub(crate) blkdev: Option<PathBuf>,
+    pub(crate) blksize: Option<u32>,
 }
 
 impl Default for MountOptions {
@@ -188,7 +380,11 @@ impl Default for MountOptions {
             options: vec![],
             auto_unmount: true,
             fusermount_path: None,
+            fusermount3: None,
+            fusermount_env: vec![],
             fuse_comm_fd: None,
+            blkdev: None,
+            blksize: None,
         }
     }
 }
@@ -208,20 +404,72 @@ impl Fusermount {
     }
 }
 
+/// Find `fusermount3` or `fusermount` in the directories of `PATH`.
+///
+/// Unless the version is specified, `fusermount3` is preferred since the
+/// distributions shipping libfuse 3 may lack `fusermount`. The program in
+/// `/usr/bin` is assumed if none is found.
+fn find_fusermount(path: Option<&OsStr>, fusermount3: Option<bool>) -> PathBuf {
+    let names: &[&str] = match fusermount3 {
+        Some(true) => &["fusermount3"],
+        Some(false) => &["fusermount"],
+        None => &["fusermount3", "fusermount"],
+    };
+    let dirs: Vec<PathBuf> = path
+        .map(|path| {
+            std::env::split_paths(path)
+                .filter(|dir| dir.is_absolute())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    names
+        .iter()
+        .flat_map(|name| dirs.iter().map(move |dir| dir.join(name)))
+        .find(|path| {
+            fs::metadata(path)
+                .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+                .unwrap_or(false)
+        })
+        .unwrap_or_else(|| match fusermount3 {
+            Some(true) => PathBuf::from(FUSERMOUNT3_PROG),
+            _ => PathBuf::from(FUSERMOUNT_PROG),
+        })
+}
+
+fn fusermount_path(mountopts: &MountOptions) -> PathBuf {
+    match mountopts.fusermount_path {
+        Some(ref path) => path.clone(),
+        None => {
+            let path = std::env::var_os("PATH");
+            find_fusermount(path.as_deref(), mountopts.fusermount3)
+        }
+    }
+}
+
+/// Create the command running `fusermount` with the extra environment.
+fn fusermount(mountopts: &MountOptions) -> Command {
+    let mut command = Command::new(fusermount_path(mountopts));
+    command.envs(mountopts.fusermount_env.iter().map(|(k, v)| (k, v)));
+    command
+}
+
 fn mount(mountpoint: &Path, mountopts: &MountOptions) -> io::Result<(RawFd, Option<Fusermount>)> {
+    if let Some(ref device) = mountopts.blkdev {
+        let fd = mount_blkdev(device, mountpoint, mountopts)?;
+        return Ok((fd, None));
+    }
+
     let (input, output) = UnixStream::pair()?;
 
-    let mut fusermount = Command::new(
-        mountopts
-            .fusermount_path
-            .as_deref()
-            .unwrap_or_else(|| Path::new(FUSERMOUNT_PROG)),
-    );
+    let mut fusermount = fusermount(mountopts);
 
+    let blksize = mountopts.blksize.map(|size| format!("blksize={}", size));
     let opts = mountopts
         .options
         .iter()
         .map(|opt| opt.as_str())
+        .chain(blksize.as_deref())
         .chain(if mountopts.auto_unmount {
             Some("auto_unmount")
         } else {
@@ -291,13 +539,180 @@ fn mount(mountpoint: &Path, mountopts: &MountOptions) -> io::Result<(RawFd, Opti
     }
 }
 
-fn unmount(mountpoint: &Path) {
-    let _ = Command::new(FUSERMOUNT_PROG)
+/// Mount the filesystem on a block device as `fuseblk` with mount(2),
+/// which requires `CAP_SYS_ADMIN`.
+///
+/// `fusermount` is not involved, since it refuses `blkdev` unless run by
+/// root anyway.
+fn mount_blkdev(device: &Path, mountpoint: &Path, mountopts: &MountOptions) -> io::Result<RawFd> {
+    let rootmode = fs::metadata(mountpoint)?.mode() & libc::S_IFMT;
+    let device = cstring(device.as_os_str())?;
+    let target = cstring(mountpoint.as_os_str())?;
+
+    let dev = CString::new(FUSE_DEV).unwrap();
+    let fd = syscall! { open(dev.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
+
+    let (fstype, flags, data) = blkdev_mount_data(fd, rootmode, mountopts);
+    let fstype = CString::new(fstype).unwrap();
+    let data = CString::new(data).unwrap();
+    let res = unsafe {
+        libc::mount(
+            device.as_ptr(),
+            target.as_ptr(),
+            fstype.as_ptr(),
+            flags,
+            data.as_ptr() as *const c_void,
+        )
+    };
+    if res == -1 {
+        let err = io::Error::last_os_error();
+        unsafe {
+            libc::close(fd);
+        }
+        return Err(err);
+    }
+
+    Ok(fd)
+}
+
+/// Build the type, the flags and the data passed to mount(2) for a
+/// `fuseblk` mount, in the same way as `fusermount` does.
+fn blkdev_mount_data(
+    fd: RawFd,
+    rootmode: u32,
+    mountopts: &MountOptions,
+) -> (String, libc::c_ulong, String) {
+    let mut fstype = String::from("fuseblk");
+    let mut flags = libc::MS_NOSUID | libc::MS_NODEV;
+    let mut data = format!(
+        "fd={},rootmode={:o},user_id={},group_id={}",
+        fd,
+        rootmode,
+        unsafe { libc::getuid() },
+        unsafe { libc::getgid() },
+    );
+
+    for option in &mountopts.options {
+        let (set, clear) = match option.as_str() {
+            "ro" => (libc::MS_RDONLY, 0),
+            "rw" => (0, libc::MS_RDONLY),
+            "suid" => (0, libc::MS_NOSUID),
+            "nosuid" => (libc::MS_NOSUID, 0),
+            "dev" => (0, libc::MS_NODEV),
+            "nodev" => (libc::MS_NODEV, 0),
+            "exec" => (0, libc::MS_NOEXEC),
+            "noexec" => (libc::MS_NOEXEC, 0),
+            "async" => (0, libc::MS_SYNCHRONOUS),
+            "sync" => (libc::MS_SYNCHRONOUS, 0),
+            "dirsync" => (libc::MS_DIRSYNC, 0),
+            "atime" => (0, libc::MS_NOATIME),
+            "noatime" => (libc::MS_NOATIME, 0),
+            "diratime" => (0, libc::MS_NODIRATIME),
+            "nodiratime" => (libc::MS_NODIRATIME, 0),
+            "relatime" => (libc::MS_RELATIME, 0),
+            "norelatime" => (0, libc::MS_RELATIME),
+            "strictatime" => (libc::MS_STRICTATIME, 0),
+            "nostrictatime" => (0, libc::MS_STRICTATIME),
+            "lazytime" => (libc::MS_LAZYTIME, 0),
+            "nolazytime" => (0, libc::MS_LAZYTIME),
+            "blkdev" | "nonempty" | "auto_unmount" => (0, 0),
+            // The device is the source of the mount.
+            option if option.starts_with("fsname=") => (0, 0),
+            option => {
+                match option.strip_prefix("subtype=") {
+                    Some(subtype) => fstype = format!("fuseblk.{}", subtype),
+                    None => {
+                        data.push(',');
+                        data.push_str(option);
+                    }
+                }
+                (0, 0)
+            }
+        };
+        flags = (flags | set) & !clear;
+    }
+    if let Some(blksize) = mountopts.blksize {
+        data.push_str(&format!(",blksize={}", blksize));
+    }
+
+    (fstype, flags, data)
+}
+
+fn unmount(mountopts: &MountOptions, mountpoint: &Path) {
+    if mountopts.blkdev.is_some() {
+        let _ = umount_detach(mountpoint);
+        return;
+    }
+    let _ = fusermount(mountopts)
         .args(&["-u", "-q", "-z", "--"])
         .arg(&mountpoint)
         .status();
 }
 
+/// Detach the mount with `fusermount -u -z`, even if its daemon has died.
+pub(crate) fn lazy_unmount(mountopts: &MountOptions, mountpoint: &Path) -> io::Result<()> {
+    if mountopts.blkdev.is_some() {
+        return umount_detach(mountpoint);
+    }
+    let status = fusermount(mountopts)
+        .arg("-u")
+        .arg("-z")
+        .arg("--")
+        .arg(mountpoint)
+        .status()?;
+    if !status.success() {
+        // The reason has already been reported by fusermount.
+        tracing::error!("failed to unmount {}: {}", mountpoint.display(), status);
+        return Err(io::Error::from_raw_os_error(libc::EIO));
+    }
+    Ok(())
+}
+
+/// Detach the mount made by [`mount_blkdev`] with umount2(2).
+fn umount_detach(mountpoint: &Path) -> io::Result<()> {
+    let target = cstring(mountpoint.as_os_str())?;
+    syscall! { umount2(target.as_ptr(), libc::MNT_DETACH) };
+    Ok(())
+}
+
+fn cstring(s: &OsStr) -> io::Result<CString> {
+    CString::new(s.as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "the path contains a nul byte"))
+}
+
+/// Find the device number of the last mount on `mountpoint`, in the form
+/// used by the names of the directories in the FUSE control filesystem.
+fn find_mount_dev(mountinfo: &str, mountpoint: &Path) -> Option<u32> {
+    let escaped = escape_mountinfo(mountpoint.as_os_str());
+    mountinfo.lines().rev().find_map(|line| {
+        let mut fields = line.split(' ');
+        let dev = fields.nth(2)?;
+        let target = fields.nth(1)?;
+        if target.as_bytes() != &escaped[..] {
+            return None;
+        }
+        let mut dev = dev.splitn(2, ':');
+        let major: u32 = dev.next()?.parse().ok()?;
+        let minor: u32 = dev.next()?.parse().ok()?;
+        // The internal representation of dev_t in the kernel.
+        Some(major << 20 | minor)
+    })
+}
+
+/// Escape the path in the same way as the kernel does in `mountinfo`.
+fn escape_mountinfo(path: &OsStr) -> Vec<u8> {
+    let mut escaped = Vec::new();
+    for &b in path.as_bytes() {
+        match b {
+            b' ' | b'\t' | b'\n' | b'\\' => {
+                escaped.extend_from_slice(format!("\\{:03o}", b).as_bytes())
+            }
+            b => escaped.push(b),
+        }
+    }
+    escaped
+}
+
 fn receive_fd(reader: &UnixStream) -> io::Result<RawFd> {
     let mut buf = [0u8; 1];
     let mut iov = libc::iovec {
@@ -360,3 +775,86 @@ unsafe fn fork() -> io::Result<ForkResult> {
         pid => Ok(ForkResult::Parent { child_pid: pid }),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_fusermount() {
+        let root = std::env::temp_dir().join(format!("polyfuse-fusermount-{}", std::process::id()));
+        let (bin, wrappers) = (root.join("bin"), root.join("wrappers"));
+        for dir in &[&bin, &wrappers] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        let install = |path: PathBuf, mode: u32| {
+            fs::write(&path, "").unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+            path
+        };
+        let fusermount = install(bin.join("fusermount"), 0o755);
+        let fusermount3 = install(wrappers.join("fusermount3"), 0o755);
+        install(wrappers.join("fusermount"), 0o644);
+
+        let path = std::env::join_paths([&wrappers, &bin].iter()).unwrap();
+        let path = Some(path.as_os_str());
+        assert_eq!(find_fusermount(path, None), fusermount3);
+        assert_eq!(find_fusermount(path, Some(true)), fusermount3);
+        // The file without the permission to execute is skipped.
+        assert_eq!(find_fusermount(path, Some(false)), fusermount);
+
+        assert_eq!(find_fusermount(None, None), Path::new(FUSERMOUNT_PROG));
+        let relative = Some(OsStr::new("bin:wrappers"));
+        assert_eq!(
+            find_fusermount(relative, Some(true)),
+            Path::new(FUSERMOUNT3_PROG)
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn mount_dev() {
+        let mountinfo = "\
+22 1 8:1 / / rw,relatime - ext4 /dev/sda1 rw
+40 22 0:45 / /mnt/a\\040b rw,nosuid,nodev - fuse.hello hello rw,user_id=0
+41 22 0:46 / /mnt/c rw,nosuid,nodev - fuse.hello hello rw,user_id=0
+42 41 0:47 / /mnt/c rw,nosuid,nodev - fuse.hello hello rw,user_id=0
+";
+        assert_eq!(find_mount_dev(mountinfo, Path::new("/mnt/a b")), Some(45));
+        assert_eq!(find_mount_dev(mountinfo, Path::new("/mnt/c")), Some(47));
+        assert_eq!(find_mount_dev(mountinfo, Path::new("/")), Some(8 << 20 | 1));
+        assert_eq!(find_mount_dev(mountinfo, Path::new("/mnt")), None);
+    }
+
+    #[test]
+    fn blkdev_options() {
+        let mountopts = MountOptions {
+            options: [
+                "ro",
+                "nodev",
+                "dev",
+                "fsname=/dev/sdb1",
+                "subtype=ntfs",
+                "allow_other",
+            ]
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
+            blkdev: Some("/dev/sdb1".into()),
+            blksize: Some(4096),
+            ..MountOptions::default()
+        };
+        let (fstype, flags, data) = blkdev_mount_data(7, libc::S_IFDIR, &mountopts);
+        assert_eq!(fstype, "fuseblk.ntfs");
+        assert_eq!(flags, libc::MS_NOSUID | libc::MS_RDONLY);
+        assert_eq!(
+            data,
+            format!(
+                "fd=7,rootmode=40000,user_id={},group_id={},allow_other,blksize=4096",
+                unsafe { libc::getuid() },
+                unsafe { libc::getgid() },
+            )
+        );
+    }
+}
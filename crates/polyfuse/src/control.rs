@@ -0,0 +1,334 @@
+//! The synthetic control directory served by the session itself.
+
+use crate::{
+    op::{Operation, ReaddirMode},
+    reply::{EntryOut, FileAttr, OpenOut, ReaddirOut, ReaddirPlusOut, StatfsOut, WriteOut},
+    session::Request,
+};
+use polyfuse_kernel::*;
+use std::{
+    convert::TryFrom,
+    ffi::OsStr,
+    fmt::{self, Write as _},
+    io::{self, Read as _},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::level_filters::LevelFilter;
+
+/// The inode number of the root directory.
+const ROOT_INO: u64 = 1;
+
+/// The name of the control directory in the root directory.
+pub(crate) const CONTROL_DIR_NAME: &str = ".polyfuse";
+
+/// The inode number of the control directory.
+///
+/// The inode numbers from this one up to `u64::MAX` are reserved.
+pub(crate) const CONTROL_DIR_INO: u64 = 0xFFFF_FFFF_FFFF_FF00;
+
+const STATS_INO: u64 = CONTROL_DIR_INO + 1;
+const INFLIGHT_INO: u64 = CONTROL_DIR_INO + 2;
+const LOG_LEVEL_INO: u64 = CONTROL_DIR_INO + 3;
+
+const FILES: &[(&str, u64)] = &[
+    ("stats", STATS_INO),
+    ("inflight", INFLIGHT_INO),
+    ("log_level", LOG_LEVEL_INO),
+];
+
+/// The opcodes tracked by the counters, above all those defined so far.
+const OPCODES: usize = 64;
+
+/// The state of the control directory, shared by the session.
+pub(crate) struct ControlDir {
+    received: Box<[AtomicU64]>,
+    replied: Box<[AtomicU64]>,
+    started: Instant,
+    mtime: Duration,
+    uid: u32,
+    gid: u32,
+    log_level: Mutex<LevelFilter>,
+}
+
+impl ControlDir {
+    pub(crate) fn new() -> Self {
+        Self {
+            received: (0..OPCODES).map(|_| AtomicU64::new(0)).collect(),
+            replied: (0..OPCODES).map(|_| AtomicU64::new(0)).collect(),
+            started: Instant::now(),
+            mtime: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            log_level: Mutex::new(LevelFilter::current()),
+        }
+    }
+
+    /// Count a request read from the kernel, if it takes a reply.
+    pub(crate) fn received(&self, opcode: u32) {
+        match fuse_opcode::try_from(opcode) {
+            Ok(fuse_opcode::FUSE_FORGET)
+            | Ok(fuse_opcode::FUSE_BATCH_FORGET)
+            | Ok(fuse_opcode::FUSE_INTERRUPT)
+            | Ok(fuse_opcode::FUSE_NOTIFY_REPLY) => (),
+            _ => {
+                if let Some(count) = self.received.get(opcode as usize) {
+                    count.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// Count a reply sent to the kernel.
+    pub(crate) fn replied(&self, opcode: u32) {
+        if let Some(count) = self.replied.get(opcode as usize) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn log_level(&self) -> LevelFilter {
+        *self.log_level.lock().unwrap()
+    }
+
+    /// Serve the request if it targets the control directory.
+    ///
+    /// `false` is returned if the request must be passed to the filesystem.
+    pub(crate) fn serve(
+        &self,
+        req: &Request,
+        stats: &dyn Fn(&mut dyn fmt::Write),
+    ) -> io::Result<bool> {
+        let ino = req.nodeid();
+        if ino < CONTROL_DIR_INO {
+            if ino != ROOT_INO || req.opcode() != fuse_opcode::FUSE_LOOKUP as u32 {
+                return Ok(false);
+            }
+            match req.operation() {
+                Ok(Operation::Lookup(op)) if op.name() == CONTROL_DIR_NAME => (),
+                _ => return Ok(false),
+            }
+        }
+
+        let op = match req.operation() {
+            Ok(op) => op,
+            Err(..) => {
+                req.reply_error(libc::EIO)?;
+                return Ok(true);
+            }
+        };
+
+        match op {
+            Operation::Lookup(op) => {
+                let ino = if op.parent() == ROOT_INO {
+                    Some(CONTROL_DIR_INO)
+                } else if op.parent() == CONTROL_DIR_INO {
+                    FILES
+                        .iter()
+                        .find(|(name, _)| op.name() == *name)
+                        .map(|&(_, ino)| ino)
+                } else {
+                    None
+                };
+                match ino {
+                    Some(ino) => {
+                        let mut out = req.entry_out();
+                        out.ino(ino);
+                        self.fill_attr(out.attr(), ino);
+                        req.reply(out)?
+                    }
+                    None => req.reply_error(libc::ENOENT)?,
+                }
+            }
+
+            Operation::Getattr(..) | Operation::Setattr(..) => match self.check_ino(ino) {
+                Ok(()) => {
+                    let mut out = req.attr_out();
+                    self.fill_attr(out.attr(), ino);
+                    req.reply(out)?
+                }
+                Err(code) => req.reply_error(code)?,
+            },
+
+            Operation::Opendir(..) if ino == CONTROL_DIR_INO => req.reply(OpenOut::default())?,
+
+            Operation::Readdir(op) if ino == CONTROL_DIR_INO => {
+                let entries = [(".", CONTROL_DIR_INO), ("..", ROOT_INO)];
+                let entries = entries.iter().chain(FILES).enumerate();
+                let entries = entries.skip(op.offset() as usize);
+                match op.mode() {
+                    ReaddirMode::Normal => {
+                        let mut out = ReaddirOut::new(op.size() as usize);
+                        for (i, &(name, ino)) in entries {
+                            if out.entry(OsStr::new(name), ino, file_type(ino), i as u64 + 1) {
+                                break;
+                            }
+                        }
+                        req.reply(out)?
+                    }
+                    ReaddirMode::Plus => {
+                        let mut out = ReaddirPlusOut::new(op.size() as usize);
+                        for (i, &(name, ino)) in entries {
+                            let mut entry = EntryOut::default();
+                            entry.ino(ino);
+                            self.fill_attr(entry.attr(), ino);
+                            if out.entry(OsStr::new(name), file_type(ino), i as u64 + 1, &entry) {
+                                break;
+                            }
+                        }
+                        req.reply(out)?
+                    }
+                }
+            }
+
+            Operation::Open(op) if ino != CONTROL_DIR_INO => {
+                let writable = op.flags() & libc::O_ACCMODE as u32 != libc::O_RDONLY as u32;
+                let code = match self.check_ino(ino) {
+                    Ok(()) if writable && ino != LOG_LEVEL_INO => Err(libc::EACCES),
+                    Ok(()) if writable => self.check_owner(req),
+                    res => res,
+                };
+                match code {
+                    Ok(()) => {
+                        // The contents are generated on each read, so the page
+                        // cache of the kernel must be bypassed.
+                        let mut out = OpenOut::default();
+                        out.direct_io(true);
+                        req.reply(out)?
+                    }
+                    Err(code) => req.reply_error(code)?,
+                }
+            }
+
+            Operation::Read(op) if ino != CONTROL_DIR_INO => {
+                let content = match ino {
+                    STATS_INO => {
+                        let mut content = self.render_stats();
+                        stats(&mut content);
+                        content
+                    }
+                    INFLIGHT_INO => self.render_inflight(),
+                    LOG_LEVEL_INO => format!("{}\n", self.log_level()),
+                    _ => return req.reply_error(libc::ENOENT).map(|_| true),
+                };
+                let content = content.as_bytes();
+                let start = std::cmp::min(op.offset(), content.len() as u64) as usize;
+                let end = std::cmp::min(start + op.size() as usize, content.len());
+                req.reply(&content[start..end])?
+            }
+
+            Operation::Write(op, mut data) if ino == LOG_LEVEL_INO => {
+                if let Err(code) = self.check_owner(req) {
+                    return req.reply_error(code).map(|_| true);
+                }
+                let mut buf = Vec::with_capacity(op.size() as usize);
+                data.read_to_end(&mut buf)?;
+                let level = std::str::from_utf8(&buf)
+                    .ok()
+                    .and_then(|s| s.trim().parse::<LevelFilter>().ok());
+                match level {
+                    Some(level) => {
+                        tracing::info!("the log level is changed to {}", level);
+                        *self.log_level.lock().unwrap() = level;
+                        let mut out = WriteOut::default();
+                        out.size(op.size());
+                        req.reply(out)?
+                    }
+                    None => req.reply_error(libc::EINVAL)?,
+                }
+            }
+
+            Operation::Flush(..)
+            | Operation::Release(..)
+            | Operation::Releasedir(..)
+            | Operation::Access(..) => req.reply(())?,
+
+            Operation::Statfs(..) => req.reply(StatfsOut::default())?,
+
+            // The lookup counts of the control inodes are not tracked.
+            Operation::Forget(..) => return Ok(true),
+
+            // ENOSYS would disable the operation for the whole filesystem.
+            _ => req.reply_error(libc::ENOTSUP)?,
+        };
+
+        Ok(true)
+    }
+
+    fn check_ino(&self, ino: u64) -> Result<(), i32> {
+        if ino == CONTROL_DIR_INO || FILES.iter().any(|&(_, i)| i == ino) {
+            Ok(())
+        } else {
+            Err(libc::ENOENT)
+        }
+    }
+
+    fn check_owner(&self, req: &Request) -> Result<(), i32> {
+        if req.uid() == self.uid || req.uid() == 0 {
+            Ok(())
+        } else {
+            Err(libc::EACCES)
+        }
+    }
+
+    fn fill_attr(&self, attr: &mut FileAttr, ino: u64) {
+        attr.ino(ino);
+        attr.mode(match ino {
+            CONTROL_DIR_INO => libc::S_IFDIR | 0o555,
+            LOG_LEVEL_INO => libc::S_IFREG | 0o644,
+            _ => libc::S_IFREG | 0o444,
+        });
+        attr.nlink(if ino == CONTROL_DIR_INO { 2 } else { 1 });
+        attr.uid(self.uid);
+        attr.gid(self.gid);
+        attr.atime(self.mtime);
+        attr.mtime(self.mtime);
+        attr.ctime(self.mtime);
+    }
+
+    fn render_stats(&self) -> String {
+        let (received, replied) = self.totals();
+        let mut content = String::new();
+        let _ = writeln!(
+            content,
+            "uptime: {:.3}s",
+            self.started.elapsed().as_secs_f64()
+        );
+        let _ = writeln!(content, "requests: {}", received);
+        let _ = writeln!(content, "replies: {}", replied);
+        let _ = writeln!(content, "in_flight: {}", received.saturating_sub(replied));
+        content
+    }
+
+    fn render_inflight(&self) -> String {
+        let mut content = String::new();
+        for opcode in 0..OPCODES {
+            let received = self.received[opcode].load(Ordering::Relaxed);
+            let replied = self.replied[opcode].load(Ordering::Relaxed);
+            let in_flight = received.saturating_sub(replied);
+            if in_flight > 0 {
+                let _ = writeln!(content, "opcode {}: {}", opcode, in_flight);
+            }
+        }
+        content
+    }
+
+    fn totals(&self) -> (u64, u64) {
+        let sum = |counts: &[AtomicU64]| -> u64 {
+            counts.iter().map(|c| c.load(Ordering::Relaxed)).sum()
+        };
+        (sum(&self.received), sum(&self.replied))
+    }
+}
+
+fn file_type(ino: u64) -> u32 {
+    if ino == CONTROL_DIR_INO || ino == ROOT_INO {
+        libc::DT_DIR as u32
+    } else {
+        libc::DT_REG as u32
+    }
+}
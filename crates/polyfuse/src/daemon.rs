@@ -0,0 +1,302 @@
+//! Running the filesystem as a background daemon.
+//!
+//! The process is detached from the terminal in the same way as
+//! `fuse_daemonize` of libfuse, but the order around the mount is reversed:
+//! the process forks *before* mounting, and the original process waits
+//! until the daemon reports that the filesystem is ready. The mount is
+//! therefore established by the process that serves it, and the shell
+//! regains control only once the mountpoint is usable, or with a failure
+//! status if mounting has failed:
+//!
+//! ```ignore
+//! let mut daemon = Daemon::new()
+//!     .pidfile("/run/hellofs.pid")
+//!     .foreground(args.foreground)
+//!     .start()?;
+//!
+//! // The errors until `ready` are still printed to the terminal.
+//! let session = Session::mount(mountpoint, config)?;
+//! daemon.ready()?;
+//!
+//! while let Some(req) = session.next_request()? {
+//!     // ...
+//! }
+//! ```
+//!
+//! Forking is only safe while the process has a single thread, so
+//! [`Daemon::start`] must be called before spawning any threads, including
+//! the ones of the asynchronous runtimes and
+//! [`KernelConfig::write_timeout`](crate::KernelConfig::write_timeout).
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, prelude::*},
+    os::unix::prelude::*,
+    path::{Path, PathBuf},
+};
+
+macro_rules! syscall {
+    ($fn:ident ( $($arg:expr),* $(,)* ) ) => {{
+        #[allow(unused_unsafe)]
+        let res = unsafe { libc::$fn($($arg),*) };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        res
+    }};
+}
+
+/// The options of daemonizing the process.
+#[derive(Debug, Default)]
+pub struct Daemon {
+    foreground: bool,
+    pidfile: Option<PathBuf>,
+    stdout: Option<PathBuf>,
+    stderr: Option<PathBuf>,
+    keep_cwd: bool,
+}
+
+impl Daemon {
+    /// Create the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Specify whether to stay in the foreground, like the `-f` option of
+    /// the libfuse filesystems.
+    ///
+    /// The process is not forked and its standard streams are kept, but the
+    /// PID file is managed in the same way.
+    pub fn foreground(&mut self, enabled: bool) -> &mut Self {
+        self.foreground = enabled;
+        self
+    }
+
+    /// Write the PID of the daemon to the file, which is removed when the
+    /// daemon exits.
+    ///
+    /// The file is kept locked while the daemon is running, so starting
+    /// another daemon with the same PID file fails with `WouldBlock`. The
+    /// file is locked before forking, so this error is reported by the
+    /// original process, and a relative path is resolved from the current
+    /// directory at that point.
+    pub fn pidfile(&mut self, path: impl AsRef<Path>) -> &mut Self {
+        self.pidfile = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Append the standard output of the daemon to the file instead of
+    /// discarding it.
+    pub fn stdout(&mut self, path: impl AsRef<Path>) -> &mut Self {
+        self.stdout = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Append the standard error of the daemon to the file instead of
+    /// discarding it.
+    pub fn stderr(&mut self, path: impl AsRef<Path>) -> &mut Self {
+        self.stderr = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Specify whether to keep the current directory, rather than changing
+    /// it to `/` so that the daemon does not keep a filesystem busy.
+    ///
+    /// The relative paths given to the daemon, including the mountpoint,
+    /// are resolved from the current directory, so they should be made
+    /// absolute before starting the daemon otherwise.
+    pub fn keep_cwd(&mut self, enabled: bool) -> &mut Self {
+        self.keep_cwd = enabled;
+        self
+    }
+
+    /// Detach the process from the terminal.
+    ///
+    /// This returns only in the daemon process. The original process exits
+    /// when the daemon calls [`Daemonized::ready`], with the status `0`, or
+    /// when the daemon exits or drops the returned value before that, with
+    /// the status `1`.
+    pub fn start(&self) -> io::Result<Daemonized> {
+        // Open the files first, so that the errors are reported by the
+        // original process. The lock of the PID file is inherited by the
+        // daemon, which writes its own PID after forking.
+        let stdout = self.stdout.as_deref().map(open_log).transpose()?;
+        let stderr = self.stderr.as_deref().map(open_log).transpose()?;
+        let pidfile = self.pidfile.as_deref().map(PidFile::lock).transpose()?;
+
+        let notify = if self.foreground {
+            None
+        } else {
+            Some(detach()?)
+        };
+
+        if !self.keep_cwd {
+            syscall! { chdir(b"/\0".as_ptr() as *const libc::c_char) };
+        }
+
+        if let Some(pidfile) = &pidfile {
+            pidfile.write_pid()?;
+        }
+
+        Ok(Daemonized {
+            notify,
+            pidfile,
+            stdout,
+            stderr,
+        })
+    }
+}
+
+/// The state of the daemon process returned by [`Daemon::start`].
+///
+/// The PID file is removed on drop, so the value should be kept until the
+/// session ends.
+#[derive(Debug)]
+pub struct Daemonized {
+    notify: Option<File>,
+    pidfile: Option<PidFile>,
+    stdout: Option<File>,
+    stderr: Option<File>,
+}
+
+impl Daemonized {
+    /// Report to the original process that the filesystem is ready, and
+    /// let it exit with the status `0`.
+    ///
+    /// The standard input of the daemon is redirected to `/dev/null`, and
+    /// the standard output and error to the files given to the options, or
+    /// to `/dev/null`. This does nothing in the foreground.
+    pub fn ready(&mut self) -> io::Result<()> {
+        let mut notify = match self.notify.take() {
+            Some(notify) => notify,
+            None => return Ok(()),
+        };
+
+        let null = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/null")?;
+        let stdout = self.stdout.take();
+        let stderr = self.stderr.take();
+        syscall! { dup2(null.as_raw_fd(), libc::STDIN_FILENO) };
+        syscall! { dup2(stdout.as_ref().unwrap_or(&null).as_raw_fd(), libc::STDOUT_FILENO) };
+        syscall! { dup2(stderr.as_ref().unwrap_or(&null).as_raw_fd(), libc::STDERR_FILENO) };
+
+        notify.write_all(&[0])
+    }
+
+    /// Return the path of the PID file.
+    pub fn pidfile(&self) -> Option<&Path> {
+        self.pidfile.as_ref().map(|pidfile| &*pidfile.path)
+    }
+}
+
+fn open_log(path: &Path) -> io::Result<File> {
+    OpenOptions::new().append(true).create(true).open(path)
+}
+
+/// Fork twice, returning in the grandchild with the pipe to notify the
+/// original process of the readiness.
+fn detach() -> io::Result<File> {
+    let mut fds = [0; 2];
+    syscall! { pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+    let (reader, writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    let pid = syscall! { fork() };
+    if pid > 0 {
+        // The original process.
+        drop(writer);
+        let mut status = 0;
+        syscall! { waitpid(pid, &mut status, 0) };
+        let mut buf = [0u8; 1];
+        let code = match (&reader).read(&mut buf) {
+            Ok(1) => 0,
+            _ => 1,
+        };
+        std::process::exit(code);
+    }
+
+    drop(reader);
+    let res = (|| -> io::Result<()> {
+        // Start a new session without the controlling terminal, and fork
+        // again so that the daemon never acquires one.
+        syscall! { setsid() };
+        if syscall! { fork() } > 0 {
+            unsafe { libc::_exit(0) };
+        }
+        Ok(())
+    })();
+    if let Err(err) = res {
+        // The original process exits with the failure status.
+        let _ = writeln!(io::stderr(), "failed to daemonize: {}", err);
+        unsafe { libc::_exit(1) };
+    }
+
+    Ok(writer)
+}
+
+/// A locked PID file, which is removed on drop.
+#[derive(Debug)]
+struct PidFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl PidFile {
+    fn lock(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o644)
+            .open(path)?;
+        syscall! { flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+
+        // Keep the path valid after changing the current directory.
+        let path = if path.is_absolute() {
+            path.to_owned()
+        } else {
+            std::env::current_dir()?.join(path)
+        };
+        Ok(Self { path, file })
+    }
+
+    fn write_pid(&self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file
+            .write_all_at(format!("{}\n", std::process::id()).as_bytes(), 0)
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Remove it only if it still refers to the locked file.
+        if let (Ok(locked), Ok(current)) = (self.file.metadata(), fs::metadata(&self.path)) {
+            if locked.ino() == current.ino() && locked.dev() == current.dev() {
+                let _ = fs::remove_file(&self.path);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pidfile_lock() {
+        let path = std::env::temp_dir().join(format!("polyfuse-pidfile-{}", std::process::id()));
+        let pidfile = PidFile::lock(&path).unwrap();
+        pidfile.write_pid().unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(content.trim(), std::process::id().to_string());
+
+        // The lock is per open file description, so it conflicts within
+        // the same process as well.
+        let err = PidFile::lock(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+}
@@ -1,25 +1,106 @@
-use std::{ffi::OsStr, mem, os::unix::prelude::*};
+//! Decoding the payload of request messages.
+//!
+//! The requests that `polyfuse` does not know about, such as the opcodes
+//! added in newer kernels, are passed through as an unknown operation. Their
+//! payload can be obtained with [`Request::payload`] and parsed with the
+//! [`Decoder`] in the same way as the built-in operations:
+//!
+//! ```
+//! use polyfuse::decoder::Decoder;
+//!
+//! let payload = b"\x2a\x00\x00\x00foo\0";
+//! let mut decoder = Decoder::new(&payload[..]);
+//! let flags = decoder.fetch::<[u8; 4]>()?;
+//! let name = decoder.fetch_str()?;
+//! assert_eq!(flags, &[0x2a, 0, 0, 0]);
+//! assert_eq!(name, "foo");
+//! assert!(decoder.remaining().is_empty());
+//! # Ok::<_, polyfuse::decoder::DecodeError>(())
+//! ```
+//!
+//! The fixed-size structures must implement [`zerocopy::FromBytes`], and
+//! must be aligned in the payload as well as in the messages from the kernel.
+//!
+//! [`Request::payload`]: crate::Request::payload
+//! [`zerocopy::FromBytes`]: https://docs.rs/zerocopy/0.3/zerocopy/trait.FromBytes.html
+
+use std::{error, ffi::OsStr, fmt, mem, os::unix::prelude::*};
 use zerocopy::{FromBytes, LayoutVerified};
 
+/// The maximum length of an entry name sent by the kernel, in bytes.
+///
+/// This is `FUSE_NAME_MAX` in the kernel, above the `NAME_MAX` of most
+/// filesystems. The filesystems with a lower limit reply `ENAMETOOLONG`
+/// by themselves.
+const FUSE_NAME_MAX: usize = 1024;
+
+/// The error that occurs when a payload cannot be decoded.
 #[derive(Debug)]
-pub(crate) enum DecodeError {
-    UnexpectedEof,
+#[non_exhaustive]
+pub enum DecodeError {
+    /// The payload is shorter than the value to be fetched.
+    UnexpectedEof { expected: usize, available: usize },
+    /// The string is not terminated with a nul character.
     MissingNulCharacter,
+    /// The value is not properly aligned in the payload.
     Unaligned,
+    /// The length declared in the message is inconsistent with the payload.
+    InvalidLength { expected: usize, available: usize },
+    /// The entry name is empty, too long or contains a slash.
+    InvalidName,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedEof {
+                expected,
+                available,
+            } => write!(
+                f,
+                "unexpected end of payload ({} bytes expected, {} bytes available)",
+                expected, available
+            ),
+            Self::MissingNulCharacter => f.write_str("missing nul terminator"),
+            Self::Unaligned => f.write_str("unaligned payload"),
+            Self::InvalidLength {
+                expected,
+                available,
+            } => write!(
+                f,
+                "inconsistent length ({} bytes declared, {} bytes available)",
+                expected, available
+            ),
+            Self::InvalidName => f.write_str("invalid entry name"),
+        }
+    }
 }
 
-pub(crate) struct Decoder<'a> {
+impl error::Error for DecodeError {}
+
+/// A cursor for reading the values from the payload of a request.
+pub struct Decoder<'a> {
     bytes: &'a [u8],
 }
 
 impl<'a> Decoder<'a> {
-    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+    /// Create a decoder that reads from the beginning of `bytes`.
+    pub fn new(bytes: &'a [u8]) -> Self {
         Self { bytes }
     }
 
-    pub(crate) fn fetch_bytes(&mut self, count: usize) -> Result<&'a [u8], DecodeError> {
+    /// Return the part of the payload that has not been fetched yet.
+    pub fn remaining(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Fetch the specified number of bytes.
+    pub fn fetch_bytes(&mut self, count: usize) -> Result<&'a [u8], DecodeError> {
         if self.bytes.len() < count {
-            return Err(DecodeError::UnexpectedEof);
+            return Err(DecodeError::UnexpectedEof {
+                expected: count,
+                available: self.bytes.len(),
+            });
         }
 
         let (bytes, remaining) = self.bytes.split_at(count);
@@ -31,7 +112,7 @@ impl<'a> Decoder<'a> {
     }
 
     /// Fetch a value of Plain-Old-Data (POD) type by reference.
-    pub(crate) fn fetch<T>(&mut self) -> Result<&'a T, DecodeError>
+    pub fn fetch<T>(&mut self) -> Result<&'a T, DecodeError>
     where
         T: FromBytes,
     {
@@ -41,19 +122,24 @@ impl<'a> Decoder<'a> {
     }
 
     /// Fetch an array of Plain-Old Data (POD) type by reference.
-    #[allow(dead_code)]
-    pub(crate) fn fetch_array<T>(&mut self, count: usize) -> Result<&'a [T], DecodeError>
+    pub fn fetch_array<T>(&mut self, count: usize) -> Result<&'a [T], DecodeError>
     where
         T: FromBytes,
     {
-        let bytes = self.fetch_bytes(mem::size_of::<T>() * count)?;
+        let len = mem::size_of::<T>()
+            .checked_mul(count)
+            .ok_or(DecodeError::UnexpectedEof {
+                expected: usize::MAX,
+                available: self.bytes.len(),
+            })?;
+        let bytes = self.fetch_bytes(len)?;
         let verified = LayoutVerified::<_, [T]>::new_slice(bytes) //
             .ok_or(DecodeError::Unaligned)?;
         Ok(verified.into_slice())
     }
 
     /// Fetch a zero-terminated OS string by reference.
-    pub(crate) fn fetch_str(&mut self) -> Result<&'a OsStr, DecodeError> {
+    pub fn fetch_str(&mut self) -> Result<&'a OsStr, DecodeError> {
         let len = self
             .bytes
             .iter()
@@ -65,6 +151,27 @@ impl<'a> Decoder<'a> {
         let bytes = &bytes[..bytes.len() - 1];
         Ok(OsStr::from_bytes(bytes))
     }
+
+    /// Fetch a zero-terminated entry name by reference.
+    ///
+    /// In addition to [`fetch_str`](Self::fetch_str), the name is checked
+    /// to be a single component of a path, i.e. non-empty, at most
+    /// `FUSE_NAME_MAX` (1024) bytes, and free of `/`. The decoder is not advanced
+    /// if the name is rejected.
+    pub fn fetch_name(&mut self) -> Result<&'a OsStr, DecodeError> {
+        let bytes = self.bytes;
+        let name = self.fetch_str()?;
+        if !is_valid_name(name.as_bytes()) {
+            self.bytes = bytes;
+            return Err(DecodeError::InvalidName);
+        }
+        Ok(name)
+    }
+}
+
+#[inline]
+fn is_valid_name(name: &[u8]) -> bool {
+    !name.is_empty() && name.len() <= FUSE_NAME_MAX && !name.contains(&b'/')
 }
 
 #[cfg(test)]
@@ -83,6 +190,16 @@ mod tests {
         let mut decoder = Decoder::new(&input[..]);
         assert_eq!(decoder.fetch_str().ok(), Some(OsStr::from_bytes(b"foo")));
         assert_eq!(decoder.fetch_str().ok(), Some(OsStr::from_bytes(b"bar")));
+        assert!(decoder.remaining().is_empty());
+    }
+
+    #[test]
+    fn remaining() {
+        let mut decoder = Decoder::new(b"foo\0bar");
+        assert_eq!(decoder.fetch_str().ok(), Some(OsStr::from_bytes(b"foo")));
+        assert_eq!(decoder.remaining(), b"bar");
+        assert!(decoder.fetch_str().is_err());
+        assert_eq!(decoder.remaining(), b"bar", "failed fetch must not advance");
     }
 
     #[test]
@@ -97,7 +214,13 @@ mod tests {
         assert!(decoder.fetch_bytes(0).is_ok());
         assert!(decoder.fetch_bytes(1).is_err());
 
-        assert!(Decoder::new(INPUT).fetch::<[u8; 10]>().is_err());
+        assert!(matches!(
+            Decoder::new(INPUT).fetch::<[u8; 10]>().err(),
+            Some(DecodeError::UnexpectedEof {
+                expected: 10,
+                available: 9,
+            })
+        ));
     }
 
     #[test]
@@ -142,6 +265,24 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn array_length_overflow() {
+        let input = [0u64; 4];
+        let input = unsafe {
+            std::slice::from_raw_parts(
+                input.as_ptr() as *const u8, //
+                input.len() * mem::size_of::<u64>(),
+            )
+        };
+        assert!(matches!(
+            Decoder::new(input).fetch_array::<u64>(usize::MAX / 4).err(),
+            Some(DecodeError::UnexpectedEof {
+                expected: usize::MAX,
+                ..
+            })
+        ));
+    }
+
     #[test]
     fn missing_nul_terminator() {
         let input = {
@@ -156,4 +297,29 @@ mod tests {
             Some(DecodeError::MissingNulCharacter)
         ));
     }
+
+    #[test]
+    fn fetch_name() {
+        let mut decoder = Decoder::new(b"foo\0..\0");
+        assert_eq!(decoder.fetch_name().ok(), Some(OsStr::from_bytes(b"foo")));
+        assert_eq!(decoder.fetch_name().ok(), Some(OsStr::from_bytes(b"..")));
+
+        let long = [b'a'; FUSE_NAME_MAX + 1];
+        for input in &[&b"\0"[..], b"foo/bar\0", &long[..]] {
+            let mut input = input.to_vec();
+            if input.last() != Some(&b'\0') {
+                input.push(b'\0');
+            }
+            let mut decoder = Decoder::new(&input[..]);
+            assert!(matches!(
+                decoder.fetch_name().err(),
+                Some(DecodeError::InvalidName)
+            ));
+            assert_eq!(decoder.remaining(), &input[..]);
+        }
+
+        let mut input = long[..FUSE_NAME_MAX].to_vec();
+        input.push(b'\0');
+        assert!(Decoder::new(&input[..]).fetch_name().is_ok());
+    }
 }
@@ -0,0 +1,461 @@
+//! Error numbers replied to the kernel.
+//!
+//! The constants here are the error numbers commonly replied by the
+//! filesystems, typed as [`Errno`], so the handlers can use them without
+//! depending on `libc`:
+//!
+//! ```
+//! use polyfuse::errno::{self, Errno};
+//!
+//! fn lookup(name: &str) -> Result<u64, Errno> {
+//!     match name {
+//!         "hello.txt" => Ok(2),
+//!         _ => Err(errno::ENOENT),
+//!     }
+//! }
+//!
+//! assert_eq!(lookup("foo").unwrap_err().code(), libc::ENOENT);
+//! ```
+//!
+//! [`Request::reply_error`](crate::Request::reply_error) accepts them as
+//! well as the raw error numbers.
+
+use crate::error::Error;
+use std::{borrow::Cow, error, fmt, io};
+
+/// An error number replied to the kernel, along with the context of the failure.
+///
+/// `Errno` can be created from an `io::Error`, so the handlers can use `?`
+/// on the standard I/O calls and still reply with the correct error number:
+///
+/// ```
+/// use polyfuse::Errno;
+/// use std::fs;
+///
+/// fn backing_size(path: &str) -> Result<u64, Errno> {
+///     let metadata = fs::metadata(path).map_err(|err| Errno::from(err).context(format!("stat {}", path)))?;
+///     Ok(metadata.len())
+/// }
+///
+/// let err = backing_size("/nonexistent").unwrap_err();
+/// assert_eq!(err.code(), libc::ENOENT);
+/// ```
+///
+/// The context and the source error are only used in the diagnostics, and
+/// the kernel receives only the error number.
+pub struct Errno {
+    code: i32,
+    context: Option<Cow<'static, str>>,
+    source: Option<io::Error>,
+}
+
+impl fmt::Debug for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Errno")
+            .field("code", &self.code)
+            .field("context", &self.context)
+            .field("source", &self.source)
+            .finish()
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ref context) = self.context {
+            write!(f, "{}: ", context)?;
+        }
+        fmt::Display::fmt(&io::Error::from_raw_os_error(self.code), f)
+    }
+}
+
+impl error::Error for Errno {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        self.source
+            .as_ref()
+            .map(|err| err as &(dyn error::Error + 'static))
+    }
+}
+
+macro_rules! define_errno_consts {
+    ($( $(#[$m:meta])* $name:ident, )*) => {$(
+        $(#[$m])*
+        pub const $name: Errno = Errno {
+            code: libc::$name,
+            context: None,
+            source: None,
+        };
+    )*};
+}
+
+define_errno_consts! {
+    /// The operation is not permitted.
+    EPERM,
+    /// The entry does not exist.
+    ENOENT,
+    /// The I/O error in the backend.
+    EIO,
+    /// The device or the address does not exist.
+    ENXIO,
+    /// The file handle is not valid.
+    EBADF,
+    /// The resource is temporarily unavailable.
+    EAGAIN,
+    /// Out of memory.
+    ENOMEM,
+    /// The permission is denied.
+    EACCES,
+    /// The resource is busy.
+    EBUSY,
+    /// The entry already exists.
+    EEXIST,
+    /// The link crosses the filesystems.
+    EXDEV,
+    /// The inode is not a directory.
+    ENOTDIR,
+    /// The inode is a directory.
+    EISDIR,
+    /// The argument is invalid.
+    EINVAL,
+    /// The file is too large.
+    EFBIG,
+    /// No space left on the filesystem.
+    ENOSPC,
+    /// The filesystem is read-only.
+    EROFS,
+    /// Too many links to the inode.
+    EMLINK,
+    /// The buffer is too small for the value.
+    ERANGE,
+    /// The name is too long.
+    ENAMETOOLONG,
+    /// The operation is not implemented.
+    ENOSYS,
+    /// The directory is not empty.
+    ENOTEMPTY,
+    /// Too many levels of symbolic links.
+    ELOOP,
+    /// The extended attribute does not exist.
+    ENODATA,
+    /// The value is too large for the type.
+    EOVERFLOW,
+    /// The operation is not supported.
+    ENOTSUP,
+    /// The operation has timed out.
+    ETIMEDOUT,
+    /// The file handle refers to an inode that no longer exists.
+    ESTALE,
+    /// The operation has been interrupted.
+    EINTR,
+}
+
+macro_rules! define_errno_ctors {
+    ($( $(#[$m:meta])* $name:ident => $code:ident, )*) => {$(
+        $(#[$m])*
+        #[inline]
+        pub fn $name() -> Self {
+            Self::new(libc::$code)
+        }
+    )*};
+}
+
+impl Errno {
+    /// Create an error with the specified positive error number, such as
+    /// `libc::ENOENT`.
+    #[inline]
+    pub fn new(code: i32) -> Self {
+        debug_assert!(code > 0, "the error number must be positive");
+        Self {
+            code,
+            context: None,
+            source: None,
+        }
+    }
+
+    define_errno_ctors! {
+        /// `EPERM`: the operation is not permitted.
+        not_permitted => EPERM,
+        /// `ENOENT`: the entry does not exist.
+        not_found => ENOENT,
+        /// `EIO`: the I/O error in the backend.
+        io => EIO,
+        /// `EBADF`: the file handle is not valid.
+        bad_handle => EBADF,
+        /// `EACCES`: the permission is denied.
+        permission_denied => EACCES,
+        /// `EEXIST`: the entry already exists.
+        exists => EEXIST,
+        /// `ENOTDIR`: the inode is not a directory.
+        not_dir => ENOTDIR,
+        /// `EISDIR`: the inode is a directory.
+        is_dir => EISDIR,
+        /// `EINVAL`: the argument is invalid.
+        invalid => EINVAL,
+        /// `ENOSPC`: no space left on the filesystem.
+        no_space => ENOSPC,
+        /// `EROFS`: the filesystem is read-only.
+        read_only => EROFS,
+        /// `ERANGE`: the buffer is too small for the value.
+        range => ERANGE,
+        /// `ENOSYS`: the operation is not implemented.
+        unsupported => ENOSYS,
+        /// `ENOTEMPTY`: the directory is not empty.
+        not_empty => ENOTEMPTY,
+        /// `ENODATA`: the extended attribute does not exist.
+        no_data => ENODATA,
+        /// `ESTALE`: the file handle refers to an inode that no longer exists.
+        stale => ESTALE,
+        /// `EINTR`: the operation has been interrupted.
+        interrupted => EINTR,
+    }
+
+    /// Attach the context describing the failed operation.
+    ///
+    /// The context attached earlier is replaced.
+    pub fn context(mut self, context: impl Into<Cow<'static, str>>) -> Self {
+        self.context = Some(context.into());
+        self
+    }
+
+    /// Return the error number.
+    #[inline]
+    pub fn code(&self) -> i32 {
+        self.code
+    }
+
+    /// Return the attached context, if any.
+    pub fn get_context(&self) -> Option<&str> {
+        self.context.as_deref()
+    }
+}
+
+impl From<i32> for Errno {
+    #[inline]
+    fn from(code: i32) -> Self {
+        Self::new(code)
+    }
+}
+
+/// The error number is taken from `raw_os_error`. The errors not caused by
+/// the system calls are mapped by their kinds, and the unknown ones are
+/// reported as `EIO`.
+impl From<io::Error> for Errno {
+    fn from(err: io::Error) -> Self {
+        Self {
+            code: io_errno(&err),
+            context: None,
+            source: Some(err),
+        }
+    }
+}
+
+/// Mapped in the same way as the `io::Error`s without the raw error number,
+/// e.g. `NotFound` to `ENOENT`, and the unknown kinds to `EIO`.
+impl From<io::ErrorKind> for Errno {
+    #[inline]
+    fn from(kind: io::ErrorKind) -> Self {
+        Self::new(kind_to_errno(kind))
+    }
+}
+
+impl From<Errno> for io::Error {
+    fn from(err: Errno) -> Self {
+        io::Error::from_raw_os_error(err.code)
+    }
+}
+
+/// A specialized `Result` type for the handlers, failing with [`Errno`].
+pub type Result<T, E = Errno> = std::result::Result<T, E>;
+
+/// The errors that can be replied to the kernel as an error number.
+///
+/// This is implemented for the error types returned from the handlers
+/// processed by [`Request::process`](crate::Request::process).
+pub trait ToErrno: fmt::Debug {
+    /// Return the positive error number replied to the kernel.
+    fn to_errno(&self) -> i32;
+}
+
+impl ToErrno for Errno {
+    #[inline]
+    fn to_errno(&self) -> i32 {
+        self.code
+    }
+}
+
+/// Mapped in the same way as `From<io::Error> for Errno`.
+impl ToErrno for io::Error {
+    #[inline]
+    fn to_errno(&self) -> i32 {
+        io_errno(self)
+    }
+}
+
+/// Mapped in the same way as `From<io::ErrorKind> for Errno`.
+impl ToErrno for io::ErrorKind {
+    #[inline]
+    fn to_errno(&self) -> i32 {
+        kind_to_errno(*self)
+    }
+}
+
+/// The non-positive values, which the kernel would take as a success or
+/// reject, are replaced with `EIO`.
+impl ToErrno for i32 {
+    #[inline]
+    fn to_errno(&self) -> i32 {
+        match *self {
+            code if code > 0 => code,
+            _ => libc::EIO,
+        }
+    }
+}
+
+impl ToErrno for Error {
+    fn to_errno(&self) -> i32 {
+        match self {
+            Error::Io(err) => io_errno(err),
+            _ => libc::EIO,
+        }
+    }
+}
+
+/// Find the error number in the chain of the sources, falling back to `EIO`.
+#[cfg(any(feature = "anyhow", feature = "eyre"))]
+fn chain_errno<'a, I>(chain: I, err: &dyn fmt::Display) -> i32
+where
+    I: IntoIterator<Item = &'a (dyn error::Error + 'static)>,
+{
+    for cause in chain {
+        if let Some(err) = cause.downcast_ref::<Errno>() {
+            return err.code;
+        }
+        if let Some(err) = cause.downcast_ref::<io::Error>() {
+            return io_errno(err);
+        }
+        if let Some(err) = cause.downcast_ref::<Error>() {
+            return err.to_errno();
+        }
+    }
+    tracing::error!("replying EIO for an unexpected error: {}", err);
+    libc::EIO
+}
+
+/// The error number is taken from the first [`Errno`], `io::Error` or
+/// [`Error`] in the chain of the error. Otherwise, the error is logged
+/// and reported as `EIO`.
+///
+/// This is available with the `anyhow` feature.
+#[cfg(feature = "anyhow")]
+impl ToErrno for anyhow::Error {
+    fn to_errno(&self) -> i32 {
+        chain_errno(self.chain(), &format_args!("{:#}", self))
+    }
+}
+
+/// The error number is taken from the first [`Errno`], `io::Error` or
+/// [`Error`] in the chain of the error. Otherwise, the error is logged
+/// and reported as `EIO`.
+///
+/// This is available with the `eyre` feature.
+#[cfg(feature = "eyre")]
+impl ToErrno for eyre::Report {
+    fn to_errno(&self) -> i32 {
+        chain_errno(self.chain(), &format_args!("{:#}", self))
+    }
+}
+
+fn io_errno(err: &io::Error) -> i32 {
+    match err.raw_os_error() {
+        Some(code) if code > 0 => code,
+        _ => kind_to_errno(err.kind()),
+    }
+}
+
+fn kind_to_errno(kind: io::ErrorKind) -> i32 {
+    use io::ErrorKind::*;
+    match kind {
+        NotFound => libc::ENOENT,
+        PermissionDenied => libc::EACCES,
+        ConnectionRefused => libc::ECONNREFUSED,
+        ConnectionReset => libc::ECONNRESET,
+        ConnectionAborted => libc::ECONNABORTED,
+        NotConnected => libc::ENOTCONN,
+        AddrInUse => libc::EADDRINUSE,
+        AddrNotAvailable => libc::EADDRNOTAVAIL,
+        BrokenPipe => libc::EPIPE,
+        AlreadyExists => libc::EEXIST,
+        WouldBlock => libc::EAGAIN,
+        InvalidInput | InvalidData => libc::EINVAL,
+        TimedOut => libc::ETIMEDOUT,
+        WriteZero => libc::ENOSPC,
+        Interrupted => libc::EINTR,
+        _ => libc::EIO,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn from_io_error() {
+        let err = Errno::from(io::Error::from_raw_os_error(libc::ENOTEMPTY));
+        assert_eq!(err.code(), libc::ENOTEMPTY);
+        let source = err.source().unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(source.raw_os_error(), Some(libc::ENOTEMPTY));
+
+        let err = io::Error::new(io::ErrorKind::NotFound, "missing");
+        assert_eq!(Errno::from(err).code(), libc::ENOENT);
+        let err = io::Error::new(io::ErrorKind::UnexpectedEof, "truncated");
+        assert_eq!(err.to_errno(), libc::EIO);
+        assert_eq!(Errno::from(err).code(), libc::EIO);
+        assert_eq!(Error::Closed.to_errno(), libc::EIO);
+        assert_eq!(libc::ENOENT.to_errno(), libc::ENOENT);
+        assert_eq!(0.to_errno(), libc::EIO);
+        assert_eq!((-libc::ENOENT).to_errno(), libc::EIO);
+    }
+
+    #[test]
+    fn consts_and_kinds() {
+        assert_eq!(ENOENT.code(), libc::ENOENT);
+        assert_eq!(ESTALE.to_errno(), libc::ESTALE);
+        assert!(ENOSYS.get_context().is_none());
+        assert_eq!(ENOTEMPTY.context("rmdir").get_context(), Some("rmdir"));
+
+        assert_eq!(Errno::from(io::ErrorKind::NotFound).code(), libc::ENOENT);
+        assert_eq!(
+            Errno::from(io::ErrorKind::AlreadyExists).code(),
+            libc::EEXIST
+        );
+        assert_eq!(io::ErrorKind::Interrupted.to_errno(), libc::EINTR);
+        assert_eq!(io::ErrorKind::UnexpectedEof.to_errno(), libc::EIO);
+    }
+
+    #[test]
+    fn context() {
+        let err = Errno::not_found().context("lookup foo");
+        assert_eq!(err.get_context(), Some("lookup foo"));
+        assert!(err.to_string().starts_with("lookup foo: "));
+        assert!(err.source().is_none());
+
+        let err = io::Error::from(err);
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    }
+
+    #[cfg(feature = "anyhow")]
+    #[test]
+    fn anyhow_chain() {
+        use anyhow::Context as _;
+
+        let err = Err::<(), _>(io::Error::from_raw_os_error(libc::ENOTDIR))
+            .context("opening the backing directory")
+            .unwrap_err();
+        assert_eq!(err.to_errno(), libc::ENOTDIR);
+
+        let err = anyhow::Error::from(Errno::stale()).context("looking up the handle");
+        assert_eq!(err.to_errno(), libc::ESTALE);
+
+        assert_eq!(anyhow::anyhow!("corrupted index").to_errno(), libc::EIO);
+    }
+}
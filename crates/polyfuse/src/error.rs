@@ -0,0 +1,132 @@
+use crate::op::DecodeError;
+use std::{error, fmt, io};
+
+/// The error type returned from the session.
+///
+/// The variants tell the callers how to react to the failure: an I/O
+/// error on the connection may be transient, whereas a protocol violation
+/// or a closed session means that the connection is no longer usable and
+/// the filesystem must be remounted or shut down.
+///
+/// The replies and the notifications, as well as [`Session::abort`] and
+/// [`Session::unmount`], return `io::Error` instead. They are called from
+/// the handlers, which are commonly written against `io::Result` and
+/// propagate the errors with `?`, and their failures are always I/O errors
+/// on the connection: a write failing with `ENODEV` means that the session
+/// has been closed. [`Request::process`] returns these failures as
+/// [`Error::Io`].
+///
+/// [`Session::abort`]: crate::Session::abort
+/// [`Session::unmount`]: crate::Session::unmount
+/// [`Request::process`]: crate::Request::process
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// An I/O error occurred while communicating with the kernel.
+    Io(io::Error),
+
+    /// The kernel sent a message that violates the FUSE protocol.
+    Protocol(String),
+
+    /// Failed to decode the argument of a request.
+    Decode(DecodeError),
+
+    /// The session has been closed, e.g. the filesystem was unmounted
+    /// before the initialization completed.
+    Closed,
+}
+
+impl Error {
+    #[inline]
+    pub(crate) fn protocol(msg: impl Into<String>) -> Self {
+        Self::Protocol(msg.into())
+    }
+
+    /// Return the raw OS error number if this error was caused by a system call.
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match self {
+            Self::Io(err) => err.raw_os_error(),
+            _ => None,
+        }
+    }
+
+    /// Return whether the operation failed because the connection is in
+    /// non-blocking mode and would block.
+    pub fn is_would_block(&self) -> bool {
+        match self {
+            Self::Io(err) => err.kind() == io::ErrorKind::WouldBlock,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(..) => f.write_str("I/O error on the FUSE connection"),
+            Self::Protocol(msg) => write!(f, "FUSE protocol violation: {}", msg),
+            Self::Decode(..) => f.write_str("failed to decode the request"),
+            Self::Closed => f.write_str("the FUSE session has been closed"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            Self::Decode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(err: DecodeError) -> Self {
+        Self::Decode(err)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        let kind = match err {
+            Error::Io(err) => return err,
+            Error::Protocol(..) | Error::Decode(..) => io::ErrorKind::InvalidData,
+            Error::Closed => io::ErrorKind::NotConnected,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn source_chain() {
+        let err = Error::from(io::Error::from_raw_os_error(libc::ENODEV));
+        assert_eq!(err.raw_os_error(), Some(libc::ENODEV));
+        let source = err.source().unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(source.raw_os_error(), Some(libc::ENODEV));
+
+        assert!(Error::Closed.source().is_none());
+    }
+
+    #[test]
+    fn into_io_error() {
+        let err = io::Error::from(Error::from(io::Error::from_raw_os_error(libc::EIO)));
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+
+        let err = io::Error::from(Error::protocol("bogus"));
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let inner = err.get_ref().unwrap().downcast_ref::<Error>().unwrap();
+        assert!(matches!(inner, Error::Protocol(msg) if msg == "bogus"));
+    }
+}
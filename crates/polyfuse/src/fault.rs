@@ -0,0 +1,118 @@
+//! A scripted connection for exercising the error handling paths.
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{self, IoSlice, IoSliceMut},
+};
+
+/// A fault injected into the next read or write.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Fault {
+    /// Fail with the specified errno.
+    Error(i32),
+    /// Deliver only the leading bytes of the next message.
+    Short(usize),
+}
+
+/// An in-memory connection that behaves like `/dev/fuse`, with the
+/// injected faults.
+///
+/// Each read returns exactly one queued message, and each write is
+/// recorded as one message. When no messages are left, reads fail with
+/// `ENODEV` as if the filesystem had been unmounted.
+#[derive(Debug, Default)]
+pub(crate) struct FaultyConn {
+    inner: RefCell<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    input: VecDeque<Vec<u8>>,
+    read_faults: VecDeque<Fault>,
+    write_faults: VecDeque<Fault>,
+    output: Vec<Vec<u8>>,
+}
+
+impl FaultyConn {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a message to be read.
+    pub(crate) fn push_message(&self, msg: &[u8]) {
+        self.inner.borrow_mut().input.push_back(msg.to_vec());
+    }
+
+    /// Inject a fault into the next read that has no fault yet.
+    pub(crate) fn read_fault(&self, fault: Fault) {
+        self.inner.borrow_mut().read_faults.push_back(fault);
+    }
+
+    /// Inject a fault into the next write that has no fault yet.
+    pub(crate) fn write_fault(&self, fault: Fault) {
+        self.inner.borrow_mut().write_faults.push_back(fault);
+    }
+
+    /// Take the messages written so far.
+    pub(crate) fn take_output(&self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.inner.borrow_mut().output)
+    }
+}
+
+impl io::Read for &FaultyConn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_vectored(&mut [IoSliceMut::new(buf)])
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        let mut inner = self.inner.borrow_mut();
+
+        let limit = match inner.read_faults.pop_front() {
+            Some(Fault::Error(errno)) => return Err(io::Error::from_raw_os_error(errno)),
+            Some(Fault::Short(len)) => len,
+            None => usize::MAX,
+        };
+
+        let msg = match inner.input.pop_front() {
+            Some(msg) => msg,
+            None => return Err(io::Error::from_raw_os_error(libc::ENODEV)),
+        };
+        let mut remaining = &msg[..std::cmp::min(msg.len(), limit)];
+
+        let mut total = 0;
+        for buf in bufs.iter_mut() {
+            let len = std::cmp::min(buf.len(), remaining.len());
+            buf[..len].copy_from_slice(&remaining[..len]);
+            remaining = &remaining[len..];
+            total += len;
+        }
+        Ok(total)
+    }
+}
+
+impl io::Write for &FaultyConn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_vectored(&[IoSlice::new(buf)])
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let mut inner = self.inner.borrow_mut();
+
+        let limit = match inner.write_faults.pop_front() {
+            Some(Fault::Error(errno)) => return Err(io::Error::from_raw_os_error(errno)),
+            Some(Fault::Short(len)) => len,
+            None => usize::MAX,
+        };
+
+        let mut msg: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+        msg.truncate(limit);
+        let len = msg.len();
+        inner.output.push(msg);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,29 @@
+//! Entry points for the fuzz targets in `fuzz/`.
+
+use crate::session::decode_request as decode;
+use polyfuse_kernel::fuse_in_header;
+use std::mem;
+use zerocopy::AsBytes as _;
+
+/// Decode a raw request message in the same way as `Request::operation`.
+///
+/// The decoded operation is formatted with `Debug` so that all accessors
+/// referenced from it are exercised.
+pub fn decode_request(msg: &[u8]) {
+    if msg.len() < mem::size_of::<fuse_in_header>() {
+        return;
+    }
+    let (header_bytes, arg) = msg.split_at(mem::size_of::<fuse_in_header>());
+
+    let mut header = fuse_in_header::default();
+    header.as_bytes_mut().copy_from_slice(header_bytes);
+
+    // Keep the argument aligned for the FUSE argument types.
+    let mut buf = vec![0u64; arg.len() / 8 + 1];
+    let aligned = &mut buf.as_mut_slice().as_bytes_mut()[..arg.len()];
+    aligned.copy_from_slice(arg);
+
+    if let Ok(op) = decode(&header, aligned) {
+        let _ = format!("{:?}", op);
+    }
+}
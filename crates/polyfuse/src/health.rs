@@ -0,0 +1,144 @@
+//! The detection of the writes to the FUSE device blocked in the kernel.
+
+use std::{
+    cmp,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The health of a session, returned by
+/// [`Session::health`](crate::Session::health).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Health {
+    /// No write to the FUSE device has been blocked longer than the timeout.
+    Healthy,
+
+    /// A write to the FUSE device has been blocked for `blocked`, beyond the
+    /// timeout set by
+    /// [`KernelConfig::write_timeout`](crate::KernelConfig::write_timeout).
+    ///
+    /// The kernel side of the connection is likely to be stuck, and the
+    /// session will not make progress until the connection is aborted.
+    Wedged {
+        /// How long the oldest pending write has been blocked.
+        blocked: Duration,
+    },
+}
+
+impl Health {
+    /// Return whether the session is healthy.
+    #[inline]
+    pub fn is_healthy(&self) -> bool {
+        matches!(self, Health::Healthy)
+    }
+}
+
+/// The writes to the FUSE device in progress.
+pub(crate) struct WriteWatch {
+    timeout: Duration,
+    pending: Mutex<HashMap<u64, Instant>>,
+    next_id: AtomicU64,
+}
+
+impl WriteWatch {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            pending: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Record the start of a write, which ends when the guard is dropped.
+    pub(crate) fn start(&self) -> WriteGuard<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.pending.lock().unwrap().insert(id, Instant::now());
+        WriteGuard { watch: self, id }
+    }
+
+    pub(crate) fn health(&self) -> Health {
+        let now = Instant::now();
+        let oldest = self
+            .pending
+            .lock()
+            .unwrap()
+            .values()
+            .map(|&started| now.saturating_duration_since(started))
+            .max();
+        match oldest {
+            Some(blocked) if blocked >= self.timeout => Health::Wedged { blocked },
+            _ => Health::Healthy,
+        }
+    }
+
+    /// Spawn the thread logging the blocked writes, which stops when the
+    /// session is dropped.
+    pub(crate) fn spawn_watchdog(this: &Arc<Self>) -> std::io::Result<()> {
+        let watch = this.clone();
+        let interval = cmp::max(watch.timeout / 2, Duration::from_millis(10));
+        thread::Builder::new()
+            .name("polyfuse-watchdog".into())
+            .spawn(move || {
+                let mut wedged = false;
+                while Arc::strong_count(&watch) > 1 {
+                    thread::sleep(interval);
+                    match watch.health() {
+                        Health::Wedged { blocked } if !wedged => {
+                            tracing::error!(
+                                "a write to the FUSE device has been blocked for {:?}",
+                                blocked
+                            );
+                            wedged = true;
+                        }
+                        Health::Healthy if wedged => {
+                            tracing::warn!("the blocked writes to the FUSE device have completed");
+                            wedged = false;
+                        }
+                        _ => (),
+                    }
+                }
+            })?;
+        Ok(())
+    }
+}
+
+pub(crate) struct WriteGuard<'a> {
+    watch: &'a WriteWatch,
+    id: u64,
+}
+
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        self.watch.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_blocked_write() {
+        let watch = WriteWatch::new(Duration::from_millis(20));
+        assert_eq!(watch.health(), Health::Healthy);
+
+        let first = watch.start();
+        thread::sleep(Duration::from_millis(30));
+        let second = watch.start();
+        match watch.health() {
+            Health::Wedged { blocked } => assert!(blocked >= Duration::from_millis(30)),
+            health => panic!("unexpected health: {:?}", health),
+        }
+
+        drop(first);
+        assert!(watch.health().is_healthy());
+        drop(second);
+        assert!(watch.pending.lock().unwrap().is_empty());
+    }
+}
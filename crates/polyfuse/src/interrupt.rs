@@ -0,0 +1,199 @@
+//! The tracking of the `INTERRUPT` requests for [`Request::interruptible`].
+//!
+//! [`Request::interruptible`]: crate::Request::interruptible
+
+use crate::errno::{Errno, EINTR};
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll, Waker},
+};
+
+/// The number of interrupts kept for the requests not yet waiting for them.
+///
+/// The kernel sends an `INTERRUPT` only after the target request, but the
+/// handler may start waiting after it has been received.
+const MAX_EARLY_INTERRUPTS: usize = 64;
+
+#[derive(Debug, Default)]
+pub(crate) struct InterruptTable {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    waiting: HashMap<u64, Slot>,
+    early: VecDeque<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Slot {
+    interrupted: bool,
+    waker: Option<Waker>,
+}
+
+impl InterruptTable {
+    /// Record the interrupt of the request, waking its waiter if any.
+    pub(crate) fn interrupt(&self, unique: u64) {
+        let mut state = self.state.lock().unwrap();
+        match state.waiting.get_mut(&unique) {
+            Some(slot) => {
+                slot.interrupted = true;
+                if let Some(waker) = slot.waker.take() {
+                    waker.wake();
+                }
+            }
+            None => {
+                if state.early.len() == MAX_EARLY_INTERRUPTS {
+                    state.early.pop_front();
+                }
+                state.early.push_back(unique);
+            }
+        }
+    }
+
+    /// Return whether the request has been interrupted, or register the
+    /// waker to be notified otherwise.
+    fn poll_interrupted(&self, unique: u64, waker: &Waker) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let early = state.early.iter().position(|&u| u == unique);
+        if let Some(pos) = early {
+            state.early.remove(pos);
+        }
+        let slot = state.waiting.entry(unique).or_default();
+        if early.is_some() || slot.interrupted {
+            slot.interrupted = true;
+            return true;
+        }
+        if !matches!(slot.waker, Some(ref w) if w.will_wake(waker)) {
+            slot.waker = Some(waker.clone());
+        }
+        false
+    }
+
+    /// Return whether the request has been interrupted, without waiting.
+    pub(crate) fn is_interrupted(&self, unique: u64) -> bool {
+        let state = self.state.lock().unwrap();
+        match state.waiting.get(&unique) {
+            Some(slot) => slot.interrupted,
+            None => state.early.contains(&unique),
+        }
+    }
+
+    fn remove(&self, unique: u64) {
+        self.state.lock().unwrap().waiting.remove(&unique);
+    }
+}
+
+/// A future returned by [`Request::interruptible`].
+///
+/// [`Request::interruptible`]: crate::Request::interruptible
+#[must_use = "futures do nothing unless polled"]
+pub struct Interruptible<'a, F> {
+    table: &'a InterruptTable,
+    unique: u64,
+    fut: F,
+}
+
+impl<'a, F> Interruptible<'a, F> {
+    pub(crate) fn new(table: &'a InterruptTable, unique: u64, fut: F) -> Self {
+        Self { table, unique, fut }
+    }
+}
+
+impl<F> std::fmt::Debug for Interruptible<'_, F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Interruptible")
+            .field("unique", &self.unique)
+            .finish()
+    }
+}
+
+impl<F, T, E> Future for Interruptible<'_, F>
+where
+    F: Future<Output = Result<T, E>>,
+    E: From<Errno>,
+{
+    type Output = Result<T, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: `fut` is never moved out, and the other fields are not
+        // structurally pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let fut = unsafe { Pin::new_unchecked(&mut this.fut) };
+        if let Poll::Ready(res) = fut.poll(cx) {
+            return Poll::Ready(res);
+        }
+        if this.table.poll_interrupted(this.unique, cx.waker()) {
+            return Poll::Ready(Err(EINTR.into()));
+        }
+        Poll::Pending
+    }
+}
+
+impl<F> Drop for Interruptible<'_, F> {
+    fn drop(&mut self) {
+        self.table.remove(self.unique);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io,
+        task::{RawWaker, RawWakerVTable},
+    };
+
+    fn noop_waker() -> Waker {
+        unsafe fn clone(_: *const ()) -> RawWaker {
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        unsafe fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &VTABLE)) }
+    }
+
+    struct Pending;
+
+    impl Future for Pending {
+        type Output = io::Result<()>;
+
+        fn poll(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Self::Output> {
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn interrupt_waiting() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let table = InterruptTable::default();
+
+        let mut fut = Box::pin(Interruptible::new(&table, 2, Pending));
+        assert!(fut.as_mut().poll(&mut cx).is_pending());
+        table.interrupt(3);
+        assert!(fut.as_mut().poll(&mut cx).is_pending());
+        table.interrupt(2);
+        assert!(table.is_interrupted(2) && !table.is_interrupted(5));
+        match fut.as_mut().poll(&mut cx) {
+            Poll::Ready(Err(err)) => assert_eq!(err.raw_os_error(), Some(libc::EINTR)),
+            _ => panic!("not interrupted"),
+        }
+        drop(fut);
+        assert!(!table.state.lock().unwrap().waiting.contains_key(&2));
+
+        // The interrupt received before waiting.
+        let mut fut = Box::pin(Interruptible::new(&table, 3, Pending));
+        assert!(fut.as_mut().poll(&mut cx).is_ready());
+
+        // The completed future wins.
+        table.interrupt(4);
+        let mut fut = Box::pin(Interruptible::new(&table, 4, async {
+            Ok::<_, io::Error>(1)
+        }));
+        assert!(matches!(fut.as_mut().poll(&mut cx), Poll::Ready(Ok(1))));
+    }
+}
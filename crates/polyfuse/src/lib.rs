@@ -3,15 +3,41 @@
 #![doc(html_root_url = "https://docs.rs/polyfuse/0.4.0")]
 #![forbid(clippy::todo, clippy::unimplemented)]
 
+mod caller;
 mod conn;
-mod decoder;
+mod control;
+mod error;
+mod health;
+mod interrupt;
 mod session;
 
+#[cfg(test)]
+mod fault;
+
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
+
+#[cfg(feature = "latency-stats")]
+pub mod stats;
+
 pub mod bytes;
+pub mod daemon;
+pub mod decoder;
+pub mod errno;
 pub mod op;
 pub mod reply;
+pub mod util;
 
 pub use crate::{
+    caller::Caller,
+    errno::{Errno, Result, ToErrno},
+    error::Error,
+    health::Health,
+    interrupt::Interruptible,
     op::Operation,
-    session::{Data, KernelConfig, Notifier, Request, Session},
+    session::{
+        ConnectionInfo, Context, Data, Handler, KernelConfig, Notifier, Replied, ReplySender,
+        Request, RequestHeader, Session,
+    },
 };
@@ -1,26 +1,105 @@
 use crate::decoder::Decoder;
 use polyfuse_kernel::*;
-use std::{convert::TryFrom, ffi::OsStr, fmt, time::Duration, u32, u64};
-
-#[derive(Debug)]
+use std::{
+    convert::TryFrom,
+    ffi::OsStr,
+    fmt, mem,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+    u32, u64,
+};
+
+/// The error that occurs when a request message is malformed.
+///
+/// The raw payload of the request is retained so that the callers can
+/// dump it for debugging the protocol.
 pub struct DecodeError {
     inner: crate::decoder::DecodeError,
+    opcode: u32,
+    unique: u64,
+    payload: Vec<u8>,
 }
 
 impl DecodeError {
     #[inline]
-    const fn new(inner: crate::decoder::DecodeError) -> Self {
-        Self { inner }
+    pub(crate) const fn new(inner: crate::decoder::DecodeError) -> Self {
+        Self {
+            inner,
+            opcode: 0,
+            unique: 0,
+            payload: Vec::new(),
+        }
+    }
+
+    /// Attach the request to this error.
+    pub(crate) fn with_request(mut self, header: &fuse_in_header, payload: &[u8]) -> Self {
+        self.opcode = header.opcode;
+        self.unique = header.unique;
+        self.payload = payload.to_vec();
+        self
+    }
+
+    /// Return the opcode of the malformed request.
+    pub fn opcode(&self) -> u32 {
+        self.opcode
+    }
+
+    /// Return the unique ID of the malformed request.
+    pub fn unique(&self) -> u64 {
+        self.unique
+    }
+
+    /// Return the number of bytes that was expected to be available,
+    /// if the error is caused by the length of the payload.
+    pub fn expected_len(&self) -> Option<usize> {
+        match self.inner {
+            crate::decoder::DecodeError::UnexpectedEof { expected, .. }
+            | crate::decoder::DecodeError::InvalidLength { expected, .. } => Some(expected),
+            _ => None,
+        }
+    }
+
+    /// Return the number of bytes that was actually available,
+    /// if the error is caused by the length of the payload.
+    pub fn available_len(&self) -> Option<usize> {
+        match self.inner {
+            crate::decoder::DecodeError::UnexpectedEof { available, .. }
+            | crate::decoder::DecodeError::InvalidLength { available, .. } => Some(available),
+            _ => None,
+        }
+    }
+
+    /// Return the raw payload of the request, following `fuse_in_header`.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload[..]
+    }
+}
+
+impl fmt::Debug for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DecodeError")
+            .field("inner", &self.inner)
+            .field("opcode", &self.opcode)
+            .field("unique", &self.unique)
+            .field("payload_len", &self.payload.len())
+            .finish()
     }
 }
 
 impl fmt::Display for DecodeError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "failed to decode request message")
+        write!(
+            f,
+            "failed to decode request message (opcode={}, unique={})",
+            self.opcode, self.unique
+        )
     }
 }
 
-impl std::error::Error for DecodeError {}
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.inner)
+    }
+}
 
 /// The kind of filesystem operation requested by the kernel.
 #[non_exhaustive]
@@ -60,6 +139,9 @@ pub enum Operation<'op, T> {
     Fallocate(Fallocate<'op>),
     CopyFileRange(CopyFileRange<'op>),
     Poll(Poll<'op>),
+    Ioctl(Ioctl<'op>),
+    Lseek(Lseek<'op>),
+    Statx(Statx<'op>),
 
     Forget(Forgets<'op>),
     Interrupt(Interrupt<'op>),
@@ -109,6 +191,9 @@ where
             Operation::Fallocate(op) => op.fmt(f),
             Operation::CopyFileRange(op) => op.fmt(f),
             Operation::Poll(op) => op.fmt(f),
+            Operation::Ioctl(op) => op.fmt(f),
+            Operation::Lseek(op) => op.fmt(f),
+            Operation::Statx(op) => op.fmt(f),
             Operation::Forget(op) => op.fmt(f),
             Operation::Interrupt(op) => op.fmt(f),
 
@@ -173,7 +258,7 @@ impl<'op, T> Operation<'op, T> {
             }
 
             Some(fuse_opcode::FUSE_LOOKUP) => {
-                let name = decoder.fetch_str().map_err(DecodeError::new)?;
+                let name = decoder.fetch_name().map_err(DecodeError::new)?;
                 Ok(Operation::Lookup(Lookup { header, name }))
             }
 
@@ -190,37 +275,55 @@ impl<'op, T> Operation<'op, T> {
             Some(fuse_opcode::FUSE_READLINK) => Ok(Operation::Readlink(Readlink { header })),
 
             Some(fuse_opcode::FUSE_SYMLINK) => {
-                let name = decoder.fetch_str().map_err(DecodeError::new)?;
+                let name = decoder.fetch_name().map_err(DecodeError::new)?;
                 let link = decoder.fetch_str().map_err(DecodeError::new)?;
-                Ok(Operation::Symlink(Symlink { header, name, link }))
+                let secctx = fetch_secctx(&mut decoder).map_err(DecodeError::new)?;
+                Ok(Operation::Symlink(Symlink {
+                    header,
+                    name,
+                    link,
+                    secctx,
+                }))
             }
 
             Some(fuse_opcode::FUSE_MKNOD) => {
                 let arg = decoder.fetch().map_err(DecodeError::new)?;
-                let name = decoder.fetch_str().map_err(DecodeError::new)?;
-                Ok(Operation::Mknod(Mknod { header, arg, name }))
+                let name = decoder.fetch_name().map_err(DecodeError::new)?;
+                let secctx = fetch_secctx(&mut decoder).map_err(DecodeError::new)?;
+                Ok(Operation::Mknod(Mknod {
+                    header,
+                    arg,
+                    name,
+                    secctx,
+                }))
             }
 
             Some(fuse_opcode::FUSE_MKDIR) => {
                 let arg = decoder.fetch().map_err(DecodeError::new)?;
-                let name = decoder.fetch_str().map_err(DecodeError::new)?;
-                Ok(Operation::Mkdir(Mkdir { header, arg, name }))
+                let name = decoder.fetch_name().map_err(DecodeError::new)?;
+                let secctx = fetch_secctx(&mut decoder).map_err(DecodeError::new)?;
+                Ok(Operation::Mkdir(Mkdir {
+                    header,
+                    arg,
+                    name,
+                    secctx,
+                }))
             }
 
             Some(fuse_opcode::FUSE_UNLINK) => {
-                let name = decoder.fetch_str().map_err(DecodeError::new)?;
+                let name = decoder.fetch_name().map_err(DecodeError::new)?;
                 Ok(Operation::Unlink(Unlink { header, name }))
             }
 
             Some(fuse_opcode::FUSE_RMDIR) => {
-                let name = decoder.fetch_str().map_err(DecodeError::new)?;
+                let name = decoder.fetch_name().map_err(DecodeError::new)?;
                 Ok(Operation::Rmdir(Rmdir { header, name }))
             }
 
             Some(fuse_opcode::FUSE_RENAME) => {
                 let arg = decoder.fetch().map_err(DecodeError::new)?;
-                let name = decoder.fetch_str().map_err(DecodeError::new)?;
-                let newname = decoder.fetch_str().map_err(DecodeError::new)?;
+                let name = decoder.fetch_name().map_err(DecodeError::new)?;
+                let newname = decoder.fetch_name().map_err(DecodeError::new)?;
                 Ok(Operation::Rename(Rename {
                     header,
                     arg: RenameArg::V1(arg),
@@ -230,8 +333,8 @@ impl<'op, T> Operation<'op, T> {
             }
             Some(fuse_opcode::FUSE_RENAME2) => {
                 let arg = decoder.fetch().map_err(DecodeError::new)?;
-                let name = decoder.fetch_str().map_err(DecodeError::new)?;
-                let newname = decoder.fetch_str().map_err(DecodeError::new)?;
+                let name = decoder.fetch_name().map_err(DecodeError::new)?;
+                let newname = decoder.fetch_name().map_err(DecodeError::new)?;
                 Ok(Operation::Rename(Rename {
                     header,
                     arg: RenameArg::V2(arg),
@@ -242,7 +345,7 @@ impl<'op, T> Operation<'op, T> {
 
             Some(fuse_opcode::FUSE_LINK) => {
                 let arg = decoder.fetch().map_err(DecodeError::new)?;
-                let newname = decoder.fetch_str().map_err(DecodeError::new)?;
+                let newname = decoder.fetch_name().map_err(DecodeError::new)?;
                 Ok(Operation::Link(Link {
                     header,
                     arg,
@@ -374,8 +477,14 @@ impl<'op, T> Operation<'op, T> {
 
             Some(fuse_opcode::FUSE_CREATE) => {
                 let arg = decoder.fetch().map_err(DecodeError::new)?;
-                let name = decoder.fetch_str().map_err(DecodeError::new)?;
-                Ok(Operation::Create(Create { header, arg, name }))
+                let name = decoder.fetch_name().map_err(DecodeError::new)?;
+                let secctx = fetch_secctx(&mut decoder).map_err(DecodeError::new)?;
+                Ok(Operation::Create(Create {
+                    header,
+                    arg,
+                    name,
+                    secctx,
+                }))
             }
 
             Some(fuse_opcode::FUSE_BMAP) => {
@@ -398,6 +507,28 @@ impl<'op, T> Operation<'op, T> {
                 Ok(Operation::Poll(Poll { header, arg }))
             }
 
+            Some(fuse_opcode::FUSE_IOCTL) => {
+                let arg = decoder.fetch::<fuse_ioctl_in>().map_err(DecodeError::new)?;
+                let in_data = decoder
+                    .fetch_bytes(arg.in_size as usize)
+                    .map_err(DecodeError::new)?;
+                Ok(Operation::Ioctl(Ioctl {
+                    header,
+                    arg,
+                    in_data,
+                }))
+            }
+
+            Some(fuse_opcode::FUSE_LSEEK) => {
+                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                Ok(Operation::Lseek(Lseek { header, arg }))
+            }
+
+            Some(fuse_opcode::FUSE_STATX) => {
+                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                Ok(Operation::Statx(Statx { header, arg }))
+            }
+
             _ => {
                 tracing::warn!("unsupported opcode: {}", header.opcode);
                 Ok(Operation::Unknown)
@@ -455,6 +586,23 @@ pub struct Forgets<'op> {
     inner: ForgetsInner<'op>,
 }
 
+impl<'op> Forgets<'op> {
+    /// Create a set of forget information from the slice.
+    ///
+    /// This is useful for the layers passing a part of the received
+    /// information to the filesystem they wrap.
+    #[inline]
+    pub fn new(forgets: &'op [Forget]) -> Self {
+        let forgets = unsafe {
+            // Safety: Forget has the same layout with fuse_forget_one
+            std::slice::from_raw_parts(forgets.as_ptr() as *const fuse_forget_one, forgets.len())
+        };
+        Self {
+            inner: ForgetsInner::Batch(forgets),
+        }
+    }
+}
+
 impl fmt::Debug for Forgets<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_set().entries(self.as_ref()).finish()
@@ -496,6 +644,17 @@ impl fmt::Debug for Forget {
 }
 
 impl Forget {
+    /// Create a new `Forget` with the specified inode number and lookup count.
+    #[inline]
+    pub fn new(ino: u64, nlookup: u64) -> Self {
+        Self {
+            forget: fuse_forget_one {
+                nodeid: ino,
+                nlookup,
+            },
+        }
+    }
+
     /// Return the inode number of the target inode.
     #[inline]
     pub fn ino(&self) -> u64 {
@@ -635,6 +794,47 @@ impl<'op> Getattr<'op> {
     }
 }
 
+/// The arguments of a `Getattr` operation issued by the filesystem itself.
+///
+/// This is useful for the layers that need the attributes of an inode
+/// missing from their caches, which can pass the operation returned from
+/// [`op`](Self::op) to the filesystem they wrap.
+pub struct GetattrArgs {
+    header: fuse_in_header,
+    arg: fuse_getattr_in,
+}
+
+impl fmt::Debug for GetattrArgs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GetattrArgs")
+            .field("ino", &self.header.nodeid)
+            .finish()
+    }
+}
+
+impl GetattrArgs {
+    /// Create the arguments for getting the attributes of the inode.
+    pub fn new(ino: u64) -> Self {
+        Self {
+            header: fuse_in_header {
+                len: (mem::size_of::<fuse_in_header>() + mem::size_of::<fuse_getattr_in>()) as u32,
+                opcode: fuse_opcode::FUSE_GETATTR as u32,
+                nodeid: ino,
+                ..Default::default()
+            },
+            arg: fuse_getattr_in::default(),
+        }
+    }
+
+    /// Return the operation borrowing these arguments.
+    pub fn op(&self) -> Getattr<'_> {
+        Getattr {
+            header: &self.header,
+            arg: &self.arg,
+        }
+    }
+}
+
 /// Set file attributes.
 ///
 /// When the setting of attribute values succeeds, the filesystem replies its value
@@ -691,6 +891,10 @@ impl<'op> Setattr<'op> {
     }
 
     /// Return the size of the file content to be set.
+    ///
+    /// When writeback caching is enabled, the kernel maintains the file size
+    /// by itself and may ignore the size replied from `Getattr`, so the
+    /// filesystem must apply this value as is.
     #[inline]
     pub fn size(&self) -> Option<u64> {
         self.get(FATTR_SIZE, |arg| arg.size)
@@ -744,6 +948,16 @@ pub enum SetAttrTime {
     Now,
 }
 
+impl SetAttrTime {
+    /// Return the requested time, using `now` for [`Now`](Self::Now).
+    pub fn resolve(self, now: SystemTime) -> SystemTime {
+        match self {
+            Self::Timespec(time) => UNIX_EPOCH + time,
+            Self::Now => now,
+        }
+    }
+}
+
 /// Read a symbolic link.
 pub struct Readlink<'op> {
     header: &'op fuse_in_header,
@@ -764,6 +978,58 @@ impl<'op> Readlink<'op> {
     }
 }
 
+/// The security context of a new inode, sent along with `Mknod`, `Mkdir`,
+/// `Symlink` and `Create`.
+///
+/// The context is computed by the LSM of the kernel, such as SELinux, from
+/// the creating process and the parent directory, and should be stored as
+/// the extended attribute of the name.
+#[derive(Debug, Clone, Copy)]
+pub struct SecurityContext<'op> {
+    name: &'op OsStr,
+    value: &'op [u8],
+}
+
+impl<'op> SecurityContext<'op> {
+    /// Return the name of the extended attribute, e.g. `security.selinux`.
+    #[inline]
+    pub fn name(&self) -> &'op OsStr {
+        self.name
+    }
+
+    /// Return the value of the extended attribute, as given by the LSM.
+    ///
+    /// The value may include the terminating NUL character.
+    #[inline]
+    pub fn value(&self) -> &'op [u8] {
+        self.value
+    }
+}
+
+/// Fetch the security context following the arguments of the requests
+/// creating an inode.
+///
+/// The context is placed right after the names without any alignment, so
+/// the headers are read by bytes.
+fn fetch_secctx<'op>(
+    decoder: &mut Decoder<'op>,
+) -> Result<Option<SecurityContext<'op>>, crate::decoder::DecodeError> {
+    if decoder.remaining().is_empty() {
+        return Ok(None);
+    }
+    let read_u32 = |b: &[u8]| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]);
+
+    let header = decoder.fetch_bytes(mem::size_of::<fuse_secctx_header>())?;
+    if read_u32(&header[4..]) == 0 {
+        return Ok(None);
+    }
+    // The kernel sends at most one context.
+    let secctx = decoder.fetch_bytes(mem::size_of::<fuse_secctx>())?;
+    let name = decoder.fetch_str()?;
+    let value = decoder.fetch_bytes(read_u32(secctx) as usize)?;
+    Ok(Some(SecurityContext { name, value }))
+}
+
 /// Create a symbolic link.
 ///
 /// When the link is successfully created, the filesystem must send
@@ -772,6 +1038,7 @@ pub struct Symlink<'op> {
     header: &'op fuse_in_header,
     name: &'op OsStr,
     link: &'op OsStr,
+    secctx: Option<SecurityContext<'op>>,
 }
 
 impl fmt::Debug for Symlink<'_> {
@@ -799,6 +1066,13 @@ impl<'op> Symlink<'op> {
     pub fn link(&self) -> &OsStr {
         self.link
     }
+    /// Return the security context of the new inode computed by the
+    /// kernel, if enabled by
+    /// [`KernelConfig::security_context`](crate::KernelConfig::security_context).
+    #[inline]
+    pub fn security_context(&self) -> Option<&SecurityContext<'op>> {
+        self.secctx.as_ref()
+    }
 }
 
 /// Create a file node.
@@ -809,6 +1083,7 @@ pub struct Mknod<'op> {
     header: &'op fuse_in_header,
     arg: &'op fuse_mknod_in,
     name: &'op OsStr,
+    secctx: Option<SecurityContext<'op>>,
 }
 
 impl fmt::Debug for Mknod<'_> {
@@ -850,6 +1125,13 @@ impl<'op> Mknod<'op> {
     pub fn umask(&self) -> u32 {
         self.arg.umask
     }
+    /// Return the security context of the new inode computed by the
+    /// kernel, if enabled by
+    /// [`KernelConfig::security_context`](crate::KernelConfig::security_context).
+    #[inline]
+    pub fn security_context(&self) -> Option<&SecurityContext<'op>> {
+        self.secctx.as_ref()
+    }
 }
 
 /// Create a directory node.
@@ -860,6 +1142,7 @@ pub struct Mkdir<'op> {
     header: &'op fuse_in_header,
     arg: &'op fuse_mkdir_in,
     name: &'op OsStr,
+    secctx: Option<SecurityContext<'op>>,
 }
 
 impl fmt::Debug for Mkdir<'_> {
@@ -892,6 +1175,13 @@ impl<'op> Mkdir<'op> {
     pub fn umask(&self) -> u32 {
         self.arg.umask
     }
+    /// Return the security context of the new inode computed by the
+    /// kernel, if enabled by
+    /// [`KernelConfig::security_context`](crate::KernelConfig::security_context).
+    #[inline]
+    pub fn security_context(&self) -> Option<&SecurityContext<'op>> {
+        self.secctx.as_ref()
+    }
 }
 
 // TODO: description about lookup count.
@@ -1206,11 +1496,27 @@ impl<'op> Write<'op> {
     }
 
     /// Return the flags specified at opening the file.
+    ///
+    /// When writeback caching is enabled, the kernel computes the offset
+    /// of appending writes by itself and the `O_APPEND` flag must be ignored.
+    /// Otherwise, the filesystem is responsible for appending the data
+    /// to the end of the file regardless of `offset`.
     #[inline]
     pub fn flags(&self) -> u32 {
         self.arg.flags
     }
 
+    /// Return whether this write is a delayed write from the page cache.
+    ///
+    /// Such writes are only sent when writeback caching is enabled.
+    /// They are not associated with the caller of `write(2)`, so the
+    /// credentials in the request header and the lock owner are not
+    /// meaningful.
+    #[inline]
+    pub fn is_writeback(&self) -> bool {
+        self.arg.write_flags & FUSE_WRITE_CACHE != 0
+    }
+
     /// Return the identifier of lock owner.
     #[inline]
     pub fn lock_owner(&self) -> Option<LockOwner> {
@@ -1860,6 +2166,7 @@ pub struct Create<'op> {
     header: &'op fuse_in_header,
     arg: &'op fuse_create_in,
     name: &'op OsStr,
+    secctx: Option<SecurityContext<'op>>,
 }
 
 impl fmt::Debug for Create<'_> {
@@ -1907,6 +2214,13 @@ impl<'op> Create<'op> {
     pub fn umask(&self) -> u32 {
         self.arg.umask
     }
+    /// Return the security context of the new inode computed by the
+    /// kernel, if enabled by
+    /// [`KernelConfig::security_context`](crate::KernelConfig::security_context).
+    #[inline]
+    pub fn security_context(&self) -> Option<&SecurityContext<'op>> {
+        self.secctx.as_ref()
+    }
 }
 
 /// Map block index within a file to block index within device.
@@ -2066,6 +2380,217 @@ impl<'op> CopyFileRange<'op> {
     }
 }
 
+/// Perform an `ioctl(2)` on an opened file.
+///
+/// The result of the command must be replied using `IoctlOut`,
+/// followed by the output data of at most `out_size` bytes.
+///
+/// For a *restricted* ioctl, which is the only kind the kernel sends for
+/// regular FUSE files, the sizes of the input and output data are derived
+/// from the direction and size encoded in the command number.
+/// An *unrestricted* ioctl carries no such information, and the filesystem
+/// needs to ask the kernel to resend the request with the user memory
+/// referenced by `arg` by using [`IoctlOut::retry_in`] and
+/// [`IoctlOut::retry_out`].
+///
+/// [`IoctlOut::retry_in`]: crate::reply::IoctlOut::retry_in
+/// [`IoctlOut::retry_out`]: crate::reply::IoctlOut::retry_out
+pub struct Ioctl<'op> {
+    header: &'op fuse_in_header,
+    arg: &'op fuse_ioctl_in,
+    in_data: &'op [u8],
+}
+
+impl fmt::Debug for Ioctl<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ioctl")
+            .field("ino", &self.ino())
+            .field("fh", &self.fh())
+            .field("flags", &self.flags())
+            .field("cmd", &self.cmd())
+            .field("arg", &self.arg())
+            .field("in_size", &self.in_data.len())
+            .field("out_size", &self.out_size())
+            .finish()
+    }
+}
+
+impl<'op> Ioctl<'op> {
+    /// Return the inode number of opened file.
+    #[inline]
+    pub fn ino(&self) -> u64 {
+        self.header.nodeid
+    }
+
+    /// Return the handle of opened file.
+    #[inline]
+    pub fn fh(&self) -> u64 {
+        self.arg.fh
+    }
+
+    /// Return the raw value of `FUSE_IOCTL_*` flags.
+    #[inline]
+    pub fn flags(&self) -> u32 {
+        self.arg.flags
+    }
+
+    /// Return the command number.
+    #[inline]
+    pub fn cmd(&self) -> u32 {
+        self.arg.cmd
+    }
+
+    /// Return the argument of the command.
+    ///
+    /// For the commands passing the data by pointers, this value is the
+    /// address in the caller's memory and is only meaningful for requesting
+    /// a retry of unrestricted ioctls.
+    #[inline]
+    pub fn arg(&self) -> u64 {
+        self.arg.arg
+    }
+
+    /// Return the input data copied from the caller.
+    #[inline]
+    pub fn in_data(&self) -> &'op [u8] {
+        self.in_data
+    }
+
+    /// Return the maximum size of the output data.
+    #[inline]
+    pub fn out_size(&self) -> u32 {
+        self.arg.out_size
+    }
+
+    /// Return whether the ioctl is unrestricted, i.e. the retry is allowed.
+    #[inline]
+    pub fn is_unrestricted(&self) -> bool {
+        self.arg.flags & FUSE_IOCTL_UNRESTRICTED != 0
+    }
+
+    /// Return whether the ioctl is issued by a 32-bit caller
+    /// through the compat syscall.
+    #[inline]
+    pub fn is_compat(&self) -> bool {
+        self.arg.flags & FUSE_IOCTL_COMPAT != 0
+    }
+
+    /// Return whether the ioctl is issued on a directory.
+    #[inline]
+    pub fn is_dir(&self) -> bool {
+        self.arg.flags & FUSE_IOCTL_DIR != 0
+    }
+}
+
+/// Reposition the offset of an opened file.
+///
+/// The kernel only sends this request for `SEEK_DATA` and `SEEK_HOLE`,
+/// and handles the other `whence` values by itself.
+/// The resulting offset must be replied using `LseekOut`, or `ENXIO` if
+/// the offset is at or beyond the end of the file, or there is no more
+/// data after the offset.
+///
+/// If the filesystem replies `ENOSYS`, the kernel treats the whole file
+/// as data and does not send this request any more.
+pub struct Lseek<'op> {
+    header: &'op fuse_in_header,
+    arg: &'op fuse_lseek_in,
+}
+
+impl fmt::Debug for Lseek<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lseek")
+            .field("ino", &self.ino())
+            .field("fh", &self.fh())
+            .field("offset", &self.offset())
+            .field("whence", &self.whence())
+            .finish()
+    }
+}
+
+impl<'op> Lseek<'op> {
+    /// Return the inode number of opened file.
+    #[inline]
+    pub fn ino(&self) -> u64 {
+        self.header.nodeid
+    }
+
+    /// Return the handle of opened file.
+    #[inline]
+    pub fn fh(&self) -> u64 {
+        self.arg.fh
+    }
+
+    /// Return the offset to start searching from.
+    #[inline]
+    pub fn offset(&self) -> u64 {
+        self.arg.offset
+    }
+
+    /// Return the directive of searching, i.e. `SEEK_DATA` or `SEEK_HOLE`.
+    #[inline]
+    pub fn whence(&self) -> u32 {
+        self.arg.whence
+    }
+}
+
+/// Get the extended file attributes, as requested by `statx(2)`.
+///
+/// The kernel sends this request only when the caller asks for the
+/// attributes out of `fuse_attr`, such as the creation time. The attributes
+/// must be replied using `StatxOut`, whose mask tells the kernel which of
+/// them are valid.
+///
+/// If the filesystem replies `ENOSYS`, the kernel falls back to `Getattr`
+/// and does not send this request any more.
+pub struct Statx<'op> {
+    header: &'op fuse_in_header,
+    arg: &'op fuse_statx_in,
+}
+
+impl fmt::Debug for Statx<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Statx")
+            .field("ino", &self.ino())
+            .field("fh", &self.fh())
+            .field("flags", &self.flags())
+            .field("mask", &self.mask())
+            .finish()
+    }
+}
+
+impl<'op> Statx<'op> {
+    /// Return the inode number for obtaining the attributes.
+    #[inline]
+    pub fn ino(&self) -> u64 {
+        self.header.nodeid
+    }
+
+    /// Return the handle of opened file, if specified.
+    #[inline]
+    pub fn fh(&self) -> Option<u64> {
+        if self.arg.getattr_flags & FUSE_GETATTR_FH != 0 {
+            Some(self.arg.fh)
+        } else {
+            None
+        }
+    }
+
+    /// Return the flags of `statx(2)`, such as `AT_STATX_FORCE_SYNC`.
+    #[inline]
+    pub fn flags(&self) -> u32 {
+        self.arg.sx_flags
+    }
+
+    /// Return the mask of the requested attributes, such as `STATX_BTIME`.
+    ///
+    /// The filesystem may reply more or fewer attributes than requested.
+    #[inline]
+    pub fn mask(&self) -> u32 {
+        self.arg.sx_mask
+    }
+}
+
 /// Poll for readiness.
 ///
 /// The mask of ready poll events must be replied using `ReplyPoll`.
@@ -3,6 +3,12 @@ use polyfuse_kernel::*;
 use std::{convert::TryInto as _, ffi::OsStr, fmt, mem, os::unix::prelude::*, time::Duration};
 use zerocopy::AsBytes as _;
 
+#[cfg(feature = "futures-core")]
+mod stream;
+
+#[cfg(feature = "futures-core")]
+pub use self::stream::{Dirent, DirentPlus};
+
 /// Attributes about a file.
 #[repr(transparent)]
 pub struct FileAttr {
@@ -27,12 +33,24 @@ impl FileAttr {
         self.attr.size = size;
     }
 
+    /// Return the size of content.
+    #[inline]
+    pub fn get_size(&self) -> u64 {
+        self.attr.size
+    }
+
     /// Set the permission of the inode.
     #[inline]
     pub fn mode(&mut self, mode: u32) {
         self.attr.mode = mode;
     }
 
+    /// Return the permission of the inode.
+    #[inline]
+    pub fn get_mode(&self) -> u32 {
+        self.attr.mode
+    }
+
     /// Set the number of hard links.
     #[inline]
     pub fn nlink(&mut self, nlink: u32) {
@@ -45,12 +63,24 @@ impl FileAttr {
         self.attr.uid = uid;
     }
 
+    /// Return the user ID.
+    #[inline]
+    pub fn get_uid(&self) -> u32 {
+        self.attr.uid
+    }
+
     /// Set the group ID.
     #[inline]
     pub fn gid(&mut self, gid: u32) {
         self.attr.gid = gid;
     }
 
+    /// Return the group ID.
+    #[inline]
+    pub fn get_gid(&self) -> u32 {
+        self.attr.gid
+    }
+
     /// Set the device ID.
     #[inline]
     pub fn rdev(&mut self, rdev: u32) {
@@ -89,9 +119,36 @@ impl FileAttr {
         self.attr.ctime = ctime.as_secs();
         self.attr.ctimensec = ctime.subsec_nanos();
     }
+
+    /// Return the last accessed time.
+    #[inline]
+    pub fn get_atime(&self) -> Duration {
+        Duration::new(self.attr.atime, self.attr.atimensec)
+    }
+
+    /// Return the last modification time.
+    #[inline]
+    pub fn get_mtime(&self) -> Duration {
+        Duration::new(self.attr.mtime, self.attr.mtimensec)
+    }
+
+    /// Return the last created time.
+    #[inline]
+    pub fn get_ctime(&self) -> Duration {
+        Duration::new(self.attr.ctime, self.attr.ctimensec)
+    }
+
+    /// Set the flags of the attributes, such as `FUSE_ATTR_SUBMOUNT`.
+    ///
+    /// The flags are defined since ABI 7.32, and the kernel ignores them
+    /// unless the corresponding features have been negotiated.
+    #[inline]
+    pub fn flags(&mut self, flags: u32) {
+        self.attr.flags = flags;
+    }
 }
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct EntryOut {
     out: fuse_entry_out,
 }
@@ -138,6 +195,12 @@ impl EntryOut {
         self.out.nodeid = ino;
     }
 
+    /// Return the inode number of this entry.
+    #[inline]
+    pub fn get_ino(&self) -> u64 {
+        self.out.nodeid
+    }
+
     /// Set the generation of this entry.
     ///
     /// This parameter is used to distinguish the inode from the past one
@@ -169,7 +232,7 @@ impl EntryOut {
     }
 }
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct AttrOut {
     out: fuse_attr_out,
 }
@@ -212,6 +275,226 @@ impl Bytes for AttrOut {
     }
 }
 
+/// The extended attributes replied to `Statx` requests.
+///
+/// Each setter marks the attribute as valid in the mask, so that the kernel
+/// only reports the attributes actually provided by the filesystem. For
+/// example, the creation time stays unavailable to the callers of
+/// `statx(2)` unless [`btime`](Self::btime) is set.
+#[repr(transparent)]
+pub struct StatxAttr {
+    stat: fuse_statx,
+}
+
+impl StatxAttr {
+    #[inline]
+    fn from_stat_mut(stat: &mut fuse_statx) -> &mut StatxAttr {
+        unsafe { &mut *(stat as *mut fuse_statx as *mut StatxAttr) }
+    }
+
+    /// Return the mask of the valid attributes, such as `STATX_BTIME`.
+    #[inline]
+    pub fn get_mask(&self) -> u32 {
+        self.stat.mask
+    }
+
+    /// Replace the mask of the valid attributes.
+    ///
+    /// This can be used to withdraw the attributes that have been set but
+    /// are not authoritative.
+    #[inline]
+    pub fn mask(&mut self, mask: u32) {
+        self.stat.mask = mask;
+    }
+
+    /// Copy the basic attributes, marking all of them as valid.
+    pub fn copy_from(&mut self, attr: &FileAttr) {
+        let attr = &attr.attr;
+        self.stat.ino = attr.ino;
+        self.stat.size = attr.size;
+        self.stat.blocks = attr.blocks;
+        self.stat.mode = attr.mode as u16;
+        self.stat.nlink = attr.nlink;
+        self.stat.uid = attr.uid;
+        self.stat.gid = attr.gid;
+        self.stat.blksize = attr.blksize;
+        // The 32-bit encoding of the device ID used in fuse_attr.
+        self.stat.rdev_major = (attr.rdev & 0xfff00) >> 8;
+        self.stat.rdev_minor = (attr.rdev & 0xff) | ((attr.rdev >> 12) & 0xfff00);
+        self.stat.atime = sx_time(Duration::new(attr.atime, attr.atimensec));
+        self.stat.mtime = sx_time(Duration::new(attr.mtime, attr.mtimensec));
+        self.stat.ctime = sx_time(Duration::new(attr.ctime, attr.ctimensec));
+        self.stat.mask |= libc::STATX_BASIC_STATS;
+    }
+
+    /// Set the inode number.
+    #[inline]
+    pub fn ino(&mut self, ino: u64) {
+        self.stat.ino = ino;
+        self.stat.mask |= libc::STATX_INO;
+    }
+
+    /// Set the size of content.
+    #[inline]
+    pub fn size(&mut self, size: u64) {
+        self.stat.size = size;
+        self.stat.mask |= libc::STATX_SIZE;
+    }
+
+    /// Set the number of allocated blocks.
+    #[inline]
+    pub fn blocks(&mut self, blocks: u64) {
+        self.stat.blocks = blocks;
+        self.stat.mask |= libc::STATX_BLOCKS;
+    }
+
+    /// Set the file type and the permission of the inode.
+    #[inline]
+    pub fn mode(&mut self, mode: u32) {
+        self.stat.mode = mode as u16;
+        self.stat.mask |= libc::STATX_TYPE | libc::STATX_MODE;
+    }
+
+    /// Set the number of hard links.
+    #[inline]
+    pub fn nlink(&mut self, nlink: u32) {
+        self.stat.nlink = nlink;
+        self.stat.mask |= libc::STATX_NLINK;
+    }
+
+    /// Set the user ID.
+    #[inline]
+    pub fn uid(&mut self, uid: u32) {
+        self.stat.uid = uid;
+        self.stat.mask |= libc::STATX_UID;
+    }
+
+    /// Return the user ID.
+    #[inline]
+    pub fn get_uid(&self) -> u32 {
+        self.stat.uid
+    }
+
+    /// Set the group ID.
+    #[inline]
+    pub fn gid(&mut self, gid: u32) {
+        self.stat.gid = gid;
+        self.stat.mask |= libc::STATX_GID;
+    }
+
+    /// Return the group ID.
+    #[inline]
+    pub fn get_gid(&self) -> u32 {
+        self.stat.gid
+    }
+
+    /// Set the block size.
+    ///
+    /// The block size is always valid and has no bit in the mask.
+    #[inline]
+    pub fn blksize(&mut self, blksize: u32) {
+        self.stat.blksize = blksize;
+    }
+
+    /// Set the device ID of the special file.
+    ///
+    /// The device ID is always valid and has no bit in the mask.
+    #[inline]
+    pub fn rdev(&mut self, major: u32, minor: u32) {
+        self.stat.rdev_major = major;
+        self.stat.rdev_minor = minor;
+    }
+
+    /// Set the last accessed time.
+    #[inline]
+    pub fn atime(&mut self, atime: Duration) {
+        self.stat.atime = sx_time(atime);
+        self.stat.mask |= libc::STATX_ATIME;
+    }
+
+    /// Set the last modification time.
+    #[inline]
+    pub fn mtime(&mut self, mtime: Duration) {
+        self.stat.mtime = sx_time(mtime);
+        self.stat.mask |= libc::STATX_MTIME;
+    }
+
+    /// Set the last changed time.
+    #[inline]
+    pub fn ctime(&mut self, ctime: Duration) {
+        self.stat.ctime = sx_time(ctime);
+        self.stat.mask |= libc::STATX_CTIME;
+    }
+
+    /// Set the creation time.
+    #[inline]
+    pub fn btime(&mut self, btime: Duration) {
+        self.stat.btime = sx_time(btime);
+        self.stat.mask |= libc::STATX_BTIME;
+    }
+
+    /// Set the file attributes, such as `STATX_ATTR_IMMUTABLE`, along with
+    /// the mask of the ones supported by the filesystem.
+    #[inline]
+    pub fn attributes(&mut self, attributes: u64, mask: u64) {
+        self.stat.attributes = attributes & mask;
+        self.stat.attributes_mask = mask;
+    }
+}
+
+#[inline]
+fn sx_time(time: Duration) -> fuse_sx_time {
+    fuse_sx_time {
+        tv_sec: time.as_secs() as i64,
+        tv_nsec: time.subsec_nanos(),
+        ..Default::default()
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct StatxOut {
+    out: fuse_statx_out,
+}
+
+impl fmt::Debug for StatxOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StatxOut")
+            .field("mask", &self.out.stat.mask)
+            .finish()
+    }
+}
+
+impl StatxOut {
+    /// Return the object to fill attribute values.
+    #[inline]
+    pub fn attr(&mut self) -> &mut StatxAttr {
+        StatxAttr::from_stat_mut(&mut self.out.stat)
+    }
+
+    /// Set the validity timeout for this attribute.
+    pub fn ttl(&mut self, ttl: Duration) {
+        self.out.attr_valid = ttl.as_secs();
+        self.out.attr_valid_nsec = ttl.subsec_nanos();
+    }
+}
+
+impl Bytes for StatxOut {
+    #[inline]
+    fn size(&self) -> usize {
+        self.out.as_bytes().len()
+    }
+
+    #[inline]
+    fn count(&self) -> usize {
+        1
+    }
+
+    #[inline]
+    fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+        dst.put(self.out.as_bytes());
+    }
+}
+
 #[derive(Default)]
 pub struct OpenOut {
     out: fuse_open_out,
@@ -278,6 +561,17 @@ impl OpenOut {
     pub fn cache_dir(&mut self, enabled: bool) {
         self.set_flag(FOPEN_CACHE_DIR, enabled);
     }
+
+    /// Allow the concurrent writes to this file in the direct I/O mode.
+    ///
+    /// By default, the kernel serializes the writes to a file opened with
+    /// [`direct_io`](Self::direct_io). With this flag, the writes not
+    /// extending the file are sent in parallel, so the filesystem must
+    /// handle the overlapping writes by itself. It is ignored by the
+    /// kernels before Linux 6.2.
+    pub fn parallel_direct_writes(&mut self, enabled: bool) {
+        self.set_flag(FOPEN_PARALLEL_DIRECT_WRITES, enabled);
+    }
 }
 
 #[derive(Default)]
@@ -315,15 +609,30 @@ impl WriteOut {
     }
 }
 
-#[derive(Default)]
+/// The maximum length of file names reported by default.
+const DEFAULT_NAMELEN: u32 = 255;
+
+/// The reply to a `Statfs` request.
+///
+/// The maximum length of file names defaults to 255 bytes, since a zero
+/// length breaks `pathconf(_PC_NAME_MAX)` on the mounted filesystem.
 pub struct StatfsOut {
     out: fuse_statfs_out,
 }
 
+impl Default for StatfsOut {
+    fn default() -> Self {
+        let mut out = fuse_statfs_out::default();
+        out.st.namelen = DEFAULT_NAMELEN;
+        Self { out }
+    }
+}
+
 impl fmt::Debug for StatfsOut {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields.
-        f.debug_struct("StatfsOut").finish()
+        f.debug_struct("StatfsOut")
+            .field("statfs", Statfs::from_kstatfs(&self.out.st))
+            .finish()
     }
 }
 
@@ -351,12 +660,37 @@ impl StatfsOut {
     }
 }
 
+/// The filesystem statistics, as reported by `statfs(2)`.
+///
+/// The mount flags (`f_flags`) are not a part of the FUSE protocol; the
+/// kernel derives them from the mount options instead.
 #[derive(Default)]
+#[repr(transparent)]
 pub struct Statfs {
     st: fuse_kstatfs,
 }
 
+impl fmt::Debug for Statfs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Statfs")
+            .field("bsize", &self.st.bsize)
+            .field("frsize", &self.st.frsize)
+            .field("blocks", &self.st.blocks)
+            .field("bfree", &self.st.bfree)
+            .field("bavail", &self.st.bavail)
+            .field("files", &self.st.files)
+            .field("ffree", &self.st.ffree)
+            .field("namelen", &self.st.namelen)
+            .finish()
+    }
+}
+
 impl Statfs {
+    #[inline]
+    fn from_kstatfs(st: &fuse_kstatfs) -> &Statfs {
+        unsafe { &*(st as *const fuse_kstatfs as *const Statfs) }
+    }
+
     #[inline]
     fn from_kstatfs_mut(st: &mut fuse_kstatfs) -> &mut Statfs {
         unsafe { &mut *(st as *mut fuse_kstatfs as *mut Statfs) }
@@ -367,11 +701,18 @@ impl Statfs {
         self.st.bsize = bsize;
     }
 
-    /// Set the fragment size.
+    /// Set the fragment size, i.e. the unit of `blocks`, `bfree` and `bavail`.
+    ///
+    /// If it is zero, the block size is used as the fragment size by `statvfs(3)`.
     pub fn frsize(&mut self, frsize: u32) {
         self.st.frsize = frsize;
     }
 
+    /// Return the fragment size.
+    pub fn get_frsize(&self) -> u32 {
+        self.st.frsize
+    }
+
     /// Set the number of blocks in the filesystem.
     pub fn blocks(&mut self, blocks: u64) {
         self.st.blocks = blocks;
@@ -398,9 +739,16 @@ impl Statfs {
     }
 
     /// Set the maximum length of file names.
+    ///
+    /// This is reported by `pathconf(_PC_NAME_MAX)` on the mounted filesystem.
     pub fn namelen(&mut self, namelen: u32) {
         self.st.namelen = namelen;
     }
+
+    /// Return the maximum length of file names.
+    pub fn get_namelen(&self) -> u32 {
+        self.st.namelen
+    }
 }
 
 #[derive(Default)]
@@ -436,6 +784,12 @@ impl XattrOut {
     pub fn size(&mut self, size: u32) {
         self.out.size = size;
     }
+
+    /// Return the size of the value set by [`size`](Self::size).
+    #[inline]
+    pub fn get_size(&self) -> u32 {
+        self.out.size
+    }
 }
 
 #[derive(Default)]
@@ -575,6 +929,7 @@ impl PollOut {
     }
 }
 
+#[derive(Clone)]
 pub struct ReaddirOut {
     buf: Vec<u8>,
 }
@@ -636,7 +991,468 @@ impl ReaddirOut {
     }
 }
 
+#[derive(Default)]
+pub struct LseekOut {
+    out: fuse_lseek_out,
+}
+
+impl fmt::Debug for LseekOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LseekOut")
+            .field("offset", &self.out.offset)
+            .finish()
+    }
+}
+
+impl Bytes for LseekOut {
+    #[inline]
+    fn size(&self) -> usize {
+        self.out.as_bytes().len()
+    }
+
+    #[inline]
+    fn count(&self) -> usize {
+        1
+    }
+
+    #[inline]
+    fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+        dst.put(self.out.as_bytes());
+    }
+}
+
+impl LseekOut {
+    pub fn offset(&mut self, offset: u64) {
+        self.out.offset = offset;
+    }
+}
+
+/// The reply to an `IOCTL` request.
+///
+/// When the ioctl is completed, the output data is sent with this value
+/// as a tuple, e.g. `req.reply((out, &data[..]))`.
+#[derive(Clone, Default)]
+pub struct IoctlOut {
+    out: fuse_ioctl_out,
+    in_iovs: Vec<fuse_ioctl_iovec>,
+    out_iovs: Vec<fuse_ioctl_iovec>,
+}
+
+impl fmt::Debug for IoctlOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IoctlOut")
+            .field("result", &self.out.result)
+            .field("flags", &self.out.flags)
+            .field("in_iovs", &self.in_iovs.len())
+            .field("out_iovs", &self.out_iovs.len())
+            .finish()
+    }
+}
+
+impl Bytes for IoctlOut {
+    #[inline]
+    fn size(&self) -> usize {
+        self.out.as_bytes().len() + self.in_iovs.as_bytes().len() + self.out_iovs.as_bytes().len()
+    }
+
+    #[inline]
+    fn count(&self) -> usize {
+        1 + (!self.in_iovs.is_empty()) as usize + (!self.out_iovs.is_empty()) as usize
+    }
+
+    fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+        dst.put(self.out.as_bytes());
+        if !self.in_iovs.is_empty() {
+            dst.put(self.in_iovs.as_bytes());
+        }
+        if !self.out_iovs.is_empty() {
+            dst.put(self.out_iovs.as_bytes());
+        }
+    }
+}
+
+impl IoctlOut {
+    /// Set the return value of `ioctl(2)`.
+    pub fn result(&mut self, result: i32) {
+        self.out.result = result;
+    }
+
+    /// Request the kernel to resend the ioctl with the input data
+    /// copied from `len` bytes at `base` in the caller's memory.
+    ///
+    /// The retry is only allowed for unrestricted ioctls,
+    /// and at most `FUSE_IOCTL_MAX_IOV` regions can be requested
+    /// for each direction.
+    pub fn retry_in(&mut self, base: u64, len: u64) {
+        self.out.flags |= FUSE_IOCTL_RETRY;
+        self.in_iovs.push(fuse_ioctl_iovec { base, len });
+        self.out.in_iovs = self.in_iovs.len() as u32;
+    }
+
+    /// Request the kernel to resend the ioctl with the room for
+    /// the output data to be copied to `len` bytes at `base`.
+    ///
+    /// See also [`retry_in`](IoctlOut::retry_in).
+    pub fn retry_out(&mut self, base: u64, len: u64) {
+        self.out.flags |= FUSE_IOCTL_RETRY;
+        self.out_iovs.push(fuse_ioctl_iovec { base, len });
+        self.out.out_iovs = self.out_iovs.len() as u32;
+    }
+}
+
+/// The directory entries with their attributes, for `READDIRPLUS`.
+pub struct ReaddirPlusOut {
+    buf: Vec<u8>,
+}
+
+impl fmt::Debug for ReaddirPlusOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReaddirPlusOut").finish()
+    }
+}
+
+impl Bytes for ReaddirPlusOut {
+    #[inline]
+    fn size(&self) -> usize {
+        self.buf.size()
+    }
+
+    #[inline]
+    fn count(&self) -> usize {
+        self.buf.count()
+    }
+
+    fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+        self.buf.fill_bytes(dst)
+    }
+}
+
+impl ReaddirPlusOut {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buf: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Append an entry, returning `true` if there is no room left for it.
+    ///
+    /// The inode number of the dirent is taken from `entry`.  Unlike
+    /// `READDIR`, the kernel looks up every entry returned here,
+    /// so the filesystem must account them in its lookup counts
+    /// unless the inode number is zero.
+    pub fn entry(&mut self, name: &OsStr, typ: u32, off: u64, entry: &EntryOut) -> bool {
+        let name = name.as_bytes();
+        let remaining = self.buf.capacity() - self.buf.len();
+
+        let entry_size = mem::size_of::<fuse_direntplus>() + name.len();
+        let aligned_entry_size = aligned(entry_size);
+
+        if remaining < aligned_entry_size {
+            return true;
+        }
+
+        let direntplus = fuse_direntplus {
+            entry_out: entry.out,
+            dirent: fuse_dirent {
+                ino: entry.out.nodeid,
+                off,
+                namelen: name.len().try_into().expect("name length is too long"),
+                typ,
+                name: [],
+            },
+        };
+        let lenbefore = self.buf.len();
+        self.buf.extend_from_slice(direntplus.as_bytes());
+        self.buf.extend_from_slice(name);
+        self.buf.resize(lenbefore + aligned_entry_size, 0);
+
+        false
+    }
+
+    /// Apply the function to each appended entry along with its name.
+    ///
+    /// This is intended for the layers rewriting the replies, such as the
+    /// ones translating the ownership of the entries.
+    pub fn for_each_entry<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&OsStr, &mut EntryOut),
+    {
+        let header_len = mem::size_of::<fuse_direntplus>();
+        let mut pos = 0;
+        while pos < self.buf.len() {
+            let mut direntplus = fuse_direntplus::default();
+            direntplus
+                .as_bytes_mut()
+                .copy_from_slice(&self.buf[pos..pos + header_len]);
+            let name_end = pos + header_len + direntplus.dirent.namelen as usize;
+
+            let mut entry = EntryOut {
+                out: direntplus.entry_out,
+            };
+            f(
+                OsStr::from_bytes(&self.buf[pos + header_len..name_end]),
+                &mut entry,
+            );
+            direntplus.entry_out = entry.out;
+            direntplus.dirent.ino = entry.out.nodeid;
+            self.buf[pos..pos + header_len].copy_from_slice(direntplus.as_bytes());
+
+            pos += aligned(header_len + direntplus.dirent.namelen as usize);
+        }
+    }
+}
+
 #[inline]
 const fn aligned(len: usize) -> usize {
     (len + mem::size_of::<u64>() - 1) & !(mem::size_of::<u64>() - 1)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{collection::vec, prelude::*};
+    use zerocopy::{AsBytes, FromBytes};
+
+    const FUSE_DIRENT_ALIGN: usize = 8;
+
+    struct Collect(Vec<u8>);
+
+    impl<'a> FillBytes<'a> for Collect {
+        fn put(&mut self, chunk: &'a [u8]) {
+            self.0.extend_from_slice(chunk);
+        }
+    }
+
+    fn to_vec<T: Bytes>(bytes: &T) -> Vec<u8> {
+        let mut dst = Collect(Vec::with_capacity(bytes.size()));
+        bytes.fill_bytes(&mut dst);
+        assert_eq!(dst.0.len(), bytes.size());
+        dst.0
+    }
+
+    fn read<T: AsBytes + FromBytes + Default>(buf: &[u8]) -> T {
+        let mut value = T::default();
+        let len = value.as_bytes_mut().len();
+        value.as_bytes_mut().copy_from_slice(&buf[..len]);
+        value
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Dirent {
+        name: Vec<u8>,
+        ino: u64,
+        typ: u32,
+        off: u64,
+    }
+
+    fn dirent() -> impl Strategy<Value = Dirent> {
+        (
+            vec(
+                any::<u8>().prop_filter("NUL or slash", |&b| b != 0 && b != b'/'),
+                1..=255,
+            ),
+            any::<u64>(),
+            0u32..16,
+            any::<u64>(),
+        )
+            .prop_map(|(name, ino, typ, off)| Dirent {
+                name,
+                ino,
+                typ,
+                off,
+            })
+    }
+
+    /// Split the packed records in the same way as the kernel's
+    /// `parse_dirfile`, checking the record boundaries and the padding.
+    fn split_records(buf: &[u8], header_len: usize) -> Vec<(&[u8], &[u8])> {
+        let mut records = vec![];
+        let mut pos = 0;
+        while pos < buf.len() {
+            assert_eq!(pos % FUSE_DIRENT_ALIGN, 0, "misaligned record");
+            let dirent: fuse_dirent =
+                read(&buf[pos + header_len - mem::size_of::<fuse_dirent>()..]);
+            let name_end = pos + header_len + dirent.namelen as usize;
+            let reclen = aligned(header_len + dirent.namelen as usize);
+            assert!(pos + reclen <= buf.len(), "truncated record");
+            assert!(
+                buf[name_end..pos + reclen].iter().all(|&b| b == 0),
+                "non-zero padding"
+            );
+            records.push((
+                &buf[pos..pos + header_len],
+                &buf[pos + header_len..name_end],
+            ));
+            pos += reclen;
+        }
+        records
+    }
+
+    #[test]
+    fn dirent_layout() {
+        // The record layouts are fixed by the protocol and must not
+        // depend on the pointer width of the target.
+        assert_eq!(mem::size_of::<fuse_dirent>(), 24);
+        assert_eq!(mem::size_of::<fuse_entry_out>(), 128);
+        assert_eq!(mem::size_of::<fuse_direntplus>(), 152);
+        assert_eq!(aligned(1), FUSE_DIRENT_ALIGN);
+        assert_eq!(aligned(24 + 1), 32);
+        assert_eq!(aligned(152 + 8), 160);
+    }
+
+    #[test]
+    fn statfs_layout() {
+        let mut out = StatfsOut::default();
+        assert_eq!(out.statfs().get_namelen(), DEFAULT_NAMELEN);
+
+        let st = out.statfs();
+        st.bsize(4096);
+        st.frsize(512);
+        st.blocks(10);
+        st.namelen(1024);
+        assert_eq!(st.get_frsize(), 512);
+
+        let buf = to_vec(&out);
+        assert_eq!(buf.len(), mem::size_of::<fuse_statfs_out>());
+        let out: fuse_statfs_out = read(&buf);
+        assert_eq!(out.st.bsize, 4096);
+        assert_eq!(out.st.frsize, 512);
+        assert_eq!(out.st.blocks, 10);
+        assert_eq!(out.st.namelen, 1024);
+    }
+
+    #[test]
+    fn open_flags() {
+        let mut out = OpenOut::default();
+        out.fh(3);
+        out.direct_io(true);
+        out.parallel_direct_writes(true);
+        out.keep_cache(true);
+        out.keep_cache(false);
+
+        let out: fuse_open_out = read(&to_vec(&out));
+        assert_eq!(out.fh, 3);
+        assert_eq!(
+            out.open_flags,
+            FOPEN_DIRECT_IO | FOPEN_PARALLEL_DIRECT_WRITES
+        );
+    }
+
+    #[test]
+    fn ioctl_retry() {
+        let mut out = IoctlOut::default();
+        out.retry_in(0x1000, 8);
+        out.retry_out(0x2000, 16);
+        out.retry_out(0x3000, 4);
+
+        let buf = to_vec(&out);
+        assert_eq!(
+            buf.len(),
+            mem::size_of::<fuse_ioctl_out>() + 3 * mem::size_of::<fuse_ioctl_iovec>()
+        );
+        let header: fuse_ioctl_out = read(&buf);
+        assert_eq!(header.flags, FUSE_IOCTL_RETRY);
+        assert_eq!(header.in_iovs, 1);
+        assert_eq!(header.out_iovs, 2);
+
+        let iovs: Vec<(u64, u64)> = buf[mem::size_of::<fuse_ioctl_out>()..]
+            .chunks(mem::size_of::<fuse_ioctl_iovec>())
+            .map(|chunk| {
+                let iov: fuse_ioctl_iovec = read(chunk);
+                (iov.base, iov.len)
+            })
+            .collect();
+        assert_eq!(iovs, [(0x1000, 8), (0x2000, 16), (0x3000, 4)]);
+    }
+
+    proptest! {
+        #[test]
+        fn readdir_roundtrip(
+            entries in vec(dirent(), 0..32),
+            capacity in 0usize..8192,
+        ) {
+            let mut out = ReaddirOut::new(capacity);
+            let mut packed = 0;
+            for entry in &entries {
+                if out.entry(OsStr::from_bytes(&entry.name), entry.ino, entry.typ, entry.off) {
+                    break;
+                }
+                packed += 1;
+            }
+            let buf = to_vec(&out);
+            prop_assert!(buf.len() <= capacity);
+            prop_assert_eq!(buf.len() % FUSE_DIRENT_ALIGN, 0);
+
+            let decoded: Vec<Dirent> = split_records(&buf, mem::size_of::<fuse_dirent>())
+                .into_iter()
+                .map(|(header, name)| {
+                    let dirent: fuse_dirent = read(header);
+                    Dirent {
+                        name: name.to_vec(),
+                        ino: dirent.ino,
+                        typ: dirent.typ,
+                        off: dirent.off,
+                    }
+                })
+                .collect();
+            prop_assert_eq!(&decoded[..], &entries[..packed]);
+
+            // A rejected entry must really not fit.
+            if let Some(next) = entries.get(packed) {
+                let reclen = aligned(mem::size_of::<fuse_dirent>() + next.name.len());
+                prop_assert!(buf.len() + reclen > capacity);
+            }
+        }
+
+        #[test]
+        fn readdirplus_roundtrip(
+            entries in vec((dirent(), any::<u64>(), any::<u32>()), 0..16),
+            capacity in 0usize..8192,
+        ) {
+            let mut out = ReaddirPlusOut::new(capacity);
+            let mut packed = 0;
+            for (entry, generation, mode) in &entries {
+                let mut entry_out = EntryOut::default();
+                entry_out.ino(entry.ino);
+                entry_out.generation(*generation);
+                entry_out.attr().mode(*mode);
+                if out.entry(OsStr::from_bytes(&entry.name), entry.typ, entry.off, &entry_out) {
+                    break;
+                }
+                packed += 1;
+            }
+
+            // Rewriting the entries keeps the records intact.
+            let mut names = vec![];
+            out.for_each_entry(|name, entry_out| {
+                names.push(name.as_bytes().to_owned());
+                let mode = entry_out.attr().get_mode();
+                entry_out.attr().uid(mode);
+            });
+            prop_assert_eq!(names.len(), packed);
+
+            let buf = to_vec(&out);
+            prop_assert!(buf.len() <= capacity);
+            prop_assert_eq!(buf.len() % FUSE_DIRENT_ALIGN, 0);
+
+            let records = split_records(&buf, mem::size_of::<fuse_direntplus>());
+            prop_assert_eq!(records.len(), packed);
+            for ((header, name), (entry, generation, mode)) in records.into_iter().zip(&entries) {
+                let plus: fuse_direntplus = read(header);
+                prop_assert_eq!(name, &entry.name[..]);
+                prop_assert_eq!(plus.dirent.ino, entry.ino);
+                prop_assert_eq!(plus.entry_out.nodeid, entry.ino);
+                prop_assert_eq!(plus.entry_out.generation, *generation);
+                prop_assert_eq!(plus.entry_out.attr.mode, *mode);
+                prop_assert_eq!(plus.entry_out.attr.uid, *mode);
+                prop_assert_eq!(plus.dirent.typ, entry.typ);
+                prop_assert_eq!(plus.dirent.off, entry.off);
+            }
+
+            if let Some((next, _, _)) = entries.get(packed) {
+                let reclen = aligned(mem::size_of::<fuse_direntplus>() + next.name.len());
+                prop_assert!(buf.len() + reclen > capacity);
+            }
+        }
+    }
+}
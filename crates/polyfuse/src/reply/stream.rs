@@ -0,0 +1,191 @@
+use super::{EntryOut, ReaddirOut, ReaddirPlusOut};
+use futures_core::Stream;
+use std::{
+    ffi::OsString,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// A directory entry pulled from a stream by
+/// [`ReaddirOut::fill_from_stream`].
+#[derive(Debug, Clone)]
+pub struct Dirent {
+    /// The name of the entry.
+    pub name: OsString,
+    /// The inode number of the entry.
+    pub ino: u64,
+    /// The file type of the entry, as `DT_*`.
+    pub typ: u32,
+    /// The offset of the next entry.
+    pub off: u64,
+}
+
+/// A directory entry with its attributes pulled from a stream by
+/// [`ReaddirPlusOut::fill_from_stream`].
+#[derive(Debug, Clone)]
+pub struct DirentPlus {
+    /// The name of the entry.
+    pub name: OsString,
+    /// The file type of the entry, as `DT_*`.
+    pub typ: u32,
+    /// The offset of the next entry.
+    pub off: u64,
+    /// The inode number and the attributes of the entry.
+    pub entry: EntryOut,
+}
+
+impl ReaddirOut {
+    /// Append the entries pulled from the stream until the buffer is full.
+    ///
+    /// The directory listings backed by a paginated source, such as a
+    /// network API, can be produced lazily, since the stream is no longer
+    /// polled once an entry does not fit:
+    ///
+    /// ```ignore
+    /// let mut entries = Box::pin(backend.list(op.ino(), op.offset()));
+    /// let mut out = ReaddirOut::new(op.size() as usize);
+    /// out.fill_from_stream(&mut entries).await?;
+    /// req.reply(out)?;
+    /// ```
+    ///
+    /// The entry that did not fit is returned, so that a stream kept across
+    /// the requests can resume from it. Otherwise it can be discarded, since
+    /// the kernel requests it again with the offset of the preceding entry.
+    /// `None` is returned if the stream has ended. An error of the stream
+    /// stops the filling, leaving the entries appended so far in the buffer.
+    pub async fn fill_from_stream<S, E>(&mut self, stream: &mut S) -> Result<Option<Dirent>, E>
+    where
+        S: Stream<Item = Result<Dirent, E>> + Unpin,
+    {
+        while let Some(dirent) = Next(stream).await {
+            let dirent = dirent?;
+            if self.entry(&dirent.name, dirent.ino, dirent.typ, dirent.off) {
+                return Ok(Some(dirent));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl ReaddirPlusOut {
+    /// Append the entries pulled from the stream until the buffer is full.
+    ///
+    /// See [`ReaddirOut::fill_from_stream`] for details. The returned entry
+    /// has not been passed to the kernel, so its lookup count must not be
+    /// incremented.
+    pub async fn fill_from_stream<S, E>(&mut self, stream: &mut S) -> Result<Option<DirentPlus>, E>
+    where
+        S: Stream<Item = Result<DirentPlus, E>> + Unpin,
+    {
+        while let Some(dirent) = Next(stream).await {
+            let dirent = dirent?;
+            if self.entry(&dirent.name, dirent.typ, dirent.off, &dirent.entry) {
+                return Ok(Some(dirent));
+            }
+        }
+        Ok(None)
+    }
+}
+
+struct Next<'a, S>(&'a mut S);
+
+impl<S> Future for Next<'_, S>
+where
+    S: Stream + Unpin,
+{
+    type Output = Option<S::Item>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut *self.0).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytes::Bytes;
+    use std::{
+        io, ptr,
+        task::{RawWaker, RawWakerVTable, Waker},
+    };
+
+    /// A stream of the entries yielding `Pending` before each one, like a
+    /// paginated source, and counting the pulled entries.
+    struct Source {
+        entries: std::vec::IntoIter<Dirent>,
+        pending: bool,
+        pulled: usize,
+    }
+
+    impl Stream for Source {
+        type Item = io::Result<Dirent>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.pending = !self.pending;
+            if self.pending {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let next = self.entries.next();
+            self.pulled += next.is_some() as usize;
+            Poll::Ready(next.map(Ok))
+        }
+    }
+
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        fn raw_waker() -> RawWaker {
+            fn clone(_: *const ()) -> RawWaker {
+                raw_waker()
+            }
+            fn noop(_: *const ()) {}
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+            RawWaker::new(ptr::null(), &VTABLE)
+        }
+        let waker = unsafe { Waker::from_raw(raw_waker()) };
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = Box::pin(fut);
+        loop {
+            if let Poll::Ready(output) = fut.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    fn source(count: u64) -> Source {
+        let entries: Vec<_> = (0..count)
+            .map(|i| Dirent {
+                name: format!("entry{}", i).into(),
+                ino: i + 2,
+                typ: libc::DT_REG as u32,
+                off: i + 1,
+            })
+            .collect();
+        Source {
+            entries: entries.into_iter(),
+            pending: false,
+            pulled: 0,
+        }
+    }
+
+    #[test]
+    fn fill_until_full() {
+        // The size of each record is 24 + 6 = 30 bytes, aligned to 32.
+        let mut stream = source(10);
+        let mut out = ReaddirOut::new(100);
+        let rest = block_on(out.fill_from_stream(&mut stream)).unwrap();
+        assert_eq!(rest.map(|d| d.off), Some(4));
+        assert_eq!(stream.pulled, 4);
+        assert_eq!(out.size(), 96);
+
+        let mut out = ReaddirOut::new(100);
+        let rest = block_on(out.fill_from_stream(&mut stream)).unwrap();
+        assert_eq!(rest.map(|d| d.off), Some(8));
+        assert_eq!(stream.pulled, 8);
+
+        let mut out = ReaddirOut::new(4096);
+        let rest = block_on(out.fill_from_stream(&mut stream)).unwrap();
+        assert!(rest.is_none());
+        assert_eq!(out.size(), 64);
+    }
+}
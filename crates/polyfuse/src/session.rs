@@ -1,37 +1,69 @@
 use crate::{
     bytes::{Bytes, FillBytes},
+    caller::{Caller, ProcCache},
     conn::{Connection, MountOptions},
-    decoder::Decoder,
-    op::{DecodeError, Operation},
+    control::{ControlDir, CONTROL_DIR_INO},
+    decoder::{self, Decoder},
+    errno::{Errno, ToErrno},
+    error::Error,
+    health::{Health, WriteGuard, WriteWatch},
+    interrupt::{InterruptTable, Interruptible},
+    op::{self, DecodeError, Operation},
+    reply::{AttrOut, EntryOut, StatxOut},
+    util::TimeGran,
 };
 use polyfuse_kernel::*;
+#[cfg(feature = "latency-stats")]
+use std::time::Instant;
 use std::{
     cmp,
     convert::{TryFrom, TryInto as _},
     ffi::OsStr,
     fmt,
+    future::Future,
     io::{self, prelude::*, IoSlice, IoSliceMut},
     mem::{self, MaybeUninit},
     os::unix::prelude::*,
     path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicBool, AtomicU64, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
     },
+    time::Duration,
 };
 use zerocopy::AsBytes as _;
 
 // The minimum supported ABI minor version by polyfuse.
 const MINIMUM_SUPPORTED_MINOR_VERSION: u32 = 23;
 
+/// The lower limit of `max_read` applied by the kernel.
+const MIN_MAX_READ: u32 = 4096;
+
+fn is_valid_blksize(size: u32) -> bool {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    size.is_power_of_two()
+        && size >= crate::conn::DEFAULT_BLKSIZE
+        && i64::from(size) <= page_size as i64
+}
+
 const DEFAULT_MAX_WRITE: u32 = 16 * 1024 * 1024;
+// The kernel limits the size of a request to 256 pages.
+const PRESET_MAX_WRITE: u32 = 1024 * 1024;
+
+// Same as the default of `entry_timeout` and `attr_timeout` in libfuse.
+const DEFAULT_TTL: Duration = Duration::from_secs(1);
+const LONG_TTL: Duration = Duration::from_secs(60 * 60 * 24);
 const MIN_MAX_WRITE: u32 = FUSE_MIN_READ_BUFFER - BUFFER_HEADER_SIZE as u32;
 
 // copied from fuse_i.h
 const MAX_MAX_PAGES: usize = 256;
-//const DEFAULT_MAX_PAGES_PER_REQ: usize = 32;
+const FUSE_DEFAULT_MAX_BACKGROUND: u16 = 12;
+const DEFAULT_MAX_PAGES_PER_REQ: u32 = 32;
 const BUFFER_HEADER_SIZE: usize = 0x1000;
 
+// copied from <limits.h>
+const IOV_MAX: usize = 1024;
+
 // TODO: add FUSE_IOCTL_DIR
 const DEFAULT_INIT_FLAGS: u32 = FUSE_ASYNC_READ
     | FUSE_PARALLEL_DIROPS
@@ -55,6 +87,8 @@ const INIT_FLAGS_MASK: u32 = FUSE_ASYNC_READ
     | FUSE_DO_READDIRPLUS
     | FUSE_READDIRPLUS_AUTO;
 
+const INIT_FLAGS2_MASK: u32 = FUSE_SECURITY_CTX;
+
 // ==== KernelConfig ====
 
 /// Parameters for setting up the connection with FUSE driver
@@ -62,6 +96,14 @@ const INIT_FLAGS_MASK: u32 = FUSE_ASYNC_READ
 pub struct KernelConfig {
     mountopts: MountOptions,
     init_out: fuse_init_out,
+    ttl: DefaultTtl,
+    max_pages_limit: Option<u16>,
+    max_read: Option<u32>,
+    memory_budget: Option<usize>,
+    control_dir: bool,
+    allowed_callers: Option<Vec<u32>>,
+    write_timeout: Option<Duration>,
+    dump_limit: Option<usize>,
 }
 
 impl Default for KernelConfig {
@@ -69,11 +111,84 @@ impl Default for KernelConfig {
         Self {
             mountopts: MountOptions::default(),
             init_out: default_init_out(),
+            ttl: DefaultTtl {
+                entry: DEFAULT_TTL,
+                attr: DEFAULT_TTL,
+            },
+            max_pages_limit: None,
+            max_read: None,
+            memory_budget: None,
+            control_dir: false,
+            allowed_callers: None,
+            write_timeout: None,
+            dump_limit: None,
         }
     }
 }
 
+/// The validity timeouts of the replies created by `Request::entry_out`
+/// and `Request::attr_out`.
+#[derive(Clone, Copy, Debug)]
+struct DefaultTtl {
+    entry: Duration,
+    attr: Duration,
+}
+
 impl KernelConfig {
+    /// A preset for the filesystems whose contents may be modified without
+    /// going through the mount, such as network filesystems.
+    ///
+    /// Writeback caching is disabled so that the writes reach the filesystem
+    /// immediately, and the kernel drops the cached pages of a file when it
+    /// notices the change of its size or modification time. The entries and
+    /// attributes are cached only for a second by default.
+    pub fn network_fs() -> Self {
+        let mut config = Self::default();
+        config
+            .writeback_cache(false)
+            .auto_inval_data(true)
+            .max_write(PRESET_MAX_WRITE)
+            .entry_ttl(DEFAULT_TTL)
+            .attr_ttl(DEFAULT_TTL);
+        config
+    }
+
+    /// A preset for the filesystems whose contents are modified only through
+    /// the mount, such as in-memory filesystems.
+    ///
+    /// Writeback caching is enabled, and the cached pages are not
+    /// invalidated on the attribute changes because nothing else can make
+    /// them stale. For the same reason, the entries and attributes are
+    /// cached for a day by default. See the `writeback` example for the
+    /// requirements of writeback caching on the filesystem.
+    pub fn local_cache_fs() -> Self {
+        let mut config = Self::default();
+        config
+            .writeback_cache(true)
+            .auto_inval_data(false)
+            .max_write(PRESET_MAX_WRITE)
+            .entry_ttl(LONG_TTL)
+            .attr_ttl(LONG_TTL);
+        config
+    }
+
+    /// A preset for the filesystems that mirror a local directory, such as
+    /// the `passthrough` example.
+    ///
+    /// In addition to the settings of [`local_cache_fs`](Self::local_cache_fs),
+    /// the permission checks are delegated to the kernel with the
+    /// `default_permissions` mount option, and the `flock` locks and the
+    /// lookups of `"."` and `".."` are enabled as the backing filesystem
+    /// supports them.
+    pub fn passthrough() -> Self {
+        let mut config = Self::local_cache_fs();
+        config
+            .mount_option("default_permissions")
+            .export_support(true)
+            .flock_locks(true);
+        config
+    }
+
     #[doc(hidden)] // TODO: dox
     pub fn auto_unmount(&mut self, enabled: bool) -> &mut Self {
         self.mountopts.auto_unmount = enabled;
@@ -82,18 +197,33 @@ impl KernelConfig {
 
     #[doc(hidden)] // TODO: dox
     pub fn mount_option(&mut self, option: &str) -> &mut Self {
-        for option in option.split(',').map(|s| s.trim()) {
+        for option in crate::util::split_mount_options(option) {
             match option {
                 "auto_unmount" => {
                     self.auto_unmount(true);
                 }
-                option => self.mountopts.options.push(option.to_owned()),
+                option => match option.strip_prefix("max_read=").map(str::parse) {
+                    Some(Ok(value)) => {
+                        self.max_read(value);
+                    }
+                    _ => match option.strip_prefix("blksize=").map(str::parse) {
+                        Some(Ok(size)) if is_valid_blksize(size) => {
+                            self.blksize(size);
+                        }
+                        _ => self.mountopts.options.push(option.to_owned()),
+                    },
+                },
             }
         }
         self
     }
 
-    #[doc(hidden)] // TODO: dox
+    /// Specify the absolute path of the `fusermount` program used for
+    /// mounting and unmounting the filesystem.
+    ///
+    /// By default, the program is searched in `PATH` (see
+    /// [`fusermount3`](Self::fusermount3)), which may not contain the setuid
+    /// wrappers on NixOS or in the containers.
     pub fn fusermount_path(&mut self, program: impl AsRef<OsStr>) -> &mut Self {
         let program = Path::new(program.as_ref());
         assert!(
@@ -104,12 +234,81 @@ impl KernelConfig {
         self
     }
 
+    /// Specify whether to use `fusermount3` of libfuse 3 instead of
+    /// `fusermount` of libfuse 2.
+    ///
+    /// By default, `fusermount3` is used if found in `PATH`, and `fusermount`
+    /// otherwise. This is ignored if [`fusermount_path`](Self::fusermount_path)
+    /// is specified.
+    pub fn fusermount3(&mut self, enabled: bool) -> &mut Self {
+        self.mountopts.fusermount3 = Some(enabled);
+        self
+    }
+
+    /// Add an environment variable passed to `fusermount`, in addition to
+    /// those inherited from the current process.
+    pub fn fusermount_env(
+        &mut self,
+        key: impl AsRef<OsStr>,
+        value: impl AsRef<OsStr>,
+    ) -> &mut Self {
+        self.mountopts
+            .fusermount_env
+            .push((key.as_ref().to_owned(), value.as_ref().to_owned()));
+        self
+    }
+
+    /// Mount the filesystem on a block device, as the `fuseblk` type.
+    ///
+    /// The device is mounted directly with mount(2) rather than through
+    /// `fusermount`, which requires `CAP_SYS_ADMIN`. The device is not
+    /// opened by the session, and the filesystem is expected to open it by
+    /// itself. Since no `fusermount` process watches the daemon,
+    /// [`auto_unmount`](Self::auto_unmount) has no effect, and the
+    /// filesystem is detached with umount2(2) when the session is dropped.
+    pub fn blkdev(&mut self, device: impl Into<PathBuf>) -> &mut Self {
+        self.mountopts.blkdev = Some(device.into());
+        self
+    }
+
+    /// Set the block size of the `fuseblk` mount, which is reported as
+    /// the block size of the filesystem and used in the `BMAP` requests.
+    ///
+    /// The size must be a power of two between 512 and the page size.
+    /// The kernel uses 512 by default, and rejects the option unless mounted
+    /// on a block device.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the size is not valid.
+    pub fn blksize(&mut self, size: u32) -> &mut Self {
+        assert!(
+            is_valid_blksize(size),
+            "the block size must be a power of two between 512 and the page size"
+        );
+        self.mountopts.blksize = Some(size);
+        self
+    }
+
     #[doc(hidden)] // TODO: dox
     pub fn fuse_comm_fd(&mut self, name: impl AsRef<OsStr>) -> &mut Self {
         self.mountopts.fuse_comm_fd = Some(name.as_ref().to_owned());
         self
     }
 
+    /// Return the mount options passed to `fusermount`.
+    pub(crate) fn mount_options(&self) -> &[String] {
+        &self.mountopts.options
+    }
+
+    /// Return `max_background` and `congestion_threshold`.
+    pub(crate) fn background_limits(&self) -> (u16, u16) {
+        (
+            self.init_out.max_background,
+            self.init_out.congestion_threshold,
+        )
+    }
+
     #[inline]
     fn set_init_flag(&mut self, flag: u32, enabled: bool) {
         if enabled {
@@ -205,6 +404,22 @@ impl KernelConfig {
         self
     }
 
+    /// Specify that the kernel should send the security context of the new
+    /// inodes along with the requests creating them.
+    ///
+    /// The context is available with `security_context` of `Mknod`, `Mkdir`,
+    /// `Symlink` and `Create`, so that the filesystem can label the inode
+    /// atomically with its creation. This requires the protocol 7.36 or
+    /// later, and is ignored by the older kernels.
+    pub fn security_context(&mut self, enabled: bool) -> &mut Self {
+        if enabled {
+            self.init_out.flags2 |= FUSE_SECURITY_CTX;
+        } else {
+            self.init_out.flags2 &= !FUSE_SECURITY_CTX;
+        }
+        self
+    }
+
     /// Specify that the filesystem supports `readdirplus` operations.
     pub fn readdirplus(&mut self, enabled: bool) -> &mut Self {
         self.set_init_flag(FUSE_DO_READDIRPLUS, enabled);
@@ -225,8 +440,36 @@ impl KernelConfig {
         self
     }
 
+    /// Set the maximum size of the data in a read request.
+    ///
+    /// The value is passed to the kernel as the `max_read` mount option, and
+    /// the readahead is limited to it as well. The values below 4096 are
+    /// raised to it as the kernel does. The larger read requests, which are
+    /// sent only if the mount option is not in effect, e.g. on the
+    /// connections passed to [`Session::from_raw_fd`], are rejected with
+    /// `EINVAL` by [`Session::next_request`]. They are not shortened, since
+    /// the kernel would take the short reply as the end of the file.
+    ///
+    /// By default, the size of read requests is limited only by the number
+    /// of pages per request.
+    pub fn max_read(&mut self, value: u32) -> &mut Self {
+        let value = cmp::max(value, MIN_MAX_READ);
+        self.max_read = Some(value);
+        self.mountopts
+            .options
+            .retain(|option| !option.starts_with("max_read="));
+        self.mountopts.options.push(format!("max_read={}", value));
+        self
+    }
+
     /// Set the maximum size of the write buffer.
     ///
+    /// The value is lowered at the initialization to fit in the number of
+    /// pages per request that the kernel allows (see `max_pages_limit`).
+    /// The buffer of this size is allocated for each request, so a larger
+    /// value improves the throughput of large sequential writes at the cost
+    /// of memory.
+    ///
     /// # Panic
     /// It causes an assertion panic if the setting value is less than the absolute minimum.
     pub fn max_write(&mut self, value: u32) -> &mut Self {
@@ -239,7 +482,21 @@ impl KernelConfig {
         self
     }
 
-    /// Return the maximum number of pending *background* requests.
+    /// Set the maximum number of pages per request that the kernel allows.
+    ///
+    /// By default, the value is read from `/proc/sys/fs/fuse/max_pages_limit`,
+    /// which is available since Linux 6.13 to raise the limit from 256 pages.
+    /// The kernels without the `FUSE_MAX_PAGES` capability always limit
+    /// a request to 32 pages, regardless of this value.
+    pub fn max_pages_limit(&mut self, limit: u16) -> &mut Self {
+        self.max_pages_limit = Some(limit);
+        self
+    }
+
+    /// Set the maximum number of pending *background* requests.
+    ///
+    /// If the setting value is 0, the default value of the kernel is used.
+    /// The kernel may lower the value further for unprivileged mounts.
     pub fn max_background(&mut self, max_background: u16) -> &mut Self {
         self.init_out.max_background = max_background;
         self
@@ -248,19 +505,10 @@ impl KernelConfig {
     /// Set the threshold number of pending background requests that the kernel marks
     /// the filesystem as *congested*.
     ///
-    /// If the setting value is 0, the value is automatically calculated by using max_background.
-    ///
-    /// # Panics
-    /// It cause a panic if the setting value is greater than `max_background`.
-    pub fn congestion_threshold(&mut self, mut threshold: u16) -> &mut Self {
-        assert!(
-            threshold <= self.init_out.max_background,
-            "The congestion_threshold must be less or equal to max_background"
-        );
-        if threshold == 0 {
-            threshold = self.init_out.max_background * 3 / 4;
-            tracing::debug!(congestion_threshold = threshold);
-        }
+    /// If the setting value is 0, the value is calculated from `max_background`
+    /// at the initialization.  A value greater than `max_background` is
+    /// clamped to it.
+    pub fn congestion_threshold(&mut self, threshold: u16) -> &mut Self {
         self.init_out.congestion_threshold = threshold;
         self
     }
@@ -268,12 +516,139 @@ impl KernelConfig {
     /// Set the timestamp resolution supported by the filesystem.
     ///
     /// The setting value has the nanosecond unit and should be a power of 10.
+    /// The timestamps can be truncated accordingly with
+    /// [`TimeGran`](crate::util::TimeGran).
     ///
     /// The default value is 1.
     pub fn time_gran(&mut self, time_gran: u32) -> &mut Self {
         self.init_out.time_gran = time_gran;
         self
     }
+
+    /// Set the default validity timeout for the directory entries.
+    ///
+    /// This value is used by the replies created with [`Request::entry_out`],
+    /// unless the handler overrides it. The default value is one second.
+    pub fn entry_ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.ttl.entry = ttl;
+        self
+    }
+
+    /// Set the default validity timeout for the inode attributes.
+    ///
+    /// This value is used by the replies created with [`Request::entry_out`]
+    /// and [`Request::attr_out`], unless the handler overrides it.
+    /// The default value is one second.
+    pub fn attr_ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.ttl.attr = ttl;
+        self
+    }
+
+    /// Set the upper limit of the memory used by the buffers of the
+    /// in-flight requests, in bytes.
+    ///
+    /// Each request occupies a buffer of about `max_write` bytes until the
+    /// request, or the [`ReplySender`] split from it with
+    /// [`Request::into_parts`], is dropped. When the budget is exhausted,
+    /// [`Session::next_request`] waits for them to be dropped before reading
+    /// the next request, rather than allocating a new buffer. At least one
+    /// request is always received even if the budget is smaller than a
+    /// buffer.
+    ///
+    /// Note that the forget and interrupt requests are subject to the budget
+    /// as well, so the handlers must not wait for them while holding requests.
+    /// By default, the memory usage is not limited.
+    pub fn memory_budget(&mut self, bytes: usize) -> &mut Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Specify whether to serve the control directory `.polyfuse` in the
+    /// root directory, exposing the runtime state of the session.
+    ///
+    /// The directory contains the following files:
+    ///
+    /// * `stats`: the uptime, the numbers of the requests and the replies,
+    ///   and the latency histograms if the `latency-stats` feature is enabled,
+    /// * `inflight`: the numbers of the requests waiting for the replies,
+    ///   per opcode,
+    /// * `log_level`: the log level read by [`Session::log_level`], which can
+    ///   be changed by writing a level such as `debug` to the file. Only the
+    ///   owner of the daemon and root are allowed to write it.
+    ///
+    /// The requests on the control directory are served by
+    /// [`Session::next_request`] and never reach the filesystem, except the
+    /// forgets in `FUSE_BATCH_FORGET` requests, which should be ignored. The
+    /// directory is not listed in the root directory. The inode numbers
+    /// above `0xFFFF_FFFF_FFFF_FF00` are reserved for the control directory
+    /// and must not be used by the filesystem.
+    ///
+    /// The requests created by [`Session::request_from_bytes`] are not
+    /// intercepted. By default, the control directory is disabled.
+    pub fn control_dir(&mut self, enabled: bool) -> &mut Self {
+        self.control_dir = enabled;
+        self
+    }
+
+    /// Specify whether to reject the requests from the users other than the
+    /// owner of the daemon.
+    ///
+    /// This is intended for the daemons that must be mounted with
+    /// `allow_other`, e.g. to be accessed by a service running as another
+    /// user, but trust only the specific callers. The requests from the
+    /// other users are replied with `EACCES` by [`Session::next_request`]
+    /// and never reach the filesystem.
+    ///
+    /// The requests from root are always allowed, since the kernel sends
+    /// some requests, such as the writeback of the cached pages, with its
+    /// credentials. The requests without a reply, `Release` and
+    /// `Releasedir` are passed regardless of the caller, so that the state
+    /// of the filesystem stays consistent with the kernel. The requests
+    /// created by [`Session::request_from_bytes`] are not checked. By
+    /// default, the callers are not restricted.
+    pub fn restrict_callers(&mut self, enabled: bool) -> &mut Self {
+        if !enabled {
+            self.allowed_callers = None;
+        } else if self.allowed_callers.is_none() {
+            self.allowed_callers = Some(vec![]);
+        }
+        self
+    }
+
+    /// Allow the requests from the specified user in addition to the owner
+    /// of the daemon.
+    ///
+    /// This implies [`restrict_callers(true)`](Self::restrict_callers).
+    pub fn allow_caller(&mut self, uid: u32) -> &mut Self {
+        self.allowed_callers.get_or_insert_with(Vec::new).push(uid);
+        self
+    }
+
+    /// Set the time after which a write to the FUSE device is considered
+    /// to be blocked by a stuck kernel.
+    ///
+    /// The replies and notifications are written synchronously, so a
+    /// connection stuck in the kernel hangs the threads sending them. With
+    /// the timeout, the writes in progress are tracked, a blocked write is
+    /// logged by a watchdog thread, and [`Session::health`] reports it so
+    /// that a supervisor can [`abort`](Session::abort) the connection. By
+    /// default, the writes are not tracked.
+    pub fn write_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Log the hexdump of the requests that cannot be decoded by
+    /// [`Request::operation`], up to `limit` bytes of each.
+    ///
+    /// The dump includes the header and is logged at the `WARN` level,
+    /// which helps debugging the mismatches of the ABI with unusual kernels.
+    /// Note that the payload may contain the file names and the data
+    /// written by the users. By default, only the decode error is reported.
+    pub fn dump_undecodable(&mut self, limit: usize) -> &mut Self {
+        self.dump_limit = Some(limit);
+        self
+    }
 }
 
 // ==== Session ====
@@ -289,10 +664,26 @@ impl fmt::Debug for Session {
     }
 }
 
+/// The callback of [`Session::next_request_or_forget`].
+type ForgetFn<'a> = dyn FnMut(&[op::Forget]) + 'a;
+
 struct SessionInner {
     conn: Connection,
     init_out: fuse_init_out,
+    info: ConnectionInfo,
+    ttl: DefaultTtl,
     bufsize: usize,
+    max_read: Option<u32>,
+    budget: Option<MemoryBudget>,
+    control: Option<ControlDir>,
+    allowed_callers: Option<Vec<u32>>,
+    procs: ProcCache,
+    writes: Option<Arc<WriteWatch>>,
+    spare: Mutex<Option<Vec<u8>>>,
+    interrupts: InterruptTable,
+    dump_limit: Option<usize>,
+    #[cfg(feature = "latency-stats")]
+    latency: crate::stats::LatencyRecorder,
     exited: AtomicBool,
     notify_unique: AtomicU64,
 }
@@ -309,6 +700,108 @@ impl SessionInner {
         // FIXME: choose appropriate atomic ordering.
         self.exited.store(true, Ordering::SeqCst)
     }
+
+    /// Track a write to the device if `KernelConfig::write_timeout` is set.
+    #[inline]
+    fn watch_write(&self) -> Option<WriteGuard<'_>> {
+        self.writes.as_ref().map(|writes| writes.start())
+    }
+
+    fn unmount(&self) -> io::Result<()> {
+        self.conn.lazy_unmount()
+    }
+
+    /// Return whether the request passes `KernelConfig::restrict_callers`.
+    fn is_allowed(&self, header: &fuse_in_header) -> bool {
+        let allowed = match self.allowed_callers {
+            Some(ref allowed) => allowed,
+            None => return true,
+        };
+        match fuse_opcode::try_from(header.opcode) {
+            Ok(fuse_opcode::FUSE_FORGET)
+            | Ok(fuse_opcode::FUSE_BATCH_FORGET)
+            | Ok(fuse_opcode::FUSE_INTERRUPT)
+            | Ok(fuse_opcode::FUSE_NOTIFY_REPLY)
+            | Ok(fuse_opcode::FUSE_RELEASE)
+            | Ok(fuse_opcode::FUSE_RELEASEDIR)
+            | Ok(fuse_opcode::FUSE_DESTROY) => true,
+            _ => allowed.contains(&header.uid),
+        }
+    }
+
+    /// Record the target of an `INTERRUPT` request for `Request::interruptible`.
+    ///
+    /// The request itself is still passed to the caller.
+    fn record_interrupt(&self, header: &fuse_in_header, arg: &[u8]) {
+        let len = mem::size_of::<fuse_interrupt_in>();
+        if header.opcode == fuse_opcode::FUSE_INTERRUPT as u32 && arg.len() >= len {
+            let mut interrupt_in = fuse_interrupt_in::default();
+            interrupt_in.as_bytes_mut().copy_from_slice(&arg[..len]);
+            self.interrupts.interrupt(interrupt_in.unique);
+        }
+    }
+
+    /// Return whether the read request exceeds `KernelConfig::max_read`.
+    fn is_oversized_read(&self, header: &fuse_in_header, arg: &[u8]) -> bool {
+        let max_read = match self.max_read {
+            Some(max_read) => max_read,
+            None => return false,
+        };
+        let len = mem::size_of::<fuse_read_in>();
+        if header.opcode != fuse_opcode::FUSE_READ as u32 || arg.len() < len {
+            return false;
+        }
+
+        let mut read_in = fuse_read_in::default();
+        read_in.as_bytes_mut().copy_from_slice(&arg[..len]);
+        read_in.size > max_read
+    }
+
+    /// Take the buffer left by a request handled in place, or allocate a new one.
+    fn take_buffer(&self) -> Vec<u8> {
+        let len = self.bufsize - mem::size_of::<fuse_in_header>();
+        match self.spare.lock().unwrap().take() {
+            Some(mut buf) => {
+                // Safety: the buffer has been allocated with `len` zeroed bytes
+                // and only shrunk since then, so its contents are initialized.
+                unsafe { buf.set_len(len) };
+                buf
+            }
+            None => vec![0u8; len],
+        }
+    }
+
+    /// Pass a forget request to `forget` in place, and keep its buffer for
+    /// the next read.
+    ///
+    /// The buffer is returned back if the message is not a forget request.
+    fn forget_in_place(
+        &self,
+        header: &fuse_in_header,
+        arg: Vec<u8>,
+        forget: &mut ForgetFn<'_>,
+    ) -> Option<Vec<u8>> {
+        match fuse_opcode::try_from(header.opcode) {
+            Ok(fuse_opcode::FUSE_FORGET) | Ok(fuse_opcode::FUSE_BATCH_FORGET) => (),
+            _ => return Some(arg),
+        }
+
+        // The inodes of the control directory are never looked up by the filesystem.
+        let on_control = self.control.is_some()
+            && header.opcode == fuse_opcode::FUSE_FORGET as u32
+            && header.nodeid >= CONTROL_DIR_INO;
+        if !on_control {
+            match decode_request(header, &arg) {
+                Ok(Operation::Forget(forgets)) => forget(&forgets),
+                Ok(..) => unreachable!(),
+                // There is no way to report the error to the kernel.
+                Err(err) => tracing::warn!("ignoring a malformed request: {}", err),
+            }
+        }
+
+        *self.spare.lock().unwrap() = Some(arg);
+        None
+    }
 }
 
 impl Drop for Session {
@@ -317,6 +810,51 @@ impl Drop for Session {
     }
 }
 
+/// The accounting of the memory used by the buffers of in-flight requests.
+struct MemoryBudget {
+    limit: usize,
+    used: Mutex<usize>,
+    released: Condvar,
+}
+
+impl MemoryBudget {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            used: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self, size: usize) {
+        let mut used = self.used.lock().unwrap();
+        while *used > 0 && *used + size > self.limit {
+            used = self.released.wait(used).unwrap();
+        }
+        *used += size;
+    }
+
+    fn release(&self, size: usize) {
+        let mut used = self.used.lock().unwrap();
+        *used -= size;
+        self.released.notify_one();
+    }
+}
+
+/// A buffer charged to the memory budget, which is returned on drop.
+struct BufferPermit {
+    session: Arc<SessionInner>,
+    size: usize,
+}
+
+impl Drop for BufferPermit {
+    fn drop(&mut self) {
+        if let Some(ref budget) = self.session.budget {
+            budget.release(self.size);
+        }
+    }
+}
+
 impl AsRawFd for Session {
     fn as_raw_fd(&self) -> RawFd {
         self.inner.conn.as_raw_fd()
@@ -325,22 +863,80 @@ impl AsRawFd for Session {
 
 impl Session {
     /// Start a FUSE daemon mount on the specified path.
-    pub fn mount(mountpoint: PathBuf, config: KernelConfig) -> io::Result<Self> {
+    pub fn mount(mountpoint: PathBuf, mut config: KernelConfig) -> Result<Self, Error> {
+        let mountopts = mem::take(&mut config.mountopts);
+        let conn = Connection::open(mountpoint, mountopts)?;
+        Self::start(conn, config)
+    }
+
+    /// Start a FUSE session on an already opened connection.
+    ///
+    /// The `INIT` handshake is performed on the specified file descriptor,
+    /// so the peer (the kernel, or a mock of it in tests) must send the
+    /// `FUSE_INIT` request first. The mount options in `config` are ignored
+    /// and the filesystem is not unmounted when the session is dropped.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be a valid file descriptor that speaks the FUSE protocol,
+    /// and its ownership is transferred to the session.
+    pub unsafe fn from_raw_fd(fd: RawFd, config: KernelConfig) -> Result<Self, Error> {
+        let conn = Connection::from_raw_fd(fd);
+        Self::start(conn, config)
+    }
+
+    fn start(conn: Connection, config: KernelConfig) -> Result<Self, Error> {
         let KernelConfig {
-            mountopts,
             mut init_out,
+            ttl,
+            max_pages_limit,
+            max_read,
+            memory_budget,
+            control_dir,
+            allowed_callers,
+            write_timeout,
+            dump_limit,
+            ..
         } = config;
 
-        let conn = Connection::open(mountpoint, mountopts)?;
-
-        init_session(&mut init_out, &conn, &conn)?;
+        let max_pages_limit = max_pages_limit.unwrap_or_else(kernel_max_pages_limit);
+        if let Some(max_read) = max_read {
+            init_out.max_readahead = cmp::min(init_out.max_readahead, max_read);
+        }
+        let info = init_session(&mut init_out, max_pages_limit, &conn, &conn)?;
         let bufsize = BUFFER_HEADER_SIZE + init_out.max_write as usize;
 
+        let writes = write_timeout.map(|timeout| Arc::new(WriteWatch::new(timeout)));
+        if let Some(ref writes) = writes {
+            WriteWatch::spawn_watchdog(writes)?;
+        }
+
         Ok(Self {
             inner: Arc::new(SessionInner {
                 conn,
                 init_out,
+                info,
+                ttl,
                 bufsize,
+                max_read,
+                budget: memory_budget.map(MemoryBudget::new),
+                control: if control_dir {
+                    Some(ControlDir::new())
+                } else {
+                    None
+                },
+                procs: ProcCache::new(),
+                writes,
+                spare: Mutex::new(None),
+                interrupts: InterruptTable::default(),
+                dump_limit,
+                allowed_callers: allowed_callers.map(|mut uids| {
+                    uids.push(unsafe { libc::getuid() });
+                    uids.push(0);
+                    uids
+                }),
+                #[cfg(feature = "latency-stats")]
+                latency: crate::stats::LatencyRecorder::new(),
                 exited: AtomicBool::new(false),
                 notify_unique: AtomicU64::new(0),
             }),
@@ -364,52 +960,319 @@ impl Session {
         self.inner.init_out.flags & FUSE_NO_OPENDIR_SUPPORT != 0
     }
 
+    /// Return the maximum size of the data in a write request negotiated
+    /// with the kernel.
+    pub fn max_write(&self) -> u32 {
+        self.inner.init_out.max_write
+    }
+
+    /// Return the maximum size of the data in a read request, if limited by
+    /// [`KernelConfig::max_read`].
+    pub fn max_read(&self) -> Option<u32> {
+        self.inner.max_read
+    }
+
+    /// Return the block size of the filesystem mounted on a block device
+    /// with [`KernelConfig::blkdev`], or `None` if not mounted on a block
+    /// device.
+    pub fn blksize(&self) -> Option<u32> {
+        self.inner.conn.blksize()
+    }
+
+    /// Return the parameters exchanged with the kernel in the `INIT`
+    /// handshake.
+    pub fn connection_info(&self) -> &ConnectionInfo {
+        &self.inner.info
+    }
+
+    /// Return the maximum number of pending background requests negotiated
+    /// with the kernel.
+    pub fn max_background(&self) -> u16 {
+        self.inner.init_out.max_background
+    }
+
+    /// Return the number of pending background requests at which the kernel
+    /// marks the filesystem as congested.
+    ///
+    /// The returned value never exceeds `max_background`.
+    pub fn congestion_threshold(&self) -> u16 {
+        self.inner.init_out.congestion_threshold
+    }
+
+    /// Return the granularity of the timestamps negotiated with the kernel.
+    pub fn time_gran(&self) -> TimeGran {
+        TimeGran::new(self.inner.init_out.time_gran)
+    }
+
+    /// Return whether the filesystem is mounted with `default_permissions`,
+    /// i.e. the kernel checks the access permissions by itself.
+    ///
+    /// The session created by [`Session::from_raw_fd`] does not know the
+    /// mount options, and `false` is returned.
+    pub fn default_permissions(&self) -> bool {
+        self.inner
+            .conn
+            .mount_options()
+            .iter()
+            .any(|option| option == "default_permissions")
+    }
+
+    /// Return the log level set through the control directory.
+    ///
+    /// The level is initialized with the maximum level of the current
+    /// `tracing` subscriber, and `None` is returned unless
+    /// [`KernelConfig::control_dir`] is enabled. Applying the level, e.g. by
+    /// reloading the filter of the subscriber, is up to the application.
+    pub fn log_level(&self) -> Option<tracing::level_filters::LevelFilter> {
+        self.inner.control.as_ref().map(ControlDir::log_level)
+    }
+
+    /// Return whether the writes to the FUSE device are progressing.
+    ///
+    /// [`Health::Wedged`] is returned while a write has been blocked longer
+    /// than [`KernelConfig::write_timeout`]. Without the timeout, the
+    /// session is always reported as healthy.
+    pub fn health(&self) -> Health {
+        match self.inner.writes {
+            Some(ref writes) => writes.health(),
+            None => Health::Healthy,
+        }
+    }
+
+    /// Abort the connection, failing all the pending and future requests.
+    ///
+    /// This is the last resort for a wedged session, and unblocks the
+    /// threads stuck in the reads and writes of the device. The connection
+    /// is aborted through the FUSE control filesystem, which must be mounted
+    /// at `/sys/fs/fuse/connections`, and the mountpoint must be known, i.e.
+    /// the session must be created by [`Session::mount`]. Root privileges
+    /// are required unless the control filesystem is mounted otherwise.
+    pub fn abort(&self) -> io::Result<()> {
+        self.inner.conn.abort()
+    }
+
+    /// Detach the filesystem from the mountpoint, to shut down the session
+    /// gracefully.
+    ///
+    /// The filesystem is unmounted lazily with `fusermount -u -z`, so the
+    /// files still open stay accessible and their requests keep arriving.
+    /// Once the kernel releases the mount, [`next_request`](Self::next_request)
+    /// returns `None`. The session must be created by [`Session::mount`].
+    ///
+    /// See also [`ShutdownSignals`](crate::util::ShutdownSignals) for doing
+    /// this on the termination signals.
+    pub fn unmount(&self) -> io::Result<()> {
+        self.inner.unmount()
+    }
+
+    /// Return a function unmounting the session without keeping it alive.
+    pub(crate) fn unmounter(&self) -> impl FnOnce() -> io::Result<()> + Send + 'static {
+        let inner = Arc::downgrade(&self.inner);
+        move || match inner.upgrade() {
+            Some(inner) => inner.unmount(),
+            None => Ok(()),
+        }
+    }
+
     /// Receive an incoming FUSE request from the kernel.
-    pub fn next_request(&self) -> io::Result<Option<Request>> {
-        let mut conn = &self.inner.conn;
+    ///
+    /// This method returns `Ok(None)` once the filesystem has been unmounted.
+    /// The reads interrupted by signals are retried transparently.
+    ///
+    /// If the connection has been switched to the non-blocking mode, an I/O
+    /// error with the kind `WouldBlock` is returned while no request is
+    /// available (see [`Error::is_would_block`]). The asynchronous runtimes
+    /// are expected to wait for the readiness of the file descriptor
+    /// (obtained via `AsRawFd`) and call it again.
+    ///
+    /// A message whose length does not match its header is reported as
+    /// [`Error::Protocol`].
+    ///
+    /// If [`KernelConfig::memory_budget`] is set, this method blocks until
+    /// the buffer of the next request fits in the budget.
+    ///
+    /// If [`KernelConfig::control_dir`] is enabled, the requests on the
+    /// control directory are served here and the next one is read instead.
+    /// The same goes for the requests rejected by
+    /// [`KernelConfig::restrict_callers`].
+    pub fn next_request(&self) -> Result<Option<Request>, Error> {
+        self.next_request_inner(None)
+    }
 
-        // FIXME: Align the allocated region in `arg` with the FUSE argument types.
-        let mut header = fuse_in_header::default();
-        let mut arg = vec![0u8; self.inner.bufsize - mem::size_of::<fuse_in_header>()];
+    /// Receive an incoming FUSE request from the kernel, passing the
+    /// `FUSE_FORGET` and `FUSE_BATCH_FORGET` requests to `forget` in place.
+    ///
+    /// The opcode of each message is inspected before a `Request` is created,
+    /// and the forget requests are decoded from a buffer reused across the
+    /// reads, so the storms of forgets, e.g. by `drop_caches` or unmounting,
+    /// do not allocate a buffer per request. `forget` is called on the
+    /// calling thread and the next request is read after it returns.
+    ///
+    /// Otherwise, this method behaves as [`next_request`](Self::next_request),
+    /// which is called by [`Session::run`] in this way.
+    pub fn next_request_or_forget<F>(&self, mut forget: F) -> Result<Option<Request>, Error>
+    where
+        F: FnMut(&[op::Forget]),
+    {
+        self.next_request_inner(Some(&mut forget))
+    }
 
+    fn next_request_inner(
+        &self,
+        mut forget: Option<&mut ForgetFn<'_>>,
+    ) -> Result<Option<Request>, Error> {
         loop {
-            match conn.read_vectored(&mut [
-                io::IoSliceMut::new(header.as_bytes_mut()),
-                io::IoSliceMut::new(&mut arg[..]),
-            ]) {
-                Ok(len) => {
-                    if len < mem::size_of::<fuse_in_header>() {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "dequeued request message is too short",
-                        ));
-                    }
-                    unsafe {
-                        arg.set_len(len - mem::size_of::<fuse_in_header>());
-                    }
-
-                    break;
+            let permit = self.inner.budget.as_ref().map(|budget| {
+                budget.acquire(self.inner.bufsize);
+                BufferPermit {
+                    session: self.inner.clone(),
+                    size: self.inner.bufsize,
                 }
+            });
 
-                Err(err) => match err.raw_os_error() {
-                    Some(libc::ENODEV) => {
-                        tracing::debug!("ENODEV");
-                        return Ok(None);
-                    }
-                    Some(libc::ENOENT) => {
-                        tracing::debug!("ENOENT");
-                        continue;
-                    }
-                    _ => return Err(err),
+            let buf = self.inner.take_buffer();
+            let (header, arg) = match read_request_into(&self.inner.conn, buf)? {
+                Some(msg) => msg,
+                None => return Ok(None),
+            };
+
+            let arg = match forget {
+                Some(ref mut forget) => match self.inner.forget_in_place(&header, arg, *forget) {
+                    Some(arg) => arg,
+                    None => continue,
                 },
+                None => arg,
+            };
+            self.inner.record_interrupt(&header, &arg);
+
+            let req = Request {
+                session: self.inner.clone(),
+                timing: Timing::start(&self.inner, &header),
+                header,
+                arg,
+                offset: 0,
+                permit,
+                reply_state: Arc::default(),
+            };
+
+            if !self.inner.is_allowed(&req.header) {
+                tracing::debug!(
+                    unique = req.unique(),
+                    uid = req.uid(),
+                    "rejecting a request from an untrusted user"
+                );
+                req.reply_error(libc::EACCES)?;
+                continue;
+            }
+
+            if self.inner.is_oversized_read(&req.header, req.payload()) {
+                tracing::warn!(
+                    unique = req.unique(),
+                    max_read = ?self.inner.max_read,
+                    "rejecting a read request larger than max_read"
+                );
+                req.reply_error(libc::EINVAL)?;
+                continue;
+            }
+
+            if let Some(ref control) = self.inner.control {
+                if control.serve(&req, &|content| self.render_stats(content))? {
+                    continue;
+                }
             }
+            return Ok(Some(req));
+        }
+    }
+
+    /// Append the latency histograms to the `stats` file of the control directory.
+    #[allow(unused_variables)]
+    fn render_stats(&self, content: &mut dyn fmt::Write) {
+        #[cfg(feature = "latency-stats")]
+        for (opcode, hist) in self.stats().latencies() {
+            let _ = writeln!(
+                content,
+                "latency opcode {}: count={} mean={:?} p50={:?} p99={:?}",
+                opcode,
+                hist.count(),
+                hist.mean(),
+                hist.quantile(0.5),
+                hist.quantile(0.99),
+            );
+        }
+    }
+
+    /// Return the size of the buffer required to receive a request message.
+    ///
+    /// The buffers passed to the kernel by [`request_from_bytes`] callers
+    /// should be at least this size, otherwise the reads of large `Write`
+    /// requests fail with `EINVAL`.
+    ///
+    /// [`request_from_bytes`]: Self::request_from_bytes
+    pub fn request_buffer_size(&self) -> usize {
+        self.inner.bufsize
+    }
+
+    /// Return a snapshot of the statistics of the requests processed so far.
+    ///
+    /// The latencies are recorded per opcode when the replies are sent, so
+    /// the requests without a reply, such as `Forget`, are not included.
+    #[cfg(feature = "latency-stats")]
+    pub fn stats(&self) -> crate::stats::Stats {
+        self.inner.latency.snapshot()
+    }
+
+    /// Create a request from a message read from the FUSE device by the caller.
+    ///
+    /// This is the counterpart of [`next_request`](Self::next_request) for
+    /// the completion-based runtimes, such as the thread-per-core executors
+    /// built on io_uring, which submit the reads themselves and hand over
+    /// the ownership of the filled buffers. `msg` must contain exactly one
+    /// message, including `fuse_in_header`; the buffer is kept as is
+    /// without copying the payload.
+    ///
+    /// ```ignore
+    /// let fd = session.as_raw_fd();
+    /// loop {
+    ///     let buf = Vec::with_capacity(session.request_buffer_size());
+    ///     let (res, buf) = read_fd(fd, buf).await;
+    ///     match res {
+    ///         Ok(0) => break,
+    ///         Ok(..) => (),
+    ///         Err(err) if err.raw_os_error() == Some(libc::ENOENT) => continue,
+    ///         Err(err) if err.raw_os_error() == Some(libc::ENODEV) => break,
+    ///         Err(err) => return Err(err.into()),
+    ///     }
+    ///     let req = session.request_from_bytes(buf)?;
+    ///     spawn_local(fs.handle(req));
+    /// }
+    /// ```
+    ///
+    /// Neither `Request` nor the replies require the tasks to be `Send`, so
+    /// the requests can be handled on the thread that has read them. Note
+    /// that the replies are written synchronously (see [`Request::reply`]). The buffer is not charged to
+    /// [`KernelConfig::memory_budget`], since its allocation is up to the
+    /// caller.
+    ///
+    /// A message whose length does not match its header is reported as
+    /// [`Error::Protocol`].
+    pub fn request_from_bytes(&self, msg: Vec<u8>) -> Result<Request, Error> {
+        let mut header = fuse_in_header::default();
+        let header_len = mem::size_of::<fuse_in_header>();
+        if msg.len() >= header_len {
+            header.as_bytes_mut().copy_from_slice(&msg[..header_len]);
         }
+        check_request_len(&header, msg.len())?;
+        self.inner.record_interrupt(&header, &msg[header_len..]);
 
-        Ok(Some(Request {
+        Ok(Request {
             session: self.inner.clone(),
+            timing: Timing::start(&self.inner, &header),
             header,
-            arg,
-        }))
+            arg: msg,
+            offset: header_len,
+            permit: None,
+            reply_state: Arc::default(),
+        })
     }
 
     /// Create an instance of `Notifier` corresponding to this session.
@@ -418,9 +1281,151 @@ impl Session {
             session: self.inner.clone(),
         }
     }
+
+    /// Receive the requests and dispatch them to `handler` until the
+    /// filesystem is unmounted.
+    ///
+    /// The requests that require a reply are passed to [`Handler::handle`],
+    /// which is expected to hand them off to other threads or tasks.
+    /// `FUSE_FORGET`, `FUSE_BATCH_FORGET` and `FUSE_INTERRUPT` never take a
+    /// reply, so they are processed in place on the calling thread instead,
+    /// and are never queued behind the requests that are slow to handle.
+    /// The forget requests are passed without creating a `Request` (see
+    /// [`next_request_or_forget`](Self::next_request_or_forget)).
+    pub fn run<H>(&self, mut handler: H) -> Result<(), Error>
+    where
+        H: Handler,
+    {
+        while let Some(req) = self.next_request_or_forget(|forgets| handler.forget(forgets))? {
+            if req.header.opcode != fuse_opcode::FUSE_INTERRUPT as u32 {
+                handler.handle(req);
+                continue;
+            }
+
+            match req.operation() {
+                Ok(Operation::Interrupt(op)) => handler.interrupt(op.unique()),
+                Ok(..) => unreachable!(),
+                // There is no way to report the error to the kernel.
+                Err(err) => tracing::warn!("ignoring a malformed request: {}", err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The handler of the requests dispatched by [`Session::run`].
+///
+/// Any `FnMut(Request)` closure can be used as a handler that ignores the
+/// forget and interrupt requests.
+///
+/// The handler is called only on the thread running the session and is not
+/// required to be `Send`, so it can spawn the requests as local tasks of a
+/// thread-per-core runtime.
+pub trait Handler {
+    /// Handle a request that requires a reply.
+    ///
+    /// This method is called on the thread reading the requests, so the
+    /// handler should spawn a thread or a task for long-running operations.
+    fn handle(&mut self, req: Request);
+
+    /// Decrement the lookup counts of the inodes.
+    ///
+    /// The default implementation ignores the request.
+    fn forget(&mut self, _forgets: &[op::Forget]) {}
+
+    /// Notify that the request with the specified unique ID is interrupted.
+    ///
+    /// The interrupted request still needs a reply, typically with `EINTR`,
+    /// from the task handling it. The interrupt may arrive before the target
+    /// request has been dispatched, or after it has already been replied to.
+    ///
+    /// The default implementation ignores the request.
+    fn interrupt(&mut self, _unique: u64) {}
+}
+
+impl<F> Handler for F
+where
+    F: FnMut(Request),
+{
+    fn handle(&mut self, req: Request) {
+        (self)(req)
+    }
+}
+
+/// Read a request message, whose argument is stored in `arg`.
+///
+/// The length of `arg` must be the maximum size of the argument.
+fn read_request_into<R>(
+    mut reader: R,
+    mut arg: Vec<u8>,
+) -> Result<Option<(fuse_in_header, Vec<u8>)>, Error>
+where
+    R: io::Read,
+{
+    // FIXME: Align the allocated region in `arg` with the FUSE argument types.
+    let mut header = fuse_in_header::default();
+
+    loop {
+        match reader.read_vectored(&mut [
+            io::IoSliceMut::new(header.as_bytes_mut()),
+            io::IoSliceMut::new(&mut arg[..]),
+        ]) {
+            // The peer of the connection has been closed.
+            Ok(0) => return Ok(None),
+
+            Ok(len) => {
+                check_request_len(&header, len)?;
+                unsafe {
+                    arg.set_len(len - mem::size_of::<fuse_in_header>());
+                }
+
+                return Ok(Some((header, arg)));
+            }
+
+            Err(err) => match err.raw_os_error() {
+                Some(libc::ENODEV) => {
+                    tracing::debug!("ENODEV");
+                    return Ok(None);
+                }
+                Some(libc::ENOENT) => {
+                    tracing::debug!("ENOENT");
+                    continue;
+                }
+                Some(libc::EINTR) => continue,
+                _ => return Err(err.into()),
+            },
+        }
+    }
+}
+
+fn check_request_len(header: &fuse_in_header, len: usize) -> Result<(), Error> {
+    if len < mem::size_of::<fuse_in_header>() {
+        return Err(Error::protocol("dequeued request message is too short"));
+    }
+    if header.len as usize != len {
+        return Err(Error::protocol(format!(
+            "the length of dequeued request message does not match the header \
+                 (opcode={}, unique={}, header.len={}, read={})",
+            header.opcode, header.unique, header.len, len
+        )));
+    }
+    Ok(())
 }
 
-fn init_session<R, W>(init_out: &mut fuse_init_out, mut reader: R, mut writer: W) -> io::Result<()>
+/// Return the maximum number of pages per request allowed by the kernel.
+fn kernel_max_pages_limit() -> u16 {
+    std::fs::read_to_string("/proc/sys/fs/fuse/max_pages_limit")
+        .ok()
+        .and_then(|limit| limit.trim().parse().ok())
+        .unwrap_or(MAX_MAX_PAGES as u16)
+}
+
+fn init_session<R, W>(
+    init_out: &mut fuse_init_out,
+    max_pages_limit: u16,
+    mut reader: R,
+    mut writer: W,
+) -> Result<ConnectionInfo, Error>
 where
     R: io::Read,
     W: io::Write,
@@ -430,15 +1435,17 @@ where
     let mut arg = vec![0u8; pagesize() * MAX_MAX_PAGES];
 
     for _ in 0..10 {
-        let len = reader.read_vectored(&mut [
+        let len = match reader.read_vectored(&mut [
             io::IoSliceMut::new(header.as_bytes_mut()),
             io::IoSliceMut::new(&mut arg[..]),
-        ])?;
+        ]) {
+            Ok(0) => return Err(Error::Closed),
+            Ok(len) => len,
+            Err(err) if err.raw_os_error() == Some(libc::ENODEV) => return Err(Error::Closed),
+            Err(err) => return Err(err.into()),
+        };
         if len < mem::size_of::<fuse_in_header>() {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "request message is too short",
-            ));
+            return Err(Error::protocol("request message is too short"));
         }
 
         let mut decoder = Decoder::new(&arg[..]);
@@ -447,16 +1454,20 @@ where
             Ok(fuse_opcode::FUSE_INIT) => {
                 let init_in = decoder
                     .fetch::<fuse_init_in>() //
-                    .map_err(|_| {
-                        io::Error::new(io::ErrorKind::Other, "failed to decode fuse_init_in")
-                    })?;
+                    .map_err(|_| Error::protocol("failed to decode fuse_init_in"))?;
 
                 let capable = init_in.flags & INIT_FLAGS_MASK;
                 let readonly_flags = init_in.flags & !INIT_FLAGS_MASK;
+                // The extended flags follow the fields known to polyfuse.
+                let flags2 = match decoder.fetch::<u32>() {
+                    Ok(&flags2) if init_in.flags & FUSE_INIT_EXT != 0 => flags2,
+                    _ => 0,
+                };
 
                 tracing::debug!("INIT request:");
                 tracing::debug!("  proto = {}.{}:", init_in.major, init_in.minor);
                 tracing::debug!("  flags = 0x{:08x} ({:?})", init_in.flags, capable);
+                tracing::debug!("  flags2 = 0x{:08x}", flags2);
                 tracing::debug!("  max_readahead = 0x{:08X}", init_in.max_readahead);
                 tracing::debug!("  max_pages = {}", readonly_flags & FUSE_MAX_PAGES != 0);
                 tracing::debug!(
@@ -500,6 +1511,28 @@ where
                 init_out.flags &= capable;
                 init_out.flags |= FUSE_BIG_WRITES; // the flag was superseded by `max_write`.
 
+                init_out.flags2 &= flags2 & INIT_FLAGS2_MASK;
+                if init_out.flags2 != 0 {
+                    init_out.flags |= FUSE_INIT_EXT;
+                }
+
+                // The larger writes are split by the kernel anyway, so avoid
+                // allocating the buffers that are never filled.
+                let max_pages = if init_in.flags & FUSE_MAX_PAGES != 0 {
+                    u32::from(max_pages_limit)
+                } else {
+                    DEFAULT_MAX_PAGES_PER_REQ
+                };
+                let max_write = cmp::max(max_pages * pagesize() as u32, MIN_MAX_WRITE);
+                if init_out.max_write > max_write {
+                    tracing::debug!(
+                        "max_write is lowered to the kernel limit: {} -> {}",
+                        init_out.max_write,
+                        max_write
+                    );
+                    init_out.max_write = max_write;
+                }
+
                 if init_in.flags & FUSE_MAX_PAGES != 0 {
                     init_out.flags |= FUSE_MAX_PAGES;
                     init_out.max_pages = cmp::min(
@@ -508,6 +1541,23 @@ where
                     ) as u16;
                 }
 
+                // The values are sent explicitly even when left to the kernel,
+                // so that the session reports the ones actually in effect.
+                if init_out.max_background == 0 {
+                    init_out.max_background = FUSE_DEFAULT_MAX_BACKGROUND;
+                }
+                if init_out.congestion_threshold == 0 {
+                    init_out.congestion_threshold =
+                        (u32::from(init_out.max_background) * 3 / 4) as u16;
+                } else if init_out.congestion_threshold > init_out.max_background {
+                    tracing::warn!(
+                        "congestion_threshold ({}) exceeds max_background ({}), clamped",
+                        init_out.congestion_threshold,
+                        init_out.max_background
+                    );
+                    init_out.congestion_threshold = init_out.max_background;
+                }
+
                 debug_assert_eq!(init_out.major, FUSE_KERNEL_VERSION);
                 debug_assert!(init_out.minor >= MINIMUM_SUPPORTED_MINOR_VERSION);
 
@@ -523,10 +1573,15 @@ where
                 );
                 tracing::debug!("  time_gran = {}", init_out.time_gran);
                 write_bytes(writer, Reply::new(header.unique, 0, init_out.as_bytes()))?;
+                let info = ConnectionInfo {
+                    init_in: *init_in,
+                    init_in_flags2: flags2,
+                    init_out: *init_out,
+                };
 
                 init_out.flags |= readonly_flags;
 
-                return Ok(());
+                return Ok(info);
             }
 
             _ => {
@@ -540,19 +1595,36 @@ where
         }
     }
 
-    Err(io::Error::new(
-        io::ErrorKind::ConnectionRefused,
-        "session initialization is aborted",
+    Err(Error::protocol(
+        "session initialization is aborted: no INIT request received",
     ))
 }
 
 // ==== Request ====
 
+/// The outcome of a reply that has been written without an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replied {
+    /// The reply has been delivered to the kernel.
+    Sent,
+
+    /// The kernel has rejected the reply since the request no longer
+    /// exists, e.g. because the caller has been interrupted and the request
+    /// has been aborted before the reply arrived.
+    Dropped,
+}
+
 /// Context about an incoming FUSE request.
 pub struct Request {
     session: Arc<SessionInner>,
     header: fuse_in_header,
     arg: Vec<u8>,
+    // The offset of the payload in `arg`, which is non-zero if the buffer
+    // also contains the header.
+    offset: usize,
+    permit: Option<BufferPermit>,
+    timing: Timing,
+    reply_state: Arc<ReplyState>,
 }
 
 impl Request {
@@ -562,6 +1634,15 @@ impl Request {
         self.header.unique
     }
 
+    /// Return the inode number that this request targets.
+    ///
+    /// For the requests on directory entries, it is the inode number of
+    /// the parent directory.
+    #[inline]
+    pub fn nodeid(&self) -> u64 {
+        self.header.nodeid
+    }
+
     /// Return the user ID of the calling process.
     #[inline]
     pub fn uid(&self) -> u32 {
@@ -580,34 +1661,704 @@ impl Request {
         self.header.pid
     }
 
+    /// Return the credentials of the calling process, along with the access
+    /// to its information in `/proc`.
+    pub fn caller(&self) -> Caller<'_> {
+        Caller::new(
+            self.header.uid,
+            self.header.gid,
+            self.header.pid,
+            &self.session.procs,
+        )
+    }
+
+    /// Return the opcode of this request.
+    #[inline]
+    pub fn opcode(&self) -> u32 {
+        self.header.opcode
+    }
+
+    /// Return the raw payload of this request, following `fuse_in_header`.
+    ///
+    /// It can be used to parse the requests unknown to `polyfuse` with
+    /// [`Decoder`](crate::decoder::Decoder).
+    #[inline]
+    pub fn payload(&self) -> &[u8] {
+        &self.arg[self.offset..]
+    }
+
     /// Decode the argument of this request.
     pub fn operation(&self) -> Result<Operation<'_, Data<'_>>, DecodeError> {
         if self.session.exited() {
             return Ok(Operation::unknown());
         }
 
-        let (arg, data) = match fuse_opcode::try_from(self.header.opcode).ok() {
-            Some(fuse_opcode::FUSE_WRITE) | Some(fuse_opcode::FUSE_NOTIFY_REPLY) => {
-                self.arg.split_at(mem::size_of::<fuse_write_in>())
+        decode_request(&self.header, &self.arg[self.offset..]).map_err(|err| {
+            if let Some(limit) = self.session.dump_limit {
+                let payload = &self.arg[self.offset..];
+                let len = cmp::min(mem::size_of::<fuse_in_header>() + payload.len(), limit);
+                let mut msg = Vec::with_capacity(len);
+                msg.extend_from_slice(self.header.as_bytes());
+                msg.extend_from_slice(payload);
+                msg.truncate(len);
+                tracing::warn!(
+                    unique = self.unique(),
+                    opcode = self.opcode(),
+                    "failed to decode the request ({}), {} of {} bytes dumped:\n{}",
+                    err,
+                    msg.len(),
+                    mem::size_of::<fuse_in_header>() + payload.len(),
+                    hexdump(&msg),
+                );
+            }
+            err
+        })
+    }
+
+    /// Create a reply for an entry, with the validity timeouts configured
+    /// by [`KernelConfig::entry_ttl`] and [`KernelConfig::attr_ttl`].
+    pub fn entry_out(&self) -> EntryOut {
+        let mut out = EntryOut::default();
+        out.ttl_entry(self.session.ttl.entry);
+        out.ttl_attr(self.session.ttl.attr);
+        out
+    }
+
+    /// Create a reply for the attributes, with the validity timeout
+    /// configured by [`KernelConfig::attr_ttl`].
+    pub fn attr_out(&self) -> AttrOut {
+        let mut out = AttrOut::default();
+        out.ttl(self.session.ttl.attr);
+        out
+    }
+
+    /// Create a reply for the extended attributes, with the validity timeout
+    /// configured by [`KernelConfig::attr_ttl`].
+    pub fn statx_out(&self) -> StatxOut {
+        let mut out = StatxOut::default();
+        out.ttl(self.session.ttl.attr);
+        out
+    }
+
+    /// Create a sender for the reply to this request.
+    ///
+    /// The sender shares the connection with the request, and can be moved
+    /// to another thread or task independently of the request.
+    pub fn reply_sender(&self) -> ReplySender {
+        ReplySender {
+            session: self.session.clone(),
+            unique: self.unique(),
+            permit: None,
+            timing: self.timing,
+            state: self.reply_state.clone(),
+        }
+    }
+
+    /// Split this request into the header, the owned argument buffer and
+    /// the sender of the reply.
+    ///
+    /// This allows, for example, dispatching the requests in one thread and
+    /// handling them in a pool of workers. The request can be reassembled
+    /// with [`from_parts`](Self::from_parts) in order to decode the argument.
+    ///
+    /// The buffer of the request is charged to [`KernelConfig::memory_budget`]
+    /// until the returned sender is dropped.
+    pub fn into_parts(self) -> (RequestHeader, Vec<u8>, ReplySender) {
+        let sender = ReplySender {
+            session: self.session,
+            unique: self.header.unique,
+            permit: self.permit,
+            timing: self.timing,
+            state: self.reply_state,
+        };
+        let mut arg = self.arg;
+        arg.drain(..self.offset);
+        (RequestHeader { inner: self.header }, arg, sender)
+    }
+
+    /// Reassemble a request from the parts returned by [`into_parts`](Self::into_parts).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `sender` is not associated with the request of `header`.
+    pub fn from_parts(header: RequestHeader, arg: Vec<u8>, sender: ReplySender) -> Self {
+        assert_eq!(
+            header.unique(),
+            sender.unique,
+            "the reply sender belongs to another request"
+        );
+        Self {
+            session: sender.session,
+            header: header.inner,
+            arg,
+            offset: 0,
+            permit: sender.permit,
+            timing: sender.timing,
+            reply_state: sender.state,
+        }
+    }
+
+    /// Send a reply to the kernel with the specified data.
+    ///
+    /// The reply is written to the FUSE device with a single `writev(2)`
+    /// on the calling thread, which blocks while the kernel is stuck with
+    /// the connection. See [`KernelConfig::write_timeout`] for detecting
+    /// such writes.
+    ///
+    /// If the request has already been aborted by the kernel, e.g. because
+    /// the caller was interrupted, the reply is discarded and this method
+    /// returns `Ok(Replied::Dropped)`. The returned errors are therefore
+    /// fatal to the session, e.g. `ENODEV` once the session has been closed;
+    /// see [`Error`] for why they are `io::Error`.
+    pub fn reply<T>(&self, arg: T) -> io::Result<Replied>
+    where
+        T: Bytes,
+    {
+        self.send_reply(Reply::new(self.unique(), 0, arg))
+    }
+
+    /// Send an error reply to the kernel.
+    ///
+    /// The error is a positive error number such as `libc::ENOENT`, one of
+    /// the constants in [`errno`](crate::errno), or any other [`ToErrno`].
+    /// An aborted request is handled in the same way as [`reply`](Self::reply).
+    pub fn reply_error<E>(&self, err: E) -> io::Result<Replied>
+    where
+        E: ToErrno,
+    {
+        self.send_reply(Reply::new(self.unique(), err.to_errno(), ()))
+    }
+
+    fn send_reply<T>(&self, reply: Reply<T>) -> io::Result<Replied>
+    where
+        T: Bytes,
+    {
+        self.reply_state.send(&self.session, &self.timing, reply)
+    }
+
+    /// Decode this request and process it with `f`, replying to the kernel
+    /// with the error returned from `f`.
+    ///
+    /// `f` receives the [`Context`] of the request along with the operation,
+    /// and sends the reply through it on success. The errors are converted
+    /// into the error numbers with [`ToErrno`], so the handlers can use `?`
+    /// on the standard I/O calls:
+    ///
+    /// ```ignore
+    /// req.process(|cx, op| match op {
+    ///     Operation::Getattr(op) => {
+    ///         let metadata = fs::symlink_metadata(backing_path(op.ino())?)?;
+    ///         cx.reply(to_attr_out(&metadata))?;
+    ///         Ok(())
+    ///     }
+    ///     _ => Err(Errno::unsupported()),
+    /// })?;
+    /// ```
+    ///
+    /// The error is replied only if `f` has not sent a reply through this
+    /// request or its [`ReplySender`]s, e.g. before failing in a cleanup.
+    /// A warning is logged if `f` succeeds without replying, since the
+    /// caller waits until the reply arrives. If sending a reply has
+    /// failed in `f`, the failure is returned as [`Error::Io`] instead,
+    /// since the connection is no longer usable. The errors of the requests
+    /// without a reply, i.e. forget, interrupt and the replies to the
+    /// notifications, are only logged. If the request cannot be decoded, it
+    /// is replied with `EIO` and the decode error is returned.
+    pub fn process<F, E>(&self, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&Context<'_>, Operation<'_, Data<'_>>) -> Result<(), E>,
+        E: ToErrno,
+    {
+        let op = match self.operation() {
+            Ok(op) => op,
+            Err(err) => {
+                self.reply_error(libc::EIO)?;
+                return Err(err.into());
             }
-            _ => (&self.arg[..], &[] as &[_]),
         };
+        let expects_reply = !matches!(
+            op,
+            Operation::Forget(..) | Operation::Interrupt(..) | Operation::NotifyReply(..)
+        );
+        let res = f(&Context { req: self }, op);
+        if let Some(err) = self.reply_state.failure() {
+            return Err(Error::Io(err));
+        }
+        let replied = self.reply_state.replied.load(Ordering::Acquire);
+        match res {
+            Ok(()) if expects_reply && !replied => {
+                tracing::warn!(unique = self.unique(), "the request has not been replied");
+                Ok(())
+            }
+            Ok(()) => Ok(()),
+            Err(err) if expects_reply && !replied => {
+                tracing::debug!(unique = self.unique(), "replying with an error: {:?}", err);
+                self.reply_error(err.to_errno())?;
+                Ok(())
+            }
+            Err(err) if expects_reply => {
+                tracing::warn!(
+                    unique = self.unique(),
+                    "an error occurred after replying: {:?}",
+                    err
+                );
+                Ok(())
+            }
+            Err(err) => {
+                tracing::warn!(unique = self.unique(), "an error occurred: {:?}", err);
+                Ok(())
+            }
+        }
+    }
 
-        Operation::decode(&self.header, arg, Data { data })
+    /// Race the future against the interrupt of this request.
+    ///
+    /// The returned future resolves to the output of `fut`, or to `EINTR`
+    /// once the kernel sends an `INTERRUPT` for this request, dropping `fut`
+    /// along with the future. The interrupts received through
+    /// [`Session::next_request`] are tracked by the session, even those
+    /// arriving before the future is polled, so the handlers need no
+    /// bookkeeping of their own:
+    ///
+    /// ```ignore
+    /// Operation::Read(op) => {
+    ///     let res = req.interruptible(backend.read(op.ino(), op.offset())).await;
+    ///     req.reply_result(res)?;
+    /// }
+    /// ```
+    pub fn interruptible<F, T, E>(&self, fut: F) -> Interruptible<'_, F>
+    where
+        F: Future<Output = Result<T, E>>,
+        E: From<Errno>,
+    {
+        Interruptible::new(&self.session.interrupts, self.unique(), fut)
     }
 
-    pub fn reply<T>(&self, arg: T) -> io::Result<()>
+    /// Reply with the data on success, or with the error number otherwise.
+    ///
+    /// The error is converted with [`ToErrno`].
+    pub fn reply_result<T, E>(&self, res: Result<T, E>) -> io::Result<Replied>
     where
         T: Bytes,
+        E: ToErrno,
+    {
+        match res {
+            Ok(arg) => self.reply(arg),
+            Err(err) => self.reply_error(err.to_errno()),
+        }
+    }
+
+    /// Reply with the attributes obtained by `stat(2)` on success, or with
+    /// the error number otherwise.
+    ///
+    /// The validity timeout of the attributes is the one created by
+    /// [`attr_out`](Self::attr_out):
+    ///
+    /// ```ignore
+    /// Operation::Getattr(op) => req.attr_or_errno(fs.stat(op.ino()))?,
+    /// ```
+    ///
+    /// The attributes are filled with [`fill_attr`], so this is available
+    /// only on Linux, like [`util::passthrough`](crate::util::passthrough).
+    ///
+    /// [`fill_attr`]: crate::util::passthrough::fill_attr
+    #[cfg(target_os = "linux")]
+    pub fn attr_or_errno<E>(&self, res: Result<libc::stat, E>) -> io::Result<Replied>
+    where
+        E: ToErrno,
     {
-        write_bytes(&self.session.conn, Reply::new(self.unique(), 0, arg))
+        self.reply_result(res.map(|st| {
+            let mut out = self.attr_out();
+            crate::util::passthrough::fill_attr(out.attr(), &st);
+            out
+        }))
     }
+}
+
+/// The context of a request, passed to the handler by [`Request::process`].
+///
+/// The context gives the handler the caller, the replies, the interrupt and
+/// the notifications of the session without capturing them separately.
+#[derive(Clone, Copy)]
+pub struct Context<'req> {
+    req: &'req Request,
+}
 
-    pub fn reply_error(&self, code: i32) -> io::Result<()> {
-        write_bytes(&self.session.conn, Reply::new(self.unique(), code, ()))
+impl fmt::Debug for Context<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Context")
+            .field("unique", &self.unique())
+            .field("uid", &self.uid())
+            .field("gid", &self.gid())
+            .field("pid", &self.pid())
+            .finish()
     }
 }
 
+impl<'req> Context<'req> {
+    /// Return the request being processed.
+    #[inline]
+    pub fn request(&self) -> &'req Request {
+        self.req
+    }
+
+    /// Return the unique ID of the request.
+    #[inline]
+    pub fn unique(&self) -> u64 {
+        self.req.unique()
+    }
+
+    /// Return the user ID of the calling process.
+    #[inline]
+    pub fn uid(&self) -> u32 {
+        self.req.uid()
+    }
+
+    /// Return the group ID of the calling process.
+    #[inline]
+    pub fn gid(&self) -> u32 {
+        self.req.gid()
+    }
+
+    /// Return the process ID of the calling process.
+    #[inline]
+    pub fn pid(&self) -> u32 {
+        self.req.pid()
+    }
+
+    /// Return the credentials of the calling process, see [`Request::caller`].
+    pub fn caller(&self) -> Caller<'req> {
+        self.req.caller()
+    }
+
+    /// Reply to the request with the data, see [`Request::reply`].
+    pub fn reply<T>(&self, arg: T) -> io::Result<Replied>
+    where
+        T: Bytes,
+    {
+        self.req.reply(arg)
+    }
+
+    /// Return whether the kernel has sent an `INTERRUPT` for the request.
+    pub fn is_interrupted(&self) -> bool {
+        self.req.session.interrupts.is_interrupted(self.unique())
+    }
+
+    /// Race the future against the interrupt of the request, see
+    /// [`Request::interruptible`].
+    pub fn interruptible<F, T, E>(&self, fut: F) -> Interruptible<'req, F>
+    where
+        F: Future<Output = Result<T, E>>,
+        E: From<Errno>,
+    {
+        self.req.interruptible(fut)
+    }
+
+    /// Create a `Notifier` of the session.
+    pub fn notifier(&self) -> Notifier {
+        Notifier {
+            session: self.req.session.clone(),
+        }
+    }
+
+    /// Return the parameters negotiated with the kernel, see
+    /// [`Session::connection_info`].
+    pub fn connection_info(&self) -> &'req ConnectionInfo {
+        &self.req.session.info
+    }
+}
+
+/// The parameters exchanged with the kernel in the `INIT` handshake, as
+/// returned by [`Session::connection_info`].
+///
+/// The raw structures are kept as sent on the wire, for dumping them in the
+/// bug reports through the `Debug` implementation.
+#[derive(Clone, Copy)]
+pub struct ConnectionInfo {
+    init_in: fuse_init_in,
+    init_in_flags2: u32,
+    init_out: fuse_init_out,
+}
+
+impl fmt::Debug for ConnectionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let init_in = &self.init_in;
+        let init_out = &self.init_out;
+        f.debug_struct("ConnectionInfo")
+            .field(
+                "kernel_proto",
+                &format_args!("{}.{}", init_in.major, init_in.minor),
+            )
+            .field("kernel_flags", &format_args!("{:#010x}", init_in.flags))
+            .field(
+                "kernel_flags2",
+                &format_args!("{:#010x}", self.init_in_flags2),
+            )
+            .field("kernel_max_readahead", &init_in.max_readahead)
+            .field(
+                "proto",
+                &format_args!("{}.{}", init_out.major, init_out.minor),
+            )
+            .field("flags", &format_args!("{:#010x}", init_out.flags))
+            .field("flags2", &format_args!("{:#010x}", init_out.flags2))
+            .field("max_readahead", &init_out.max_readahead)
+            .field("max_write", &init_out.max_write)
+            .field("max_pages", &init_out.max_pages)
+            .field("max_background", &init_out.max_background)
+            .field("congestion_threshold", &init_out.congestion_threshold)
+            .field("time_gran", &init_out.time_gran)
+            .finish()
+    }
+}
+
+impl ConnectionInfo {
+    /// Return the reply to the `INIT` request, as sent to the kernel.
+    pub fn raw(&self) -> &fuse_init_out {
+        &self.init_out
+    }
+
+    /// Return the `INIT` request sent by the kernel.
+    ///
+    /// The extended flags following the structure are returned by
+    /// [`raw_init_in_flags2`](Self::raw_init_in_flags2).
+    pub fn raw_init_in(&self) -> &fuse_init_in {
+        &self.init_in
+    }
+
+    /// Return the extended flags of the `INIT` request, or zero if the
+    /// kernel does not send them.
+    pub fn raw_init_in_flags2(&self) -> u32 {
+        self.init_in_flags2
+    }
+}
+
+/// The header of a request, detached by [`Request::into_parts`].
+#[derive(Clone, Copy)]
+pub struct RequestHeader {
+    inner: fuse_in_header,
+}
+
+impl fmt::Debug for RequestHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestHeader")
+            .field("unique", &self.inner.unique)
+            .field("opcode", &self.inner.opcode)
+            .field("nodeid", &self.inner.nodeid)
+            .field("uid", &self.inner.uid)
+            .field("gid", &self.inner.gid)
+            .field("pid", &self.inner.pid)
+            .finish()
+    }
+}
+
+impl RequestHeader {
+    /// Return the unique ID of the request.
+    #[inline]
+    pub fn unique(&self) -> u64 {
+        self.inner.unique
+    }
+
+    /// Return the opcode of the request.
+    #[inline]
+    pub fn opcode(&self) -> u32 {
+        self.inner.opcode
+    }
+
+    /// Return the inode number that the request targets.
+    #[inline]
+    pub fn nodeid(&self) -> u64 {
+        self.inner.nodeid
+    }
+
+    /// Return the user ID of the calling process.
+    #[inline]
+    pub fn uid(&self) -> u32 {
+        self.inner.uid
+    }
+
+    /// Return the group ID of the calling process.
+    #[inline]
+    pub fn gid(&self) -> u32 {
+        self.inner.gid
+    }
+
+    /// Return the process ID of the calling process.
+    #[inline]
+    pub fn pid(&self) -> u32 {
+        self.inner.pid
+    }
+}
+
+/// The sender of the reply to a request.
+///
+/// The replies are sent in the same way as [`Request::reply`] and
+/// [`Request::reply_error`], and are seen by [`Request::process`] as sent
+/// through the request.
+pub struct ReplySender {
+    session: Arc<SessionInner>,
+    unique: u64,
+    permit: Option<BufferPermit>,
+    timing: Timing,
+    state: Arc<ReplyState>,
+}
+
+impl fmt::Debug for ReplySender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReplySender")
+            .field("unique", &self.unique)
+            .finish()
+    }
+}
+
+impl ReplySender {
+    /// Return the unique ID of the request to reply.
+    #[inline]
+    pub fn unique(&self) -> u64 {
+        self.unique
+    }
+
+    /// Send a reply to the kernel with the specified data.
+    pub fn reply<T>(&self, arg: T) -> io::Result<Replied>
+    where
+        T: Bytes,
+    {
+        let reply = Reply::new(self.unique, 0, arg);
+        self.state.send(&self.session, &self.timing, reply)
+    }
+
+    /// Send an error reply to the kernel.
+    ///
+    /// See [`Request::reply_error`] for the accepted errors.
+    pub fn reply_error<E>(&self, err: E) -> io::Result<Replied>
+    where
+        E: ToErrno,
+    {
+        let reply = Reply::new(self.unique, err.to_errno(), ());
+        self.state.send(&self.session, &self.timing, reply)
+    }
+}
+
+/// The state of the reply to a request, shared with its senders so that
+/// [`Request::process`] never replies twice.
+#[derive(Default)]
+struct ReplyState {
+    // Whether a reply has been sent, and the error number of the reply that
+    // failed (-1 if not an OS error).
+    replied: AtomicBool,
+    failure: AtomicI32,
+}
+
+impl ReplyState {
+    fn send<T>(
+        &self,
+        session: &SessionInner,
+        timing: &Timing,
+        reply: Reply<T>,
+    ) -> io::Result<Replied>
+    where
+        T: Bytes,
+    {
+        self.replied.store(true, Ordering::Release);
+        let _watch = session.watch_write();
+        match write_reply(&session.conn, reply) {
+            Ok(replied) => {
+                timing.finish(session);
+                Ok(replied)
+            }
+            Err(err) => {
+                let code = err.raw_os_error().unwrap_or(-1);
+                self.failure.store(code, Ordering::Release);
+                Err(err)
+            }
+        }
+    }
+
+    /// Return the error of the reply that failed, reconstructed since
+    /// `io::Error` cannot be cloned.
+    fn failure(&self) -> Option<io::Error> {
+        match self.failure.load(Ordering::Acquire) {
+            0 => None,
+            -1 => Some(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "failed to write the reply",
+            )),
+            code => Some(io::Error::from_raw_os_error(code)),
+        }
+    }
+}
+
+/// The opcode of a request and the time at which it has been read, for
+/// recording its reply.
+///
+/// The time is recorded only if the `latency-stats` feature is enabled.
+#[derive(Clone, Copy)]
+struct Timing {
+    opcode: u32,
+    #[cfg(feature = "latency-stats")]
+    received: Instant,
+}
+
+impl Timing {
+    #[inline]
+    fn start(session: &SessionInner, header: &fuse_in_header) -> Self {
+        if let Some(ref control) = session.control {
+            control.received(header.opcode);
+        }
+        Self {
+            opcode: header.opcode,
+            #[cfg(feature = "latency-stats")]
+            received: Instant::now(),
+        }
+    }
+
+    #[inline]
+    fn finish(&self, session: &SessionInner) {
+        if let Some(ref control) = session.control {
+            control.replied(self.opcode);
+        }
+        #[cfg(feature = "latency-stats")]
+        session.latency.record(self.opcode, self.received.elapsed());
+    }
+}
+
+/// Decode the request message, rejecting the malformed ones instead of panicking.
+pub(crate) fn decode_request<'a>(
+    header: &'a fuse_in_header,
+    arg: &'a [u8],
+) -> Result<Operation<'a, Data<'a>>, DecodeError> {
+    decode_request_inner(header, arg).map_err(|err| err.with_request(header, arg))
+}
+
+fn decode_request_inner<'a>(
+    header: &'a fuse_in_header,
+    arg: &'a [u8],
+) -> Result<Operation<'a, Data<'a>>, DecodeError> {
+    if header.len as usize != mem::size_of::<fuse_in_header>() + arg.len() {
+        return Err(DecodeError::new(decoder::DecodeError::InvalidLength {
+            expected: header.len as usize,
+            available: mem::size_of::<fuse_in_header>() + arg.len(),
+        }));
+    }
+
+    let (arg, data) = match fuse_opcode::try_from(header.opcode).ok() {
+        Some(fuse_opcode::FUSE_WRITE) | Some(fuse_opcode::FUSE_NOTIFY_REPLY) => {
+            arg.split_at(cmp::min(arg.len(), mem::size_of::<fuse_write_in>()))
+        }
+        _ => (arg, &[] as &[_]),
+    };
+
+    let op = Operation::decode(header, arg, Data { data })?;
+    if let Operation::Write(ref op, ref data) = op {
+        if op.size() as usize > data.data.len() {
+            return Err(DecodeError::new(decoder::DecodeError::InvalidLength {
+                expected: op.size() as usize,
+                available: data.data.len(),
+            }));
+        }
+    }
+    Ok(op)
+}
+
 /// The remaining part of request message.
 pub struct Data<'op> {
     data: &'op [u8],
@@ -643,51 +2394,23 @@ impl<'op> BufRead for Data<'op> {
     }
 }
 
-// ==== Notifier ====
-
-#[derive(Clone)]
-pub struct Notifier {
-    session: Arc<SessionInner>,
-}
-
-impl Notifier {
-    /// Notify the cache invalidation about an inode to the kernel.
-    pub fn inval_inode(&self, ino: u64, off: i64, len: i64) -> io::Result<()> {
-        let total_len = u32::try_from(
-            mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_notify_inval_inode_out>(),
-        )
-        .unwrap();
-
-        return write_bytes(
-            &self.session.conn,
-            InvalInode {
-                header: fuse_out_header {
-                    len: total_len,
-                    error: fuse_notify_code::FUSE_NOTIFY_INVAL_INODE as i32,
-                    unique: 0,
-                },
-                arg: fuse_notify_inval_inode_out { ino, off, len },
-            },
-        );
-
-        struct InvalInode {
-            header: fuse_out_header,
-            arg: fuse_notify_inval_inode_out,
-        }
-        impl Bytes for InvalInode {
-            fn size(&self) -> usize {
-                self.header.len as usize
-            }
+// ==== Notifier ====
 
-            fn count(&self) -> usize {
-                2
-            }
+/// The sender of the notifications to the kernel.
+///
+/// The notifications are written to the FUSE device in the same way as the
+/// replies, and fail with `ENODEV` once the session has been closed. See
+/// [`Error`] for why they return `io::Error`.
+#[derive(Clone)]
+pub struct Notifier {
+    session: Arc<SessionInner>,
+}
 
-            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
-                dst.put(self.header.as_bytes());
-                dst.put(self.arg.as_bytes());
-            }
-        }
+impl Notifier {
+    /// Notify the cache invalidation about an inode to the kernel.
+    pub fn inval_inode(&self, ino: u64, off: i64, len: i64) -> io::Result<()> {
+        let arg = fuse_notify_inval_inode_out { ino, off, len };
+        self.send(fuse_notify_code::FUSE_NOTIFY_INVAL_INODE, arg.as_bytes())
     }
 
     /// Notify the invalidation about a directory entry to the kernel.
@@ -695,60 +2418,16 @@ impl Notifier {
     where
         T: AsRef<OsStr>,
     {
-        let namelen = u32::try_from(name.as_ref().len()).expect("provided name is too long");
-
-        let total_len = u32::try_from(
-            mem::size_of::<fuse_out_header>()
-                + mem::size_of::<fuse_notify_inval_entry_out>()
-                + name.as_ref().len()
-                + 1,
+        let name = name.as_ref();
+        let arg = fuse_notify_inval_entry_out {
+            parent,
+            namelen: u32::try_from(name.len()).expect("provided name is too long"),
+            padding: 0,
+        };
+        self.send(
+            fuse_notify_code::FUSE_NOTIFY_INVAL_ENTRY,
+            (arg.as_bytes(), name, &b"\0"[..]), // null terminator
         )
-        .unwrap();
-
-        return write_bytes(
-            &self.session.conn,
-            InvalEntry {
-                header: fuse_out_header {
-                    len: total_len,
-                    error: fuse_notify_code::FUSE_NOTIFY_INVAL_ENTRY as i32,
-                    unique: 0,
-                },
-                arg: fuse_notify_inval_entry_out {
-                    parent,
-                    namelen,
-                    padding: 0,
-                },
-                name,
-            },
-        );
-
-        struct InvalEntry<T>
-        where
-            T: AsRef<OsStr>,
-        {
-            header: fuse_out_header,
-            arg: fuse_notify_inval_entry_out,
-            name: T,
-        }
-        impl<T> Bytes for InvalEntry<T>
-        where
-            T: AsRef<OsStr>,
-        {
-            fn size(&self) -> usize {
-                self.header.len as usize
-            }
-
-            fn count(&self) -> usize {
-                4
-            }
-
-            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
-                dst.put(self.header.as_bytes());
-                dst.put(self.arg.as_bytes());
-                dst.put(self.name.as_ref().as_bytes());
-                dst.put(b"\0"); // null terminator
-            }
-        }
     }
 
     /// Notify the invalidation about a directory entry to the kernel.
@@ -761,210 +2440,101 @@ impl Notifier {
     where
         T: AsRef<OsStr>,
     {
-        let namelen = u32::try_from(name.as_ref().len()).expect("provided name is too long");
-
-        let total_len = u32::try_from(
-            mem::size_of::<fuse_out_header>()
-                + mem::size_of::<fuse_notify_delete_out>()
-                + name.as_ref().len()
-                + 1,
+        let name = name.as_ref();
+        let arg = fuse_notify_delete_out {
+            parent,
+            child,
+            namelen: u32::try_from(name.len()).expect("provided name is too long"),
+            padding: 0,
+        };
+        self.send(
+            fuse_notify_code::FUSE_NOTIFY_DELETE,
+            (arg.as_bytes(), name, &b"\0"[..]), // null terminator
         )
-        .expect("payload is too long");
-
-        return write_bytes(
-            &self.session.conn,
-            Delete {
-                header: fuse_out_header {
-                    len: total_len,
-                    error: fuse_notify_code::FUSE_NOTIFY_DELETE as i32,
-                    unique: 0,
-                },
-                arg: fuse_notify_delete_out {
-                    parent,
-                    child,
-                    namelen,
-                    padding: 0,
-                },
-                name,
-            },
-        );
-
-        struct Delete<T>
-        where
-            T: AsRef<OsStr>,
-        {
-            header: fuse_out_header,
-            arg: fuse_notify_delete_out,
-            name: T,
-        }
-        impl<T> Bytes for Delete<T>
-        where
-            T: AsRef<OsStr>,
-        {
-            fn size(&self) -> usize {
-                self.header.len as usize
-            }
-
-            fn count(&self) -> usize {
-                4
-            }
-
-            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
-                dst.put(self.header.as_bytes());
-                dst.put(self.arg.as_bytes());
-                dst.put(self.name.as_ref().as_bytes());
-                dst.put(b"\0"); // null terminator
-            }
-        }
     }
 
     /// Push the data in an inode for updating the kernel cache.
+    ///
+    /// The chunks of `data` are passed to the kernel as they are, without
+    /// being copied into an intermediate buffer.
     pub fn store<T>(&self, ino: u64, offset: u64, data: T) -> io::Result<()>
     where
         T: Bytes,
     {
-        let size = u32::try_from(data.size()).expect("provided data is too large");
-
-        let total_len = u32::try_from(
-            mem::size_of::<fuse_out_header>()
-                + mem::size_of::<fuse_notify_store_out>()
-                + data.size(),
-        )
-        .expect("payload is too long");
-
-        return write_bytes(
-            &self.session.conn,
-            Store {
-                header: fuse_out_header {
-                    len: total_len,
-                    error: fuse_notify_code::FUSE_NOTIFY_STORE as i32,
-                    unique: 0,
-                },
-                arg: fuse_notify_store_out {
-                    nodeid: ino,
-                    offset,
-                    size,
-                    padding: 0,
-                },
-                data,
-            },
-        );
+        let arg = store_out(ino, offset, &data);
+        self.send(fuse_notify_code::FUSE_NOTIFY_STORE, (arg.as_bytes(), data))
+    }
 
-        struct Store<T>
-        where
-            T: Bytes,
-        {
-            header: fuse_out_header,
-            arg: fuse_notify_store_out,
-            data: T,
+    /// Push the data in an inode for updating the kernel cache, without
+    /// copying it through `write(2)`.
+    ///
+    /// The pages of `data` are mapped into a pipe with `vmsplice(2)` and then
+    /// spliced into the connection. This saves copying multi-megabyte
+    /// payloads, but costs a pipe and a few more system calls per call, so
+    /// `store` is preferable for small data.
+    ///
+    /// The whole message must fit in the pipe. If the pipe cannot be grown
+    /// enough (see `/proc/sys/fs/pipe-max-size`), the data is sent in the
+    /// same way as `store`.
+    pub fn store_spliced<T>(&self, ino: u64, offset: u64, data: T) -> io::Result<()>
+    where
+        T: Bytes,
+    {
+        let arg = store_out(ino, offset, &data);
+        let msg = Reply::notify(fuse_notify_code::FUSE_NOTIFY_STORE, (arg.as_bytes(), data));
+
+        let _watch = self.session.watch_write();
+        let mut bufs = CollectSlices(Vec::with_capacity(msg.count()));
+        msg.fill_bytes(&mut bufs);
+        if self.session.conn.splice_vectored(&bufs.0)? {
+            return Ok(());
         }
-        impl<T> Bytes for Store<T>
-        where
-            T: Bytes,
-        {
-            fn size(&self) -> usize {
-                self.header.len as usize
-            }
-
-            fn count(&self) -> usize {
-                2 + self.data.count()
-            }
 
-            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
-                dst.put(self.header.as_bytes());
-                dst.put(self.arg.as_bytes());
-                self.data.fill_bytes(dst);
-            }
-        }
+        tracing::debug!("failed to splice the message, falling back to writev");
+        write_bytes(&self.session.conn, msg)
     }
 
     /// Retrieve data in an inode from the kernel cache.
     pub fn retrieve(&self, ino: u64, offset: u64, size: u32) -> io::Result<u64> {
-        let total_len = u32::try_from(
-            mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_notify_retrieve_out>(),
-        )
-        .unwrap();
-
         // FIXME: choose appropriate memory ordering.
         let notify_unique = self.session.notify_unique.fetch_add(1, Ordering::SeqCst);
 
-        write_bytes(
-            &self.session.conn,
-            Retrieve {
-                header: fuse_out_header {
-                    len: total_len,
-                    error: fuse_notify_code::FUSE_NOTIFY_RETRIEVE as i32,
-                    unique: 0,
-                },
-                arg: fuse_notify_retrieve_out {
-                    nodeid: ino,
-                    offset,
-                    size,
-                    notify_unique,
-                    padding: 0,
-                },
-            },
-        )?;
-
-        return Ok(notify_unique);
-
-        struct Retrieve {
-            header: fuse_out_header,
-            arg: fuse_notify_retrieve_out,
-        }
-        impl Bytes for Retrieve {
-            fn size(&self) -> usize {
-                self.header.len as usize
-            }
-
-            fn count(&self) -> usize {
-                2
-            }
+        let arg = fuse_notify_retrieve_out {
+            nodeid: ino,
+            offset,
+            size,
+            notify_unique,
+            padding: 0,
+        };
+        self.send(fuse_notify_code::FUSE_NOTIFY_RETRIEVE, arg.as_bytes())?;
 
-            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
-                dst.put(self.header.as_bytes());
-                dst.put(self.arg.as_bytes());
-            }
-        }
+        Ok(notify_unique)
     }
 
     /// Send I/O readiness to the kernel.
     pub fn poll_wakeup(&self, kh: u64) -> io::Result<()> {
-        let total_len = u32::try_from(
-            mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_notify_poll_wakeup_out>(),
-        )
-        .unwrap();
-
-        return write_bytes(
-            &self.session.conn,
-            PollWakeup {
-                header: fuse_out_header {
-                    len: total_len,
-                    error: fuse_notify_code::FUSE_NOTIFY_POLL as i32,
-                    unique: 0,
-                },
-                arg: fuse_notify_poll_wakeup_out { kh },
-            },
-        );
-
-        struct PollWakeup {
-            header: fuse_out_header,
-            arg: fuse_notify_poll_wakeup_out,
-        }
-        impl Bytes for PollWakeup {
-            fn size(&self) -> usize {
-                self.header.len as usize
-            }
+        let arg = fuse_notify_poll_wakeup_out { kh };
+        self.send(fuse_notify_code::FUSE_NOTIFY_POLL, arg.as_bytes())
+    }
 
-            fn count(&self) -> usize {
-                2
-            }
+    fn send<T>(&self, code: fuse_notify_code, arg: T) -> io::Result<()>
+    where
+        T: Bytes,
+    {
+        let _watch = self.session.watch_write();
+        write_bytes(&self.session.conn, Reply::notify(code, arg))
+    }
+}
 
-            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
-                dst.put(self.header.as_bytes());
-                dst.put(self.arg.as_bytes());
-            }
-        }
+fn store_out<T>(ino: u64, offset: u64, data: &T) -> fuse_notify_store_out
+where
+    T: Bytes,
+{
+    fuse_notify_store_out {
+        nodeid: ino,
+        offset,
+        size: u32::try_from(data.size()).expect("provided data is too large"),
+        padding: 0,
     }
 }
 
@@ -992,6 +2562,13 @@ where
             arg,
         }
     }
+
+    /// Create a notification message, which is distinguished from the replies
+    /// by the zero `unique` and carries the notify code in the `error` field.
+    #[inline]
+    fn notify(code: fuse_notify_code, arg: T) -> Self {
+        Self::new(0, -(code as i32), arg)
+    }
 }
 impl<T> Bytes for Reply<T>
 where
@@ -1014,7 +2591,7 @@ where
 }
 
 #[inline]
-fn write_bytes<W, T>(mut writer: W, bytes: T) -> io::Result<()>
+fn write_bytes<W, T>(writer: W, bytes: T) -> io::Result<()>
 where
     W: io::Write,
     T: Bytes,
@@ -1022,8 +2599,6 @@ where
     let size = bytes.size();
     let count = bytes.count();
 
-    let written;
-
     macro_rules! small_write {
         ($n:expr) => {{
             let mut vec: [MaybeUninit<IoSlice<'_>>; $n] =
@@ -1034,13 +2609,13 @@ where
             });
             let vec = unsafe { slice_assume_init_ref(&vec[..]) };
 
-            written = writer.write_vectored(vec)?;
+            send_msg(writer, vec, size)
         }};
     }
 
     match count {
         // Skip writing.
-        0 => return Ok(()),
+        0 => Ok(()),
 
         // Avoid heap allocation if count is small.
         1 => small_write!(1),
@@ -1062,10 +2637,64 @@ where
                 vec.set_len(count);
             }
 
-            written = writer.write_vectored(&*vec)?;
+            if count > IOV_MAX {
+                // writev(2) rejects such a long vector, so the message is
+                // gathered into a single buffer instead.
+                let mut buf = Vec::with_capacity(size);
+                for chunk in &vec {
+                    buf.extend_from_slice(chunk);
+                }
+                return send_msg(writer, &[IoSlice::new(&buf)], size);
+            }
+
+            send_msg(writer, &vec, size)
+        }
+    }
+}
+
+/// Write a reply to the FUSE device, reporting the requests that no longer
+/// exist as [`Replied::Dropped`].
+///
+/// The kernel forgets a request when it is aborted, such as when the caller
+/// is interrupted before the reply arrives, and rejects the late reply with
+/// `ENOENT`. This is not an error of the session.
+fn write_reply<W, T>(writer: W, reply: Reply<T>) -> io::Result<Replied>
+where
+    W: io::Write,
+    T: Bytes,
+{
+    let unique = reply.header.unique;
+    match write_bytes(writer, reply) {
+        Ok(()) => Ok(Replied::Sent),
+        Err(ref err) if err.raw_os_error() == Some(libc::ENOENT) => {
+            tracing::debug!("the request has already been aborted (unique={})", unique);
+            Ok(Replied::Dropped)
         }
+        Err(err) => Err(err),
     }
+}
 
+/// Write a message to the FUSE device with a single `writev(2)`.
+///
+/// The kernel handles each write to the device as one complete message, so
+/// a message must not be split into multiple writes, and the remainder of
+/// a short write cannot be retried. Writing the whole message at once also
+/// ensures that the messages sent concurrently from multiple threads are
+/// never interleaved.
+fn send_msg<W>(mut writer: W, bufs: &[IoSlice<'_>], size: usize) -> io::Result<()>
+where
+    W: io::Write,
+{
+    debug_assert!(bufs.len() <= IOV_MAX);
+    debug_assert_eq!(bufs.iter().map(|buf| buf.len()).sum::<usize>(), size);
+
+    let written = loop {
+        match writer.write_vectored(bufs) {
+            // Nothing has been written, so the whole message can be sent again.
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            res => break res?,
+        }
+    };
     if written < size {
         return Err(io::Error::new(
             io::ErrorKind::Other,
@@ -1088,6 +2717,14 @@ impl<'a, 'vec> FillBytes<'a> for FillWriteBytes<'a, 'vec> {
     }
 }
 
+struct CollectSlices<'a>(Vec<IoSlice<'a>>);
+
+impl<'a> FillBytes<'a> for CollectSlices<'a> {
+    fn put(&mut self, chunk: &'a [u8]) {
+        self.0.push(IoSlice::new(chunk));
+    }
+}
+
 // FIXME: replace with stabilized MaybeUninit::slice_assume_init_ref.
 #[inline(always)]
 unsafe fn slice_assume_init_ref<T>(slice: &[MaybeUninit<T>]) -> &[T] {
@@ -1097,6 +2734,30 @@ unsafe fn slice_assume_init_ref<T>(slice: &[MaybeUninit<T>]) -> &[T] {
     }
 }
 
+/// Format the bytes in the form of `hexdump -C`, without the final offset.
+fn hexdump(bytes: &[u8]) -> String {
+    use std::fmt::Write as _;
+    let mut dump = String::new();
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let _ = write!(dump, "{:08x} ", i * 16);
+        for (j, b) in line.iter().enumerate() {
+            let sep = if j == 8 { "  " } else { " " };
+            let _ = write!(dump, "{}{:02x}", sep, b);
+        }
+        let padding = (16 - line.len()) * 3 + if line.len() <= 8 { 1 } else { 0 };
+        let _ = write!(dump, "{:1$}  |", "", padding);
+        dump.extend(line.iter().map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        }));
+        dump.push_str("|\n");
+    }
+    dump
+}
+
 #[inline]
 fn pagesize() -> usize {
     unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
@@ -1115,13 +2776,15 @@ const fn default_init_out() -> fuse_init_out {
         time_gran: 1,
         max_pages: 0,
         padding: 0,
-        unused: [0; 8],
+        flags2: 0,
+        unused: [0; 7],
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fault::{Fault, FaultyConn};
     use std::mem;
 
     #[test]
@@ -1155,15 +2818,22 @@ mod tests {
         let mut output = Vec::<u8>::new();
 
         let mut init_out = default_init_out();
-        init_session(&mut init_out, &input[..], &mut output).expect("initialization failed");
+        let info = init_session(&mut init_out, u16::MAX, &input[..], &mut output)
+            .expect("initialization failed");
+        assert_eq!(info.raw_init_in().flags, init_in.flags);
+        assert_eq!(
+            info.raw().as_bytes(),
+            &output[mem::size_of::<fuse_out_header>()..]
+        );
+        assert_eq!(info.raw().flags & FUSE_NO_OPEN_SUPPORT, 0);
 
         let expected_max_pages = (DEFAULT_MAX_WRITE / (pagesize() as u32)) as u16;
 
         assert_eq!(init_out.major, 7);
         assert_eq!(init_out.minor, 23);
         assert_eq!(init_out.max_readahead, 40);
-        assert_eq!(init_out.max_background, 0);
-        assert_eq!(init_out.congestion_threshold, 0);
+        assert_eq!(init_out.max_background, 12);
+        assert_eq!(init_out.congestion_threshold, 9);
         assert_eq!(init_out.max_write, DEFAULT_MAX_WRITE);
         assert_eq!(init_out.max_pages, expected_max_pages);
         assert_eq!(init_out.time_gran, 1);
@@ -1181,13 +2851,14 @@ mod tests {
             minor: 23,
             max_readahead: 40,
             flags: DEFAULT_INIT_FLAGS | FUSE_MAX_PAGES | FUSE_BIG_WRITES,
-            max_background: 0,
-            congestion_threshold: 0,
+            max_background: 12,
+            congestion_threshold: 9,
             max_write: DEFAULT_MAX_WRITE,
             time_gran: 1,
             max_pages: expected_max_pages,
             padding: 0,
-            unused: [0; 8],
+            flags2: 0,
+            unused: [0; 7],
         };
 
         let mut expected = Vec::with_capacity(output_len);
@@ -1228,6 +2899,48 @@ mod tests {
         ($($b:expr),*$(,)?) => ( *bytes(&[$($b),*]) );
     }
 
+    #[test]
+    fn config_presets() {
+        let config = KernelConfig::network_fs();
+        assert_eq!(config.init_out.flags & FUSE_WRITEBACK_CACHE, 0);
+        assert_ne!(config.init_out.flags & FUSE_AUTO_INVAL_DATA, 0);
+
+        let config = KernelConfig::local_cache_fs();
+        assert_ne!(config.init_out.flags & FUSE_WRITEBACK_CACHE, 0);
+        assert_eq!(config.init_out.flags & FUSE_AUTO_INVAL_DATA, 0);
+
+        let config = KernelConfig::passthrough();
+        assert_ne!(config.init_out.flags & FUSE_WRITEBACK_CACHE, 0);
+        assert_ne!(config.init_out.flags & FUSE_EXPORT_SUPPORT, 0);
+        assert_ne!(config.init_out.flags & FUSE_FLOCK_LOCKS, 0);
+        assert_eq!(config.mountopts.options, ["default_permissions"]);
+
+        let mut config = KernelConfig::default();
+        config.mount_option("ro,max_read=100").max_read(8192);
+        assert_eq!(config.mountopts.options, ["ro", "max_read=8192"]);
+        assert_eq!(config.max_read, Some(8192));
+
+        let mut config = KernelConfig::default();
+        config.mount_option("blkdev,blksize=4096,blksize=100");
+        assert_eq!(config.mountopts.options, ["blkdev", "blksize=100"]);
+        assert_eq!(config.mountopts.blksize, Some(4096));
+
+        assert_eq!(KernelConfig::network_fs().ttl.entry, DEFAULT_TTL);
+        assert_eq!(KernelConfig::passthrough().ttl.attr, LONG_TTL);
+
+        for config in &[
+            KernelConfig::network_fs(),
+            KernelConfig::local_cache_fs(),
+            KernelConfig::passthrough(),
+        ] {
+            assert_eq!(config.init_out.max_write, PRESET_MAX_WRITE);
+            assert_eq!(
+                config.init_out.flags & DEFAULT_INIT_FLAGS & !FUSE_AUTO_INVAL_DATA,
+                DEFAULT_INIT_FLAGS & !FUSE_AUTO_INVAL_DATA
+            );
+        }
+    }
+
     #[test]
     fn send_msg_empty() {
         let mut buf = vec![0u8; 0];
@@ -1274,4 +2987,392 @@ mod tests {
         );
         assert_eq!(buf[16..], *b"hello, this is a message.", "payload");
     }
+
+    #[test]
+    fn send_msg_too_many_chunks() {
+        let payload: Vec<&[u8]> = vec![b"x"; IOV_MAX * 2];
+        let conn = FaultyConn::new();
+        write_bytes(&conn, Reply::new(26, 0, &payload[..])).unwrap();
+
+        let output = conn.take_output();
+        assert_eq!(output.len(), 1, "the message must be written at once");
+        assert_eq!(
+            output[0].len(),
+            mem::size_of::<fuse_out_header>() + IOV_MAX * 2
+        );
+        assert!(output[0][mem::size_of::<fuse_out_header>()..]
+            .iter()
+            .all(|&b| b == b'x'));
+    }
+
+    fn request_message(opcode: fuse_opcode, unique: u64, arg: &[u8]) -> Vec<u8> {
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + arg.len()) as u32,
+            opcode: opcode as u32,
+            unique,
+            nodeid: 1,
+            uid: 0,
+            gid: 0,
+            pid: 1,
+            padding: 0,
+        };
+        let mut msg = header.as_bytes().to_vec();
+        msg.extend_from_slice(arg);
+        msg
+    }
+
+    const BUFSIZE: usize = BUFFER_HEADER_SIZE + MIN_MAX_WRITE as usize;
+
+    fn read_request<R>(
+        reader: R,
+        bufsize: usize,
+    ) -> Result<Option<(fuse_in_header, Vec<u8>)>, Error>
+    where
+        R: io::Read,
+    {
+        read_request_into(
+            reader,
+            vec![0u8; bufsize - mem::size_of::<fuse_in_header>()],
+        )
+    }
+
+    #[test]
+    fn read_request_skips_enoent() {
+        let conn = FaultyConn::new();
+        conn.push_message(&request_message(fuse_opcode::FUSE_GETATTR, 4, &[0; 16]));
+        conn.read_fault(Fault::Error(libc::ENOENT));
+
+        let (header, arg) = read_request(&conn, BUFSIZE).unwrap().unwrap();
+        assert_eq!(header.unique, 4);
+        assert_eq!(arg.len(), 16);
+    }
+
+    #[test]
+    fn read_request_enodev() {
+        let conn = FaultyConn::new();
+        conn.push_message(&request_message(fuse_opcode::FUSE_GETATTR, 4, &[0; 16]));
+        conn.read_fault(Fault::Error(libc::ENODEV));
+        assert!(read_request(&conn, BUFSIZE).unwrap().is_none());
+
+        // No more messages, i.e. unmounted.
+        let conn = FaultyConn::new();
+        assert!(read_request(&conn, BUFSIZE).unwrap().is_none());
+    }
+
+    #[test]
+    fn read_request_short_message() {
+        let conn = FaultyConn::new();
+        conn.push_message(&request_message(fuse_opcode::FUSE_GETATTR, 4, &[0; 16]));
+        conn.read_fault(Fault::Short(mem::size_of::<fuse_in_header>() - 1));
+
+        let err = read_request(&conn, BUFSIZE).err().unwrap();
+        assert!(matches!(err, Error::Protocol(..)), "{:?}", err);
+    }
+
+    #[test]
+    fn read_request_retries_eintr() {
+        let conn = FaultyConn::new();
+        conn.push_message(&request_message(fuse_opcode::FUSE_GETATTR, 4, &[0; 16]));
+        conn.read_fault(Fault::Error(libc::EINTR));
+
+        let (header, _) = read_request(&conn, BUFSIZE).unwrap().unwrap();
+        assert_eq!(header.unique, 4);
+    }
+
+    #[test]
+    fn read_request_length_mismatch() {
+        let mut msg = request_message(fuse_opcode::FUSE_GETATTR, 4, &[0; 16]);
+        msg[0..4].copy_from_slice(&100u32.to_ne_bytes());
+        let conn = FaultyConn::new();
+        conn.push_message(&msg);
+
+        let err = read_request(&conn, BUFSIZE).err().unwrap();
+        assert!(matches!(err, Error::Protocol(..)), "{:?}", err);
+    }
+
+    #[test]
+    fn read_request_reports_other_errors() {
+        for &errno in &[libc::EAGAIN, libc::EIO] {
+            let conn = FaultyConn::new();
+            conn.push_message(&request_message(fuse_opcode::FUSE_GETATTR, 4, &[0; 16]));
+            conn.read_fault(Fault::Error(errno));
+
+            let err = read_request(&conn, BUFSIZE).err().unwrap();
+            assert_eq!(err.raw_os_error(), Some(errno));
+
+            // The message is still available at the next call.
+            assert!(read_request(&conn, BUFSIZE).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn write_bytes_torn() {
+        let conn = FaultyConn::new();
+        conn.write_fault(Fault::Short(10));
+        let err = write_bytes(&conn, Reply::new(2, 0, "hello".as_bytes())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(conn.take_output()[0].len(), 10);
+
+        conn.write_fault(Fault::Error(libc::ENOENT));
+        let err = write_bytes(&conn, Reply::new(2, 0, ())).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    }
+
+    #[test]
+    fn write_reply_aborted() {
+        let conn = FaultyConn::new();
+        conn.write_fault(Fault::Error(libc::ENOENT));
+        let replied = write_reply(&conn, Reply::new(2, 0, "hello".as_bytes())).unwrap();
+        assert_eq!(replied, Replied::Dropped);
+        assert!(conn.take_output().is_empty());
+
+        conn.write_fault(Fault::Error(libc::EINTR));
+        let replied = write_reply(&conn, Reply::new(3, 0, "hello".as_bytes())).unwrap();
+        assert_eq!(replied, Replied::Sent);
+        let output = conn.take_output();
+        assert_eq!(output.len(), 1, "interrupted write must be retried");
+        assert_eq!(output[0][8..16], 3u64.to_ne_bytes());
+
+        conn.write_fault(Fault::Error(libc::ENODEV));
+        let err = write_reply(&conn, Reply::new(4, 0, ())).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENODEV));
+    }
+
+    #[test]
+    fn init_session_rejects_requests_before_init() {
+        let init_in = fuse_init_in {
+            major: 7,
+            minor: 23,
+            max_readahead: 40,
+            flags: 0,
+        };
+
+        let conn = FaultyConn::new();
+        conn.push_message(&request_message(fuse_opcode::FUSE_GETATTR, 2, &[0; 16]));
+        conn.push_message(&request_message(
+            fuse_opcode::FUSE_INIT,
+            4,
+            init_in.as_bytes(),
+        ));
+
+        let mut init_out = default_init_out();
+        init_session(&mut init_out, u16::MAX, &conn, &conn).unwrap();
+
+        let output = conn.take_output();
+        assert_eq!(output.len(), 2);
+        assert_eq!(output[0].len(), mem::size_of::<fuse_out_header>());
+        assert_eq!(output[0][4..8], (-libc::EIO).to_ne_bytes(), "header.error");
+        assert_eq!(output[1][8..16], 4u64.to_ne_bytes(), "header.unique");
+    }
+
+    #[test]
+    fn init_session_closed() {
+        let conn = FaultyConn::new();
+        let mut init_out = default_init_out();
+        let err = init_session(&mut init_out, u16::MAX, &conn, &conn).unwrap_err();
+        assert!(matches!(err, Error::Closed), "{:?}", err);
+    }
+
+    #[test]
+    fn init_background_limits() {
+        let init_in = fuse_init_in {
+            major: 7,
+            minor: 23,
+            max_readahead: 40,
+            flags: INIT_FLAGS_MASK,
+        };
+        let input = request_message(fuse_opcode::FUSE_INIT, 2, init_in.as_bytes());
+        let init = |max_background, congestion_threshold| {
+            let mut config = KernelConfig::default();
+            config
+                .congestion_threshold(congestion_threshold)
+                .max_background(max_background);
+            let mut init_out = config.init_out;
+            init_session(&mut init_out, u16::MAX, &input[..], io::sink()).unwrap();
+            (init_out.max_background, init_out.congestion_threshold)
+        };
+
+        assert_eq!(init(0, 0), (12, 9));
+        assert_eq!(init(100, 0), (100, 75));
+        assert_eq!(init(100, 20), (100, 20));
+        assert_eq!(init(10, 20), (10, 10));
+        assert_eq!(init(u16::MAX, 0), (u16::MAX, 49151));
+    }
+
+    #[test]
+    fn init_max_pages_limit() {
+        let init = |flags, max_pages_limit, max_write| {
+            let init_in = fuse_init_in {
+                major: 7,
+                minor: 23,
+                max_readahead: 40,
+                flags,
+            };
+            let input = request_message(fuse_opcode::FUSE_INIT, 2, init_in.as_bytes());
+            let mut config = KernelConfig::default();
+            config.max_write(max_write);
+            let mut init_out = config.init_out;
+            init_session(&mut init_out, max_pages_limit, &input[..], io::sink()).unwrap();
+            (init_out.max_write, init_out.max_pages)
+        };
+        let page = pagesize() as u32;
+
+        // The default limit of the kernel.
+        assert_eq!(
+            init(FUSE_MAX_PAGES, 256, DEFAULT_MAX_WRITE),
+            (256 * page, 256)
+        );
+        // The raised limit.
+        let max_write = 64 * 1024 * 1024;
+        assert_eq!(
+            init(FUSE_MAX_PAGES, u16::MAX, max_write),
+            (max_write, (max_write / page) as u16)
+        );
+        // A smaller value is kept.
+        assert_eq!(init(FUSE_MAX_PAGES, 256, 8 * page), (8 * page, 8));
+        // The kernel without FUSE_MAX_PAGES.
+        assert_eq!(init(0, u16::MAX, DEFAULT_MAX_WRITE), (32 * page, 0));
+    }
+
+    #[test]
+    fn init_session_aborted() {
+        let conn = FaultyConn::new();
+        conn.read_fault(Fault::Error(libc::EINTR));
+        let mut init_out = default_init_out();
+        let err = init_session(&mut init_out, u16::MAX, &conn, &conn).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINTR));
+    }
+
+    #[test]
+    fn decode_malformed_requests() {
+        let decode = |msg: &[u8]| {
+            let mut header = fuse_in_header::default();
+            header
+                .as_bytes_mut()
+                .copy_from_slice(&msg[..mem::size_of::<fuse_in_header>()]);
+            decode_request(&header, &msg[mem::size_of::<fuse_in_header>()..]).map(|_| ())
+        };
+
+        // The argument is shorter than fuse_write_in.
+        let msg = request_message(fuse_opcode::FUSE_WRITE, 2, &[0; 8]);
+        assert!(decode(&msg).is_err());
+
+        // The length in the header mismatches.
+        let mut msg = request_message(fuse_opcode::FUSE_LOOKUP, 2, b"foo\0");
+        msg[0] += 1;
+        assert!(decode(&msg).is_err());
+
+        // Missing NUL terminator.
+        let msg = request_message(fuse_opcode::FUSE_LOOKUP, 2, b"foo");
+        assert!(decode(&msg).is_err());
+
+        // The data is shorter than the size claimed in fuse_write_in.
+        let write_in = fuse_write_in {
+            size: 16,
+            ..Default::default()
+        };
+        let mut arg = write_in.as_bytes().to_vec();
+        arg.extend_from_slice(&[0; 8]);
+        let msg = request_message(fuse_opcode::FUSE_WRITE, 2, &arg);
+        assert!(decode(&msg).is_err());
+
+        arg.extend_from_slice(&[0; 8]);
+        let msg = request_message(fuse_opcode::FUSE_WRITE, 2, &arg);
+        assert!(decode(&msg).is_ok());
+
+        // The input data of ioctl is shorter than in_size.
+        let ioctl_in = fuse_ioctl_in {
+            in_size: 8,
+            ..Default::default()
+        };
+        let mut arg = ioctl_in.as_bytes().to_vec();
+        arg.extend_from_slice(&[0; 4]);
+        let msg = request_message(fuse_opcode::FUSE_IOCTL, 2, &arg);
+        assert!(decode(&msg).is_err());
+    }
+
+    #[test]
+    fn decode_error_context() {
+        let msg = request_message(fuse_opcode::FUSE_GETATTR, 42, &[0xab; 4]);
+        let mut header = fuse_in_header::default();
+        header
+            .as_bytes_mut()
+            .copy_from_slice(&msg[..mem::size_of::<fuse_in_header>()]);
+        let err = decode_request(&header, &msg[mem::size_of::<fuse_in_header>()..])
+            .err()
+            .unwrap();
+
+        assert_eq!(err.opcode(), fuse_opcode::FUSE_GETATTR as u32);
+        assert_eq!(err.unique(), 42);
+        assert_eq!(err.expected_len(), Some(mem::size_of::<fuse_getattr_in>()));
+        assert_eq!(err.available_len(), Some(4));
+        assert_eq!(err.payload(), &[0xab; 4]);
+        assert!(err.to_string().contains("unique=42"), "{}", err);
+    }
+
+    #[test]
+    fn decode_ioctl() {
+        let ioctl_in = fuse_ioctl_in {
+            fh: 3,
+            flags: FUSE_IOCTL_UNRESTRICTED,
+            cmd: 0x1234,
+            arg: 0xdead_beef,
+            in_size: 4,
+            out_size: 16,
+        };
+        let mut arg = ioctl_in.as_bytes().to_vec();
+        arg.extend_from_slice(b"abcd");
+        let msg = request_message(fuse_opcode::FUSE_IOCTL, 2, &arg);
+
+        let mut header = fuse_in_header::default();
+        header
+            .as_bytes_mut()
+            .copy_from_slice(&msg[..mem::size_of::<fuse_in_header>()]);
+        match decode_request(&header, &msg[mem::size_of::<fuse_in_header>()..]) {
+            Ok(Operation::Ioctl(op)) => {
+                assert_eq!(op.fh(), 3);
+                assert_eq!(op.cmd(), 0x1234);
+                assert_eq!(op.arg(), 0xdead_beef);
+                assert_eq!(op.in_data(), b"abcd");
+                assert_eq!(op.out_size(), 16);
+                assert!(op.is_unrestricted());
+                assert!(!op.is_compat());
+            }
+            _ => panic!("unexpected operation"),
+        }
+    }
+
+    #[test]
+    fn decode_lseek() {
+        let lseek_in = fuse_lseek_in {
+            fh: 3,
+            offset: 4096,
+            whence: libc::SEEK_HOLE as u32,
+            padding: 0,
+        };
+        let msg = request_message(fuse_opcode::FUSE_LSEEK, 2, lseek_in.as_bytes());
+
+        let mut header = fuse_in_header::default();
+        header
+            .as_bytes_mut()
+            .copy_from_slice(&msg[..mem::size_of::<fuse_in_header>()]);
+        match decode_request(&header, &msg[mem::size_of::<fuse_in_header>()..]) {
+            Ok(Operation::Lseek(op)) => {
+                assert_eq!(op.fh(), 3);
+                assert_eq!(op.offset(), 4096);
+                assert_eq!(op.whence(), libc::SEEK_HOLE as u32);
+            }
+            _ => panic!("unexpected operation"),
+        }
+    }
+
+    #[test]
+    fn hexdump_format() {
+        assert_eq!(
+            hexdump(b"0123456789abcdef\0\xff"),
+            "00000000  30 31 32 33 34 35 36 37  38 39 61 62 63 64 65 66  |0123456789abcdef|\n\
+             00000010  00 ff                                             |..|\n"
+        );
+        assert_eq!(hexdump(b""), "");
+    }
 }
@@ -0,0 +1,211 @@
+//! Statistics of the requests processed by a session.
+//!
+//! This module is available with the `latency-stats` feature.
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// The number of histogram buckets, covering the latencies up to 2^63 ns.
+const BUCKETS: usize = 64;
+
+/// The opcodes tracked by the session, above all those defined so far.
+const OPCODES: usize = 64;
+
+/// Return the index of the bucket containing the latency of `nanos`.
+///
+/// The bucket `i` contains the latencies in `[2^(i-1), 2^i)` nanoseconds,
+/// and the bucket 0 contains only zero.
+#[inline]
+fn bucket(nanos: u64) -> usize {
+    let i = (64 - nanos.leading_zeros()) as usize;
+    if i < BUCKETS {
+        i
+    } else {
+        BUCKETS - 1
+    }
+}
+
+/// The latency histograms of all opcodes, updated by the session.
+pub(crate) struct LatencyRecorder {
+    counts: Box<[AtomicU64]>,
+    sums: Box<[AtomicU64]>,
+}
+
+impl LatencyRecorder {
+    pub(crate) fn new() -> Self {
+        Self {
+            counts: (0..OPCODES * BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            sums: (0..OPCODES).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// Record the latency of a request with the specified opcode.
+    ///
+    /// The unknown opcodes outside the tracked range are ignored.
+    pub(crate) fn record(&self, opcode: u32, latency: Duration) {
+        let opcode = opcode as usize;
+        if opcode >= OPCODES {
+            return;
+        }
+        let nanos = latency.as_nanos().min(u64::MAX as u128) as u64;
+        self.counts[opcode * BUCKETS + bucket(nanos)].fetch_add(1, Ordering::Relaxed);
+        self.sums[opcode].fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> Stats {
+        let mut latencies = vec![];
+        for opcode in 0..OPCODES {
+            let mut hist = LatencyHistogram {
+                counts: [0; BUCKETS],
+                sum: self.sums[opcode].load(Ordering::Relaxed),
+            };
+            for (i, count) in hist.counts.iter_mut().enumerate() {
+                *count = self.counts[opcode * BUCKETS + i].load(Ordering::Relaxed);
+            }
+            if hist.count() > 0 {
+                latencies.push((opcode as u32, hist));
+            }
+        }
+        Stats { latencies }
+    }
+}
+
+/// A snapshot of the statistics of a session, obtained by
+/// [`Session::stats`](crate::Session::stats).
+#[derive(Debug, Clone)]
+pub struct Stats {
+    latencies: Vec<(u32, LatencyHistogram)>,
+}
+
+impl Stats {
+    /// Return the latency histogram of the requests with the specified opcode.
+    ///
+    /// `None` is returned if no such request has been replied to.
+    pub fn latency(&self, opcode: u32) -> Option<&LatencyHistogram> {
+        self.latencies
+            .iter()
+            .find(|(op, _)| *op == opcode)
+            .map(|(_, hist)| hist)
+    }
+
+    /// Iterate over the latency histograms of the opcodes that have been
+    /// replied to at least once, in ascending order of the opcodes.
+    pub fn latencies(&self) -> impl Iterator<Item = (u32, &LatencyHistogram)> + '_ {
+        self.latencies.iter().map(|(op, hist)| (*op, hist))
+    }
+}
+
+/// The histogram of the latencies of a request type.
+///
+/// The latency is measured from the time the request has been read from the
+/// kernel until its reply is written, and is recorded into the buckets with
+/// the power-of-two boundaries. The quantiles are therefore estimated within
+/// a factor of two.
+#[derive(Clone)]
+pub struct LatencyHistogram {
+    counts: [u64; BUCKETS],
+    sum: u64,
+}
+
+impl fmt::Debug for LatencyHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LatencyHistogram")
+            .field("count", &self.count())
+            .field("mean", &self.mean())
+            .field("p50", &self.quantile(0.5))
+            .field("p99", &self.quantile(0.99))
+            .field("max", &self.quantile(1.0))
+            .finish()
+    }
+}
+
+impl LatencyHistogram {
+    /// Return the number of the recorded requests.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Return the mean latency.
+    pub fn mean(&self) -> Duration {
+        match self.count() {
+            0 => Duration::default(),
+            count => Duration::from_nanos(self.sum / count),
+        }
+    }
+
+    /// Return the upper bound of the latency below which the fraction `q`
+    /// of the requests fall, e.g. `quantile(0.99)` for the 99th percentile.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `q` is not in the range `0.0..=1.0`.
+    pub fn quantile(&self, q: f64) -> Duration {
+        assert!((0.0..=1.0).contains(&q), "the quantile must be in 0..=1");
+
+        let count = self.count();
+        let target = ((q * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (upper, n) in self.buckets() {
+            seen += n;
+            if seen >= target {
+                return upper;
+            }
+        }
+        Duration::default()
+    }
+
+    /// Iterate over the buckets of the histogram, as the pairs of the
+    /// exclusive upper bound and the number of the requests.
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(i, count)| (Duration::from_nanos(1 << i), *count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_boundaries() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(1), 1);
+        assert_eq!(bucket(2), 2);
+        assert_eq!(bucket(3), 2);
+        assert_eq!(bucket(1024), 11);
+        assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+    }
+
+    #[test]
+    fn record_latencies() {
+        let recorder = LatencyRecorder::new();
+        for _ in 0..99 {
+            recorder.record(3, Duration::from_micros(10));
+        }
+        recorder.record(3, Duration::from_millis(10));
+        recorder.record(15, Duration::from_nanos(0));
+        recorder.record(4096, Duration::from_secs(1));
+
+        let stats = recorder.snapshot();
+        assert_eq!(
+            stats.latencies().map(|(op, _)| op).collect::<Vec<_>>(),
+            [3, 15]
+        );
+        assert!(stats.latency(4).is_none());
+
+        let hist = stats.latency(3).unwrap();
+        assert_eq!(hist.count(), 100);
+        assert_eq!(hist.mean(), Duration::from_nanos(109_900));
+        assert_eq!(hist.quantile(0.5), Duration::from_nanos(16384));
+        assert_eq!(hist.quantile(0.99), Duration::from_nanos(16384));
+        assert_eq!(hist.quantile(1.0), Duration::from_nanos(16_777_216));
+
+        let hist = stats.latency(15).unwrap();
+        assert_eq!(hist.quantile(0.0), Duration::from_nanos(1));
+    }
+}
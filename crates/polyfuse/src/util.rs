@@ -0,0 +1,48 @@
+//! Building blocks for implementing filesystems.
+
+mod cache;
+mod dirent;
+mod export;
+mod handle;
+mod inode;
+mod inval;
+mod mountopt;
+mod open;
+mod poll;
+mod probe;
+mod rmw;
+mod serial;
+mod setattr;
+mod signal;
+mod supervisor;
+mod time;
+mod watch;
+mod worker;
+
+#[cfg(target_os = "linux")]
+pub mod harden;
+#[cfg(target_os = "linux")]
+pub mod passthrough;
+
+pub use self::{
+    cache::PageCache,
+    dirent::{DirEntries, DirSnapshot},
+    export::ExportTable,
+    handle::{HandleTable, UnknownHandle},
+    inode::InodeTable,
+    inval::{InvalBatcher, InvalFlusher},
+    mountopt::{FuseOptions, MountArgs},
+    open::{OpenCounter, Released},
+    poll::PollHandle,
+    probe::{KernelFeature, KernelProbe},
+    rmw::{align_write, BlockWrite},
+    serial::{mutated_inodes, InodeSerializer, InodeTicket},
+    setattr::apply_setattr,
+    signal::ShutdownSignals,
+    supervisor::{MountRecord, MountSupervisor},
+    time::TimeGran,
+    watch::DirWatcher,
+    worker::{WorkerOptions, WorkerPool},
+};
+
+pub(crate) use self::mountopt::split_mount_options;
@@ -0,0 +1,305 @@
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    sync::{Arc, Mutex},
+};
+
+/// The default size of the blocks in the cache.
+const DEFAULT_BLOCK_SIZE: usize = 128 * 1024;
+
+/// An LRU cache of the file contents in the daemon.
+///
+/// When the page cache of the kernel is bypassed, e.g. with
+/// [`OpenOut::direct_io`](crate::reply::OpenOut::direct_io), every read
+/// reaches the filesystem. The cache keeps the recently read blocks of the
+/// files so that the backend is consulted only on misses:
+///
+/// ```ignore
+/// let cache = Arc::new(PageCache::new(64 * 1024 * 1024));
+/// let mut batcher = InvalBatcher::new(session.notifier());
+/// batcher.page_cache(cache.clone());
+///
+/// Operation::Read(op) => {
+///     let data = cache.read(op.ino(), op.offset(), op.size() as usize, |offset, len| {
+///         backend.read(op.ino(), offset, len)
+///     })?;
+///     req.reply(data)?;
+/// }
+/// ```
+///
+/// The changed ranges added to the [`InvalBatcher`](super::InvalBatcher)
+/// given the cache are dropped from it immediately, before the notifications
+/// are sent to the kernel. The cache can also be invalidated directly with
+/// [`invalidate`](Self::invalidate).
+pub struct PageCache {
+    block_size: usize,
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    blocks: BTreeMap<(u64, u64), Block>,
+    lru: BTreeMap<u64, (u64, u64)>,
+    tick: u64,
+    size: usize,
+    // Incremented on each invalidation, so that the blocks fetched from the
+    // backend before an invalidation are not inserted after it.
+    epoch: u64,
+}
+
+struct Block {
+    data: Arc<[u8]>,
+    tick: u64,
+}
+
+impl fmt::Debug for PageCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = self.inner.lock().unwrap();
+        f.debug_struct("PageCache")
+            .field("block_size", &self.block_size)
+            .field("capacity", &self.capacity)
+            .field("blocks", &inner.blocks.len())
+            .field("size", &inner.size)
+            .finish()
+    }
+}
+
+impl PageCache {
+    /// Create an empty cache holding up to `capacity` bytes.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            block_size: DEFAULT_BLOCK_SIZE,
+            capacity,
+            inner: Mutex::new(Inner {
+                blocks: BTreeMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+                size: 0,
+                epoch: 0,
+            }),
+        }
+    }
+
+    /// Set the size of the blocks fetched from the backend.
+    ///
+    /// The default is 128 KiB.
+    pub fn block_size(&mut self, block_size: usize) -> &mut Self {
+        assert!(block_size > 0, "the block size must be positive");
+        self.inner.get_mut().unwrap().clear();
+        self.block_size = block_size;
+        self
+    }
+
+    /// Return the number of the cached blocks.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().blocks.len()
+    }
+
+    /// Return whether no blocks are cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return the total size of the cached blocks in bytes.
+    pub fn size(&self) -> usize {
+        self.inner.lock().unwrap().size
+    }
+
+    /// Read the range of the file, fetching the missing blocks with `fetch`.
+    ///
+    /// `fetch` is called with the offset and the size of a block, and
+    /// returns its contents. A block shorter than requested denotes the end
+    /// of file, so the returned data may be shorter than `size`. The cache
+    /// is not locked while fetching the blocks.
+    pub fn read<F>(&self, ino: u64, offset: u64, size: usize, mut fetch: F) -> io::Result<Vec<u8>>
+    where
+        F: FnMut(u64, usize) -> io::Result<Vec<u8>>,
+    {
+        let block_size = self.block_size as u64;
+        let end = offset.saturating_add(size as u64);
+        let mut data = Vec::with_capacity(size);
+        let mut index = offset / block_size;
+        while let Some(block_offset) = index.checked_mul(block_size).filter(|&o| o < end) {
+            let block = match self.get(ino, index) {
+                Some(block) => block,
+                None => {
+                    let epoch = self.inner.lock().unwrap().epoch;
+                    let mut block = fetch(block_offset, self.block_size)?;
+                    block.truncate(self.block_size);
+                    let block: Arc<[u8]> = block.into();
+                    self.insert(ino, index, block.clone(), epoch);
+                    block
+                }
+            };
+
+            let start = offset.saturating_sub(block_offset) as usize;
+            let stop = std::cmp::min((end - block_offset) as usize, block.len());
+            if start < stop {
+                data.extend_from_slice(&block[start..stop]);
+            }
+            if block.len() < self.block_size {
+                break;
+            }
+            index += 1;
+        }
+        Ok(data)
+    }
+
+    /// Drop the cached blocks overlapping the range of the file.
+    ///
+    /// A `len` of `u64::MAX` denotes the range extending to the end of file.
+    pub fn invalidate(&self, ino: u64, offset: u64, len: u64) {
+        if len == 0 {
+            return;
+        }
+        let block_size = self.block_size as u64;
+        let first = offset / block_size;
+        let last = match offset.checked_add(len) {
+            Some(end) if len != u64::MAX => (end - 1) / block_size,
+            _ => u64::MAX,
+        };
+        let mut inner = self.inner.lock().unwrap();
+        inner.epoch += 1;
+        inner.remove_range((ino, first), (ino, last));
+    }
+
+    /// Drop all the cached blocks of the file.
+    pub fn invalidate_inode(&self, ino: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.epoch += 1;
+        inner.remove_range((ino, 0), (ino, u64::MAX));
+    }
+
+    /// Drop all the cached blocks.
+    pub fn clear(&self) {
+        self.inner.lock().unwrap().clear();
+    }
+
+    fn get(&self, ino: u64, index: u64) -> Option<Arc<[u8]>> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let block = inner.blocks.get_mut(&(ino, index))?;
+        inner.lru.remove(&block.tick);
+        inner.tick += 1;
+        block.tick = inner.tick;
+        inner.lru.insert(block.tick, (ino, index));
+        Some(block.data.clone())
+    }
+
+    fn insert(&self, ino: u64, index: u64, data: Arc<[u8]>, epoch: u64) {
+        if data.len() > self.capacity {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if inner.epoch != epoch {
+            return;
+        }
+        inner.remove_range((ino, index), (ino, index));
+        while inner.size + data.len() > self.capacity {
+            let key = match inner.lru.values().next() {
+                Some(&key) => key,
+                None => break,
+            };
+            inner.remove_range(key, key);
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.size += data.len();
+        inner.lru.insert(tick, (ino, index));
+        inner.blocks.insert((ino, index), Block { data, tick });
+    }
+}
+
+impl Inner {
+    fn remove_range(&mut self, first: (u64, u64), last: (u64, u64)) {
+        let keys: Vec<_> = self.blocks.range(first..=last).map(|(&k, _)| k).collect();
+        for key in keys {
+            if let Some(block) = self.blocks.remove(&key) {
+                self.lru.remove(&block.tick);
+                self.size -= block.data.len();
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.epoch += 1;
+        self.blocks.clear();
+        self.lru.clear();
+        self.size = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(capacity: usize) -> PageCache {
+        let mut cache = PageCache::new(capacity);
+        cache.block_size(4);
+        cache
+    }
+
+    /// Read from a file of 10 bytes, counting the fetched blocks.
+    fn read(cache: &PageCache, ino: u64, offset: u64, size: usize, fetched: &mut usize) -> Vec<u8> {
+        let content: Vec<u8> = (0..10).collect();
+        cache
+            .read(ino, offset, size, |offset, len| {
+                *fetched += 1;
+                let start = std::cmp::min(offset as usize, content.len());
+                let end = std::cmp::min(start + len, content.len());
+                Ok(content[start..end].to_vec())
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn read_through() {
+        let cache = cache(1024);
+        let mut fetched = 0;
+        assert_eq!(read(&cache, 1, 2, 5, &mut fetched), [2, 3, 4, 5, 6]);
+        assert_eq!(fetched, 2);
+        assert_eq!(
+            read(&cache, 1, 0, 8, &mut fetched),
+            [0, 1, 2, 3, 4, 5, 6, 7]
+        );
+        assert_eq!(fetched, 2);
+
+        // Stops at the end of file.
+        assert_eq!(read(&cache, 1, 6, 100, &mut fetched), [6, 7, 8, 9]);
+        assert_eq!(fetched, 3);
+        assert!(read(&cache, 1, 20, 4, &mut fetched).is_empty());
+        assert_eq!(fetched, 4);
+        assert_eq!(cache.size(), 10);
+
+        cache.invalidate(1, 5, 1);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(
+            read(&cache, 1, 0, 8, &mut fetched),
+            [0, 1, 2, 3, 4, 5, 6, 7]
+        );
+        assert_eq!(fetched, 5);
+
+        cache.invalidate(1, 4, u64::MAX);
+        assert_eq!(cache.len(), 1);
+        cache.invalidate_inode(1);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn evict_lru() {
+        let cache = cache(8);
+        let mut fetched = 0;
+        read(&cache, 1, 0, 4, &mut fetched);
+        read(&cache, 2, 0, 4, &mut fetched);
+        read(&cache, 1, 0, 4, &mut fetched);
+        read(&cache, 3, 0, 4, &mut fetched);
+        assert_eq!(fetched, 3);
+        assert_eq!(cache.size(), 8);
+
+        // The block of the inode 2 was the least recently used.
+        read(&cache, 1, 0, 4, &mut fetched);
+        assert_eq!(fetched, 3);
+        read(&cache, 2, 0, 4, &mut fetched);
+        assert_eq!(fetched, 4);
+    }
+}
@@ -0,0 +1,414 @@
+use crate::reply::ReaddirOut;
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::{OsStr, OsString},
+    fmt, mem,
+    ops::Bound,
+    os::unix::prelude::*,
+};
+
+/// The largest cookie, since the offsets are signed in the kernel.
+const MAX_COOKIE: u64 = i64::MAX as u64;
+
+/// A list of directory entries with stable offsets.
+///
+/// Each entry is assigned a *cookie* when it is inserted, which is
+/// monotonically increasing and never reused during the lifetime of the
+/// list. The cookie is used as the offset of the entry in `Readdir`, so
+/// the position of a directory stream (as seen by `telldir(3)`/`seekdir(3)`)
+/// stays valid even if the entries are inserted or removed between the
+/// `Readdir` requests:
+///
+/// * the entries that have already been returned are never returned again,
+/// * the entries that exist during the whole listing are always returned,
+/// * the entries inserted during the listing are returned at the end.
+///
+/// Alternatively, the list created by [`with_hashed_cookies`] derives the
+/// cookies from the hashes of the names, like the hashed directories of
+/// ext4. Since the cookie of a name does not depend on the history of the
+/// list, the positions remain valid even if the list is rebuilt from the
+/// backend, e.g. on every `Opendir`, or an entry is removed and inserted
+/// again during the listing. The entries inserted during the listing are
+/// returned only if their cookies are located after the current position.
+///
+/// [`with_hashed_cookies`]: DirEntries::with_hashed_cookies
+pub struct DirEntries<T> {
+    entries: BTreeMap<u64, Entry<T>>,
+    names: HashMap<OsString, u64>,
+    next_cookie: u64,
+    hashed: bool,
+}
+
+struct Entry<T> {
+    name: OsString,
+    value: T,
+}
+
+impl<T> fmt::Debug for DirEntries<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DirEntries")
+            .field("len", &self.entries.len())
+            .field("next_cookie", &self.next_cookie)
+            .field("hashed", &self.hashed)
+            .finish()
+    }
+}
+
+impl<T> Default for DirEntries<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> DirEntries<T> {
+    /// Create an empty list.
+    pub fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            names: HashMap::new(),
+            // The offset 0 means the beginning of the stream.
+            next_cookie: 1,
+            hashed: false,
+        }
+    }
+
+    /// Create an empty list assigning the cookies hashed from the names.
+    ///
+    /// The entries are listed in the order of the hashes. If the hashes of
+    /// two names collide, the later one is assigned the next free cookie.
+    pub fn with_hashed_cookies() -> Self {
+        Self {
+            hashed: true,
+            ..Self::new()
+        }
+    }
+
+    /// Return the number of entries.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Return whether the list has no entries.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Insert an entry with the specified name.
+    ///
+    /// If an entry with the same name already exists, its value is
+    /// replaced while keeping its position, and the old value is returned.
+    pub fn insert(&mut self, name: impl Into<OsString>, value: T) -> Option<T> {
+        let name = name.into();
+        if let Some(cookie) = self.names.get(&name) {
+            let entry = self.entries.get_mut(cookie).expect("inconsistent entries");
+            return Some(std::mem::replace(&mut entry.value, value));
+        }
+
+        let cookie = if self.hashed {
+            let mut cookie = name_hash(&name);
+            while self.entries.contains_key(&cookie) {
+                cookie = cookie % MAX_COOKIE + 1;
+            }
+            cookie
+        } else {
+            let cookie = self.next_cookie;
+            self.next_cookie += 1;
+            cookie
+        };
+        self.names.insert(name.clone(), cookie);
+        self.entries.insert(cookie, Entry { name, value });
+        None
+    }
+
+    /// Remove the entry with the specified name.
+    pub fn remove(&mut self, name: &OsStr) -> Option<T> {
+        let cookie = self.names.remove(name)?;
+        self.entries.remove(&cookie).map(|entry| entry.value)
+    }
+
+    /// Return whether the entry with the specified name exists.
+    #[inline]
+    pub fn contains(&self, name: &OsStr) -> bool {
+        self.names.contains_key(name)
+    }
+
+    /// Get a reference to the value of the entry.
+    pub fn get(&self, name: &OsStr) -> Option<&T> {
+        let cookie = self.names.get(name)?;
+        self.entries.get(cookie).map(|entry| &entry.value)
+    }
+
+    /// Get a mutable reference to the value of the entry.
+    pub fn get_mut(&mut self, name: &OsStr) -> Option<&mut T> {
+        let cookie = self.names.get(name)?;
+        self.entries.get_mut(cookie).map(|entry| &mut entry.value)
+    }
+
+    /// Return the cookie assigned to the entry.
+    #[inline]
+    pub fn cookie(&self, name: &OsStr) -> Option<u64> {
+        self.names.get(name).copied()
+    }
+
+    /// Return an iterator over the entries in the order of their cookies.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &OsStr, &T)> + '_ {
+        self.iter_from(0)
+    }
+
+    /// Return an iterator over the entries located after the specified offset.
+    pub fn iter_from(&self, offset: u64) -> impl Iterator<Item = (u64, &OsStr, &T)> + '_ {
+        self.entries
+            .range((Bound::Excluded(offset), Bound::Unbounded))
+            .map(|(&cookie, entry)| (cookie, &*entry.name, &entry.value))
+    }
+
+    /// Fill the reply of `Readdir` with the entries after the specified offset.
+    ///
+    /// The closure `f` returns the inode number and the file type of
+    /// the entry. The entries are filled until the reply becomes full.
+    pub fn fill<F>(&self, offset: u64, out: &mut ReaddirOut, mut f: F)
+    where
+        F: FnMut(&T) -> (u64, u32),
+    {
+        for (cookie, name, value) in self.iter_from(offset) {
+            let (ino, typ) = f(value);
+            if out.entry(name, ino, typ, cookie) {
+                break;
+            }
+        }
+    }
+}
+
+/// A view of [`DirEntries`] fixed per directory handle.
+///
+/// The listing of [`DirEntries`] reflects the modifications made while
+/// the directory stream is open. When a consistent view is required, a
+/// `DirSnapshot` created on `Opendir` and kept with the handle copies the
+/// entries when the stream is read from the beginning, i.e. on the first
+/// `Readdir` and after `rewinddir(3)`. The subsequent `Readdir` requests
+/// are served from the copy, so that `seekdir(3)` to any position
+/// returned by `telldir(3)` lists the same entries regardless of the
+/// modifications, and `rewinddir(3)` picks up the current state of the
+/// directory as required by POSIX.
+///
+/// The memory used by the copy is limited to the specified number of
+/// bytes per handle. If the directory is larger than that, the handle
+/// falls back to the live listing with stable offsets until the next
+/// rewind.
+pub struct DirSnapshot<T> {
+    max_bytes: usize,
+    entries: Option<Vec<(u64, OsString, T)>>,
+}
+
+impl<T> fmt::Debug for DirSnapshot<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DirSnapshot")
+            .field("max_bytes", &self.max_bytes)
+            .field("len", &self.entries.as_ref().map(Vec::len))
+            .finish()
+    }
+}
+
+impl<T> DirSnapshot<T> {
+    /// Create a view that copies at most `max_bytes` of the entries.
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            entries: None,
+        }
+    }
+
+    /// Return whether the entries are served from the copy.
+    #[inline]
+    pub fn is_taken(&self) -> bool {
+        self.entries.is_some()
+    }
+
+    /// Fill the reply of `Readdir` with the entries after the specified offset.
+    ///
+    /// The copy of `entries` is taken again if the offset is zero. The
+    /// closure `f` is the same as that of [`DirEntries::fill`].
+    pub fn fill<F>(&mut self, entries: &DirEntries<T>, offset: u64, out: &mut ReaddirOut, mut f: F)
+    where
+        T: Clone,
+        F: FnMut(&T) -> (u64, u32),
+    {
+        if offset == 0 {
+            self.take(entries);
+        }
+        let snapshot = match self.entries {
+            Some(ref snapshot) => snapshot,
+            None => return entries.fill(offset, out, f),
+        };
+        let start = match snapshot.binary_search_by_key(&offset, |&(cookie, _, _)| cookie) {
+            Ok(pos) => pos + 1,
+            Err(pos) => pos,
+        };
+        for (cookie, name, value) in &snapshot[start..] {
+            let (ino, typ) = f(value);
+            if out.entry(name, ino, typ, *cookie) {
+                break;
+            }
+        }
+    }
+
+    fn take(&mut self, entries: &DirEntries<T>)
+    where
+        T: Clone,
+    {
+        self.entries = None;
+        let mut size = 0;
+        for (_, name, _) in entries.iter() {
+            size += mem::size_of::<(u64, OsString, T)>() + name.len();
+            if size > self.max_bytes {
+                return;
+            }
+        }
+        self.entries = Some(
+            entries
+                .iter()
+                .map(|(cookie, name, value)| (cookie, name.to_owned(), value.clone()))
+                .collect(),
+        );
+    }
+}
+
+/// Hash the name into a cookie in `1..=MAX_COOKIE` with FNV-1a, which is
+/// stable across the processes unlike the hashers of `std`.
+fn name_hash(name: &OsStr) -> u64 {
+    let hash = name
+        .as_bytes()
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, &b| {
+            (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+        });
+    hash % MAX_COOKIE + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names_from(entries: &DirEntries<u64>, offset: u64) -> Vec<&OsStr> {
+        entries.iter_from(offset).map(|(_, name, _)| name).collect()
+    }
+
+    #[test]
+    fn stable_offsets() {
+        let mut entries = DirEntries::new();
+        entries.insert(".", 1);
+        entries.insert("..", 1);
+        entries.insert("a", 2);
+        entries.insert("b", 3);
+        entries.insert("c", 4);
+
+        let offset = entries.cookie(OsStr::new("a")).unwrap();
+
+        // Modifications between the readdir calls.
+        assert_eq!(entries.remove(OsStr::new("a")), Some(2));
+        assert_eq!(entries.remove(OsStr::new("c")), Some(4));
+        entries.insert("d", 5);
+        assert_eq!(entries.insert("b", 6), Some(3));
+
+        assert_eq!(names_from(&entries, offset), vec!["b", "d"]);
+        assert_eq!(entries.get(OsStr::new("b")), Some(&6));
+        assert_eq!(entries.len(), 4);
+    }
+
+    #[test]
+    fn cookies_are_not_reused() {
+        let mut entries = DirEntries::new();
+        entries.insert("a", 1);
+        let first = entries.cookie(OsStr::new("a")).unwrap();
+        entries.remove(OsStr::new("a"));
+        entries.insert("a", 1);
+        assert!(entries.cookie(OsStr::new("a")).unwrap() > first);
+        assert_eq!(names_from(&entries, first), vec!["a"]);
+    }
+
+    #[test]
+    fn hashed_cookies() {
+        let mut entries = DirEntries::with_hashed_cookies();
+        for (i, name) in ["a", "b", "c", "d"].iter().enumerate() {
+            entries.insert(*name, i as u64);
+        }
+        let listed: Vec<_> = entries.iter().map(|(cookie, _, _)| cookie).collect();
+        let (offset, returned, _) = entries.iter().nth(1).unwrap();
+        let returned = returned.to_owned();
+
+        // The entry removed and inserted again keeps its position.
+        entries.remove(&returned);
+        entries.insert(returned.clone(), 10);
+        let rest = names_from(&entries, offset);
+        assert!(!rest.contains(&&*returned));
+        assert_eq!(rest.len(), 2);
+
+        // The cookies do not depend on the order of the insertions.
+        let mut rebuilt = DirEntries::with_hashed_cookies();
+        for name in &["d", "c", "b", "a"] {
+            rebuilt.insert(*name, 0);
+        }
+        let relisted: Vec<_> = rebuilt.iter().map(|(cookie, _, _)| cookie).collect();
+        assert_eq!(listed, relisted);
+        assert!(listed
+            .iter()
+            .all(|&cookie| (1..=MAX_COOKIE).contains(&cookie)));
+    }
+
+    #[test]
+    fn hash_collision() {
+        let mut entries = DirEntries::with_hashed_cookies();
+        entries.insert("a", 1);
+        let cookie = entries.cookie(OsStr::new("a")).unwrap();
+        // Occupy the cookie of "b" with another entry.
+        let taken = name_hash(OsStr::new("b"));
+        let entry = entries.entries.remove(&cookie).unwrap();
+        entries.entries.insert(taken, entry);
+        entries.names.insert("a".into(), taken);
+
+        entries.insert("b", 2);
+        assert_eq!(
+            entries.cookie(OsStr::new("b")),
+            Some(taken % MAX_COOKIE + 1)
+        );
+        assert_eq!(entries.get(OsStr::new("b")), Some(&2));
+    }
+
+    #[test]
+    fn snapshot_view() {
+        let mut entries = DirEntries::new();
+        for (i, name) in ["a", "b", "c"].iter().enumerate() {
+            entries.insert(*name, i as u64);
+        }
+        let mut snapshot = DirSnapshot::new(4096);
+        let mut out = ReaddirOut::new(4096);
+        snapshot.fill(&entries, 0, &mut out, |&v| (v + 1, 0));
+        assert!(snapshot.is_taken());
+        let offset = entries.cookie(OsStr::new("a")).unwrap();
+
+        // Modifications between the readdir calls are not visible.
+        entries.remove(OsStr::new("b"));
+        entries.insert("d", 3);
+        let listed = |snapshot: &DirSnapshot<u64>, offset| -> Vec<OsString> {
+            let snapshot = snapshot.entries.as_ref().unwrap();
+            snapshot
+                .iter()
+                .filter(|&&(cookie, _, _)| cookie > offset)
+                .map(|(_, name, _)| name.clone())
+                .collect()
+        };
+        snapshot.fill(&entries, offset, &mut out, |&v| (v + 1, 0));
+        assert_eq!(listed(&snapshot, offset), ["b", "c"]);
+
+        // Rewinding picks up the current state.
+        snapshot.fill(&entries, 0, &mut out, |&v| (v + 1, 0));
+        assert_eq!(listed(&snapshot, 0), ["a", "c", "d"]);
+
+        // The directory larger than the limit is listed live.
+        let mut snapshot = DirSnapshot::new(mem::size_of::<(u64, OsString, u64)>() * 2);
+        snapshot.fill(&entries, 0, &mut out, |&v| (v + 1, 0));
+        assert!(!snapshot.is_taken());
+    }
+}
@@ -0,0 +1,244 @@
+use crate::errno::Errno;
+use std::{collections::HashMap, ffi::OsStr, fmt, hash::Hash};
+
+/// The inode number of the root directory.
+const ROOT_INO: u64 = 1;
+
+/// A persistent mapping between the inode numbers and the objects of the
+/// backend, for the filesystems re-exported over NFS.
+///
+/// With [`KernelConfig::export_support`](crate::KernelConfig::export_support),
+/// the NFS server resolves its file handles by the inode numbers alone, long
+/// after the kernel has forgotten them. The kernel then sends `Lookup` of
+/// `"."` to find the inode itself, and `Lookup` of `".."` to find the parent
+/// of a directory. Unlike [`InodeTable`](super::InodeTable), the table keeps
+/// the mapping regardless of the lookup counts, and records the parent of
+/// each inode so that both lookups can be answered:
+///
+/// ```ignore
+/// Operation::Lookup(op) => {
+///     let ino = match exports.lookup_dot(op.parent(), op.name()) {
+///         Some(res) => res?,
+///         None => {
+///             let key = fs.backend_id(op.parent(), op.name())?;
+///             exports.insert(op.parent(), key)
+///         }
+///     };
+///     let mut out = req.entry_out();
+///     out.ino(ino);
+///     fs.fill_attr(exports.key(ino).unwrap(), out.attr())?;
+///     req.reply(out)?;
+/// }
+/// ```
+///
+/// The key identifies an object of the backend that outlives the daemon,
+/// such as the pair of the device and the inode number of a passthrough
+/// filesystem. The inode numbers are never reused, and the mapping can be
+/// saved with [`iter`](Self::iter) and [`restore`](Self::restore)d after a
+/// restart of the daemon so that the file handles held by the NFS clients
+/// stay valid.
+pub struct ExportTable<K> {
+    nodes: HashMap<u64, Node<K>>,
+    inos: HashMap<K, u64>,
+    next_ino: u64,
+}
+
+struct Node<K> {
+    key: K,
+    parent: u64,
+}
+
+impl<K> fmt::Debug for ExportTable<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExportTable")
+            .field("len", &self.nodes.len())
+            .field("next_ino", &self.next_ino)
+            .finish()
+    }
+}
+
+impl<K> ExportTable<K>
+where
+    K: Hash + Eq + Clone,
+{
+    /// Create a table containing only the root directory.
+    ///
+    /// The parent of the root directory is the root itself.
+    pub fn new(root: K) -> Self {
+        let mut table = Self {
+            nodes: HashMap::new(),
+            inos: HashMap::new(),
+            next_ino: ROOT_INO + 1,
+        };
+        table.inos.insert(root.clone(), ROOT_INO);
+        table.nodes.insert(
+            ROOT_INO,
+            Node {
+                key: root,
+                parent: ROOT_INO,
+            },
+        );
+        table
+    }
+
+    /// Return the number of inodes in the table, including the root.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Return whether the table is empty.
+    ///
+    /// This is always `false`, since the root directory cannot be removed.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Return the inode number of the object found in the directory,
+    /// allocating a new one if the object is not in the table.
+    ///
+    /// The parent of the inode is updated to `parent`, so this should
+    /// also be called when the object has been renamed.
+    pub fn insert(&mut self, parent: u64, key: K) -> u64 {
+        if let Some(&ino) = self.inos.get(&key) {
+            if ino != ROOT_INO {
+                if let Some(node) = self.nodes.get_mut(&ino) {
+                    node.parent = parent;
+                }
+            }
+            return ino;
+        }
+
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.inos.insert(key.clone(), ino);
+        self.nodes.insert(ino, Node { key, parent });
+        ino
+    }
+
+    /// Restore the mapping saved by a previous run of the daemon.
+    ///
+    /// The mapping of `ino` or `key` already in the table is replaced.
+    pub fn restore(&mut self, ino: u64, parent: u64, key: K) {
+        if let Some(node) = self.nodes.remove(&ino) {
+            self.inos.remove(&node.key);
+        }
+        if let Some(old) = self.inos.insert(key.clone(), ino) {
+            self.nodes.remove(&old);
+        }
+        self.nodes.insert(ino, Node { key, parent });
+        if ino >= self.next_ino {
+            self.next_ino = ino + 1;
+        }
+    }
+
+    /// Return the key of the object associated with the inode.
+    #[inline]
+    pub fn key(&self, ino: u64) -> Option<&K> {
+        self.nodes.get(&ino).map(|node| &node.key)
+    }
+
+    /// Return the inode number associated with the object.
+    #[inline]
+    pub fn ino(&self, key: &K) -> Option<u64> {
+        self.inos.get(key).copied()
+    }
+
+    /// Return the inode number of the directory containing the inode.
+    #[inline]
+    pub fn parent(&self, ino: u64) -> Option<u64> {
+        self.nodes.get(&ino).map(|node| node.parent)
+    }
+
+    /// Resolve the lookups of `"."` and `".."` sent by the kernel.
+    ///
+    /// `None` is returned for the other names, which should be looked up
+    /// in the backend. The inodes not in the table, e.g. the ones removed
+    /// before a restart of the daemon, fail with `ESTALE` so that the NFS
+    /// clients drop their file handles.
+    pub fn lookup_dot(&self, parent: u64, name: &OsStr) -> Option<Result<u64, Errno>> {
+        let res = match name.to_str() {
+            Some(".") => self.key(parent).map(|_| parent),
+            Some("..") => self.parent(parent),
+            _ => return None,
+        };
+        Some(res.ok_or_else(|| Errno::stale().context("unknown inode in the file handle")))
+    }
+
+    /// Remove the inode when the object has been deleted from the backend.
+    ///
+    /// The root directory cannot be removed.
+    pub fn remove(&mut self, ino: u64) -> Option<K> {
+        if ino == ROOT_INO {
+            return None;
+        }
+        let node = self.nodes.remove(&ino)?;
+        self.inos.remove(&node.key);
+        Some(node.key)
+    }
+
+    /// Return an iterator over the inode numbers, the parents and the keys
+    /// in the table, in arbitrary order.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u64, &K)> + '_ {
+        self.nodes
+            .iter()
+            .map(|(&ino, node)| (ino, node.parent, &node.key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_dots() {
+        let mut table = ExportTable::new("/");
+        let dir = table.insert(1, "/a");
+        let file = table.insert(dir, "/a/b");
+        assert_eq!(table.insert(dir, "/a/b"), file);
+        assert_eq!(table.len(), 3);
+
+        let dot = |table: &ExportTable<_>, ino, name: &str| {
+            table
+                .lookup_dot(ino, OsStr::new(name))
+                .map(|res| res.map_err(|err| err.code()))
+        };
+        assert_eq!(dot(&table, file, "."), Some(Ok(file)));
+        assert_eq!(dot(&table, file, ".."), Some(Ok(dir)));
+        assert_eq!(dot(&table, dir, ".."), Some(Ok(1)));
+        assert_eq!(dot(&table, 1, ".."), Some(Ok(1)));
+        assert_eq!(dot(&table, dir, "b"), None);
+        assert_eq!(dot(&table, 42, "."), Some(Err(libc::ESTALE)));
+
+        // Renamed into the root directory.
+        assert_eq!(table.insert(1, "/a/b"), file);
+        assert_eq!(table.parent(file), Some(1));
+
+        assert_eq!(table.remove(file), Some("/a/b"));
+        assert_eq!(table.ino(&"/a/b"), None);
+        assert_ne!(table.insert(1, "/a/b"), file);
+        assert_eq!(table.remove(1), None);
+    }
+
+    #[test]
+    fn restore() {
+        let mut table = ExportTable::new(0);
+        let a = table.insert(1, 10);
+        let b = table.insert(a, 20);
+        let mut saved = table.iter().collect::<Vec<_>>();
+        saved.sort();
+        let saved = saved
+            .into_iter()
+            .map(|(ino, parent, &key)| (ino, parent, key))
+            .collect::<Vec<_>>();
+
+        let mut table = ExportTable::new(0);
+        for &(ino, parent, key) in &saved {
+            table.restore(ino, parent, key);
+        }
+        assert_eq!(table.key(b), Some(&20));
+        assert_eq!(table.parent(b), Some(a));
+        assert_eq!(table.insert(a, 30), b + 1);
+    }
+}
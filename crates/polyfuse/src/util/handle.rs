@@ -0,0 +1,218 @@
+use std::{
+    error, fmt, io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// A concurrent table of the handles of opened files or directories.
+///
+/// The handle allocated by [`insert`](HandleTable::insert) is intended to be
+/// replied as the `fh` of `Open`/`Opendir` and is released on
+/// `Release`/`Releasedir`. The lower 32 bits of the handle is the index of the
+/// slot and the upper 32 bits is its generation, which is incremented each time
+/// the slot is released, so that a stale handle is never confused with the one
+/// reusing the same slot.
+///
+/// If the kernel sends a handle that is not in the table, the table is marked
+/// as *poisoned*. It means that the state of the filesystem is inconsistent
+/// with the kernel, and it can be checked with
+/// [`is_poisoned`](HandleTable::is_poisoned).
+pub struct HandleTable<T> {
+    inner: Mutex<Inner<T>>,
+    poisoned: AtomicBool,
+}
+
+struct Inner<T> {
+    slots: Vec<Slot<T>>,
+    vacant: Vec<u32>,
+    len: usize,
+}
+
+struct Slot<T> {
+    generation: u32,
+    value: Option<Arc<T>>,
+}
+
+impl<T> fmt::Debug for HandleTable<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HandleTable")
+            .field("len", &self.len())
+            .field("poisoned", &self.is_poisoned())
+            .finish()
+    }
+}
+
+impl<T> Default for HandleTable<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> HandleTable<T> {
+    /// Create an empty handle table.
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                slots: Vec::new(),
+                vacant: Vec::new(),
+                len: 0,
+            }),
+            poisoned: AtomicBool::new(false),
+        }
+    }
+
+    /// Return the number of handles in the table.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().len
+    }
+
+    /// Return whether the table has no handles.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return whether an unknown handle has been passed to the table.
+    #[inline]
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Acquire)
+    }
+
+    /// Allocate a new handle associated with the value.
+    pub fn insert(&self, value: T) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        let value = Some(Arc::new(value));
+        let index = match inner.vacant.pop() {
+            Some(index) => {
+                inner.slots[index as usize].value = value;
+                index
+            }
+            None => {
+                let index = inner.slots.len() as u32;
+                inner.slots.push(Slot {
+                    generation: 0,
+                    value,
+                });
+                index
+            }
+        };
+        inner.len += 1;
+        make_handle(index, inner.slots[index as usize].generation)
+    }
+
+    /// Get the value associated with the handle.
+    pub fn get(&self, fh: u64) -> Result<Arc<T>, UnknownHandle> {
+        let (index, generation) = split_handle(fh);
+        let inner = self.inner.lock().unwrap();
+        let value = inner
+            .slots
+            .get(index as usize)
+            .filter(|slot| slot.generation == generation)
+            .and_then(|slot| slot.value.clone());
+        drop(inner);
+        value.ok_or_else(|| self.poison(fh))
+    }
+
+    /// Release the handle and return the associated value.
+    pub fn remove(&self, fh: u64) -> Result<Arc<T>, UnknownHandle> {
+        let (index, generation) = split_handle(fh);
+        let mut inner = self.inner.lock().unwrap();
+        let value = match inner.slots.get_mut(index as usize) {
+            Some(slot) if slot.generation == generation && slot.value.is_some() => {
+                slot.generation = slot.generation.wrapping_add(1);
+                slot.value.take()
+            }
+            _ => None,
+        };
+        match value {
+            Some(value) => {
+                inner.vacant.push(index);
+                inner.len -= 1;
+                Ok(value)
+            }
+            None => {
+                drop(inner);
+                Err(self.poison(fh))
+            }
+        }
+    }
+
+    fn poison(&self, fh: u64) -> UnknownHandle {
+        tracing::error!(fh, "the kernel sent an unknown file handle");
+        self.poisoned.store(true, Ordering::Release);
+        UnknownHandle { fh }
+    }
+}
+
+#[inline]
+fn make_handle(index: u32, generation: u32) -> u64 {
+    (generation as u64) << 32 | index as u64
+}
+
+#[inline]
+fn split_handle(fh: u64) -> (u32, u32) {
+    (fh as u32, (fh >> 32) as u32)
+}
+
+/// The error returned when the handle is not found in the table.
+#[derive(Debug, Clone, Copy)]
+pub struct UnknownHandle {
+    fh: u64,
+}
+
+impl UnknownHandle {
+    pub(crate) fn new(fh: u64) -> Self {
+        Self { fh }
+    }
+
+    /// Return the value of the unknown handle.
+    #[inline]
+    pub fn fh(&self) -> u64 {
+        self.fh
+    }
+}
+
+impl fmt::Display for UnknownHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown file handle: {:#x}", self.fh)
+    }
+}
+
+impl error::Error for UnknownHandle {}
+
+impl From<UnknownHandle> for io::Error {
+    fn from(_: UnknownHandle) -> Self {
+        io::Error::from_raw_os_error(libc::EBADF)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_and_remove() {
+        let table = HandleTable::new();
+        let fh1 = table.insert("foo");
+        let fh2 = table.insert("bar");
+        assert_ne!(fh1, fh2);
+        assert_eq!(*table.get(fh1).unwrap(), "foo");
+        assert_eq!(*table.remove(fh2).unwrap(), "bar");
+        assert_eq!(table.len(), 1);
+        assert!(!table.is_poisoned());
+    }
+
+    #[test]
+    fn stale_handle() {
+        let table = HandleTable::new();
+        let fh1 = table.insert("foo");
+        assert!(table.remove(fh1).is_ok());
+
+        let fh2 = table.insert("bar");
+        assert_ne!(fh1, fh2);
+        assert!(table.get(fh1).is_err());
+        assert!(table.is_poisoned());
+        assert_eq!(*table.get(fh2).unwrap(), "bar");
+    }
+}
@@ -0,0 +1,343 @@
+//! Dropping the privileges of the daemon after the filesystem is mounted.
+//!
+//! A FUSE daemon parses the requests from arbitrary processes that access
+//! the mountpoint, so it should run with the least privileges once the
+//! connection has been established. [`Hardening`] bundles the usual steps:
+//!
+//! ```ignore
+//! let session = Session::mount(mountpoint, config)?;
+//! Hardening::new().seccomp(true).apply()?;
+//! while let Some(req) = session.next_request()? {
+//!     // ...
+//! }
+//! ```
+//!
+//! The capabilities, `NO_NEW_PRIVS` and the seccomp filter apply to the
+//! calling thread and the threads spawned afterwards, so the hardening must
+//! be applied before spawning any threads, including the worker pools and
+//! the threads of the asynchronous runtimes.
+//!
+//! `NO_NEW_PRIVS` disables the setuid bit of `fusermount`, which the session
+//! runs to unmount the filesystem on drop. The unmount is then left to the
+//! `fusermount` process started at mount time with `auto_unmount`, which is
+//! enabled by default.
+
+use std::io;
+
+// copied from <linux/capability.h>
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+// copied from <linux/filter.h> and <linux/seccomp.h>
+const BPF_LD_W_ABS: u16 = 0x20; // BPF_LD | BPF_W | BPF_ABS
+const BPF_JMP_JEQ_K: u16 = 0x15; // BPF_JMP | BPF_JEQ | BPF_K
+const BPF_JMP_JGE_K: u16 = 0x35; // BPF_JMP | BPF_JGE | BPF_K
+const BPF_RET_K: u16 = 0x06; // BPF_RET | BPF_K
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+// copied from <linux/audit.h>
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+// The syscalls of the x32 ABI, which share the architecture with x86_64.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: Option<u32> = Some(0x4000_0000);
+#[cfg(not(target_arch = "x86_64"))]
+const X32_SYSCALL_BIT: Option<u32> = None;
+
+/// The syscalls denied by the seccomp filter, which have no use in a FUSE
+/// daemon but widen the attack surface of the kernel.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_setns,
+    libc::SYS_unshare,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_kexec_load,
+    libc::SYS_kexec_file_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_acct,
+    libc::SYS_settimeofday,
+    libc::SYS_clock_settime,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_keyctl,
+];
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const DENIED_SYSCALLS: &[libc::c_long] = &[];
+
+/// The steps of dropping the privileges of the daemon.
+///
+/// By default, all capabilities are dropped, including the bounding and
+/// the ambient sets, and `NO_NEW_PRIVS` is set so that they cannot be
+/// regained by executing the setuid programs. The seccomp filter is
+/// disabled by default.
+#[derive(Debug, Clone)]
+pub struct Hardening {
+    keep_caps: u64,
+    no_new_privs: bool,
+    seccomp: bool,
+    denied: Vec<libc::c_long>,
+}
+
+impl Default for Hardening {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hardening {
+    /// Create the default steps of hardening.
+    pub fn new() -> Self {
+        Self {
+            keep_caps: 0,
+            no_new_privs: true,
+            seccomp: false,
+            denied: vec![],
+        }
+    }
+
+    /// Keep the capability, such as `CAP_DAC_READ_SEARCH` (2) required by
+    /// `open_by_handle_at(2)`, in the effective and permitted sets.
+    pub fn keep_capability(&mut self, cap: u32) -> &mut Self {
+        assert!(cap < 64, "invalid capability: {}", cap);
+        self.keep_caps |= 1 << cap;
+        self
+    }
+
+    /// Specify whether to set `NO_NEW_PRIVS`.
+    pub fn no_new_privs(&mut self, enabled: bool) -> &mut Self {
+        self.no_new_privs = enabled;
+        self
+    }
+
+    /// Specify whether to install the seccomp filter.
+    ///
+    /// The filter denies the syscalls that have nothing to do with serving
+    /// a filesystem, such as `mount(2)`, `ptrace(2)` and loading kernel
+    /// modules, with `EPERM`, and kills the process on the syscalls of the
+    /// foreign architectures. It is supported on x86_64 and aarch64,
+    /// and `apply` fails with `ENOTSUP` on the other architectures.
+    pub fn seccomp(&mut self, enabled: bool) -> &mut Self {
+        self.seccomp = enabled;
+        self
+    }
+
+    /// Deny the syscall in addition to the default ones, e.g.
+    /// `libc::SYS_execve` if the daemon never executes other programs.
+    ///
+    /// This implies [`seccomp(true)`](Self::seccomp).
+    pub fn deny_syscall(&mut self, nr: libc::c_long) -> &mut Self {
+        self.denied.push(nr);
+        self.seccomp = true;
+        self
+    }
+
+    /// Apply the hardening to the calling thread.
+    pub fn apply(&self) -> io::Result<()> {
+        if self.no_new_privs {
+            prctl(libc::PR_SET_NO_NEW_PRIVS, 1)?;
+        }
+
+        // Installing a filter without NO_NEW_PRIVS requires CAP_SYS_ADMIN,
+        // so it must precede the dropping of the capabilities.
+        if self.seccomp {
+            self.install_filter()?;
+        }
+
+        self.drop_capabilities()
+    }
+
+    fn drop_capabilities(&self) -> io::Result<()> {
+        for cap in 0..64 {
+            if self.keep_caps & (1 << cap) != 0 {
+                continue;
+            }
+            match prctl(libc::PR_CAPBSET_DROP, cap) {
+                Ok(()) => (),
+                // Beyond the last capability supported by the kernel.
+                Err(ref err) if err.raw_os_error() == Some(libc::EINVAL) => break,
+                // Without CAP_SETPCAP, i.e. an unprivileged daemon, which
+                // cannot regain the capabilities as long as NO_NEW_PRIVS is set.
+                Err(ref err) if err.raw_os_error() == Some(libc::EPERM) => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        match prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_CLEAR_ALL as libc::c_ulong,
+        ) {
+            // The ambient set is not supported before Linux 4.3.
+            Err(ref err) if err.raw_os_error() == Some(libc::EINVAL) => (),
+            res => res?,
+        }
+
+        let header = CapUserHeader {
+            version: LINUX_CAPABILITY_VERSION_3,
+            pid: 0,
+        };
+        let data = [
+            CapUserData::new(self.keep_caps as u32),
+            CapUserData::new((self.keep_caps >> 32) as u32),
+        ];
+        let res = unsafe { libc::syscall(libc::SYS_capset, &header, data.as_ptr()) };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn install_filter(&self) -> io::Result<()> {
+        // The filter is not supported on this architecture.
+        let arch = AUDIT_ARCH.ok_or_else(|| io::Error::from_raw_os_error(libc::ENOTSUP))?;
+        let mut filter = build_filter(arch, DENIED_SYSCALLS.iter().chain(&self.denied));
+        let prog = libc::sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_mut_ptr(),
+        };
+        let res = unsafe {
+            libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER as libc::c_ulong,
+                &prog as *const libc::sock_fprog,
+            )
+        };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+impl CapUserData {
+    fn new(caps: u32) -> Self {
+        Self {
+            effective: caps,
+            permitted: caps,
+            inheritable: 0,
+        }
+    }
+}
+
+fn prctl(option: libc::c_int, arg: libc::c_ulong) -> io::Result<()> {
+    let res = unsafe { libc::prctl(option, arg, 0, 0, 0) };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn bpf_stmt(code: u16, k: u32) -> libc::sock_filter {
+    bpf_jump(code, k, 0, 0)
+}
+
+fn bpf_jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+/// Build the filter that returns `EPERM` for the denied syscalls.
+fn build_filter<'a, I>(arch: u32, denied: I) -> Vec<libc::sock_filter>
+where
+    I: IntoIterator<Item = &'a libc::c_long>,
+{
+    let denied: Vec<u32> = denied.into_iter().map(|&nr| nr as u32).collect();
+
+    let mut filter = vec![
+        bpf_stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+        bpf_jump(BPF_JMP_JEQ_K, arch, 1, 0),
+        bpf_stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+        bpf_stmt(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+    ];
+
+    // The jumps are relative to the next instruction, and the last two
+    // instructions are the allow and the deny returns.
+    let checks = denied.len() + X32_SYSCALL_BIT.map_or(0, |_| 1);
+    assert!(checks < 255, "too many denied syscalls");
+    let mut remaining = checks;
+    if let Some(bit) = X32_SYSCALL_BIT {
+        remaining -= 1;
+        filter.push(bpf_jump(BPF_JMP_JGE_K, bit, remaining as u8 + 1, 0));
+    }
+    for nr in denied {
+        remaining -= 1;
+        filter.push(bpf_jump(BPF_JMP_JEQ_K, nr, remaining as u8 + 1, 0));
+    }
+
+    filter.push(bpf_stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+    filter.push(bpf_stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+    filter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run the filter on the syscall, as the kernel does.
+    fn run(filter: &[libc::sock_filter], arch: u32, nr: u32) -> u32 {
+        let mut acc = 0;
+        let mut pc = 0;
+        loop {
+            let insn = &filter[pc];
+            pc += 1;
+            match insn.code {
+                BPF_LD_W_ABS if insn.k == SECCOMP_DATA_ARCH => acc = arch,
+                BPF_LD_W_ABS if insn.k == SECCOMP_DATA_NR => acc = nr,
+                BPF_JMP_JEQ_K | BPF_JMP_JGE_K => {
+                    let taken = match insn.code {
+                        BPF_JMP_JEQ_K => acc == insn.k,
+                        _ => acc >= insn.k,
+                    };
+                    pc += if taken { insn.jt } else { insn.jf } as usize;
+                }
+                BPF_RET_K => return insn.k,
+                code => panic!("unexpected instruction: {:#x}", code),
+            }
+        }
+    }
+
+    #[test]
+    fn filter() {
+        let filter = build_filter(0xc000_003e, &[165, 101]);
+        let eperm = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+        assert_eq!(run(&filter, 0xc000_003e, 165), eperm);
+        assert_eq!(run(&filter, 0xc000_003e, 101), eperm);
+        assert_eq!(run(&filter, 0xc000_003e, 0), SECCOMP_RET_ALLOW);
+        assert_eq!(run(&filter, 0x4000_0003, 0), SECCOMP_RET_KILL_PROCESS);
+        if let Some(bit) = X32_SYSCALL_BIT {
+            assert_eq!(run(&filter, 0xc000_003e, bit), eperm);
+        }
+    }
+}
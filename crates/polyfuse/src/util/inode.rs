@@ -0,0 +1,277 @@
+use crate::{op::Forget, reply::EntryOut};
+use std::fmt;
+
+/// The inode number of the root directory.
+const ROOT_INO: u64 = 1;
+
+/// A table of inodes that tracks the lookup counts of each entry.
+///
+/// The inodes are allocated from a slab, and the inode number is derived
+/// from the index of the slot (the first allocated entry is assigned to
+/// the root inode, `ino = 1`). When a slot is reused after the previous
+/// inode has been released, its generation number is incremented so that
+/// the pair of `(ino, generation)` stays unique during the lifetime of
+/// the filesystem.
+///
+/// The lookup count of an entry is incremented each time the entry is
+/// replied to the kernel through [`lookup`](InodeTable::lookup), and is
+/// decremented by [`forget`](InodeTable::forget). The entry is released
+/// when the count drops to zero, except for the root inode.
+pub struct InodeTable<T> {
+    slots: Vec<Slot<T>>,
+    vacant: Vec<usize>,
+    len: usize,
+}
+
+struct Slot<T> {
+    generation: u64,
+    entry: Option<Entry<T>>,
+}
+
+struct Entry<T> {
+    nlookup: u64,
+    value: T,
+}
+
+impl<T> fmt::Debug for InodeTable<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InodeTable")
+            .field("len", &self.len)
+            .field("capacity", &self.slots.len())
+            .finish()
+    }
+}
+
+impl<T> Default for InodeTable<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> InodeTable<T> {
+    /// Create an empty inode table.
+    pub fn new() -> Self {
+        Self {
+            slots: Vec::new(),
+            vacant: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// Return the number of inodes in the table.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Return whether the table has no inodes.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Insert a new inode and return its inode number.
+    ///
+    /// The lookup count of the inserted inode is initialized to zero.
+    pub fn insert(&mut self, value: T) -> u64 {
+        let entry = Entry { nlookup: 0, value };
+        let index = match self.vacant.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index];
+                debug_assert!(slot.entry.is_none());
+                slot.entry = Some(entry);
+                index
+            }
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    entry: Some(entry),
+                });
+                self.slots.len() - 1
+            }
+        };
+        self.len += 1;
+        index as u64 + ROOT_INO
+    }
+
+    #[inline]
+    fn slot(&self, ino: u64) -> Option<&Slot<T>> {
+        let index = ino.checked_sub(ROOT_INO)?;
+        self.slots.get(index as usize)
+    }
+
+    #[inline]
+    fn entry(&self, ino: u64) -> Option<&Entry<T>> {
+        self.slot(ino)?.entry.as_ref()
+    }
+
+    #[inline]
+    fn entry_mut(&mut self, ino: u64) -> Option<&mut Entry<T>> {
+        let index = ino.checked_sub(ROOT_INO)?;
+        self.slots.get_mut(index as usize)?.entry.as_mut()
+    }
+
+    /// Return whether the table contains the specified inode.
+    #[inline]
+    pub fn contains(&self, ino: u64) -> bool {
+        self.entry(ino).is_some()
+    }
+
+    /// Get a reference to the value associated with the inode.
+    #[inline]
+    pub fn get(&self, ino: u64) -> Option<&T> {
+        self.entry(ino).map(|entry| &entry.value)
+    }
+
+    /// Get a mutable reference to the value associated with the inode.
+    #[inline]
+    pub fn get_mut(&mut self, ino: u64) -> Option<&mut T> {
+        self.entry_mut(ino).map(|entry| &mut entry.value)
+    }
+
+    /// Return the generation number of the inode.
+    #[inline]
+    pub fn generation(&self, ino: u64) -> Option<u64> {
+        let slot = self.slot(ino)?;
+        slot.entry.as_ref().map(|_| slot.generation)
+    }
+
+    /// Return the current lookup count of the inode.
+    #[inline]
+    pub fn nlookup(&self, ino: u64) -> Option<u64> {
+        self.entry(ino).map(|entry| entry.nlookup)
+    }
+
+    /// Increment the lookup count of the inode and fill the identifiers
+    /// of the entry to be replied.
+    ///
+    /// This method should be called every time an `EntryOut` is replied
+    /// to the kernel, i.e. on `Lookup`, `Mknod`, `Mkdir`, `Symlink`,
+    /// `Link` and `Create`. The returned reference can be used to fill
+    /// the remaining attributes.
+    pub fn lookup(&mut self, ino: u64, out: &mut EntryOut) -> Option<&mut T> {
+        let index = ino.checked_sub(ROOT_INO)? as usize;
+        let slot = self.slots.get_mut(index)?;
+        let entry = slot.entry.as_mut()?;
+        entry.nlookup += 1;
+
+        out.ino(ino);
+        out.generation(slot.generation);
+        out.attr().ino(ino);
+
+        Some(&mut entry.value)
+    }
+
+    /// Decrement the lookup count of the inode without releasing it,
+    /// and return the remaining count.
+    ///
+    /// This is useful when the inode should be kept alive for another
+    /// reason (e.g. it is still linked from a directory).
+    pub fn unref(&mut self, ino: u64, nlookup: u64) -> Option<u64> {
+        let entry = self.entry_mut(ino)?;
+        entry.nlookup = entry.nlookup.saturating_sub(nlookup);
+        Some(entry.nlookup)
+    }
+
+    /// Decrement the lookup count of the inode.
+    ///
+    /// If the count drops to zero, the inode is released from the table
+    /// and the associated value is returned. The root inode is never
+    /// released, since the kernel keeps using it until unmounting.
+    pub fn forget(&mut self, ino: u64, nlookup: u64) -> Option<T> {
+        match self.unref(ino, nlookup)? {
+            0 if ino != ROOT_INO => self.remove(ino),
+            _ => None,
+        }
+    }
+
+    /// Apply a set of `Forget` (or `BatchForget`) requests to the table.
+    ///
+    /// The released values are dropped.
+    pub fn forget_all(&mut self, forgets: &[Forget]) {
+        for forget in forgets {
+            let _ = self.forget(forget.ino(), forget.nlookup());
+        }
+    }
+
+    /// Remove the inode from the table regardless of its lookup count.
+    pub fn remove(&mut self, ino: u64) -> Option<T> {
+        let index = ino.checked_sub(ROOT_INO)? as usize;
+        let slot = self.slots.get_mut(index)?;
+        let entry = slot.entry.take()?;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.vacant.push(index);
+        self.len -= 1;
+        Some(entry.value)
+    }
+
+    /// Return an iterator over the inode numbers and values in the table.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &T)> + '_ {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            slot.entry
+                .as_ref()
+                .map(|entry| (index as u64 + ROOT_INO, &entry.value))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_ino() {
+        let mut table = InodeTable::new();
+        assert_eq!(table.insert("root"), 1);
+        assert_eq!(table.insert("foo"), 2);
+        assert_eq!(table.get(1), Some(&"root"));
+        assert_eq!(table.get(0), None);
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    fn lookup_and_forget() {
+        let mut table = InodeTable::new();
+        let _root = table.insert("root");
+        let ino = table.insert("foo");
+
+        let mut out = EntryOut::default();
+        assert!(table.lookup(ino, &mut out).is_some());
+        assert!(table.lookup(ino, &mut out).is_some());
+        assert_eq!(table.nlookup(ino), Some(2));
+
+        assert_eq!(table.forget(ino, 1), None);
+        assert_eq!(table.nlookup(ino), Some(1));
+        assert_eq!(table.forget(ino, 1), Some("foo"));
+        assert!(!table.contains(ino));
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn pin_root() {
+        let mut table = InodeTable::new();
+        let root = table.insert("root");
+
+        let mut out = EntryOut::default();
+        assert!(table.lookup(root, &mut out).is_some());
+        assert_eq!(table.forget(root, 1), None);
+        assert_eq!(table.forget(root, 1), None);
+        assert_eq!(table.nlookup(root), Some(0));
+        assert_eq!(table.get(root), Some(&"root"));
+    }
+
+    #[test]
+    fn bump_generation_on_reuse() {
+        let mut table = InodeTable::new();
+        let _root = table.insert("root");
+        let ino = table.insert("foo");
+        assert_eq!(table.generation(ino), Some(0));
+
+        assert_eq!(table.remove(ino), Some("foo"));
+        assert_eq!(table.generation(ino), None);
+
+        assert_eq!(table.insert("bar"), ino);
+        assert_eq!(table.generation(ino), Some(1));
+        assert_eq!(table.get(ino), Some(&"bar"));
+    }
+}
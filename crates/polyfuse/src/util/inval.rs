@@ -0,0 +1,310 @@
+use super::PageCache;
+use crate::session::Notifier;
+use std::{
+    collections::HashMap,
+    fmt, io, mem,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// The default maximum number of pending ranges per inode.
+const DEFAULT_MAX_RANGES: usize = 16;
+
+/// A helper for batching the `inval_inode` notifications.
+///
+/// The ranges added by [`add`](InvalBatcher::add) are rounded to the page
+/// boundaries, since the kernel invalidates its page cache by pages, and
+/// merged with the overlapping or adjacent ranges of the same inode. The
+/// merged ranges are sent to the kernel by [`flush`](InvalBatcher::flush),
+/// or periodically by a thread started with
+/// [`spawn_flusher`](InvalBatcher::spawn_flusher):
+///
+/// ```ignore
+/// let batcher = Arc::new(InvalBatcher::new(session.notifier()));
+/// let _flusher = InvalBatcher::spawn_flusher(&batcher, Duration::from_millis(100))?;
+///
+/// // on every change in the backend
+/// batcher.add(ino, offset, len);
+/// ```
+///
+/// Each notification also invalidates the cached attributes of the inode.
+pub struct InvalBatcher {
+    notifier: Notifier,
+    pagesize: u64,
+    max_ranges: usize,
+    dirty: Mutex<HashMap<u64, Ranges>>,
+    cache: Option<Arc<PageCache>>,
+}
+
+impl fmt::Debug for InvalBatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InvalBatcher")
+            .field("max_ranges", &self.max_ranges)
+            .field("pending", &self.len())
+            .finish()
+    }
+}
+
+impl InvalBatcher {
+    /// Create an empty batcher sending the notifications with `notifier`.
+    pub fn new(notifier: Notifier) -> Self {
+        Self {
+            notifier,
+            pagesize: unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 },
+            max_ranges: DEFAULT_MAX_RANGES,
+            dirty: Mutex::default(),
+            cache: None,
+        }
+    }
+
+    /// Set the maximum number of pending ranges per inode.
+    ///
+    /// If an inode has more disjoint ranges than this, they are replaced
+    /// with a single range covering all of them, trading the precision of
+    /// the invalidation for the number of notifications. The default is 16.
+    pub fn max_ranges(&mut self, max_ranges: usize) -> &mut Self {
+        assert!(
+            max_ranges > 0,
+            "the maximum number of ranges must be positive"
+        );
+        self.max_ranges = max_ranges;
+        self
+    }
+
+    /// Set the cache of the daemon that holds the contents of the inodes.
+    ///
+    /// The ranges added to the batcher are dropped from the cache at once,
+    /// so that the following reads reach the backend even before the kernel
+    /// is notified.
+    pub fn page_cache(&mut self, cache: Arc<PageCache>) -> &mut Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Return the number of inodes with pending ranges.
+    pub fn len(&self) -> usize {
+        self.dirty.lock().unwrap().len()
+    }
+
+    /// Return whether no ranges are pending.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Add a changed range of the inode.
+    ///
+    /// A `len` of `u64::MAX` denotes the range extending to the end of file.
+    /// The empty ranges are ignored.
+    pub fn add(&self, ino: u64, offset: u64, len: u64) {
+        if len == 0 {
+            return;
+        }
+        if let Some(ref cache) = self.cache {
+            cache.invalidate(ino, offset, len);
+        }
+        let start = offset / self.pagesize * self.pagesize;
+        let end = match offset.checked_add(len) {
+            Some(end) if len != u64::MAX => round_up(end, self.pagesize),
+            _ => u64::MAX,
+        };
+        self.dirty
+            .lock()
+            .unwrap()
+            .entry(ino)
+            .or_default()
+            .insert(start, end, self.max_ranges);
+    }
+
+    /// Send the pending ranges of all inodes to the kernel.
+    ///
+    /// The inodes not cached by the kernel are skipped silently. If sending a
+    /// notification fails, the ranges not sent yet are kept pending and the
+    /// error is returned.
+    pub fn flush(&self) -> io::Result<()> {
+        let dirty = mem::take(&mut *self.dirty.lock().unwrap());
+        let mut inodes = dirty.into_iter();
+        while let Some((ino, ranges)) = inodes.next() {
+            if let Err((err, rest)) = self.send(ino, ranges) {
+                let mut dirty = self.dirty.lock().unwrap();
+                for (ino, ranges) in Some((ino, rest)).into_iter().chain(inodes) {
+                    let pending = dirty.entry(ino).or_default();
+                    for (start, end) in ranges.0 {
+                        pending.insert(start, end, self.max_ranges);
+                    }
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Send the pending ranges of the inode to the kernel.
+    pub fn flush_inode(&self, ino: u64) -> io::Result<()> {
+        let ranges = match self.dirty.lock().unwrap().remove(&ino) {
+            Some(ranges) => ranges,
+            None => return Ok(()),
+        };
+        self.send(ino, ranges).map_err(|(err, rest)| {
+            let mut dirty = self.dirty.lock().unwrap();
+            let pending = dirty.entry(ino).or_default();
+            for (start, end) in rest.0 {
+                pending.insert(start, end, self.max_ranges);
+            }
+            err
+        })
+    }
+
+    /// Discard the pending ranges of the inode, e.g. when it is forgotten.
+    ///
+    /// The contents of the inode are also dropped from the cache.
+    pub fn remove_inode(&self, ino: u64) {
+        self.dirty.lock().unwrap().remove(&ino);
+        if let Some(ref cache) = self.cache {
+            cache.invalidate_inode(ino);
+        }
+    }
+
+    fn send(&self, ino: u64, ranges: Ranges) -> Result<(), (io::Error, Ranges)> {
+        let mut ranges = ranges.0.into_iter();
+        while let Some((start, end)) = ranges.next() {
+            let (off, len) = to_notify_range(start, end);
+            match self.notifier.inval_inode(ino, off, len) {
+                Ok(()) => (),
+                // The kernel does not have the inode in its cache.
+                Err(ref err) if err.raw_os_error() == Some(libc::ENOENT) => return Ok(()),
+                Err(err) => {
+                    let rest = Some((start, end)).into_iter().chain(ranges).collect();
+                    return Err((err, Ranges(rest)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Spawn a thread flushing the pending ranges at the specified interval.
+    ///
+    /// The thread stops after a final flush when the returned handle is
+    /// dropped. The errors are logged and do not stop the thread.
+    pub fn spawn_flusher(this: &Arc<Self>, interval: Duration) -> io::Result<InvalFlusher> {
+        let batcher = this.clone();
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::Builder::new()
+            .name("polyfuse-inval".into())
+            .spawn(move || loop {
+                let res = stopped.recv_timeout(interval);
+                if let Err(err) = batcher.flush() {
+                    tracing::error!("failed to send the invalidation: {}", err);
+                }
+                if res != Err(RecvTimeoutError::Timeout) {
+                    break;
+                }
+            })?;
+        Ok(InvalFlusher {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+/// The handle of the thread started by [`InvalBatcher::spawn_flusher`].
+#[derive(Debug)]
+pub struct InvalFlusher {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for InvalFlusher {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The disjoint, non-adjacent ranges `[start, end)` sorted by their offsets.
+#[derive(Debug, Default, PartialEq)]
+struct Ranges(Vec<(u64, u64)>);
+
+impl Ranges {
+    fn insert(&mut self, mut start: u64, mut end: u64, max_ranges: usize) {
+        // The ranges overlapping or adjacent to the new one are merged into it.
+        let lo = self.0.iter().take_while(|&&(_, e)| e < start).count();
+        let hi = lo + self.0[lo..].iter().take_while(|&&(s, _)| s <= end).count();
+        if lo < hi {
+            start = start.min(self.0[lo].0);
+            end = end.max(self.0[hi - 1].1);
+        }
+        self.0.splice(lo..hi, Some((start, end)));
+
+        if self.0.len() > max_ranges {
+            let start = self.0[0].0;
+            let end = self.0[self.0.len() - 1].1;
+            self.0.clear();
+            self.0.push((start, end));
+        }
+    }
+}
+
+#[inline]
+fn round_up(n: u64, align: u64) -> u64 {
+    match n % align {
+        0 => n,
+        r => n.saturating_add(align - r),
+    }
+}
+
+/// Convert the range into the arguments of `inval_inode`, where the zero
+/// length denotes the end of file.
+fn to_notify_range(start: u64, end: u64) -> (i64, i64) {
+    let off = start.min(i64::MAX as u64) as i64;
+    let len = end - start;
+    if end == u64::MAX || len > i64::MAX as u64 {
+        (off, 0)
+    } else {
+        (off, len as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(list: &[(u64, u64)], max_ranges: usize) -> Vec<(u64, u64)> {
+        let mut ranges = Ranges::default();
+        for &(start, end) in list {
+            ranges.insert(start, end, max_ranges);
+        }
+        ranges.0
+    }
+
+    #[test]
+    fn merge_ranges() {
+        assert_eq!(ranges(&[(0, 10), (20, 30)], 16), [(0, 10), (20, 30)]);
+        assert_eq!(ranges(&[(0, 10), (10, 20)], 16), [(0, 20)]);
+        assert_eq!(ranges(&[(20, 30), (0, 10), (5, 25)], 16), [(0, 30)]);
+        assert_eq!(
+            ranges(&[(0, 10), (20, 30), (40, 50), (15, 16)], 16),
+            [(0, 10), (15, 16), (20, 30), (40, 50)]
+        );
+        assert_eq!(ranges(&[(40, 50), (0, u64::MAX)], 16), [(0, u64::MAX)]);
+    }
+
+    #[test]
+    fn collapse_ranges() {
+        assert_eq!(ranges(&[(0, 10), (20, 30), (40, 50)], 2), [(0, 50)]);
+    }
+
+    #[test]
+    fn notify_range() {
+        assert_eq!(round_up(0, 4096), 0);
+        assert_eq!(round_up(1, 4096), 4096);
+        assert_eq!(round_up(u64::MAX - 1, 4096), u64::MAX);
+        assert_eq!(to_notify_range(4096, 8192), (4096, 4096));
+        assert_eq!(to_notify_range(0, u64::MAX), (0, 0));
+    }
+}
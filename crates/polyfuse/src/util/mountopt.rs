@@ -0,0 +1,352 @@
+use crate::session::KernelConfig;
+use std::{
+    ffi::{OsStr, OsString},
+    io,
+    path::{Path, PathBuf},
+};
+
+/// The options passed through to `fusermount` and the kernel.
+const KERNEL_FLAGS: &[&str] = &[
+    "rw",
+    "ro",
+    "suid",
+    "nosuid",
+    "dev",
+    "nodev",
+    "exec",
+    "noexec",
+    "async",
+    "sync",
+    "dirsync",
+    "atime",
+    "noatime",
+    "diratime",
+    "nodiratime",
+    "relatime",
+    "norelatime",
+    "strictatime",
+    "nostrictatime",
+    "lazytime",
+    "nolazytime",
+    "default_permissions",
+    "allow_other",
+    "auto_unmount",
+    "blkdev",
+    "nonempty",
+];
+
+/// The options with a value passed through to `fusermount` and the kernel.
+const KERNEL_VALUES: &[&str] = &["fsname", "subtype", "blksize", "max_read"];
+
+/// The options only meaningful to `mount(8)` and fstab, which are dropped.
+const FSTAB_FLAGS: &[&str] = &[
+    "defaults", "auto", "noauto", "nofail", "_netdev", "user", "nouser", "users", "owner", "group",
+];
+
+/// The mount options parsed from a string such as `ro,allow_other,cache=loose`.
+///
+/// The options are classified into those understood by `fusermount` and
+/// the kernel, which are applied to [`KernelConfig`] with
+/// [`apply`](Self::apply), and the options specific to the filesystem,
+/// which are left to the daemon. The options only used by `mount(8)` and
+/// fstab, such as `noauto`, `nofail`, `_netdev` and `x-*`, are dropped.
+///
+/// A comma in a value must be escaped with a backslash, as in the option
+/// strings of `fusermount`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FuseOptions {
+    kernel: Vec<String>,
+    allow_root: bool,
+    fs: Vec<(String, Option<String>)>,
+}
+
+impl FuseOptions {
+    /// Parse a comma-separated list of options.
+    ///
+    /// The option without a name, e.g. `=foo`, is rejected with
+    /// `InvalidInput`. The empty options are ignored.
+    pub fn parse(s: &str) -> io::Result<Self> {
+        let mut opts = Self::default();
+        opts.extend(s)?;
+        Ok(opts)
+    }
+
+    fn extend(&mut self, s: &str) -> io::Result<()> {
+        for option in split_mount_options(s) {
+            let (name, value) = match option.find('=') {
+                Some(pos) => (&option[..pos], Some(&option[pos + 1..])),
+                None => (option, None),
+            };
+            if name.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("the mount option without a name: {:?}", option),
+                ));
+            }
+
+            match (name, value) {
+                ("allow_root", None) => self.allow_root = true,
+                (name, None) if KERNEL_FLAGS.contains(&name) => self.kernel.push(option.into()),
+                (name, Some(..)) if KERNEL_VALUES.contains(&name) => {
+                    self.kernel.push(option.into())
+                }
+                (name, None) if FSTAB_FLAGS.contains(&name) => (),
+                (name, _) if name.starts_with("x-") || name == "comment" => (),
+                (name, value) => self.fs.push((unescape(name), value.map(unescape))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the options passed through to `fusermount`, escaped as given.
+    pub fn kernel_options(&self) -> impl Iterator<Item = &str> + '_ {
+        self.kernel.iter().map(|s| s.as_str())
+    }
+
+    /// Return the options specific to the filesystem, along with their
+    /// unescaped values.
+    pub fn fs_options(&self) -> impl Iterator<Item = (&str, Option<&str>)> + '_ {
+        self.fs
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_deref()))
+    }
+
+    /// Return whether the filesystem-specific option is specified, with or
+    /// without a value.
+    pub fn has(&self, name: &str) -> bool {
+        self.fs.iter().any(|(n, _)| n == name)
+    }
+
+    /// Return the value of the filesystem-specific option, the last one if
+    /// specified more than once.
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.fs
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .and_then(|(_, value)| value.as_deref())
+    }
+
+    /// Return whether the filesystem is mounted read-only.
+    pub fn read_only(&self) -> bool {
+        let last = self
+            .kernel
+            .iter()
+            .rev()
+            .find(|opt| *opt == "ro" || *opt == "rw");
+        matches!(last, Some(opt) if opt == "ro")
+    }
+
+    /// Apply the options to the configuration of the mount.
+    ///
+    /// `allow_root` is turned into `allow_other` along with
+    /// [`KernelConfig::restrict_callers`], since the kernel does not know
+    /// the option.
+    pub fn apply(&self, config: &mut KernelConfig) {
+        for option in &self.kernel {
+            config.mount_option(option);
+        }
+        if self.allow_root {
+            config.mount_option("allow_other").restrict_callers(true);
+        }
+    }
+}
+
+/// The command line of a mount helper, such as `mount.fuse.<subtype>`.
+///
+/// `mount(8)` runs the helper as `<helper> <source> <mountpoint> [-sfnv]
+/// [-o <options>]`, with the options from fstab:
+///
+/// ```ignore
+/// let args = MountArgs::parse(std::env::args_os().skip(1))?;
+/// let mut config = KernelConfig::default();
+/// args.options().apply(&mut config);
+/// let backing = args.source().expect("missing the source");
+/// let cache = args.options().value("cache").unwrap_or("loose");
+/// let session = Session::mount(args.mountpoint().into(), config)?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct MountArgs {
+    source: Option<OsString>,
+    mountpoint: PathBuf,
+    options: FuseOptions,
+}
+
+impl MountArgs {
+    /// Parse the arguments, excluding the name of the program.
+    ///
+    /// The options are given as `-o <options>` or `-o<options>`, possibly
+    /// more than once. The flags `-s`, `-f`, `-n` and `-v` of `mount(8)`
+    /// are ignored. With a single positional argument, it is taken as the
+    /// mountpoint without the source.
+    pub fn parse<I>(args: I) -> io::Result<Self>
+    where
+        I: IntoIterator,
+        I::Item: Into<OsString>,
+    {
+        let mut positional = vec![];
+        let mut options = FuseOptions::default();
+        let mut args = args.into_iter().map(Into::into);
+        while let Some(arg) = args.next() {
+            let arg_str = arg.to_str();
+            match arg_str {
+                Some("-o") => {
+                    let opts = args
+                        .next()
+                        .ok_or_else(|| invalid_args("-o without the options"))?;
+                    let opts = opts
+                        .to_str()
+                        .ok_or_else(|| invalid_args("the options are not valid UTF-8"))?;
+                    options.extend(opts)?;
+                }
+                Some(s) if s.starts_with("-o") => options.extend(&s[2..])?,
+                Some("-s") | Some("-f") | Some("-n") | Some("-v") => (),
+                Some("--") => positional.extend(&mut args),
+                Some(s) if s.starts_with('-') => {
+                    return Err(invalid_args(&format!("unknown flag {}", s)))
+                }
+                _ => positional.push(arg),
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        let (source, mountpoint) = match (positional.next(), positional.next()) {
+            (Some(source), Some(mountpoint)) => (Some(source), mountpoint),
+            (Some(mountpoint), None) => (None, mountpoint),
+            (None, _) => return Err(invalid_args("the mountpoint is missing")),
+        };
+        if positional.next().is_some() {
+            return Err(invalid_args("too many arguments"));
+        }
+
+        Ok(Self {
+            source,
+            mountpoint: mountpoint.into(),
+            options,
+        })
+    }
+
+    /// Return the source of the mount, such as the backing directory.
+    pub fn source(&self) -> Option<&OsStr> {
+        self.source.as_deref()
+    }
+
+    /// Return the mountpoint.
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// Return the mount options.
+    pub fn options(&self) -> &FuseOptions {
+        &self.options
+    }
+}
+
+fn invalid_args(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.to_owned())
+}
+
+/// Split the comma-separated options, keeping the commas escaped with a
+/// backslash.
+pub(crate) fn split_mount_options(s: &str) -> impl Iterator<Item = &str> {
+    let mut rest = s;
+    std::iter::from_fn(move || loop {
+        if rest.is_empty() {
+            return None;
+        }
+        let mut escaped = false;
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| match c {
+                '\\' if !escaped => {
+                    escaped = true;
+                    false
+                }
+                ',' if !escaped => true,
+                _ => {
+                    escaped = false;
+                    false
+                }
+            })
+            .map_or(rest.len(), |(pos, _)| pos);
+        let option = rest[..end].trim();
+        rest = rest.get(end + 1..).unwrap_or("");
+        if !option.is_empty() {
+            return Some(option);
+        }
+    })
+}
+
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.extend(chars.next()),
+            c => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_options() {
+        let opts = FuseOptions::parse(
+            "ro,allow_other,nofail,x-systemd.automount,_netdev,fsname=a\\,b,cache=loose,sync_meta, ,",
+        )
+        .unwrap();
+        assert_eq!(
+            opts.kernel_options().collect::<Vec<_>>(),
+            ["ro", "allow_other", "fsname=a\\,b"]
+        );
+        assert_eq!(
+            opts.fs_options().collect::<Vec<_>>(),
+            [("cache", Some("loose")), ("sync_meta", None)]
+        );
+        assert!(opts.read_only());
+        assert!(opts.has("sync_meta"));
+        assert_eq!(opts.value("cache"), Some("loose"));
+        assert_eq!(opts.value("sync_meta"), None);
+
+        let opts = FuseOptions::parse("ro,rw,label=x\\,y").unwrap();
+        assert!(!opts.read_only());
+        assert_eq!(opts.value("label"), Some("x,y"));
+
+        let err = FuseOptions::parse("ro,=foo").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn parse_mount_args() {
+        let args = MountArgs::parse(vec![
+            "/srv/data",
+            "/mnt/data",
+            "-n",
+            "-o",
+            "rw,allow_root",
+            "-ocache=none",
+        ])
+        .unwrap();
+        assert_eq!(args.source(), Some(OsStr::new("/srv/data")));
+        assert_eq!(args.mountpoint(), Path::new("/mnt/data"));
+        assert_eq!(args.options().value("cache"), Some("none"));
+        assert!(args.options().allow_root);
+
+        let args = MountArgs::parse(vec!["/mnt/data"]).unwrap();
+        assert_eq!(args.source(), None);
+
+        for args in &[
+            &["/srv/data", "/mnt/data", "-o"][..],
+            &["/srv/data", "/mnt/data", "-x"],
+            &["a", "b", "c"],
+            &[],
+        ] {
+            let err = MountArgs::parse(args.iter().copied()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{:?}", args);
+        }
+    }
+}
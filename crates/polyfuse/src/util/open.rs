@@ -0,0 +1,164 @@
+use super::UnknownHandle;
+use std::{collections::HashMap, fmt, sync::Mutex};
+
+/// A counter of the opened handles, for detecting the final release of
+/// a file.
+///
+/// The kernel sends `Open`/`Create`/`Opendir` once for each `open(2)`, and
+/// the matching `Release`/`Releasedir` when the last descriptor referring to
+/// the open file description is closed. The descriptors duplicated by
+/// `dup(2)` or `fork(2)` share the description, so they only produce a
+/// `Flush` on each `close(2)`, and `Flush` must not be treated as the final
+/// release.
+///
+/// The counter records the opens per `(ino, fh)` pair, so the filesystems
+/// that return the same `fh` for several opens are also supported, and
+/// reports when the last handle of the inode is released, e.g. in order to
+/// reclaim the contents of an unlinked file.
+#[derive(Default)]
+pub struct OpenCounter {
+    inodes: Mutex<HashMap<u64, Opens>>,
+}
+
+#[derive(Default)]
+struct Opens {
+    total: u64,
+    handles: HashMap<u64, u64>,
+}
+
+impl fmt::Debug for OpenCounter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpenCounter")
+            .field("inodes", &self.inodes.lock().unwrap().len())
+            .finish()
+    }
+}
+
+/// The result of [`OpenCounter::release`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Released {
+    /// The handle is still referenced by other opens.
+    Shared,
+
+    /// The last reference to the handle is gone, and the inode has other
+    /// open handles.
+    LastHandle,
+
+    /// The last open handle of the inode is gone.
+    LastInode,
+}
+
+impl Released {
+    /// Return whether the last reference to the handle is gone.
+    #[inline]
+    pub fn is_last_handle(self) -> bool {
+        !matches!(self, Self::Shared)
+    }
+
+    /// Return whether the inode has no open handles.
+    #[inline]
+    pub fn is_last_inode(self) -> bool {
+        matches!(self, Self::LastInode)
+    }
+}
+
+impl OpenCounter {
+    /// Create an empty counter.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record an open of the handle, on replying to `Open`, `Create` or `Opendir`.
+    pub fn open(&self, ino: u64, fh: u64) {
+        let mut inodes = self.inodes.lock().unwrap();
+        let opens = inodes.entry(ino).or_default();
+        opens.total += 1;
+        *opens.handles.entry(fh).or_default() += 1;
+    }
+
+    /// Record a release of the handle, on receiving `Release` or `Releasedir`.
+    ///
+    /// An error is returned if the handle has not been opened.
+    pub fn release(&self, ino: u64, fh: u64) -> Result<Released, UnknownHandle> {
+        let mut inodes = self.inodes.lock().unwrap();
+        let opens = match inodes.get_mut(&ino) {
+            Some(opens) => opens,
+            None => return Err(unknown(ino, fh)),
+        };
+        let count = match opens.handles.get_mut(&fh) {
+            Some(count) => count,
+            None => return Err(unknown(ino, fh)),
+        };
+
+        *count -= 1;
+        opens.total -= 1;
+        if *count > 0 {
+            return Ok(Released::Shared);
+        }
+        opens.handles.remove(&fh);
+        if opens.total > 0 {
+            return Ok(Released::LastHandle);
+        }
+        inodes.remove(&ino);
+        Ok(Released::LastInode)
+    }
+
+    /// Return the number of the open handles of the inode.
+    pub fn open_count(&self, ino: u64) -> u64 {
+        self.inodes
+            .lock()
+            .unwrap()
+            .get(&ino)
+            .map_or(0, |opens| opens.total)
+    }
+
+    /// Return whether the inode has any open handles.
+    pub fn is_open(&self, ino: u64) -> bool {
+        self.open_count(ino) > 0
+    }
+}
+
+fn unknown(ino: u64, fh: u64) -> UnknownHandle {
+    tracing::error!(ino, fh, "the kernel released a handle that is not opened");
+    UnknownHandle::new(fh)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_release() {
+        let counter = OpenCounter::new();
+        counter.open(2, 10);
+        counter.open(2, 11);
+        counter.open(3, 10);
+        assert_eq!(counter.open_count(2), 2);
+
+        assert_eq!(counter.release(2, 10).unwrap(), Released::LastHandle);
+        assert_eq!(counter.release(2, 11).unwrap(), Released::LastInode);
+        assert!(!counter.is_open(2));
+        assert!(counter.is_open(3));
+    }
+
+    #[test]
+    fn shared_handle() {
+        let counter = OpenCounter::new();
+        counter.open(2, 0);
+        counter.open(2, 0);
+
+        let released = counter.release(2, 0).unwrap();
+        assert_eq!(released, Released::Shared);
+        assert!(!released.is_last_handle());
+        assert!(counter.release(2, 0).unwrap().is_last_inode());
+    }
+
+    #[test]
+    fn unknown_release() {
+        let counter = OpenCounter::new();
+        counter.open(2, 10);
+        assert_eq!(counter.release(2, 11).unwrap_err().fh(), 11);
+        assert_eq!(counter.release(3, 10).unwrap_err().fh(), 10);
+        assert_eq!(counter.open_count(2), 1);
+    }
+}
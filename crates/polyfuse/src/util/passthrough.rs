@@ -0,0 +1,403 @@
+//! Building blocks for passthrough filesystems.
+//!
+//! The inodes of a passthrough filesystem are represented by [`PathHandle`],
+//! a file descriptor opened with `O_PATH` on the underlying filesystem. Keeping
+//! such descriptors instead of paths makes the filesystem robust against
+//! renames in the underlying directory tree, and the files are reopened with
+//! the actual access mode only when they are opened from the kernel.
+
+use crate::reply::{FileAttr, Statfs};
+use std::{
+    collections::HashMap,
+    ffi::{CString, OsStr},
+    fmt, io, mem,
+    os::unix::prelude::*,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+macro_rules! syscall {
+    ($fn:ident ( $($arg:expr),* $(,)* ) ) => {{
+        #[allow(unused_unsafe)]
+        let res = unsafe { libc::$fn($($arg),*) };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        res
+    }};
+}
+
+/// The identifier of a file on the underlying filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SourceId {
+    /// The device ID of the filesystem containing the file.
+    pub dev: u64,
+    /// The inode number on the underlying filesystem.
+    pub ino: u64,
+}
+
+impl SourceId {
+    /// Extract the identifier from the result of `stat(2)`.
+    #[allow(clippy::unnecessary_cast)] // the widths of stat fields vary among platforms
+    #[inline]
+    pub fn from_stat(st: &libc::stat) -> Self {
+        Self {
+            dev: st.st_dev as u64,
+            ino: st.st_ino as u64,
+        }
+    }
+}
+
+/// A file descriptor opened with `O_PATH`.
+pub struct PathHandle {
+    fd: RawFd,
+    source: SourceId,
+    mode: libc::mode_t,
+}
+
+impl fmt::Debug for PathHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PathHandle")
+            .field("fd", &self.fd)
+            .field("source", &self.source)
+            .finish()
+    }
+}
+
+impl Drop for PathHandle {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+impl AsRawFd for PathHandle {
+    #[inline]
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl PathHandle {
+    /// Open the specified path, typically the root directory of
+    /// the underlying filesystem.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let c_path = CString::new(path.as_ref().as_os_str().as_bytes())?;
+        let fd = syscall!(open(
+            c_path.as_ptr(),
+            libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC
+        ));
+        unsafe { Self::from_fd(fd) }
+    }
+
+    unsafe fn from_fd(fd: RawFd) -> io::Result<Self> {
+        let mut st = mem::MaybeUninit::<libc::stat>::uninit();
+        if libc::fstat(fd, st.as_mut_ptr()) == -1 {
+            let err = io::Error::last_os_error();
+            libc::close(fd);
+            return Err(err);
+        }
+        let st = st.assume_init();
+        Ok(Self {
+            fd,
+            source: SourceId::from_stat(&st),
+            mode: st.st_mode,
+        })
+    }
+
+    /// Return the identifier of the file on the underlying filesystem.
+    #[inline]
+    pub fn source(&self) -> SourceId {
+        self.source
+    }
+
+    /// Return whether the file is a symbolic link.
+    #[inline]
+    pub fn is_symlink(&self) -> bool {
+        self.mode & libc::S_IFMT == libc::S_IFLNK
+    }
+
+    /// Return whether the file is a directory.
+    #[inline]
+    pub fn is_dir(&self) -> bool {
+        self.mode & libc::S_IFMT == libc::S_IFDIR
+    }
+
+    /// Return the path to the magic link of this descriptor in `procfs`.
+    pub fn procname(&self) -> PathBuf {
+        PathBuf::from(format!("/proc/self/fd/{}", self.fd))
+    }
+
+    /// Look up the entry in this directory and open it with `O_PATH`.
+    ///
+    /// The trailing symbolic link is not followed, so the returned handle
+    /// may refer to a symbolic link.
+    pub fn lookup(&self, name: &OsStr) -> io::Result<Self> {
+        let fd = openat2(
+            self.fd,
+            name,
+            libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            libc::RESOLVE_NO_SYMLINKS | libc::RESOLVE_BENEATH,
+        )?;
+        unsafe { Self::from_fd(fd) }
+    }
+
+    /// Open the entry in this directory with the specified flags.
+    ///
+    /// The entry is resolved with `openat2(2)` using `RESOLVE_NO_SYMLINKS`
+    /// and `RESOLVE_BENEATH`, which prevents the access outside of this
+    /// directory via symbolic links or `..` components. On kernels without
+    /// `openat2(2)`, it falls back to `openat(2)` with `O_NOFOLLOW`.
+    pub fn open_at(&self, name: &OsStr, flags: libc::c_int) -> io::Result<std::fs::File> {
+        let fd = openat2(
+            self.fd,
+            name,
+            flags | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            libc::RESOLVE_NO_SYMLINKS | libc::RESOLVE_BENEATH,
+        )?;
+        Ok(unsafe { std::fs::File::from_raw_fd(fd) })
+    }
+
+    /// Reopen the file with the specified flags.
+    ///
+    /// Symbolic links cannot be reopened and `ELOOP` is returned for them.
+    pub fn reopen(&self, flags: libc::c_int) -> io::Result<std::fs::File> {
+        if self.is_symlink() {
+            return Err(io::Error::from_raw_os_error(libc::ELOOP));
+        }
+        // The magic link in procfs is always followed regardless of O_NOFOLLOW.
+        let flags = (flags | libc::O_CLOEXEC) & !(libc::O_NOFOLLOW | libc::O_CREAT);
+        let c_path = CString::new(self.procname().into_os_string().into_vec())?;
+        let fd = syscall!(open(c_path.as_ptr(), flags));
+        Ok(unsafe { std::fs::File::from_raw_fd(fd) })
+    }
+
+    /// Retrieve the attributes of the file without following symbolic links.
+    pub fn stat(&self) -> io::Result<libc::stat> {
+        let mut st = mem::MaybeUninit::<libc::stat>::uninit();
+        syscall!(fstatat(
+            self.fd,
+            b"\0".as_ptr().cast(),
+            st.as_mut_ptr(),
+            libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW
+        ));
+        Ok(unsafe { st.assume_init() })
+    }
+
+    /// Retrieve the statistics of the filesystem containing the file.
+    pub fn statfs(&self) -> io::Result<libc::statvfs> {
+        let mut st = mem::MaybeUninit::<libc::statvfs>::uninit();
+        syscall!(fstatvfs(self.fd, st.as_mut_ptr()));
+        Ok(unsafe { st.assume_init() })
+    }
+
+    fn xattr_path(&self) -> io::Result<CString> {
+        // The extended attributes of symbolic links cannot be accessed through
+        // the magic link, since it is always followed.
+        if self.is_symlink() {
+            return Err(io::Error::from_raw_os_error(libc::ENOTSUP));
+        }
+        Ok(CString::new(self.procname().into_os_string().into_vec())?)
+    }
+
+    /// Get an extended attribute.
+    ///
+    /// If `value` is `None`, only the size of the value is returned.
+    pub fn getxattr(&self, name: &OsStr, value: Option<&mut [u8]>) -> io::Result<usize> {
+        let c_path = self.xattr_path()?;
+        let c_name = CString::new(name.as_bytes())?;
+        let (ptr, size) = match value {
+            Some(value) => (value.as_mut_ptr().cast(), value.len()),
+            None => (std::ptr::null_mut(), 0),
+        };
+        let len = syscall!(getxattr(c_path.as_ptr(), c_name.as_ptr(), ptr, size));
+        Ok(len as usize)
+    }
+
+    /// List the names of extended attributes.
+    ///
+    /// If `list` is `None`, only the size of the list is returned.
+    pub fn listxattr(&self, list: Option<&mut [u8]>) -> io::Result<usize> {
+        let c_path = self.xattr_path()?;
+        let (ptr, size) = match list {
+            Some(list) => (list.as_mut_ptr().cast(), list.len()),
+            None => (std::ptr::null_mut(), 0),
+        };
+        let len = syscall!(listxattr(c_path.as_ptr(), ptr, size));
+        Ok(len as usize)
+    }
+
+    /// Set an extended attribute.
+    pub fn setxattr(&self, name: &OsStr, value: &[u8], flags: libc::c_int) -> io::Result<()> {
+        let c_path = self.xattr_path()?;
+        let c_name = CString::new(name.as_bytes())?;
+        syscall!(setxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            flags
+        ));
+        Ok(())
+    }
+
+    /// Remove an extended attribute.
+    pub fn removexattr(&self, name: &OsStr) -> io::Result<()> {
+        let c_path = self.xattr_path()?;
+        let c_name = CString::new(name.as_bytes())?;
+        syscall!(removexattr(c_path.as_ptr(), c_name.as_ptr()));
+        Ok(())
+    }
+}
+
+fn openat2(dirfd: RawFd, name: &OsStr, flags: libc::c_int, resolve: u64) -> io::Result<RawFd> {
+    let c_name = CString::new(name.as_bytes())?;
+
+    let mut how: libc::open_how = unsafe { mem::zeroed() };
+    how.flags = flags as u64;
+    how.resolve = resolve;
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            dirfd,
+            c_name.as_ptr(),
+            &how as *const libc::open_how,
+            mem::size_of::<libc::open_how>(),
+        )
+    };
+    if res >= 0 {
+        return Ok(res as RawFd);
+    }
+
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() != Some(libc::ENOSYS) {
+        return Err(err);
+    }
+
+    // The kernel is older than 5.6.
+    if name.as_bytes() == b".." || name.as_bytes().contains(&b'/') {
+        return Err(io::Error::from_raw_os_error(libc::EXDEV));
+    }
+    Ok(syscall!(openat(dirfd, c_name.as_ptr(), flags)))
+}
+
+/// A cache of [`PathHandle`]s keyed by the inode number.
+///
+/// The cache also maintains the reverse index from [`SourceId`], so that
+/// the same file reached via different paths (e.g. hard links) is mapped
+/// to the same inode.
+#[derive(Debug, Default)]
+pub struct HandleCache {
+    inner: Mutex<CacheInner>,
+}
+
+#[derive(Debug, Default)]
+struct CacheInner {
+    handles: HashMap<u64, Arc<PathHandle>>,
+    sources: HashMap<SourceId, u64>,
+}
+
+impl HandleCache {
+    /// Create an empty handle cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return the number of cached handles.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().handles.len()
+    }
+
+    /// Return whether the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the handle associated with the inode number.
+    pub fn get(&self, ino: u64) -> Option<Arc<PathHandle>> {
+        self.inner.lock().unwrap().handles.get(&ino).cloned()
+    }
+
+    /// Find the inode number that the underlying file is mapped to.
+    pub fn find(&self, source: SourceId) -> Option<u64> {
+        self.inner.lock().unwrap().sources.get(&source).copied()
+    }
+
+    /// Associate the handle with the inode number.
+    ///
+    /// The previously cached handle is returned, if any.
+    pub fn insert(&self, ino: u64, handle: PathHandle) -> Option<Arc<PathHandle>> {
+        let mut inner = self.inner.lock().unwrap();
+        let source = handle.source();
+        let old = inner.handles.insert(ino, Arc::new(handle));
+        if let Some(ref old) = old {
+            if old.source() != source && inner.sources.get(&old.source()) == Some(&ino) {
+                inner.sources.remove(&old.source());
+            }
+        }
+        inner.sources.insert(source, ino);
+        old
+    }
+
+    /// Remove the handle associated with the inode number.
+    pub fn remove(&self, ino: u64) -> Option<Arc<PathHandle>> {
+        let mut inner = self.inner.lock().unwrap();
+        let handle = inner.handles.remove(&ino)?;
+        if inner.sources.get(&handle.source()) == Some(&ino) {
+            inner.sources.remove(&handle.source());
+        }
+        Some(handle)
+    }
+}
+
+/// Fill the file attributes with the result of `stat(2)`.
+#[allow(clippy::unnecessary_cast)]
+pub fn fill_attr(attr: &mut FileAttr, st: &libc::stat) {
+    attr.ino(st.st_ino as u64);
+    attr.size(st.st_size as u64);
+    attr.mode(st.st_mode);
+    attr.nlink(st.st_nlink as u32);
+    attr.uid(st.st_uid);
+    attr.gid(st.st_gid);
+    attr.rdev(st.st_rdev as u32);
+    attr.blksize(st.st_blksize as u32);
+    attr.blocks(st.st_blocks as u64);
+    attr.atime(Duration::new(st.st_atime as u64, st.st_atime_nsec as u32));
+    attr.mtime(Duration::new(st.st_mtime as u64, st.st_mtime_nsec as u32));
+    attr.ctime(Duration::new(st.st_ctime as u64, st.st_ctime_nsec as u32));
+}
+
+/// Fill the filesystem statistics with the result of `statvfs(2)`.
+#[allow(clippy::unnecessary_cast)]
+pub fn fill_statfs(statfs: &mut Statfs, st: &libc::statvfs) {
+    statfs.bsize(st.f_bsize as u32);
+    statfs.frsize(st.f_frsize as u32);
+    statfs.blocks(st.f_blocks as u64);
+    statfs.bfree(st.f_bfree as u64);
+    statfs.bavail(st.f_bavail as u64);
+    statfs.files(st.f_files as u64);
+    statfs.ffree(st.f_ffree as u64);
+    statfs.namelen(st.f_namemax as u32);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_beneath() {
+        let root = PathHandle::open(env!("CARGO_MANIFEST_DIR")).unwrap();
+        assert!(root.is_dir());
+
+        let src = root.lookup(OsStr::new("src")).unwrap();
+        assert!(src.is_dir());
+        assert_eq!(SourceId::from_stat(&src.stat().unwrap()), src.source());
+
+        assert!(src.lookup(OsStr::new("../Cargo.toml")).is_err());
+        assert!(root
+            .open_at(OsStr::new("Cargo.toml"), libc::O_RDONLY)
+            .is_ok());
+    }
+}
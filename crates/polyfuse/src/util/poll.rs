@@ -0,0 +1,191 @@
+use crate::{op, session::Notifier};
+use std::{
+    fmt, io,
+    mem::ManuallyDrop,
+    sync::{Arc, Mutex},
+    task::{self, RawWaker, RawWakerVTable, Waker},
+};
+
+/// A helper for implementing pollable files.
+///
+/// The handle remembers the kernel handles (`kh`) of the pending `Poll`
+/// requests and sends `poll_wakeup` notifications for them when the backend
+/// signals readiness, either by calling [`wakeup`](PollHandle::wakeup)
+/// directly or by waking the [`Waker`] returned from
+/// [`waker`](PollHandle::waker). The latter allows to pass the readiness of
+/// `Future`-based I/O resources to the kernel without any extra plumbing:
+///
+/// ```ignore
+/// async fn poll(&self, op: op::Poll<'_>) -> io::Result<PollOut> {
+///     let mut out = PollOut::default();
+///     out.revents(self.poll_handle.poll(&op, |cx, events| {
+///         match self.pipe.poll_read_ready(cx) {
+///             Poll::Ready(..) => events & libc::POLLIN as u32,
+///             Poll::Pending => 0,
+///         }
+///     }));
+///     Ok(out)
+/// }
+/// ```
+#[derive(Clone)]
+pub struct PollHandle {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    notifier: Notifier,
+    pending: Mutex<Pending>,
+}
+
+impl fmt::Debug for PollHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PollHandle")
+            .field("pending", &*self.inner.pending.lock().unwrap())
+            .finish()
+    }
+}
+
+impl PollHandle {
+    /// Create a handle that sends the notifications with `notifier`.
+    pub fn new(notifier: Notifier) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                notifier,
+                pending: Mutex::new(Pending::default()),
+            }),
+        }
+    }
+
+    /// Register the kernel handle to be notified at the next wakeup.
+    pub fn register(&self, kh: u64) {
+        self.inner.pending.lock().unwrap().register(kh);
+    }
+
+    /// Return whether there are kernel handles waiting for a wakeup.
+    pub fn is_pending(&self) -> bool {
+        !self.inner.pending.lock().unwrap().is_empty()
+    }
+
+    /// Notify the readiness to all the registered kernel handles.
+    ///
+    /// The handles are unregistered after the notification is sent.
+    pub fn wakeup(&self) -> io::Result<()> {
+        self.inner.wakeup()
+    }
+
+    /// Create a `Waker` that calls [`wakeup`](PollHandle::wakeup) when woken.
+    ///
+    /// Since `Waker` cannot report errors, the failure of notifications
+    /// is only logged.
+    pub fn waker(&self) -> Waker {
+        let raw = Arc::into_raw(self.inner.clone()) as *const ();
+        unsafe { Waker::from_raw(RawWaker::new(raw, &VTABLE)) }
+    }
+
+    /// Check the readiness of the backend for a `Poll` request.
+    ///
+    /// The kernel handle of the request, if any, is registered before
+    /// `f` is called with a task context bound to this handle and the
+    /// requested events, so that no wakeup between the check and the
+    /// reply is lost. The return value of `f` should be the mask of
+    /// ready events, which is replied to the kernel as `revents`.
+    pub fn poll<F>(&self, op: &op::Poll<'_>, f: F) -> u32
+    where
+        F: FnOnce(&mut task::Context<'_>, u32) -> u32,
+    {
+        if let Some(kh) = op.kh() {
+            self.register(kh);
+        }
+        let waker = self.waker();
+        let mut cx = task::Context::from_waker(&waker);
+        f(&mut cx, op.events())
+    }
+}
+
+impl Inner {
+    fn wakeup(&self) -> io::Result<()> {
+        let khs = self.pending.lock().unwrap().take();
+        // The handles have already been taken, so notify all of them and
+        // report the first failure afterwards.
+        let mut res = Ok(());
+        for kh in khs {
+            match self.notifier.poll_wakeup(kh) {
+                Ok(()) => (),
+                // The kernel has already forgotten the handle.
+                Err(ref err) if err.raw_os_error() == Some(libc::ENOENT) => (),
+                Err(err) => {
+                    if res.is_ok() {
+                        res = Err(err);
+                    }
+                }
+            }
+        }
+        res
+    }
+
+    fn wake_by_ref(&self) {
+        if let Err(err) = self.wakeup() {
+            tracing::error!("failed to send poll wakeup notification: {}", err);
+        }
+    }
+}
+
+static VTABLE: RawWakerVTable =
+    RawWakerVTable::new(waker_clone, waker_wake, waker_wake_by_ref, waker_drop);
+
+unsafe fn waker_clone(ptr: *const ()) -> RawWaker {
+    let inner = ManuallyDrop::new(Arc::from_raw(ptr as *const Inner));
+    let cloned = Arc::into_raw(Arc::clone(&*inner)) as *const ();
+    RawWaker::new(cloned, &VTABLE)
+}
+
+unsafe fn waker_wake(ptr: *const ()) {
+    let inner = Arc::from_raw(ptr as *const Inner);
+    inner.wake_by_ref();
+}
+
+unsafe fn waker_wake_by_ref(ptr: *const ()) {
+    let inner = ManuallyDrop::new(Arc::from_raw(ptr as *const Inner));
+    inner.wake_by_ref();
+}
+
+unsafe fn waker_drop(ptr: *const ()) {
+    drop(Arc::from_raw(ptr as *const Inner));
+}
+
+/// The set of kernel handles waiting for a wakeup.
+#[derive(Debug, Default)]
+struct Pending {
+    khs: Vec<u64>,
+}
+
+impl Pending {
+    fn register(&mut self, kh: u64) {
+        if !self.khs.contains(&kh) {
+            self.khs.push(kh);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.khs.is_empty()
+    }
+
+    fn take(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.khs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_dedup() {
+        let mut pending = Pending::default();
+        pending.register(1);
+        pending.register(2);
+        pending.register(1);
+        assert_eq!(pending.take(), vec![1, 2]);
+        assert!(pending.is_empty());
+    }
+}
@@ -0,0 +1,282 @@
+use crate::session::KernelConfig;
+use std::{cmp, fs, path::Path};
+
+/// The first kernel releases speaking each minor version of the protocol.
+const PROTO_MINOR_VERSIONS: &[((u32, u32), u32)] = &[
+    ((3, 15), 23),
+    ((4, 5), 24),
+    ((4, 7), 25),
+    ((4, 9), 26),
+    ((4, 18), 27),
+    ((4, 20), 28),
+    ((5, 1), 29),
+    ((5, 2), 30),
+    ((5, 4), 31),
+    ((5, 10), 32),
+    ((5, 11), 33),
+    ((5, 14), 34),
+    ((5, 16), 35),
+    ((5, 17), 36),
+    ((6, 0), 37),
+    ((6, 2), 38),
+    ((6, 6), 39),
+    ((6, 9), 40),
+];
+
+/// A feature of the FUSE kernel driver, whose availability is predicted by
+/// [`KernelProbe`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum KernelFeature {
+    /// [`KernelConfig::writeback_cache`].
+    WritebackCache,
+    /// [`KernelConfig::parallel_dirops`].
+    ParallelDirops,
+    /// [`KernelConfig::posix_acl`].
+    PosixAcl,
+    /// [`KernelConfig::handle_killpriv`].
+    HandleKillpriv,
+    /// The larger requests than 32 pages, limited by
+    /// [`KernelConfig::max_pages_limit`].
+    MaxPages,
+    /// The caching of the symbolic links in the page cache.
+    CacheSymlinks,
+    /// The zero-message opendirs, see [`Session::no_opendir_support`].
+    ///
+    /// [`Session::no_opendir_support`]: crate::Session::no_opendir_support
+    NoOpendirSupport,
+    /// The invalidation of the cached data only by the notifications.
+    ExplicitInvalData,
+    /// [`KernelConfig::security_context`].
+    SecurityContext,
+    /// The concurrent direct I/O writes, see
+    /// [`OpenOut::parallel_direct_writes`].
+    ///
+    /// [`OpenOut::parallel_direct_writes`]: crate::reply::OpenOut::parallel_direct_writes
+    ParallelDirectWrites,
+    /// The `STATX` requests.
+    Statx,
+}
+
+impl KernelFeature {
+    /// Return the minor version of the protocol introducing the feature.
+    pub fn proto_minor(self) -> u32 {
+        match self {
+            Self::WritebackCache => 23,
+            Self::ParallelDirops => 25,
+            Self::PosixAcl | Self::HandleKillpriv => 26,
+            Self::MaxPages | Self::CacheSymlinks => 28,
+            Self::NoOpendirSupport => 29,
+            Self::ExplicitInvalData => 30,
+            Self::SecurityContext => 36,
+            Self::ParallelDirectWrites => 38,
+            Self::Statx => 39,
+        }
+    }
+}
+
+/// The FUSE support of the running kernel, inspected before mounting.
+///
+/// The features are predicted from the kernel release in
+/// `/proc/sys/kernel/osrelease`, and the limits are read from
+/// `/proc/sys/fs/fuse` and the parameters of the `fuse` module, so that the
+/// daemon can refuse to start or adjust the configuration instead of
+/// failing in the middle of the `INIT` handshake:
+///
+/// ```ignore
+/// let probe = KernelProbe::probe();
+/// if !probe.fuse_available() {
+///     return Err("FUSE is not available".into());
+/// }
+/// let missing = probe.missing(&[KernelFeature::SecurityContext]);
+/// let mut config = KernelConfig::default();
+/// config.security_context(missing.is_empty());
+/// probe.adjust(&mut config);
+/// ```
+///
+/// The distributions often backport the features to older releases, so the
+/// prediction is conservative. The features actually negotiated are known
+/// only after mounting, through [`Session::connection_info`].
+///
+/// [`Session::connection_info`]: crate::Session::connection_info
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KernelProbe {
+    release: Option<(u32, u32)>,
+    dev_fuse: bool,
+    fuseblk: bool,
+    max_pages_limit: Option<u16>,
+    max_user_bgreq: Option<u32>,
+    max_user_congthresh: Option<u32>,
+}
+
+impl KernelProbe {
+    /// Inspect the running kernel.
+    ///
+    /// The files that cannot be read are treated as unknown, rather than
+    /// reported as errors.
+    pub fn probe() -> Self {
+        let read = |path: &str| fs::read_to_string(path).ok();
+        let release = read("/proc/sys/kernel/osrelease")
+            .or_else(|| {
+                // "Linux version 6.1.0-13-amd64 (...) ..."
+                let version = read("/proc/version")?;
+                version.split_whitespace().nth(2).map(Into::into)
+            })
+            .and_then(|release| parse_release(&release));
+        let filesystems = read("/proc/filesystems").unwrap_or_default();
+        let has_fs = |name: &str| {
+            filesystems
+                .lines()
+                .any(|line| line.split_whitespace().last() == Some(name))
+        };
+
+        Self {
+            release,
+            dev_fuse: Path::new("/dev/fuse").exists(),
+            fuseblk: has_fs("fuseblk"),
+            max_pages_limit: read_param("/proc/sys/fs/fuse/max_pages_limit"),
+            max_user_bgreq: read_param("/sys/module/fuse/parameters/max_user_bgreq"),
+            max_user_congthresh: read_param("/sys/module/fuse/parameters/max_user_congthresh"),
+        }
+    }
+
+    /// Return the major and the minor version of the kernel release.
+    pub fn release(&self) -> Option<(u32, u32)> {
+        self.release
+    }
+
+    /// Return the minor version of the protocol predicted from the kernel
+    /// release.
+    pub fn proto_minor(&self) -> Option<u32> {
+        let release = self.release?;
+        Some(
+            PROTO_MINOR_VERSIONS
+                .iter()
+                .rev()
+                .find(|(since, _)| *since <= release)
+                .map_or(0, |&(_, minor)| minor),
+        )
+    }
+
+    /// Return whether the FUSE device is available.
+    ///
+    /// The device node may exist before the module is loaded, which is then
+    /// loaded on demand when the device is opened.
+    pub fn fuse_available(&self) -> bool {
+        self.dev_fuse
+    }
+
+    /// Return whether the `fuseblk` filesystem type, used by
+    /// [`KernelConfig::blkdev`], is registered.
+    pub fn fuseblk_available(&self) -> bool {
+        self.fuseblk
+    }
+
+    /// Return whether the feature is predicted to be available, or `None`
+    /// if the kernel release is unknown.
+    pub fn supports(&self, feature: KernelFeature) -> Option<bool> {
+        self.proto_minor()
+            .map(|minor| minor >= feature.proto_minor())
+    }
+
+    /// Return the features not predicted to be available, including those
+    /// unknown.
+    pub fn missing(&self, features: &[KernelFeature]) -> Vec<KernelFeature> {
+        features
+            .iter()
+            .filter(|&&feature| self.supports(feature) != Some(true))
+            .copied()
+            .collect()
+    }
+
+    /// Return the maximum number of pages per request, set in
+    /// `/proc/sys/fs/fuse/max_pages_limit`.
+    pub fn max_pages_limit(&self) -> Option<u16> {
+        self.max_pages_limit
+    }
+
+    /// Return the limit of `max_background` for the unprivileged mounts,
+    /// set by the `max_user_bgreq` parameter of the module.
+    pub fn max_user_bgreq(&self) -> Option<u32> {
+        self.max_user_bgreq
+    }
+
+    /// Return the limit of `congestion_threshold` for the unprivileged
+    /// mounts, set by the `max_user_congthresh` parameter of the module.
+    pub fn max_user_congthresh(&self) -> Option<u32> {
+        self.max_user_congthresh
+    }
+
+    /// Lower the limits of the configuration to those applied by the
+    /// kernel, so that the values reported by the session after mounting
+    /// match those in effect.
+    ///
+    /// The limits of the background requests are lowered unless running
+    /// as root.
+    pub fn adjust(&self, config: &mut KernelConfig) {
+        if unsafe { libc::geteuid() } == 0 {
+            return;
+        }
+        let clamp = |value: u16, limit: Option<u32>| match limit {
+            Some(limit) => {
+                let limit = cmp::min(limit, u32::from(u16::MAX)) as u16;
+                if value == 0 || value > limit {
+                    limit
+                } else {
+                    value
+                }
+            }
+            None => value,
+        };
+        let (max_background, congestion_threshold) = config.background_limits();
+        config
+            .max_background(clamp(max_background, self.max_user_bgreq))
+            .congestion_threshold(clamp(congestion_threshold, self.max_user_congthresh));
+    }
+}
+
+fn read_param<T: std::str::FromStr>(path: &str) -> Option<T> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Parse the release such as `6.1.0-13-amd64` into the major and the minor
+/// version.
+fn parse_release(release: &str) -> Option<(u32, u32)> {
+    let mut parts = release.trim().splitn(3, |c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn predict_features() {
+        assert_eq!(parse_release("6.1.0-13-amd64\n"), Some((6, 1)));
+        assert_eq!(parse_release("5.15.0"), Some((5, 15)));
+        assert_eq!(parse_release("4.18.0-513.el8.x86_64"), Some((4, 18)));
+        assert_eq!(parse_release("unknown"), None);
+
+        let probe = |release| KernelProbe {
+            release,
+            ..KernelProbe::default()
+        };
+        assert_eq!(probe(Some((5, 15))).proto_minor(), Some(34));
+        assert_eq!(probe(Some((3, 10))).proto_minor(), Some(0));
+        assert_eq!(probe(Some((7, 0))).proto_minor(), Some(40));
+
+        let probe = probe(Some((5, 15)));
+        assert_eq!(probe.supports(KernelFeature::MaxPages), Some(true));
+        assert_eq!(probe.supports(KernelFeature::SecurityContext), Some(false));
+        assert_eq!(
+            probe.missing(&[KernelFeature::PosixAcl, KernelFeature::Statx]),
+            [KernelFeature::Statx]
+        );
+        assert_eq!(
+            KernelProbe::default().supports(KernelFeature::PosixAcl),
+            None
+        );
+    }
+}
@@ -0,0 +1,228 @@
+use std::{cmp, io};
+
+/// A write aligned to the blocks of the backend, returned by
+/// [`align_write`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockWrite {
+    offset: u64,
+    data: Vec<u8>,
+    file_size: u64,
+}
+
+impl BlockWrite {
+    /// Return the offset of the first block.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Return the contents of the blocks.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Take the contents of the blocks.
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Return the size of the file after the write.
+    ///
+    /// The last block may extend beyond it, filled with zeros, so it must
+    /// be recorded along with the blocks if the write extends the file.
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+}
+
+/// Turn a `Write` request into the write of the whole blocks, for the
+/// backends that can only store the blocks of `block_size`.
+///
+/// The partially written blocks at both ends are fetched with `fetch`,
+/// which is called with the offset and the size of a block and returns its
+/// contents, and the payload is merged into them:
+///
+/// ```no_run
+/// # use polyfuse::{reply::WriteOut, util::align_write, Operation, Request};
+/// # use std::io::{self, Read as _};
+/// # const BLOCK_SIZE: usize = 4096;
+/// # struct Backend;
+/// # impl Backend {
+/// #     fn read_block(&self, ino: u64, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+/// #         unimplemented!()
+/// #     }
+/// #     fn write_blocks(&self, ino: u64, offset: u64, data: &[u8]) -> io::Result<()> {
+/// #         unimplemented!()
+/// #     }
+/// # }
+/// # struct Inode { size: u64 }
+/// # fn handle(req: &Request, backend: &Backend, inode: &mut Inode) -> io::Result<()> {
+/// # match req.operation().expect("malformed request") {
+/// Operation::Write(op, mut data) => {
+///     let mut payload = vec![0; op.size() as usize];
+///     data.read_exact(&mut payload)?;
+///     let write = align_write(BLOCK_SIZE, inode.size, op.offset(), &payload, |offset, len| {
+///         backend.read_block(op.ino(), offset, len)
+///     })?;
+///     backend.write_blocks(op.ino(), write.offset(), write.data())?;
+///     inode.size = write.file_size();
+///     let mut out = WriteOut::default();
+///     out.size(op.size());
+///     req.reply(out)?;
+/// }
+/// # _ => unimplemented!(),
+/// # }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// With the writeback cache, the kernel may flush the dirty pages beyond the
+/// size of the file known to the backend, or leave a hole between it and
+/// the written range. `file_size` is the size before the write, and:
+///
+/// * the blocks starting at or beyond it are not fetched,
+/// * the fetched blocks may be shorter than `block_size`, and
+/// * the contents of the fetched blocks beyond it are discarded, since the
+///   backend may keep stale data there after a truncation,
+///
+/// so the gap between the end of file and the written range reads as zeros.
+///
+/// The write of no data yields no blocks. The cache of the backend, if any,
+/// must be invalidated or updated by the caller, since the fetched blocks
+/// are not written back on failure.
+pub fn align_write<F>(
+    block_size: usize,
+    file_size: u64,
+    offset: u64,
+    data: &[u8],
+    mut fetch: F,
+) -> io::Result<BlockWrite>
+where
+    F: FnMut(u64, usize) -> io::Result<Vec<u8>>,
+{
+    assert!(block_size > 0, "the block size must be positive");
+    if data.is_empty() {
+        return Ok(BlockWrite {
+            offset,
+            data: vec![],
+            file_size,
+        });
+    }
+
+    let bs = block_size as u64;
+    let end = offset
+        .checked_add(data.len() as u64)
+        .ok_or_else(|| io::Error::from_raw_os_error(libc::EFBIG))?;
+    let start = offset - offset % bs;
+    let aligned_end = match end % bs {
+        0 => end,
+        rem => end
+            .checked_add(bs - rem)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EFBIG))?,
+    };
+
+    let mut buf = vec![0u8; (aligned_end - start) as usize];
+
+    let mut merge = |block_offset: u64| -> io::Result<()> {
+        if block_offset >= file_size {
+            return Ok(());
+        }
+        let block = fetch(block_offset, block_size)?;
+        let valid = cmp::min(
+            cmp::min(block.len(), block_size) as u64,
+            file_size - block_offset,
+        ) as usize;
+        let pos = (block_offset - start) as usize;
+        buf[pos..pos + valid].copy_from_slice(&block[..valid]);
+        Ok(())
+    };
+
+    let head_partial = offset > start;
+    if head_partial {
+        merge(start)?;
+    }
+    let tail_start = aligned_end - bs;
+    if end < aligned_end && !(head_partial && tail_start == start) {
+        merge(tail_start)?;
+    }
+
+    let pos = (offset - start) as usize;
+    buf[pos..pos + data.len()].copy_from_slice(data);
+
+    Ok(BlockWrite {
+        offset: start,
+        data: buf,
+        file_size: cmp::max(file_size, end),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A backend of 4-byte blocks, which keeps the stale bytes beyond the
+    /// end of file, and records the fetched offsets.
+    struct Backend {
+        contents: Vec<u8>,
+        fetched: Vec<u64>,
+    }
+
+    impl Backend {
+        fn fetch(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+            self.fetched.push(offset);
+            let start = cmp::min(offset as usize, self.contents.len());
+            let end = cmp::min(start + len, self.contents.len());
+            Ok(self.contents[start..end].to_vec())
+        }
+    }
+
+    #[test]
+    fn merge_partial_blocks() {
+        let mut backend = Backend {
+            contents: b"abcdefghijkl".to_vec(),
+            fetched: vec![],
+        };
+        let write = align_write(4, 12, 2, b"XYZW", |o, l| backend.fetch(o, l)).unwrap();
+        assert_eq!(write.offset(), 0);
+        assert_eq!(write.data(), b"abXYZWgh");
+        assert_eq!(write.file_size(), 12);
+        assert_eq!(backend.fetched, [0, 4]);
+
+        // Both ends in the same block.
+        backend.fetched.clear();
+        let write = align_write(4, 12, 5, b"X", |o, l| backend.fetch(o, l)).unwrap();
+        assert_eq!(write.offset(), 4);
+        assert_eq!(write.data(), b"eXgh");
+        assert_eq!(backend.fetched, [4]);
+
+        // Aligned writes fetch nothing.
+        backend.fetched.clear();
+        let write = align_write(4, 12, 4, b"XYZW", |o, l| backend.fetch(o, l)).unwrap();
+        assert_eq!(write.data(), b"XYZW");
+        assert!(backend.fetched.is_empty());
+    }
+
+    #[test]
+    fn extend_beyond_eof() {
+        // The file has been truncated to 6 bytes, leaving the stale bytes.
+        let mut backend = Backend {
+            contents: b"abcdefgh".to_vec(),
+            fetched: vec![],
+        };
+        let write = align_write(4, 6, 7, b"XY", |o, l| backend.fetch(o, l)).unwrap();
+        assert_eq!(write.offset(), 4);
+        assert_eq!(write.data(), b"ef\0XY\0\0\0");
+        assert_eq!(write.file_size(), 9);
+        assert_eq!(backend.fetched, [4]);
+
+        // The blocks beyond the end of file are not fetched.
+        backend.fetched.clear();
+        let write = align_write(4, 6, 13, b"X", |o, l| backend.fetch(o, l)).unwrap();
+        assert_eq!(write.offset(), 12);
+        assert_eq!(write.data(), b"\0X\0\0");
+        assert_eq!(write.file_size(), 14);
+        assert!(backend.fetched.is_empty());
+
+        let err = align_write(4, 0, u64::MAX, b"X", |o, l| backend.fetch(o, l)).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EFBIG));
+    }
+}
@@ -0,0 +1,365 @@
+use crate::{op::Operation, session::Request};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Condvar, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+/// Serialization of the mutating requests on each inode in the order of
+/// their arrival.
+///
+/// When the requests are handled concurrently, two requests on the same
+/// inode may complete in either order, e.g. a `Setattr` truncating a file
+/// may overtake the `Write` that the kernel sent before it. The serializer
+/// hands out a ticket per request on the thread reading the requests, and
+/// each ticket waits until the tickets taken before it on the same inodes
+/// are dropped, while the requests on the other inodes proceed in parallel:
+///
+/// ```ignore
+/// let serializer = InodeSerializer::new();
+/// while let Some(req) = session.next_request()? {
+///     let ticket = serializer.enqueue_request(&req);
+///     tokio::spawn(async move {
+///         ticket.turn().await;
+///         handle(req).await;
+///         // The next request on the inode starts when `ticket` is dropped.
+///     });
+/// }
+/// ```
+///
+/// [`WorkerOptions::serialize_by_inode`](super::WorkerOptions::serialize_by_inode)
+/// does the same for the requests dispatched to a [`WorkerPool`](super::WorkerPool).
+#[derive(Clone, Default)]
+pub struct InodeSerializer {
+    shared: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    queues: HashMap<u64, VecDeque<u64>>,
+    wakers: HashMap<u64, Waker>,
+}
+
+impl State {
+    fn is_first(&self, id: u64, keys: &[u64]) -> bool {
+        keys.iter()
+            .all(|key| self.queues.get(key).and_then(|q| q.front()) == Some(&id))
+    }
+}
+
+impl fmt::Debug for InodeSerializer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InodeSerializer")
+            .field("inodes", &self.len())
+            .finish()
+    }
+}
+
+impl InodeSerializer {
+    /// Create an empty serializer.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return the number of inodes with the outstanding tickets.
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().queues.len()
+    }
+
+    /// Return whether no ticket is outstanding.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Take a ticket ordered after the outstanding tickets on any of the
+    /// inodes.
+    ///
+    /// A ticket without the inodes is ready immediately.
+    pub fn enqueue<I>(&self, inodes: I) -> InodeTicket
+    where
+        I: IntoIterator<Item = u64>,
+    {
+        let mut keys: Vec<u64> = inodes.into_iter().collect();
+        keys.sort_unstable();
+        keys.dedup();
+
+        let mut state = self.shared.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        for &key in &keys {
+            state.queues.entry(key).or_default().push_back(id);
+        }
+        drop(state);
+
+        InodeTicket {
+            shared: self.shared.clone(),
+            id,
+            keys,
+        }
+    }
+
+    /// Take a ticket for the request, keyed by the inodes it mutates.
+    ///
+    /// This must be called in the order the requests are read from the
+    /// kernel. See [`mutated_inodes`] for the inodes of each request.
+    pub fn enqueue_request(&self, req: &Request) -> InodeTicket {
+        let inodes = match req.operation() {
+            Ok(op) => mutated_inodes(&op),
+            Err(..) => vec![req.nodeid()],
+        };
+        self.enqueue(inodes)
+    }
+}
+
+/// Return the inodes whose mutations the operation must be ordered with.
+///
+/// The operations creating, removing or renaming an entry are keyed by the
+/// parent directories, and `Link` and `CopyFileRange` by both of their
+/// inodes. The other operations changing the contents or the attributes,
+/// including `Open`, `Flush`, `Fsync` and `Release` that may write back
+/// the data, are keyed by the target inode.
+///
+/// The read-only operations, such as `Lookup`, `Getattr`, `Read` and
+/// `Readdir`, are not ordered at all, since the kernel does not send them
+/// before the mutations they depend on have been replied to. Neither is
+/// the inode moved by `Rename`, which is not known from the request.
+///
+/// Nor are `Setlk` and `Flock`. A blocking lock request waits in the
+/// filesystem until the conflicting lock is released, so the unlock
+/// request of the holder must not be queued behind it.
+pub fn mutated_inodes<T>(op: &Operation<'_, T>) -> Vec<u64> {
+    match op {
+        Operation::Lookup(..)
+        | Operation::Getattr(..)
+        | Operation::Readlink(..)
+        | Operation::Read(..)
+        | Operation::Statfs(..)
+        | Operation::Getxattr(..)
+        | Operation::Listxattr(..)
+        | Operation::Opendir(..)
+        | Operation::Readdir(..)
+        | Operation::Releasedir(..)
+        | Operation::Getlk(..)
+        | Operation::Setlk(..)
+        | Operation::Flock(..)
+        | Operation::Access(..)
+        | Operation::Bmap(..)
+        | Operation::Poll(..)
+        | Operation::Lseek(..)
+        | Operation::Statx(..)
+        | Operation::Forget(..)
+        | Operation::Interrupt(..)
+        | Operation::NotifyReply(..)
+        | Operation::Unknown => vec![],
+
+        Operation::Setattr(op) => vec![op.ino()],
+        Operation::Symlink(op) => vec![op.parent()],
+        Operation::Mknod(op) => vec![op.parent()],
+        Operation::Mkdir(op) => vec![op.parent()],
+        Operation::Unlink(op) => vec![op.parent()],
+        Operation::Rmdir(op) => vec![op.parent()],
+        Operation::Rename(op) => vec![op.parent(), op.newparent()],
+        Operation::Link(op) => vec![op.ino(), op.newparent()],
+        Operation::Open(op) => vec![op.ino()],
+        Operation::Write(op, _) => vec![op.ino()],
+        Operation::Release(op) => vec![op.ino()],
+        Operation::Fsync(op) => vec![op.ino()],
+        Operation::Setxattr(op) => vec![op.ino()],
+        Operation::Removexattr(op) => vec![op.ino()],
+        Operation::Flush(op) => vec![op.ino()],
+        Operation::Fsyncdir(op) => vec![op.ino()],
+        Operation::Create(op) => vec![op.parent()],
+        Operation::Fallocate(op) => vec![op.ino()],
+        Operation::CopyFileRange(op) => vec![op.ino_in(), op.ino_out()],
+        Operation::Ioctl(op) => vec![op.ino()],
+    }
+}
+
+/// The turn of a request in [`InodeSerializer`].
+///
+/// Dropping the ticket lets the next tickets on its inodes proceed, whether
+/// or not it has waited for its turn.
+pub struct InodeTicket {
+    shared: Arc<Shared>,
+    id: u64,
+    keys: Vec<u64>,
+}
+
+impl fmt::Debug for InodeTicket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InodeTicket")
+            .field("id", &self.id)
+            .field("inodes", &self.keys)
+            .finish()
+    }
+}
+
+impl InodeTicket {
+    /// Return the inodes the ticket is ordered on.
+    pub fn inodes(&self) -> &[u64] {
+        &self.keys
+    }
+
+    /// Return whether the preceding tickets on the inodes have been dropped.
+    pub fn is_ready(&self) -> bool {
+        self.shared
+            .state
+            .lock()
+            .unwrap()
+            .is_first(self.id, &self.keys)
+    }
+
+    /// Block the current thread until the preceding tickets on the inodes
+    /// are dropped.
+    pub fn wait(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        while !state.is_first(self.id, &self.keys) {
+            state = self.shared.cond.wait(state).unwrap();
+        }
+    }
+
+    /// Wait asynchronously until the preceding tickets on the inodes are
+    /// dropped.
+    pub fn turn(&self) -> impl Future<Output = ()> + '_ {
+        Turn(self)
+    }
+}
+
+impl Drop for InodeTicket {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.wakers.remove(&self.id);
+
+        let mut next = Vec::with_capacity(self.keys.len());
+        for key in &self.keys {
+            let queue = match state.queues.get_mut(key) {
+                Some(queue) => queue,
+                None => continue,
+            };
+            if let Some(pos) = queue.iter().position(|&id| id == self.id) {
+                queue.remove(pos);
+            }
+            match queue.front() {
+                Some(&id) => next.push(id),
+                None => {
+                    state.queues.remove(key);
+                }
+            }
+        }
+
+        for id in next {
+            if let Some(waker) = state.wakers.get(&id) {
+                waker.wake_by_ref();
+            }
+        }
+        drop(state);
+        self.shared.cond.notify_all();
+    }
+}
+
+struct Turn<'a>(&'a InodeTicket);
+
+impl Future for Turn<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let ticket = self.0;
+        let mut state = ticket.shared.state.lock().unwrap();
+        if state.is_first(ticket.id, &ticket.keys) {
+            state.wakers.remove(&ticket.id);
+            return Poll::Ready(());
+        }
+        state.wakers.insert(ticket.id, cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::decode_request;
+    use polyfuse_kernel::*;
+    use std::{mem, thread, time::Duration};
+    use zerocopy::AsBytes;
+
+    #[test]
+    fn order_per_inode() {
+        let serializer = InodeSerializer::new();
+        let write = serializer.enqueue(vec![2]);
+        let rename = serializer.enqueue(vec![1, 3]);
+        let setattr = serializer.enqueue(vec![2]);
+        let link = serializer.enqueue(vec![2, 3, 3]);
+        let lookup = serializer.enqueue(vec![]);
+        assert_eq!(link.inodes(), &[2, 3]);
+        assert_eq!(serializer.len(), 3);
+
+        assert!(write.is_ready());
+        assert!(rename.is_ready());
+        assert!(!setattr.is_ready());
+        assert!(!link.is_ready());
+        assert!(lookup.is_ready());
+
+        // Dropping a ticket before its turn does not block the others.
+        drop(setattr);
+        assert!(!link.is_ready());
+
+        let waiter = thread::spawn(move || {
+            link.wait();
+            link
+        });
+        thread::sleep(Duration::from_millis(10));
+        drop(write);
+        drop(rename);
+        let link = waiter.join().unwrap();
+        assert!(link.is_ready());
+
+        drop(link);
+        drop(lookup);
+        assert!(serializer.is_empty());
+    }
+
+    #[test]
+    fn locks_are_not_ordered() {
+        let lk_in = |typ: u32| fuse_lk_in {
+            owner: 1,
+            lk: fuse_file_lock {
+                start: 0,
+                end: u64::MAX,
+                typ,
+                pid: 10,
+            },
+            ..Default::default()
+        };
+        let header = |opcode: fuse_opcode| fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + mem::size_of::<fuse_lk_in>()) as u32,
+            opcode: opcode as u32,
+            nodeid: 2,
+            ..Default::default()
+        };
+
+        let serializer = InodeSerializer::new();
+        let setlkw_in = lk_in(libc::F_WRLCK as u32);
+        let setlkw_header = header(fuse_opcode::FUSE_SETLKW);
+        let setlkw = decode_request(&setlkw_header, setlkw_in.as_bytes()).unwrap();
+        let waiter = serializer.enqueue(mutated_inodes(&setlkw));
+
+        // The unlock of the holder proceeds while the SETLKW is waiting.
+        let unlock_in = lk_in(libc::F_UNLCK as u32);
+        let unlock_header = header(fuse_opcode::FUSE_SETLK);
+        let unlock = decode_request(&unlock_header, unlock_in.as_bytes()).unwrap();
+        let holder = serializer.enqueue(mutated_inodes(&unlock));
+        assert!(holder.is_ready());
+        assert!(serializer.is_empty());
+        drop(waiter);
+    }
+}
@@ -0,0 +1,155 @@
+use crate::{op, reply::FileAttr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Apply the changes requested by `Setattr` to the attributes.
+///
+/// The timestamps requested as [`SetAttrTime::Now`](op::SetAttrTime::Now)
+/// are set to `now`, which should be truncated to the granularity of the
+/// timestamps beforehand. In addition to the requested changes, the rules
+/// of `utimensat(2)` and `truncate(2)` are followed:
+///
+/// * the file type bits are preserved on `chmod`,
+/// * a change of the size updates the modification time, unless it is
+///   requested explicitly,
+/// * any change updates the change time, unless it is requested explicitly.
+///
+/// `true` is returned if any of the attributes have been changed.
+///
+/// ```ignore
+/// Operation::Setattr(op) => {
+///     let mut inode = fs.inode_mut(op.ino())?;
+///     if let Some(size) = op.size() {
+///         inode.data.resize(size as usize, 0);
+///     }
+///     let now = UNIX_EPOCH + session.time_gran().now();
+///     apply_setattr(&mut inode.attr, &op, now);
+/// }
+/// ```
+pub fn apply_setattr(attr: &mut FileAttr, op: &op::Setattr<'_>, now: SystemTime) -> bool {
+    let mut changed = false;
+
+    if let Some(mode) = op.mode() {
+        attr.mode((attr.get_mode() & libc::S_IFMT) | (mode & !libc::S_IFMT));
+        changed = true;
+    }
+    if let Some(uid) = op.uid() {
+        attr.uid(uid);
+        changed = true;
+    }
+    if let Some(gid) = op.gid() {
+        attr.gid(gid);
+        changed = true;
+    }
+    if let Some(size) = op.size() {
+        attr.size(size);
+        if op.mtime().is_none() {
+            attr.mtime(since_epoch(now));
+        }
+        changed = true;
+    }
+    if let Some(atime) = op.atime() {
+        attr.atime(since_epoch(atime.resolve(now)));
+        changed = true;
+    }
+    if let Some(mtime) = op.mtime() {
+        attr.mtime(since_epoch(mtime.resolve(now)));
+        changed = true;
+    }
+
+    match op.ctime() {
+        Some(ctime) => {
+            attr.ctime(ctime);
+            changed = true;
+        }
+        None if changed => attr.ctime(since_epoch(now)),
+        None => (),
+    }
+
+    changed
+}
+
+// The times before the Unix epoch cannot be represented in FUSE.
+fn since_epoch(time: SystemTime) -> Duration {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{op::Operation, reply::AttrOut};
+    use polyfuse_kernel::*;
+    use std::mem;
+    use zerocopy::AsBytes as _;
+
+    fn setattr(arg: &fuse_setattr_in, f: impl FnOnce(&op::Setattr<'_>)) {
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + mem::size_of::<fuse_setattr_in>()) as u32,
+            opcode: fuse_opcode::FUSE_SETATTR as u32,
+            nodeid: 2,
+            ..Default::default()
+        };
+        match Operation::decode(&header, arg.as_bytes(), ()).unwrap() {
+            Operation::Setattr(op) => f(&op),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn chmod_and_touch() {
+        let now = UNIX_EPOCH + Duration::from_secs(100);
+        let mut out = AttrOut::default();
+        out.attr().mode(libc::S_IFREG | 0o644);
+
+        let arg = fuse_setattr_in {
+            valid: FATTR_MODE | FATTR_ATIME | FATTR_MTIME | FATTR_MTIME_NOW,
+            mode: libc::S_IFDIR | 0o600,
+            atime: 42,
+            ..Default::default()
+        };
+        setattr(&arg, |op| assert!(apply_setattr(out.attr(), op, now)));
+
+        let attr = out.attr();
+        assert_eq!(attr.get_mode(), libc::S_IFREG | 0o600);
+        assert_eq!(attr.get_atime(), Duration::from_secs(42));
+        assert_eq!(attr.get_mtime(), Duration::from_secs(100));
+        assert_eq!(attr.get_ctime(), Duration::from_secs(100));
+    }
+
+    #[test]
+    fn truncate() {
+        let now = UNIX_EPOCH + Duration::from_secs(100);
+        let mut out = AttrOut::default();
+        out.attr().size(4096);
+
+        let arg = fuse_setattr_in {
+            valid: FATTR_SIZE | FATTR_CTIME,
+            size: 10,
+            ctime: 7,
+            ..Default::default()
+        };
+        setattr(&arg, |op| assert!(apply_setattr(out.attr(), op, now)));
+
+        let attr = out.attr();
+        assert_eq!(attr.get_size(), 10);
+        assert_eq!(attr.get_mtime(), Duration::from_secs(100));
+        assert_eq!(attr.get_ctime(), Duration::from_secs(7));
+
+        // Nothing is requested, e.g. only the lock owner is set.
+        let arg = fuse_setattr_in {
+            valid: FATTR_LOCKOWNER,
+            ..Default::default()
+        };
+        setattr(&arg, |op| assert!(!apply_setattr(out.attr(), op, now)));
+        assert_eq!(out.attr().get_ctime(), Duration::from_secs(7));
+    }
+
+    #[test]
+    fn resolve_time() {
+        let now = UNIX_EPOCH + Duration::from_secs(100);
+        assert_eq!(op::SetAttrTime::Now.resolve(now), now);
+        assert_eq!(
+            op::SetAttrTime::Timespec(Duration::from_secs(5)).resolve(now),
+            UNIX_EPOCH + Duration::from_secs(5)
+        );
+    }
+}
@@ -0,0 +1,269 @@
+use crate::session::Session;
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read as _},
+    os::unix::prelude::*,
+    sync::atomic::{AtomicI32, Ordering},
+    thread::{self, JoinHandle},
+};
+
+/// The signals requesting the daemon to shut down.
+const SIGNALS: &[libc::c_int] = &[libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+/// The write end of the pipe the signal handler writes to, or `-1`.
+static PIPE_WRITER: AtomicI32 = AtomicI32::new(-1);
+
+/// The handlers of `SIGINT`, `SIGTERM` and `SIGHUP` for shutting down the
+/// session gracefully.
+///
+/// If the daemon is killed by these signals, the filesystem is left mounted
+/// without its daemon, and any access to it fails with `ENOTCONN` until it is
+/// unmounted by hand. Instead, the session should be unmounted on the
+/// signals, so that [`Session::next_request`] returns `None` once the kernel
+/// releases the mount and the in-flight requests can be drained:
+///
+/// ```ignore
+/// let session = Session::mount(mountpoint, config)?;
+/// let _unmounter = ShutdownSignals::install()?.spawn_unmounter(&session)?;
+///
+/// while let Some(req) = session.next_request()? {
+///     // ...
+/// }
+/// // Wait for the pending replies here.
+/// ```
+///
+/// The handlers write the signal numbers to a non-blocking pipe, whose read
+/// end is exposed with `AsRawFd`, so the asynchronous runtimes can wait for
+/// the signals along with the requests, e.g. with `AsyncFd` of tokio:
+///
+/// ```ignore
+/// let signals = AsyncFd::new(ShutdownSignals::install()?)?;
+/// loop {
+///     tokio::select! {
+///         req = session.next_request() => { /* ... */ }
+///         guard = signals.readable() => {
+///             if let Some(signo) = guard?.get_inner().try_recv()? {
+///                 tracing::info!("received signal {}", signo);
+///                 session.unmount()?;
+///             }
+///         }
+///     }
+/// }
+/// ```
+///
+/// The signals ignored at the installation, e.g. `SIGHUP` under `nohup`,
+/// are left ignored. The previous dispositions of the signals are restored
+/// on drop, so a second signal after the handlers are gone terminates the
+/// process as usual. Only one instance can be installed at a time.
+pub struct ShutdownSignals {
+    reader: File,
+    writer: File,
+    previous: Vec<(libc::c_int, libc::sigaction)>,
+}
+
+impl fmt::Debug for ShutdownSignals {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let signals: Vec<_> = self.previous.iter().map(|&(signo, _)| signo).collect();
+        f.debug_struct("ShutdownSignals")
+            .field("reader", &self.reader)
+            .field("signals", &signals)
+            .finish()
+    }
+}
+
+impl ShutdownSignals {
+    /// Install the signal handlers.
+    pub fn install() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        let res = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let (reader, writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+        if PIPE_WRITER
+            .compare_exchange(-1, writer.as_raw_fd(), Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "the shutdown signals are already installed",
+            ));
+        }
+        let mut signals = Self {
+            reader,
+            writer,
+            previous: Vec::with_capacity(SIGNALS.len()),
+        };
+
+        // The handlers already installed are restored by `drop` on error.
+        for &signo in SIGNALS {
+            let previous = get_action(signo)?;
+            if previous.sa_sigaction == libc::SIG_IGN {
+                continue;
+            }
+            set_handler(
+                signo,
+                on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            )?;
+            signals.previous.push((signo, previous));
+        }
+
+        Ok(signals)
+    }
+
+    /// Wait until one of the signals is received, and return its number.
+    pub fn wait(&self) -> io::Result<libc::c_int> {
+        loop {
+            if let Some(signo) = self.try_recv()? {
+                return Ok(signo);
+            }
+            let mut pollfd = libc::pollfd {
+                fd: self.reader.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let res = unsafe { libc::poll(&mut pollfd, 1, -1) };
+            if res == -1 {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    /// Return the number of a received signal, if any.
+    pub fn try_recv(&self) -> io::Result<Option<libc::c_int>> {
+        let mut buf = [0u8; 1];
+        match (&self.reader).read(&mut buf) {
+            Ok(1) => Ok(Some(libc::c_int::from(buf[0]))),
+            Ok(..) => Ok(None),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Spawn a thread unmounting the session when one of the signals is
+    /// received.
+    ///
+    /// The thread stops after unmounting the session, restoring the previous
+    /// dispositions of the signals. It does not keep the session alive, and
+    /// does nothing if the session has already been dropped.
+    pub fn spawn_unmounter(self, session: &Session) -> io::Result<JoinHandle<()>> {
+        let unmount = session.unmounter();
+        thread::Builder::new()
+            .name("polyfuse-signal".into())
+            .spawn(move || {
+                let signo = match self.wait() {
+                    Ok(signo) => signo,
+                    Err(err) => {
+                        tracing::error!("failed to wait for the signals: {}", err);
+                        return;
+                    }
+                };
+                tracing::info!("received signal {}, unmounting the filesystem", signo);
+                if let Err(err) = unmount() {
+                    tracing::error!("failed to unmount the filesystem: {}", err);
+                }
+            })
+    }
+}
+
+impl AsRawFd for ShutdownSignals {
+    fn as_raw_fd(&self) -> RawFd {
+        self.reader.as_raw_fd()
+    }
+}
+
+impl Drop for ShutdownSignals {
+    fn drop(&mut self) {
+        for (signo, action) in &self.previous {
+            unsafe { libc::sigaction(*signo, action, std::ptr::null_mut()) };
+        }
+        let _ = PIPE_WRITER.compare_exchange(
+            self.writer.as_raw_fd(),
+            -1,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+    }
+}
+
+fn get_action(signo: libc::c_int) -> io::Result<libc::sigaction> {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        if libc::sigaction(signo, std::ptr::null(), &mut action) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(action)
+    }
+}
+
+fn set_handler(signo: libc::c_int, handler: libc::sighandler_t) -> io::Result<()> {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(signo, &action, std::ptr::null_mut()) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Only async-signal-safe functions are called here.
+extern "C" fn on_signal(signo: libc::c_int) {
+    let fd = PIPE_WRITER.load(Ordering::SeqCst);
+    if fd != -1 {
+        let buf = [signo as u8];
+        unsafe {
+            let errno = *libc::__errno_location();
+            libc::write(fd, buf.as_ptr() as *const libc::c_void, 1);
+            *libc::__errno_location() = errno;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn receive_signal() {
+        let signals = ShutdownSignals::install().unwrap();
+        assert_eq!(
+            ShutdownSignals::install().unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(signals.try_recv().unwrap(), None);
+
+        unsafe { libc::raise(libc::SIGHUP) };
+        assert_eq!(signals.wait().unwrap(), libc::SIGHUP);
+        assert_eq!(signals.try_recv().unwrap(), None);
+
+        drop(signals);
+        assert_eq!(
+            get_action(libc::SIGHUP).unwrap().sa_sigaction,
+            libc::SIG_DFL
+        );
+
+        // The ignored signals are left ignored, as with `nohup`.
+        set_handler(libc::SIGHUP, libc::SIG_IGN).unwrap();
+        let signals = ShutdownSignals::install().unwrap();
+        assert_eq!(
+            get_action(libc::SIGHUP).unwrap().sa_sigaction,
+            libc::SIG_IGN
+        );
+        unsafe { libc::raise(libc::SIGHUP) };
+        assert_eq!(signals.try_recv().unwrap(), None);
+        drop(signals);
+        assert_eq!(
+            get_action(libc::SIGHUP).unwrap().sa_sigaction,
+            libc::SIG_IGN
+        );
+        set_handler(libc::SIGHUP, libc::SIG_DFL).unwrap();
+    }
+}
@@ -0,0 +1,302 @@
+use crate::{
+    conn::{self, MountOptions},
+    error::Error,
+    session::{KernelConfig, Session},
+};
+use std::{
+    ffi::OsStr,
+    fmt, fs, io,
+    os::unix::prelude::*,
+    path::{Path, PathBuf},
+};
+
+/// A helper for recovering the mount left by a crashed daemon.
+///
+/// The supervisor records the parameters of the mount, along with the
+/// process ID of the daemon, in a state file. When the daemon is started
+/// again after a crash, [`mount`](MountSupervisor::mount) finds the record of
+/// the dead daemon, detaches its stale mount (whose accesses fail with
+/// `ENOTCONN`) by a lazy unmount, and mounts the filesystem again:
+///
+/// ```ignore
+/// let supervisor = MountSupervisor::new("/mnt", "/run/myfs.mount");
+/// supervisor.run(KernelConfig::default, |session| {
+///     while let Some(req) = session.next_request()? {
+///         fs.handle(req)?;
+///     }
+///     Ok(())
+/// })?;
+/// ```
+///
+/// Note that the mounts with `auto_unmount`, which is enabled by default,
+/// are detached by `fusermount` when the daemon dies, unless `fusermount`
+/// itself has been killed.
+#[derive(Debug)]
+pub struct MountSupervisor {
+    mountpoint: PathBuf,
+    state_path: PathBuf,
+    max_restarts: usize,
+}
+
+/// The parameters of a mount recorded by [`MountSupervisor`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct MountRecord {
+    pid: u32,
+    mountpoint: PathBuf,
+    options: Vec<String>,
+}
+
+impl fmt::Display for MountRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (pid={})", self.mountpoint.display(), self.pid)
+    }
+}
+
+impl MountRecord {
+    /// Return the process ID of the daemon that mounted the filesystem.
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Return the path of the mountpoint.
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// Return the mount options passed to `fusermount`.
+    pub fn options(&self) -> &[String] {
+        &self.options
+    }
+
+    // Each field, including every option, is terminated by NUL, which
+    // cannot appear in them. The options may contain commas in their values.
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = format!("{}\0", self.pid).into_bytes();
+        buf.extend_from_slice(self.mountpoint.as_os_str().as_bytes());
+        buf.push(b'\0');
+        for opt in &self.options {
+            buf.extend_from_slice(opt.as_bytes());
+            buf.push(b'\0');
+        }
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let (last, buf) = buf.split_last()?;
+        if *last != b'\0' {
+            return None;
+        }
+        let mut fields = buf.split(|&b| b == b'\0');
+        let pid = std::str::from_utf8(fields.next()?).ok()?.parse().ok()?;
+        let mountpoint = PathBuf::from(OsStr::from_bytes(fields.next()?));
+        let options = fields
+            .map(|opt| std::str::from_utf8(opt).ok().map(ToOwned::to_owned))
+            .collect::<Option<_>>()?;
+        Some(Self {
+            pid,
+            mountpoint,
+            options,
+        })
+    }
+}
+
+impl MountSupervisor {
+    /// Create a supervisor of the mount on `mountpoint`, recording its
+    /// parameters at `state_path`.
+    pub fn new(mountpoint: impl Into<PathBuf>, state_path: impl Into<PathBuf>) -> Self {
+        Self {
+            mountpoint: mountpoint.into(),
+            state_path: state_path.into(),
+            max_restarts: 3,
+        }
+    }
+
+    /// Set the maximum number of times [`run`](Self::run) remounts the
+    /// filesystem after the session failed.
+    ///
+    /// The default is 3.
+    pub fn max_restarts(&mut self, max_restarts: usize) -> &mut Self {
+        self.max_restarts = max_restarts;
+        self
+    }
+
+    /// Return the record of the current mount, if any.
+    pub fn record(&self) -> io::Result<Option<MountRecord>> {
+        match fs::read(&self.state_path) {
+            Ok(buf) => match MountRecord::decode(&buf) {
+                Some(record) => Ok(Some(record)),
+                None => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "malformed mount record",
+                )),
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Detach the stale mount left by a dead daemon, if any.
+    ///
+    /// `true` is returned if the stale mount has been unmounted. If the
+    /// recorded daemon is still alive, the mount is left as is and `EBUSY`
+    /// is returned.
+    pub fn recover(&self) -> io::Result<bool> {
+        if let Some(record) = self.record()? {
+            if record.pid != std::process::id() && is_alive(record.pid) {
+                tracing::error!(
+                    pid = record.pid,
+                    "the filesystem is still served by another daemon"
+                );
+                return Err(io::Error::from_raw_os_error(libc::EBUSY));
+            }
+        }
+
+        if !is_stale(&self.mountpoint) {
+            return Ok(false);
+        }
+        tracing::warn!(
+            "unmounting the stale mount on {}",
+            self.mountpoint.display()
+        );
+        conn::lazy_unmount(&MountOptions::default(), &self.mountpoint)?;
+        Ok(true)
+    }
+
+    /// Mount the filesystem after recovering the stale mount, and record
+    /// the parameters of the new mount.
+    pub fn mount(&self, config: KernelConfig) -> Result<Session, Error> {
+        self.recover()?;
+
+        let record = MountRecord {
+            pid: std::process::id(),
+            mountpoint: self.mountpoint.clone(),
+            options: config.mount_options().to_vec(),
+        };
+        let session = Session::mount(self.mountpoint.clone(), config)?;
+        self.write_record(&record)?;
+        Ok(session)
+    }
+
+    /// Serve the filesystem with `f`, remounting it with a fresh session
+    /// whenever `f` fails.
+    ///
+    /// The record is removed when `f` returns successfully, i.e. when the
+    /// filesystem has been unmounted normally. The error is returned after
+    /// the restarts reach the limit set by
+    /// [`max_restarts`](Self::max_restarts).
+    pub fn run<C, F>(&self, mut config: C, mut f: F) -> Result<(), Error>
+    where
+        C: FnMut() -> KernelConfig,
+        F: FnMut(&Session) -> Result<(), Error>,
+    {
+        let mut restarts = 0;
+        loop {
+            let session = self.mount(config())?;
+            match f(&session) {
+                Ok(()) => {
+                    drop(session);
+                    self.clear()?;
+                    return Ok(());
+                }
+                Err(err) if restarts < self.max_restarts => {
+                    tracing::error!("restarting the session: {}", err);
+                    restarts += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Remove the record of the mount.
+    pub fn clear(&self) -> io::Result<()> {
+        match fs::remove_file(&self.state_path) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+
+    fn write_record(&self, record: &MountRecord) -> io::Result<()> {
+        // Replace the record atomically so that a crash never leaves a torn one.
+        let mut tmp = self.state_path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, record.encode())?;
+        fs::rename(&tmp, &self.state_path)
+    }
+}
+
+fn is_alive(pid: u32) -> bool {
+    let res = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    res == 0 || io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+/// Return whether the mountpoint refers to a FUSE mount without its daemon.
+fn is_stale(mountpoint: &Path) -> bool {
+    match fs::metadata(mountpoint) {
+        Err(err) => matches!(
+            err.raw_os_error(),
+            Some(libc::ENOTCONN) | Some(libc::ECONNABORTED)
+        ),
+        Ok(..) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_roundtrip() {
+        let record = MountRecord {
+            pid: 42,
+            mountpoint: PathBuf::from("/mnt/with,comma"),
+            options: vec![
+                "ro".into(),
+                "fsname=a,b".into(),
+                "context=system_u:object_r:tmp_t:s0:c1,c2".into(),
+            ],
+        };
+        assert_eq!(MountRecord::decode(&record.encode()), Some(record));
+        let record = MountRecord {
+            pid: 42,
+            mountpoint: PathBuf::from("/mnt"),
+            options: vec![],
+        };
+        assert_eq!(MountRecord::decode(&record.encode()), Some(record));
+        assert_eq!(MountRecord::decode(b"x\0/mnt\0\0"), None);
+        assert_eq!(MountRecord::decode(b"42\0/mnt"), None);
+    }
+
+    #[test]
+    fn recover_dead_daemon() {
+        let dir = std::env::temp_dir().join(format!("polyfuse-supervisor-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let supervisor = MountSupervisor::new(&dir, dir.join("state"));
+        assert!(supervisor.record().unwrap().is_none());
+
+        // The recorded daemon has exited and left no stale mount.
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        let record = MountRecord {
+            pid: child.id(),
+            mountpoint: dir.clone(),
+            options: vec![],
+        };
+        supervisor.write_record(&record).unwrap();
+        assert_eq!(supervisor.record().unwrap(), Some(record));
+        assert!(!supervisor.recover().unwrap());
+
+        // The daemon is still alive.
+        let record = MountRecord {
+            pid: 1,
+            mountpoint: dir.clone(),
+            options: vec![],
+        };
+        supervisor.write_record(&record).unwrap();
+        let err = supervisor.recover().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBUSY));
+
+        supervisor.clear().unwrap();
+        assert!(supervisor.record().unwrap().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
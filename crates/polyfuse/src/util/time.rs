@@ -0,0 +1,120 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const NANOS_PER_SEC: u32 = 1_000_000_000;
+
+/// The granularity of the timestamps, negotiated with the kernel by
+/// [`KernelConfig::time_gran`](crate::KernelConfig::time_gran).
+///
+/// The kernel truncates the timestamps it sets by itself, e.g. on writes
+/// with the write-back cache, to this granularity. The filesystem should
+/// truncate its own timestamps in the same way, so that the cached and the
+/// replied attributes do not disagree:
+///
+/// ```
+/// use polyfuse::util::TimeGran;
+/// use std::time::{Duration, UNIX_EPOCH};
+///
+/// let gran = TimeGran::new(1_000_000);
+/// let mtime = gran.timestamp(UNIX_EPOCH + Duration::new(5, 123_456_789));
+/// assert_eq!(mtime, Duration::new(5, 123_000_000));
+/// assert_eq!(TimeGran::to_fuse(mtime), (5, 123_000_000));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeGran {
+    nanos: u32,
+}
+
+impl Default for TimeGran {
+    fn default() -> Self {
+        Self { nanos: 1 }
+    }
+}
+
+impl TimeGran {
+    /// Create the granularity of the specified nanoseconds.
+    ///
+    /// The kernel treats zero as one nanosecond, and the values above one
+    /// second as one second.
+    pub fn new(nanos: u32) -> Self {
+        let nanos = match nanos {
+            0 => 1,
+            n if n > NANOS_PER_SEC => NANOS_PER_SEC,
+            n => n,
+        };
+        Self { nanos }
+    }
+
+    /// Return the granularity in nanoseconds.
+    #[inline]
+    pub fn nanos(self) -> u32 {
+        self.nanos
+    }
+
+    /// Truncate the timestamp, relative to the Unix epoch, to the granularity.
+    pub fn truncate(self, time: Duration) -> Duration {
+        let nanos = time.subsec_nanos();
+        Duration::new(time.as_secs(), nanos - nanos % self.nanos)
+    }
+
+    /// Convert the time to the timestamp relative to the Unix epoch,
+    /// truncated to the granularity.
+    ///
+    /// The times before the Unix epoch, which cannot be represented in the
+    /// attributes of FUSE, are clamped to the epoch.
+    pub fn timestamp(self, time: SystemTime) -> Duration {
+        self.truncate(time.duration_since(UNIX_EPOCH).unwrap_or_default())
+    }
+
+    /// Return the current time truncated to the granularity.
+    pub fn now(self) -> Duration {
+        self.timestamp(SystemTime::now())
+    }
+
+    /// Split the timestamp into the seconds and nanoseconds, as stored in
+    /// the FUSE messages.
+    #[inline]
+    pub fn to_fuse(time: Duration) -> (u64, u32) {
+        (time.as_secs(), time.subsec_nanos())
+    }
+
+    /// Convert the timestamp in the FUSE messages, such as the ones requested
+    /// by `Setattr`, back to the time.
+    ///
+    /// The nanoseconds out of range are carried into the seconds.
+    pub fn to_system_time(secs: u64, nsecs: u32) -> SystemTime {
+        UNIX_EPOCH + Duration::new(secs, 0) + Duration::from_nanos(nsecs.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncate() {
+        let time = Duration::new(7, 999_999_999);
+        assert_eq!(TimeGran::default().truncate(time), time);
+        assert_eq!(TimeGran::new(0).truncate(time), time);
+        assert_eq!(
+            TimeGran::new(1000).truncate(time),
+            Duration::new(7, 999_999_000)
+        );
+        assert_eq!(TimeGran::new(u32::MAX).truncate(time), Duration::new(7, 0));
+    }
+
+    #[test]
+    fn system_time() {
+        let gran = TimeGran::new(10);
+        let time = UNIX_EPOCH + Duration::new(3, 15);
+        assert_eq!(gran.timestamp(time), Duration::new(3, 10));
+        assert_eq!(
+            gran.timestamp(UNIX_EPOCH - Duration::from_secs(1)),
+            Duration::default()
+        );
+        assert_eq!(TimeGran::to_system_time(3, 15), time);
+        assert_eq!(
+            TimeGran::to_system_time(3, 1_500_000_000),
+            UNIX_EPOCH + Duration::new(4, 500_000_000)
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use crate::session::Notifier;
+use std::{ffi::OsStr, fmt, io};
+
+/// A helper for propagating the changes made in the backend to the kernel.
+///
+/// When the directories are modified without going through the kernel, e.g.
+/// by another client of a network filesystem, the filesystem reports the
+/// changes to the watcher, and it sends the notifications so that the dentry
+/// cache, the directory contents and the inotify watchers of the mountpoint
+/// catch up with them:
+///
+/// * a creation invalidates the entry, which may be cached as a negative
+///   one, and the directory,
+/// * a deletion is sent as `notify_delete` if the inode of the entry is
+///   known, so that the inotify watchers see `IN_DELETE`, or invalidates the
+///   entry otherwise,
+/// * a rename invalidates the entries and the directories on both sides.
+///
+/// The notifications for the entries and the inodes that are not cached by
+/// the kernel are ignored.
+#[derive(Clone)]
+pub struct DirWatcher {
+    notifier: Notifier,
+}
+
+impl fmt::Debug for DirWatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DirWatcher").finish()
+    }
+}
+
+impl DirWatcher {
+    /// Create a watcher sending the notifications with `notifier`.
+    pub fn new(notifier: Notifier) -> Self {
+        Self { notifier }
+    }
+
+    /// Report that an entry has been created in the directory.
+    pub fn created(&self, parent: u64, name: &OsStr) -> io::Result<()> {
+        ignore_enoent(self.notifier.inval_entry(parent, name))?;
+        self.inval_dir(parent)
+    }
+
+    /// Report that an entry has been removed from the directory.
+    ///
+    /// `child` is the inode number of the removed entry, if known.
+    pub fn deleted(&self, parent: u64, name: &OsStr, child: Option<u64>) -> io::Result<()> {
+        match child {
+            // The kernel fails with ENOENT if the cached entry refers to
+            // another inode, and it still needs to be invalidated.
+            Some(child) => match self.notifier.delete(parent, child, name) {
+                Err(ref err) if err.raw_os_error() == Some(libc::ENOENT) => {
+                    ignore_enoent(self.notifier.inval_entry(parent, name))?
+                }
+                res => res?,
+            },
+            None => ignore_enoent(self.notifier.inval_entry(parent, name))?,
+        }
+        self.inval_dir(parent)
+    }
+
+    /// Report that an entry has been renamed.
+    ///
+    /// The entry replaced by the rename, if any, does not need to be reported
+    /// separately.
+    pub fn renamed(
+        &self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+    ) -> io::Result<()> {
+        ignore_enoent(self.notifier.inval_entry(parent, name))?;
+        ignore_enoent(self.notifier.inval_entry(newparent, newname))?;
+        self.inval_dir(parent)?;
+        if newparent != parent {
+            self.inval_dir(newparent)?;
+        }
+        Ok(())
+    }
+
+    /// Invalidate the attributes and the cached contents of the directory.
+    fn inval_dir(&self, ino: u64) -> io::Result<()> {
+        ignore_enoent(self.notifier.inval_inode(ino, 0, 0))
+    }
+}
+
+// The kernel returns ENOENT if the target is not in its cache.
+fn ignore_enoent(res: io::Result<()>) -> io::Result<()> {
+    match res {
+        Err(ref err) if err.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+        res => res,
+    }
+}
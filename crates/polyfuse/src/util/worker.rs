@@ -0,0 +1,348 @@
+use super::serial::{InodeSerializer, InodeTicket};
+use crate::session::{Handler, Request, Session};
+use std::{
+    any::Any,
+    convert::Infallible,
+    fmt, io, mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+type Unmounter = Box<dyn FnOnce() -> io::Result<()> + Send>;
+
+/// Options for spawning a [`WorkerPool`].
+#[derive(Debug, Clone, Default)]
+pub struct WorkerOptions {
+    threads: Option<usize>,
+    cpus: Vec<usize>,
+    shard_by_nodeid: bool,
+    serialize_by_inode: bool,
+}
+
+impl WorkerOptions {
+    /// Set the number of worker threads.
+    ///
+    /// The default is the number of CPUs to pin the threads to, or the number
+    /// of online CPUs if the threads are not pinned.
+    pub fn threads(&mut self, threads: usize) -> &mut Self {
+        assert!(threads > 0, "the number of threads must be positive");
+        self.threads = Some(threads);
+        self
+    }
+
+    /// Pin the worker threads to the specified CPUs.
+    ///
+    /// The `i`-th thread is pinned to the `i % cpus.len()`-th CPU in the list.
+    pub fn cpus<I>(&mut self, cpus: I) -> &mut Self
+    where
+        I: IntoIterator<Item = usize>,
+    {
+        self.cpus = cpus.into_iter().collect();
+        self
+    }
+
+    /// Dispatch the requests for the same inode to the same worker thread.
+    ///
+    /// This keeps the per-inode state local to a thread (and its CPU, if
+    /// pinned) and serializes the requests for each inode, at the cost of
+    /// balancing the load. Note that the requests on a directory entry,
+    /// such as `Lookup`, are dispatched by the inode of the parent.
+    /// Otherwise, the requests are taken by any idle worker.
+    pub fn shard_by_nodeid(&mut self, enabled: bool) -> &mut Self {
+        self.shard_by_nodeid = enabled;
+        self
+    }
+
+    /// Run the mutating requests on the same inode in the order the kernel
+    /// sent them, with [`InodeSerializer`].
+    ///
+    /// Unlike [`shard_by_nodeid`](Self::shard_by_nodeid), the requests are
+    /// still taken by any idle worker, and the ones on the other inodes are
+    /// not delayed behind them.
+    pub fn serialize_by_inode(&mut self, enabled: bool) -> &mut Self {
+        self.serialize_by_inode = enabled;
+        self
+    }
+}
+
+/// A [`Handler`] that handles the requests on a pool of worker threads.
+///
+/// ```ignore
+/// let mut options = WorkerOptions::default();
+/// options.cpus(0..4).shard_by_nodeid(true);
+/// let pool = WorkerPool::spawn(&options, move |req| fs.handle(req))?;
+/// session.run(pool)?;
+/// ```
+///
+/// Dropping the pool, e.g. when [`Session::run`] returns, waits for the
+/// workers to finish the queued requests, so none of them is left without
+/// a reply.
+///
+/// The errors returned by the handler (see
+/// [`spawn_fallible`](Self::spawn_fallible)) and the panics are logged, and
+/// the worker goes on with the next request. The request whose handler
+/// panicked is replied to with `EIO`. The session can be unmounted on the
+/// first failure instead with [`unmount_on_error`](Self::unmount_on_error).
+pub struct WorkerPool {
+    queues: Vec<mpsc::Sender<(Request, Option<InodeTicket>)>>,
+    workers: Vec<JoinHandle<()>>,
+    serializer: Option<InodeSerializer>,
+    unmounter: Arc<Mutex<Option<Unmounter>>>,
+}
+
+impl fmt::Debug for WorkerPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WorkerPool")
+            .field("queues", &self.queues.len())
+            .field("workers", &self.workers.len())
+            .finish()
+    }
+}
+
+impl WorkerPool {
+    /// Spawn the worker threads that call `f` for each request.
+    ///
+    /// An error is returned if the threads cannot be spawned or pinned.
+    pub fn spawn<F>(options: &WorkerOptions, f: F) -> io::Result<Self>
+    where
+        F: Fn(Request) + Send + Sync + 'static,
+    {
+        Self::spawn_fallible(options, move |req| {
+            f(req);
+            Ok::<_, Infallible>(())
+        })
+    }
+
+    /// Spawn the worker threads that call `f` for each request, handling
+    /// the errors returned from it.
+    ///
+    /// `f` is expected to reply to the request even on failure, e.g. with
+    /// [`Request::process`], and the returned errors are those that prevent
+    /// the session from going on, such as a failure to send the reply.
+    pub fn spawn_fallible<F, E>(options: &WorkerOptions, f: F) -> io::Result<Self>
+    where
+        F: Fn(Request) -> Result<(), E> + Send + Sync + 'static,
+        E: fmt::Display,
+    {
+        let threads = match (options.threads, options.cpus.len()) {
+            (Some(threads), _) => threads,
+            (None, 0) => num_cpus(),
+            (None, ncpus) => ncpus,
+        };
+        let nqueues = if options.shard_by_nodeid { threads } else { 1 };
+
+        let mut queues = Vec::with_capacity(nqueues);
+        let mut receivers = Vec::with_capacity(nqueues);
+        for _ in 0..nqueues {
+            let (tx, rx) = mpsc::channel();
+            queues.push(tx);
+            receivers.push(Arc::new(Mutex::new(rx)));
+        }
+
+        let f = Arc::new(f);
+        let mut pool = Self {
+            queues,
+            workers: Vec::with_capacity(threads),
+            serializer: if options.serialize_by_inode {
+                Some(InodeSerializer::new())
+            } else {
+                None
+            },
+            unmounter: Arc::new(Mutex::new(None)),
+        };
+        let (pinned_tx, pinned) = mpsc::channel();
+        for i in 0..threads {
+            let queue = receivers[i % nqueues].clone();
+            let cpu = if options.cpus.is_empty() {
+                None
+            } else {
+                Some(options.cpus[i % options.cpus.len()])
+            };
+            let pinned_tx = pinned_tx.clone();
+            let f = f.clone();
+            let unmounter = pool.unmounter.clone();
+            let worker = thread::Builder::new()
+                .name(format!("polyfuse-worker-{}", i))
+                .spawn(move || {
+                    let res = cpu.map_or(Ok(()), pin_to_cpu);
+                    let failed = res.is_err();
+                    let _ = pinned_tx.send(res);
+                    if failed {
+                        return;
+                    }
+                    drop(pinned_tx);
+
+                    loop {
+                        // Release the lock before handling the request.
+                        let (req, ticket) = match queue.lock().unwrap().recv() {
+                            Ok(item) => item,
+                            Err(..) => break,
+                        };
+                        // The earliest outstanding ticket is always held by
+                        // a worker that can run it, so this does not block
+                        // all the workers forever.
+                        if let Some(ref ticket) = ticket {
+                            ticket.wait();
+                        }
+                        let unique = req.unique();
+                        let sender = req.reply_sender();
+                        match panic::catch_unwind(AssertUnwindSafe(|| f(req))) {
+                            Ok(Ok(())) => (),
+                            Ok(Err(err)) => {
+                                tracing::error!(unique, "the handler failed: {}", err);
+                                fail(&unmounter);
+                            }
+                            Err(payload) => {
+                                tracing::error!(
+                                    unique,
+                                    "the handler panicked: {}",
+                                    panic_message(&*payload)
+                                );
+                                // The kernel discards the reply if the handler
+                                // has already replied before panicking.
+                                if let Err(err) = sender.reply_error(libc::EIO) {
+                                    tracing::error!(unique, "failed to reply: {}", err);
+                                }
+                                fail(&unmounter);
+                            }
+                        }
+                        drop(ticket);
+                    }
+                })?;
+            pool.workers.push(worker);
+        }
+        drop(pinned_tx);
+
+        // Dropping the pool on error stops the spawned workers.
+        for res in pinned {
+            res?;
+        }
+
+        Ok(pool)
+    }
+
+    /// Unmount the session on the first error or panic of the handler,
+    /// instead of going on with the next request.
+    ///
+    /// [`Session::run`] then returns once the kernel releases the mount,
+    /// and the requests already queued are still handled.
+    pub fn unmount_on_error(&mut self, session: &Session) -> &mut Self {
+        *self.unmounter.lock().unwrap() = Some(Box::new(session.unmounter()));
+        self
+    }
+
+    fn queue_index(&self, nodeid: u64) -> usize {
+        shard(nodeid, self.queues.len())
+    }
+}
+
+impl Handler for WorkerPool {
+    fn handle(&mut self, req: Request) {
+        let i = self.queue_index(req.nodeid());
+        let ticket = self
+            .serializer
+            .as_ref()
+            .map(|serializer| serializer.enqueue_request(&req));
+        if self.queues[i].send((req, ticket)).is_err() {
+            tracing::error!("the worker thread has been terminated");
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.queues.clear();
+        for worker in mem::take(&mut self.workers) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Unmount the session if requested by `WorkerPool::unmount_on_error`.
+fn fail(unmounter: &Mutex<Option<Unmounter>>) {
+    let unmount = unmounter.lock().unwrap().take();
+    if let Some(unmount) = unmount {
+        tracing::error!("unmounting the filesystem due to the failure");
+        if let Err(err) = unmount() {
+            tracing::error!("failed to unmount the filesystem: {}", err);
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(msg) => msg,
+        None => payload
+            .downcast_ref::<String>()
+            .map_or("Box<dyn Any>", |msg| msg.as_str()),
+    }
+}
+
+#[inline]
+fn shard(nodeid: u64, nqueues: usize) -> usize {
+    // The inode numbers are usually allocated sequentially, so they are
+    // evenly distributed without hashing.
+    (nodeid % nqueues as u64) as usize
+}
+
+fn num_cpus() -> usize {
+    let n = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    if n > 0 {
+        n as usize
+    } else {
+        1
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn pin_to_cpu(cpu: usize) -> io::Result<()> {
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_to_cpu(_cpu: usize) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "CPU affinity is not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shard_by_nodeid() {
+        assert_eq!(shard(1, 1), 0);
+        assert_eq!(shard(5, 4), 1);
+        assert_eq!(shard(u64::MAX, 3), (u64::MAX % 3) as usize);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn pin_current_thread() {
+        thread::spawn(|| {
+            pin_to_cpu(0).unwrap();
+            unsafe {
+                let mut set: libc::cpu_set_t = mem::zeroed();
+                let res = libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set);
+                assert_eq!(res, 0);
+                assert!(libc::CPU_ISSET(0, &set));
+                assert_eq!(libc::CPU_COUNT(&set), 1);
+            }
+        })
+        .join()
+        .unwrap();
+    }
+}
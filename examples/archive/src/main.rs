@@ -0,0 +1,507 @@
+//! Mount a tar or zip archive as a read-only filesystem.
+//!
+//! The directory tree is built from the paths of the archive members at
+//! startup, creating the intermediate directories that the archive does not
+//! list explicitly. The contents of tar members are read directly from the
+//! archive file, while the compressed zip members are inflated when opened
+//! and kept in memory until released.
+//!
+//! ```shell-session
+//! $ cargo run -p polyfuse-example-archive -- ./sources.tar /mnt/archive
+//! ```
+
+#![allow(clippy::unnecessary_mut_passed)]
+
+use polyfuse::{
+    op,
+    reply::{AttrOut, EntryOut, FileAttr, OpenOut, ReaddirOut, StatfsOut},
+    util::DirEntries,
+    KernelConfig, Operation, Replied, Request, Session,
+};
+
+use anyhow::{ensure, Context as _, Result};
+use std::{
+    cmp,
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fs::File,
+    io::{self, Read as _},
+    os::unix::prelude::*,
+    path::{Component, Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+const TTL: Duration = Duration::from_secs(60 * 60 * 24 * 365);
+const ROOT_INO: u64 = 1;
+const BLOCK_SIZE: u64 = 512;
+
+fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let mut args = pico_args::Arguments::from_env();
+
+    let archive: PathBuf = args.free_from_str()?.context("missing archive path")?;
+    let mountpoint: PathBuf = args.free_from_str()?.context("missing mountpoint")?;
+    ensure!(mountpoint.is_dir(), "mountpoint must be a directory");
+
+    let mut fs = ArchiveFS::load(&archive)
+        .with_context(|| format!("failed to load {}", archive.display()))?;
+    tracing::info!(
+        "loaded {} inodes from {}",
+        fs.nodes.len(),
+        archive.display()
+    );
+
+    let session = Session::mount(mountpoint, KernelConfig::default())?;
+
+    while let Some(req) = session.next_request()? {
+        let span = tracing::debug_span!("handle_request", unique = req.unique());
+        let _enter = span.enter();
+
+        let op = req.operation()?;
+        tracing::debug!(?op);
+
+        match op {
+            Operation::Lookup(op) => fs.lookup(&req, op)?,
+            Operation::Getattr(op) => fs.getattr(&req, op)?,
+            Operation::Readlink(op) => fs.readlink(&req, op)?,
+            Operation::Open(op) => fs.open(&req, op)?,
+            Operation::Read(op) => fs.read(&req, op)?,
+            Operation::Release(op) => fs.release(&req, op)?,
+            Operation::Readdir(op) => fs.readdir(&req, op)?,
+            Operation::Statfs(..) => fs.statfs(&req)?,
+            // The inodes are never removed, so the lookup counts need not be tracked.
+            Operation::Forget(..) => continue,
+            _ => req.reply_error(libc::ENOSYS)?,
+        };
+    }
+
+    Ok(())
+}
+
+enum Source {
+    Tar(File),
+    Zip(zip::ZipArchive<File>),
+}
+
+struct Node {
+    kind: Kind,
+    mode: u32,
+    size: u64,
+    mtime: Duration,
+}
+
+enum Kind {
+    Dir(DirEntries<u64>),
+    File(Data),
+    Symlink(OsString),
+}
+
+/// The location of the contents of a regular file in the archive.
+enum Data {
+    Tar { offset: u64 },
+    Zip { index: usize },
+}
+
+impl Node {
+    fn typ(&self) -> u32 {
+        match self.kind {
+            Kind::Dir(..) => libc::DT_DIR as u32,
+            Kind::File(..) => libc::DT_REG as u32,
+            Kind::Symlink(..) => libc::DT_LNK as u32,
+        }
+    }
+
+    fn file_type(&self) -> u32 {
+        match self.kind {
+            Kind::Dir(..) => libc::S_IFDIR,
+            Kind::File(..) => libc::S_IFREG,
+            Kind::Symlink(..) => libc::S_IFLNK,
+        }
+    }
+}
+
+struct ArchiveFS {
+    source: Source,
+    nodes: Vec<Node>,
+    // The inflated contents of the opened zip members.
+    handles: HashMap<u64, Vec<u8>>,
+    next_fh: u64,
+    uid: u32,
+    gid: u32,
+}
+
+impl ArchiveFS {
+    fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        let mtime = file.metadata()?.modified()?.duration_since(UNIX_EPOCH)?;
+
+        let mut root = DirEntries::new();
+        root.insert(".", ROOT_INO);
+        root.insert("..", ROOT_INO);
+
+        let mut fs = Self {
+            source: Source::Tar(file.try_clone()?),
+            nodes: vec![Node {
+                kind: Kind::Dir(root),
+                mode: 0o555,
+                size: 0,
+                mtime,
+            }],
+            handles: HashMap::new(),
+            next_fh: 0,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        };
+
+        let is_zip = path.extension() == Some(OsStr::new("zip"));
+        if is_zip {
+            fs.load_zip(zip::ZipArchive::new(file)?, mtime)?;
+        } else {
+            fs.load_tar(tar::Archive::new(file))?;
+        }
+
+        Ok(fs)
+    }
+
+    fn load_tar(&mut self, mut archive: tar::Archive<File>) -> Result<()> {
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let header = entry.header();
+            let path = entry.path()?.into_owned();
+            let mode = header.mode()? & 0o7777;
+            let mtime = Duration::from_secs(header.mtime()?);
+
+            let kind = match header.entry_type() {
+                tar::EntryType::Directory => Kind::Dir(DirEntries::new()),
+                tar::EntryType::Regular | tar::EntryType::Continuous => Kind::File(Data::Tar {
+                    offset: entry.raw_file_position(),
+                }),
+                tar::EntryType::Symlink => match entry.link_name()? {
+                    Some(target) => Kind::Symlink(target.into_owned().into_os_string()),
+                    None => continue,
+                },
+                typ => {
+                    tracing::warn!("skip unsupported entry {:?} ({:?})", path, typ);
+                    continue;
+                }
+            };
+
+            self.insert(&path, kind, mode, entry.size(), mtime);
+        }
+
+        Ok(())
+    }
+
+    fn load_zip(&mut self, mut archive: zip::ZipArchive<File>, mtime: Duration) -> Result<()> {
+        for index in 0..archive.len() {
+            let mut file = archive.by_index(index)?;
+            let path = match file.enclosed_name() {
+                Some(path) => path.to_owned(),
+                None => {
+                    tracing::warn!("skip unsafe path {:?}", file.name());
+                    continue;
+                }
+            };
+            let unix_mode = file.unix_mode().unwrap_or(0);
+
+            let kind = if file.is_dir() {
+                Kind::Dir(DirEntries::new())
+            } else if unix_mode & libc::S_IFMT == libc::S_IFLNK {
+                let mut target = vec![];
+                file.read_to_end(&mut target)?;
+                Kind::Symlink(OsString::from_vec(target))
+            } else {
+                Kind::File(Data::Zip { index })
+            };
+
+            let mode = match unix_mode & 0o7777 {
+                0 if file.is_dir() => 0o555,
+                0 => 0o444,
+                mode => mode,
+            };
+
+            self.insert(&path, kind, mode, file.size(), mtime);
+        }
+
+        self.source = Source::Zip(archive);
+
+        Ok(())
+    }
+
+    /// Insert a node at the specified path, creating the missing parents.
+    ///
+    /// When the archive contains the same path more than once,
+    /// the last one wins as `tar -x` does.
+    fn insert(&mut self, path: &Path, kind: Kind, mode: u32, size: u64, mtime: Duration) {
+        let mut names = path
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(name) => Some(name),
+                _ => None,
+            })
+            .peekable();
+
+        let mut parent = ROOT_INO;
+        while let Some(name) = names.next() {
+            let existing = self.child(parent, name);
+
+            if names.peek().is_some() {
+                parent = match existing {
+                    Some(ino) if matches!(self.node(ino).kind, Kind::Dir(..)) => ino,
+                    _ => self.add_node(parent, name, Kind::Dir(DirEntries::new()), 0o555, 0, mtime),
+                };
+                continue;
+            }
+
+            match existing {
+                // Keep the children of a directory listed before its own entry.
+                Some(ino)
+                    if matches!(
+                        (&self.node(ino).kind, &kind),
+                        (Kind::Dir(..), Kind::Dir(..))
+                    ) =>
+                {
+                    let node = self.node_mut(ino);
+                    node.mode = mode;
+                    node.mtime = mtime;
+                }
+                _ => {
+                    self.add_node(parent, name, kind, mode, size, mtime);
+                }
+            }
+            return;
+        }
+    }
+
+    fn add_node(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        mut kind: Kind,
+        mode: u32,
+        size: u64,
+        mtime: Duration,
+    ) -> u64 {
+        let ino = self.nodes.len() as u64 + 1;
+        if let Kind::Dir(ref mut children) = kind {
+            children.insert(".", ino);
+            children.insert("..", parent);
+        }
+        self.nodes.push(Node {
+            kind,
+            mode,
+            size,
+            mtime,
+        });
+        if let Kind::Dir(ref mut children) = self.node_mut(parent).kind {
+            children.insert(name, ino);
+        }
+        ino
+    }
+
+    fn node(&self, ino: u64) -> &Node {
+        &self.nodes[ino as usize - 1]
+    }
+
+    fn node_mut(&mut self, ino: u64) -> &mut Node {
+        &mut self.nodes[ino as usize - 1]
+    }
+
+    fn get(&self, ino: u64) -> Option<&Node> {
+        ino.checked_sub(1)
+            .and_then(|index| self.nodes.get(index as usize))
+    }
+
+    fn child(&self, parent: u64, name: &OsStr) -> Option<u64> {
+        match self.get(parent)?.kind {
+            Kind::Dir(ref children) => children.get(name).copied(),
+            _ => None,
+        }
+    }
+
+    fn fill_attr(&self, ino: u64, attr: &mut FileAttr) {
+        let node = self.node(ino);
+        attr.ino(ino);
+        attr.mode(node.file_type() | node.mode);
+        attr.size(node.size);
+        attr.blocks(blocks(node.size));
+        attr.nlink(match node.kind {
+            Kind::Dir(..) => 2,
+            _ => 1,
+        });
+        attr.uid(self.uid);
+        attr.gid(self.gid);
+        attr.atime(node.mtime);
+        attr.mtime(node.mtime);
+        attr.ctime(node.mtime);
+    }
+
+    fn lookup(&self, req: &Request, op: op::Lookup<'_>) -> io::Result<Replied> {
+        let ino = match self.get(op.parent()) {
+            Some(Node {
+                kind: Kind::Dir(..),
+                ..
+            }) => match self.child(op.parent(), op.name()) {
+                Some(ino) => ino,
+                None => return req.reply_error(libc::ENOENT),
+            },
+            Some(..) => return req.reply_error(libc::ENOTDIR),
+            None => return req.reply_error(libc::ENOENT),
+        };
+
+        let mut out = EntryOut::default();
+        self.fill_attr(ino, out.attr());
+        out.ino(ino);
+        out.ttl_attr(TTL);
+        out.ttl_entry(TTL);
+        req.reply(out)
+    }
+
+    fn getattr(&self, req: &Request, op: op::Getattr<'_>) -> io::Result<Replied> {
+        if self.get(op.ino()).is_none() {
+            return req.reply_error(libc::ENOENT);
+        }
+
+        let mut out = AttrOut::default();
+        self.fill_attr(op.ino(), out.attr());
+        out.ttl(TTL);
+        req.reply(out)
+    }
+
+    fn readlink(&self, req: &Request, op: op::Readlink<'_>) -> io::Result<Replied> {
+        match self.get(op.ino()) {
+            Some(Node {
+                kind: Kind::Symlink(ref target),
+                ..
+            }) => req.reply(target),
+            Some(..) => req.reply_error(libc::EINVAL),
+            None => req.reply_error(libc::ENOENT),
+        }
+    }
+
+    fn open(&mut self, req: &Request, op: op::Open<'_>) -> io::Result<Replied> {
+        if op.flags() as i32 & libc::O_ACCMODE != libc::O_RDONLY {
+            return req.reply_error(libc::EROFS);
+        }
+
+        let index = match self.get(op.ino()) {
+            Some(Node {
+                kind: Kind::File(Data::Zip { index }),
+                ..
+            }) => *index,
+            Some(Node {
+                kind: Kind::File(Data::Tar { .. }),
+                ..
+            }) => return req.reply(OpenOut::default()),
+            Some(..) => return req.reply_error(libc::EISDIR),
+            None => return req.reply_error(libc::ENOENT),
+        };
+
+        let archive = match self.source {
+            Source::Zip(ref mut archive) => archive,
+            Source::Tar(..) => unreachable!(),
+        };
+        let mut content = vec![];
+        if let Err(err) = archive
+            .by_index(index)
+            .map_err(io::Error::from)
+            .and_then(|mut file| file.read_to_end(&mut content))
+        {
+            tracing::error!("failed to inflate the member: {}", err);
+            return req.reply_error(libc::EIO);
+        }
+
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.handles.insert(fh, content);
+
+        let mut out = OpenOut::default();
+        out.fh(fh);
+        out.keep_cache(true);
+        req.reply(out)
+    }
+
+    fn read(&self, req: &Request, op: op::Read<'_>) -> io::Result<Replied> {
+        let node = match self.get(op.ino()) {
+            Some(node) => node,
+            None => return req.reply_error(libc::ENOENT),
+        };
+
+        let offset = cmp::min(op.offset(), node.size);
+        let size = cmp::min(op.size() as u64, node.size - offset) as usize;
+
+        match (&node.kind, &self.source) {
+            (Kind::File(Data::Tar { offset: start }), Source::Tar(file)) => {
+                let mut buf = vec![0u8; size];
+                let mut filled = 0;
+                while filled < size {
+                    match file.read_at(&mut buf[filled..], start + offset + filled as u64) {
+                        Ok(0) => break,
+                        Ok(n) => filled += n,
+                        Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(err) => {
+                            return req.reply_error(err.raw_os_error().unwrap_or(libc::EIO))
+                        }
+                    }
+                }
+                req.reply(&buf[..filled])
+            }
+            (Kind::File(Data::Zip { .. }), _) => match self.handles.get(&op.fh()) {
+                Some(content) => {
+                    let content = content.get(offset as usize..).unwrap_or(&[]);
+                    req.reply(&content[..cmp::min(content.len(), size)])
+                }
+                None => req.reply_error(libc::EBADF),
+            },
+            _ => req.reply_error(libc::EISDIR),
+        }
+    }
+
+    fn release(&mut self, req: &Request, op: op::Release<'_>) -> io::Result<Replied> {
+        if let Some(Node {
+            kind: Kind::File(Data::Zip { .. }),
+            ..
+        }) = self.get(op.ino())
+        {
+            self.handles.remove(&op.fh());
+        }
+        req.reply(())
+    }
+
+    fn readdir(&self, req: &Request, op: op::Readdir<'_>) -> io::Result<Replied> {
+        if op.mode() == op::ReaddirMode::Plus {
+            return req.reply_error(libc::ENOSYS);
+        }
+
+        let children = match self.get(op.ino()) {
+            Some(Node {
+                kind: Kind::Dir(ref children),
+                ..
+            }) => children,
+            Some(..) => return req.reply_error(libc::ENOTDIR),
+            None => return req.reply_error(libc::ENOENT),
+        };
+
+        let mut out = ReaddirOut::new(op.size() as usize);
+        children.fill(op.offset(), &mut out, |&ino| (ino, self.node(ino).typ()));
+        req.reply(out)
+    }
+
+    fn statfs(&self, req: &Request) -> io::Result<Replied> {
+        let blocks = self.nodes.iter().map(|node| blocks(node.size)).sum();
+
+        let mut out = StatfsOut::default();
+        let st = out.statfs();
+        st.bsize(BLOCK_SIZE as u32);
+        st.frsize(BLOCK_SIZE as u32);
+        st.blocks(blocks);
+        st.files(self.nodes.len() as u64);
+        st.namelen(255);
+        req.reply(out)
+    }
+}
+
+fn blocks(size: u64) -> u64 {
+    size.saturating_add(BLOCK_SIZE - 1) / BLOCK_SIZE
+}
@@ -1,4 +1,4 @@
-use polyfuse::{op, reply::AttrOut, KernelConfig, Operation, Request, Session};
+use polyfuse::{op, reply::AttrOut, KernelConfig, Operation, Replied, Request, Session};
 
 use anyhow::{ensure, Context as _, Result};
 use std::{io, path::PathBuf, time::Duration};
@@ -31,7 +31,7 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn getattr(req: &Request, op: op::Getattr<'_>) -> io::Result<()> {
+fn getattr(req: &Request, op: op::Getattr<'_>) -> io::Result<Replied> {
     if op.ino() != 1 {
         return req.reply_error(libc::ENOENT);
     }
@@ -48,7 +48,7 @@ fn getattr(req: &Request, op: op::Getattr<'_>) -> io::Result<()> {
     req.reply(out)
 }
 
-fn read(req: &Request, op: op::Read<'_>) -> io::Result<()> {
+fn read(req: &Request, op: op::Read<'_>) -> io::Result<Replied> {
     if op.ino() != 1 {
         return req.reply_error(libc::ENOENT);
     }
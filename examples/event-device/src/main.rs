@@ -0,0 +1,186 @@
+//! A virtual "event device" whose readability is driven by a timer.
+//!
+//! The root of the filesystem is a single file, which behaves like an
+//! `eventfd`: a background thread raises an event every `--interval`
+//! seconds, and reading the file returns the number of events raised
+//! since the last read and resets it. `poll(2)` and friends report the
+//! file as readable while at least one event is pending.
+//!
+//! ```shell-session
+//! $ touch /tmp/events
+//! $ cargo run -p polyfuse-example-event-device -- /tmp/events --interval 2 &
+//! $ python3 -c 'import select; p = select.poll(); p.register(open("/tmp/events")); print(p.poll())'
+//! ```
+
+use polyfuse::{
+    op,
+    reply::{AttrOut, OpenOut, PollOut},
+    util::PollHandle,
+    Operation, Replied, Request, Session,
+};
+
+use anyhow::{ensure, Context as _, Result};
+use std::{
+    path::PathBuf,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+
+fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let mut args = pico_args::Arguments::from_env();
+
+    let interval = Duration::from_secs(
+        args //
+            .opt_value_from_str("--interval")?
+            .unwrap_or(5),
+    );
+
+    let mountpoint: PathBuf = args.free_from_str()?.context("missing mountpoint")?;
+    ensure!(mountpoint.is_file(), "mountpoint must be a regular file");
+
+    let session = Session::mount(mountpoint, Default::default())?;
+
+    let device = Arc::new(EventDevice::new(PollHandle::new(session.notifier())));
+
+    thread::spawn({
+        let device = Arc::downgrade(&device);
+        move || {
+            while let Some(device) = {
+                thread::sleep(interval);
+                device.upgrade()
+            } {
+                if let Err(err) = device.raise() {
+                    tracing::error!("failed to send poll wakeup: {}", err);
+                }
+            }
+        }
+    });
+
+    while let Some(req) = session.next_request()? {
+        let device = device.clone();
+        // Blocking reads park the handling thread until the next event,
+        // so each request is handled on its own thread.
+        thread::spawn(move || -> Result<()> {
+            device.handle_request(&req)?;
+            Ok(())
+        });
+    }
+
+    Ok(())
+}
+
+struct EventDevice {
+    pending: Mutex<u64>,
+    condvar: Condvar,
+    poll_handle: PollHandle,
+    uid: u32,
+    gid: u32,
+}
+
+impl EventDevice {
+    fn new(poll_handle: PollHandle) -> Self {
+        Self {
+            pending: Mutex::new(0),
+            condvar: Condvar::new(),
+            poll_handle,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        }
+    }
+
+    /// Raise an event, waking up the blocked readers and the pollers.
+    fn raise(&self) -> std::io::Result<()> {
+        {
+            let mut pending = self.pending.lock().unwrap();
+            *pending += 1;
+            tracing::info!("raise an event (pending={})", *pending);
+        }
+        self.condvar.notify_all();
+        self.poll_handle.wakeup()
+    }
+
+    fn handle_request(&self, req: &Request) -> Result<()> {
+        let span = tracing::debug_span!("handle_request", unique = req.unique());
+        let _enter = span.enter();
+
+        let op = req.operation()?;
+        tracing::debug!(?op);
+
+        match op {
+            Operation::Getattr(..) => self.getattr(req)?,
+            Operation::Open(op) => self.open(req, op)?,
+            Operation::Read(op) => self.read(req, op)?,
+            Operation::Poll(op) => self.poll(req, op)?,
+            Operation::Release(..) => req.reply(())?,
+            _ => req.reply_error(libc::ENOSYS)?,
+        };
+
+        Ok(())
+    }
+
+    fn getattr(&self, req: &Request) -> std::io::Result<Replied> {
+        let mut out = AttrOut::default();
+        out.attr().ino(1);
+        out.attr().nlink(1);
+        out.attr().mode(libc::S_IFREG | 0o444);
+        out.attr().uid(self.uid);
+        out.attr().gid(self.gid);
+        out.ttl(Duration::from_secs(60));
+        req.reply(out)
+    }
+
+    fn open(&self, req: &Request, op: op::Open<'_>) -> std::io::Result<Replied> {
+        if op.flags() as i32 & libc::O_ACCMODE != libc::O_RDONLY {
+            return req.reply_error(libc::EACCES);
+        }
+
+        // The content changes regardless of the offset, so the page cache
+        // and the file position are meaningless for this file.
+        let mut out = OpenOut::default();
+        out.direct_io(true);
+        out.nonseekable(true);
+        req.reply(out)
+    }
+
+    fn read(&self, req: &Request, op: op::Read<'_>) -> std::io::Result<Replied> {
+        let mut pending = self.pending.lock().unwrap();
+        if *pending == 0 {
+            if op.flags() as i32 & libc::O_NONBLOCK != 0 {
+                return req.reply_error(libc::EAGAIN);
+            }
+            tracing::info!("wait for the next event");
+            while *pending == 0 {
+                pending = self.condvar.wait(pending).unwrap();
+            }
+        }
+
+        let content = format!("{}\n", *pending);
+        if content.len() > op.size() as usize {
+            return req.reply_error(libc::EINVAL);
+        }
+        *pending = 0;
+        drop(pending);
+
+        req.reply(content)
+    }
+
+    fn poll(&self, req: &Request, op: op::Poll<'_>) -> std::io::Result<Replied> {
+        // The kernel handle is registered before checking the readiness,
+        // so an event raised in between still reaches the kernel.
+        let revents = self.poll_handle.poll(&op, |_cx, events| {
+            if *self.pending.lock().unwrap() > 0 {
+                events & libc::POLLIN as u32
+            } else {
+                0
+            }
+        });
+        tracing::info!("poll: revents={:#x}", revents);
+
+        let mut out = PollOut::default();
+        out.revents(revents);
+        req.reply(out)
+    }
+}
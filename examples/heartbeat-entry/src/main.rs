@@ -170,7 +170,9 @@ impl Heartbeat {
                         req.reply_error(libc::ENOENT)?;
                     }
                 }
-                _ => req.reply_error(libc::ENOTDIR)?,
+                _ => {
+                    req.reply_error(libc::ENOTDIR)?;
+                }
             },
 
             Operation::Forget(forgets) => {
@@ -186,7 +188,10 @@ impl Heartbeat {
                 let attr = match op.ino() {
                     ROOT_INO => &self.root_attr,
                     FILE_INO => &self.file_attr,
-                    _ => return req.reply_error(libc::ENOENT).map_err(Into::into),
+                    _ => {
+                        req.reply_error(libc::ENOENT)?;
+                        return Ok(());
+                    }
                 };
 
                 let mut out = AttrOut::default();
@@ -197,9 +202,15 @@ impl Heartbeat {
             }
 
             Operation::Read(op) => match op.ino() {
-                ROOT_INO => req.reply_error(libc::EISDIR)?,
-                FILE_INO => req.reply(&[])?,
-                _ => req.reply_error(libc::ENOENT)?,
+                ROOT_INO => {
+                    req.reply_error(libc::EISDIR)?;
+                }
+                FILE_INO => {
+                    req.reply(&[])?;
+                }
+                _ => {
+                    req.reply_error(libc::ENOENT)?;
+                }
             },
 
             Operation::Readdir(op) => match op.ino() {
@@ -214,10 +225,14 @@ impl Heartbeat {
                         req.reply(&[])?;
                     }
                 }
-                _ => req.reply_error(libc::ENOTDIR)?,
+                _ => {
+                    req.reply_error(libc::ENOTDIR)?;
+                }
             },
 
-            _ => req.reply_error(libc::ENOSYS)?,
+            _ => {
+                req.reply_error(libc::ENOSYS)?;
+            }
         }
 
         Ok(())
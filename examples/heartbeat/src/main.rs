@@ -34,6 +34,7 @@ fn main() -> Result<()> {
     let notify_kind = args
         .opt_value_from_os_str("--notify-kind", |s| match s.to_str() {
             Some("store") => Ok(NotifyKind::Store),
+            Some("splice") => Ok(NotifyKind::Splice),
             Some("invalidate") => Ok(NotifyKind::Invalidate),
             s => Err(anyhow!("invalid notify kind: {:?}", s)),
         })?
@@ -63,7 +64,8 @@ fn main() -> Result<()> {
 
                 if let Some(ref notifier) = notifier {
                     match notify_kind {
-                        NotifyKind::Store => heartbeat.notify_store(notifier)?,
+                        NotifyKind::Store => heartbeat.notify_store(notifier, false)?,
+                        NotifyKind::Splice => heartbeat.notify_store(notifier, true)?,
                         NotifyKind::Invalidate => heartbeat.notify_inval_inode(notifier)?,
                     }
                 }
@@ -92,7 +94,9 @@ fn main() -> Result<()> {
                         fill_attr(out.attr(), &inner.attr);
                         req.reply(out)?;
                     }
-                    _ => req.reply_error(libc::ENOENT)?,
+                    _ => {
+                        req.reply_error(libc::ENOENT)?;
+                    }
                 },
                 Operation::Open(op) => match op.ino() {
                     ROOT_INO => {
@@ -100,7 +104,9 @@ fn main() -> Result<()> {
                         out.keep_cache(true);
                         req.reply(out)?;
                     }
-                    _ => req.reply_error(libc::ENOENT)?,
+                    _ => {
+                        req.reply_error(libc::ENOENT)?;
+                    }
                 },
                 Operation::Read(op) => match op.ino() {
                     ROOT_INO => {
@@ -116,7 +122,9 @@ fn main() -> Result<()> {
                             req.reply(data)?;
                         }
                     }
-                    _ => req.reply_error(libc::ENOENT)?,
+                    _ => {
+                        req.reply_error(libc::ENOENT)?;
+                    }
                 },
                 Operation::NotifyReply(op, mut data) => {
                     let mut retrieves = heartbeat.retrieves.lock().unwrap();
@@ -127,7 +135,9 @@ fn main() -> Result<()> {
                     }
                 }
 
-                _ => req.reply_error(libc::ENOSYS)?,
+                _ => {
+                    req.reply_error(libc::ENOSYS)?;
+                }
             }
 
             Ok(())
@@ -140,6 +150,7 @@ fn main() -> Result<()> {
 #[derive(Debug, Copy, Clone, PartialEq)]
 enum NotifyKind {
     Store,
+    Splice,
     Invalidate,
 }
 
@@ -175,12 +186,16 @@ impl Heartbeat {
         inner.content = content;
     }
 
-    fn notify_store(&self, notifier: &Notifier) -> io::Result<()> {
+    fn notify_store(&self, notifier: &Notifier, splice: bool) -> io::Result<()> {
         let inner = self.inner.lock().unwrap();
         let content = &inner.content;
 
-        tracing::info!("send notify_store(data={:?})", content);
-        notifier.store(ROOT_INO, 0, content)?;
+        tracing::info!("send notify_store(data={:?}, splice={})", content, splice);
+        if splice {
+            notifier.store_spliced(ROOT_INO, 0, content)?;
+        } else {
+            notifier.store(ROOT_INO, 0, content)?;
+        }
 
         // To check if the cache is updated correctly, pull the
         // content from the kernel using notify_retrieve.
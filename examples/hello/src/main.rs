@@ -3,8 +3,9 @@
 
 use polyfuse::{
     op,
-    reply::{AttrOut, EntryOut, FileAttr, ReaddirOut},
-    KernelConfig, Operation, Request, Session,
+    reply::{FileAttr, ReaddirOut},
+    util::ShutdownSignals,
+    KernelConfig, Operation, Replied, Request, Session,
 };
 
 use anyhow::{ensure, Context as _, Result};
@@ -22,9 +23,16 @@ fn main() -> Result<()> {
     let mut args = pico_args::Arguments::from_env();
 
     let mountpoint: PathBuf = args.free_from_str()?.context("missing mountpoint")?;
-    ensure!(mountpoint.is_dir(), "tmountpoint must be a directory");
+    ensure!(mountpoint.is_dir(), "mountpoint must be a directory");
 
-    let session = Session::mount(mountpoint, KernelConfig::default())?;
+    let mut config = KernelConfig::default();
+    config.entry_ttl(TTL);
+    config.attr_ttl(TTL);
+
+    let session = Session::mount(mountpoint, config)?;
+
+    // Unmount the filesystem on Ctrl-C, instead of leaving a stale mount.
+    let _unmounter = ShutdownSignals::install()?.spawn_unmounter(&session)?;
 
     let fs = Hello::new();
 
@@ -35,7 +43,7 @@ fn main() -> Result<()> {
             Operation::Read(op) => fs.read(&req, op)?,
             Operation::Readdir(op) => fs.readdir(&req, op)?,
             _ => req.reply_error(libc::ENOSYS)?,
-        }
+        };
     }
 
     Ok(())
@@ -96,35 +104,32 @@ impl Hello {
         attr.gid(self.gid);
     }
 
-    fn lookup(&self, req: &Request, op: op::Lookup<'_>) -> io::Result<()> {
+    fn lookup(&self, req: &Request, op: op::Lookup<'_>) -> io::Result<Replied> {
         match op.parent() {
             ROOT_INO if op.name().as_bytes() == HELLO_FILENAME.as_bytes() => {
-                let mut out = EntryOut::default();
+                let mut out = req.entry_out();
                 self.fill_hello_attr(out.attr());
                 out.ino(HELLO_INO);
-                out.ttl_attr(TTL);
-                out.ttl_entry(TTL);
                 req.reply(out)
             }
             _ => req.reply_error(libc::ENOENT),
         }
     }
 
-    fn getattr(&self, req: &Request, op: op::Getattr<'_>) -> io::Result<()> {
+    fn getattr(&self, req: &Request, op: op::Getattr<'_>) -> io::Result<Replied> {
         let fill_attr = match op.ino() {
             ROOT_INO => Self::fill_root_attr,
             HELLO_INO => Self::fill_hello_attr,
             _ => return req.reply_error(libc::ENOENT),
         };
 
-        let mut out = AttrOut::default();
+        let mut out = req.attr_out();
         fill_attr(self, out.attr());
-        out.ttl(TTL);
 
         req.reply(out)
     }
 
-    fn read(&self, req: &Request, op: op::Read<'_>) -> io::Result<()> {
+    fn read(&self, req: &Request, op: op::Read<'_>) -> io::Result<Replied> {
         match op.ino() {
             HELLO_INO => (),
             ROOT_INO => return req.reply_error(libc::EISDIR),
@@ -150,7 +155,7 @@ impl Hello {
         })
     }
 
-    fn readdir(&self, req: &Request, op: op::Readdir<'_>) -> io::Result<()> {
+    fn readdir(&self, req: &Request, op: op::Readdir<'_>) -> io::Result<Replied> {
         if op.ino() != ROOT_INO {
             return req.reply_error(libc::ENOTDIR);
         }
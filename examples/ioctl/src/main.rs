@@ -0,0 +1,165 @@
+//! A control file that implements a couple of ioctls.
+//!
+//! The root of the filesystem is a single file holding a counter, which can
+//! be read as text or manipulated with the following commands:
+//!
+//! * `COUNTER_GET` (`_IOR('C', 1, u64)`) - get the current value.
+//! * `COUNTER_SET` (`_IOW('C', 2, u64)`) - replace the value.
+//! * `COUNTER_INCR` (`_IO('C', 3)`) - increment the value by one.
+//!
+//! ```shell-session
+//! $ touch /tmp/counter
+//! $ cargo run -p polyfuse-example-ioctl -- /tmp/counter &
+//! $ python3 -c 'import fcntl, struct; fcntl.ioctl(open("/tmp/counter"), 0x40084302, struct.pack("Q", 42))'
+//! $ cat /tmp/counter
+//! 42
+//! ```
+
+use polyfuse::{
+    op,
+    reply::{AttrOut, IoctlOut, OpenOut},
+    Operation, Replied, Request, Session,
+};
+
+use anyhow::{ensure, Context as _, Result};
+use std::{
+    mem,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+// The encoding of the command numbers follows the generic one used by
+// x86 and ARM; a few architectures such as PowerPC and MIPS differ.
+const IOC_NONE: u32 = 0;
+const IOC_WRITE: u32 = 1;
+const IOC_READ: u32 = 2;
+
+const fn ioc(dir: u32, typ: u8, nr: u8, size: usize) -> u32 {
+    (dir << 30) | ((size as u32) << 16) | ((typ as u32) << 8) | nr as u32
+}
+
+const COUNTER_GET: u32 = ioc(IOC_READ, b'C', 1, mem::size_of::<u64>());
+const COUNTER_SET: u32 = ioc(IOC_WRITE, b'C', 2, mem::size_of::<u64>());
+const COUNTER_INCR: u32 = ioc(IOC_NONE, b'C', 3, 0);
+
+fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let mut args = pico_args::Arguments::from_env();
+
+    let mountpoint: PathBuf = args.free_from_str()?.context("missing mountpoint")?;
+    ensure!(mountpoint.is_file(), "mountpoint must be a regular file");
+
+    let session = Session::mount(mountpoint, Default::default())?;
+
+    let fs = Counter {
+        value: AtomicU64::new(0),
+        uid: unsafe { libc::getuid() },
+        gid: unsafe { libc::getgid() },
+    };
+
+    while let Some(req) = session.next_request()? {
+        let span = tracing::debug_span!("handle_request", unique = req.unique());
+        let _enter = span.enter();
+
+        let op = req.operation()?;
+        tracing::debug!(?op);
+
+        match op {
+            Operation::Getattr(..) => fs.getattr(&req)?,
+            Operation::Open(..) => fs.open(&req)?,
+            Operation::Read(op) => fs.read(&req, op)?,
+            Operation::Ioctl(op) => fs.ioctl(&req, op)?,
+            Operation::Release(..) => req.reply(())?,
+            _ => req.reply_error(libc::ENOSYS)?,
+        };
+    }
+
+    Ok(())
+}
+
+struct Counter {
+    value: AtomicU64,
+    uid: u32,
+    gid: u32,
+}
+
+impl Counter {
+    fn getattr(&self, req: &Request) -> std::io::Result<Replied> {
+        let mut out = AttrOut::default();
+        out.attr().ino(1);
+        out.attr().nlink(1);
+        out.attr().mode(libc::S_IFREG | 0o444);
+        out.attr().uid(self.uid);
+        out.attr().gid(self.gid);
+        out.ttl(Duration::from_secs(60));
+        req.reply(out)
+    }
+
+    fn open(&self, req: &Request) -> std::io::Result<Replied> {
+        // The content changes by ioctls, so bypass the page cache.
+        let mut out = OpenOut::default();
+        out.direct_io(true);
+        req.reply(out)
+    }
+
+    fn read(&self, req: &Request, op: op::Read<'_>) -> std::io::Result<Replied> {
+        let content = format!("{}\n", self.value.load(Ordering::SeqCst));
+        let content = content
+            .as_bytes()
+            .get(op.offset() as usize..)
+            .unwrap_or(&[]);
+        req.reply(&content[..std::cmp::min(content.len(), op.size() as usize)])
+    }
+
+    fn ioctl(&self, req: &Request, op: op::Ioctl<'_>) -> std::io::Result<Replied> {
+        const SIZE: usize = mem::size_of::<u64>();
+
+        let mut out = IoctlOut::default();
+        match op.cmd() {
+            COUNTER_GET => {
+                if (op.out_size() as usize) < SIZE {
+                    // An unrestricted ioctl does not know the size of the output
+                    // in advance, so ask the kernel to retry with the room for it.
+                    if op.is_unrestricted() {
+                        out.retry_out(op.arg(), SIZE as u64);
+                        return req.reply(out);
+                    }
+                    return req.reply_error(libc::EINVAL);
+                }
+                let value = self.value.load(Ordering::SeqCst);
+                tracing::info!("COUNTER_GET -> {}", value);
+                req.reply((out, &value.to_ne_bytes()[..]))
+            }
+
+            COUNTER_SET => {
+                if op.in_data().len() < SIZE {
+                    // Likewise, fetch the input from the caller's memory.
+                    if op.is_unrestricted() {
+                        out.retry_in(op.arg(), SIZE as u64);
+                        return req.reply(out);
+                    }
+                    return req.reply_error(libc::EINVAL);
+                }
+                let mut buf = [0u8; SIZE];
+                buf.copy_from_slice(&op.in_data()[..SIZE]);
+                let value = u64::from_ne_bytes(buf);
+                tracing::info!("COUNTER_SET <- {}", value);
+                self.value.store(value, Ordering::SeqCst);
+                req.reply(out)
+            }
+
+            COUNTER_INCR => {
+                let value = self.value.fetch_add(1, Ordering::SeqCst) + 1;
+                tracing::info!("COUNTER_INCR -> {}", value);
+                req.reply(out)
+            }
+
+            cmd => {
+                tracing::debug!("unknown ioctl: {:#x}", cmd);
+                req.reply_error(libc::ENOTTY)
+            }
+        }
+    }
+}
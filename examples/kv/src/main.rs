@@ -0,0 +1,375 @@
+//! A filesystem backed by a remote key-value store.
+//!
+//! Every key on the server appears as a regular file in the root directory.
+//! The entries, the attributes and the contents are cached in the kernel
+//! with long timeouts, and the filesystem keeps the caches coherent by
+//! watching the changes on the server and forwarding them to the kernel
+//! as notifications:
+//!
+//! * an updated value is pushed into the page cache with `store`, after
+//!   dropping the stale pages and attributes with `inval_inode`,
+//! * a new key drops the negative dentry cached for the name with
+//!   `inval_entry`,
+//! * a removed key is notified with `delete`, which also informs the
+//!   inotify watchers on the directory.
+//!
+//! ```shell-session
+//! $ cargo run -p polyfuse-example-kv --bin polyfuse-example-kv-server &
+//! $ cargo run -p polyfuse-example-kv -- /mnt/kv &
+//! $ printf 'PUT greeting 6\nhello\n' | nc -q1 127.0.0.1 7878
+//! $ cat /mnt/kv/greeting
+//! hello
+//! ```
+
+#![allow(clippy::unnecessary_mut_passed)]
+
+mod proto;
+
+use polyfuse::{
+    op,
+    reply::{AttrOut, EntryOut, FileAttr, OpenOut, ReaddirOut},
+    util::DirEntries,
+    KernelConfig, Notifier, Operation, Replied, Request, Session,
+};
+
+use anyhow::{ensure, Context as _, Result};
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    io::{self, BufReader, Write as _},
+    net::TcpStream,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+const ROOT_INO: u64 = 1;
+const TTL: Duration = Duration::from_secs(60 * 60 * 24 * 365);
+
+fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let mut args = pico_args::Arguments::from_env();
+
+    let addr: String = args
+        .opt_value_from_str("--server")?
+        .unwrap_or_else(|| "127.0.0.1:7878".into());
+
+    let mountpoint: PathBuf = args.free_from_str()?.context("missing mountpoint")?;
+    ensure!(mountpoint.is_dir(), "mountpoint must be a directory");
+
+    // Start watching before listing the keys, so that no change is missed.
+    let mut watcher = Client::connect(&addr)?;
+    watcher.call("WATCH\n")?;
+
+    let client = Client::connect(&addr)?;
+    let fs = Arc::new(KvFS::new(client)?);
+
+    let session = Session::mount(mountpoint, KernelConfig::default())?;
+
+    thread::spawn({
+        let fs = fs.clone();
+        let notifier = session.notifier();
+        move || {
+            if let Err(err) = fs.watch(&mut watcher, &notifier) {
+                tracing::error!("stop watching the changes: {}", err);
+            }
+        }
+    });
+
+    while let Some(req) = session.next_request()? {
+        let span = tracing::debug_span!("handle_request", unique = req.unique());
+        let _enter = span.enter();
+
+        let op = req.operation()?;
+        tracing::debug!(?op);
+
+        match op {
+            Operation::Lookup(op) => fs.lookup(&req, op)?,
+            Operation::Getattr(op) => fs.getattr(&req, op)?,
+            Operation::Open(op) => fs.open(&req, op)?,
+            Operation::Read(op) => fs.read(&req, op)?,
+            Operation::Release(..) => req.reply(())?,
+            Operation::Readdir(op) => fs.readdir(&req, op)?,
+            // The inodes live as long as their keys, regardless of the lookup counts.
+            Operation::Forget(..) => continue,
+            _ => req.reply_error(libc::ENOSYS)?,
+        };
+    }
+
+    Ok(())
+}
+
+/// A connection to the key-value server.
+struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Client {
+    fn connect(addr: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(Self {
+            writer: stream.try_clone()?,
+            reader: BufReader::new(stream),
+        })
+    }
+
+    fn call(&mut self, request: &str) -> io::Result<String> {
+        self.writer.write_all(request.as_bytes())?;
+        let line = proto::read_line(&mut self.reader)?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        proto::parse_ok(&line).map(ToOwned::to_owned)
+    }
+
+    fn list(&mut self) -> io::Result<Vec<(String, u64)>> {
+        let count: usize = parse_num(&self.call("LIST\n")?)?;
+        (0..count)
+            .map(|_| {
+                let line = proto::read_line(&mut self.reader)?
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+                let mut fields = line.splitn(2, ' ');
+                match (fields.next(), fields.next()) {
+                    (Some(key), Some(size)) => Ok((key.to_owned(), parse_num(size)?)),
+                    _ => Err(io::ErrorKind::InvalidData.into()),
+                }
+            })
+            .collect()
+    }
+
+    fn get(&mut self, key: &str) -> io::Result<Vec<u8>> {
+        let len = parse_num(&self.call(&format!("GET {}\n", key))?)?;
+        proto::read_payload(&mut self.reader, len)
+    }
+}
+
+fn parse_num<T: std::str::FromStr>(s: &str) -> io::Result<T> {
+    s.parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("invalid number: {}", s)))
+}
+
+struct KvFS {
+    client: Mutex<Client>,
+    state: Mutex<State>,
+    uid: u32,
+    gid: u32,
+}
+
+struct State {
+    entries: DirEntries<u64>,
+    inodes: HashMap<u64, Inode>,
+    next_ino: u64,
+}
+
+struct Inode {
+    key: String,
+    size: u64,
+}
+
+impl State {
+    /// Add or update the key, returning its inode number and whether it is new.
+    fn put(&mut self, key: &str, size: u64) -> (u64, bool) {
+        if let Some(&ino) = self.entries.get(OsStr::new(key)) {
+            self.inodes.get_mut(&ino).unwrap().size = size;
+            return (ino, false);
+        }
+
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.entries.insert(key, ino);
+        self.inodes.insert(
+            ino,
+            Inode {
+                key: key.to_owned(),
+                size,
+            },
+        );
+        (ino, true)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<u64> {
+        let ino = self.entries.remove(OsStr::new(key))?;
+        self.inodes.remove(&ino);
+        Some(ino)
+    }
+}
+
+impl KvFS {
+    fn new(mut client: Client) -> io::Result<Self> {
+        let mut state = State {
+            entries: DirEntries::new(),
+            inodes: HashMap::new(),
+            next_ino: ROOT_INO + 1,
+        };
+        state.entries.insert(".", ROOT_INO);
+        state.entries.insert("..", ROOT_INO);
+        for (key, size) in client.list()? {
+            state.put(&key, size);
+        }
+
+        Ok(Self {
+            client: Mutex::new(client),
+            state: Mutex::new(state),
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        })
+    }
+
+    /// Forward the changes on the server to the kernel.
+    ///
+    /// The notifications are sent without holding the lock of the state,
+    /// since the kernel may be waiting for a reply to a request that needs
+    /// the lock while processing them.
+    fn watch(&self, watcher: &mut Client, notifier: &Notifier) -> io::Result<()> {
+        while let Some(event) = proto::read_line(&mut watcher.reader)? {
+            let mut fields = event.splitn(2, ' ');
+            match (fields.next(), fields.next()) {
+                (Some("PUT"), Some(key)) => {
+                    let value = match self.client.lock().unwrap().get(key) {
+                        Ok(value) => value,
+                        // Already removed; the DEL event follows.
+                        Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+                        Err(err) => return Err(err),
+                    };
+
+                    let (ino, is_new) = self.state.lock().unwrap().put(key, value.len() as u64);
+                    if is_new {
+                        tracing::info!("created {:?} (ino={})", key, ino);
+                        ignore_enoent(notifier.inval_entry(ROOT_INO, key))?;
+                    } else {
+                        tracing::info!("updated {:?} (ino={})", key, ino);
+                        ignore_enoent(notifier.inval_inode(ino, 0, 0))?;
+                        ignore_enoent(notifier.store(ino, 0, &value[..]))?;
+                    }
+                }
+
+                (Some("DEL"), Some(key)) => {
+                    let ino = self.state.lock().unwrap().remove(key);
+                    if let Some(ino) = ino {
+                        tracing::info!("removed {:?} (ino={})", key, ino);
+                        ignore_enoent(notifier.delete(ROOT_INO, ino, key))?;
+                    }
+                }
+
+                _ => tracing::warn!("unknown event: {:?}", event),
+            }
+
+            // The size and the timestamps of the directory have changed.
+            ignore_enoent(notifier.inval_inode(ROOT_INO, 0, 0))?;
+        }
+
+        Ok(())
+    }
+
+    fn fill_attr(&self, ino: u64, size: u64, attr: &mut FileAttr) {
+        attr.ino(ino);
+        if ino == ROOT_INO {
+            attr.mode(libc::S_IFDIR | 0o555);
+            attr.nlink(2);
+        } else {
+            attr.mode(libc::S_IFREG | 0o444);
+            attr.nlink(1);
+            attr.size(size);
+        }
+        attr.uid(self.uid);
+        attr.gid(self.gid);
+    }
+
+    fn lookup(&self, req: &Request, op: op::Lookup<'_>) -> io::Result<Replied> {
+        if op.parent() != ROOT_INO {
+            return req.reply_error(libc::ENOTDIR);
+        }
+
+        let state = self.state.lock().unwrap();
+        let mut out = EntryOut::default();
+        match state.entries.get(op.name()) {
+            Some(&ino) if ino != ROOT_INO => {
+                self.fill_attr(ino, state.inodes[&ino].size, out.attr());
+                out.ino(ino);
+                out.ttl_attr(TTL);
+            }
+            // Cache the negative entry, which is invalidated when the key is created.
+            _ => (),
+        }
+        out.ttl_entry(TTL);
+        req.reply(out)
+    }
+
+    fn getattr(&self, req: &Request, op: op::Getattr<'_>) -> io::Result<Replied> {
+        let size = match op.ino() {
+            ROOT_INO => 0,
+            ino => match self.state.lock().unwrap().inodes.get(&ino) {
+                Some(inode) => inode.size,
+                None => return req.reply_error(libc::ENOENT),
+            },
+        };
+
+        let mut out = AttrOut::default();
+        self.fill_attr(op.ino(), size, out.attr());
+        out.ttl(TTL);
+        req.reply(out)
+    }
+
+    fn open(&self, req: &Request, op: op::Open<'_>) -> io::Result<Replied> {
+        if op.flags() as i32 & libc::O_ACCMODE != libc::O_RDONLY {
+            return req.reply_error(libc::EROFS);
+        }
+
+        // The page cache is kept across opens, and refreshed by the notifications.
+        let mut out = OpenOut::default();
+        out.keep_cache(true);
+        req.reply(out)
+    }
+
+    fn read(&self, req: &Request, op: op::Read<'_>) -> io::Result<Replied> {
+        let key = match self.state.lock().unwrap().inodes.get(&op.ino()) {
+            Some(inode) => inode.key.clone(),
+            None => return req.reply_error(libc::ENOENT),
+        };
+
+        let value = match self.client.lock().unwrap().get(&key) {
+            Ok(value) => value,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                return req.reply_error(libc::ENOENT)
+            }
+            Err(err) => {
+                tracing::error!("failed to fetch {:?}: {}", key, err);
+                return req.reply_error(libc::EIO);
+            }
+        };
+
+        let content = value.get(op.offset() as usize..).unwrap_or(&[]);
+        req.reply(&content[..std::cmp::min(content.len(), op.size() as usize)])
+    }
+
+    fn readdir(&self, req: &Request, op: op::Readdir<'_>) -> io::Result<Replied> {
+        if op.ino() != ROOT_INO {
+            return req.reply_error(libc::ENOTDIR);
+        }
+        if op.mode() == op::ReaddirMode::Plus {
+            return req.reply_error(libc::ENOSYS);
+        }
+
+        let state = self.state.lock().unwrap();
+        let mut out = ReaddirOut::new(op.size() as usize);
+        state.entries.fill(op.offset(), &mut out, |&ino| {
+            let typ = if ino == ROOT_INO {
+                libc::DT_DIR
+            } else {
+                libc::DT_REG
+            };
+            (ino, typ as u32)
+        });
+        req.reply(out)
+    }
+}
+
+/// The kernel replies `ENOENT` to the notifications about
+/// the inodes and the entries that are not cached.
+fn ignore_enoent(res: io::Result<()>) -> io::Result<()> {
+    match res {
+        Err(ref err) if err.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+        res => res,
+    }
+}
@@ -0,0 +1,64 @@
+//! A line-based protocol between the key-value server and its clients.
+//!
+//! Each request is a single line, optionally followed by a payload:
+//!
+//! * `LIST` - replies `OK <count>` followed by `<key> <size>` lines.
+//! * `GET <key>` - replies `OK <size>` followed by the value.
+//! * `PUT <key> <size>` followed by the value - replies `OK`.
+//! * `DEL <key>` - replies `OK`.
+//! * `WATCH` - replies `OK`, and then the connection is turned into a stream
+//!   of `PUT <key>` and `DEL <key>` lines, one for each change.
+//!
+//! The errors are replied as `ERR <message>`.
+
+#![allow(dead_code)]
+
+use std::io::{self, BufRead, Read as _};
+
+/// Read a line, without the trailing newline.
+pub fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.ends_with('\n') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// Read the payload of the specified length.
+pub fn read_payload<R: BufRead>(reader: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(len);
+    reader.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buf)
+}
+
+/// Return whether the key can be used as a file name.
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key != "."
+        && key != ".."
+        && !key.contains(|c: char| c == '/' || c.is_whitespace() || c.is_control())
+}
+
+/// Parse the reply line of `OK [<arg>]`.
+pub fn parse_ok(line: &str) -> io::Result<&str> {
+    if line == "OK" {
+        return Ok("");
+    }
+    if let Some(arg) = line.strip_prefix("OK ") {
+        return Ok(arg);
+    }
+    match line.strip_prefix("ERR ") {
+        Some("not found") => Err(io::ErrorKind::NotFound.into()),
+        Some(msg) => Err(io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected reply: {:?}", line),
+        )),
+    }
+}
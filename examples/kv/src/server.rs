@@ -0,0 +1,130 @@
+//! A trivial in-memory key-value server for the `kv` example.
+//!
+//! The values can be changed with any line-oriented client:
+//!
+//! ```shell-session
+//! $ printf 'PUT greeting 6\nhello\n' | nc -q1 127.0.0.1 7878
+//! $ printf 'DEL greeting\n' | nc -q1 127.0.0.1 7878
+//! ```
+
+mod proto;
+
+use anyhow::Result;
+use std::{
+    collections::BTreeMap,
+    io::{self, BufReader, Write as _},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let mut args = pico_args::Arguments::from_env();
+
+    let addr: String = args
+        .opt_value_from_str("--listen")?
+        .unwrap_or_else(|| "127.0.0.1:7878".into());
+
+    let listener = TcpListener::bind(&addr)?;
+    tracing::info!("listening on {}", addr);
+
+    let store = Arc::new(Mutex::new(Store::default()));
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let store = store.clone();
+        thread::spawn(move || {
+            if let Err(err) = serve(stream, &store) {
+                tracing::error!("connection closed: {}", err);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct Store {
+    entries: BTreeMap<String, Vec<u8>>,
+    watchers: Vec<TcpStream>,
+}
+
+impl Store {
+    fn broadcast(&mut self, event: &str) {
+        tracing::info!("{}", event);
+        self.watchers
+            .retain(|mut watcher| writeln!(watcher, "{}", event).is_ok());
+    }
+}
+
+fn serve(stream: TcpStream, store: &Mutex<Store>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    while let Some(line) = proto::read_line(&mut reader)? {
+        let mut args = line.split(' ');
+        match (args.next(), args.next(), args.next()) {
+            (Some("LIST"), None, None) => {
+                let store = store.lock().unwrap();
+                let mut reply = format!("OK {}\n", store.entries.len());
+                for (key, value) in &store.entries {
+                    reply += &format!("{} {}\n", key, value.len());
+                }
+                writer.write_all(reply.as_bytes())?;
+            }
+
+            (Some("GET"), Some(key), None) => {
+                let store = store.lock().unwrap();
+                match store.entries.get(key) {
+                    Some(value) => {
+                        writeln!(writer, "OK {}", value.len())?;
+                        writer.write_all(value)?;
+                    }
+                    None => writeln!(writer, "ERR not found")?,
+                }
+            }
+
+            (Some("PUT"), Some(key), Some(len)) => {
+                let len = match len.parse() {
+                    Ok(len) => len,
+                    Err(..) => {
+                        writeln!(writer, "ERR invalid length")?;
+                        continue;
+                    }
+                };
+                let value = proto::read_payload(&mut reader, len)?;
+                if !proto::is_valid_key(key) {
+                    writeln!(writer, "ERR invalid key")?;
+                    continue;
+                }
+
+                let mut store = store.lock().unwrap();
+                store.entries.insert(key.to_owned(), value);
+                store.broadcast(&format!("PUT {}", key));
+                writeln!(writer, "OK")?;
+            }
+
+            (Some("DEL"), Some(key), None) => {
+                let mut store = store.lock().unwrap();
+                if store.entries.remove(key).is_some() {
+                    store.broadcast(&format!("DEL {}", key));
+                    writeln!(writer, "OK")?;
+                } else {
+                    writeln!(writer, "ERR not found")?;
+                }
+            }
+
+            (Some("WATCH"), None, None) => {
+                writeln!(writer, "OK")?;
+                store.lock().unwrap().watchers.push(writer);
+                return Ok(());
+            }
+
+            _ => writeln!(writer, "ERR unknown command")?,
+        }
+    }
+
+    Ok(())
+}
@@ -4,7 +4,7 @@
 use polyfuse::{
     op,
     reply::{AttrOut, EntryOut, FileAttr, OpenOut, ReaddirOut, WriteOut, XattrOut},
-    KernelConfig, Operation, Request, Session,
+    KernelConfig, Operation, Replied, Request, Session,
 };
 
 use anyhow::{ensure, Context as _, Result};
@@ -257,6 +257,7 @@ impl MemFS {
             Operation::Lookup(op) => self.do_lookup(&req, op)?,
             Operation::Forget(forgets) => {
                 self.do_forget(forgets.as_ref());
+                return Ok(());
             }
             Operation::Getattr(op) => self.do_getattr(&req, op)?,
             Operation::Setattr(op) => self.do_setattr(&req, op)?,
@@ -284,14 +285,14 @@ impl MemFS {
 
             _ => {
                 tracing::debug!("NOSYS");
-                req.reply_error(libc::ENOSYS)?;
+                req.reply_error(libc::ENOSYS)?
             }
-        }
+        };
 
         Ok(())
     }
 
-    fn do_lookup(&self, req: &Request, op: op::Lookup<'_>) -> io::Result<()> {
+    fn do_lookup(&self, req: &Request, op: op::Lookup<'_>) -> io::Result<Replied> {
         let parent = match self.inodes.get(op.parent()) {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
@@ -333,7 +334,7 @@ impl MemFS {
         }
     }
 
-    fn do_getattr(&self, req: &Request, op: op::Getattr<'_>) -> io::Result<()> {
+    fn do_getattr(&self, req: &Request, op: op::Getattr<'_>) -> io::Result<Replied> {
         let inode = match self.inodes.get(op.ino()) {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
@@ -346,7 +347,7 @@ impl MemFS {
         req.reply(out)
     }
 
-    fn do_setattr(&self, req: &Request, op: op::Setattr<'_>) -> io::Result<()> {
+    fn do_setattr(&self, req: &Request, op: op::Setattr<'_>) -> io::Result<Replied> {
         let mut inode = match self.inodes.get_mut(op.ino()) {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
@@ -395,7 +396,7 @@ impl MemFS {
         req.reply(out)
     }
 
-    fn do_readlink(&self, req: &Request, op: op::Readlink<'_>) -> io::Result<()> {
+    fn do_readlink(&self, req: &Request, op: op::Readlink<'_>) -> io::Result<Replied> {
         let inode = match self.inodes.get(op.ino()) {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
@@ -409,7 +410,7 @@ impl MemFS {
         req.reply(link)
     }
 
-    fn do_opendir(&mut self, req: &Request, op: op::Opendir<'_>) -> io::Result<()> {
+    fn do_opendir(&mut self, req: &Request, op: op::Opendir<'_>) -> io::Result<Replied> {
         let inode = match self.inodes.get(op.ino()) {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
@@ -433,7 +434,7 @@ impl MemFS {
         req.reply(out)
     }
 
-    fn do_readdir(&self, req: &Request, op: op::Readdir<'_>) -> io::Result<()> {
+    fn do_readdir(&self, req: &Request, op: op::Readdir<'_>) -> io::Result<Replied> {
         if op.mode() == op::ReaddirMode::Plus {
             return req.reply_error(libc::ENOSYS);
         }
@@ -455,12 +456,12 @@ impl MemFS {
         req.reply(out)
     }
 
-    fn do_releasedir(&mut self, req: &Request, op: op::Releasedir<'_>) -> io::Result<()> {
+    fn do_releasedir(&mut self, req: &Request, op: op::Releasedir<'_>) -> io::Result<Replied> {
         self.dir_handles.remove(op.fh() as usize);
         req.reply(())
     }
 
-    fn do_mknod(&self, req: &Request, op: op::Mknod<'_>) -> io::Result<()> {
+    fn do_mknod(&self, req: &Request, op: op::Mknod<'_>) -> io::Result<Replied> {
         match op.mode() & libc::S_IFMT {
             libc::S_IFREG => (),
             _ => return req.reply_error(libc::ENOTSUP),
@@ -481,7 +482,7 @@ impl MemFS {
         })
     }
 
-    fn do_mkdir(&self, req: &Request, op: op::Mkdir<'_>) -> io::Result<()> {
+    fn do_mkdir(&self, req: &Request, op: op::Mkdir<'_>) -> io::Result<Replied> {
         self.make_node(req, op.parent(), op.name(), |entry| INode {
             attr: {
                 let mut attr = unsafe { mem::zeroed::<libc::stat>() };
@@ -500,7 +501,7 @@ impl MemFS {
         })
     }
 
-    fn do_symlink(&self, req: &Request, op: op::Symlink<'_>) -> io::Result<()> {
+    fn do_symlink(&self, req: &Request, op: op::Symlink<'_>) -> io::Result<Replied> {
         self.make_node(req, op.parent(), op.name(), |entry| INode {
             attr: {
                 let mut attr = unsafe { mem::zeroed::<libc::stat>() };
@@ -516,7 +517,7 @@ impl MemFS {
         })
     }
 
-    fn make_node<F>(&self, req: &Request, parent: Ino, name: &OsStr, f: F) -> io::Result<()>
+    fn make_node<F>(&self, req: &Request, parent: Ino, name: &OsStr, f: F) -> io::Result<Replied>
     where
         F: FnOnce(&VacantEntry<'_>) -> INode,
     {
@@ -540,15 +541,15 @@ impl MemFS {
         out.ino(inode_entry.ino());
         fill_attr(out.attr(), &inode.attr);
         out.ttl_entry(self.ttl);
-        req.reply(out)?;
+        let replied = req.reply(out)?;
 
         map_entry.insert(inode_entry.ino());
         inode_entry.insert(inode);
 
-        Ok(())
+        Ok(replied)
     }
 
-    fn do_link(&self, req: &Request, op: op::Link<'_>) -> io::Result<()> {
+    fn do_link(&self, req: &Request, op: op::Link<'_>) -> io::Result<Replied> {
         let mut inode = match self.inodes.get_mut(op.ino()) {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
@@ -582,15 +583,15 @@ impl MemFS {
         req.reply(out)
     }
 
-    fn do_unlink(&self, req: &Request, op: op::Unlink<'_>) -> io::Result<()> {
+    fn do_unlink(&self, req: &Request, op: op::Unlink<'_>) -> io::Result<Replied> {
         self.unlink_node(req, op.parent(), op.name())
     }
 
-    fn do_rmdir(&self, req: &Request, op: op::Rmdir<'_>) -> io::Result<()> {
+    fn do_rmdir(&self, req: &Request, op: op::Rmdir<'_>) -> io::Result<Replied> {
         self.unlink_node(req, op.parent(), op.name())
     }
 
-    fn unlink_node(&self, req: &Request, parent: Ino, name: &OsStr) -> io::Result<()> {
+    fn unlink_node(&self, req: &Request, parent: Ino, name: &OsStr) -> io::Result<Replied> {
         let mut parent = match self.inodes.get_mut(parent) {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
@@ -624,7 +625,7 @@ impl MemFS {
         req.reply(())
     }
 
-    fn do_rename(&self, req: &Request, op: op::Rename<'_>) -> io::Result<()> {
+    fn do_rename(&self, req: &Request, op: op::Rename<'_>) -> io::Result<Replied> {
         if op.flags() != 0 {
             // TODO: handle RENAME_NOREPLACE and RENAME_EXCHANGE.
             return req.reply_error(libc::EINVAL);
@@ -683,7 +684,7 @@ impl MemFS {
         req.reply(())
     }
 
-    fn do_getxattr(&self, req: &Request, op: op::Getxattr<'_>) -> io::Result<()> {
+    fn do_getxattr(&self, req: &Request, op: op::Getxattr<'_>) -> io::Result<Replied> {
         let inode = match self.inodes.get(op.ino()) {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
@@ -709,7 +710,7 @@ impl MemFS {
         }
     }
 
-    fn do_setxattr(&self, req: &Request, op: op::Setxattr<'_>) -> io::Result<()> {
+    fn do_setxattr(&self, req: &Request, op: op::Setxattr<'_>) -> io::Result<Replied> {
         let create = op.flags() as i32 & libc::XATTR_CREATE != 0;
         let replace = op.flags() as i32 & libc::XATTR_REPLACE != 0;
         if create && replace {
@@ -744,7 +745,7 @@ impl MemFS {
         req.reply(())
     }
 
-    fn do_listxattr(&self, req: &Request, op: op::Listxattr<'_>) -> io::Result<()> {
+    fn do_listxattr(&self, req: &Request, op: op::Listxattr<'_>) -> io::Result<Replied> {
         let inode = match self.inodes.get(op.ino()) {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
@@ -776,7 +777,7 @@ impl MemFS {
         }
     }
 
-    fn do_removexattr(&self, req: &Request, op: op::Removexattr<'_>) -> io::Result<()> {
+    fn do_removexattr(&self, req: &Request, op: op::Removexattr<'_>) -> io::Result<Replied> {
         let mut inode = match self.inodes.get_mut(op.ino()) {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
@@ -792,7 +793,7 @@ impl MemFS {
         req.reply(())
     }
 
-    fn do_read(&self, req: &Request, op: op::Read<'_>) -> io::Result<()> {
+    fn do_read(&self, req: &Request, op: op::Read<'_>) -> io::Result<Replied> {
         let inode = match self.inodes.get(op.ino()) {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
@@ -812,7 +813,7 @@ impl MemFS {
         req.reply(content)
     }
 
-    fn do_write<T>(&self, req: &Request, op: op::Write<'_>, mut data: T) -> io::Result<()>
+    fn do_write<T>(&self, req: &Request, op: op::Write<'_>, mut data: T) -> io::Result<Replied>
     where
         T: BufRead + Unpin,
     {
@@ -50,11 +50,8 @@ fn main() -> Result<()> {
 
     // TODO: splice read/write
     let session = Session::mount(mountpoint, {
-        let mut config = KernelConfig::default();
-        config.mount_option("default_permissions");
+        let mut config = KernelConfig::passthrough();
         config.mount_option("fsname=passthrough");
-        config.export_support(true);
-        config.flock_locks(true);
         config.writeback_cache(timeout.is_some());
         config
     })?;
@@ -148,7 +145,9 @@ fn main() -> Result<()> {
 
                 Operation::Statfs(op) => try_reply!(fs.do_statfs(&op)),
 
-                _ => req.reply_error(libc::ENOSYS)?,
+                _ => {
+                    req.reply_error(libc::ENOSYS)?;
+                }
             }
 
             Ok(())
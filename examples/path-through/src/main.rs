@@ -53,8 +53,12 @@ fn main() -> Result<()> {
         macro_rules! try_reply {
             ($e:expr) => {
                 match $e {
-                    Ok(data) => req.reply(data)?,
-                    Err(err) => req.reply_error(err.raw_os_error().unwrap_or(libc::EIO))?,
+                    Ok(data) => {
+                        req.reply(data)?;
+                    }
+                    Err(err) => {
+                        req.reply_error(err.raw_os_error().unwrap_or(libc::EIO))?;
+                    }
                 }
             };
         }
@@ -77,7 +81,9 @@ fn main() -> Result<()> {
             Operation::Fsync(op) => try_reply!(fs.do_fsync(&op)),
             Operation::Release(op) => try_reply!(fs.do_release(&op)),
 
-            _ => req.reply_error(libc::ENOSYS)?,
+            _ => {
+                req.reply_error(libc::ENOSYS)?;
+            }
         }
     }
 
@@ -85,7 +85,8 @@ impl PollFS {
 
             Operation::Open(op) => {
                 if op.flags() as i32 & libc::O_ACCMODE != libc::O_RDONLY {
-                    return req.reply_error(libc::EACCES).map_err(Into::into);
+                    req.reply_error(libc::EACCES)?;
+                    return Ok(());
                 }
 
                 let is_nonblock = op.flags() as i32 & libc::O_NONBLOCK != 0;
@@ -142,14 +143,18 @@ impl PollFS {
             Operation::Read(op) => {
                 let handle = match self.handles.get(&op.fh()) {
                     Some(h) => h,
-                    None => return req.reply_error(libc::EINVAL).map_err(Into::into),
+                    None => {
+                        req.reply_error(libc::EINVAL)?;
+                        return Ok(());
+                    }
                 };
 
                 let mut state = handle.state.lock().unwrap();
                 if handle.is_nonblock {
                     if !state.is_ready {
                         tracing::info!("send EAGAIN immediately");
-                        return req.reply_error(libc::EAGAIN).map_err(Into::into);
+                        req.reply_error(libc::EAGAIN)?;
+                        return Ok(());
                     }
                 } else {
                     tracing::info!("wait for the completion of background task");
@@ -173,7 +178,10 @@ impl PollFS {
             Operation::Poll(op) => {
                 let handle = match self.handles.get(&op.fh()) {
                     Some(h) => h,
-                    None => return req.reply_error(libc::EINVAL).map_err(Into::into),
+                    None => {
+                        req.reply_error(libc::EINVAL)?;
+                        return Ok(());
+                    }
                 };
                 let state = &mut *handle.state.lock().unwrap();
 
@@ -195,7 +203,9 @@ impl PollFS {
                 req.reply(&[])?;
             }
 
-            _ => req.reply_error(libc::ENOSYS)?,
+            _ => {
+                req.reply_error(libc::ENOSYS)?;
+            }
         }
 
         Ok(())
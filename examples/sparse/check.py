@@ -0,0 +1,113 @@
+#!/usr/bin/env python3
+# Exercise SEEK_DATA/SEEK_HOLE and the punch-hole fallocate on a file
+# mounted by the sparse example.
+#
+# Usage: check.py <mounted-file>
+
+import ctypes
+import ctypes.util
+import errno
+import os
+import sys
+
+BLOCK = 4096
+FALLOC_FL_KEEP_SIZE = 0x01
+FALLOC_FL_PUNCH_HOLE = 0x02
+
+libc = ctypes.CDLL(ctypes.util.find_library("c"), use_errno=True)
+libc.fallocate.argtypes = [ctypes.c_int, ctypes.c_int, ctypes.c_int64, ctypes.c_int64]
+
+
+def fallocate(fd, mode, offset, length):
+    if libc.fallocate(fd, mode, offset, length) != 0:
+        err = ctypes.get_errno()
+        raise OSError(err, os.strerror(err))
+
+
+def seek(fd, offset, whence):
+    try:
+        return os.lseek(fd, offset, whence)
+    except OSError as e:
+        if e.errno == errno.ENXIO:
+            return "ENXIO"
+        raise
+
+
+def expect(what, actual, expected):
+    if actual != expected:
+        sys.exit("{}: expected {!r}, got {!r}".format(what, expected, actual))
+
+
+def check_layout(fd, layout):
+    """Check the result of SEEK_DATA/SEEK_HOLE from each block boundary."""
+    for offset, (data, hole) in layout.items():
+        expect("SEEK_DATA from {}".format(offset), seek(fd, offset, os.SEEK_DATA), data)
+        expect("SEEK_HOLE from {}".format(offset), seek(fd, offset, os.SEEK_HOLE), hole)
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit("usage: check.py <mounted-file>")
+
+    fd = os.open(sys.argv[1], os.O_RDWR | os.O_TRUNC)
+    try:
+        # [hole][data][hole][data data][hole] (6 blocks)
+        os.pwrite(fd, b"a" * BLOCK, 1 * BLOCK)
+        os.pwrite(fd, b"b" * (2 * BLOCK), 3 * BLOCK)
+        os.ftruncate(fd, 6 * BLOCK)
+        expect("size", os.fstat(fd).st_size, 6 * BLOCK)
+
+        check_layout(fd, {
+            0: (1 * BLOCK, 0),
+            100: (1 * BLOCK, 100),
+            1 * BLOCK + 10: (1 * BLOCK + 10, 2 * BLOCK),
+            2 * BLOCK: (3 * BLOCK, 2 * BLOCK),
+            3 * BLOCK: (3 * BLOCK, 5 * BLOCK),
+            5 * BLOCK: ("ENXIO", 5 * BLOCK),
+            6 * BLOCK: ("ENXIO", "ENXIO"),
+        })
+
+        # Punch out the first block of the second extent.
+        blocks = os.fstat(fd).st_blocks
+        fallocate(fd, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE, 3 * BLOCK, BLOCK)
+        expect("size after punching", os.fstat(fd).st_size, 6 * BLOCK)
+        expect("st_blocks after punching", os.fstat(fd).st_blocks, blocks - BLOCK // 512)
+        expect("punched range", os.pread(fd, BLOCK, 3 * BLOCK), b"\0" * BLOCK)
+        expect("data after the hole", os.pread(fd, BLOCK, 4 * BLOCK), b"b" * BLOCK)
+        check_layout(fd, {
+            2 * BLOCK: (4 * BLOCK, 2 * BLOCK),
+            3 * BLOCK: (4 * BLOCK, 3 * BLOCK),
+            4 * BLOCK: (4 * BLOCK, 5 * BLOCK),
+        })
+
+        # An unaligned punch zeroes the partial block but keeps it allocated.
+        fallocate(fd, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE, BLOCK + 100, 200)
+        expect(
+            "partially punched block",
+            os.pread(fd, BLOCK, BLOCK),
+            b"a" * 100 + b"\0" * 200 + b"a" * (BLOCK - 300),
+        )
+        expect("SEEK_DATA in a partial hole", seek(fd, BLOCK + 150, os.SEEK_DATA), BLOCK + 150)
+
+        # Blocks allocated beyond EOF are not visible.
+        fallocate(fd, FALLOC_FL_KEEP_SIZE, 6 * BLOCK, 2 * BLOCK)
+        expect("size after allocating", os.fstat(fd).st_size, 6 * BLOCK)
+        expect("SEEK_DATA beyond the data", seek(fd, 5 * BLOCK, os.SEEK_DATA), "ENXIO")
+
+        # Truncation discards the data beyond the new size.
+        os.ftruncate(fd, BLOCK + 10)
+        os.ftruncate(fd, 6 * BLOCK)
+        expect("extended range", os.pread(fd, 20, BLOCK), b"a" * 10 + b"\0" * 10)
+        check_layout(fd, {
+            0: (1 * BLOCK, 0),
+            1 * BLOCK: (1 * BLOCK, 2 * BLOCK),
+            2 * BLOCK: ("ENXIO", 2 * BLOCK),
+        })
+    finally:
+        os.close(fd)
+
+    print("ok")
+
+
+if __name__ == "__main__":
+    main()
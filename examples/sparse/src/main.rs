@@ -0,0 +1,275 @@
+//! A single-file filesystem that supports sparse files.
+//!
+//! The content of the file is stored as a set of fixed-size blocks, and the
+//! blocks that have never been written, or have been deallocated with
+//! `fallocate(FALLOC_FL_PUNCH_HOLE)`, are holes read as zeros.  The layout
+//! is exposed to the applications through `lseek(SEEK_DATA)` and
+//! `lseek(SEEK_HOLE)`, which allows tools such as `cp --sparse=always`
+//! to skip the holes.
+//!
+//! The accompanying `check.py` exercises these operations on the mounted
+//! file:
+//!
+//! ```shell-session
+//! $ touch /tmp/sparse
+//! $ cargo run -p polyfuse-example-sparse -- /tmp/sparse &
+//! $ python3 ./examples/sparse/check.py /tmp/sparse
+//! ```
+
+use polyfuse::{
+    op,
+    reply::{AttrOut, FileAttr, LseekOut, OpenOut, WriteOut},
+    KernelConfig, Operation, Replied, Request, Session,
+};
+
+use anyhow::{ensure, Context as _, Result};
+use std::{
+    cmp,
+    collections::BTreeMap,
+    io,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const BLOCK_SIZE: u64 = 4096;
+const TTL: Duration = Duration::from_secs(1);
+
+fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let mut args = pico_args::Arguments::from_env();
+
+    let mountpoint: PathBuf = args.free_from_str()?.context("missing mountpoint")?;
+    ensure!(mountpoint.is_file(), "mountpoint must be a regular file");
+
+    let session = Session::mount(mountpoint, KernelConfig::default())?;
+
+    let mut fs = SparseFS::new();
+
+    while let Some(req) = session.next_request()? {
+        let span = tracing::debug_span!("handle_request", unique = req.unique());
+        let _enter = span.enter();
+
+        let op = req.operation()?;
+        tracing::debug!(?op);
+
+        match op {
+            Operation::Getattr(..) => fs.getattr(&req)?,
+            Operation::Setattr(op) => fs.setattr(&req, op)?,
+            Operation::Open(..) => req.reply(OpenOut::default())?,
+            Operation::Read(op) => fs.read(&req, op)?,
+            Operation::Write(op, data) => fs.write(&req, op, data)?,
+            Operation::Fallocate(op) => fs.fallocate(&req, op)?,
+            Operation::Lseek(op) => fs.lseek(&req, op)?,
+            Operation::Flush(..) | Operation::Fsync(..) | Operation::Release(..) => req.reply(())?,
+            _ => req.reply_error(libc::ENOSYS)?,
+        };
+    }
+
+    Ok(())
+}
+
+type Block = Box<[u8; BLOCK_SIZE as usize]>;
+
+struct SparseFS {
+    blocks: BTreeMap<u64, Block>,
+    size: u64,
+    mode: u32,
+    mtime: Duration,
+    uid: u32,
+    gid: u32,
+}
+
+fn now() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+}
+
+impl SparseFS {
+    fn new() -> Self {
+        Self {
+            blocks: BTreeMap::new(),
+            size: 0,
+            mode: 0o644,
+            mtime: now(),
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        }
+    }
+
+    fn fill_attr(&self, attr: &mut FileAttr) {
+        attr.ino(1);
+        attr.mode(libc::S_IFREG | self.mode);
+        attr.nlink(1);
+        attr.size(self.size);
+        attr.blksize(BLOCK_SIZE as u32);
+        // st_blocks is counted in 512-byte units.
+        attr.blocks(self.blocks.len() as u64 * (BLOCK_SIZE / 512));
+        attr.uid(self.uid);
+        attr.gid(self.gid);
+        attr.atime(self.mtime);
+        attr.mtime(self.mtime);
+        attr.ctime(self.mtime);
+    }
+
+    fn getattr(&self, req: &Request) -> io::Result<Replied> {
+        let mut out = AttrOut::default();
+        self.fill_attr(out.attr());
+        out.ttl(TTL);
+        req.reply(out)
+    }
+
+    fn setattr(&mut self, req: &Request, op: op::Setattr<'_>) -> io::Result<Replied> {
+        if let Some(size) = op.size() {
+            if size < self.size {
+                self.zero_range(size, self.size - size);
+            }
+            self.size = size;
+            self.mtime = now();
+        }
+        if let Some(mode) = op.mode() {
+            self.mode = mode & 0o7777;
+        }
+        if let Some(mtime) = op.mtime() {
+            self.mtime = match mtime {
+                op::SetAttrTime::Timespec(ts) => ts,
+                _ => now(),
+            };
+        }
+        self.getattr(req)
+    }
+
+    fn read(&self, req: &Request, op: op::Read<'_>) -> io::Result<Replied> {
+        let offset = cmp::min(op.offset(), self.size);
+        let end = cmp::min(offset + op.size() as u64, self.size);
+
+        let mut buf = vec![0u8; (end - offset) as usize];
+        for_each_chunk(offset, end - offset, |index, range, pos| {
+            if let Some(block) = self.blocks.get(&index) {
+                buf[pos..pos + range.len()].copy_from_slice(&block[range]);
+            }
+        });
+        req.reply(buf)
+    }
+
+    fn write<T>(&mut self, req: &Request, op: op::Write<'_>, mut data: T) -> io::Result<Replied>
+    where
+        T: io::BufRead,
+    {
+        let mut buf = vec![0u8; op.size() as usize];
+        data.read_exact(&mut buf)?;
+
+        let offset = op.offset();
+        let blocks = &mut self.blocks;
+        for_each_chunk(offset, buf.len() as u64, |index, range, pos| {
+            let block = blocks
+                .entry(index)
+                .or_insert_with(|| Box::new([0u8; BLOCK_SIZE as usize]));
+            let len = range.len();
+            block[range].copy_from_slice(&buf[pos..pos + len]);
+        });
+        self.size = cmp::max(self.size, offset + buf.len() as u64);
+        self.mtime = now();
+
+        let mut out = WriteOut::default();
+        out.size(op.size());
+        req.reply(out)
+    }
+
+    fn fallocate(&mut self, req: &Request, op: op::Fallocate<'_>) -> io::Result<Replied> {
+        let mode = op.mode() as i32;
+        let (offset, length) = (op.offset(), op.length());
+
+        if mode & libc::FALLOC_FL_PUNCH_HOLE != 0 {
+            // The kernel requires PUNCH_HOLE to be combined with KEEP_SIZE.
+            if mode & !(libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE) != 0 {
+                return req.reply_error(libc::EOPNOTSUPP);
+            }
+            tracing::info!("punch a hole: offset={}, length={}", offset, length);
+            self.zero_range(offset, length);
+        } else {
+            if mode & !libc::FALLOC_FL_KEEP_SIZE != 0 {
+                return req.reply_error(libc::EOPNOTSUPP);
+            }
+            tracing::info!("allocate: offset={}, length={}", offset, length);
+            let blocks = &mut self.blocks;
+            for_each_chunk(offset, length, |index, _, _| {
+                blocks
+                    .entry(index)
+                    .or_insert_with(|| Box::new([0u8; BLOCK_SIZE as usize]));
+            });
+            if mode & libc::FALLOC_FL_KEEP_SIZE == 0 {
+                self.size = cmp::max(self.size, offset + length);
+            }
+        }
+        self.mtime = now();
+
+        req.reply(())
+    }
+
+    fn lseek(&self, req: &Request, op: op::Lseek<'_>) -> io::Result<Replied> {
+        let offset = op.offset();
+        if offset >= self.size {
+            return req.reply_error(libc::ENXIO);
+        }
+
+        let index = offset / BLOCK_SIZE;
+        let found = match op.whence() as i32 {
+            // The blocks allocated beyond the end of file with
+            // FALLOC_FL_KEEP_SIZE are not visible as data.
+            libc::SEEK_DATA => match self.blocks.range(index..).next() {
+                Some((&index, _)) if index * BLOCK_SIZE < self.size => {
+                    cmp::max(offset, index * BLOCK_SIZE)
+                }
+                _ => return req.reply_error(libc::ENXIO),
+            },
+            libc::SEEK_HOLE => {
+                // Find the first gap in the allocated blocks. There is an
+                // implicit hole at the end of the file.
+                let mut hole = index;
+                for (&index, _) in self.blocks.range(index..) {
+                    if index != hole {
+                        break;
+                    }
+                    hole += 1;
+                }
+                cmp::min(cmp::max(offset, hole * BLOCK_SIZE), self.size)
+            }
+            _ => return req.reply_error(libc::EINVAL),
+        };
+
+        let mut out = LseekOut::default();
+        out.offset(found);
+        req.reply(out)
+    }
+
+    /// Fill the range with zeros, deallocating the blocks fully covered.
+    fn zero_range(&mut self, offset: u64, length: u64) {
+        let blocks = &mut self.blocks;
+        for_each_chunk(offset, length, |index, range, _| {
+            if range.len() == BLOCK_SIZE as usize {
+                blocks.remove(&index);
+            } else if let Some(block) = blocks.get_mut(&index) {
+                for b in &mut block[range] {
+                    *b = 0;
+                }
+            }
+        });
+    }
+}
+
+/// Split the range into the chunks of each block, calling `f` with the block
+/// index, the range within the block and the position relative to `offset`.
+fn for_each_chunk<F>(offset: u64, length: u64, mut f: F)
+where
+    F: FnMut(u64, std::ops::Range<usize>, usize),
+{
+    let end = offset.saturating_add(length);
+    let mut pos = offset;
+    while pos < end {
+        let index = pos / BLOCK_SIZE;
+        let start = (pos % BLOCK_SIZE) as usize;
+        let len = cmp::min(BLOCK_SIZE - start as u64, end - pos) as usize;
+        f(index, start..start + len, (pos - offset) as usize);
+        pos += len as u64;
+    }
+}
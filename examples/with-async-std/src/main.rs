@@ -4,7 +4,7 @@
 use polyfuse::{
     op,
     reply::{AttrOut, EntryOut, FileAttr, ReaddirOut},
-    KernelConfig, Operation, Request, Session,
+    KernelConfig, Operation, Replied, Request, Session,
 };
 
 use anyhow::{ensure, Context as _, Result};
@@ -40,7 +40,7 @@ async fn main() -> Result<()> {
                 Operation::Read(op) => fs.read(&req, op).await?,
                 Operation::Readdir(op) => fs.readdir(&req, op).await?,
                 _ => req.reply_error(libc::ENOSYS)?,
-            }
+            };
 
             Ok(())
         });
@@ -104,7 +104,7 @@ impl Hello {
         attr.gid(self.gid);
     }
 
-    async fn lookup(&self, req: &Request, op: op::Lookup<'_>) -> io::Result<()> {
+    async fn lookup(&self, req: &Request, op: op::Lookup<'_>) -> io::Result<Replied> {
         match op.parent() {
             ROOT_INO if op.name().as_bytes() == HELLO_FILENAME.as_bytes() => {
                 let mut out = EntryOut::default();
@@ -118,7 +118,7 @@ impl Hello {
         }
     }
 
-    async fn getattr(&self, req: &Request, op: op::Getattr<'_>) -> io::Result<()> {
+    async fn getattr(&self, req: &Request, op: op::Getattr<'_>) -> io::Result<Replied> {
         let mut out = AttrOut::default();
         match op.ino() {
             ROOT_INO => self.fill_root_attr(out.attr()),
@@ -131,7 +131,7 @@ impl Hello {
         req.reply(out)
     }
 
-    async fn read(&self, req: &Request, op: op::Read<'_>) -> io::Result<()> {
+    async fn read(&self, req: &Request, op: op::Read<'_>) -> io::Result<Replied> {
         match op.ino() {
             HELLO_INO => (),
             ROOT_INO => return req.reply_error(libc::EISDIR),
@@ -157,7 +157,7 @@ impl Hello {
         })
     }
 
-    async fn readdir(&self, req: &Request, op: op::Readdir<'_>) -> io::Result<()> {
+    async fn readdir(&self, req: &Request, op: op::Readdir<'_>) -> io::Result<Replied> {
         if op.ino() != ROOT_INO {
             return req.reply_error(libc::ENOTDIR);
         }
@@ -202,11 +202,11 @@ impl AsyncSession {
 
         poll_fn(|cx| loop {
             match self.inner.get_ref().next_request() {
-                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                Err(err) if err.is_would_block() => {
                     ready!(self.inner.poll_readable(cx))?;
                     continue;
                 }
-                res => return Poll::Ready(res),
+                res => return Poll::Ready(res.map_err(Into::into)),
             }
         })
         .await
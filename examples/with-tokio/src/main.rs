@@ -4,13 +4,15 @@
 use polyfuse::{
     op,
     reply::{AttrOut, EntryOut, FileAttr, ReaddirOut},
-    KernelConfig, Operation, Request, Session,
+    KernelConfig, Operation, Replied, Request, Session,
 };
 
 use anyhow::{ensure, Context as _, Result};
 use std::{io, os::unix::prelude::*, path::PathBuf, sync::Arc, time::Duration};
 use tokio::{
     io::{unix::AsyncFd, Interest},
+    signal,
+    sync::mpsc,
     task::{self, JoinHandle},
 };
 
@@ -33,22 +35,49 @@ async fn main() -> Result<()> {
 
     let fs = Arc::new(Hello::new());
 
-    while let Some(req) = session.next_request().await? {
+    // Each spawned task holds a clone of `tx`, so that `rx` is closed once all
+    // the in-flight requests have been replied.
+    let (tx, mut rx) = mpsc::channel::<()>(1);
+
+    let ctrl_c = signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    loop {
+        let req = tokio::select! {
+            req = session.next_request() => match req? {
+                Some(req) => req,
+                None => break,
+            },
+            res = &mut ctrl_c => {
+                res?;
+                tracing::info!("received Ctrl-C, shutting down");
+                break;
+            }
+        };
+
         let fs = fs.clone();
+        let tx = tx.clone();
 
         let _: JoinHandle<Result<()>> = task::spawn(async move {
+            let _tx = tx;
+
             match req.operation()? {
                 Operation::Lookup(op) => fs.lookup(&req, op).await?,
                 Operation::Getattr(op) => fs.getattr(&req, op).await?,
                 Operation::Read(op) => fs.read(&req, op).await?,
                 Operation::Readdir(op) => fs.readdir(&req, op).await?,
                 _ => req.reply_error(libc::ENOSYS)?,
-            }
+            };
 
             Ok(())
         });
     }
 
+    // Wait for the pending replies before the session is dropped, which
+    // closes the connection and unmounts the filesystem.
+    drop(tx);
+    let _ = rx.recv().await;
+
     Ok(())
 }
 
@@ -107,7 +136,7 @@ impl Hello {
         attr.gid(self.gid);
     }
 
-    async fn lookup(&self, req: &Request, op: op::Lookup<'_>) -> io::Result<()> {
+    async fn lookup(&self, req: &Request, op: op::Lookup<'_>) -> io::Result<Replied> {
         match op.parent() {
             ROOT_INO if op.name().as_bytes() == HELLO_FILENAME.as_bytes() => {
                 let mut out = EntryOut::default();
@@ -121,7 +150,7 @@ impl Hello {
         }
     }
 
-    async fn getattr(&self, req: &Request, op: op::Getattr<'_>) -> io::Result<()> {
+    async fn getattr(&self, req: &Request, op: op::Getattr<'_>) -> io::Result<Replied> {
         let fill_attr = match op.ino() {
             ROOT_INO => Self::fill_root_attr,
             HELLO_INO => Self::fill_hello_attr,
@@ -135,7 +164,7 @@ impl Hello {
         req.reply(out)
     }
 
-    async fn read(&self, req: &Request, op: op::Read<'_>) -> io::Result<()> {
+    async fn read(&self, req: &Request, op: op::Read<'_>) -> io::Result<Replied> {
         match op.ino() {
             HELLO_INO => (),
             ROOT_INO => return req.reply_error(libc::EISDIR),
@@ -161,7 +190,7 @@ impl Hello {
         })
     }
 
-    async fn readdir(&self, req: &Request, op: op::Readdir<'_>) -> io::Result<()> {
+    async fn readdir(&self, req: &Request, op: op::Readdir<'_>) -> io::Result<Replied> {
         if op.ino() != ROOT_INO {
             return req.reply_error(libc::ENOTDIR);
         }
@@ -208,13 +237,13 @@ impl AsyncSession {
         poll_fn(|cx| {
             let mut guard = ready!(self.inner.poll_read_ready(cx))?;
             match self.inner.get_ref().next_request() {
-                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                Err(err) if err.is_would_block() => {
                     guard.clear_ready();
                     Poll::Pending
                 }
                 res => {
                     guard.retain_ready();
-                    Poll::Ready(res)
+                    Poll::Ready(res.map_err(Into::into))
                 }
             }
         })
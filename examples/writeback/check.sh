@@ -0,0 +1,64 @@
+#!/bin/bash
+# Exercise the writes, appends and truncations on a file mounted by the
+# writeback example, and check its content against a local reference file.
+#
+# Usage: check.sh <mounted-file> [<iterations>]
+
+set -euo pipefail
+
+target=${1:?usage: check.sh <mounted-file> [<iterations>]}
+iterations=${2:-200}
+
+workdir=$(mktemp -d)
+trap 'rm -rf "$workdir"' EXIT
+reference="$workdir/reference"
+chunk="$workdir/chunk"
+
+: > "$target"
+: > "$reference"
+
+drop_caches() {
+    sync "$target"
+    # Reading back through the kernel cache does not prove that the data
+    # reached the filesystem, so drop it if permitted.
+    echo 3 2>/dev/null > /proc/sys/vm/drop_caches || true
+}
+
+for i in $(seq 1 "$iterations"); do
+    size=$((RANDOM % 20000 + 1))
+    head -c "$size" /dev/urandom > "$chunk"
+
+    case $((RANDOM % 4)) in
+    0) # overwrite at a random offset, possibly extending the file
+        offset=$((RANDOM * 4 % 200000))
+        dd if="$chunk" of="$target" bs=64K seek="$offset" oflag=seek_bytes conv=notrunc status=none
+        dd if="$chunk" of="$reference" bs=64K seek="$offset" oflag=seek_bytes conv=notrunc status=none
+        ;;
+    1) # append
+        cat "$chunk" >> "$target"
+        cat "$chunk" >> "$reference"
+        ;;
+    2) # truncate, shrinking or extending the file
+        length=$((RANDOM * 4 % 200000))
+        truncate -s "$length" "$target"
+        truncate -s "$length" "$reference"
+        ;;
+    3) # check in the middle of the sequence
+        drop_caches
+        ;;
+    esac
+
+    if ! cmp -s "$target" "$reference"; then
+        echo "content mismatch after step $i" >&2
+        cmp "$target" "$reference" >&2 || true
+        exit 1
+    fi
+done
+
+drop_caches
+if [ "$(stat -c %s "$target")" != "$(stat -c %s "$reference")" ]; then
+    echo "size mismatch" >&2
+    exit 1
+fi
+cmp "$target" "$reference"
+echo "ok: $iterations steps"
@@ -0,0 +1,222 @@
+//! A single-file filesystem demonstrating the semantics of writeback caching.
+//!
+//! With writeback caching enabled (`FUSE_WRITEBACK_CACHE`), the kernel
+//! buffers the writes in the page cache and flushes them later, which
+//! changes how some of the requests must be interpreted:
+//!
+//! * The file size and the modification time are maintained by the kernel.
+//!   A truncation arrives as `Setattr` with the new size, which must be
+//!   applied as is, and the timestamps are updated with `Setattr` as well.
+//! * The offsets of the appending writes are computed by the kernel, so the
+//!   `O_APPEND` flag of the writes must be ignored. Without writeback
+//!   caching, the filesystem itself must append the data to the end.
+//! * The delayed writes from the page cache are not associated with the
+//!   writer process, and they are sent through any handle of the file.
+//!   The kernel may also read the file through a write-only handle in order
+//!   to fill the partially written pages.
+//!
+//! The accompanying `check.sh` performs a series of writes, appends and
+//! truncations on the mounted file and compares the result with the same
+//! operations on a local file:
+//!
+//! ```shell-session
+//! $ touch /tmp/wb
+//! $ cargo run -p polyfuse-example-writeback -- /tmp/wb &
+//! $ ./examples/writeback/check.sh /tmp/wb
+//! ```
+
+use polyfuse::{
+    op,
+    reply::{AttrOut, FileAttr, OpenOut, WriteOut},
+    KernelConfig, Operation, Replied, Request, Session,
+};
+
+use anyhow::{ensure, Context as _, Result};
+use std::{
+    cmp, io,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const TTL: Duration = Duration::from_secs(1);
+
+fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let mut args = pico_args::Arguments::from_env();
+
+    let writeback = !args.contains("--no-writeback");
+    tracing::info!(writeback);
+
+    let mountpoint: PathBuf = args.free_from_str()?.context("missing mountpoint")?;
+    ensure!(mountpoint.is_file(), "mountpoint must be a regular file");
+
+    let mut config = KernelConfig::default();
+    config.writeback_cache(writeback);
+
+    let session = Session::mount(mountpoint, config)?;
+
+    let mut fs = WritebackFS::new(writeback);
+
+    while let Some(req) = session.next_request()? {
+        let span = tracing::debug_span!("handle_request", unique = req.unique());
+        let _enter = span.enter();
+
+        match req.operation()? {
+            Operation::Getattr(..) => fs.getattr(&req)?,
+            Operation::Setattr(op) => fs.setattr(&req, op)?,
+            Operation::Open(op) => fs.open(&req, op)?,
+            Operation::Read(op) => fs.read(&req, op)?,
+            Operation::Write(op, data) => fs.write(&req, op, data)?,
+            Operation::Flush(..) | Operation::Fsync(..) | Operation::Release(..) => req.reply(())?,
+            _ => req.reply_error(libc::ENOSYS)?,
+        };
+    }
+
+    Ok(())
+}
+
+struct WritebackFS {
+    writeback: bool,
+    content: Vec<u8>,
+    mode: u32,
+    atime: Duration,
+    mtime: Duration,
+    ctime: Duration,
+    uid: u32,
+    gid: u32,
+}
+
+fn now() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+}
+
+impl WritebackFS {
+    fn new(writeback: bool) -> Self {
+        let now = now();
+        Self {
+            writeback,
+            content: vec![],
+            mode: 0o644,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        }
+    }
+
+    fn fill_attr(&self, attr: &mut FileAttr) {
+        attr.ino(1);
+        attr.mode(libc::S_IFREG | self.mode);
+        attr.nlink(1);
+        attr.size(self.content.len() as u64);
+        attr.uid(self.uid);
+        attr.gid(self.gid);
+        attr.atime(self.atime);
+        attr.mtime(self.mtime);
+        attr.ctime(self.ctime);
+    }
+
+    fn getattr(&self, req: &Request) -> io::Result<Replied> {
+        let mut out = AttrOut::default();
+        self.fill_attr(out.attr());
+        out.ttl(TTL);
+        req.reply(out)
+    }
+
+    fn setattr(&mut self, req: &Request, op: op::Setattr<'_>) -> io::Result<Replied> {
+        fn to_duration(t: op::SetAttrTime) -> Duration {
+            match t {
+                op::SetAttrTime::Timespec(ts) => ts,
+                _ => now(),
+            }
+        }
+
+        if let Some(size) = op.size() {
+            // The kernel has already flushed the dirty pages before
+            // sending the truncation, so the data can be resized safely.
+            tracing::info!("truncate: {} -> {}", self.content.len(), size);
+            self.content.resize(size as usize, 0);
+            self.mtime = now();
+            self.ctime = self.mtime;
+        }
+        if let Some(mode) = op.mode() {
+            self.mode = mode & 0o7777;
+        }
+        if let Some(atime) = op.atime() {
+            self.atime = to_duration(atime);
+        }
+        if let Some(mtime) = op.mtime() {
+            // With writeback caching, the modification time of the buffered
+            // writes is reported with this value rather than by each write.
+            self.mtime = to_duration(mtime);
+        }
+        if let Some(ctime) = op.ctime() {
+            self.ctime = ctime;
+        }
+
+        self.getattr(req)
+    }
+
+    fn open(&self, req: &Request, op: op::Open<'_>) -> io::Result<Replied> {
+        let flags = op.flags() as i32;
+        tracing::info!(
+            "open: accmode={}, append={}",
+            flags & libc::O_ACCMODE,
+            flags & libc::O_APPEND != 0
+        );
+
+        // The handles are not distinguished in this filesystem, but a real one
+        // must open the backing file with `O_RDWR` when `O_WRONLY` is requested,
+        // and must not pass `O_APPEND` to the backing file in writeback mode.
+        req.reply(OpenOut::default())
+    }
+
+    fn read(&mut self, req: &Request, op: op::Read<'_>) -> io::Result<Replied> {
+        self.atime = now();
+
+        let offset = cmp::min(op.offset() as usize, self.content.len());
+        let size = cmp::min(op.size() as usize, self.content.len() - offset);
+        req.reply(&self.content[offset..offset + size])
+    }
+
+    fn write<T>(&mut self, req: &Request, op: op::Write<'_>, mut data: T) -> io::Result<Replied>
+    where
+        T: io::BufRead,
+    {
+        let append = op.flags() as i32 & libc::O_APPEND != 0;
+
+        let offset = if append && !self.writeback {
+            self.content.len()
+        } else {
+            op.offset() as usize
+        };
+        let size = op.size() as usize;
+
+        tracing::info!(
+            "write: offset={}, size={}, append={}, delayed={}",
+            offset,
+            size,
+            append,
+            op.is_writeback(),
+        );
+
+        let mut buf = vec![0u8; size];
+        data.read_exact(&mut buf)?;
+
+        if self.content.len() < offset + size {
+            self.content.resize(offset + size, 0);
+        }
+        self.content[offset..offset + size].copy_from_slice(&buf);
+
+        if !self.writeback {
+            self.mtime = now();
+            self.ctime = self.mtime;
+        }
+
+        let mut out = WriteOut::default();
+        out.size(op.size());
+        req.reply(out)
+    }
+}
@@ -15,6 +15,8 @@ const CARGO_DOC_TIMEOUT: Duration = Duration::from_secs(60 * 15);
 const DOC_PACKAGES: &[&str] = &[
     "polyfuse", //
     "polyfuse-kernel",
+    "polyfuse-fs",
+    "polyfuse-test",
 ];
 
 pub struct DocBuilder<'env> {